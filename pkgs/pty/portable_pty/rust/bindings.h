@@ -11,6 +11,386 @@ This header is designed to be consumed by ffigen over on the Dart side.
 #include <stdint.h>
 #include <stdlib.h>
 
+/**
+ * Local PTYs and processes: `portable_pty_open`, `portable_pty_spawn`,
+ * `portable_pty_run`, `portable_pty_open_piped`.
+ */
+#define PORTABLE_PTY_CAP_LOCAL_PROCESSES (1 << 0)
+
+/**
+ * Remote sessions with `portable_pty_open_ssh`.
+ */
+#define PORTABLE_PTY_CAP_SSH (1 << 1)
+
+/**
+ * WSL sessions with `portable_pty_spawn_wsl`.
+ */
+#define PORTABLE_PTY_CAP_WSL (1 << 2)
+
+/**
+ * Compressed recordings (`PORTABLE_PTY_RECORD_ZSTD`).
+ */
+#define PORTABLE_PTY_CAP_ZSTD (1 << 3)
+
+/**
+ * POSIX signals and process groups: `portable_pty_kill` delivers the
+ * requested signal, and foreground process group queries work.
+ */
+#define PORTABLE_PTY_CAP_SIGNALS (1 << 4)
+
+/**
+ * Sessions that outlive their handle: the `persistent` backend and
+ * `portable_pty_detach`.
+ */
+#define PORTABLE_PTY_CAP_PERSISTENT (1 << 5)
+
+/**
+ * Transcripts of recordings with `portable_pty_recording_to_text`, and
+ * the tracked screen of `portable_pty_screen_start`.
+ */
+#define PORTABLE_PTY_CAP_SCREEN (1 << 6)
+
+/**
+ * Session recording with `portable_pty_record_start`.
+ */
+#define PORTABLE_PTY_CAP_RECORD (1 << 7)
+
+/**
+ * Existing terminals with `portable_pty_open_device` and
+ * `portable_pty_open_stdio`.
+ */
+#define PORTABLE_PTY_CAP_SERIAL (1 << 8)
+
+/**
+ * Output posted to Dart ports with `portable_pty_attach_dart_port`.
+ */
+#define PORTABLE_PTY_CAP_DART (1 << 9)
+
+/**
+ * Most clipboard data sent at once, before encoding.
+ */
+#define PORTABLE_PTY_CLIPBOARD_MAX (1024 * 1024)
+
+/**
+ * Start the cursor where the embedder's terminal has it, instead of at
+ * the top left. The console then asks where that is (`ESC [ 6 n`) before
+ * writing anything: answer with `ESC [ row ; col R` on the handle, or the
+ * child's output never arrives.
+ */
+#define PORTABLE_PTY_CONPTY_INHERIT_CURSOR 1
+
+/**
+ * Don't repaint the whole screen on every resize.
+ */
+#define PORTABLE_PTY_CONPTY_RESIZE_QUIRK 2
+
+/**
+ * Deliver key events to the child as Win32 input records.
+ */
+#define PORTABLE_PTY_CONPTY_WIN32_INPUT_MODE 4
+
+/**
+ * Pass the child's VT output through unrendered. Needs Windows 11 24H2
+ * or a newer `conpty.dll`.
+ */
+#define PORTABLE_PTY_CONPTY_PASSTHROUGH_MODE 8
+
+/**
+ * The flags `portable_pty_open` creates its console with.
+ */
+#define PORTABLE_PTY_CONPTY_DEFAULT ((PORTABLE_PTY_CONPTY_INHERIT_CURSOR | PORTABLE_PTY_CONPTY_RESIZE_QUIRK) | PORTABLE_PTY_CONPTY_WIN32_INPUT_MODE)
+
+/**
+ * ConPTY's requests for its own input modes: win32-input-mode (`CSI ?
+ * 9001 h`) and focus events (`CSI ? 1004 h`).
+ */
+#define PORTABLE_PTY_CONPTY_FILTER_MODES (1 << 0)
+
+/**
+ * Window titles (`OSC 0`, `1` and `2`), the executable's path included.
+ */
+#define PORTABLE_PTY_CONPTY_FILTER_TITLE (1 << 1)
+
+/**
+ * Cursor hiding and showing, reported once per read as it stands at the
+ * end of it, rather than around every frame.
+ */
+#define PORTABLE_PTY_CONPTY_FILTER_CURSOR (1 << 2)
+
+/**
+ * Clearing the screen and homing the cursor before the child has written
+ * anything.
+ */
+#define PORTABLE_PTY_CONPTY_FILTER_CLEAR (1 << 3)
+
+/**
+ * Reads that repaint the screen without changing it.
+ */
+#define PORTABLE_PTY_CONPTY_FILTER_REPAINT (1 << 4)
+
+/**
+ * All of the above.
+ */
+#define PORTABLE_PTY_CONPTY_FILTER_ALL ((1 << 5) - 1)
+
+/**
+ * Reads end once the child has exited and its output has been read.
+ */
+#define PORTABLE_PTY_EOF_CHILD_EXIT 0
+
+/**
+ * Reads never end.
+ */
+#define PORTABLE_PTY_EOF_NEVER 1
+
+/**
+ * Reads end once nothing holds the terminal open any more.
+ */
+#define PORTABLE_PTY_EOF_ALL_EXIT 2
+
+/**
+ * A registered pattern matched (`id` = pattern ID, `value` = offset of the
+ * match in the output stream, `data` = matched bytes).
+ */
+#define PORTABLE_PTY_EVENT_MATCH 1
+
+/**
+ * A queued command finished (`id` = command ID, `value` = exit status or
+ * -1 if unknown, `data` = captured output).
+ */
+#define PORTABLE_PTY_EVENT_COMMAND_DONE 2
+
+/**
+ * The child went over a resource threshold (`id` = one of the
+ * `PORTABLE_PTY_RESOURCE_*` constants, `value` = the sampled value).
+ */
+#define PORTABLE_PTY_EVENT_RESOURCE 3
+
+/**
+ * The child was stopped or continued (`id` = the
+ * `PORTABLE_PTY_CHILD_*` state it's now in, `value` = the signal that
+ * stopped it, or 0).
+ */
+#define PORTABLE_PTY_EVENT_JOB 4
+
+/**
+ * No output has been read for the handle's idle timeout (`value` = how
+ * long it has been quiet, in milliseconds).
+ */
+#define PORTABLE_PTY_EVENT_IDLE 5
+
+/**
+ * Output was read after none had been for the handle's activity
+ * threshold (`value` = how long it had been quiet, in milliseconds).
+ */
+#define PORTABLE_PTY_EVENT_ACTIVITY 6
+
+/**
+ * The child is running (and in an event, has been continued).
+ */
+#define PORTABLE_PTY_CHILD_RUNNING 0
+
+/**
+ * The child is stopped (`value` in an event = the signal that stopped it).
+ */
+#define PORTABLE_PTY_CHILD_STOPPED 1
+
+/**
+ * The child has exited.
+ */
+#define PORTABLE_PTY_CHILD_EXITED 2
+
+#define PORTABLE_PTY_KEY_UP 0
+
+#define PORTABLE_PTY_KEY_DOWN 1
+
+#define PORTABLE_PTY_KEY_RIGHT 2
+
+#define PORTABLE_PTY_KEY_LEFT 3
+
+#define PORTABLE_PTY_KEY_HOME 4
+
+#define PORTABLE_PTY_KEY_END 5
+
+#define PORTABLE_PTY_KEY_INSERT 6
+
+#define PORTABLE_PTY_KEY_DELETE 7
+
+#define PORTABLE_PTY_KEY_PAGE_UP 8
+
+#define PORTABLE_PTY_KEY_PAGE_DOWN 9
+
+/**
+ * F1; F2 to F12 follow it, up to `PORTABLE_PTY_KEY_F1 + 11`.
+ */
+#define PORTABLE_PTY_KEY_F1 16
+
+/**
+ * Keypad 0; keypad 1 to 9 follow it, up to `PORTABLE_PTY_KEY_KP_0 + 9`.
+ */
+#define PORTABLE_PTY_KEY_KP_0 32
+
+#define PORTABLE_PTY_KEY_KP_DECIMAL 42
+
+#define PORTABLE_PTY_KEY_KP_ENTER 43
+
+#define PORTABLE_PTY_KEY_KP_PLUS 44
+
+#define PORTABLE_PTY_KEY_KP_MINUS 45
+
+#define PORTABLE_PTY_KEY_KP_MULTIPLY 46
+
+#define PORTABLE_PTY_KEY_KP_DIVIDE 47
+
+/**
+ * `id` of a resource event for resident memory (`value` in bytes).
+ */
+#define PORTABLE_PTY_RESOURCE_RSS 1
+
+/**
+ * `id` of a resource event for CPU use (`value` in percent of a core).
+ */
+#define PORTABLE_PTY_RESOURCE_CPU 2
+
+#define PORTABLE_PTY_MOUSE_LEFT 0
+
+#define PORTABLE_PTY_MOUSE_MIDDLE 1
+
+#define PORTABLE_PTY_MOUSE_RIGHT 2
+
+/**
+ * No button held (motion-only events).
+ */
+#define PORTABLE_PTY_MOUSE_NONE 3
+
+#define PORTABLE_PTY_MOUSE_WHEEL_UP 4
+
+#define PORTABLE_PTY_MOUSE_WHEEL_DOWN 5
+
+#define PORTABLE_PTY_MOUSE_WHEEL_LEFT 6
+
+#define PORTABLE_PTY_MOUSE_WHEEL_RIGHT 7
+
+#define PORTABLE_PTY_MOUSE_PRESS 0
+
+#define PORTABLE_PTY_MOUSE_RELEASE 1
+
+#define PORTABLE_PTY_MOUSE_MOTION 2
+
+#define PORTABLE_PTY_MOD_SHIFT 1
+
+#define PORTABLE_PTY_MOD_ALT 2
+
+#define PORTABLE_PTY_MOD_CTRL 4
+
+#define PORTABLE_PTY_MOUSE_TRACKING_OFF 0
+
+#define PORTABLE_PTY_MOUSE_TRACKING_X10 1
+
+#define PORTABLE_PTY_MOUSE_TRACKING_NORMAL 2
+
+#define PORTABLE_PTY_MOUSE_TRACKING_BUTTON_EVENT 3
+
+#define PORTABLE_PTY_MOUSE_TRACKING_ANY_EVENT 4
+
+#define PORTABLE_PTY_MOUSE_ENCODING_DEFAULT 0
+
+#define PORTABLE_PTY_MOUSE_ENCODING_UTF8 1
+
+#define PORTABLE_PTY_MOUSE_ENCODING_SGR 2
+
+#define PORTABLE_PTY_MOUSE_ENCODING_URXVT 3
+
+/**
+ * What `portable_pty_try_read` returns at end of file, 0 meaning there's
+ * nothing to read yet.
+ */
+#define PORTABLE_PTY_READ_EOF -2
+
+/**
+ * What `portable_pty_read_timeout` returns when nothing came in time.
+ */
+#define PORTABLE_PTY_READ_TIMEOUT -3
+
+/**
+ * What `portable_pty_write` returns, with non-blocking writes on, when
+ * the child's input has no room for any of it.
+ */
+#define PORTABLE_PTY_WRITE_WOULD_BLOCK -4
+
+/**
+ * What `portable_pty_read` returns, with the master non-blocking, when
+ * there's nothing to read; the same as `PORTABLE_PTY_WRITE_WOULD_BLOCK`.
+ */
+#define PORTABLE_PTY_READ_WOULD_BLOCK -4
+
+/**
+ * Put the terminal in raw mode (`cfmakeraw`) before anything runs in it.
+ */
+#define PORTABLE_PTY_OPEN_RAW 1
+
+/**
+ * Turn the terminal's echo off.
+ */
+#define PORTABLE_PTY_OPEN_NO_ECHO 2
+
+/**
+ * Start with auto-flush off; see `portable_pty_set_auto_flush`.
+ */
+#define PORTABLE_PTY_OPEN_NO_AUTO_FLUSH 4
+
+/**
+ * asciinema's asciicast v2 (newline-delimited JSON).
+ */
+#define PORTABLE_PTY_RECORD_ASCIICAST_V2 1
+
+/**
+ * ttyrec, as read by `ttyplay` and `ipbt`. Records output only.
+ */
+#define PORTABLE_PTY_RECORD_TTYREC 2
+
+/**
+ * Flag OR'd into a format to zstd-compress the file as it is written.
+ * Needs the `zstd` feature.
+ */
+#define PORTABLE_PTY_RECORD_ZSTD 256
+
+/**
+ * Everything left on the primary screen and in its scrollback.
+ */
+#define PORTABLE_PTY_TRANSCRIPT_SCREEN 1
+
+/**
+ * A JSON array of strings, one per shell prompt (split at OSC 133 `A`
+ * marks), each holding the prompt, the command and its output.
+ */
+#define PORTABLE_PTY_TRANSCRIPT_COMMANDS 2
+
+/**
+ * Speak telnet to clients instead of passing raw bytes.
+ */
+#define PORTABLE_PTY_SERVE_TELNET (1 << 0)
+
+/**
+ * Ignore clients' input and window sizes; they only watch.
+ */
+#define PORTABLE_PTY_SERVE_READ_ONLY (1 << 1)
+
+/**
+ * A word: letters, digits and `_`.
+ */
+#define PORTABLE_PTY_SELECT_WORD 0
+
+/**
+ * A URL or path, or a word with the characters they hold.
+ */
+#define PORTABLE_PTY_SELECT_PATH 1
+
+/**
+ * A shell argument, quotes and escapes included.
+ */
+#define PORTABLE_PTY_SELECT_ARGUMENT 2
+
 typedef enum PortablePtyResult {
   Ok = 0,
   ErrOpen = 1,
@@ -25,14 +405,264 @@ typedef enum PortablePtyResult {
   ErrSize = 10,
   ErrWaitBlocking = 11,
   ErrProcessGroup = 12,
+  ErrTimeout = 13,
+  ErrPattern = 14,
+  ErrEof = 15,
+  ErrRecord = 16,
+  ErrReplay = 17,
+  ErrBackend = 18,
+  ErrAuth = 19,
+  ErrUnsupported = 20,
+  ErrDenied = 21,
+  ErrPolicy = 22,
+  ErrInternal = 23,
 } PortablePtyResult;
 
 typedef struct PortablePty PortablePty;
 
+/**
+ * A command being built.
+ */
+typedef struct PortablePtyCmd PortablePtyCmd;
+
+/**
+ * A command being decided on. Changed with `portable_pty_policy_rewrite`
+ * during the callback.
+ */
+typedef struct PortablePtyPolicyRewrite PortablePtyPolicyRewrite;
+
+/**
+ * A byte buffer allocated by this library and owned by the caller.
+ *
+ * Release it with `portable_pty_buffer_free`. An empty buffer has a NULL
+ * `data` pointer.
+ */
+typedef struct PortablePtyBuffer {
+  uint8_t *data;
+  uintptr_t len;
+} PortablePtyBuffer;
+
+/**
+ * Cumulative I/O of a session, filled in by `portable_pty_io_stats`.
+ */
+typedef struct PortablePtyIoStats {
+  /**
+   * Bytes of output read off the handle.
+   */
+  uint64_t output_bytes;
+  /**
+   * Bytes written to the child.
+   */
+  uint64_t input_bytes;
+  /**
+   * Bytes the child has read from storage, or -1 if unknown.
+   */
+  int64_t disk_read_bytes;
+  /**
+   * Bytes the child has written to storage, or -1 if unknown.
+   */
+  int64_t disk_write_bytes;
+} PortablePtyIoStats;
+
+/**
+ * What's about to be spawned. Only valid for the duration of the call.
+ */
+typedef struct PortablePtySpawnInfo {
+  /**
+   * The session spawning it.
+   */
+  const struct PortablePty *handle;
+  /**
+   * The program, as it will be looked up.
+   */
+  const char *program;
+  /**
+   * `argc` arguments, `argv[0]` included, followed by NULL.
+   */
+  const char *const *argv;
+  uintptr_t argc;
+  /**
+   * The directory it will start in, or NULL for the caller's.
+   */
+  const char *cwd;
+  /**
+   * The user it will run as, or -1 where there are no user IDs.
+   */
+  int64_t uid;
+  /**
+   * When it was asked for, in milliseconds since the Unix epoch.
+   */
+  uint64_t timestamp_ms;
+} PortablePtySpawnInfo;
+
+/**
+ * Callbacks implementing a backend, for `portable_pty_register_backend`.
+ *
+ * Apart from `open`, each takes the session pointer `open` produced.
+ * `read` runs on a background thread while the others are called from
+ * whichever thread calls into the library, so they must be safe to call
+ * concurrently with it.
+ */
+typedef struct PortablePtyBackend {
+  /**
+   * Passed to `open`.
+   */
+  void *user_data;
+  /**
+   * Required. Start a session: `(user_data, config, rows, cols,
+   * out_session)`. `config` is the JSON object given to
+   * `portable_pty_open_backend`, valid for the call only. Store the
+   * session in `*out_session` and return `Ok`, or return the error for
+   * `portable_pty_open_backend` to pass on.
+   */
+  enum PortablePtyResult (*open)(void*, const char*, uint16_t, uint16_t, void**);
+  /**
+   * Required. Block until there is output, then copy up to `len` bytes
+   * of it to `buf`: `(session, buf, len)`. Returns the number copied, or
+   * 0 (or -1 on error) once the session has ended.
+   */
+  int64_t (*read)(void*, uint8_t*, uintptr_t);
+  /**
+   * Required. Send input: `(session, buf, len)`. Returns the number of
+   * bytes taken, or -1 on error.
+   */
+  int64_t (*write)(void*, const uint8_t*, uintptr_t);
+  /**
+   * Optional. Resize the remote terminal: `(session, rows, cols)`.
+   */
+  enum PortablePtyResult (*resize)(void*, uint16_t, uint16_t);
+  /**
+   * Required. End the session: `(session)`. A blocked `read` must return.
+   * Called when the handle is killed or closed, at most once.
+   */
+  void (*kill)(void*);
+  /**
+   * Optional. The exit status of a session that has ended: `(session)`.
+   * Without it, sessions exit with 0, or 137 once killed.
+   */
+  int (*wait)(void*);
+  /**
+   * Optional. Release the session: `(session)`. Called once, after the
+   * handle is closed and `read` has returned for the last time.
+   */
+  void (*close)(void*);
+} PortablePtyBackend;
+
+/**
+ * An event delivered by `portable_pty_next_event` or the event callback.
+ *
+ * Events returned by `portable_pty_next_event` own `data`; release it with
+ * `portable_pty_event_free`. Events passed to a callback are only valid for
+ * the duration of the call.
+ */
+typedef struct PortablePtyEvent {
+  /**
+   * One of the `PORTABLE_PTY_EVENT_*` constants.
+   */
+  uint32_t kind;
+  /**
+   * Kind-specific identifier.
+   */
+  uint64_t id;
+  /**
+   * Kind-specific value.
+   */
+  int64_t value;
+  /**
+   * Kind-specific payload; empty when unused.
+   */
+  struct PortablePtyBuffer data;
+} PortablePtyEvent;
+
+/**
+ * Result of a successful `portable_pty_expect`.
+ *
+ * Release with `portable_pty_expect_match_free`.
+ */
+typedef struct PortablePtyExpectMatch {
+  /**
+   * Output consumed before the match: at most the last 1 MiB of it,
+   * less the match.
+   */
+  struct PortablePtyBuffer before;
+  /**
+   * The matched bytes.
+   */
+  struct PortablePtyBuffer matched;
+  /**
+   * Bytes of output dropped before `before` to keep within 1 MiB; 0
+   * if `before` is all of it.
+   */
+  uintptr_t dropped;
+} PortablePtyExpectMatch;
+
+/**
+ * Close callback: `(userdata, result)`.
+ */
+typedef void (*PortablePtyCloseCallback)(void*, enum PortablePtyResult);
+
+/**
+ * How to open a handle. Set `struct_size` to `sizeof` the struct and
+ * zero what you don't use.
+ */
+typedef struct PortablePtyOpenConfig {
+  /**
+   * `sizeof(PortablePtyOpenConfig)` as the caller was built with.
+   */
+  uint32_t struct_size;
+  /**
+   * Size in cells; 0 for 24 rows or 80 columns.
+   */
+  uint16_t rows;
+  uint16_t cols;
+  /**
+   * Size in pixels, for programs that draw images; 0 if unknown.
+   */
+  uint16_t pixel_width;
+  uint16_t pixel_height;
+  /**
+   * `PORTABLE_PTY_OPEN_*` flags.
+   */
+  uint32_t flags;
+  /**
+   * Null-terminated name of a backend, as `portable_pty_open_backend`
+   * takes, or NULL for a local PTY as `portable_pty_open` opens.
+   */
+  const char *backend;
+  /**
+   * Null-terminated UTF-8 JSON config for `backend`, or NULL for `{}`.
+   * `rows` and `cols` above fill in for those it doesn't give.
+   */
+  const char *backend_config;
+  /**
+   * Capacity of the buffer holding input while auto-flush is off; 0
+   * for the default.
+   */
+  uintptr_t write_buffer_size;
+} PortablePtyOpenConfig;
+
+/**
+ * One buffer of a vectored read or write.
+ */
+typedef struct PortablePtyIoVec {
+  /**
+   * Start of the buffer; only read from by `portable_pty_writev`. May be
+   * NULL if `len` is 0.
+   */
+  uint8_t *data;
+  uintptr_t len;
+} PortablePtyIoVec;
+
+/**
+ * Free a buffer returned by this library. Safe to call on an empty buffer.
+ */
+void portable_pty_buffer_free(struct PortablePtyBuffer buffer);
+
 /**
  * Open a new PTY with the given dimensions.
  *
  * On success, writes the opaque handle to `*out` and returns `Ok`.
+ * Returns `ErrUnsupported` on platforms without local processes (iOS).
  */
 enum PortablePtyResult portable_pty_open(uint16_t rows, uint16_t cols, struct PortablePty **out);
 
@@ -44,6 +674,10 @@ enum PortablePtyResult portable_pty_open(uint16_t rows, uint16_t cols, struct Po
  *   or NULL to use `cmd` as the sole argument.
  * - `envp`: null-terminated array of `"KEY=VALUE"` strings, or NULL to
  *   inherit the current environment.
+ *
+ * On Unix these are taken as the bytes they are, UTF-8 or not; elsewhere
+ * they must be UTF-8, and `ErrSpawn` is returned for a `cmd` or `argv`
+ * entry that isn't.
  */
 enum PortablePtyResult portable_pty_spawn(struct PortablePty *handle,
                                           const char *cmd,
@@ -53,31 +687,87 @@ enum PortablePtyResult portable_pty_spawn(struct PortablePty *handle,
 /**
  * Read bytes from the PTY master side (child's stdout).
  *
- * Returns number of bytes read, or -1 on error/EOF.
+ * Returns number of bytes read, 0 at end of file, or -1 on error. On Unix
+ * the end comes, by default, once the child has exited and everything it
+ * wrote has been read; `portable_pty_set_eof_policy` changes that. While
+ * output is paused, or held back by the watermark, it blocks until
+ * reading restarts (see `flow`). With the master made non-blocking, it
+ * returns `PORTABLE_PTY_READ_WOULD_BLOCK` rather than wait for output
+ * (see `nonblocking`).
  */
 int64_t portable_pty_read(struct PortablePty *handle, uint8_t *buf, uintptr_t len);
 
 /**
  * Write bytes to the PTY master side (child's stdin).
  *
- * Returns number of bytes written, or -1 on error.
+ * Returns number of bytes written, or -1 on error. Unless auto-flush has
+ * been turned off, they're on their way to the child by then. With
+ * non-blocking writes on, fewer may be written, or none, returning
+ * `PORTABLE_PTY_WRITE_WOULD_BLOCK` (see `nonblocking`).
  */
 int64_t portable_pty_write(struct PortablePty *handle, const uint8_t *buf, uintptr_t len);
 
 /**
- * Resize the PTY.
+ * Send the child whatever has been written but not yet flushed.
+ *
+ * Returns `ErrWrite` if it couldn't all be written.
  */
-enum PortablePtyResult portable_pty_resize(struct PortablePty *handle,
-                                           uint16_t rows,
-                                           uint16_t cols);
+enum PortablePtyResult portable_pty_flush(const struct PortablePty *handle);
 
 /**
- * Get the PTY master side file descriptor.
+ * Promise that only `portable_pty_read` reads the handle, from one thread
+ * at a time, so it can skip the locks that keep readers apart.
+ *
+ * For embedders reading heavy output on a dedicated thread, where those
+ * locks show up in profiles. While it's on, nothing else may read the
+ * handle: no `portable_pty_expect`, `portable_pty_run` or other call
+ * that waits for output, and no second thread calling
+ * `portable_pty_read`. Breaking the promise is undefined behavior. Turn
+ * it on before the reading thread starts and off after it stops.
  */
-int portable_pty_master_fd(struct PortablePty *handle);
+enum PortablePtyResult portable_pty_set_exclusive_reader(const struct PortablePty *handle,
+                                                         bool exclusive);
 
 /**
- * Get the current PTY size as tracked by the kernel.
+ * Choose whether each `portable_pty_write` is flushed as it's made (the
+ * default).
+ *
+ * With auto-flush off, writes collect in the handle's buffer and only go
+ * to the child once it fills or on `portable_pty_flush`, so a paste sent
+ * in chunks goes out in large writes. Input the library writes itself,
+ * such as queued commands and replies to queries, still goes straight
+ * out, after anything buffered. Turning auto-flush back on flushes.
+ */
+enum PortablePtyResult portable_pty_set_auto_flush(const struct PortablePty *handle, bool enabled);
+
+/**
+ * Resize the PTY.
+ */
+enum PortablePtyResult portable_pty_resize(struct PortablePty *handle,
+                                           uint16_t rows,
+                                           uint16_t cols);
+
+/**
+ * Wait until the child's input has room for a write, for callers doing
+ * their own flow control before large writes.
+ *
+ * - `timeout_ms`: maximum time to wait; negative waits indefinitely.
+ *
+ * Returns `Ok` once a write would make progress, `ErrTimeout` if
+ * `timeout_ms` elapsed first, and `ErrWrite` if the PTY can't be polled.
+ * Only a local Unix PTY fills up; on Windows, and for the other backends,
+ * this returns `Ok` straight away.
+ */
+enum PortablePtyResult portable_pty_wait_writable(const struct PortablePty *handle,
+                                                  int32_t timeout_ms);
+
+/**
+ * Get the PTY master side file descriptor.
+ */
+int portable_pty_master_fd(struct PortablePty *handle);
+
+/**
+ * Get the current PTY size as tracked by the kernel.
  */
 enum PortablePtyResult portable_pty_get_size(struct PortablePty *handle,
                                              uint16_t *out_rows,
@@ -112,17 +802,75 @@ enum PortablePtyResult portable_pty_wait(struct PortablePty *handle, int *out_st
  */
 enum PortablePtyResult portable_pty_wait_blocking(struct PortablePty *handle, int *out_status);
 
+/**
+ * Block until the child exits, then take everything it left unread.
+ *
+ * Waiting and reading separately races: the exit can be seen before the
+ * last of the output has been read. This returns only once that output
+ * is in hand, including anything an earlier expect or query held back.
+ * Like anything read, it's seen by the handle's pattern matchers,
+ * recording and clients, so with `out_output` NULL it still reaches those
+ * and is otherwise dropped.
+ *
+ * - `out_status`: receives the exit code, as for
+ *   `portable_pty_wait_blocking`.
+ * - `out_output`: receives the remaining output; release with
+ *   `portable_pty_buffer_free`. May be NULL.
+ *
+ * On Unix, reading stops at end of file under the EOF policy or once no
+ * more output arrives for a moment, so descendants that keep the terminal
+ * busy after the child exits keep this reading too. The ConPTY's pipe
+ * can't be polled, so off Unix only output the library already holds is
+ * returned.
+ */
+enum PortablePtyResult portable_pty_wait_drained(struct PortablePty *handle,
+                                                 int *out_status,
+                                                 struct PortablePtyBuffer *out_output);
+
+/**
+ * Get the status the child's exit code was taken from, for callers that
+ * decode it themselves.
+ *
+ * The wait functions report a child killed by a signal as 128 plus the
+ * signal, and a Windows exit code that doesn't fit a C int as -1. This is
+ * what they started from: on Unix the `waitpid` status word, to take
+ * apart with `WIFSIGNALED`, `WCOREDUMP` and the rest; on Windows the whole
+ * exit code, as unsigned bits.
+ *
+ * - `out_raw`: receives the status.
+ *
+ * Returns `ErrWait` until a wait has seen the child exit, and
+ * `ErrUnsupported` if there's no status to give: the child was reaped
+ * elsewhere without its status being seen, or isn't a local process.
+ */
+enum PortablePtyResult portable_pty_raw_status(const struct PortablePty *handle, int *out_raw);
+
 /**
  * Kill the child process.
  *
  * On POSIX, `signal` is the signal number (e.g. 15 for SIGTERM).
- * On Windows, `signal` is ignored — the process is terminated.
+ * On Windows, `signal` is ignored — the process is terminated with exit
+ * code 1 (`portable_pty_kill_with_exit_code` chooses another).
  *
  * If the child has already exited (or been reaped), returns `Ok` rather
  * than failing.
  */
 enum PortablePtyResult portable_pty_kill(struct PortablePty *handle, int signal);
 
+/**
+ * Kill the child with `TerminateProcess`, making `exit_code` its exit
+ * code, which the wait functions then report. A supervisor can pick a
+ * code of its own to tell a child it killed from one that crashed;
+ * `portable_pty_kill` always uses 1.
+ *
+ * Windows only: a child killed by a signal on Unix already reports the
+ * signal. Returns `ErrUnsupported` elsewhere and for children that aren't
+ * local processes, and `Ok` if the child has already exited, whose exit
+ * code then stays as it was.
+ */
+enum PortablePtyResult portable_pty_kill_with_exit_code(struct PortablePty *handle,
+                                                        uint32_t exit_code);
+
 /**
  * Return the master process group ID (POSIX) or -1 when unsupported.
  */
@@ -141,9 +889,1588 @@ enum PortablePtyResult portable_pty_get_mode(struct PortablePty *handle,
 /**
  * Close the PTY and free all resources.
  *
- * Kills the child process if still running. Safe to call with NULL.
+ * Kills the child process if still running. Safe to call with NULL or
+ * with a handle that is already closed.
  * Handles the case where the child was already reaped by the Dart VM.
+ *
+ * On Windows this waits (up to five seconds) for the console's last
+ * output to be read off and the console to shut down; see
+ * `portable_pty_close_async` to do that without blocking.
  */
 void portable_pty_close(struct PortablePty *handle);
 
+/**
+ * Get the session's cumulative I/O.
+ *
+ * - `out_stats`: receives the counts. The storage counts are -1 when
+ *   there's no child, it has been reaped, or the system doesn't say.
+ */
+enum PortablePtyResult portable_pty_io_stats(const struct PortablePty *handle,
+                                             struct PortablePtyIoStats *out_stats);
+
+/**
+ * Register (or with a NULL `callback`, remove) the spawn audit hook.
+ *
+ * The hook applies to every handle, from the next spawn on, and is
+ * called before each child starts; returning false refuses it.
+ */
+enum PortablePtyResult portable_pty_set_spawn_audit(bool (*callback)(void*,
+                                                                     const struct PortablePtySpawnInfo*),
+                                                    void *userdata);
+
+/**
+ * Open a handle with the backend called `name`.
+ *
+ * - `name`: null-terminated backend name: one registered with
+ *   `portable_pty_register_backend`, or a built-in one. Those are
+ *   `native`, `loopback`, `mock`, `replay`, `ssh`, `persistent`,
+ *   `device`, `winpty` and `piped`.
+ * - `config`: null-terminated UTF-8 JSON object, or NULL for `{}`. Most
+ *   backends take `rows` and `cols`, defaulting to 24 and 80; the rest
+ *   is what the backend's own entry point takes (`portable_pty_open_mock`
+ *   for `mock`, `path` for `replay` and so on), and `native` takes
+ *   `conpty_flags` to open with `portable_pty_open_conpty` on Windows.
+ * - `out`: receives the new handle; close it with `portable_pty_close`.
+ *
+ * Returns `ErrBackend` if no backend has that name and `ErrOpen` if the
+ * config is malformed. Otherwise the result is what the backend's own
+ * entry point would return: `ErrUnsupported` for a backend left out of
+ * this build, `ErrAuth` from SSH, and so on.
+ */
+enum PortablePtyResult portable_pty_open_backend(const char *name,
+                                                 const char *config,
+                                                 struct PortablePty **out);
+
+/**
+ * Register a backend implemented by the embedder under `name`.
+ *
+ * - `name`: null-terminated name to pass to `portable_pty_open_backend`.
+ * - `backend`: its callbacks, copied; see [`PortablePtyBackend`].
+ *
+ * Replaces a backend registered under the same name before, and takes
+ * precedence over a built-in one. Handles already open keep the callbacks
+ * they were opened with. Returns `ErrNull` if a required callback is
+ * missing and `ErrBackend` if the name is empty or not UTF-8.
+ */
+enum PortablePtyResult portable_pty_register_backend(const char *name,
+                                                     const struct PortablePtyBackend *backend);
+
+/**
+ * Remove a backend added with `portable_pty_register_backend`.
+ *
+ * A built-in backend of the same name is used again afterwards. Handles
+ * it opened stay usable. Returns false if nothing was registered under
+ * `name`.
+ */
+bool portable_pty_unregister_backend(const char *name);
+
+/**
+ * Write `len` bytes from `buf` to each of `count` handles.
+ *
+ * - `handles`: array of `count` handles, none of them NULL. The same
+ *   handle twice gets the bytes twice.
+ * - `buf`, `len`: the bytes; nothing is written if `len` is 0.
+ *
+ * Returns `ErrNull` without writing anything if `handles`, `buf` or any
+ * handle is NULL, and `ErrWrite` if any handle failed to take all of the
+ * bytes; the others are written regardless.
+ */
+enum PortablePtyResult portable_pty_broadcast_write(const struct PortablePty *const *handles,
+                                                    uintptr_t count,
+                                                    const uint8_t *buf,
+                                                    uintptr_t len);
+
+/**
+ * The `PORTABLE_PTY_CAP_*` flags supported by this build.
+ */
+uint32_t portable_pty_capabilities(void);
+
+/**
+ * Send the child clipboard contents as an OSC 52 sequence.
+ *
+ * - `selection`: NUL-terminated selection names, as in the child's query:
+ *   `c` for the clipboard, `p` for the primary selection, `s`, `q` or
+ *   `0` to `7`; NULL for `c`.
+ * - `data`, `len`: the contents, up to `PORTABLE_PTY_CLIPBOARD_MAX` bytes.
+ *
+ * Returns `ErrSize` for more, and `ErrUnsupported` for a selection name
+ * OSC 52 doesn't have.
+ */
+enum PortablePtyResult portable_pty_send_clipboard(const struct PortablePty *handle,
+                                                   const char *selection,
+                                                   const uint8_t *data,
+                                                   uintptr_t len);
+
+/**
+ * Start a command running `program`.
+ *
+ * - `program`: NUL-terminated executable path; also the command's first
+ *   argument.
+ * - `out`: receives the command, to free with `portable_pty_cmd_free`.
+ *
+ * Returns `ErrSpawn` if `program` can't be a program name here.
+ */
+enum PortablePtyResult portable_pty_cmd_new(const char *program, struct PortablePtyCmd **out);
+
+/**
+ * Append an argument.
+ */
+enum PortablePtyResult portable_pty_cmd_arg(struct PortablePtyCmd *cmd, const char *arg);
+
+/**
+ * Set an environment variable, replacing any it had.
+ */
+enum PortablePtyResult portable_pty_cmd_env(struct PortablePtyCmd *cmd,
+                                            const char *key,
+                                            const char *value);
+
+/**
+ * Leave an environment variable out, inherited or not.
+ */
+enum PortablePtyResult portable_pty_cmd_env_remove(struct PortablePtyCmd *cmd, const char *key);
+
+/**
+ * Drop every environment variable, inherited or set so far.
+ */
+enum PortablePtyResult portable_pty_cmd_env_clear(struct PortablePtyCmd *cmd);
+
+/**
+ * Set the directory the command starts in.
+ */
+enum PortablePtyResult portable_pty_cmd_cwd(struct PortablePtyCmd *cmd, const char *dir);
+
+/**
+ * Give the command the options of a spawn config, replacing any given
+ * before.
+ *
+ * - `config`: null-terminated UTF-8 JSON object of options, as for
+ *   `portable_pty_spawn_config`, or NULL for none.
+ *
+ * Returns `ErrSpawn` if the config is malformed and `ErrUnsupported` if
+ * it asks for what this platform can't do; the command keeps the options
+ * it had.
+ */
+enum PortablePtyResult portable_pty_cmd_config(struct PortablePtyCmd *cmd, const char *config);
+
+/**
+ * Free a command. `cmd` may be NULL.
+ */
+void portable_pty_cmd_free(struct PortablePtyCmd *cmd);
+
+/**
+ * Spawn a command attached to the PTY, as `portable_pty_spawn` does, or
+ * `portable_pty_spawn_config` with its config.
+ *
+ * The command is left as it is, and still the caller's to free.
+ */
+enum PortablePtyResult portable_pty_spawn_cmd(struct PortablePty *handle,
+                                              const struct PortablePtyCmd *cmd);
+
+/**
+ * Hold auto-flushed writes back for up to `delay_ms`, to send bursts of
+ * them in one go: the first write held starts the delay, and the writes
+ * made before it's up are flushed with it. With auto-flush off, writes
+ * still wait for `portable_pty_flush`.
+ *
+ * - `delay_ms`: how long the first write held waits; 0 to stop
+ *   coalescing, flushing anything held.
+ * - `max_bytes`: how much may be held before it's flushed regardless; 0
+ *   for as much as the write buffer holds.
+ *
+ * A few milliseconds is enough to merge key repeat without it being felt.
+ */
+enum PortablePtyResult portable_pty_set_write_coalescing(const struct PortablePty *handle,
+                                                         uint32_t delay_ms,
+                                                         uintptr_t max_bytes);
+
+/**
+ * Queue a shell command to run after the currently running one finishes.
+ *
+ * `command` is written followed by a carriage return once the shell is at
+ * a prompt. The assigned ID is written to `*out_id` and reported in the
+ * command's `PORTABLE_PTY_EVENT_COMMAND_DONE` event (`value` = exit status
+ * or -1 if unknown, `data` = captured output).
+ *
+ * Sequencing is driven by output read from the handle, so the embedder
+ * must keep reading. Requires a shell that emits OSC 133 prompt marks.
+ */
+enum PortablePtyResult portable_pty_queue_command(const struct PortablePty *handle,
+                                                  const char *command,
+                                                  uint64_t *out_id);
+
+/**
+ * Drop all queued commands that haven't started yet. A command that is
+ * already running is unaffected.
+ */
+enum PortablePtyResult portable_pty_clear_command_queue(const struct PortablePty *handle);
+
+/**
+ * Open a PTY backed by a pseudo console created with `flags`.
+ *
+ * - `flags`: `PORTABLE_PTY_CONPTY_*` bits, or others the system knows.
+ *   `PORTABLE_PTY_CONPTY_DEFAULT` gives what `portable_pty_open` does.
+ * - `out`: receives the new handle; close it with `portable_pty_close`.
+ *
+ * Returns `ErrUnsupported` off Windows and on Windows without ConPTY,
+ * and `ErrOpen` if the system rejects the flags.
+ */
+enum PortablePtyResult portable_pty_open_conpty(uint16_t rows,
+                                                uint16_t cols,
+                                                uint32_t flags,
+                                                struct PortablePty **out);
+
+/**
+ * Choose which of ConPTY's own sequences to remove from the output.
+ *
+ * - `flags`: `PORTABLE_PTY_CONPTY_FILTER_*` bits; 0 turns filtering off.
+ *
+ * Takes effect from the next read. Output already filtered, or held back
+ * as an incomplete sequence, is still delivered in order.
+ */
+enum PortablePtyResult portable_pty_set_conpty_filter(struct PortablePty *handle, uint32_t flags);
+
+/**
+ * Start listening for control connections on a Unix-domain socket.
+ *
+ * - `path`: null-terminated path of the socket to create.
+ *
+ * Returns `ErrOpen` if the socket is already running or `path` can't be
+ * bound (including when a file already exists there), and
+ * `ErrUnsupported` on Windows.
+ */
+enum PortablePtyResult portable_pty_control_start(const char *path);
+
+/**
+ * Stop the control socket, disconnecting every client and removing the
+ * socket file. Harmless if it isn't running.
+ */
+enum PortablePtyResult portable_pty_control_stop(void);
+
+/**
+ * Make a session available on the control socket under `name`.
+ *
+ * - `name`: null-terminated UTF-8 name clients attach by; no newlines.
+ *
+ * Sessions can be published whether or not the socket is running. A
+ * closed handle is unpublished automatically. Returns `ErrOpen` if the
+ * handle is already published or the name is taken or invalid.
+ */
+enum PortablePtyResult portable_pty_control_publish(const struct PortablePty *handle,
+                                                    const char *name);
+
+/**
+ * Withdraw a session from the control socket. Attached clients get
+ * `CLOSED`. Harmless if it isn't published.
+ */
+enum PortablePtyResult portable_pty_control_unpublish(const struct PortablePty *handle);
+
+/**
+ * Set up posting to Dart ports, once per process, before any
+ * `portable_pty_attach_dart_port`. The library doesn't link against the
+ * Dart VM; it finds `Dart_PostCObject` through the API this is given.
+ *
+ * - `data`: what `NativeApi.initializeApiDLData` returns.
+ *
+ * Returns `ErrUnsupported` if `data` isn't a version of the API this
+ * library knows, or without the `dart-api` feature.
+ */
+enum PortablePtyResult portable_pty_dart_init(void *data);
+
+/**
+ * Post the handle's output to a Dart port as a background thread reads
+ * it, or with `port` 0 (`ILLEGAL_PORT`), stop.
+ *
+ * - `port`: the native port of a `ReceivePort`'s `SendPort`. It gets each
+ *   chunk of output as a `Uint8List` over memory freed when Dart collects
+ *   it, then once output ends the child's exit code as an `int`, or
+ *   `null` if that isn't known by then, and nothing more.
+ *
+ * Takes the place of a data callback (see `pump`), and nothing else may
+ * read the handle meanwhile. Returns `ErrUnsupported` before
+ * `portable_pty_dart_init`, where the handle can't be polled, or without
+ * the `dart-api` feature.
+ */
+enum PortablePtyResult portable_pty_attach_dart_port(const struct PortablePty *handle,
+                                                     int64_t port);
+
+/**
+ * Wrap an existing terminal device in a handle.
+ *
+ * - `path`: null-terminated path of the device, e.g. `/dev/ttyUSB0`.
+ * - `config`: null-terminated JSON object of settings to change, or NULL
+ *   to leave the device as it is: `raw` (`cfmakeraw`) and `echo`
+ *   booleans, `baud` (e.g. 115200), `data_bits` (5 to 8), `parity`
+ *   (`"none"`, `"even"` or `"odd"`), `stop_bits` (1 or 2), `flow`
+ *   (`"none"`, `"hardware"` or `"software"`), and `rows` and `cols` for
+ *   the size reported if the device doesn't know its own (24x80 by
+ *   default). Changing any setting also turns on `CLOCAL` and `CREAD`.
+ * - `out`: receives the new handle; close it with `portable_pty_close`.
+ *
+ * There's no child, so waiting, killing and spawning fail. Closing the
+ * handle puts the device's settings back as they were.
+ *
+ * Returns `ErrOpen` if the device can't be opened, isn't a terminal or
+ * the config is malformed (including an unsupported baud rate),
+ * `ErrMode` if the device refuses the settings, and `ErrUnsupported` on
+ * Windows or without the `serial` feature.
+ */
+enum PortablePtyResult portable_pty_open_device(const char *path,
+                                                const char *config,
+                                                struct PortablePty **out);
+
+/**
+ * Wrap the calling process's own stdin and stdout in a handle.
+ *
+ * For command-line apps that drive a full-screen UI through the same API
+ * as a PTY: reads come from stdin, writes go to stdout, and the size is
+ * the real terminal's. Nothing reports when the user resizes it; watch
+ * for `SIGWINCH` and ask `portable_pty_get_size` again.
+ *
+ * - `config`: null-terminated JSON object of settings for stdin, as for
+ *   `portable_pty_open_device`, or NULL for `{"raw": true}`. Ignored if
+ *   stdin isn't a terminal.
+ * - `out`: receives the new handle; closing it restores the terminal.
+ *
+ * Returns `ErrOpen` if the config is malformed, `ErrMode` if the terminal
+ * refuses the settings, and `ErrUnsupported` on Windows or
+ * without the `serial` feature.
+ */
+enum PortablePtyResult portable_pty_open_stdio(const char *config, struct PortablePty **out);
+
+/**
+ * Read the environment the system has for the handle's child, or for
+ * the terminal's foreground process. That's what the process was started
+ * with: a shell's own `export`s don't show in it, but they do in the
+ * environment of the commands it runs, so ask about the foreground
+ * process to see those.
+ *
+ * - `foreground`: false for the child, true for the process group leader
+ *   in the foreground, which is the child itself while nothing else is.
+ * - `out_json`: receives a UTF-8 JSON object of the environment, to free
+ *   with `portable_pty_buffer_free`. Bytes that aren't UTF-8 are replaced
+ *   with U+FFFD.
+ *
+ * Returns `ErrWait` if there's no child or it has gone,
+ * `ErrProcessGroup` if there's no foreground process to ask about,
+ * `ErrDenied` if the system won't say (another user's process, say) and
+ * `ErrUnsupported` on systems it can't be read on.
+ */
+enum PortablePtyResult portable_pty_child_environ(const struct PortablePty *handle,
+                                                  bool foreground,
+                                                  struct PortablePtyBuffer *out_json);
+
+/**
+ * Find the current directory of the terminal's foreground process, or
+ * off Unix of the child. It follows every `cd`, so it's what a "new tab
+ * here" can open in when the shell doesn't report its directory.
+ *
+ * - `out_path`: receives the path, to free with `portable_pty_buffer_free`.
+ *   It's the system's bytes on Unix and UTF-8 on Windows, and isn't
+ *   NUL-terminated.
+ *
+ * Returns `ErrWait` if there's no child or it has gone,
+ * `ErrProcessGroup` if there's no foreground process to ask about,
+ * `ErrDenied` if the system won't say and `ErrUnsupported` on systems it
+ * can't be read on.
+ */
+enum PortablePtyResult portable_pty_child_cwd(const struct PortablePty *handle,
+                                              struct PortablePtyBuffer *out_path);
+
+/**
+ * Choose when reads on the handle reach end of file.
+ *
+ * - `policy`: `PORTABLE_PTY_EOF_CHILD_EXIT`, the default on Unix, to end
+ *   once the child has exited and its queued output has been read,
+ *   without waiting for descendants still running;
+ *   `PORTABLE_PTY_EOF_NEVER`, what other handles do, for reads never to
+ *   end; or `PORTABLE_PTY_EOF_ALL_EXIT` to end when the child and
+ *   everything it started have closed the terminal. That closes the
+ *   handle's slave, so nothing more can be spawned, and on Linux output
+ *   still queued at the end may be lost.
+ *
+ * Returns `ErrUnsupported` for an unknown policy, or one the handle can't
+ * follow: policies other than its own need a local PTY on Unix.
+ */
+enum PortablePtyResult portable_pty_set_eof_policy(struct PortablePty *handle, uint32_t policy);
+
+/**
+ * Query when reads on the handle reach end of file.
+ *
+ * - `out_policy`: receives one of the `PORTABLE_PTY_EOF_*` constants.
+ */
+enum PortablePtyResult portable_pty_eof_policy(const struct PortablePty *handle,
+                                               uint32_t *out_policy);
+
+/**
+ * Pop the oldest queued event into `*out_event`.
+ *
+ * Returns `true` if an event was written. The caller owns the event's
+ * `data` and must release it with `portable_pty_event_free`.
+ */
+bool portable_pty_next_event(const struct PortablePty *handle, struct PortablePtyEvent *out_event);
+
+/**
+ * Release the payload of an event returned by `portable_pty_next_event`.
+ * Safe to call with NULL.
+ */
+void portable_pty_event_free(struct PortablePtyEvent *event);
+
+/**
+ * Register (or with a NULL `callback`, remove) the event callback.
+ *
+ * While a callback is set, events are passed to it on the thread that
+ * produced them (e.g. the thread calling `portable_pty_read`) instead of
+ * being queued. Events already queued stay queued.
+ */
+enum PortablePtyResult portable_pty_set_event_callback(const struct PortablePty *handle,
+                                                       void (*callback)(void*,
+                                                                        const struct PortablePtyEvent*),
+                                                       void *userdata);
+
+/**
+ * Consume output until `pattern` matches or `timeout_ms` elapses.
+ *
+ * - `pattern`: null-terminated UTF-8 pattern.
+ * - `is_regex`: treat `pattern` as a regular expression (`regex` crate
+ *   syntax, matched against raw bytes) instead of a literal string.
+ * - `timeout_ms`: maximum time to wait; negative waits indefinitely.
+ * - `out_match`: receives the match and the output preceding it.
+ *
+ * Returns `Ok` on a match, `ErrTimeout` if the deadline passed, `ErrEof`
+ * if the child side closed first, and `ErrPattern` for an invalid pattern.
+ * Only the last 1 MiB of output is searched and kept, so a match must fit
+ * in it; older output is dropped, and counted in the match's `dropped`.
+ * On any failure the output read so far, or the last 1 MiB of it, stays
+ * buffered on the handle for the next `portable_pty_read` or
+ * `portable_pty_expect`.
+ *
+ * Timeouts rely on polling the master and are only honored on Unix.
+ */
+enum PortablePtyResult portable_pty_expect(struct PortablePty *handle,
+                                           const char *pattern,
+                                           bool is_regex,
+                                           int32_t timeout_ms,
+                                           struct PortablePtyExpectMatch *out_match);
+
+/**
+ * Free the buffers held by a `PortablePtyExpectMatch`. Safe to call with
+ * NULL or on an already-freed match.
+ */
+void portable_pty_expect_match_free(struct PortablePtyExpectMatch *m);
+
+/**
+ * Stop reading from the terminal until `portable_pty_resume_output`.
+ * Output waits in the terminal's own buffer meanwhile, and once that's
+ * full the child blocks writing. `portable_pty_read` blocks until reading
+ * restarts, even if the child exits, so resume before tearing the reader
+ * down; reads the library makes itself aren't held back.
+ */
+enum PortablePtyResult portable_pty_pause_output(const struct PortablePty *handle);
+
+/**
+ * Undo `portable_pty_pause_output`. Reading restarts unless the watermark
+ * still holds it back.
+ */
+enum PortablePtyResult portable_pty_resume_output(const struct PortablePty *handle);
+
+/**
+ * Stop reading while `bytes` of output or more are unacknowledged.
+ * Output `portable_pty_read` hands out counts until it's acknowledged
+ * with `portable_pty_output_consumed`; reading stops, as with
+ * `portable_pty_pause_output`, when the count reaches the watermark, and
+ * restarts once it's down to half.
+ *
+ * - `bytes`: the watermark, or 0 to stop counting. The count starts
+ *   afresh at 0 whenever the watermark is set.
+ */
+enum PortablePtyResult portable_pty_set_output_watermark(const struct PortablePty *handle,
+                                                         uintptr_t bytes);
+
+/**
+ * Acknowledge `bytes` of output read earlier as dealt with, against the
+ * watermark. Acknowledging more than is outstanding is harmless.
+ */
+enum PortablePtyResult portable_pty_output_consumed(const struct PortablePty *handle,
+                                                    uintptr_t bytes);
+
+/**
+ * Kill the child and what it started. On Unix the signal goes to the
+ * child's process group, which what it starts stays in unless moved out,
+ * and to the terminal's foreground group; a background job in a group of
+ * its own isn't reached. On Windows the child's job object, which what
+ * it starts joins, is terminated.
+ *
+ * - `signal`: as for `portable_pty_kill`. Ignored on Windows, where they
+ *   are all terminated with exit code 1.
+ *
+ * Unlike `portable_pty_kill` this goes ahead once the child has exited,
+ * for what it left running. On Unix, once the child has been reaped its
+ * process group ID may belong to someone else, so only the terminal's
+ * foreground group is signalled then, if it has one. Returns `Ok` if
+ * there's nothing left to kill, and `ErrUnsupported` on Windows if the
+ * child couldn't be put in a job.
+ */
+enum PortablePtyResult portable_pty_kill_group(struct PortablePty *handle, int signal);
+
+/**
+ * Post a `PORTABLE_PTY_EVENT_IDLE` event whenever no output has been
+ * read for `idle_ms`, once per quiet spell; the next output starts
+ * another. Output counts when it's read off the handle, so the embedder
+ * has to be reading.
+ *
+ * - `idle_ms`: how long output must stop for; 0 to stop watching.
+ *
+ * The first quiet spell is timed from this call. Replaces any timeout
+ * already set on the handle.
+ */
+enum PortablePtyResult portable_pty_set_idle_timeout(const struct PortablePty *handle,
+                                                     uint32_t idle_ms);
+
+/**
+ * Post a `PORTABLE_PTY_EVENT_ACTIVITY` event when output is read after
+ * none has been for at least `quiet_ms`: once per quiet spell, with the
+ * first output that ends it.
+ *
+ * - `quiet_ms`: how long output must have stopped for; 0 to stop
+ *   reporting.
+ *
+ * A quiet spell already under way when this is called is timed from the
+ * last output read, or from the call if there's been none since the
+ * handle stopped timing it.
+ */
+enum PortablePtyResult portable_pty_set_activity_threshold(const struct PortablePty *handle,
+                                                           uint32_t quiet_ms);
+
+/**
+ * Send the terminal's interrupt character, Ctrl-C unless remapped.
+ *
+ * Returns `ErrUnsupported` if the terminal has none set, and `ErrMode` if
+ * its settings can't be read. On a piped handle, returns `ErrKill` if the
+ * child can't be signalled and `ErrUnsupported` off Unix or before a
+ * spawn.
+ */
+enum PortablePtyResult portable_pty_send_interrupt(const struct PortablePty *handle);
+
+/**
+ * Send the terminal's end-of-file character, Ctrl-D unless remapped.
+ *
+ * Returns as `portable_pty_send_interrupt` does.
+ */
+enum PortablePtyResult portable_pty_send_eof(const struct PortablePty *handle);
+
+/**
+ * Send the terminal's suspend character, Ctrl-Z unless remapped.
+ *
+ * Returns as `portable_pty_send_interrupt` does.
+ */
+enum PortablePtyResult portable_pty_send_suspend(const struct PortablePty *handle);
+
+/**
+ * Send the control character for Ctrl and `key`.
+ *
+ * - `key`: NUL-terminated key name: a letter, in either case, or one of
+ *   `@`, `[`, `\`, `]`, `^`, `_` and `?` (DEL). So `"C"` sends 0x03,
+ *   `"\\"` 0x1c and `"["` escape.
+ *
+ * The character is sent as it is; it interrupts the child only if it's
+ * the terminal's interrupt character (see `portable_pty_send_interrupt`
+ * to send whichever that is). ConPTY turns a Ctrl-C into `CTRL_C_EVENT`
+ * for console programs that haven't turned processed input off, as a
+ * keypress in a console window would be.
+ *
+ * Returns `ErrUnsupported` for any other key name.
+ */
+enum PortablePtyResult portable_pty_send_control(const struct PortablePty *handle, const char *key);
+
+/**
+ * Query whether the child is running, stopped or has exited.
+ *
+ * - `out_state`: receives one of the `PORTABLE_PTY_CHILD_*` constants.
+ *
+ * Returns `ErrWait` if nothing has been spawned.
+ */
+enum PortablePtyResult portable_pty_child_state(struct PortablePty *handle, uint32_t *out_state);
+
+/**
+ * Encode a key press using the child's current cursor-key and keypad
+ * modes.
+ *
+ * - `key`: one of the `PORTABLE_PTY_KEY_*` constants.
+ * - `modifiers`: bitwise OR of `PORTABLE_PTY_MOD_*`.
+ *
+ * Writes the sequence to `out_buf` and returns its length, or -1 on error
+ * (an unknown key, or an `out_buf` too small for the sequence; 8 bytes is
+ * always enough). Modes are tracked from output returned by
+ * `portable_pty_read`.
+ */
+int64_t portable_pty_encode_key(const struct PortablePty *handle,
+                                uint32_t key,
+                                uint32_t modifiers,
+                                uint8_t *out_buf,
+                                uintptr_t out_len);
+
+/**
+ * Send a key press to the child, encoded as `portable_pty_encode_key`
+ * does.
+ *
+ * Returns `ErrUnsupported` for an unknown key.
+ */
+enum PortablePtyResult portable_pty_send_key(const struct PortablePty *handle,
+                                             uint32_t key,
+                                             uint32_t modifiers);
+
+/**
+ * Close the PTY on a background thread, reporting when it's done.
+ *
+ * The handle is gone as soon as this returns and must not be used again.
+ * Killing and reaping the child and, on Windows, letting the console
+ * flush its last output and shut down (see `portable_pty_close`) happen
+ * afterwards. `portable_pty_deinit` waits for closes still in progress.
+ *
+ * - `callback`: called once teardown has fully completed, on the
+ *   background thread, with `userdata` and `Ok`, or `ErrTimeout` if the
+ *   console's output never ended (the handle is freed either way).
+ *
+ * Returns `ErrNull` if `handle` is NULL or not open, in which case the
+ * callback isn't called.
+ */
+enum PortablePtyResult portable_pty_close_async(struct PortablePty *handle,
+                                                PortablePtyCloseCallback callback,
+                                                void *userdata);
+
+/**
+ * Label the handle's background threads from now on. Their names are
+ * what they do plus the label, as in `portable-pty-idle-<label>`, so a
+ * profiler or crash dump says whose they are.
+ *
+ * - `label`: NUL-terminated label, such as a tab's title; NULL to go
+ *   back to the child's process ID.
+ *
+ * Threads already running keep their names. Some platforms cut names
+ * short (Linux at 15 bytes), so a short label is best.
+ */
+enum PortablePtyResult portable_pty_set_thread_label(const struct PortablePty *handle,
+                                                     const char *label);
+
+/**
+ * Cap the background threads running at once, across every handle.
+ *
+ * - `max_threads`: the cap; 0 for none, the default.
+ *
+ * Threads already running are left alone. Past the cap, whatever needs a
+ * new thread fails as it would if the thread couldn't be created, and
+ * `portable_pty_close_async` closes the handle before returning.
+ */
+enum PortablePtyResult portable_pty_set_max_threads(uint32_t max_threads);
+
+/**
+ * Set up the library's global state ahead of first use.
+ *
+ * Installs the `SIGCHLD` handler now rather than on the first spawn, so
+ * it's in place before the host starts children of its own. It reaps the
+ * library's children and caches their statuses before chaining to the
+ * handler it replaced. Calling it again is harmless.
+ */
+enum PortablePtyResult portable_pty_init(void);
+
+/**
+ * Tear down the library's global state.
+ *
+ * Closes every handle still open (killing their children), stops the
+ * control socket, restores the `SIGCHLD` handler that was in place before
+ * ours (unless someone has replaced ours since), and waits for background
+ * threads to finish.
+ * Pointers to the closed handles must not be used again.
+ *
+ * Returns `ErrTimeout` if some thread was still running after five
+ * seconds. The library can be used again afterwards; it sets itself up
+ * as on first use.
+ */
+enum PortablePtyResult portable_pty_deinit(void);
+
+/**
+ * Find the links on lines `first` to `first + count - 1` of the tracked
+ * screen. A `url` is an `http(s)`, `ftp`, `file`, `ssh`, `git` or `ws(s)`
+ * URL or a `mailto:` address; a `path` is `/abs`, `~/home`, `./rel`,
+ * `../rel` or `dir/file.ext`, optionally ending in `:line` or
+ * `:line:column`. Punctuation ending a sentence, and a closing bracket
+ * the link didn't open, are left out. A link wrapped onto the next line
+ * is found whole, with a range on each line it covers.
+ *
+ * - `first`, `count`: line numbers as `portable_pty_screen_lines` gives
+ *   them. Lines no longer kept are skipped.
+ * - `out_json`: receives a UTF-8 JSON array, in order, of objects with
+ *   `line`, the `start` and `end` columns of the link's part on that line
+ *   (end exclusive, wide characters taking two), its `kind` and its whole
+ *   `text`. Free with `portable_pty_buffer_free`.
+ *
+ * Returns `ErrUnsupported` if the screen isn't tracked.
+ */
+enum PortablePtyResult portable_pty_screen_links(const struct PortablePty *handle,
+                                                 uint64_t first,
+                                                 uint64_t count,
+                                                 struct PortablePtyBuffer *out_json);
+
+/**
+ * Open a loopback handle of `rows` x `cols`.
+ *
+ * The handle already has its stand-in child; `portable_pty_spawn` fails
+ * on it.
+ */
+enum PortablePtyResult portable_pty_open_loopback(uint16_t rows,
+                                                  uint16_t cols,
+                                                  struct PortablePty **out);
+
+/**
+ * Write `len` bytes from the child end, for the handle to read.
+ *
+ * Returns the number of bytes written, or -1 on error (including after
+ * the child has exited).
+ */
+int64_t portable_pty_loopback_write(const struct PortablePty *handle,
+                                    const uint8_t *buf,
+                                    uintptr_t len);
+
+/**
+ * Read what the handle has written, from the child end.
+ *
+ * Blocks until data is available. Returns the number of bytes read, or
+ * -1 on error.
+ */
+int64_t portable_pty_loopback_read(const struct PortablePty *handle, uint8_t *buf, uintptr_t len);
+
+/**
+ * Get the size as the child end sees it, i.e. after any
+ * `portable_pty_resize`.
+ */
+enum PortablePtyResult portable_pty_loopback_size(const struct PortablePty *handle,
+                                                  uint16_t *out_rows,
+                                                  uint16_t *out_cols);
+
+/**
+ * Make the stand-in child exit with `status`.
+ *
+ * The handle reads EOF once it has drained what was written, and
+ * `portable_pty_wait` reports `status`. Has no effect if the child has
+ * already exited.
+ */
+enum PortablePtyResult portable_pty_loopback_exit(const struct PortablePty *handle,
+                                                  uint32_t status);
+
+/**
+ * Register a persistent pattern on the handle.
+ *
+ * Every match in subsequently read output posts a
+ * `PORTABLE_PTY_EVENT_MATCH` event carrying the pattern ID. On success the
+ * new ID is written to `*out_id`.
+ */
+enum PortablePtyResult portable_pty_add_pattern(const struct PortablePty *handle,
+                                                const char *pattern,
+                                                bool is_regex,
+                                                uint64_t *out_id);
+
+/**
+ * Remove a pattern registered with `portable_pty_add_pattern`.
+ *
+ * Returns `ErrPattern` if no pattern has that ID.
+ */
+enum PortablePtyResult portable_pty_remove_pattern(const struct PortablePty *handle, uint64_t id);
+
+/**
+ * Take a snapshot of the whole library, across every open handle.
+ *
+ * - `out_json`: receives a UTF-8 JSON object, to free with
+ *   `portable_pty_buffer_free`: `handles` open; `children`, counting
+ *   their children `running`, `stopped` and `exited` (until the handle
+ *   is closed); `output_bytes` read and `input_bytes` written so far,
+ *   closed handles included; background `threads` running; and on Unix
+ *   `sigchld`, with `installed`, whether the library's handler is, and
+ *   `tracked`, how many children it's following, of `capacity` (null
+ *   elsewhere). Past `capacity`, a child's exit can be missed if the host
+ *   reaps it first.
+ */
+enum PortablePtyResult portable_pty_metrics_json(struct PortablePtyBuffer *out_json);
+
+/**
+ * Open a handle whose child follows a JSON script instead of being a
+ * real process.
+ *
+ * - `script`: null-terminated UTF-8 JSON: an object with optional `rows`
+ *   and `cols` and a `steps` array, run in order, each after an optional
+ *   `delay_ms`. `{"output": text}` gives the handle text to read.
+ *   `{"input": text, "timeout_ms": n}` waits until the handle has written
+ *   `text`, skipping anything written before it; on timeout the child
+ *   exits with status 124. `{"exit": status}` ends the script.
+ * - `out`: receives the new handle; close it with `portable_pty_close`.
+ *
+ * The script starts running immediately. Running out of steps exits with
+ * status 0, and killing the child with 137; reads then return EOF.
+ * Everything written is kept for `portable_pty_mock_take_input`. Returns
+ * `ErrOpen` if the script is malformed.
+ */
+enum PortablePtyResult portable_pty_open_mock(const char *script, struct PortablePty **out);
+
+/**
+ * Take everything written to a mock handle since the last call.
+ *
+ * - `out_input`: receives the bytes; free with `portable_pty_buffer_free`.
+ *
+ * Returns `ErrBackend` if the handle isn't a mock.
+ */
+enum PortablePtyResult portable_pty_mock_take_input(const struct PortablePty *handle,
+                                                    struct PortablePtyBuffer *out_input);
+
+/**
+ * Watch the child's resource use, posting `PORTABLE_PTY_EVENT_RESOURCE`
+ * events when it goes over a threshold.
+ *
+ * - `interval_ms`: time between samples; 0 for one second.
+ * - `max_rss_bytes`: resident memory threshold, or 0 for none.
+ * - `max_cpu_percent`: CPU threshold in percent of one core, or 0 for
+ *   none.
+ *
+ * Replaces any monitor already running on the handle. It stops by itself
+ * once the child is gone. Returns `ErrWait` if no child has been spawned
+ * and `ErrUnsupported` where the child can't be sampled.
+ */
+enum PortablePtyResult portable_pty_monitor(const struct PortablePty *handle,
+                                            uint32_t interval_ms,
+                                            uint64_t max_rss_bytes,
+                                            uint32_t max_cpu_percent);
+
+/**
+ * Stop watching the child's resource use. Harmless if the handle isn't
+ * being monitored.
+ */
+enum PortablePtyResult portable_pty_monitor_stop(const struct PortablePty *handle);
+
+/**
+ * Sample the child's resource use now.
+ *
+ * - `out_rss_bytes`: receives its resident memory; may be NULL.
+ * - `out_cpu_ms`: receives the CPU time it has used, user and system,
+ *   in milliseconds; may be NULL.
+ *
+ * Returns `ErrWait` if no child has been spawned or it has been reaped,
+ * and `ErrUnsupported` where the child can't be sampled.
+ */
+enum PortablePtyResult portable_pty_resource_usage(const struct PortablePty *handle,
+                                                   uint64_t *out_rss_bytes,
+                                                   uint64_t *out_cpu_ms);
+
+/**
+ * Encode a mouse event using the child's currently active mouse modes.
+ *
+ * - `button`: one of the `PORTABLE_PTY_MOUSE_*` button constants.
+ * - `x`, `y`: zero-based cell column and row.
+ * - `event_type`: `PORTABLE_PTY_MOUSE_PRESS`, `_RELEASE`, or `_MOTION`.
+ * - `modifiers`: bitwise OR of `PORTABLE_PTY_MOD_*`.
+ *
+ * Writes the sequence to `out_buf` and returns its length, `0` when the
+ * child isn't interested in this event, or -1 on error (including an
+ * `out_buf` too small for the sequence). Pass the bytes to
+ * `portable_pty_write` to deliver them. With alternate scroll on, a wheel
+ * press the child wouldn't get may encode to an arrow key instead (see
+ * `portable_pty_set_alternate_scroll`).
+ */
+int64_t portable_pty_encode_mouse(const struct PortablePty *handle,
+                                  uint32_t button,
+                                  uint32_t x,
+                                  uint32_t y,
+                                  uint32_t event_type,
+                                  uint32_t modifiers,
+                                  uint8_t *out_buf,
+                                  uintptr_t out_len);
+
+/**
+ * Report the mouse modes the child has requested.
+ *
+ * Writes one of the `PORTABLE_PTY_MOUSE_TRACKING_*` constants to
+ * `*out_tracking` and one of the `PORTABLE_PTY_MOUSE_ENCODING_*` constants
+ * to `*out_encoding`. Modes are tracked from output returned by
+ * `portable_pty_read`.
+ */
+enum PortablePtyResult portable_pty_mouse_mode(const struct PortablePty *handle,
+                                               uint32_t *out_tracking,
+                                               uint32_t *out_encoding);
+
+/**
+ * Choose whether the wheel scrolls a child on the alternate screen that
+ * isn't reporting the mouse, by having `portable_pty_encode_mouse` turn
+ * wheel presses into Up, Down, Left and Right keys. Off by default.
+ *
+ * Which screen the child is on and whether it reports the mouse are
+ * tracked from output returned by `portable_pty_read`; the arrows follow
+ * its cursor-key mode as `portable_pty_encode_key` does. On the normal
+ * screen the wheel is the embedder's to scroll its scrollback with.
+ */
+enum PortablePtyResult portable_pty_set_alternate_scroll(const struct PortablePty *handle,
+                                                         bool enabled);
+
+/**
+ * Read what output is available without waiting for more, so output can
+ * be polled for from a timer on the thread that draws it.
+ *
+ * Returns the number of bytes read, 0 if there's nothing to read yet or
+ * output is paused (see `flow`), `PORTABLE_PTY_READ_EOF` at end of file,
+ * or -1 on error. That includes handles that can't be polled — on Windows,
+ * and any but a local Unix PTY, piped or loopback handle — unless output
+ * the handle already holds is waiting.
+ */
+int64_t portable_pty_try_read(struct PortablePty *handle, uint8_t *buf, uintptr_t len);
+
+/**
+ * Read output as `portable_pty_read` does, waiting at most `timeout_ms`
+ * for some to come. The time is kept as long as this is the handle's
+ * only reader.
+ *
+ * - `timeout_ms`: the longest to wait, paused output included; negative
+ *   waits indefinitely, as `portable_pty_read` does.
+ *
+ * Returns the number of bytes read, 0 at end of file,
+ * `PORTABLE_PTY_READ_TIMEOUT` if there was nothing to read in time, or
+ * -1 on error. Waiting polls the master, so on Windows and on any handle
+ * but a local Unix PTY, piped or loopback one that's an error too, unless
+ * output the handle already holds is waiting.
+ */
+int64_t portable_pty_read_timeout(struct PortablePty *handle,
+                                  uint8_t *buf,
+                                  uintptr_t len,
+                                  int32_t timeout_ms);
+
+/**
+ * Wait until `portable_pty_read` has something to return, for callers
+ * that wait first and read after.
+ *
+ * - `timeout_ms`: the longest to wait; negative waits indefinitely.
+ *
+ * Returns `Ok` once output, or end of file, is there to read and isn't
+ * paused (see `flow`), `ErrTimeout` if `timeout_ms` elapsed first,
+ * `ErrUnsupported` where the handle can't be polled — on Windows, and
+ * any but a local Unix PTY, piped or loopback handle, unless it already
+ * holds output — and `ErrRead` if polling failed.
+ */
+enum PortablePtyResult portable_pty_wait_readable(const struct PortablePty *handle,
+                                                  int32_t timeout_ms);
+
+/**
+ * Have `portable_pty_write` take only what the child's input has room
+ * for, rather than wait for room for all of it, returning
+ * `PORTABLE_PTY_WRITE_WOULD_BLOCK` if nothing fits;
+ * `portable_pty_wait_writable` waits for room. Such writes go straight to
+ * the child, past any coalescing. Only a local Unix PTY fills up, so
+ * elsewhere writes are as they always are.
+ *
+ * - `enabled`: true for non-blocking writes, false to block again.
+ */
+enum PortablePtyResult portable_pty_set_nonblocking_writes(const struct PortablePty *handle,
+                                                           bool enabled);
+
+/**
+ * Make the master non-blocking, or blocking again, for an embedder
+ * watching `portable_pty_master_fd` from its own event loop. Reads then
+ * return `PORTABLE_PTY_READ_WOULD_BLOCK` when there's nothing to read,
+ * and writes are as after `portable_pty_set_nonblocking_writes`.
+ *
+ * - `enabled`: true to set `O_NONBLOCK`, false to clear it.
+ *
+ * Returns `ErrUnsupported` on Windows, where ConPTY's pipes can't be made
+ * non-blocking once open, and for backends without a file descriptor,
+ * and `ErrMode` if the flag couldn't be changed.
+ */
+enum PortablePtyResult portable_pty_set_nonblocking(const struct PortablePty *handle, bool enabled);
+
+/**
+ * Open a handle as `config` describes.
+ *
+ * - `config`: the options; see [`PortablePtyOpenConfig`].
+ * - `out`: receives the new handle; close it with `portable_pty_close`.
+ *
+ * Returns `ErrOpen` if `struct_size` is smaller than the first version of
+ * the struct or the backend config is malformed, `ErrUnsupported` for
+ * flags this build doesn't know or terminal modes asked of a handle
+ * without them (anything but a local Unix PTY), and `ErrMode` if the
+ * terminal refuses them. Otherwise as `portable_pty_open` or
+ * `portable_pty_open_backend`.
+ */
+enum PortablePtyResult portable_pty_open_ex(const struct PortablePtyOpenConfig *config,
+                                            struct PortablePty **out);
+
+/**
+ * Open a handle as `config` describes and spawn a child in it, in one
+ * call: the handle is only handed out once the child is running.
+ *
+ * - `config`: as for `portable_pty_open_ex`, or NULL for a 24x80 local
+ *   PTY.
+ * - `cmd`, `argv`, `envp`: as for `portable_pty_spawn`.
+ * - `out`: receives the new handle; close it with `portable_pty_close`.
+ *
+ * Returns what `portable_pty_open_ex` or `portable_pty_spawn` would; on
+ * any error nothing is left open and `*out` is untouched.
+ */
+enum PortablePtyResult portable_pty_launch(const struct PortablePtyOpenConfig *config,
+                                           const char *cmd,
+                                           const char *const *argv,
+                                           const char *const *envp,
+                                           struct PortablePty **out);
+
+/**
+ * Close a persistent session's handle, leaving the session running.
+ *
+ * The session can be reattached by opening the `persistent` backend with
+ * the same name. Returns `ErrBackend` if the handle isn't a persistent
+ * session; it stays open then.
+ */
+enum PortablePtyResult portable_pty_detach(struct PortablePty *handle);
+
+/**
+ * List the persistent sessions that are running.
+ *
+ * - `dir`: null-terminated socket directory, or NULL for the default
+ *   (the `dir` of the backend's config).
+ * - `out_names`: receives the names, one per line; free with
+ *   `portable_pty_buffer_free`. Empty if there are none.
+ *
+ * Returns `ErrUnsupported` on Windows.
+ */
+enum PortablePtyResult portable_pty_persistent_list(const char *dir,
+                                                    struct PortablePtyBuffer *out_names);
+
+/**
+ * Open a handle whose child runs on pipes instead of a terminal: what the
+ * handle writes is the child's stdin, and its stdout and stderr, merged,
+ * are what the handle reads. `portable_pty_send_eof` closes the child's
+ * stdin, and on Unix `portable_pty_send_interrupt` and
+ * `portable_pty_send_suspend` signal its process group. Reads end once
+ * everything holding the output pipe has closed it. One child per
+ * handle; spawn configs that need a terminal return `ErrUnsupported`.
+ *
+ * - `rows`, `cols`: the size `portable_pty_get_size` reports; the child
+ *   never sees it.
+ * - `out`: receives the new handle; spawn into it with
+ *   `portable_pty_spawn` and close it with `portable_pty_close`.
+ *
+ * Returns `ErrOpen` if the pipes can't be made.
+ */
+enum PortablePtyResult portable_pty_open_piped(uint16_t rows,
+                                               uint16_t cols,
+                                               struct PortablePty **out);
+
+/**
+ * Replace the arguments of the command being decided on. Only valid from
+ * within the policy callback, on the `rewrite` it was given.
+ *
+ * - `argv`: NULL-terminated arguments, `argv[0]` being the program to
+ *   run; at least one. Taken as `portable_pty_spawn` takes them, and
+ *   copied before this returns.
+ *
+ * Returns `ErrSpawn` if `argv` is empty or, off Unix, not UTF-8, leaving
+ * the command as it was.
+ */
+enum PortablePtyResult portable_pty_policy_rewrite(struct PortablePtyPolicyRewrite *rewrite,
+                                                   const char *const *argv);
+
+/**
+ * Register (or with a NULL `callback`, remove) the spawn policy.
+ *
+ * The policy applies to every handle, from the next spawn on, and is
+ * called before each child starts; returning false refuses it with
+ * `ErrPolicy`.
+ */
+enum PortablePtyResult portable_pty_set_spawn_policy(bool (*callback)(void*,
+                                                                      const char*,
+                                                                      const char*const *,
+                                                                      uintptr_t,
+                                                                      struct PortablePtyPolicyRewrite*),
+                                                     void *userdata);
+
+/**
+ * Register (or with a NULL `callback`, remove) a callback that gets the
+ * handle's output as a background thread reads it. Output is read as
+ * `portable_pty_read` reads it, tracked, recorded and held back by flow
+ * control, and nothing else may read the handle while this is set.
+ *
+ * - `callback`: called on the reader thread with `userdata` and each
+ *   chunk of output, valid only for the call; then once with a length of
+ *   0 at end of file, or if reading fails, after which the thread ends.
+ *   It may set or remove the callback, and close the handle.
+ *
+ * Replaces any callback already set. Removing it waits for the thread to
+ * end, so the callback isn't called afterwards unless that's being done
+ * from inside it. Returns `ErrUnsupported` where the handle can't be
+ * polled, on Windows and any but a local Unix PTY, piped or loopback
+ * handle, or the thread can't start.
+ */
+enum PortablePtyResult portable_pty_set_data_callback(const struct PortablePty *handle,
+                                                      void (*callback)(void*,
+                                                                       const uint8_t*,
+                                                                       uintptr_t),
+                                                      void *userdata);
+
+/**
+ * Start reading the handle on a background thread into a ring buffer,
+ * for `portable_pty_drain` to empty. The child never waits on a slow
+ * embedder: past the buffer's capacity the oldest output is dropped, and
+ * `portable_pty_pump_dropped` says how much.
+ *
+ * - `capacity`: the most output held undrained, in bytes; 0 for 1 MiB.
+ *
+ * Replaces any reader thread or data callback the handle has. Returns
+ * `ErrUnsupported` where the handle can't be polled, on Windows and any
+ * but a local Unix PTY, piped or loopback handle, or the thread can't
+ * start.
+ */
+enum PortablePtyResult portable_pty_pump_start(const struct PortablePty *handle,
+                                               uintptr_t capacity);
+
+/**
+ * Stop the handle's reader thread, whether it was started by
+ * `portable_pty_pump_start` or for a data callback, and wait for it to
+ * end. Output not yet drained is discarded. Harmless if there's none.
+ */
+enum PortablePtyResult portable_pty_pump_stop(const struct PortablePty *handle);
+
+/**
+ * Take output the reader thread started by `portable_pty_pump_start` has
+ * read, without waiting for more.
+ *
+ * Returns the number of bytes copied into `buf`, 0 if there are none
+ * yet, `PORTABLE_PTY_READ_EOF` once output has ended and everything has
+ * been drained, or -1 on error, including if the thread isn't running.
+ */
+int64_t portable_pty_drain(const struct PortablePty *handle, uint8_t *buf, uintptr_t len);
+
+/**
+ * Get how much output the reader thread started by
+ * `portable_pty_pump_start` has dropped for want of room, in bytes.
+ *
+ * Returns `ErrUnsupported` if the thread isn't running.
+ */
+enum PortablePtyResult portable_pty_pump_dropped(const struct PortablePty *handle,
+                                                 uint64_t *out_bytes);
+
+/**
+ * Ask the peer terminal for the cursor position.
+ *
+ * Writes `ESC[6n` and reads until a `ESC[row;colR` reply arrives or
+ * `timeout_ms` elapses (negative waits indefinitely). Other output read in
+ * the meantime, before or after the reply, stays buffered on the handle in
+ * order. `row` and `col` are 1-based.
+ *
+ * Returns `ErrTimeout` if no reply arrived, `ErrEof` if the peer closed
+ * first. Note that a modified F3 key (`ESC[1;<mod>R`) is indistinguishable
+ * from a reply.
+ *
+ * Timeouts rely on polling the master and are only honored on Unix.
+ */
+enum PortablePtyResult portable_pty_query_cursor_position(struct PortablePty *handle,
+                                                          int32_t timeout_ms,
+                                                          uint16_t *out_row,
+                                                          uint16_t *out_col);
+
+/**
+ * Start recording the session to `path`.
+ *
+ * - `format`: one of the `PORTABLE_PTY_RECORD_*` formats, optionally with
+ *   `PORTABLE_PTY_RECORD_ZSTD` set.
+ * - `record_input`: also record what is written to the child (ignored by
+ *   formats that only carry output).
+ *
+ * The file is created (or truncated) immediately. Returns `ErrRecord` if
+ * a recording is already running, the format is unknown, or the file
+ * can't be written, and `ErrUnsupported` in a build without the `recorder`
+ * feature or if compression was requested in a build without it.
+ */
+enum PortablePtyResult portable_pty_record_start(const struct PortablePty *handle,
+                                                 const char *path,
+                                                 uint32_t format,
+                                                 bool record_input);
+
+/**
+ * Stop the running recording and finalize the file.
+ *
+ * Returns `ErrRecord` if nothing was being recorded or if any part of the
+ * recording could not be written.
+ */
+enum PortablePtyResult portable_pty_record_stop(const struct PortablePty *handle);
+
+/**
+ * Open a recording for playback.
+ *
+ * The format (asciicast v2 or ttyrec) is detected from the file. Playback
+ * starts immediately at normal speed. Returns `ErrOpen` if the file can't
+ * be read and `ErrRecord` if it isn't a recording.
+ */
+enum PortablePtyResult portable_pty_open_replay(const char *path, struct PortablePty **out);
+
+/**
+ * Set the playback speed multiplier (1.0 = recorded pace).
+ *
+ * Returns `ErrReplay` if the handle isn't a replay or `speed` isn't a
+ * positive finite number.
+ */
+enum PortablePtyResult portable_pty_replay_set_speed(const struct PortablePty *handle,
+                                                     double speed);
+
+/**
+ * Pause or resume playback.
+ */
+enum PortablePtyResult portable_pty_replay_set_paused(const struct PortablePty *handle,
+                                                      bool paused);
+
+/**
+ * In instant mode the rest of the recording is emitted as fast as it is
+ * read, ignoring timing (pausing still applies).
+ */
+enum PortablePtyResult portable_pty_replay_set_instant(const struct PortablePty *handle,
+                                                       bool instant);
+
+/**
+ * Jump to `position_ms` into the recording.
+ *
+ * Output up to the target is emitted at once. Seeking backwards first
+ * emits a terminal reset (`ESC c`) and replays from the start, so the
+ * screen ends up as it was at the target. Has no effect once playback has
+ * ended.
+ */
+enum PortablePtyResult portable_pty_replay_seek(const struct PortablePty *handle,
+                                                uint64_t position_ms);
+
+/**
+ * Get the playback position and total length of the recording, in
+ * milliseconds.
+ */
+enum PortablePtyResult portable_pty_replay_position(const struct PortablePty *handle,
+                                                    uint64_t *out_position_ms,
+                                                    uint64_t *out_duration_ms);
+
+/**
+ * Render a recording to readable text.
+ *
+ * The recording is played instantly through a headless screen model, so
+ * progress bars, cursor movement and full-screen programs leave only what
+ * a user would have seen on the normal screen. Soft-wrapped lines are
+ * joined and trailing blanks trimmed.
+ *
+ * - `path`: recording in any format the replay engine reads.
+ * - `mode`: one of the `PORTABLE_PTY_TRANSCRIPT_*` constants.
+ * - `out_text`: receives UTF-8 text; release with
+ *   `portable_pty_buffer_free`.
+ *
+ * Returns `ErrOpen` if the file can't be read, `ErrRecord` if it isn't a
+ * recording or `mode` is unknown, and `ErrUnsupported` in builds without
+ * the `screen` feature.
+ */
+enum PortablePtyResult portable_pty_recording_to_text(const char *path,
+                                                      uint32_t mode,
+                                                      struct PortablePtyBuffer *out_text);
+
+/**
+ * Run a command in a fresh PTY, collect its output and wait for it to exit.
+ *
+ * - `cmd`, `argv`, `envp`: as for `portable_pty_spawn`.
+ * - `timeout_ms`: maximum run time; negative waits indefinitely.
+ * - `out_output`: receives everything the command wrote to the terminal.
+ *   Release with `portable_pty_buffer_free`.
+ * - `out_exit`: receives the exit code.
+ *
+ * Returns `ErrTimeout` if the command was still running at the deadline;
+ * it is killed, `*out_output` holds the output collected so far and
+ * `*out_exit` is left untouched. Spawn and open failures are returned as-is
+ * with nothing written.
+ *
+ * Timeouts rely on polling the master and are only honored on Unix.
+ */
+enum PortablePtyResult portable_pty_run(const char *cmd,
+                                        const char *const *argv,
+                                        const char *const *envp,
+                                        int32_t timeout_ms,
+                                        struct PortablePtyBuffer *out_output,
+                                        int *out_exit);
+
+/**
+ * Measure spawn latency and throughput on this machine, through the same
+ * calls an app makes. Takes around a second, longer on a slow device.
+ *
+ * - `out_json`: receives a UTF-8 JSON object, to free with
+ *   `portable_pty_buffer_free`: `spawn_ms`, the median of 5 opens and
+ *   spawns; `round_trip_ms`, the same through to seeing the child exit;
+ *   and `read_mib_per_s` and `write_mib_per_s`, moving 4 MiB each way,
+ *   null off Unix.
+ *
+ * Returns `ErrUnsupported` where local processes can't be run, and
+ * otherwise the error of whichever step failed. Spawns go through the
+ * spawn policy and audit hook, so a policy refusing them fails the test.
+ */
+enum PortablePtyResult portable_pty_selftest(struct PortablePtyBuffer *out_json);
+
+/**
+ * Share the session with clients connecting to `addr` over TCP.
+ *
+ * - `addr`: null-terminated `host:port` to listen on, such as
+ *   `"127.0.0.1:0"`. Port 0 picks a free port.
+ * - `flags`: `PORTABLE_PTY_SERVE_*` flags.
+ * - `out_port`: receives the port listened on; may be NULL.
+ *
+ * Clients see output as the handle reads it, so the embedder must keep
+ * reading. There is no authentication: anyone who can reach the address
+ * gets the terminal, so listen on loopback unless that is what's wanted.
+ * Returns `ErrOpen` if the handle is already being served or `addr` can't
+ * be listened on.
+ */
+enum PortablePtyResult portable_pty_serve(const struct PortablePty *handle,
+                                          const char *addr,
+                                          uint32_t flags,
+                                          uint16_t *out_port);
+
+/**
+ * Stop serving the handle, disconnecting every client. Harmless if it
+ * isn't being served.
+ */
+enum PortablePtyResult portable_pty_serve_stop(const struct PortablePty *handle);
+
+/**
+ * Spawn the user's shell attached to the PTY: on Unix `$SHELL` if it can
+ * be run, else the user's shell from the password database, else
+ * `/bin/sh`; on Windows `%ComSpec%`, else `cmd.exe`. It inherits this
+ * process's environment, with an unset, empty or `dumb` `TERM` replaced
+ * by `xterm-256color`.
+ *
+ * - `login`: true to start it as a login shell, with `-` before its name
+ *   in `argv[0]` so it reads the profile. Ignored on Windows.
+ */
+enum PortablePtyResult portable_pty_spawn_shell(struct PortablePty *handle, bool login);
+
+/**
+ * Spawn a child process attached to the PTY, with a config.
+ *
+ * - `cmd`, `argv`, `envp`: as for `portable_pty_spawn`.
+ * - `config`: null-terminated UTF-8 JSON object of options, or NULL for
+ *   `{}`. Every option is off unless given:
+ *   - `cwd`: directory to start the child in.
+ *   - `inherit_env`: `true` to lay `envp` over the environment the child
+ *     would inherit, rather than replace it.
+ *   - `unset_env`: names of variables to leave out.
+ *   - `scrub_env`: `true` to drop tokens, passwords, cloud credentials
+ *     and agent sockets the child would inherit, or an array of name
+ *     patterns such as `"*_TOKEN"` to drop.
+ *   - `argv0`: `argv[0]` to run the program under (Unix).
+ *   - `uid`, `gid`, `groups`: user, group and supplementary group IDs to
+ *     run it as (Unix). With `uid` the terminal is handed to that user;
+ *     without `gid` it gets the user's login group, and without `groups`
+ *     a child leaving root keeps no supplementary groups.
+ *   - `selinux_context`, `apparmor_profile`: a context or profile to exec
+ *     the child in (Linux).
+ *   - `sandbox_profile`, `sandbox_parameters`: an SBPL profile to run it
+ *     in and an object of strings for its `param`s (macOS).
+ *   - `cgroup`: cgroup directory for it and all it starts (Linux).
+ *   - `namespaces`: any of `pid`, `mount`, `network`, `ipc` and `uts`, to
+ *     start it in new ones of (Linux).
+ *   - `landlock_read`, `landlock_write`: paths it may only read and
+ *     execute, and paths it may also change; giving either confines it
+ *     to both (Linux).
+ *   - `seccomp_deny`: `true` to deny it calls for administering the
+ *     machine, or an array of system call names or numbers (Linux).
+ *   - `no_new_privs`: `true` to bar it gaining privileges (Linux), which
+ *     Landlock and `seccomp_deny` imply.
+ *   - `no_core_dumps`: `true` to keep its crashes from dumping core.
+ *
+ * Returns `ErrSpawn` if the config is malformed or a step it asks for
+ * fails in the child, and `ErrUnsupported` if the handle or platform
+ * can't honour it: most options need a local PTY.
+ */
+enum PortablePtyResult portable_pty_spawn_config(struct PortablePty *handle,
+                                                 const char *cmd,
+                                                 const char *const *argv,
+                                                 const char *const *envp,
+                                                 const char *config);
+
+extern int32_t sandbox_init_with_parameters(const char *profile,
+                                            uint64_t flags,
+                                            const char *const *parameters,
+                                            char **errorbuf);
+
+/**
+ * Describe the command the handle's last child was actually spawned
+ * with, after `scrub_env`, any spawn policy rewrite, Android's shell and
+ * home, and on a local Unix PTY the `PATH` lookup, the fallback to the
+ * home directory and `SHELL`. Elsewhere it's the command as handed over.
+ *
+ * - `out_json`: receives a UTF-8 JSON object, to free with
+ *   `portable_pty_buffer_free`: `program`, `argv`, `cwd` (null for this
+ *   process's) and `env`, an object of the child's whole environment.
+ *   Bytes that aren't UTF-8 are replaced with U+FFFD.
+ *
+ * Returns `ErrWait` if nothing has been spawned on the handle.
+ */
+enum PortablePtyResult portable_pty_spawned_command(const struct PortablePty *handle,
+                                                    struct PortablePtyBuffer *out_json);
+
+/**
+ * Connect to an SSH server and open a handle for a remote session.
+ *
+ * - `config`: null-terminated JSON object:
+ *   - `host` (required), `port` (default 22), `user` (default `$USER`).
+ *   - `password`, `identity_file`, `passphrase`: credentials. The SSH
+ *     agent is tried first, then the identity file, then the password.
+ *   - `known_hosts`: OpenSSH known-hosts file to check the host key
+ *     against (default `~/.ssh/known_hosts`).
+ *   - `accept_unknown_host`: connect to hosts that aren't in the file
+ *     (default false). A changed key is always refused.
+ *   - `timeout_ms`: limit for connecting and each setup step (default
+ *     10000).
+ * - `rows`, `cols`: initial size of the remote PTY.
+ * - `out`: receives the new handle.
+ *
+ * Nothing runs until `portable_pty_spawn`, whose command line is quoted
+ * and run by the remote user's shell. Environment entries are sent as
+ * `env` requests, which most servers only accept for `LANG`, `LC_*` and
+ * whatever their `AcceptEnv` allows; `TERM` selects the remote terminal
+ * type. The exit status is the remote command's, or 128 + the signal
+ * number if it was killed by a signal. `portable_pty_kill` closes the
+ * channel, which the server usually delivers as `SIGHUP`.
+ *
+ * Returns `ErrOpen` if the config is malformed or the host can't be
+ * reached, `ErrAuth` if the host key was refused or no credential was
+ * accepted, and `ErrUnsupported` in builds without the `ssh` feature.
+ */
+enum PortablePtyResult portable_pty_open_ssh(const char *config,
+                                             uint16_t rows,
+                                             uint16_t cols,
+                                             struct PortablePty **out);
+
+/**
+ * Read output into the `count` buffers at `iov`, filling each before the
+ * next, as `portable_pty_read` would into one buffer as large as them all
+ * (up to 1 MiB).
+ *
+ * Returns the number of bytes read, 0 at end of file, or -1 on error,
+ * including when the buffers have no room at all.
+ */
+int64_t portable_pty_readv(struct PortablePty *handle,
+                           const struct PortablePtyIoVec *iov,
+                           uintptr_t count);
+
+/**
+ * Write all of the `count` buffers at `iov` to the child, in order.
+ *
+ * Nothing else written to the handle lands between them, and they're
+ * flushed together as `portable_pty_write` would flush one write.
+ *
+ * Returns the number of bytes written, all of them, or -1 on error, in
+ * which case some of them may have been.
+ */
+int64_t portable_pty_writev(const struct PortablePty *handle,
+                            const struct PortablePtyIoVec *iov,
+                            uintptr_t count);
+
+/**
+ * Start tracking the screen, from a blank one of the handle's size.
+ * Starting again starts afresh.
+ *
+ * - `scrollback`: lines kept above the screen once they scroll off.
+ *
+ * Returns `ErrSize` if the handle's size can't be read, and
+ * `ErrUnsupported` without the `screen` feature.
+ */
+enum PortablePtyResult portable_pty_screen_start(const struct PortablePty *handle,
+                                                 uintptr_t scrollback);
+
+/**
+ * Stop tracking the screen and free it. Harmless if it isn't tracked.
+ */
+enum PortablePtyResult portable_pty_screen_stop(const struct PortablePty *handle);
+
+/**
+ * Get the numbers of the lines the tracked screen still keeps.
+ *
+ * - `out_first`: receives the number of the oldest line kept.
+ * - `out_count`: receives how many are kept, scrollback and screen.
+ *
+ * Returns `ErrUnsupported` if the screen isn't tracked.
+ */
+enum PortablePtyResult portable_pty_screen_lines(const struct PortablePty *handle,
+                                                 uint64_t *out_first,
+                                                 uint64_t *out_count);
+
+/**
+ * `portable_pty_spawn` taking UTF-16.
+ *
+ * - `cmd`: NUL-terminated executable path.
+ * - `argv`: NULL-terminated array of arguments, or NULL to use `cmd` as
+ *   the sole argument.
+ * - `envp`: NULL-terminated array of `"KEY=VALUE"` strings, or NULL to
+ *   inherit the current environment.
+ */
+enum PortablePtyResult portable_pty_spawn_w(struct PortablePty *handle,
+                                            const uint16_t *cmd,
+                                            const uint16_t *const *argv,
+                                            const uint16_t *const *envp);
+
+/**
+ * `portable_pty_record_start` taking a UTF-16 `path`.
+ */
+enum PortablePtyResult portable_pty_record_start_w(const struct PortablePty *handle,
+                                                   const uint16_t *path,
+                                                   uint32_t format,
+                                                   bool record_input);
+
+/**
+ * `portable_pty_open_replay` taking a UTF-16 `path`.
+ */
+enum PortablePtyResult portable_pty_open_replay_w(const uint16_t *path, struct PortablePty **out);
+
+/**
+ * `portable_pty_recording_to_text` taking a UTF-16 `path`.
+ */
+enum PortablePtyResult portable_pty_recording_to_text_w(const uint16_t *path,
+                                                        uint32_t mode,
+                                                        struct PortablePtyBuffer *out_text);
+
+/**
+ * Find what a double-click on a cell of the tracked screen selects.
+ * Wrapped lines count as one, and on whitespace every mode selects the
+ * run of it, bar an escaped or quoted space in an argument.
+ *
+ * - `line`: line number, as `portable_pty_screen_lines` gives them.
+ * - `col`: column of the cell; past the end of the line means its last.
+ * - `mode`: `PORTABLE_PTY_SELECT_WORD` for letters, digits and `_`, or a
+ *   run of one punctuation character; `PORTABLE_PTY_SELECT_PATH` for the
+ *   link there as `portable_pty_screen_links` finds it, else a word that
+ *   may hold `/.~-:@+%#?=&`; `PORTABLE_PTY_SELECT_ARGUMENT` for a shell
+ *   argument, up to unquoted whitespace, quotes and `\` escapes included.
+ * - `out_json`: receives a UTF-8 JSON object with the `start` and `end`
+ *   of the selection, each `[line, column]` with the end exclusive, and
+ *   its `text`. Free with `portable_pty_buffer_free`.
+ *
+ * Returns `ErrUnsupported` if the screen isn't tracked, no longer keeps
+ * `line`, or `mode` is unknown.
+ */
+enum PortablePtyResult portable_pty_screen_word_at(const struct PortablePty *handle,
+                                                   uint64_t line,
+                                                   uint16_t col,
+                                                   uint32_t mode,
+                                                   struct PortablePtyBuffer *out_json);
+
+/**
+ * Spawn a shell or command inside a WSL distribution.
+ *
+ * - `distro`: distribution name, or NULL for the default one.
+ * - `cwd`: starting directory, or NULL for the distribution's default.
+ *   Windows paths (`C:\Users\me`, `\\wsl$\Ubuntu\home\me`) are translated;
+ *   Linux paths and `~` are used as is.
+ * - `argv`: null-terminated command and arguments to run directly (no
+ *   shell involved), or NULL for the user's login shell.
+ * - `envp`: null-terminated `"KEY=VALUE"` entries to set inside the
+ *   distribution, or NULL. They're added to the inherited environment and
+ *   forwarded through `WSLENV`.
+ *
+ * Only available on Windows; elsewhere this returns `ErrUnsupported`.
+ */
+enum PortablePtyResult portable_pty_spawn_wsl(struct PortablePty *handle,
+                                              const char *distro,
+                                              const char *cwd,
+                                              const char *const *argv,
+                                              const char *const *envp);
+
 #endif  /* PORTABLE_PTY_H */
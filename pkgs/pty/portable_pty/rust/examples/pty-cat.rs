@@ -0,0 +1,155 @@
+//! A terminal session through the C API alone, for reproducing native-layer
+//! bugs without the Dart binding: `cargo run --example pty-cat [COMMAND
+//! [ARGS...]]`, running `$SHELL` by default.
+//!
+//! The command gets a PTY the size of this terminal. This terminal is put
+//! in raw mode, everything typed is written to the PTY and everything read
+//! from it is printed, and the PTY follows this terminal's size on
+//! `SIGWINCH`. When the child is done, `pty-cat` exits with its exit code.
+
+#[cfg(unix)]
+mod cat {
+    use portable_pty_rs::{
+        portable_pty_close, portable_pty_open, portable_pty_read, portable_pty_resize,
+        portable_pty_spawn, portable_pty_wait_blocking, portable_pty_write, PortablePty,
+        PortablePtyResult,
+    };
+    use std::ffi::{c_char, c_int, CString};
+    use std::io::{Read, Write};
+    use std::os::unix::ffi::OsStringExt;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+
+    static RESIZED: AtomicBool = AtomicBool::new(false);
+
+    extern "C" fn on_sigwinch(_: c_int) {
+        RESIZED.store(true, Ordering::Relaxed);
+    }
+
+    /// This terminal's rows and columns, or 24x80 if it isn't one.
+    fn terminal_size() -> (u16, u16) {
+        let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+        if unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ as _, &mut size) } != 0
+            || size.ws_row == 0
+        {
+            return (24, 80);
+        }
+        (size.ws_row, size.ws_col)
+    }
+
+    /// Puts stdin back as it was when dropped.
+    struct RawMode(libc::termios);
+
+    impl RawMode {
+        fn enable() -> Option<RawMode> {
+            let mut saved = std::mem::MaybeUninit::uninit();
+            if unsafe { libc::tcgetattr(libc::STDIN_FILENO, saved.as_mut_ptr()) } != 0 {
+                return None;
+            }
+            let saved = unsafe { saved.assume_init() };
+            let mut raw = saved;
+            unsafe {
+                libc::cfmakeraw(&mut raw);
+                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw);
+            }
+            Some(RawMode(saved))
+        }
+    }
+
+    impl Drop for RawMode {
+        fn drop(&mut self) {
+            unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.0) };
+        }
+    }
+
+    /// The command line to run: the arguments, or `$SHELL`.
+    fn command() -> Vec<CString> {
+        let mut args: Vec<_> = std::env::args_os().skip(1).collect();
+        if args.is_empty() {
+            args.push(std::env::var_os("SHELL").unwrap_or_else(|| "/bin/sh".into()));
+        }
+        args.into_iter()
+            .map(|arg| CString::new(arg.into_vec()).expect("argument contains NUL"))
+            .collect()
+    }
+
+    pub(crate) fn main() -> i32 {
+        let (rows, cols) = terminal_size();
+        let mut handle: *mut PortablePty = std::ptr::null_mut();
+        if !matches!(
+            portable_pty_open(rows, cols, &mut handle),
+            PortablePtyResult::Ok
+        ) {
+            eprintln!("pty-cat: can't open a PTY");
+            return 1;
+        }
+        let args = command();
+        let argv: Vec<*const c_char> = args
+            .iter()
+            .map(|arg| arg.as_ptr())
+            .chain([std::ptr::null()])
+            .collect();
+        let result = portable_pty_spawn(handle, args[0].as_ptr(), argv.as_ptr(), std::ptr::null());
+        if !matches!(result, PortablePtyResult::Ok) {
+            eprintln!("pty-cat: can't run {:?} ({})", args[0], result as u32);
+            portable_pty_close(handle);
+            return 1;
+        }
+        let raw_mode = RawMode::enable();
+
+        // The handle is shared by the threads below and outlives them: the
+        // process exits before it's closed.
+        let shared = handle as usize;
+        unsafe {
+            libc::signal(
+                libc::SIGWINCH,
+                on_sigwinch as *const () as libc::sighandler_t,
+            )
+        };
+        std::thread::spawn(move || loop {
+            std::thread::sleep(Duration::from_millis(50));
+            if RESIZED.swap(false, Ordering::Relaxed) {
+                let (rows, cols) = terminal_size();
+                portable_pty_resize(shared as *mut PortablePty, rows, cols);
+            }
+        });
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 4096];
+            let mut stdin = std::io::stdin().lock();
+            while let Ok(n @ 1..) = stdin.read(&mut buf) {
+                if portable_pty_write(shared as *mut PortablePty, buf.as_ptr(), n) < 0 {
+                    break;
+                }
+            }
+        });
+
+        let mut stdout = std::io::stdout().lock();
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = portable_pty_read(handle, buf.as_mut_ptr(), buf.len());
+            if n <= 0 {
+                break;
+            }
+            if stdout.write_all(&buf[..n as usize]).is_err() {
+                break;
+            }
+            let _ = stdout.flush();
+        }
+
+        let mut status = 1;
+        portable_pty_wait_blocking(handle, &mut status);
+        drop(raw_mode);
+        status
+    }
+}
+
+#[cfg(unix)]
+fn main() {
+    std::process::exit(cat::main());
+}
+
+#[cfg(not(unix))]
+fn main() {
+    eprintln!("pty-cat drives the terminal with termios and SIGWINCH, which are Unix only");
+    std::process::exit(2);
+}
@@ -0,0 +1,193 @@
+//! Per-session I/O accounting.
+//!
+//! Every handle counts the bytes its child has written to the terminal
+//! and the bytes written to the child, for quotas and usage reports.
+//! Output counts once it's read off the handle, by whichever call reads
+//! it (`portable_pty_read`, `expect`, `run`, …); input counts once it's
+//! written, whether by the embedder or by the library (queued commands,
+//! clients of `portable_pty_serve`). `portable_pty_io_stats` also samples
+//! the child's storage I/O where the system keeps track: `/proc/<pid>/io`
+//! on Linux and Android, `proc_pid_rusage` on macOS and iOS, and the
+//! process I/O counters on Windows, which count every transfer rather
+//! than only disk.
+
+use crate::{PortablePty, PortablePtyResult};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A handle's byte counts.
+#[derive(Default)]
+pub(crate) struct IoCounters {
+    output: AtomicU64,
+    input: AtomicU64,
+}
+
+/// Every handle's counts together, closed ones included.
+static TOTALS: IoCounters = IoCounters {
+    output: AtomicU64::new(0),
+    input: AtomicU64::new(0),
+};
+
+impl IoCounters {
+    pub(crate) fn add_output(&self, n: usize) {
+        self.output.fetch_add(n as u64, Ordering::Relaxed);
+        TOTALS.output.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_input(&self, n: usize) {
+        self.input.fetch_add(n as u64, Ordering::Relaxed);
+        TOTALS.input.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
+/// Bytes of output read and of input written on every handle so far.
+pub(crate) fn totals() -> (u64, u64) {
+    (
+        TOTALS.output.load(Ordering::Relaxed),
+        TOTALS.input.load(Ordering::Relaxed),
+    )
+}
+
+/// Cumulative I/O of a session, filled in by `portable_pty_io_stats`.
+#[repr(C)]
+pub struct PortablePtyIoStats {
+    /// Bytes of output read off the handle.
+    pub output_bytes: u64,
+    /// Bytes written to the child.
+    pub input_bytes: u64,
+    /// Bytes the child has read from storage, or -1 if unknown.
+    pub disk_read_bytes: i64,
+    /// Bytes the child has written to storage, or -1 if unknown.
+    pub disk_write_bytes: i64,
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn disk_io(pid: i32) -> io::Result<(u64, u64)> {
+    let io = std::fs::read_to_string(format!("/proc/{pid}/io"))?;
+    let field = |name: &str| -> io::Result<u64> {
+        io.lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(": "))
+            .and_then(|value| value.trim().parse().ok())
+            .ok_or(io::ErrorKind::InvalidData.into())
+    };
+    Ok((field("read_bytes")?, field("write_bytes")?))
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+fn disk_io(pid: i32) -> io::Result<(u64, u64)> {
+    let mut info: libc::rusage_info_v2 = unsafe { std::mem::zeroed() };
+    let buffer = &mut info as *mut _ as *mut libc::rusage_info_t;
+    if unsafe { libc::proc_pid_rusage(pid, libc::RUSAGE_INFO_V2, buffer) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((info.ri_diskio_bytesread, info.ri_diskio_byteswritten))
+}
+
+#[cfg(windows)]
+fn disk_io(pid: i32) -> io::Result<(u64, u64)> {
+    use std::os::windows::io::{AsRawHandle, FromRawHandle, OwnedHandle};
+    use winapi::um::processthreadsapi::OpenProcess;
+    use winapi::um::winbase::GetProcessIoCounters;
+    use winapi::um::winnt::{IO_COUNTERS, PROCESS_QUERY_LIMITED_INFORMATION};
+
+    let process = unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid as u32) };
+    if process.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    let process = unsafe { OwnedHandle::from_raw_handle(process as _) };
+    let mut counters: IO_COUNTERS = unsafe { std::mem::zeroed() };
+    if unsafe { GetProcessIoCounters(process.as_raw_handle() as _, &mut counters) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((counters.ReadTransferCount, counters.WriteTransferCount))
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    windows
+)))]
+fn disk_io(_pid: i32) -> io::Result<(u64, u64)> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Get the session's cumulative I/O.
+///
+/// - `out_stats`: receives the counts. The storage counts are -1 when
+///   there's no child, it has been reaped, or the system doesn't say.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_io_stats(
+    handle: *const PortablePty,
+    out_stats: *mut PortablePtyIoStats,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        if out_stats.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+
+        let disk = match pty.child_pid {
+            pid if pid > 0 => disk_io(pid).ok(),
+            _ => None,
+        };
+        let count = |n: u64| i64::try_from(n).unwrap_or(i64::MAX);
+        unsafe {
+            *out_stats = PortablePtyIoStats {
+                output_bytes: pty.io_counters.output.load(Ordering::Relaxed),
+                input_bytes: pty.io_counters.input.load(Ordering::Relaxed),
+                disk_read_bytes: disk.map_or(-1, |(read, _)| count(read)),
+                disk_write_bytes: disk.map_or(-1, |(_, written)| count(written)),
+            };
+        }
+        PortablePtyResult::Ok
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loopback::{
+        portable_pty_loopback_read, portable_pty_loopback_write, portable_pty_open_loopback,
+    };
+
+    #[test]
+    fn test_counts_bytes_both_ways() {
+        let mut handle = std::ptr::null_mut();
+        let result = portable_pty_open_loopback(24, 80, &mut handle);
+        assert!(matches!(result, PortablePtyResult::Ok));
+
+        let input = b"hello";
+        assert_eq!(
+            crate::portable_pty_write(handle, input.as_ptr(), input.len()),
+            5
+        );
+        let mut buf = [0u8; 5];
+        portable_pty_loopback_read(handle, buf.as_mut_ptr(), buf.len());
+        portable_pty_loopback_write(handle, b"out".as_ptr(), 3);
+        assert_eq!(crate::tests::read_string(handle), "out");
+
+        let mut stats: PortablePtyIoStats = unsafe { std::mem::zeroed() };
+        let result = portable_pty_io_stats(handle, &mut stats);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        assert_eq!((stats.output_bytes, stats.input_bytes), (3, 5));
+        // No child, so nothing to sample.
+        assert_eq!((stats.disk_read_bytes, stats.disk_write_bytes), (-1, -1));
+        crate::portable_pty_close(handle);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_samples_child_storage_io() {
+        let handle = crate::tests::open_and_spawn("sleep", &["sleep", "10"]);
+        let mut stats: PortablePtyIoStats = unsafe { std::mem::zeroed() };
+        let result = portable_pty_io_stats(handle, &mut stats);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        assert!(stats.disk_read_bytes >= 0 && stats.disk_write_bytes >= 0);
+        crate::portable_pty_close(handle);
+    }
+}
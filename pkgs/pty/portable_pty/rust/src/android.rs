@@ -0,0 +1,192 @@
+//! Android (Bionic) specifics.
+//!
+//! - `openpty` only exists from API level 23, while Flutter apps commonly
+//!   target 21. We define it ourselves from `posix_openpt` and friends,
+//!   which Bionic has had since 21, so the library links and runs on older
+//!   releases too. Where the system has its own, either one may be picked
+//!   up; they behave the same.
+//! - ART routes `sigaction` through libsigchain, which keeps its own
+//!   handlers in front and reports the app-level handler as the previous
+//!   one, so the `SIGCHLD` chaining in the crate root works unchanged.
+//! - App processes have no `/bin/sh` before Android 10, no usable `$SHELL`,
+//!   and a passwd home (`/data`) they can't read. [`prepare`] points
+//!   spawns at the system shell and somewhere they can start in.
+//! - Fresh PTYs come up with most line-discipline flags cleared; see
+//!   [`normalize_pty_termios`].
+
+use crate::pty::CommandBuilder;
+use std::ffi::OsStr;
+use std::path::Path;
+
+/// The shell every Android release ships.
+const SYSTEM_SHELL: &str = "/system/bin/sh";
+
+/// Directories that are only symlinks to `/system/bin` on newer releases.
+const LEGACY_BIN_DIRS: [&str; 2] = ["/bin/", "/usr/bin/"];
+
+fn is_executable(path: &OsStr) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::metadata(path).is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+/// Adjust a command for an app process before it's spawned.
+///
+/// Programs under `/bin` or `/usr/bin` that don't exist are looked up in
+/// `/system/bin`. `SHELL` falls back to the system shell. Without a `HOME`
+/// that is a directory, portable-pty would start the child in `/data`; use
+/// `TMPDIR` if set, else `/`. The embedder can pass the app's own storage
+/// as `HOME` to choose a better starting point.
+pub(crate) fn prepare(mut builder: CommandBuilder) -> CommandBuilder {
+    if let Some(program) = builder.get_argv().first().and_then(|p| p.to_str()) {
+        let name = LEGACY_BIN_DIRS
+            .iter()
+            .find_map(|dir| program.strip_prefix(dir));
+        if let Some(name) = name.filter(|_| !Path::new(program).exists()) {
+            let program = format!("/system/bin/{name}");
+            builder.get_argv_mut()[0] = program.into();
+        }
+    }
+
+    if !builder.get_env("SHELL").is_some_and(is_executable) {
+        builder.env("SHELL", SYSTEM_SHELL);
+    }
+
+    let is_dir = |dir: &&OsStr| Path::new(dir).is_dir();
+    if builder.get_env("HOME").filter(is_dir).is_none() {
+        let home = builder
+            .get_env("TMPDIR")
+            .filter(is_dir)
+            .map_or_else(|| "/".into(), OsStr::to_owned);
+        builder.env("HOME", home);
+    }
+    builder
+}
+
+/// Give a freshly opened PTY the usual cooked-mode flags (echo, canonical
+/// input, signals, CR/NL translation), which Bionic leaves cleared.
+#[cfg(target_os = "android")]
+pub(crate) fn normalize_pty_termios(master: &dyn crate::pty::MasterPty) {
+    use std::os::fd::AsRawFd;
+
+    let Some(tty_path) = master.tty_name() else {
+        return;
+    };
+
+    let Ok(tty) = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&tty_path)
+    else {
+        return;
+    };
+
+    let fd = tty.as_raw_fd();
+    let mut termios = std::mem::MaybeUninit::<libc::termios>::uninit();
+    if unsafe { libc::tcgetattr(fd, termios.as_mut_ptr()) } != 0 {
+        return;
+    }
+    let mut termios = unsafe { termios.assume_init() };
+
+    termios.c_iflag |= libc::BRKINT | libc::ICRNL | libc::IXON | libc::IUTF8;
+    termios.c_oflag |= libc::OPOST | libc::ONLCR;
+    termios.c_cflag |= libc::CREAD;
+    termios.c_lflag |=
+        libc::ECHO | libc::ECHOE | libc::ECHOK | libc::ICANON | libc::IEXTEN | libc::ISIG;
+    termios.c_cc[libc::VMIN] = 1;
+    termios.c_cc[libc::VTIME] = 0;
+
+    unsafe {
+        let _ = libc::tcsetattr(fd, libc::TCSANOW, &termios);
+    }
+}
+
+/// `openpty(3)` for API levels that lack it. Kept out of the generated
+/// header: it's libc's interface, not ours.
+///
+/// cbindgen:ignore
+#[cfg(target_os = "android")]
+#[unsafe(no_mangle)]
+unsafe extern "C" fn openpty(
+    amaster: *mut libc::c_int,
+    aslave: *mut libc::c_int,
+    name: *mut libc::c_char,
+    termp: *const libc::termios,
+    winp: *const libc::winsize,
+) -> libc::c_int {
+    crate::ffi::guard(|| {
+        let master = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC) };
+        if master < 0 {
+            return -1;
+        }
+        let fail = || {
+            let errno = std::io::Error::last_os_error();
+            unsafe { libc::close(master) };
+            if let Some(code) = errno.raw_os_error() {
+                unsafe { *libc::__errno() = code };
+            }
+            -1
+        };
+
+        let mut path = [0 as libc::c_char; 64];
+        if unsafe { libc::grantpt(master) } != 0
+            || unsafe { libc::unlockpt(master) } != 0
+            || unsafe { libc::ptsname_r(master, path.as_mut_ptr(), path.len()) } != 0
+        {
+            return fail();
+        }
+        let slave = unsafe { libc::open(path.as_ptr(), libc::O_RDWR | libc::O_NOCTTY) };
+        if slave < 0 {
+            return fail();
+        }
+
+        unsafe {
+            if !termp.is_null() {
+                libc::tcsetattr(slave, libc::TCSANOW, termp);
+            }
+            if !winp.is_null() {
+                libc::ioctl(slave, libc::TIOCSWINSZ, winp);
+            }
+            if !name.is_null() {
+                // openpty(3): the caller's buffer must be large enough.
+                libc::strcpy(name, path.as_ptr());
+            }
+            *amaster = master;
+            *aslave = slave;
+        }
+        0
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prepare_fills_in_app_defaults() {
+        let mut builder = CommandBuilder::new("/bin/definitely-not-installed");
+        builder.env_clear();
+        builder.env("HOME", "/definitely/not/a/dir");
+        builder.env("TMPDIR", "/tmp");
+        let builder = prepare(builder);
+
+        assert_eq!(
+            builder.get_argv()[0],
+            "/system/bin/definitely-not-installed"
+        );
+        assert_eq!(builder.get_env("SHELL").unwrap(), SYSTEM_SHELL);
+        assert_eq!(builder.get_env("HOME").unwrap(), "/tmp");
+    }
+
+    #[test]
+    fn test_prepare_keeps_what_works() {
+        let mut builder = CommandBuilder::new("/bin/sh");
+        builder.env_clear();
+        builder.env("SHELL", "/bin/sh");
+        builder.env("HOME", "/");
+        let builder = prepare(builder);
+
+        assert_eq!(builder.get_argv()[0], "/bin/sh");
+        assert_eq!(builder.get_env("SHELL").unwrap(), "/bin/sh");
+        assert_eq!(builder.get_env("HOME").unwrap(), "/");
+    }
+}
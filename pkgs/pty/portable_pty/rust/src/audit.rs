@@ -0,0 +1,166 @@
+//! A process-wide hook on every spawn, for audit logging and policy.
+//!
+//! `portable_pty_set_spawn_audit` registers a callback that sees each
+//! child before it starts — whichever entry point or backend spawned it —
+//! and may refuse it, in which case the spawn returns `ErrDenied` and
+//! nothing runs. The callback runs on the spawning thread with no library
+//! lock held; it must not use the handle it's told about, which is only
+//! there to tell sessions apart.
+
+use crate::pty::CommandBuilder;
+use crate::{PortablePty, PortablePtyResult};
+use std::ffi::{c_char, c_void, CString, OsStr};
+use std::sync::{Mutex, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What's about to be spawned. Only valid for the duration of the call.
+#[repr(C)]
+pub struct PortablePtySpawnInfo {
+    /// The session spawning it.
+    pub handle: *const PortablePty,
+    /// The program, as it will be looked up.
+    pub program: *const c_char,
+    /// `argc` arguments, `argv[0]` included, followed by NULL.
+    pub argv: *const *const c_char,
+    pub argc: usize,
+    /// The directory it will start in, or NULL for the caller's.
+    pub cwd: *const c_char,
+    /// The user it will run as, or -1 where there are no user IDs.
+    pub uid: i64,
+    /// When it was asked for, in milliseconds since the Unix epoch.
+    pub timestamp_ms: u64,
+}
+
+/// Spawn audit callback: `(userdata, info)`. Returns false to refuse the
+/// spawn.
+pub type PortablePtySpawnAuditCallback =
+    extern "C" fn(*mut c_void, *const PortablePtySpawnInfo) -> bool;
+
+struct Hook {
+    func: PortablePtySpawnAuditCallback,
+    userdata: *mut c_void,
+}
+
+// The userdata pointer is opaque to us; the embedder promises it may be
+// used from whichever thread spawns.
+unsafe impl Send for Hook {}
+
+static HOOK: Mutex<Option<Hook>> = Mutex::new(None);
+
+#[cfg(unix)]
+pub(crate) fn c_string(s: &OsStr) -> CString {
+    use std::os::unix::ffi::OsStrExt;
+    CString::new(s.as_bytes()).unwrap_or_default()
+}
+
+#[cfg(not(unix))]
+pub(crate) fn c_string(s: &OsStr) -> CString {
+    CString::new(s.to_string_lossy().into_owned()).unwrap_or_default()
+}
+
+/// Run `builder` past the hook, if one is registered.
+///
+/// Returns `ErrDenied` if the hook refuses it.
+pub(crate) fn check(pty: &PortablePty, builder: &CommandBuilder) -> PortablePtyResult {
+    // Copied out so the hook can re-register without deadlocking.
+    let hook = HOOK
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .as_ref()
+        .map(|hook| (hook.func, hook.userdata));
+    let Some((func, userdata)) = hook else {
+        return PortablePtyResult::Ok;
+    };
+
+    let args: Vec<CString> = builder.get_argv().iter().map(|a| c_string(a)).collect();
+    let mut argv: Vec<*const c_char> = args.iter().map(|a| a.as_ptr()).collect();
+    argv.push(std::ptr::null());
+    let cwd = builder.get_cwd().map(|cwd| c_string(cwd));
+    #[cfg(unix)]
+    let uid = unsafe { libc::geteuid() }.into();
+    #[cfg(not(unix))]
+    let uid = -1;
+    let timestamp_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |t| t.as_millis() as u64);
+
+    let info = PortablePtySpawnInfo {
+        handle: pty,
+        program: args.first().map_or(std::ptr::null(), |a| a.as_ptr()),
+        argv: argv.as_ptr(),
+        argc: args.len(),
+        cwd: cwd.as_ref().map_or(std::ptr::null(), |c| c.as_ptr()),
+        uid,
+        timestamp_ms,
+    };
+    if func(userdata, &info) {
+        PortablePtyResult::Ok
+    } else {
+        PortablePtyResult::ErrDenied
+    }
+}
+
+/// Register (or with a NULL `callback`, remove) the spawn audit hook.
+///
+/// The hook applies to every handle, from the next spawn on, and is
+/// called before each child starts; returning false refuses it.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_set_spawn_audit(
+    callback: Option<extern "C" fn(*mut c_void, *const PortablePtySpawnInfo) -> bool>,
+    userdata: *mut c_void,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        *HOOK.lock().unwrap_or_else(PoisonError::into_inner) =
+            callback.map(|func| Hook { func, userdata });
+        PortablePtyResult::Ok
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// Refuses `false`, and notes whether it saw `true` spawned.
+    extern "C" fn audit(userdata: *mut c_void, info: *const PortablePtySpawnInfo) -> bool {
+        let saw = unsafe { &*(userdata as *const AtomicBool) };
+        let info = unsafe { &*info };
+        let program = unsafe { CStr::from_ptr(info.program) };
+        let args: Vec<&CStr> = (0..info.argc)
+            .map(|i| unsafe { CStr::from_ptr(*info.argv.add(i)) })
+            .collect();
+        if program == c"true" {
+            assert_eq!(args, [c"true", c"--audited"]);
+            assert!(unsafe { (*info.argv.add(info.argc)).is_null() });
+            assert_eq!(info.uid, i64::from(unsafe { libc::geteuid() }));
+            assert!(info.timestamp_ms > 0 && !info.handle.is_null());
+            saw.store(true, Ordering::SeqCst);
+        }
+        program != c"false"
+    }
+
+    // Other tests spawn in parallel, so the hook lets their children by.
+    #[test]
+    fn test_hook_sees_and_vetoes_spawns() {
+        static SAW: AtomicBool = AtomicBool::new(false);
+        let userdata = &SAW as *const AtomicBool as *mut c_void;
+        portable_pty_set_spawn_audit(Some(audit), userdata);
+
+        let handle = crate::tests::open_and_spawn("true", &["true", "--audited"]);
+        assert!(SAW.load(Ordering::SeqCst));
+        crate::portable_pty_close(handle);
+
+        let mut handle = std::ptr::null_mut();
+        let result = crate::portable_pty_open(24, 80, &mut handle);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        let argv = [c"false".as_ptr(), std::ptr::null()];
+        let result =
+            crate::portable_pty_spawn(handle, c"false".as_ptr(), argv.as_ptr(), std::ptr::null());
+        assert!(matches!(result, PortablePtyResult::ErrDenied));
+        assert_eq!(crate::portable_pty_child_pid(handle), -1);
+        crate::portable_pty_close(handle);
+
+        portable_pty_set_spawn_audit(None, std::ptr::null_mut());
+    }
+}
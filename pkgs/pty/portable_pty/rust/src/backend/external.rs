@@ -0,0 +1,525 @@
+//! Backends implemented by the embedder.
+//!
+//! `portable_pty_register_backend` adds a transport living on the other
+//! side of the FFI — a serial port, a container's exec stream, a
+//! WebSocket — as a table of callbacks. Its handles work like any other:
+//! a pump thread copies output from `read` into a pipe the handle reads,
+//! so the descriptor can be polled and expect, events and recording all
+//! see it. Like loopback and mock handles, an opened session is its own
+//! child; `portable_pty_spawn` fails on it.
+
+use super::Backend;
+use crate::mock::KILLED_STATUS;
+use crate::pty::{
+    Child, ChildKiller, CommandBuilder, ExitStatus, MasterPty, PtyPair, PtySize, SlavePty,
+};
+use crate::{PipeReader, PipeWriter, PortablePty, PortablePtyResult};
+use serde_json::Value;
+use std::ffi::{c_char, c_int, c_void, CString};
+use std::io::{ErrorKind, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+
+type OpenFn =
+    extern "C" fn(*mut c_void, *const c_char, u16, u16, *mut *mut c_void) -> PortablePtyResult;
+type ReadFn = extern "C" fn(*mut c_void, *mut u8, usize) -> i64;
+type WriteFn = extern "C" fn(*mut c_void, *const u8, usize) -> i64;
+type ResizeFn = extern "C" fn(*mut c_void, u16, u16) -> PortablePtyResult;
+type SessionFn = extern "C" fn(*mut c_void);
+type WaitFn = extern "C" fn(*mut c_void) -> c_int;
+
+/// Callbacks implementing a backend, for `portable_pty_register_backend`.
+///
+/// Apart from `open`, each takes the session pointer `open` produced.
+/// `read` runs on a background thread while the others are called from
+/// whichever thread calls into the library, so they must be safe to call
+/// concurrently with it.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct PortablePtyBackend {
+    /// Passed to `open`.
+    pub user_data: *mut c_void,
+    /// Required. Start a session: `(user_data, config, rows, cols,
+    /// out_session)`. `config` is the JSON object given to
+    /// `portable_pty_open_backend`, valid for the call only. Store the
+    /// session in `*out_session` and return `Ok`, or return the error for
+    /// `portable_pty_open_backend` to pass on.
+    pub open: Option<
+        extern "C" fn(*mut c_void, *const c_char, u16, u16, *mut *mut c_void) -> PortablePtyResult,
+    >,
+    /// Required. Block until there is output, then copy up to `len` bytes
+    /// of it to `buf`: `(session, buf, len)`. Returns the number copied, or
+    /// 0 (or -1 on error) once the session has ended.
+    pub read: Option<extern "C" fn(*mut c_void, *mut u8, usize) -> i64>,
+    /// Required. Send input: `(session, buf, len)`. Returns the number of
+    /// bytes taken, or -1 on error.
+    pub write: Option<extern "C" fn(*mut c_void, *const u8, usize) -> i64>,
+    /// Optional. Resize the remote terminal: `(session, rows, cols)`.
+    pub resize: Option<extern "C" fn(*mut c_void, u16, u16) -> PortablePtyResult>,
+    /// Required. End the session: `(session)`. A blocked `read` must return.
+    /// Called when the handle is killed or closed, at most once.
+    pub kill: Option<extern "C" fn(*mut c_void)>,
+    /// Optional. The exit status of a session that has ended: `(session)`.
+    /// Without it, sessions exit with 0, or 137 once killed.
+    pub wait: Option<extern "C" fn(*mut c_void) -> c_int>,
+    /// Optional. Release the session: `(session)`. Called once, after the
+    /// handle is closed and `read` has returned for the last time.
+    pub close: Option<extern "C" fn(*mut c_void)>,
+}
+
+/// A [`PortablePtyBackend`] with its required callbacks checked.
+struct Callbacks {
+    user_data: *mut c_void,
+    open: OpenFn,
+    read: ReadFn,
+    write: WriteFn,
+    resize: Option<ResizeFn>,
+    kill: SessionFn,
+    wait: Option<WaitFn>,
+    close: Option<SessionFn>,
+}
+
+// The pointers are opaque to us; the embedder promises the callbacks may
+// be used from any thread.
+unsafe impl Send for Callbacks {}
+unsafe impl Sync for Callbacks {}
+
+/// A registered backend. Its sessions keep the callbacks alive after it's
+/// replaced or unregistered.
+pub(super) struct External(Arc<Callbacks>);
+
+impl External {
+    /// None if a required callback is missing.
+    pub(super) fn new(backend: &PortablePtyBackend) -> Option<External> {
+        Some(External(Arc::new(Callbacks {
+            user_data: backend.user_data,
+            open: backend.open?,
+            read: backend.read?,
+            write: backend.write?,
+            resize: backend.resize,
+            kill: backend.kill?,
+            wait: backend.wait,
+            close: backend.close,
+        })))
+    }
+}
+
+impl Backend for External {
+    fn open(&self, config: &Value) -> Result<Box<PortablePty>, PortablePtyResult> {
+        let size = super::parse_size(config).ok_or(PortablePtyResult::ErrOpen)?;
+        let json = CString::new(config.to_string()).map_err(|_| PortablePtyResult::ErrOpen)?;
+        let mut session = std::ptr::null_mut();
+        let callbacks = &self.0;
+        let result = (callbacks.open)(
+            callbacks.user_data,
+            json.as_ptr(),
+            size.rows,
+            size.cols,
+            &mut session,
+        );
+        if !matches!(result, PortablePtyResult::Ok) {
+            return Err(result);
+        }
+
+        // From here on, dropping `session` closes it.
+        let session = Arc::new(Session {
+            callbacks: Arc::clone(callbacks),
+            session,
+            size: Mutex::new(size),
+            killed: AtomicBool::new(false),
+            exit: Mutex::new(None),
+            exited: Condvar::new(),
+        });
+        let (reader, output) = crate::pipe().map_err(|_| {
+            session.kill();
+            PortablePtyResult::ErrOpen
+        })?;
+        let pair = PtyPair {
+            slave: Box::new(ExternalSlave),
+            master: Box::new(ExternalMaster {
+                session: Arc::clone(&session),
+                reader,
+            }),
+        };
+        let mut handle = PortablePty::from_pair(pair)?;
+        handle.child = Some(Box::new(ExternalChild {
+            session: Arc::clone(&session),
+        }));
+        crate::lifecycle::spawn_thread("portable-pty-backend", move || pump(session, output))
+            .map_err(|_| PortablePtyResult::ErrOpen)?;
+        Ok(handle)
+    }
+}
+
+/// One open session, shared by the handle and the pump thread.
+struct Session {
+    callbacks: Arc<Callbacks>,
+    session: *mut c_void,
+    size: Mutex<PtySize>,
+    killed: AtomicBool,
+    exit: Mutex<Option<u32>>,
+    /// Signalled when `exit` is set.
+    exited: Condvar,
+}
+
+// As for `Callbacks`.
+unsafe impl Send for Session {}
+unsafe impl Sync for Session {}
+
+impl Session {
+    fn kill(&self) {
+        if !self.killed.swap(true, Ordering::AcqRel) {
+            (self.callbacks.kill)(self.session);
+        }
+    }
+
+    fn exit(&self) -> Option<u32> {
+        *self.exit.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        if let Some(close) = self.callbacks.close {
+            close(self.session);
+        }
+    }
+}
+
+/// Copy the session's output into `output` until it ends, then record its
+/// exit status.
+fn pump(session: Arc<Session>, mut output: PipeWriter) {
+    // If the handle goes away mid-write, get EPIPE rather than a
+    // process-wide SIGPIPE.
+    #[cfg(unix)]
+    crate::block_sigpipe();
+
+    let mut buf = [0u8; 16 * 1024];
+    loop {
+        let n = (session.callbacks.read)(session.session, buf.as_mut_ptr(), buf.len());
+        let n = match usize::try_from(n) {
+            Ok(n) if n > 0 => n.min(buf.len()),
+            _ => break,
+        };
+        if output.write_all(&buf[..n]).is_err() {
+            break;
+        }
+    }
+
+    // Dropping `output` gives the reader EOF.
+    drop(output);
+    let status = match session.callbacks.wait {
+        Some(wait) => wait(session.session) as u32,
+        None if session.killed.load(Ordering::Acquire) => KILLED_STATUS,
+        None => 0,
+    };
+    session
+        .exit
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get_or_insert(status);
+    session.exited.notify_all();
+}
+
+struct ExternalMaster {
+    session: Arc<Session>,
+    reader: PipeReader,
+}
+
+impl Drop for ExternalMaster {
+    fn drop(&mut self) {
+        if self.session.exit().is_none() {
+            self.session.kill();
+        }
+    }
+}
+
+impl MasterPty for ExternalMaster {
+    fn resize(&self, size: PtySize) -> anyhow::Result<()> {
+        if let Some(resize) = self.session.callbacks.resize {
+            let result = resize(self.session.session, size.rows, size.cols);
+            if !matches!(result, PortablePtyResult::Ok) {
+                anyhow::bail!("backend refused the resize");
+            }
+        }
+        *self
+            .session
+            .size
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = size;
+        Ok(())
+    }
+
+    fn get_size(&self) -> anyhow::Result<PtySize> {
+        Ok(*self
+            .session
+            .size
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner))
+    }
+
+    fn try_clone_reader(&self) -> anyhow::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(self.reader.try_clone()?))
+    }
+
+    fn take_writer(&self) -> anyhow::Result<Box<dyn Write + Send>> {
+        Ok(Box::new(ExternalWriter {
+            session: Arc::clone(&self.session),
+        }))
+    }
+
+    #[cfg(unix)]
+    fn process_group_leader(&self) -> Option<libc::pid_t> {
+        None
+    }
+
+    #[cfg(unix)]
+    fn as_raw_fd(&self) -> Option<std::os::fd::RawFd> {
+        use std::os::fd::AsRawFd;
+        Some(self.reader.as_raw_fd())
+    }
+
+    #[cfg(unix)]
+    fn tty_name(&self) -> Option<std::path::PathBuf> {
+        None
+    }
+}
+
+/// Passes input straight to the `write` callback.
+struct ExternalWriter {
+    session: Arc<Session>,
+}
+
+impl Write for ExternalWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.session.exit().is_some() {
+            return Err(ErrorKind::BrokenPipe.into());
+        }
+        let n = (self.session.callbacks.write)(self.session.session, buf.as_ptr(), buf.len());
+        usize::try_from(n)
+            .map(|n| n.min(buf.len()))
+            .map_err(|_| ErrorKind::Other.into())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+struct ExternalSlave;
+
+impl SlavePty for ExternalSlave {
+    fn spawn_command(&self, _cmd: CommandBuilder) -> anyhow::Result<Box<dyn Child + Send + Sync>> {
+        anyhow::bail!("backend sessions can't spawn processes")
+    }
+}
+
+/// Stands in for a child process: exits when the session ends.
+#[derive(Clone)]
+struct ExternalChild {
+    session: Arc<Session>,
+}
+
+impl std::fmt::Debug for ExternalChild {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ExternalChild").finish_non_exhaustive()
+    }
+}
+
+impl ChildKiller for ExternalChild {
+    fn kill(&mut self) -> std::io::Result<()> {
+        self.session.kill();
+        Ok(())
+    }
+
+    fn clone_killer(&self) -> Box<dyn ChildKiller + Send + Sync> {
+        Box::new(self.clone())
+    }
+}
+
+impl Child for ExternalChild {
+    fn try_wait(&mut self) -> std::io::Result<Option<ExitStatus>> {
+        Ok(self.session.exit().map(ExitStatus::with_exit_code))
+    }
+
+    fn wait(&mut self) -> std::io::Result<ExitStatus> {
+        let mut exit = self
+            .session
+            .exit
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        loop {
+            if let Some(code) = *exit {
+                return Ok(ExitStatus::with_exit_code(code));
+            }
+            exit = self
+                .session
+                .exited
+                .wait(exit)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    fn process_id(&self) -> Option<u32> {
+        None
+    }
+
+    #[cfg(windows)]
+    fn as_raw_handle(&self) -> Option<std::os::windows::io::RawHandle> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{portable_pty_open_backend, portable_pty_register_backend};
+    use std::collections::VecDeque;
+    use std::sync::atomic::AtomicUsize;
+
+    /// A session that echoes its input back, upper-cased.
+    #[derive(Default)]
+    struct Echo {
+        state: Mutex<(VecDeque<u8>, bool)>,
+        changed: Condvar,
+    }
+
+    static CLOSED: AtomicUsize = AtomicUsize::new(0);
+
+    fn echo<'a>(session: *mut c_void) -> &'a Echo {
+        unsafe { &*(session as *const Echo) }
+    }
+
+    extern "C" fn echo_open(
+        _user_data: *mut c_void,
+        config: *const c_char,
+        _rows: u16,
+        _cols: u16,
+        out: *mut *mut c_void,
+    ) -> PortablePtyResult {
+        let config = unsafe { std::ffi::CStr::from_ptr(config) }
+            .to_str()
+            .unwrap();
+        if config.contains("deny") {
+            return PortablePtyResult::ErrAuth;
+        }
+        unsafe { *out = Box::into_raw(Box::<Echo>::default()).cast() };
+        PortablePtyResult::Ok
+    }
+
+    extern "C" fn echo_read(session: *mut c_void, buf: *mut u8, len: usize) -> i64 {
+        let echo = echo(session);
+        let mut state = echo.state.lock().unwrap();
+        while state.0.is_empty() && !state.1 {
+            state = echo.changed.wait(state).unwrap();
+        }
+        let n = len.min(state.0.len());
+        for (i, byte) in state.0.drain(..n).enumerate() {
+            unsafe { *buf.add(i) = byte };
+        }
+        n as i64
+    }
+
+    extern "C" fn echo_write(session: *mut c_void, buf: *const u8, len: usize) -> i64 {
+        let echo = echo(session);
+        let input = unsafe { std::slice::from_raw_parts(buf, len) };
+        echo.state
+            .lock()
+            .unwrap()
+            .0
+            .extend(input.to_ascii_uppercase());
+        echo.changed.notify_all();
+        len as i64
+    }
+
+    extern "C" fn echo_kill(session: *mut c_void) {
+        let echo = echo(session);
+        echo.state.lock().unwrap().1 = true;
+        echo.changed.notify_all();
+    }
+
+    extern "C" fn echo_wait(_session: *mut c_void) -> c_int {
+        3
+    }
+
+    extern "C" fn echo_close(session: *mut c_void) {
+        drop(unsafe { Box::from_raw(session as *mut Echo) });
+        CLOSED.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn register_echo(name: &str) {
+        let backend = PortablePtyBackend {
+            user_data: std::ptr::null_mut(),
+            open: Some(echo_open),
+            read: Some(echo_read),
+            write: Some(echo_write),
+            resize: None,
+            kill: Some(echo_kill),
+            wait: Some(echo_wait),
+            close: Some(echo_close),
+        };
+        let name = CString::new(name).unwrap();
+        let result = portable_pty_register_backend(name.as_ptr(), &backend);
+        assert!(matches!(result, PortablePtyResult::Ok));
+    }
+
+    fn open(name: &str, config: &str) -> (PortablePtyResult, *mut PortablePty) {
+        let name = CString::new(name).unwrap();
+        let config = CString::new(config).unwrap();
+        let mut handle = std::ptr::null_mut();
+        let result = portable_pty_open_backend(name.as_ptr(), config.as_ptr(), &mut handle);
+        (result, handle)
+    }
+
+    #[test]
+    fn test_registered_backend_drives_handle() {
+        register_echo("test-echo");
+        let (result, handle) = open("test-echo", "{}");
+        assert!(matches!(result, PortablePtyResult::Ok));
+
+        let input = b"ping";
+        let n = crate::portable_pty_write(handle, input.as_ptr(), input.len());
+        assert_eq!(n, 4);
+        assert_eq!(crate::tests::read_string(handle), "PING");
+
+        let result = crate::portable_pty_kill(handle, 9);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        let mut status = 0;
+        let result = crate::portable_pty_wait_blocking(handle, &mut status);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        assert_eq!(status, 3);
+
+        // The pump thread may hold the session a moment longer.
+        let closed = CLOSED.load(Ordering::SeqCst);
+        crate::portable_pty_close(handle);
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while CLOSED.load(Ordering::SeqCst) == closed {
+            assert!(std::time::Instant::now() < deadline, "session not closed");
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_open_errors_pass_through() {
+        register_echo("test-echo-deny");
+        let (result, _) = open("test-echo-deny", r#"{"mode": "deny"}"#);
+        assert!(matches!(result, PortablePtyResult::ErrAuth));
+    }
+
+    #[test]
+    fn test_requires_callbacks() {
+        let backend = PortablePtyBackend {
+            user_data: std::ptr::null_mut(),
+            open: Some(echo_open),
+            read: None,
+            write: None,
+            resize: None,
+            kill: None,
+            wait: None,
+            close: None,
+        };
+        let name = CString::new("test-incomplete").unwrap();
+        let result = portable_pty_register_backend(name.as_ptr(), &backend);
+        assert!(matches!(result, PortablePtyResult::ErrNull));
+    }
+}
@@ -0,0 +1,300 @@
+//! Opening handles by backend name.
+//!
+//! A handle only reaches its transport through portable-pty's `MasterPty`,
+//! `SlavePty` and `Child` traits, so reads, writes, resizes, waits and
+//! everything built on them work the same whatever is behind it. Only
+//! opening differs. A [`Backend`] opens one kind of handle from a JSON
+//! config, and `portable_pty_open_backend` picks one by name:
+//!
+//! | name         | config                                             |
+//! |--------------|----------------------------------------------------|
+//! | `native`     | `rows`, `cols`, `conpty_flags`; see below          |
+//! | `loopback`   | `rows`, `cols`; as `portable_pty_open_loopback`    |
+//! | `mock`       | the script `portable_pty_open_mock` takes          |
+//! | `replay`     | `path`; as `portable_pty_open_replay`              |
+//! | `ssh`        | what `portable_pty_open_ssh` takes, `rows`, `cols` |
+//! | `persistent` | a session `name` and more; see `persist`           |
+//! | `device`     | `path` and tty settings; see `device`              |
+//! | `winpty`     | `rows`, `cols`; WinPTY on Windows, see `conpty`    |
+//! | `piped`      | `rows`, `cols`; as `portable_pty_open_piped`       |
+//!
+//! `rows` and `cols` default to 24 and 80. `native` is `portable_pty_open`
+//! unless `conpty_flags` is given, when on Windows with ConPTY it's
+//! `portable_pty_open_conpty` with those flags; elsewhere they're ignored.
+//!
+//! A new transport in this crate implements the traits and gets an entry
+//! in `BUILTINS`; one living in the embedder is added at runtime with
+//! `portable_pty_register_backend` (see `external`). Either way no other
+//! entry point changes.
+
+mod external;
+
+pub use external::PortablePtyBackend;
+
+use crate::pty::PtySize;
+use crate::{PortablePty, PortablePtyResult};
+use serde_json::Value;
+use std::ffi::{c_char, CStr};
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// Opens one kind of handle.
+pub(crate) trait Backend: Send + Sync {
+    /// Open a handle as `config` describes. `ErrOpen` if it's malformed.
+    fn open(&self, config: &Value) -> Result<Box<PortablePty>, PortablePtyResult>;
+}
+
+/// A backend that is just a function of its config.
+struct Builtin(fn(&Value) -> Result<Box<PortablePty>, PortablePtyResult>);
+
+impl Backend for Builtin {
+    fn open(&self, config: &Value) -> Result<Box<PortablePty>, PortablePtyResult> {
+        (self.0)(config)
+    }
+}
+
+/// The backends this crate provides.
+static BUILTINS: [(&str, Builtin); 9] = [
+    ("native", Builtin(native)),
+    (
+        "loopback",
+        Builtin(|config| crate::loopback::open(size(config)?)),
+    ),
+    ("mock", Builtin(crate::mock::open_script)),
+    (
+        "replay",
+        Builtin(|config| {
+            let path = config.get("path").and_then(Value::as_str);
+            let path = path.ok_or(PortablePtyResult::ErrOpen)?;
+            crate::replay::open_file(std::path::Path::new(path))
+        }),
+    ),
+    ("ssh", Builtin(crate::ssh::open_config)),
+    ("persistent", Builtin(crate::persist::open_config)),
+    ("device", Builtin(crate::device::open_config)),
+    (
+        "winpty",
+        Builtin(|config| crate::conpty::open_winpty(size(config)?)),
+    ),
+    ("piped", Builtin(|config| crate::piped::open(size(config)?))),
+];
+
+/// Backends registered at runtime. Searched before `BUILTINS`, so they
+/// can replace one.
+static REGISTERED: Mutex<Vec<(String, Arc<dyn Backend>)>> = Mutex::new(Vec::new());
+
+/// The `rows` and `cols` of a config, defaulting to 24x80. None if either
+/// is present but not a valid dimension.
+pub(crate) fn parse_size(config: &Value) -> Option<PtySize> {
+    let dimension = |key: &str, default: u16| match config.get(key) {
+        Some(v) => v.as_u64()?.try_into().ok(),
+        None => Some(default),
+    };
+    Some(PtySize {
+        rows: dimension("rows", 24)?,
+        cols: dimension("cols", 80)?,
+        pixel_width: 0,
+        pixel_height: 0,
+    })
+}
+
+fn size(config: &Value) -> Result<PtySize, PortablePtyResult> {
+    parse_size(config).ok_or(PortablePtyResult::ErrOpen)
+}
+
+/// The `native` backend: a local PTY, with ConPTY flags if given.
+fn native(config: &Value) -> Result<Box<PortablePty>, PortablePtyResult> {
+    let size = size(config)?;
+    let Some(flags) = config.get("conpty_flags") else {
+        return crate::open_native(size);
+    };
+    let flags = flags
+        .as_u64()
+        .and_then(|f| u32::try_from(f).ok())
+        .ok_or(PortablePtyResult::ErrOpen)?;
+    // Only ConPTY has flags; a config naming them works anywhere.
+    match crate::conpty::available() {
+        true => crate::conpty::open(size, flags),
+        false => crate::open_native(size),
+    }
+}
+
+/// Add `backend` under `name`, replacing any registered before.
+pub(crate) fn register(name: &str, backend: Arc<dyn Backend>) {
+    let mut registered = REGISTERED.lock().unwrap_or_else(PoisonError::into_inner);
+    registered.retain(|(n, _)| n != name);
+    registered.push((name.to_owned(), backend));
+}
+
+/// Remove the backend registered under `name`. False if there was none.
+fn unregister(name: &str) -> bool {
+    let mut registered = REGISTERED.lock().unwrap_or_else(PoisonError::into_inner);
+    let before = registered.len();
+    registered.retain(|(n, _)| n != name);
+    registered.len() != before
+}
+
+/// Open a handle with the backend called `name`.
+pub(crate) fn open(name: &str, config: &Value) -> Result<Box<PortablePty>, PortablePtyResult> {
+    // Out of the lock while opening: a registered backend may call back in.
+    let registered = REGISTERED
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, backend)| Arc::clone(backend));
+    if let Some(backend) = registered {
+        return backend.open(config);
+    }
+    match BUILTINS.iter().find(|(n, _)| *n == name) {
+        Some((_, backend)) => backend.open(config),
+        None => Err(PortablePtyResult::ErrBackend),
+    }
+}
+
+/// Open a handle with the backend called `name`.
+///
+/// - `name`: null-terminated backend name: one registered with
+///   `portable_pty_register_backend`, or a built-in one. Those are
+///   `native`, `loopback`, `mock`, `replay`, `ssh`, `persistent`,
+///   `device`, `winpty` and `piped`.
+/// - `config`: null-terminated UTF-8 JSON object, or NULL for `{}`. Most
+///   backends take `rows` and `cols`, defaulting to 24 and 80; the rest
+///   is what the backend's own entry point takes (`portable_pty_open_mock`
+///   for `mock`, `path` for `replay` and so on), and `native` takes
+///   `conpty_flags` to open with `portable_pty_open_conpty` on Windows.
+/// - `out`: receives the new handle; close it with `portable_pty_close`.
+///
+/// Returns `ErrBackend` if no backend has that name and `ErrOpen` if the
+/// config is malformed. Otherwise the result is what the backend's own
+/// entry point would return: `ErrUnsupported` for a backend left out of
+/// this build, `ErrAuth` from SSH, and so on.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_open_backend(
+    name: *const c_char,
+    config: *const c_char,
+    out: *mut *mut PortablePty,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        if name.is_null() || out.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+        let Ok(name) = unsafe { CStr::from_ptr(name) }.to_str() else {
+            return PortablePtyResult::ErrBackend;
+        };
+        let config = if config.is_null() {
+            Some(Value::Object(Default::default()))
+        } else {
+            unsafe { CStr::from_ptr(config) }
+                .to_str()
+                .ok()
+                .and_then(|json| serde_json::from_str(json).ok())
+        };
+        let Some(config) = config.filter(Value::is_object) else {
+            return PortablePtyResult::ErrOpen;
+        };
+        match open(name, &config) {
+            Ok(handle) => {
+                unsafe {
+                    *out = crate::lifecycle::register(handle);
+                }
+                PortablePtyResult::Ok
+            }
+            Err(e) => e,
+        }
+    })
+}
+
+/// Register a backend implemented by the embedder under `name`.
+///
+/// - `name`: null-terminated name to pass to `portable_pty_open_backend`.
+/// - `backend`: its callbacks, copied; see [`PortablePtyBackend`].
+///
+/// Replaces a backend registered under the same name before, and takes
+/// precedence over a built-in one. Handles already open keep the callbacks
+/// they were opened with. Returns `ErrNull` if a required callback is
+/// missing and `ErrBackend` if the name is empty or not UTF-8.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_register_backend(
+    name: *const c_char,
+    backend: *const PortablePtyBackend,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let backend = match unsafe { backend.as_ref() } {
+            Some(b) => b,
+            None => return PortablePtyResult::ErrNull,
+        };
+        if name.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+        let name = match unsafe { CStr::from_ptr(name) }.to_str() {
+            Ok(name) if !name.is_empty() => name,
+            _ => return PortablePtyResult::ErrBackend,
+        };
+        match external::External::new(backend) {
+            Some(external) => {
+                register(name, Arc::new(external));
+                PortablePtyResult::Ok
+            }
+            None => PortablePtyResult::ErrNull,
+        }
+    })
+}
+
+/// Remove a backend added with `portable_pty_register_backend`.
+///
+/// A built-in backend of the same name is used again afterwards. Handles
+/// it opened stay usable. Returns false if nothing was registered under
+/// `name`.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_unregister_backend(name: *const c_char) -> bool {
+    crate::ffi::guard(|| {
+        if name.is_null() {
+            return false;
+        }
+        match unsafe { CStr::from_ptr(name) }.to_str() {
+            Ok(name) => unregister(name),
+            Err(_) => false,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+
+    fn open_backend(name: &str, config: &str) -> (PortablePtyResult, *mut PortablePty) {
+        let name = CString::new(name).unwrap();
+        let config = CString::new(config).unwrap();
+        let mut handle = std::ptr::null_mut();
+        let result = portable_pty_open_backend(name.as_ptr(), config.as_ptr(), &mut handle);
+        (result, handle)
+    }
+
+    #[test]
+    fn test_opens_builtins_by_name() {
+        let (result, handle) = open_backend("loopback", r#"{"rows": 30, "cols": 100}"#);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        let (mut rows, mut cols, mut width, mut height) = (0, 0, 0, 0);
+        crate::portable_pty_get_size(handle, &mut rows, &mut cols, &mut width, &mut height);
+        assert_eq!((rows, cols), (30, 100));
+        crate::portable_pty_close(handle);
+
+        let (result, handle) = open_backend("mock", r#"{"steps": [{"output": "hi"}]}"#);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        assert_eq!(crate::tests::read_string(handle), "hi");
+        crate::portable_pty_close(handle);
+    }
+
+    #[test]
+    fn test_rejects_unknown_names_and_bad_configs() {
+        let (result, _) = open_backend("carrier-pigeon", "{}");
+        assert!(matches!(result, PortablePtyResult::ErrBackend));
+        let (result, _) = open_backend("loopback", r#"{"rows": -1}"#);
+        assert!(matches!(result, PortablePtyResult::ErrOpen));
+        let (result, _) = open_backend("loopback", "[24, 80]");
+        assert!(matches!(result, PortablePtyResult::ErrOpen));
+        let (result, _) = open_backend("replay", "{}");
+        assert!(matches!(result, PortablePtyResult::ErrOpen));
+    }
+}
@@ -0,0 +1,8 @@
+//! Helper that owns a persistent session's PTY and child; started by the
+//! library, not by hand. See `portable_pty_rs::persist`.
+
+fn main() {
+    std::process::exit(portable_pty_rs::persist::daemon_main(
+        std::env::args_os().skip(1),
+    ));
+}
@@ -0,0 +1,175 @@
+//! Stress test for many PTYs at once: `portable-pty-stress [HANDLES
+//! [ROUNDS]]`, 256 handles and 4 rounds by default.
+//!
+//! Each round opens every handle and runs a short-lived child in it, twice:
+//! once with all the children waiting to be released together, so their
+//! `SIGCHLD`s coalesce, and once with each exiting as soon as it starts,
+//! while the rest are still being spawned. Past 64 handles the `SIGCHLD`
+//! registry is full and the rest rely on the fallbacks. Every child exits
+//! with a code of its own, which its handle has to report; afterwards no
+//! file descriptors or registry slots may be left in use. Any problem is
+//! printed and the exit code is 1.
+
+#[cfg(unix)]
+mod stress {
+    use portable_pty_rs::metrics::portable_pty_metrics_json;
+    use portable_pty_rs::{
+        portable_pty_buffer_free, portable_pty_close, portable_pty_open, portable_pty_spawn,
+        portable_pty_wait_blocking, portable_pty_write, PortablePty, PortablePtyBuffer,
+        PortablePtyResult,
+    };
+    use std::ffi::{c_char, CString};
+    use std::ptr;
+
+    /// What child `i` exits with, so a status reported to the wrong handle
+    /// shows.
+    fn exit_code(i: usize) -> i32 {
+        (i % 250) as i32 + 1
+    }
+
+    fn open_fds() -> usize {
+        std::fs::read_dir("/dev/fd").map_or(0, |dir| dir.count())
+    }
+
+    /// Children the `SIGCHLD` registry is following.
+    fn tracked_children() -> u64 {
+        let mut buffer = PortablePtyBuffer {
+            data: ptr::null_mut(),
+            len: 0,
+        };
+        if !matches!(
+            portable_pty_metrics_json(&mut buffer),
+            PortablePtyResult::Ok
+        ) {
+            return 0;
+        }
+        let json = unsafe { std::slice::from_raw_parts(buffer.data, buffer.len) };
+        let metrics: serde_json::Value = serde_json::from_slice(json).unwrap_or_default();
+        portable_pty_buffer_free(buffer);
+        metrics["sigchld"]["tracked"].as_u64().unwrap_or(0)
+    }
+
+    /// Open `count` handles, each running `script` with its exit code as
+    /// `$1`.
+    fn spawn_all(
+        count: usize,
+        script: &str,
+        problems: &mut Vec<String>,
+    ) -> Vec<(usize, *mut PortablePty)> {
+        let cmd = CString::new("/bin/sh").unwrap();
+        let mut handles = Vec::with_capacity(count);
+        for i in 0..count {
+            let mut handle = ptr::null_mut();
+            let result = portable_pty_open(24, 80, &mut handle);
+            if !matches!(result, PortablePtyResult::Ok) {
+                problems.push(format!("handle {i}: open failed ({})", result as u32));
+                continue;
+            }
+            let args = ["sh", "-c", script, "sh", &exit_code(i).to_string()]
+                .map(|arg| CString::new(arg).unwrap());
+            let argv: Vec<*const c_char> = args
+                .iter()
+                .map(|arg| arg.as_ptr())
+                .chain([ptr::null()])
+                .collect();
+            let result = portable_pty_spawn(handle, cmd.as_ptr(), argv.as_ptr(), ptr::null());
+            if !matches!(result, PortablePtyResult::Ok) {
+                problems.push(format!("handle {i}: spawn failed ({})", result as u32));
+                portable_pty_close(handle);
+                continue;
+            }
+            handles.push((i, handle));
+        }
+        handles
+    }
+
+    /// Wait for every child, check what it exited with, and close.
+    fn reap(handles: Vec<(usize, *mut PortablePty)>, problems: &mut Vec<String>) {
+        for (i, handle) in handles {
+            let mut status = -1;
+            let result = portable_pty_wait_blocking(handle, &mut status);
+            if !matches!(result, PortablePtyResult::Ok) {
+                problems.push(format!("child {i}: wait failed ({})", result as u32));
+            } else if status != exit_code(i) {
+                problems.push(format!(
+                    "child {i}: exited {status}, expected {}",
+                    exit_code(i)
+                ));
+            }
+            portable_pty_close(handle);
+        }
+    }
+
+    /// Run `rounds` rounds over `count` handles, returning the problems
+    /// found.
+    pub(crate) fn run(count: usize, rounds: usize) -> Vec<String> {
+        let mut problems = Vec::new();
+        // The first spawn starts the process-wide job watcher, whose pipe
+        // stays open.
+        reap(spawn_all(1, "exit \"$1\"", &mut problems), &mut problems);
+        let fds = open_fds();
+
+        for _ in 0..rounds {
+            let handles = spawn_all(count, "read _; exit \"$1\"", &mut problems);
+            for (_, handle) in &handles {
+                portable_pty_write(*handle, b"\n".as_ptr(), 1);
+            }
+            reap(handles, &mut problems);
+
+            let handles = spawn_all(count, "exit \"$1\"", &mut problems);
+            reap(handles, &mut problems);
+        }
+
+        let leaked = open_fds().saturating_sub(fds);
+        if leaked > 0 {
+            problems.push(format!("{leaked} file descriptors left open"));
+        }
+        let tracked = tracked_children();
+        if tracked > 0 {
+            problems.push(format!("{tracked} registry slots left in use"));
+        }
+        problems
+    }
+
+    pub(crate) fn main() -> i32 {
+        let mut args = std::env::args().skip(1).map(|arg| arg.parse::<usize>());
+        let (count, rounds) = match (args.next(), args.next(), args.next()) {
+            (None, None, None) => (256, 4),
+            (Some(Ok(count)), None, None) => (count, 4),
+            (Some(Ok(count)), Some(Ok(rounds)), None) => (count, rounds),
+            _ => {
+                eprintln!("usage: portable-pty-stress [HANDLES [ROUNDS]]");
+                return 2;
+            }
+        };
+
+        let problems = run(count, rounds);
+        for problem in &problems {
+            eprintln!("{problem}");
+        }
+        if !problems.is_empty() {
+            return 1;
+        }
+        println!("ok: {} children over {count} handles", 2 * count * rounds);
+        0
+    }
+
+    #[cfg(test)]
+    mod tests {
+        #[test]
+        fn test_more_handles_than_registry_slots() {
+            assert_eq!(super::run(96, 1), Vec::<String>::new());
+        }
+    }
+}
+
+#[cfg(unix)]
+fn main() {
+    std::process::exit(stress::main());
+}
+
+#[cfg(not(unix))]
+fn main() {
+    eprintln!("portable-pty-stress exercises SIGCHLD handling, which is Unix only");
+    std::process::exit(2);
+}
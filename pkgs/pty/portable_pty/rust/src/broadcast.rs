@@ -0,0 +1,79 @@
+//! Writing the same input to several handles.
+//!
+//! For synchronized panes: `portable_pty_broadcast_write` sends one
+//! keystroke's bytes to every pane in a single call, rather than one call
+//! per pane. Each handle takes the whole of it in one go, so nothing
+//! written to that handle from elsewhere lands in the middle; there's no
+//! ordering across handles.
+
+use crate::{PortablePty, PortablePtyResult};
+
+/// Write `len` bytes from `buf` to each of `count` handles.
+///
+/// - `handles`: array of `count` handles, none of them NULL. The same
+///   handle twice gets the bytes twice.
+/// - `buf`, `len`: the bytes; nothing is written if `len` is 0.
+///
+/// Returns `ErrNull` without writing anything if `handles`, `buf` or any
+/// handle is NULL, and `ErrWrite` if any handle failed to take all of the
+/// bytes; the others are written regardless.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_broadcast_write(
+    handles: *const *const PortablePty,
+    count: usize,
+    buf: *const u8,
+    len: usize,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        if (handles.is_null() && count > 0) || (buf.is_null() && len > 0) {
+            return PortablePtyResult::ErrNull;
+        }
+        if count == 0 || len == 0 {
+            return PortablePtyResult::Ok;
+        }
+        let handles = unsafe { std::slice::from_raw_parts(handles, count) };
+        let Some(ptys) = handles
+            .iter()
+            .map(|&handle| unsafe { handle.as_ref() })
+            .collect::<Option<Vec<_>>>()
+        else {
+            return PortablePtyResult::ErrNull;
+        };
+        let bytes = unsafe { std::slice::from_raw_parts(buf, len) };
+        let mut result = PortablePtyResult::Ok;
+        for pty in ptys {
+            if pty.write_whole(&[bytes]).is_err() {
+                result = PortablePtyResult::ErrWrite;
+            }
+        }
+        result
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::tests::{open_and_spawn, read_string};
+
+    #[test]
+    fn test_broadcast_reaches_every_handle() {
+        let panes = [
+            open_and_spawn("cat", &["cat"]),
+            open_and_spawn("cat", &["cat"]),
+        ];
+        let handles = panes.map(|pane| pane as *const PortablePty);
+        let result = portable_pty_broadcast_write(handles.as_ptr(), 2, b"sync\n".as_ptr(), 5);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        for pane in panes {
+            let mut output = String::new();
+            while !output.contains("sync") {
+                output.push_str(&read_string(pane));
+            }
+            crate::portable_pty_close(pane);
+        }
+
+        let handles = [std::ptr::null()];
+        let result = portable_pty_broadcast_write(handles.as_ptr(), 1, b"x".as_ptr(), 1);
+        assert!(matches!(result, PortablePtyResult::ErrNull));
+    }
+}
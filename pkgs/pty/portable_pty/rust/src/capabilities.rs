@@ -0,0 +1,100 @@
+//! What this build of the library can do.
+//!
+//! Some operations depend on the platform or on optional features: iOS
+//! and WebAssembly, for one, have no way to run local processes. Calls
+//! that can't work return `ErrUnsupported`, and
+//! `portable_pty_capabilities` lets the embedder find out up front and
+//! adapt its UI. The loopback, mock and replay backends work everywhere
+//! and have no flag.
+//!
+//! The cargo features behind the flags, all but `ssh` and `dart-api` on
+//! by default:
+//!
+//! | feature    | flag                             |
+//! |------------|----------------------------------|
+//! | `ssh`      | `PORTABLE_PTY_CAP_SSH`           |
+//! | `zstd`     | `PORTABLE_PTY_CAP_ZSTD`          |
+//! | `screen`   | `PORTABLE_PTY_CAP_SCREEN`        |
+//! | `recorder` | `PORTABLE_PTY_CAP_RECORD`        |
+//! | `serial`   | `PORTABLE_PTY_CAP_SERIAL`        |
+//! | `dart-api` | `PORTABLE_PTY_CAP_DART`          |
+
+/// Local PTYs and processes: `portable_pty_open`, `portable_pty_spawn`,
+/// `portable_pty_run`, `portable_pty_open_piped`.
+pub const PORTABLE_PTY_CAP_LOCAL_PROCESSES: u32 = 1 << 0;
+/// Remote sessions with `portable_pty_open_ssh`.
+pub const PORTABLE_PTY_CAP_SSH: u32 = 1 << 1;
+/// WSL sessions with `portable_pty_spawn_wsl`.
+pub const PORTABLE_PTY_CAP_WSL: u32 = 1 << 2;
+/// Compressed recordings (`PORTABLE_PTY_RECORD_ZSTD`).
+pub const PORTABLE_PTY_CAP_ZSTD: u32 = 1 << 3;
+/// POSIX signals and process groups: `portable_pty_kill` delivers the
+/// requested signal, and foreground process group queries work.
+pub const PORTABLE_PTY_CAP_SIGNALS: u32 = 1 << 4;
+/// Sessions that outlive their handle: the `persistent` backend and
+/// `portable_pty_detach`.
+pub const PORTABLE_PTY_CAP_PERSISTENT: u32 = 1 << 5;
+/// Transcripts of recordings with `portable_pty_recording_to_text`, and
+/// the tracked screen of `portable_pty_screen_start`.
+pub const PORTABLE_PTY_CAP_SCREEN: u32 = 1 << 6;
+/// Session recording with `portable_pty_record_start`.
+pub const PORTABLE_PTY_CAP_RECORD: u32 = 1 << 7;
+/// Existing terminals with `portable_pty_open_device` and
+/// `portable_pty_open_stdio`.
+pub const PORTABLE_PTY_CAP_SERIAL: u32 = 1 << 8;
+/// Output posted to Dart ports with `portable_pty_attach_dart_port`.
+pub const PORTABLE_PTY_CAP_DART: u32 = 1 << 9;
+
+/// Whether local processes can be spawned on this platform.
+pub(crate) const LOCAL_PROCESSES: bool = cfg!(not(any(target_os = "ios", target_family = "wasm")));
+
+/// The `PORTABLE_PTY_CAP_*` flags supported by this build.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_capabilities() -> u32 {
+    crate::ffi::guard(|| {
+        let flags = [
+            (LOCAL_PROCESSES, PORTABLE_PTY_CAP_LOCAL_PROCESSES),
+            (cfg!(feature = "ssh"), PORTABLE_PTY_CAP_SSH),
+            (cfg!(windows), PORTABLE_PTY_CAP_WSL),
+            (cfg!(feature = "zstd"), PORTABLE_PTY_CAP_ZSTD),
+            (LOCAL_PROCESSES && cfg!(unix), PORTABLE_PTY_CAP_SIGNALS),
+            (LOCAL_PROCESSES && cfg!(unix), PORTABLE_PTY_CAP_PERSISTENT),
+            (cfg!(feature = "screen"), PORTABLE_PTY_CAP_SCREEN),
+            (cfg!(feature = "recorder"), PORTABLE_PTY_CAP_RECORD),
+            (cfg!(all(unix, feature = "serial")), PORTABLE_PTY_CAP_SERIAL),
+            (cfg!(all(unix, feature = "dart-api")), PORTABLE_PTY_CAP_DART),
+        ];
+        flags
+            .iter()
+            .filter(|(supported, _)| *supported)
+            .fold(0, |caps, (_, flag)| caps | flag)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capabilities_match_build() {
+        let caps = portable_pty_capabilities();
+        assert_eq!(
+            caps & PORTABLE_PTY_CAP_LOCAL_PROCESSES != 0,
+            LOCAL_PROCESSES
+        );
+        assert_eq!(caps & PORTABLE_PTY_CAP_SSH != 0, cfg!(feature = "ssh"));
+        assert_eq!(caps & PORTABLE_PTY_CAP_ZSTD != 0, cfg!(feature = "zstd"));
+        assert_eq!(
+            caps & PORTABLE_PTY_CAP_SCREEN != 0,
+            cfg!(feature = "screen")
+        );
+        assert_eq!(
+            caps & PORTABLE_PTY_CAP_RECORD != 0,
+            cfg!(feature = "recorder")
+        );
+        assert_eq!(
+            caps & PORTABLE_PTY_CAP_DART != 0,
+            cfg!(all(unix, feature = "dart-api"))
+        );
+    }
+}
@@ -0,0 +1,110 @@
+//! Handing the host's clipboard to the child with OSC 52.
+//!
+//! Programs like tmux and vim ask the terminal for the clipboard with
+//! `OSC 52 ; c ; ?` and take the reply, `OSC 52 ; c ; <base64>`, as its
+//! contents. `portable_pty_send_clipboard` sends that reply, for embedders
+//! that answer the query or keep the child's clipboard in step with the
+//! host's.
+
+use crate::{PortablePty, PortablePtyResult};
+use std::ffi::{c_char, CStr};
+
+/// Most clipboard data sent at once, before encoding.
+pub const PORTABLE_PTY_CLIPBOARD_MAX: usize = 1024 * 1024;
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[((n >> (18 - 6 * i)) & 0x3f) as usize]);
+            } else {
+                out.push(b'=');
+            }
+        }
+    }
+    out
+}
+
+/// The OSC 52 sequence setting `selection` to `data`.
+pub(crate) fn encode(selection: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut seq = b"\x1b]52;".to_vec();
+    seq.extend_from_slice(selection);
+    seq.push(b';');
+    seq.extend(base64(data));
+    seq.push(0x07);
+    seq
+}
+
+/// Send the child clipboard contents as an OSC 52 sequence.
+///
+/// - `selection`: NUL-terminated selection names, as in the child's query:
+///   `c` for the clipboard, `p` for the primary selection, `s`, `q` or
+///   `0` to `7`; NULL for `c`.
+/// - `data`, `len`: the contents, up to `PORTABLE_PTY_CLIPBOARD_MAX` bytes.
+///
+/// Returns `ErrSize` for more, and `ErrUnsupported` for a selection name
+/// OSC 52 doesn't have.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_send_clipboard(
+    handle: *const PortablePty,
+    selection: *const c_char,
+    data: *const u8,
+    len: usize,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        if data.is_null() && len > 0 {
+            return PortablePtyResult::ErrNull;
+        }
+        if len > PORTABLE_PTY_CLIPBOARD_MAX {
+            return PortablePtyResult::ErrSize;
+        }
+        let selection = if selection.is_null() {
+            &b"c"[..]
+        } else {
+            unsafe { CStr::from_ptr(selection) }.to_bytes()
+        };
+        let known = |c: &u8| matches!(c, b'c' | b'p' | b'q' | b's' | b'0'..=b'7');
+        if selection.is_empty() || !selection.iter().all(known) {
+            return PortablePtyResult::ErrUnsupported;
+        }
+        let data = if len == 0 {
+            &[][..]
+        } else {
+            unsafe { std::slice::from_raw_parts(data, len) }
+        };
+
+        match pty.write_input(&encode(selection, data)) {
+            Ok(()) => PortablePtyResult::Ok,
+            Err(_) => PortablePtyResult::ErrWrite,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(base64(b""), b"");
+        assert_eq!(base64(b"f"), b"Zg==");
+        assert_eq!(base64(b"fo"), b"Zm8=");
+        assert_eq!(base64(b"foo"), b"Zm9v");
+        assert_eq!(base64(b"foob"), b"Zm9vYg==");
+        assert_eq!(base64(&[0xff, 0xfe, 0x00]), b"//4A");
+        assert_eq!(encode(b"c", b"hi"), b"\x1b]52;c;aGk=\x07");
+    }
+}
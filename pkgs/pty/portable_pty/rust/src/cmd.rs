@@ -0,0 +1,266 @@
+//! Building a command a piece at a time.
+//!
+//! `portable_pty_spawn` takes the command as NULL-terminated arrays, which
+//! bindings have to marshal in one go. A `PortablePtyCmd` is built up by
+//! calls instead: `portable_pty_cmd_new` with the program, then any of
+//! `portable_pty_cmd_arg`, `portable_pty_cmd_env`, `portable_pty_cmd_cwd`
+//! and the rest, then `portable_pty_spawn_cmd` to run it. The command is
+//! left as it was by spawning, so it can be spawned again, and is freed
+//! with `portable_pty_cmd_free`. New options come as new calls, leaving
+//! the ones there are as they are.
+//!
+//! `portable_pty_cmd_config` gives a command the options of a spawn config
+//! (see `spawn`), so it's spawned as `portable_pty_spawn_config` would.
+//!
+//! A new command inherits this process's environment and working
+//! directory; `portable_pty_cmd_env_clear` starts from an empty
+//! environment instead. Strings are taken as the narrow spawn functions
+//! take them: bytes on Unix, UTF-8 elsewhere.
+
+use crate::spawn::SpawnConfig;
+use crate::{CommandBuilder, PortablePty, PortablePtyResult};
+use std::ffi::{c_char, CStr, OsString};
+
+/// A command being built.
+pub struct PortablePtyCmd {
+    builder: CommandBuilder,
+    config: SpawnConfig,
+}
+
+/// The string at `s`, or the error to return for it.
+fn string(s: *const c_char) -> Result<OsString, PortablePtyResult> {
+    if s.is_null() {
+        return Err(PortablePtyResult::ErrNull);
+    }
+    crate::os_string(unsafe { CStr::from_ptr(s) }.to_bytes()).ok_or(PortablePtyResult::ErrSpawn)
+}
+
+/// Apply `edit` to the command at `cmd`.
+fn edit(
+    cmd: *mut PortablePtyCmd,
+    edit: impl FnOnce(&mut CommandBuilder) -> Result<(), PortablePtyResult>,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let cmd = match unsafe { cmd.as_mut() } {
+            Some(c) => c,
+            None => return PortablePtyResult::ErrNull,
+        };
+        match edit(&mut cmd.builder) {
+            Ok(()) => PortablePtyResult::Ok,
+            Err(e) => e,
+        }
+    })
+}
+
+/// Start a command running `program`.
+///
+/// - `program`: NUL-terminated executable path; also the command's first
+///   argument.
+/// - `out`: receives the command, to free with `portable_pty_cmd_free`.
+///
+/// Returns `ErrSpawn` if `program` can't be a program name here.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_cmd_new(
+    program: *const c_char,
+    out: *mut *mut PortablePtyCmd,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        if out.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+        let program = match string(program) {
+            Ok(program) => program,
+            Err(e) => return e,
+        };
+        let cmd = Box::new(PortablePtyCmd {
+            builder: CommandBuilder::new(program),
+            config: SpawnConfig::default(),
+        });
+        unsafe { *out = Box::into_raw(cmd) };
+        PortablePtyResult::Ok
+    })
+}
+
+/// Append an argument.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_cmd_arg(
+    cmd: *mut PortablePtyCmd,
+    arg: *const c_char,
+) -> PortablePtyResult {
+    edit(cmd, |builder| {
+        builder.arg(string(arg)?);
+        Ok(())
+    })
+}
+
+/// Set an environment variable, replacing any it had.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_cmd_env(
+    cmd: *mut PortablePtyCmd,
+    key: *const c_char,
+    value: *const c_char,
+) -> PortablePtyResult {
+    edit(cmd, |builder| {
+        builder.env(string(key)?, string(value)?);
+        Ok(())
+    })
+}
+
+/// Leave an environment variable out, inherited or not.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_cmd_env_remove(
+    cmd: *mut PortablePtyCmd,
+    key: *const c_char,
+) -> PortablePtyResult {
+    edit(cmd, |builder| {
+        builder.env_remove(string(key)?);
+        Ok(())
+    })
+}
+
+/// Drop every environment variable, inherited or set so far.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_cmd_env_clear(cmd: *mut PortablePtyCmd) -> PortablePtyResult {
+    edit(cmd, |builder| {
+        builder.env_clear();
+        Ok(())
+    })
+}
+
+/// Set the directory the command starts in.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_cmd_cwd(
+    cmd: *mut PortablePtyCmd,
+    dir: *const c_char,
+) -> PortablePtyResult {
+    edit(cmd, |builder| {
+        builder.cwd(string(dir)?);
+        Ok(())
+    })
+}
+
+/// Give the command the options of a spawn config, replacing any given
+/// before.
+///
+/// - `config`: null-terminated UTF-8 JSON object of options, as for
+///   `portable_pty_spawn_config`, or NULL for none.
+///
+/// Returns `ErrSpawn` if the config is malformed and `ErrUnsupported` if
+/// it asks for what this platform can't do; the command keeps the options
+/// it had.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_cmd_config(
+    cmd: *mut PortablePtyCmd,
+    config: *const c_char,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let cmd = match unsafe { cmd.as_mut() } {
+            Some(c) => c,
+            None => return PortablePtyResult::ErrNull,
+        };
+        match SpawnConfig::from_json(config) {
+            Ok(config) => {
+                cmd.config = config;
+                PortablePtyResult::Ok
+            }
+            Err(e) => e,
+        }
+    })
+}
+
+/// Free a command. `cmd` may be NULL.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_cmd_free(cmd: *mut PortablePtyCmd) {
+    crate::ffi::guard(|| {
+        if !cmd.is_null() {
+            drop(unsafe { Box::from_raw(cmd) });
+        }
+    })
+}
+
+/// Spawn a command attached to the PTY, as `portable_pty_spawn` does, or
+/// `portable_pty_spawn_config` with its config.
+///
+/// The command is left as it is, and still the caller's to free.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_spawn_cmd(
+    handle: *mut PortablePty,
+    cmd: *const PortablePtyCmd,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_mut() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        let cmd = match unsafe { cmd.as_ref() } {
+            Some(c) => c,
+            None => return PortablePtyResult::ErrNull,
+        };
+        pty.spawn_with(cmd.builder.clone(), &cmd.config)
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spawn_built_command() {
+        let mut cmd = std::ptr::null_mut();
+        assert!(matches!(
+            portable_pty_cmd_new(c"sh".as_ptr(), &mut cmd),
+            PortablePtyResult::Ok
+        ));
+        for arg in [c"-c", c"echo \"$GREETING from $(pwd) ${GONE:-unset}\""] {
+            assert!(matches!(
+                portable_pty_cmd_arg(cmd, arg.as_ptr()),
+                PortablePtyResult::Ok
+            ));
+        }
+        portable_pty_cmd_env_clear(cmd);
+        portable_pty_cmd_env(cmd, c"PATH".as_ptr(), c"/bin:/usr/bin".as_ptr());
+        portable_pty_cmd_env(cmd, c"GREETING".as_ptr(), c"hello".as_ptr());
+        portable_pty_cmd_env(cmd, c"GONE".as_ptr(), c"set".as_ptr());
+        portable_pty_cmd_env_remove(cmd, c"GONE".as_ptr());
+        portable_pty_cmd_cwd(cmd, c"/".as_ptr());
+        let result = portable_pty_cmd_arg(cmd, std::ptr::null());
+        assert!(matches!(result, PortablePtyResult::ErrNull));
+
+        let mut handle = std::ptr::null_mut();
+        crate::portable_pty_open(24, 80, &mut handle);
+        assert!(matches!(
+            portable_pty_spawn_cmd(handle, cmd),
+            PortablePtyResult::Ok
+        ));
+        portable_pty_cmd_free(cmd);
+        let output = crate::tests::read_string(handle);
+        assert!(output.contains("hello from / unset"), "{output:?}");
+        crate::portable_pty_close(handle);
+    }
+
+    #[test]
+    fn test_spawn_built_command_with_config() {
+        let mut cmd = std::ptr::null_mut();
+        portable_pty_cmd_new(c"sh".as_ptr(), &mut cmd);
+        portable_pty_cmd_arg(cmd, c"-c".as_ptr());
+        portable_pty_cmd_arg(cmd, c"echo in $(pwd) core $(ulimit -c)".as_ptr());
+        let config = cr#"{"cwd": "/", "no_core_dumps": true}"#;
+        assert!(matches!(
+            portable_pty_cmd_config(cmd, config.as_ptr()),
+            PortablePtyResult::Ok
+        ));
+        let result = portable_pty_cmd_config(cmd, c"[]".as_ptr());
+        assert!(matches!(result, PortablePtyResult::ErrSpawn));
+
+        let mut handle = std::ptr::null_mut();
+        crate::portable_pty_open(24, 80, &mut handle);
+        assert!(matches!(
+            portable_pty_spawn_cmd(handle, cmd),
+            PortablePtyResult::Ok
+        ));
+        portable_pty_cmd_free(cmd);
+        let output = crate::tests::read_string(handle);
+        assert!(output.contains("in / core 0"), "{output:?}");
+        crate::portable_pty_close(handle);
+    }
+}
@@ -0,0 +1,211 @@
+//! Merging bursts of small writes.
+//!
+//! Key repeat and mouse-motion reports arrive as a flood of writes of a
+//! few bytes each, and flushing every one wakes the child as often.
+//! `portable_pty_set_write_coalescing` holds auto-flushed writes back for
+//! a short delay instead, so a burst goes out in one write: the first
+//! write held starts the delay, and the writes made before it's up are
+//! flushed with it. Enough held at once is flushed straight away.
+//!
+//! Coalescing only delays auto-flush; with auto-flush off, writes still
+//! wait for `portable_pty_flush`. Input the library writes itself goes
+//! straight out, as always.
+
+use crate::lifecycle::{HandleRef, ThreadGroup};
+use crate::{PortablePty, PortablePtyResult};
+use std::io::Write;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[derive(Default)]
+struct State {
+    /// How long writes are held, while coalescing.
+    delay: Option<Duration>,
+    /// How much may be held before it's flushed regardless.
+    max_bytes: usize,
+    /// When held writes are to be flushed; None if none are held.
+    due: Option<Instant>,
+    /// Asks the flusher thread to end.
+    stop: bool,
+}
+
+/// A handle's write coalescing.
+#[derive(Default)]
+pub(crate) struct Coalescer {
+    state: Mutex<State>,
+    changed: Condvar,
+    /// The flusher thread, while one is running.
+    flusher: Mutex<Option<Arc<ThreadGroup>>>,
+}
+
+impl Coalescer {
+    /// Note an auto-flushed write now `buffered` bytes are held; true if
+    /// they should be flushed now rather than later.
+    pub(crate) fn wrote(&self, buffered: usize) -> bool {
+        let mut state = lock(&self.state);
+        let Some(delay) = state.delay else {
+            return true;
+        };
+        if buffered >= state.max_bytes {
+            state.due = None;
+            return true;
+        }
+        if state.due.is_none() && buffered > 0 {
+            state.due = Some(Instant::now() + delay);
+            self.changed.notify_all();
+        }
+        false
+    }
+}
+
+/// Flush held writes as they come due, until stopped.
+fn flush_due(handle: HandleRef) {
+    let pty = handle.get();
+    let coalescer = &pty.coalescer;
+    let mut state = lock(&coalescer.state);
+    loop {
+        if state.stop || state.delay.is_none() {
+            return;
+        }
+        let Some(due) = state.due else {
+            state = coalescer
+                .changed
+                .wait(state)
+                .unwrap_or_else(PoisonError::into_inner);
+            continue;
+        };
+        let now = Instant::now();
+        if now < due {
+            state = coalescer
+                .changed
+                .wait_timeout(state, due - now)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+            continue;
+        }
+        state.due = None;
+        // Writers take the writer lock first.
+        drop(state);
+        let _ = lock(&pty.writer).flush();
+        state = lock(&coalescer.state);
+    }
+}
+
+/// Stop coalescing the handle's writes, if it is, flushing what's held.
+pub(crate) fn stop(pty: &PortablePty) {
+    let flusher = lock(&pty.coalescer.flusher).take();
+    if let Some(threads) = flusher {
+        lock(&pty.coalescer.state).stop = true;
+        pty.coalescer.changed.notify_all();
+        threads.wait();
+        let mut state = lock(&pty.coalescer.state);
+        state.stop = false;
+        state.delay = None;
+        let held = state.due.take().is_some();
+        drop(state);
+        if held {
+            let _ = lock(&pty.writer).flush();
+        }
+    }
+}
+
+/// Hold auto-flushed writes back for up to `delay_ms`, to send bursts of
+/// them in one go: the first write held starts the delay, and the writes
+/// made before it's up are flushed with it. With auto-flush off, writes
+/// still wait for `portable_pty_flush`.
+///
+/// - `delay_ms`: how long the first write held waits; 0 to stop
+///   coalescing, flushing anything held.
+/// - `max_bytes`: how much may be held before it's flushed regardless; 0
+///   for as much as the write buffer holds.
+///
+/// A few milliseconds is enough to merge key repeat without it being felt.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_set_write_coalescing(
+    handle: *const PortablePty,
+    delay_ms: u32,
+    max_bytes: usize,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        stop(pty);
+        if delay_ms == 0 {
+            return PortablePtyResult::Ok;
+        }
+        {
+            let mut state = lock(&pty.coalescer.state);
+            state.delay = Some(Duration::from_millis(delay_ms.into()));
+            state.max_bytes = match max_bytes {
+                0 => usize::MAX,
+                n => n,
+            };
+        }
+
+        let threads: Arc<ThreadGroup> = Arc::default();
+        let name = crate::lifecycle::thread_name(pty, "coalesce");
+        let handle = HandleRef::new(pty);
+        if threads.spawn(&name, move || flush_due(handle)).is_err() {
+            lock(&pty.coalescer.state).delay = None;
+            return PortablePtyResult::ErrUnsupported;
+        }
+        *lock(&pty.coalescer.flusher) = Some(threads);
+        PortablePtyResult::Ok
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::tests::{open_and_spawn, read_string};
+
+    #[test]
+    fn test_coalesces_small_writes() {
+        // Each read the child makes is reported on a line of its own.
+        let script = "stty raw -echo; echo ready; \
+                      while n=$(dd bs=64 count=1 2>/dev/null | wc -c); do echo \"got $n\"; done";
+        let handle = open_and_spawn("sh", &["sh", "-c", script]);
+        let mut output = String::new();
+        while !output.contains("ready") {
+            output += &read_string(handle);
+        }
+        assert!(matches!(
+            portable_pty_set_write_coalescing(handle, 100, 0),
+            PortablePtyResult::Ok
+        ));
+        for _ in 0..5 {
+            assert_eq!(crate::portable_pty_write(handle, b"k".as_ptr(), 1), 1);
+        }
+        let mut output = String::new();
+        while !output.contains("got") {
+            output += &read_string(handle);
+        }
+        assert!(output.contains("got 5"), "{output:?}");
+
+        // Past the size limit, writes go straight out.
+        assert!(matches!(
+            portable_pty_set_write_coalescing(handle, 60_000, 2),
+            PortablePtyResult::Ok
+        ));
+        for _ in 0..2 {
+            assert_eq!(crate::portable_pty_write(handle, b"k".as_ptr(), 1), 1);
+        }
+        let mut output = String::new();
+        while !output.contains("got") {
+            output += &read_string(handle);
+        }
+        assert!(output.contains("got 2"), "{output:?}");
+
+        assert!(matches!(
+            portable_pty_set_write_coalescing(handle, 0, 0),
+            PortablePtyResult::Ok
+        ));
+        crate::portable_pty_close(handle);
+    }
+}
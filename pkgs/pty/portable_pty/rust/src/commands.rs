@@ -0,0 +1,250 @@
+//! Prompt-aware command queue.
+//!
+//! Commands queued with `portable_pty_queue_command` are typed into the
+//! shell one at a time. The next command is only written once the shell
+//! reports (via OSC 133 marks, see [`crate::modes`]) that the previous one
+//! finished and a new prompt is up. Each command's output and exit status
+//! are delivered as a `PORTABLE_PTY_EVENT_COMMAND_DONE` event.
+//!
+//! This needs a shell with terminal integration that emits at least the
+//! `A` (prompt) and `D` (command finished) marks. Output is captured from
+//! the `C` mark when the shell sends one; otherwise it starts when the
+//! command is written and includes the echoed command line.
+
+use crate::events::PORTABLE_PTY_EVENT_COMMAND_DONE;
+use crate::modes::{MarkAt, PromptMark};
+use crate::{PortablePty, PortablePtyResult};
+use std::collections::VecDeque;
+use std::ffi::{c_char, CStr};
+use std::sync::PoisonError;
+
+/// Per-command output beyond this is dropped (the head is kept).
+const MAX_CAPTURE: usize = 4 * 1024 * 1024;
+
+struct Running {
+    id: u64,
+    output: Vec<u8>,
+}
+
+/// A finished command: `(id, exit status or -1, captured output)`.
+pub(crate) type Finished = (u64, i32, Vec<u8>);
+
+#[derive(Default)]
+pub(crate) struct CommandQueue {
+    queued: VecDeque<(u64, Vec<u8>)>,
+    running: Option<Running>,
+    /// The shell is at a prompt and ready for input.
+    at_prompt: bool,
+    next_id: u64,
+}
+
+impl CommandQueue {
+    fn push(&mut self, command: &[u8]) -> u64 {
+        self.next_id += 1;
+        let mut line = command.to_vec();
+        line.push(b'\r');
+        self.queued.push_back((self.next_id, line));
+        self.next_id
+    }
+
+    /// Start the next command if the shell is ready, returning the bytes
+    /// to write.
+    fn dispatch(&mut self) -> Option<Vec<u8>> {
+        if !self.at_prompt || self.running.is_some() {
+            return None;
+        }
+        let (id, line) = self.queued.pop_front()?;
+        self.at_prompt = false;
+        self.running = Some(Running {
+            id,
+            output: Vec::new(),
+        });
+        Some(line)
+    }
+
+    fn capture(&mut self, bytes: &[u8]) {
+        if let Some(running) = self.running.as_mut() {
+            let room = MAX_CAPTURE.saturating_sub(running.output.len());
+            running
+                .output
+                .extend_from_slice(&bytes[..bytes.len().min(room)]);
+        }
+    }
+
+    fn finish(&mut self, status: i32, finished: &mut Vec<Finished>) {
+        if let Some(running) = self.running.take() {
+            finished.push((running.id, status, running.output));
+        }
+    }
+
+    /// Process a chunk of output and the prompt marks found in it.
+    ///
+    /// Returns the commands that finished and, if one should start now,
+    /// the bytes to write to the child.
+    pub(crate) fn observe(
+        &mut self,
+        bytes: &[u8],
+        marks: &[MarkAt],
+    ) -> (Vec<Finished>, Option<Vec<u8>>) {
+        let mut finished = Vec::new();
+        let mut pos = 0;
+        for m in marks {
+            self.capture(&bytes[pos.min(m.start)..m.start]);
+            pos = m.end;
+            match m.mark {
+                PromptMark::PromptStart | PromptMark::CommandStart => {
+                    // A prompt without a `D` mark still ends the command.
+                    self.finish(-1, &mut finished);
+                    self.at_prompt = true;
+                }
+                PromptMark::OutputStart => {
+                    // Drop the echoed command line.
+                    if let Some(running) = self.running.as_mut() {
+                        running.output.clear();
+                    }
+                }
+                PromptMark::CommandEnd(status) => {
+                    self.finish(status.unwrap_or(-1), &mut finished);
+                }
+            }
+        }
+        if !self.at_prompt {
+            self.capture(&bytes[pos.min(bytes.len())..]);
+        }
+        (finished, self.dispatch())
+    }
+}
+
+/// Queue a shell command to run after the currently running one finishes.
+///
+/// `command` is written followed by a carriage return once the shell is at
+/// a prompt. The assigned ID is written to `*out_id` and reported in the
+/// command's `PORTABLE_PTY_EVENT_COMMAND_DONE` event (`value` = exit status
+/// or -1 if unknown, `data` = captured output).
+///
+/// Sequencing is driven by output read from the handle, so the embedder
+/// must keep reading. Requires a shell that emits OSC 133 prompt marks.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_queue_command(
+    handle: *const PortablePty,
+    command: *const c_char,
+    out_id: *mut u64,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        if command.is_null() || out_id.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+        let command = unsafe { CStr::from_ptr(command) }.to_bytes();
+
+        let (id, line) = {
+            let mut queue = pty.commands.lock().unwrap_or_else(PoisonError::into_inner);
+            let id = queue.push(command);
+            (id, queue.dispatch())
+        };
+        unsafe {
+            *out_id = id;
+        }
+
+        if let Some(line) = line {
+            if pty.write_input(&line).is_err() {
+                return PortablePtyResult::ErrWrite;
+            }
+        }
+        PortablePtyResult::Ok
+    })
+}
+
+/// Drop all queued commands that haven't started yet. A command that is
+/// already running is unaffected.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_clear_command_queue(
+    handle: *const PortablePty,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+
+        pty.commands
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .queued
+            .clear();
+        PortablePtyResult::Ok
+    })
+}
+
+/// Feed output and marks to the queue, posting completions and writing the
+/// next command if one is due.
+pub(crate) fn observe(pty: &PortablePty, bytes: &[u8], marks: &[MarkAt]) {
+    let (finished, line) = pty
+        .commands
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .observe(bytes, marks);
+
+    for (id, status, output) in finished {
+        pty.events.post(
+            PORTABLE_PTY_EVENT_COMMAND_DONE,
+            id,
+            i64::from(status),
+            output,
+        );
+    }
+    if let Some(line) = line {
+        let _ = pty.write_input(&line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modes::ModeTracker;
+
+    fn feed(
+        queue: &mut CommandQueue,
+        tracker: &mut ModeTracker,
+        bytes: &[u8],
+    ) -> (Vec<Finished>, Option<Vec<u8>>) {
+        let marks = tracker.feed(bytes);
+        queue.observe(bytes, &marks)
+    }
+
+    #[test]
+    fn test_runs_commands_in_sequence() {
+        let mut queue = CommandQueue::default();
+        let mut tracker = ModeTracker::default();
+
+        let first = queue.push(b"make");
+        let second = queue.push(b"make test");
+        assert_eq!(queue.dispatch(), None, "no prompt seen yet");
+
+        let (done, line) = feed(&mut queue, &mut tracker, b"\x1b]133;A\x07$ ");
+        assert!(done.is_empty());
+        assert_eq!(line.as_deref(), Some(&b"make\r"[..]));
+
+        let (done, line) = feed(
+            &mut queue,
+            &mut tracker,
+            b"make\r\n\x1b]133;C\x07built\r\n\x1b]133;D;0\x07",
+        );
+        assert_eq!(done, vec![(first, 0, b"built\r\n".to_vec())]);
+        assert_eq!(line, None, "waits for the next prompt");
+
+        let (_, line) = feed(&mut queue, &mut tracker, b"\x1b]133;A\x07$ ");
+        assert_eq!(line.as_deref(), Some(&b"make test\r"[..]));
+
+        // No C mark: capture starts at the write and keeps the echo.
+        let (done, _) = feed(
+            &mut queue,
+            &mut tracker,
+            b"make test\r\nfail\r\n\x1b]133;D;2\x07",
+        );
+        assert_eq!(done, vec![(second, 2, b"make test\r\nfail\r\n".to_vec())]);
+    }
+}
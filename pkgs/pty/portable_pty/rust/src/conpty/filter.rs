@@ -0,0 +1,813 @@
+//! Removing the output ConPTY adds on its own.
+//!
+//! ConPTY doesn't pass the child's output through: it renders the console
+//! and sends what changed. On the way it adds sequences the child never
+//! wrote: requests for its own input modes, a window title naming the
+//! executable, a clear screen before the first prompt, cursor hiding
+//! around every frame, and whole-screen repaints that redraw what is
+//! already there. A terminal shrugs them off, but they bloat recordings
+//! and look like changes to renderers that diff the stream.
+//!
+//! `portable_pty_set_conpty_filter` takes the `PORTABLE_PTY_CONPTY_FILTER_*`
+//! flags saying which to remove from the handle's output before anything
+//! sees it: reads, recordings, matchers and everything else built on them.
+//! Nothing is filtered by default. The filter knows nothing about Windows,
+//! so it works on any handle; replaying a recording made on Windows, say.
+//!
+//! Sequences split across reads are held back until they're complete.
+//! Repaints are found by keeping a copy of the screen: a read that only
+//! rewrites cells with the characters and attributes they already have,
+//! and leaves the cursor and attributes as they were, is dropped. The
+//! copy starts out unknown, and after anything it doesn't model (scroll
+//! regions, the alternate screen, a resize) it's unknown again until
+//! output has covered the whole screen; ConPTY's next full repaint does.
+//! Only then can reads be dropped.
+
+use crate::{PortablePty, PortablePtyResult};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read};
+use std::mem;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use unicode_width::UnicodeWidthChar;
+
+/// ConPTY's requests for its own input modes: win32-input-mode (`CSI ?
+/// 9001 h`) and focus events (`CSI ? 1004 h`).
+pub const PORTABLE_PTY_CONPTY_FILTER_MODES: u32 = 1 << 0;
+/// Window titles (`OSC 0`, `1` and `2`), the executable's path included.
+pub const PORTABLE_PTY_CONPTY_FILTER_TITLE: u32 = 1 << 1;
+/// Cursor hiding and showing, reported once per read as it stands at the
+/// end of it, rather than around every frame.
+pub const PORTABLE_PTY_CONPTY_FILTER_CURSOR: u32 = 1 << 2;
+/// Clearing the screen and homing the cursor before the child has written
+/// anything.
+pub const PORTABLE_PTY_CONPTY_FILTER_CLEAR: u32 = 1 << 3;
+/// Reads that repaint the screen without changing it.
+pub const PORTABLE_PTY_CONPTY_FILTER_REPAINT: u32 = 1 << 4;
+/// All of the above.
+pub const PORTABLE_PTY_CONPTY_FILTER_ALL: u32 = (1 << 5) - 1;
+
+/// An incomplete sequence longer than this is passed on as it is.
+const MAX_HELD: usize = 64 * 1024;
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// One piece of output.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Token<'a> {
+    /// Characters and C0 controls.
+    Text(&'a [u8]),
+    /// `CSI params intermediates final`; `private` is a leading `?`, `<`,
+    /// `=` or `>`.
+    Csi {
+        private: Option<u8>,
+        params: &'a [u8],
+        intermediates: bool,
+        action: u8,
+    },
+    /// An OSC string, without its introducer and terminator.
+    Osc(&'a [u8]),
+    /// A DCS, SOS, PM or APC string, introduced by `ESC kind`.
+    Control(u8),
+    /// Any other escape sequence: its final byte.
+    Esc(u8),
+}
+
+/// Split `data` into tokens and their bytes. Returns where the incomplete
+/// tail, if any, starts.
+fn tokenize(data: &[u8], mut emit: impl FnMut(Token, &[u8])) -> usize {
+    let mut i = 0;
+    while i < data.len() {
+        let Some(len) = token_len(&data[i..]) else {
+            return i;
+        };
+        let bytes = &data[i..i + len];
+        emit(classify(bytes), bytes);
+        i += len;
+    }
+    i
+}
+
+/// The length of the token at the start of `data`, or None if it isn't
+/// complete yet.
+fn token_len(data: &[u8]) -> Option<usize> {
+    if data[0] != 0x1b {
+        let end = data.iter().position(|&b| b == 0x1b).unwrap_or(data.len());
+        if end < data.len() {
+            return Some(end);
+        }
+        // Hold back a character split across reads.
+        return match std::str::from_utf8(data) {
+            Err(e) if e.error_len().is_none() => match e.valid_up_to() {
+                0 => None,
+                valid => Some(valid),
+            },
+            _ => Some(end),
+        };
+    }
+    let kind = *data.get(1)?;
+    match kind {
+        b'[' => {
+            for (i, &b) in data.iter().enumerate().skip(2) {
+                match b {
+                    0x20..=0x3f => {}
+                    0x40..=0x7e => return Some(i + 1),
+                    // Not a valid sequence: pass on what there is of it.
+                    _ => return Some(i),
+                }
+            }
+            None
+        }
+        b']' | b'P' | b'X' | b'^' | b'_' => {
+            for (i, &b) in data.iter().enumerate().skip(2) {
+                match b {
+                    0x07 if kind == b']' => return Some(i + 1),
+                    0x1b => {
+                        let next = *data.get(i + 1)?;
+                        // A string is ended by ST, or cut short by another
+                        // sequence.
+                        return Some(if next == b'\\' { i + 2 } else { i });
+                    }
+                    _ => {}
+                }
+            }
+            None
+        }
+        _ => {
+            for (i, &b) in data.iter().enumerate().skip(1) {
+                match b {
+                    0x20..=0x2f => {}
+                    0x30..=0x7e => return Some(i + 1),
+                    _ => return Some(i),
+                }
+            }
+            None
+        }
+    }
+}
+
+fn classify(bytes: &[u8]) -> Token<'_> {
+    if bytes[0] != 0x1b {
+        return Token::Text(bytes);
+    }
+    let body = &bytes[2.min(bytes.len())..];
+    match bytes.get(1) {
+        Some(b'[') => {
+            let Some((&action, rest)) = body.split_last() else {
+                return Token::Esc(b'[');
+            };
+            if !(0x40..=0x7e).contains(&action) {
+                return Token::Esc(b'[');
+            }
+            let (private, rest) = match rest.first() {
+                Some(&p @ (b'?' | b'<' | b'=' | b'>')) => (Some(p), &rest[1..]),
+                _ => (None, rest),
+            };
+            let split = rest
+                .iter()
+                .position(|b| (0x20..=0x2f).contains(b))
+                .unwrap_or(rest.len());
+            Token::Csi {
+                private,
+                params: &rest[..split],
+                intermediates: split < rest.len(),
+                action,
+            }
+        }
+        Some(b']') => {
+            let body = body
+                .strip_suffix(b"\x07")
+                .or_else(|| body.strip_suffix(b"\x1b\\"))
+                .unwrap_or(body);
+            Token::Osc(body)
+        }
+        Some(&kind @ (b'P' | b'X' | b'^' | b'_')) => Token::Control(kind),
+        _ => Token::Esc(*bytes.last().unwrap_or(&0)),
+    }
+}
+
+/// Numeric parameters; an empty one is None.
+fn params(params: &[u8]) -> impl Iterator<Item = Option<usize>> + '_ {
+    params.split(|&b| b == b';').map(|p| {
+        std::str::from_utf8(p)
+            .ok()
+            .and_then(|p| p.split(':').next()?.parse().ok())
+    })
+}
+
+/// Parameter `i`, with `default` when missing or zero.
+fn param(list: &[u8], i: usize, default: usize) -> usize {
+    match params(list).nth(i).flatten() {
+        Some(0) | None => default,
+        Some(n) => n,
+    }
+}
+
+/// Private modes ConPTY sets for itself.
+fn is_conpty_mode(list: &[u8]) -> bool {
+    params(list).all(|p| matches!(p, Some(1004 | 9001)))
+}
+
+/// `CSI ? 25 h` or `l`: whether it shows the cursor.
+fn cursor_visibility(token: &Token) -> Option<bool> {
+    match *token {
+        Token::Csi {
+            private: Some(b'?'),
+            params: b"25",
+            intermediates: false,
+            action,
+        } if action == b'h' || action == b'l' => Some(action == b'h'),
+        _ => None,
+    }
+}
+
+/// A cell: its character and a fingerprint of its attributes.
+type Cell = (char, u64);
+
+/// Cell after a double-width character.
+const WIDE_TAIL: char = '\0';
+
+/// How a token bears on the copy of the screen.
+#[derive(PartialEq)]
+enum Bearing {
+    /// Only touches cells and the cursor, which the copy follows.
+    Grid,
+    /// Changes nothing on the grid, but isn't nothing either.
+    Effect,
+    /// Changes the grid in a way the copy doesn't follow.
+    Unknown,
+}
+
+/// The screen as the embedder was last sent it.
+struct Shadow {
+    rows: usize,
+    cols: usize,
+    cells: Vec<Cell>,
+    row: usize,
+    col: usize,
+    wrap_pending: bool,
+    /// Attributes new characters get: a fingerprint of every SGR since the
+    /// last reset. Equal styles may differ, which only means a read isn't
+    /// dropped.
+    style: u64,
+    /// The cells match the embedder's screen.
+    synced: bool,
+    /// For the read in progress: a cell changed.
+    changed: bool,
+    /// For the read in progress: something besides the grid happened.
+    effect: bool,
+    /// For the read in progress, while not synced: cells written.
+    covered: Vec<bool>,
+}
+
+impl Shadow {
+    fn new(rows: usize, cols: usize) -> Shadow {
+        let (rows, cols) = (rows.max(1), cols.max(1));
+        Shadow {
+            rows,
+            cols,
+            cells: vec![(' ', 0); rows * cols],
+            row: 0,
+            col: 0,
+            wrap_pending: false,
+            style: 0,
+            synced: false,
+            changed: false,
+            effect: false,
+            covered: Vec::new(),
+        }
+    }
+
+    fn set(&mut self, index: usize, cell: Cell) {
+        if self.cells[index] != cell {
+            self.changed = true;
+            self.cells[index] = cell;
+        }
+        if let Some(covered) = self.covered.get_mut(index) {
+            *covered = true;
+        }
+    }
+
+    /// Blank `start..end` of the cells.
+    fn erase(&mut self, start: usize, end: usize) {
+        for index in start..end.min(self.cells.len()) {
+            self.set(index, (' ', self.style));
+        }
+    }
+
+    fn linefeed(&mut self) {
+        if self.row + 1 < self.rows {
+            self.row += 1;
+            return;
+        }
+        self.changed = true;
+        self.cells.drain(..self.cols);
+        let blank = (' ', self.style);
+        self.cells.extend(std::iter::repeat_n(blank, self.cols));
+        if !self.covered.is_empty() {
+            self.covered.drain(..self.cols);
+            self.covered.extend(std::iter::repeat_n(false, self.cols));
+        }
+    }
+
+    fn print(&mut self, c: char) {
+        let width = match c.width() {
+            Some(width @ 1..=2) => width,
+            // Combining marks and the like alter a cell the copy can't
+            // represent.
+            _ => {
+                self.changed = true;
+                return;
+            }
+        };
+        if self.wrap_pending || self.col + width > self.cols {
+            self.col = 0;
+            self.linefeed();
+        }
+        self.wrap_pending = false;
+        let index = self.row * self.cols + self.col;
+        self.set(index, (c, self.style));
+        if width == 2 && self.col + 1 < self.cols {
+            self.set(index + 1, (WIDE_TAIL, self.style));
+        }
+        if self.col + width >= self.cols {
+            self.col = self.cols - 1;
+            self.wrap_pending = true;
+        } else {
+            self.col += width;
+        }
+    }
+
+    fn text(&mut self, bytes: &[u8]) -> Bearing {
+        let mut bearing = Bearing::Grid;
+        for c in String::from_utf8_lossy(bytes).chars() {
+            match c {
+                '\r' => {
+                    self.col = 0;
+                    self.wrap_pending = false;
+                }
+                '\n' | '\x0b' | '\x0c' => {
+                    self.linefeed();
+                    self.wrap_pending = false;
+                }
+                '\x08' => {
+                    self.col = self.col.saturating_sub(1);
+                    self.wrap_pending = false;
+                }
+                '\t' => self.col = ((self.col / 8 + 1) * 8).min(self.cols - 1),
+                '\x07' => bearing = Bearing::Effect,
+                '\0'..='\x1f' | '\x7f' => {}
+                c => self.print(c),
+            }
+        }
+        bearing
+    }
+
+    fn csi(&mut self, params: &[u8], action: u8) -> Bearing {
+        let n = param(params, 0, 1);
+        let (rows, cols) = (self.rows, self.cols);
+        let line = self.row * cols;
+        let cursor = line + self.col;
+        match action {
+            b'H' | b'f' => {
+                self.row = (n - 1).min(rows - 1);
+                self.col = (param(params, 1, 1) - 1).min(cols - 1);
+            }
+            b'A' => self.row = self.row.saturating_sub(n),
+            b'B' => self.row = self.row.saturating_add(n).min(rows - 1),
+            b'C' => self.col = self.col.saturating_add(n).min(cols - 1),
+            b'D' => self.col = self.col.saturating_sub(n),
+            b'E' | b'F' => {
+                self.row = match action {
+                    b'E' => self.row.saturating_add(n).min(rows - 1),
+                    _ => self.row.saturating_sub(n),
+                };
+                self.col = 0;
+            }
+            b'G' | b'`' => self.col = (n - 1).min(cols - 1),
+            b'd' => self.row = (n - 1).min(rows - 1),
+            b'K' => {
+                match param(params, 0, 0) {
+                    0 => self.erase(cursor, line + cols),
+                    1 => self.erase(line, cursor + 1),
+                    2 => self.erase(line, line + cols),
+                    _ => {}
+                }
+                return Bearing::Grid;
+            }
+            b'J' => {
+                match param(params, 0, 0) {
+                    0 => self.erase(cursor, rows * cols),
+                    1 => self.erase(0, cursor + 1),
+                    2 => self.erase(0, rows * cols),
+                    _ => return Bearing::Effect,
+                }
+                return Bearing::Grid;
+            }
+            b'X' => {
+                self.erase(cursor, cursor + n.min(cols - self.col));
+                return Bearing::Grid;
+            }
+            b'm' => {
+                self.style = match params {
+                    b"" | b"0" => 0,
+                    _ => {
+                        let mut hasher = DefaultHasher::new();
+                        (self.style, params).hash(&mut hasher);
+                        hasher.finish()
+                    }
+                };
+                return Bearing::Grid;
+            }
+            // Reports and window operations.
+            b'c' | b'n' | b't' => return Bearing::Effect,
+            _ => return Bearing::Unknown,
+        }
+        self.wrap_pending = false;
+        Bearing::Grid
+    }
+
+    fn apply(&mut self, token: &Token) -> Bearing {
+        match *token {
+            Token::Text(bytes) => self.text(bytes),
+            Token::Csi {
+                private: None,
+                intermediates: false,
+                params,
+                action,
+            } => self.csi(params, action),
+            Token::Csi {
+                private: Some(b'?'),
+                params,
+                ..
+            } => match params {
+                b"25" => Bearing::Grid,
+                // Cursor blinking and ConPTY's own modes.
+                _ if params == b"12" || is_conpty_mode(params) => Bearing::Effect,
+                _ => Bearing::Unknown,
+            },
+            // Cursor style and other settings.
+            Token::Csi { .. } => Bearing::Effect,
+            // Hyperlinks are attributes of cells.
+            Token::Osc(body) if body.starts_with(b"8;") => Bearing::Unknown,
+            Token::Osc(_) | Token::Control(b'X' | b'^' | b'_') => Bearing::Effect,
+            // Keypad modes.
+            Token::Esc(b'=' | b'>') => Bearing::Effect,
+            Token::Control(_) | Token::Esc(_) => Bearing::Unknown,
+        }
+    }
+
+    /// Start following a read.
+    fn begin(&mut self) -> (usize, usize, bool, u64) {
+        self.changed = false;
+        self.effect = false;
+        self.covered.clear();
+        if !self.synced {
+            self.covered.resize(self.cells.len(), false);
+        }
+        (self.row, self.col, self.wrap_pending, self.style)
+    }
+
+    fn follow(&mut self, token: &Token) {
+        match self.apply(token) {
+            Bearing::Grid => {}
+            Bearing::Effect => self.effect = true,
+            Bearing::Unknown => {
+                self.synced = false;
+                self.effect = true;
+                self.covered.clear();
+            }
+        }
+    }
+
+    /// Finish following a read that started at `start`. True if it left
+    /// the screen as it was.
+    fn end(&mut self, start: (usize, usize, bool, u64)) -> bool {
+        let unchanged = self.synced
+            && !self.changed
+            && !self.effect
+            && start == (self.row, self.col, self.wrap_pending, self.style);
+        if !self.synced && !self.covered.is_empty() && self.covered.iter().all(|&c| c) {
+            self.synced = true;
+        }
+        unchanged
+    }
+}
+
+/// What the filter keeps between reads.
+#[derive(Default)]
+struct State {
+    /// The incomplete tail of the last read.
+    held: Vec<u8>,
+    /// Filtered output not yet handed out.
+    ready: Vec<u8>,
+    /// The child has shown something; it's no longer ConPTY's own clear.
+    started: bool,
+    /// Whether the cursor is visible as the child last set it.
+    visible: Option<bool>,
+    /// And as the embedder was last told.
+    shown: Option<bool>,
+    /// The screen, while repaints are filtered.
+    shadow: Option<Shadow>,
+}
+
+impl State {
+    fn filter(&mut self, input: &[u8], flags: u32, size: (u16, u16)) {
+        let has = |flag| flags & flag != 0;
+        if !has(PORTABLE_PTY_CONPTY_FILTER_REPAINT) {
+            self.shadow = None;
+        } else if self.shadow.is_none() {
+            self.shadow = Some(Shadow::new(size.0.into(), size.1.into()));
+        }
+        let mut data = mem::take(&mut self.held);
+        data.extend_from_slice(input);
+
+        let mut out = Vec::with_capacity(data.len());
+        let mut shadow = self.shadow.take();
+        let start = shadow.as_mut().map(Shadow::begin);
+        let shown = self.shown;
+        let tail = tokenize(&data, |token, bytes| {
+            let visibility = cursor_visibility(&token);
+            if visibility.is_some() {
+                self.visible = visibility;
+            }
+            let drop = (visibility.is_some() && has(PORTABLE_PTY_CONPTY_FILTER_CURSOR))
+                || match token {
+                    Token::Csi {
+                        private: Some(b'?'),
+                        params,
+                        action: b'h' | b'l',
+                        ..
+                    } if has(PORTABLE_PTY_CONPTY_FILTER_MODES) => is_conpty_mode(params),
+                    Token::Osc(body) if has(PORTABLE_PTY_CONPTY_FILTER_TITLE) => {
+                        matches!(body.split(|&b| b == b';').next(), Some(b"0" | b"1" | b"2"))
+                    }
+                    Token::Csi {
+                        private: None,
+                        params,
+                        action,
+                        ..
+                    } if has(PORTABLE_PTY_CONPTY_FILTER_CLEAR) && !self.started => match action {
+                        b'J' => true,
+                        b'H' | b'f' => param(params, 0, 1) == 1 && param(params, 1, 1) == 1,
+                        _ => false,
+                    },
+                    _ => false,
+                };
+            if drop {
+                return;
+            }
+            if let Token::Text(text) = token {
+                self.started |= text.iter().any(|&b| b >= 0x20 && b != 0x7f);
+            }
+            if let Some(shadow) = shadow.as_mut() {
+                shadow.follow(&token);
+            }
+            out.extend_from_slice(bytes);
+        });
+        if tail < data.len() && data.len() - tail <= MAX_HELD {
+            self.held = data[tail..].to_vec();
+        } else {
+            out.extend_from_slice(&data[tail..]);
+        }
+
+        if has(PORTABLE_PTY_CONPTY_FILTER_CURSOR) && self.visible != self.shown {
+            if let Some(visible) = self.visible {
+                out.extend_from_slice(match visible {
+                    true => b"\x1b[?25h",
+                    false => b"\x1b[?25l",
+                });
+            }
+        }
+        let unchanged = match (shadow.as_mut(), start) {
+            (Some(shadow), Some(start)) => shadow.end(start),
+            _ => false,
+        };
+        self.shadow = shadow;
+        // Dropped only if the embedder's cursor is still right, too.
+        if unchanged && self.visible == shown {
+            return;
+        }
+        self.shown = self.visible;
+        self.ready.append(&mut out);
+    }
+}
+
+/// The handle's output filter.
+#[derive(Default)]
+pub(crate) struct OutputFilter {
+    flags: AtomicU32,
+    state: Mutex<State>,
+    /// `state.ready` has output, for checking without the lock a blocked
+    /// read holds.
+    buffered: AtomicBool,
+    /// The size the next read sees the screen at; None if unchanged.
+    resized: Mutex<Option<(u16, u16)>>,
+}
+
+impl OutputFilter {
+    pub(crate) fn set_flags(&self, flags: u32, size: (u16, u16)) {
+        *lock(&self.resized) = Some(size);
+        self.flags.store(flags, Ordering::Relaxed);
+    }
+
+    /// The screen is now `size`. The copy of it starts over.
+    pub(crate) fn resize(&self, size: (u16, u16)) {
+        *lock(&self.resized) = Some(size);
+    }
+
+    /// Filtered output is waiting to be read.
+    pub(crate) fn has_buffered(&self) -> bool {
+        self.buffered.load(Ordering::Relaxed)
+    }
+
+    /// Read from `reader` through the filter.
+    pub(crate) fn read(&self, reader: &mut dyn Read, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = lock(&self.state);
+        read(
+            &mut state,
+            &self.flags,
+            &self.buffered,
+            &self.resized,
+            reader,
+            buf,
+        )
+    }
+
+    /// `read`, without taking the lock the caller's exclusive access makes
+    /// unnecessary.
+    pub(crate) fn read_exclusive(
+        &mut self,
+        reader: &mut dyn Read,
+        buf: &mut [u8],
+    ) -> io::Result<usize> {
+        let state = self.state.get_mut().unwrap_or_else(PoisonError::into_inner);
+        read(
+            state,
+            &self.flags,
+            &self.buffered,
+            &self.resized,
+            reader,
+            buf,
+        )
+    }
+}
+
+fn read(
+    state: &mut State,
+    flags: &AtomicU32,
+    buffered: &AtomicBool,
+    resized: &Mutex<Option<(u16, u16)>>,
+    reader: &mut dyn Read,
+    buf: &mut [u8],
+) -> io::Result<usize> {
+    loop {
+        if !state.ready.is_empty() {
+            let n = state.ready.len().min(buf.len());
+            buf[..n].copy_from_slice(&state.ready[..n]);
+            state.ready.drain(..n);
+            buffered.store(!state.ready.is_empty(), Ordering::Relaxed);
+            return Ok(n);
+        }
+        let flags = flags.load(Ordering::Relaxed);
+        if flags == 0 && state.held.is_empty() {
+            return reader.read(buf);
+        }
+        let n = match reader.read(buf) {
+            Ok(0) | Err(_) if !state.held.is_empty() => {
+                // Nothing will complete it now.
+                let held = mem::take(&mut state.held);
+                state.ready = held;
+                continue;
+            }
+            result => result?,
+        };
+        if n == 0 {
+            return Ok(0);
+        }
+        let resized = lock(resized).take();
+        if let Some((rows, cols)) = resized {
+            if let Some(shadow) = state.shadow.as_mut() {
+                *shadow = Shadow::new(rows.into(), cols.into());
+            }
+        }
+        let size = resized.unwrap_or((24, 80));
+        state.filter(&buf[..n], flags, size);
+        buffered.store(!state.ready.is_empty(), Ordering::Relaxed);
+    }
+}
+
+/// Choose which of ConPTY's own sequences to remove from the output.
+///
+/// - `flags`: `PORTABLE_PTY_CONPTY_FILTER_*` bits; 0 turns filtering off.
+///
+/// Takes effect from the next read. Output already filtered, or held back
+/// as an incomplete sequence, is still delivered in order.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_set_conpty_filter(
+    handle: *mut PortablePty,
+    flags: u32,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        let size = pty
+            .master
+            .get_size()
+            .map_or((24, 80), |size| (size.rows, size.cols));
+        pty.output_filter.set_flags(flags, size);
+        PortablePtyResult::Ok
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(flags: u32, reads: &[&[u8]]) -> Vec<Vec<u8>> {
+        let mut state = State::default();
+        reads
+            .iter()
+            .map(|read| {
+                state.filter(read, flags, (2, 10));
+                mem::take(&mut state.ready)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_removes_conpty_sequences() {
+        let startup =
+            b"\x1b[?9001h\x1b[?1004h\x1b[?25l\x1b[2J\x1b[m\x1b[H\x1b]0;C:\\cmd.exe\x07\x1b[?25h";
+        let out = filter(
+            PORTABLE_PTY_CONPTY_FILTER_ALL & !PORTABLE_PTY_CONPTY_FILTER_REPAINT,
+            &[
+                startup,
+                b"\x1b[?25lC:\\>\x1b[?25h",
+                b"\x1b[?25l\x1b[2Jcleared\x1b[?25h",
+            ],
+        );
+        assert_eq!(out[0], b"\x1b[m\x1b[?25h");
+        assert_eq!(out[1], b"C:\\>");
+        // The child's own clear stays.
+        assert_eq!(out[2], b"\x1b[2Jcleared");
+
+        let out = filter(0, &[startup]);
+        assert_eq!(out[0], startup);
+    }
+
+    #[test]
+    fn test_holds_split_sequences() {
+        let out = filter(
+            PORTABLE_PTY_CONPTY_FILTER_TITLE,
+            &[b"a\x1b]0;ti", b"tle\x07b\xe2\x82", b"\xac"],
+        );
+        assert_eq!(out, [&b"a"[..], b"b", "€".as_bytes()]);
+    }
+
+    #[test]
+    fn test_drops_repaints_that_change_nothing() {
+        let repaint = b"\x1b[Hhello\x1b[K\r\nworld\x1b[K\x1b[2;6H";
+        let out = filter(
+            PORTABLE_PTY_CONPTY_FILTER_REPAINT,
+            &[
+                b"hello\r\nworld",
+                // Not known to match the screen until it's covered once.
+                repaint,
+                repaint,
+                b"\x1b[1;1H\x1b[31mhello\x1b[m\x1b[2;6H",
+                b"\x1b[?1049h",
+                repaint,
+            ],
+        );
+        assert_eq!(out[0], b"hello\r\nworld");
+        assert_eq!(out[1], repaint);
+        assert!(out[2].is_empty());
+        // New colors are a change.
+        assert!(!out[3].is_empty());
+        assert_eq!(out[4], b"\x1b[?1049h");
+        assert_eq!(out[5], repaint);
+    }
+
+    #[test]
+    fn test_filters_handle_output() {
+        use crate::loopback::{portable_pty_loopback_write, portable_pty_open_loopback};
+
+        let mut handle = std::ptr::null_mut();
+        portable_pty_open_loopback(24, 80, &mut handle);
+        let flags = PORTABLE_PTY_CONPTY_FILTER_TITLE;
+        assert!(matches!(
+            portable_pty_set_conpty_filter(handle, flags),
+            PortablePtyResult::Ok
+        ));
+        let output = b"\x1b]0;C:\\cmd.exe\x07C:\\>";
+        portable_pty_loopback_write(handle, output.as_ptr(), output.len());
+        assert_eq!(crate::tests::read_string(handle), "C:\\>");
+        crate::portable_pty_close(handle);
+    }
+}
@@ -0,0 +1,184 @@
+//! Windows consoles: ConPTY with caller-chosen creation flags, and WinPTY
+//! where there is no ConPTY.
+//!
+//! portable-pty creates every pseudo console with the same flags. That
+//! suits a terminal that starts a fresh session, but an embedder taking
+//! over an existing console needs `PORTABLE_PTY_CONPTY_INHERIT_CURSOR` or
+//! the first repaint jumps the cursor to the top left, and one driving
+//! programs that read input records may not want Win32 input mode.
+//! `portable_pty_open_conpty` creates the console itself with exactly the
+//! flags it's given, as does the `native` backend when its config has a
+//! `conpty_flags` number. Bits without a constant here are passed through,
+//! so flags added to later Windows releases work as soon as the system
+//! knows them; a system that doesn't rejects them and the open fails with
+//! `ErrOpen`.
+//!
+//! With `PORTABLE_PTY_CONPTY_INHERIT_CURSOR`, the console asks where the
+//! cursor is (`ESC [ 6 n`) before writing anything and waits for the
+//! reply. Answer it with `ESC [ row ; col R` on the handle, or the child's
+//! output never arrives.
+//!
+//! ConPTY needs Windows 10 1809 or later. A `conpty.dll` next to the
+//! application is preferred over the system's, as portable-pty does.
+//!
+//! Where there's no ConPTY, `portable_pty_open` and the `native` backend
+//! fall back to WinPTY instead of failing, if the application ships
+//! `winpty.dll` and `winpty-agent.exe`; creation flags don't apply to it.
+//! The `winpty` backend opens one on any Windows, for testing. Without
+//! either, opening returns `ErrUnsupported`.
+
+pub mod filter;
+#[cfg(windows)]
+mod win;
+#[cfg(windows)]
+mod winpty;
+
+use crate::pty::PtySize;
+use crate::{PortablePty, PortablePtyResult};
+
+/// Start the cursor where the embedder's terminal has it, instead of at
+/// the top left. The console then asks where that is (`ESC [ 6 n`) before
+/// writing anything: answer with `ESC [ row ; col R` on the handle, or the
+/// child's output never arrives.
+pub const PORTABLE_PTY_CONPTY_INHERIT_CURSOR: u32 = 0x1;
+/// Don't repaint the whole screen on every resize.
+pub const PORTABLE_PTY_CONPTY_RESIZE_QUIRK: u32 = 0x2;
+/// Deliver key events to the child as Win32 input records.
+pub const PORTABLE_PTY_CONPTY_WIN32_INPUT_MODE: u32 = 0x4;
+/// Pass the child's VT output through unrendered. Needs Windows 11 24H2
+/// or a newer `conpty.dll`.
+pub const PORTABLE_PTY_CONPTY_PASSTHROUGH_MODE: u32 = 0x8;
+/// The flags `portable_pty_open` creates its console with.
+pub const PORTABLE_PTY_CONPTY_DEFAULT: u32 = PORTABLE_PTY_CONPTY_INHERIT_CURSOR
+    | PORTABLE_PTY_CONPTY_RESIZE_QUIRK
+    | PORTABLE_PTY_CONPTY_WIN32_INPUT_MODE;
+
+/// Whether this system has ConPTY. False off Windows.
+pub(crate) fn available() -> bool {
+    #[cfg(windows)]
+    {
+        win::available()
+    }
+    #[cfg(not(windows))]
+    {
+        false
+    }
+}
+
+/// Open a pseudo console of `size` created with `flags`, with no child
+/// yet.
+#[cfg(windows)]
+pub(crate) fn open(size: PtySize, flags: u32) -> Result<Box<PortablePty>, PortablePtyResult> {
+    wrap(win::openpty(size, flags))
+}
+
+/// Open a WinPTY console of `size`, with no child yet.
+#[cfg(windows)]
+pub(crate) fn open_winpty(size: PtySize) -> Result<Box<PortablePty>, PortablePtyResult> {
+    wrap(winpty::openpty(size))
+}
+
+/// Open a console as `portable_pty_open` does: ConPTY with the default
+/// flags, or WinPTY without it. For builds without portable-pty.
+#[cfg(all(windows, not(feature = "portable-pty")))]
+pub(crate) fn openpty(size: PtySize) -> std::io::Result<crate::pty::PtyPair> {
+    match win::available() {
+        true => win::openpty(size, PORTABLE_PTY_CONPTY_DEFAULT),
+        false => winpty::openpty(size),
+    }
+}
+
+/// A process started outside a console, as a handle's child.
+#[cfg(windows)]
+pub(crate) fn adopt(child: std::process::Child) -> Box<dyn crate::pty::Child + Send + Sync> {
+    let pid = child.id();
+    Box::new(win::Process::new(child.into(), pid))
+}
+
+#[cfg(windows)]
+fn wrap(pair: std::io::Result<crate::pty::PtyPair>) -> Result<Box<PortablePty>, PortablePtyResult> {
+    let pair = pair.map_err(|e| match e.kind() {
+        std::io::ErrorKind::Unsupported => PortablePtyResult::ErrUnsupported,
+        _ => PortablePtyResult::ErrOpen,
+    })?;
+    PortablePty::from_pair(pair)
+}
+
+#[cfg(not(windows))]
+pub(crate) fn open(_size: PtySize, _flags: u32) -> Result<Box<PortablePty>, PortablePtyResult> {
+    Err(PortablePtyResult::ErrUnsupported)
+}
+
+#[cfg(not(windows))]
+pub(crate) fn open_winpty(_size: PtySize) -> Result<Box<PortablePty>, PortablePtyResult> {
+    Err(PortablePtyResult::ErrUnsupported)
+}
+
+/// Open a PTY backed by a pseudo console created with `flags`.
+///
+/// - `flags`: `PORTABLE_PTY_CONPTY_*` bits, or others the system knows.
+///   `PORTABLE_PTY_CONPTY_DEFAULT` gives what `portable_pty_open` does.
+/// - `out`: receives the new handle; close it with `portable_pty_close`.
+///
+/// Returns `ErrUnsupported` off Windows and on Windows without ConPTY,
+/// and `ErrOpen` if the system rejects the flags.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_open_conpty(
+    rows: u16,
+    cols: u16,
+    flags: u32,
+    out: *mut *mut PortablePty,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        if out.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+        let size = PtySize {
+            rows,
+            cols,
+            pixel_width: 0,
+            pixel_height: 0,
+        };
+        match open(size, flags) {
+            Ok(handle) => {
+                unsafe {
+                    *out = crate::lifecycle::register(handle);
+                }
+                PortablePtyResult::Ok
+            }
+            Err(e) => e,
+        }
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+
+    #[test]
+    fn test_flags_only_apply_to_conpty() {
+        let mut handle = std::ptr::null_mut();
+        let result = portable_pty_open_conpty(24, 80, PORTABLE_PTY_CONPTY_DEFAULT, &mut handle);
+        assert!(matches!(result, PortablePtyResult::ErrUnsupported));
+
+        // The native backend takes them anywhere, so one config serves
+        // every platform.
+        let name = CString::new("native").unwrap();
+        let config = CString::new(r#"{"conpty_flags": 1}"#).unwrap();
+        let result =
+            crate::backend::portable_pty_open_backend(name.as_ptr(), config.as_ptr(), &mut handle);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        crate::portable_pty_close(handle);
+
+        let config = CString::new(r#"{"conpty_flags": -1}"#).unwrap();
+        let result =
+            crate::backend::portable_pty_open_backend(name.as_ptr(), config.as_ptr(), &mut handle);
+        assert!(matches!(result, PortablePtyResult::ErrOpen));
+
+        let name = CString::new("winpty").unwrap();
+        let result =
+            crate::backend::portable_pty_open_backend(name.as_ptr(), std::ptr::null(), &mut handle);
+        assert!(matches!(result, PortablePtyResult::ErrUnsupported));
+    }
+}
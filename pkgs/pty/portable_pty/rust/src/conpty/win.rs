@@ -0,0 +1,478 @@
+//! The pseudo console itself, and the processes started in it.
+//!
+//! ConPTY's functions are looked up at runtime so the library still loads
+//! on Windows releases without them.
+
+use crate::pty::{
+    Child, ChildKiller, CommandBuilder, ExitStatus, MasterPty, PtyPair, PtySize, SlavePty,
+};
+use crate::{pipe, PipeReader, PipeWriter};
+use anyhow::anyhow;
+use std::ffi::{c_void, CStr, OsStr, OsString};
+use std::io::{self, Read, Write};
+use std::os::windows::ffi::OsStrExt;
+use std::os::windows::io::{AsRawHandle, FromRawHandle, OwnedHandle, RawHandle};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, PoisonError};
+use std::{mem, ptr};
+use winapi::shared::minwindef::{DWORD, FALSE, FARPROC, HMODULE};
+use winapi::shared::winerror::{HRESULT, S_OK};
+use winapi::um::handleapi::INVALID_HANDLE_VALUE;
+use winapi::um::libloaderapi::{GetProcAddress, LoadLibraryW};
+use winapi::um::processthreadsapi::{
+    CreateProcessW, DeleteProcThreadAttributeList, GetExitCodeProcess,
+    InitializeProcThreadAttributeList, TerminateProcess, UpdateProcThreadAttribute,
+    LPPROC_THREAD_ATTRIBUTE_LIST, PROCESS_INFORMATION,
+};
+use winapi::um::synchapi::WaitForSingleObject;
+use winapi::um::winbase::{
+    CREATE_UNICODE_ENVIRONMENT, EXTENDED_STARTUPINFO_PRESENT, INFINITE, STARTF_USESTDHANDLES,
+    STARTUPINFOEXW, WAIT_OBJECT_0,
+};
+use winapi::um::wincon::COORD;
+use winapi::um::winnt::HANDLE;
+
+/// Not in winapi: attaches a child to a pseudo console.
+const PROC_THREAD_ATTRIBUTE_PSEUDOCONSOLE: usize = 0x0002_0016;
+
+type CreatePseudoConsole =
+    unsafe extern "system" fn(COORD, HANDLE, HANDLE, DWORD, *mut HANDLE) -> HRESULT;
+type ResizePseudoConsole = unsafe extern "system" fn(HANDLE, COORD) -> HRESULT;
+type ClosePseudoConsole = unsafe extern "system" fn(HANDLE);
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// ConPTY's entry points in one library.
+struct Functions {
+    create: CreatePseudoConsole,
+    resize: ResizePseudoConsole,
+    close: ClosePseudoConsole,
+}
+
+impl Functions {
+    fn load(library: &str) -> Option<Functions> {
+        let module = load_library(library)?;
+        unsafe {
+            Some(Functions {
+                create: symbol(module, c"CreatePseudoConsole")?,
+                resize: symbol(module, c"ResizePseudoConsole")?,
+                close: symbol(module, c"ClosePseudoConsole")?,
+            })
+        }
+    }
+}
+
+/// Load `library`, which then stays loaded for the life of the process.
+pub(super) fn load_library(library: &str) -> Option<HMODULE> {
+    let name: Vec<u16> = library.encode_utf16().chain(Some(0)).collect();
+    let module = unsafe { LoadLibraryW(name.as_ptr()) };
+    (!module.is_null()).then_some(module)
+}
+
+/// Look up `name` in `module`.
+///
+/// # Safety
+///
+/// `F` must be the function pointer type of the symbol.
+pub(super) unsafe fn symbol<F: Copy>(module: HMODULE, name: &CStr) -> Option<F> {
+    let address = unsafe { GetProcAddress(module, name.as_ptr()) };
+    (!address.is_null()).then(|| unsafe { mem::transmute_copy::<FARPROC, F>(&address) })
+}
+
+/// ConPTY, or None if this Windows predates it.
+fn functions() -> Option<&'static Functions> {
+    static FUNCTIONS: OnceLock<Option<Functions>> = OnceLock::new();
+    FUNCTIONS
+        .get_or_init(|| {
+            // A sideloaded conpty.dll is only trusted where the system
+            // could run a pseudo console anyway.
+            let system = Functions::load("kernel32.dll")?;
+            Some(Functions::load("conpty.dll").unwrap_or(system))
+        })
+        .as_ref()
+}
+
+fn coord(size: PtySize) -> COORD {
+    COORD {
+        X: i16::try_from(size.cols).unwrap_or(i16::MAX),
+        Y: i16::try_from(size.rows).unwrap_or(i16::MAX),
+    }
+}
+
+/// An open pseudo console, closed on drop.
+struct PseudoConsole {
+    handle: HANDLE,
+    functions: &'static Functions,
+}
+
+// The handle is only passed to ConPTY's functions, which are thread-safe.
+unsafe impl Send for PseudoConsole {}
+unsafe impl Sync for PseudoConsole {}
+
+impl Drop for PseudoConsole {
+    fn drop(&mut self) {
+        unsafe { (self.functions.close)(self.handle) };
+    }
+}
+
+/// Whether this system has ConPTY.
+pub(super) fn available() -> bool {
+    functions().is_some()
+}
+
+/// Create a pseudo console of `size` with `flags`. `Unsupported` if the
+/// system has no ConPTY.
+pub(super) fn openpty(size: PtySize, flags: u32) -> io::Result<PtyPair> {
+    let functions = functions().ok_or(io::ErrorKind::Unsupported)?;
+    let (input, to_console) = pipe()?;
+    let (from_console, output) = pipe()?;
+    let mut handle = INVALID_HANDLE_VALUE;
+    let result = unsafe {
+        (functions.create)(
+            coord(size),
+            input.as_raw_handle() as HANDLE,
+            output.as_raw_handle() as HANDLE,
+            flags,
+            &mut handle,
+        )
+    };
+    if result != S_OK {
+        return Err(io::Error::other(format!(
+            "CreatePseudoConsole failed: HRESULT {result:#010x}"
+        )));
+    }
+    // The console holds its own copies of its ends of the pipes.
+    drop((input, output));
+
+    let inner = Arc::new(Mutex::new(Inner {
+        console: PseudoConsole { handle, functions },
+        reader: from_console,
+        writer: Some(to_console),
+        size,
+    }));
+    Ok(PtyPair {
+        master: Box::new(ConPtyMaster {
+            inner: Arc::clone(&inner),
+        }),
+        slave: Box::new(ConPtySlave { inner }),
+    })
+}
+
+struct Inner {
+    console: PseudoConsole,
+    reader: PipeReader,
+    writer: Option<PipeWriter>,
+    size: PtySize,
+}
+
+struct ConPtyMaster {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl MasterPty for ConPtyMaster {
+    fn resize(&self, size: PtySize) -> anyhow::Result<()> {
+        let mut inner = lock(&self.inner);
+        let console = &inner.console;
+        let result = unsafe { (console.functions.resize)(console.handle, coord(size)) };
+        if result != S_OK {
+            return Err(anyhow!(
+                "ResizePseudoConsole failed: HRESULT {result:#010x}"
+            ));
+        }
+        inner.size = size;
+        Ok(())
+    }
+
+    fn get_size(&self) -> anyhow::Result<PtySize> {
+        Ok(lock(&self.inner).size)
+    }
+
+    fn try_clone_reader(&self) -> anyhow::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(lock(&self.inner).reader.try_clone()?))
+    }
+
+    fn take_writer(&self) -> anyhow::Result<Box<dyn Write + Send>> {
+        let writer = lock(&self.inner).writer.take();
+        Ok(Box::new(
+            writer.ok_or_else(|| anyhow!("writer already taken"))?,
+        ))
+    }
+}
+
+struct ConPtySlave {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl SlavePty for ConPtySlave {
+    fn spawn_command(&self, cmd: CommandBuilder) -> anyhow::Result<Box<dyn Child + Send + Sync>> {
+        let console = lock(&self.inner).console.handle;
+        Ok(Box::new(spawn(console, &cmd)?))
+    }
+}
+
+/// A `PROC_THREAD_ATTRIBUTE_LIST` naming the console to attach to.
+struct AttributeList {
+    // usize for the alignment the list needs.
+    data: Vec<usize>,
+}
+
+impl AttributeList {
+    fn new(console: HANDLE) -> io::Result<AttributeList> {
+        let mut bytes = 0;
+        unsafe { InitializeProcThreadAttributeList(ptr::null_mut(), 1, 0, &mut bytes) };
+        let mut data = vec![0usize; bytes.div_ceil(mem::size_of::<usize>())];
+        let list = data.as_mut_ptr() as LPPROC_THREAD_ATTRIBUTE_LIST;
+        if unsafe { InitializeProcThreadAttributeList(list, 1, 0, &mut bytes) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut attributes = AttributeList { data };
+        // The console handle itself is the value, not a pointer to it.
+        let updated = unsafe {
+            UpdateProcThreadAttribute(
+                attributes.as_mut_ptr(),
+                0,
+                PROC_THREAD_ATTRIBUTE_PSEUDOCONSOLE,
+                console,
+                mem::size_of::<HANDLE>(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        };
+        if updated == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(attributes)
+    }
+
+    fn as_mut_ptr(&mut self) -> LPPROC_THREAD_ATTRIBUTE_LIST {
+        self.data.as_mut_ptr() as LPPROC_THREAD_ATTRIBUTE_LIST
+    }
+}
+
+impl Drop for AttributeList {
+    fn drop(&mut self) {
+        unsafe { DeleteProcThreadAttributeList(self.as_mut_ptr()) };
+    }
+}
+
+fn wide(s: &OsStr) -> Vec<u16> {
+    s.encode_wide().chain(Some(0)).collect()
+}
+
+/// Find `program` on the command's `PATH`, trying each of `PATHEXT`.
+fn search_path(cmd: &CommandBuilder, program: &OsStr) -> Option<OsString> {
+    let extensions = cmd.get_env("PATHEXT").unwrap_or(OsStr::new(".EXE"));
+    for dir in std::env::split_paths(cmd.get_env("PATH")?) {
+        let candidate = dir.join(program);
+        if candidate.is_file() {
+            return Some(candidate.into_os_string());
+        }
+        for extension in std::env::split_paths(extensions) {
+            let mut candidate = candidate.clone().into_os_string();
+            candidate.push(extension.as_os_str());
+            if Path::new(&candidate).is_file() {
+                return Some(candidate);
+            }
+        }
+    }
+    None
+}
+
+/// Append `arg` to a command line, quoted as the C runtime parses it.
+fn append_quoted(cmdline: &mut Vec<u16>, arg: &OsStr) {
+    let needs_quotes = arg.is_empty()
+        || arg
+            .encode_wide()
+            .any(|c| matches!(c, 0x20 | 0x09 | 0x0a | 0x0b | 0x22));
+    if !needs_quotes {
+        cmdline.extend(arg.encode_wide());
+        return;
+    }
+    cmdline.push(b'"' as u16);
+    let mut backslashes = 0;
+    for c in arg.encode_wide() {
+        if c == b'\\' as u16 {
+            backslashes += 1;
+            continue;
+        }
+        // Backslashes only escape when a quote follows.
+        let escapes = if c == b'"' as u16 {
+            backslashes * 2 + 1
+        } else {
+            backslashes
+        };
+        cmdline.extend(std::iter::repeat_n(b'\\' as u16, escapes));
+        cmdline.push(c);
+        backslashes = 0;
+    }
+    cmdline.extend(std::iter::repeat_n(b'\\' as u16, backslashes * 2));
+    cmdline.push(b'"' as u16);
+}
+
+/// The program to run, if found, and the full command line.
+pub(super) fn command_line(cmd: &CommandBuilder) -> io::Result<(Option<Vec<u16>>, Vec<u16>)> {
+    let argv = cmd.get_argv();
+    let shell;
+    let (program, args) = match argv.split_first() {
+        Some((program, args)) if !cmd.is_default_prog() => (program.as_os_str(), args),
+        _ => {
+            shell = cmd.get_env("ComSpec").unwrap_or(OsStr::new("cmd.exe"));
+            (shell, &[][..])
+        }
+    };
+    let resolved = search_path(cmd, program);
+    let mut cmdline = Vec::new();
+    append_quoted(&mut cmdline, resolved.as_deref().unwrap_or(program));
+    for arg in args {
+        if arg.encode_wide().any(|c| c == 0) {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        cmdline.push(b' ' as u16);
+        append_quoted(&mut cmdline, arg);
+    }
+    cmdline.push(0);
+    Ok((resolved.as_deref().map(wide), cmdline))
+}
+
+/// The command's environment as `CreateProcessW` takes it.
+pub(super) fn environment_block(cmd: &CommandBuilder) -> Vec<u16> {
+    let mut block = Vec::new();
+    for (key, value) in cmd.iter_full_env_as_str() {
+        block.extend(OsStr::new(key).encode_wide());
+        block.push(b'=' as u16);
+        block.extend(OsStr::new(value).encode_wide());
+        block.push(0);
+    }
+    block.push(0);
+    block
+}
+
+/// The command's directory, or the user's profile if it has none.
+pub(super) fn current_directory(cmd: &CommandBuilder) -> Option<Vec<u16>> {
+    let dir = cmd
+        .get_cwd()
+        .map(OsString::as_os_str)
+        .filter(|dir| Path::new(dir).is_dir())
+        .or_else(|| cmd.get_env("USERPROFILE"))
+        .filter(|dir| Path::new(dir).is_dir())?;
+    let dir = std::path::absolute(dir).ok()?;
+    Some(wide(dir.as_os_str()))
+}
+
+/// Start `cmd` attached to `console`.
+fn spawn(console: HANDLE, cmd: &CommandBuilder) -> io::Result<Process> {
+    let (program, mut cmdline) = command_line(cmd)?;
+    let mut environment = environment_block(cmd);
+    let cwd = current_directory(cmd);
+    let mut attributes = AttributeList::new(console)?;
+
+    let mut info: STARTUPINFOEXW = unsafe { mem::zeroed() };
+    info.StartupInfo.cb = mem::size_of::<STARTUPINFOEXW>() as DWORD;
+    // Otherwise the child can end up with our own redirected stdio rather
+    // than the console.
+    info.StartupInfo.dwFlags = STARTF_USESTDHANDLES;
+    info.StartupInfo.hStdInput = INVALID_HANDLE_VALUE;
+    info.StartupInfo.hStdOutput = INVALID_HANDLE_VALUE;
+    info.StartupInfo.hStdError = INVALID_HANDLE_VALUE;
+    info.lpAttributeList = attributes.as_mut_ptr();
+
+    let mut process: PROCESS_INFORMATION = unsafe { mem::zeroed() };
+    let created = unsafe {
+        CreateProcessW(
+            program.as_ref().map_or(ptr::null(), |p| p.as_ptr()),
+            cmdline.as_mut_ptr(),
+            ptr::null_mut(),
+            ptr::null_mut(),
+            FALSE,
+            EXTENDED_STARTUPINFO_PRESENT | CREATE_UNICODE_ENVIRONMENT,
+            environment.as_mut_ptr() as *mut c_void,
+            cwd.as_ref().map_or(ptr::null(), |c| c.as_ptr()),
+            &mut info.StartupInfo,
+            &mut process,
+        )
+    };
+    if created == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    unsafe {
+        drop(OwnedHandle::from_raw_handle(process.hThread as RawHandle));
+        Ok(Process::new(
+            OwnedHandle::from_raw_handle(process.hProcess as RawHandle),
+            process.dwProcessId,
+        ))
+    }
+}
+
+/// A child process, whatever console it was started in.
+#[derive(Debug, Clone)]
+pub(super) struct Process {
+    process: Arc<OwnedHandle>,
+    pid: u32,
+}
+
+impl Process {
+    pub(super) fn new(process: OwnedHandle, pid: u32) -> Process {
+        Process {
+            process: Arc::new(process),
+            pid,
+        }
+    }
+
+    fn handle(&self) -> HANDLE {
+        self.process.as_raw_handle() as HANDLE
+    }
+
+    fn exit_code(&self) -> io::Result<ExitStatus> {
+        let mut code: DWORD = 0;
+        if unsafe { GetExitCodeProcess(self.handle(), &mut code) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ExitStatus::with_exit_code(code))
+    }
+
+    fn has_exited(&self) -> bool {
+        unsafe { WaitForSingleObject(self.handle(), 0) == WAIT_OBJECT_0 }
+    }
+}
+
+impl ChildKiller for Process {
+    fn kill(&mut self) -> io::Result<()> {
+        if unsafe { TerminateProcess(self.handle(), 1) } != 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        match self.has_exited() {
+            true => Ok(()),
+            false => Err(err),
+        }
+    }
+
+    fn clone_killer(&self) -> Box<dyn ChildKiller + Send + Sync> {
+        Box::new(self.clone())
+    }
+}
+
+impl Child for Process {
+    fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        // Not `STILL_ACTIVE`: a process may exit with that code.
+        if !self.has_exited() {
+            return Ok(None);
+        }
+        self.exit_code().map(Some)
+    }
+
+    fn wait(&mut self) -> io::Result<ExitStatus> {
+        unsafe { WaitForSingleObject(self.handle(), INFINITE) };
+        self.exit_code()
+    }
+
+    fn process_id(&self) -> Option<u32> {
+        Some(self.pid)
+    }
+
+    fn as_raw_handle(&self) -> Option<RawHandle> {
+        Some(self.process.as_raw_handle())
+    }
+}
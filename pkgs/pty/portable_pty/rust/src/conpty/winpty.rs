@@ -0,0 +1,265 @@
+//! WinPTY, for Windows releases without ConPTY.
+//!
+//! winpty runs a hidden console in its agent process (`winpty-agent.exe`)
+//! and relays it over named pipes. `winpty.dll` is looked up at runtime
+//! like ConPTY, so only applications supporting Windows before 10 1809
+//! need to ship it, next to the agent.
+
+use super::win::{
+    command_line, current_directory, environment_block, load_library, symbol, Process,
+};
+use crate::pty::{Child, CommandBuilder, MasterPty, PtyPair, PtySize, SlavePty};
+use anyhow::anyhow;
+use std::ffi::{c_int, c_void};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::windows::io::{FromRawHandle, OwnedHandle, RawHandle};
+use std::ptr;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, PoisonError};
+use winapi::shared::minwindef::{BOOL, DWORD};
+use winapi::um::fileapi::{CreateFileW, OPEN_EXISTING};
+use winapi::um::handleapi::INVALID_HANDLE_VALUE;
+use winapi::um::processthreadsapi::GetProcessId;
+use winapi::um::winnt::{GENERIC_READ, GENERIC_WRITE, HANDLE};
+
+/// Have the agent translate console colors to escape sequences.
+const WINPTY_FLAG_COLOR_ESCAPES: u64 = 0x4;
+/// Shut the console down once the child exits, ending its output.
+const WINPTY_SPAWN_FLAG_AUTO_SHUTDOWN: u64 = 0x1;
+
+/// `winpty_t`, `winpty_config_t` and `winpty_spawn_config_t`.
+type Opaque = *mut c_void;
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The part of winpty's API used here. Every error out-parameter is
+/// passed NULL: a failure is reported by the return value alone.
+struct Functions {
+    config_new: unsafe extern "C" fn(u64, *mut Opaque) -> Opaque,
+    config_free: unsafe extern "C" fn(Opaque),
+    config_set_initial_size: unsafe extern "C" fn(Opaque, c_int, c_int),
+    open: unsafe extern "C" fn(Opaque, *mut Opaque) -> Opaque,
+    conin_name: unsafe extern "C" fn(Opaque) -> *const u16,
+    conout_name: unsafe extern "C" fn(Opaque) -> *const u16,
+    spawn_config_new: unsafe extern "C" fn(
+        u64,
+        *const u16,
+        *const u16,
+        *const u16,
+        *const u16,
+        *mut Opaque,
+    ) -> Opaque,
+    spawn_config_free: unsafe extern "C" fn(Opaque),
+    spawn: unsafe extern "C" fn(
+        Opaque,
+        Opaque,
+        *mut HANDLE,
+        *mut HANDLE,
+        *mut DWORD,
+        *mut Opaque,
+    ) -> BOOL,
+    set_size: unsafe extern "C" fn(Opaque, c_int, c_int, *mut Opaque) -> BOOL,
+    free: unsafe extern "C" fn(Opaque),
+}
+
+/// winpty, or None if the application doesn't ship it.
+fn functions() -> Option<&'static Functions> {
+    static FUNCTIONS: OnceLock<Option<Functions>> = OnceLock::new();
+    FUNCTIONS
+        .get_or_init(|| {
+            let module = load_library("winpty.dll")?;
+            unsafe {
+                Some(Functions {
+                    config_new: symbol(module, c"winpty_config_new")?,
+                    config_free: symbol(module, c"winpty_config_free")?,
+                    config_set_initial_size: symbol(module, c"winpty_config_set_initial_size")?,
+                    open: symbol(module, c"winpty_open")?,
+                    conin_name: symbol(module, c"winpty_conin_name")?,
+                    conout_name: symbol(module, c"winpty_conout_name")?,
+                    spawn_config_new: symbol(module, c"winpty_spawn_config_new")?,
+                    spawn_config_free: symbol(module, c"winpty_spawn_config_free")?,
+                    spawn: symbol(module, c"winpty_spawn")?,
+                    set_size: symbol(module, c"winpty_set_size")?,
+                    free: symbol(module, c"winpty_free")?,
+                })
+            }
+        })
+        .as_ref()
+}
+
+/// A running agent, stopped on drop along with its console.
+struct Agent {
+    handle: Opaque,
+    functions: &'static Functions,
+}
+
+// Only used under the handle's lock.
+unsafe impl Send for Agent {}
+
+impl Drop for Agent {
+    fn drop(&mut self) {
+        unsafe { (self.functions.free)(self.handle) };
+    }
+}
+
+/// Open the agent end of one of its pipes.
+fn connect(name: *const u16, access: DWORD) -> io::Result<File> {
+    if name.is_null() {
+        return Err(io::ErrorKind::NotFound.into());
+    }
+    let pipe = unsafe {
+        CreateFileW(
+            name,
+            access,
+            0,
+            ptr::null_mut(),
+            OPEN_EXISTING,
+            0,
+            ptr::null_mut(),
+        )
+    };
+    if pipe == INVALID_HANDLE_VALUE {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_handle(pipe as RawHandle) })
+}
+
+/// Start an agent with a console of `size`. `Unsupported` if winpty
+/// isn't there.
+pub(super) fn openpty(size: PtySize) -> io::Result<PtyPair> {
+    let functions = functions().ok_or(io::ErrorKind::Unsupported)?;
+    let config = unsafe { (functions.config_new)(WINPTY_FLAG_COLOR_ESCAPES, ptr::null_mut()) };
+    if config.is_null() {
+        return Err(io::Error::other("winpty_config_new failed"));
+    }
+    let handle = unsafe {
+        (functions.config_set_initial_size)(config, size.cols.into(), size.rows.into());
+        let handle = (functions.open)(config, ptr::null_mut());
+        (functions.config_free)(config);
+        handle
+    };
+    if handle.is_null() {
+        return Err(io::Error::other("winpty_open failed"));
+    }
+    let agent = Agent { handle, functions };
+    let input = connect(unsafe { (functions.conin_name)(handle) }, GENERIC_WRITE)?;
+    let output = connect(unsafe { (functions.conout_name)(handle) }, GENERIC_READ)?;
+
+    let inner = Arc::new(Mutex::new(Inner {
+        agent,
+        input: Some(input),
+        output,
+        size,
+    }));
+    Ok(PtyPair {
+        master: Box::new(WinptyMaster {
+            inner: Arc::clone(&inner),
+        }),
+        slave: Box::new(WinptySlave { inner }),
+    })
+}
+
+struct Inner {
+    agent: Agent,
+    input: Option<File>,
+    output: File,
+    size: PtySize,
+}
+
+struct WinptyMaster {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl MasterPty for WinptyMaster {
+    fn resize(&self, size: PtySize) -> anyhow::Result<()> {
+        let mut inner = lock(&self.inner);
+        let agent = &inner.agent;
+        let resized = unsafe {
+            (agent.functions.set_size)(
+                agent.handle,
+                size.cols.into(),
+                size.rows.into(),
+                ptr::null_mut(),
+            )
+        };
+        if resized == 0 {
+            return Err(anyhow!("winpty_set_size failed"));
+        }
+        inner.size = size;
+        Ok(())
+    }
+
+    fn get_size(&self) -> anyhow::Result<PtySize> {
+        Ok(lock(&self.inner).size)
+    }
+
+    fn try_clone_reader(&self) -> anyhow::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(lock(&self.inner).output.try_clone()?))
+    }
+
+    fn take_writer(&self) -> anyhow::Result<Box<dyn Write + Send>> {
+        let input = lock(&self.inner).input.take();
+        Ok(Box::new(
+            input.ok_or_else(|| anyhow!("writer already taken"))?,
+        ))
+    }
+}
+
+struct WinptySlave {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl SlavePty for WinptySlave {
+    fn spawn_command(&self, cmd: CommandBuilder) -> anyhow::Result<Box<dyn Child + Send + Sync>> {
+        let inner = lock(&self.inner);
+        Ok(Box::new(spawn(&inner.agent, &cmd)?))
+    }
+}
+
+/// Start `cmd` in the agent's console.
+fn spawn(agent: &Agent, cmd: &CommandBuilder) -> io::Result<Process> {
+    let functions = agent.functions;
+    let (program, cmdline) = command_line(cmd)?;
+    let environment = environment_block(cmd);
+    let cwd = current_directory(cmd);
+    let config = unsafe {
+        (functions.spawn_config_new)(
+            WINPTY_SPAWN_FLAG_AUTO_SHUTDOWN,
+            program.as_ref().map_or(ptr::null(), |p| p.as_ptr()),
+            cmdline.as_ptr(),
+            cwd.as_ref().map_or(ptr::null(), |c| c.as_ptr()),
+            environment.as_ptr(),
+            ptr::null_mut(),
+        )
+    };
+    if config.is_null() {
+        return Err(io::Error::other("winpty_spawn_config_new failed"));
+    }
+    let mut process: HANDLE = ptr::null_mut();
+    let mut create_error: DWORD = 0;
+    let spawned = unsafe {
+        let spawned = (functions.spawn)(
+            agent.handle,
+            config,
+            &mut process,
+            ptr::null_mut(),
+            &mut create_error,
+            ptr::null_mut(),
+        );
+        (functions.spawn_config_free)(config);
+        spawned
+    };
+    if spawned == 0 || process.is_null() {
+        return Err(match create_error {
+            0 => io::Error::other("winpty_spawn failed"),
+            code => io::Error::from_raw_os_error(code as i32),
+        });
+    }
+    let pid = unsafe { GetProcessId(process) };
+    Ok(Process::new(
+        unsafe { OwnedHandle::from_raw_handle(process as RawHandle) },
+        pid,
+    ))
+}
@@ -0,0 +1,494 @@
+//! Control socket for other processes.
+//!
+//! `portable_pty_control_start` listens on a Unix-domain socket through
+//! which other processes — a CLI, a test harness, a second app — can drive
+//! the sessions the embedder publishes with `portable_pty_control_publish`.
+//! The socket is created readable and writable by the owner only.
+//!
+//! Every message, in either direction, is a frame (see `frames`): a 4-byte
+//! big-endian length of what follows, a 1-byte type, then the payload.
+//!
+//! | type | request  | payload                                   |
+//! |------|----------|-------------------------------------------|
+//! | 1    | `LIST`   | none                                      |
+//! | 2    | `ATTACH` | session name                              |
+//! | 3    | `DETACH` | none                                      |
+//! | 4    | `WRITE`  | bytes for the attached session's child    |
+//! | 5    | `RESIZE` | rows, cols: big-endian `u16` each         |
+//!
+//! Each request gets one reply: `OK` (0x80), whose payload for `LIST` is
+//! the published names one per line, or `ERROR` (0x81) with a UTF-8
+//! message. After a successful `ATTACH` the connection also receives
+//! `OUTPUT` (0x82) frames carrying what the session reads, as with
+//! `portable_pty_serve`, and a final `CLOSED` (0x83) if the session is
+//! closed or unpublished. A connection that falls too far behind is
+//! dropped.
+
+// Only Unix has the socket to speak the protocol on.
+#![cfg_attr(not(unix), allow(dead_code))]
+
+use crate::frames::{frame, read_frame, Outbox};
+use crate::lifecycle::HandleRef;
+use crate::{PortablePty, PortablePtyResult};
+use std::ffi::{c_char, CStr};
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock};
+
+const LIST: u8 = 1;
+const ATTACH: u8 = 2;
+const DETACH: u8 = 3;
+const WRITE: u8 = 4;
+const RESIZE: u8 = 5;
+const OK: u8 = 0x80;
+const ERROR: u8 = 0x81;
+const OUTPUT: u8 = 0x82;
+const CLOSED: u8 = 0x83;
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// A session published on the control socket, kept on its handle.
+pub(crate) struct Published {
+    name: String,
+    handle: HandleRef,
+    /// False once unpublished. Requests hold it for reading while they use
+    /// the handle, so unpublishing waits for them.
+    live: RwLock<bool>,
+    /// Outboxes of the attached connections.
+    watchers: Mutex<Vec<Arc<Outbox>>>,
+}
+
+static PUBLISHED: Mutex<Vec<Arc<Published>>> = Mutex::new(Vec::new());
+
+/// Pass output the handle has read on to attached connections.
+pub(crate) fn broadcast(pty: &PortablePty, bytes: &[u8]) {
+    if let Some(published) = lock(&pty.published).as_ref() {
+        let output = frame(OUTPUT, bytes);
+        lock(&published.watchers).retain(|outbox| outbox.push(Arc::clone(&output)));
+    }
+}
+
+/// Withdraw the handle from the control socket, if it's published.
+pub(crate) fn unpublish(pty: &PortablePty) {
+    let Some(published) = lock(&pty.published).take() else {
+        return;
+    };
+    lock(&PUBLISHED).retain(|p| !Arc::ptr_eq(p, &published));
+    *published
+        .live
+        .write()
+        .unwrap_or_else(PoisonError::into_inner) = false;
+    let closed = frame(CLOSED, &[]);
+    for outbox in lock(&published.watchers).drain(..) {
+        outbox.push(Arc::clone(&closed));
+    }
+}
+
+/// What one connection has attached to.
+struct Connection {
+    outbox: Arc<Outbox>,
+    attached: Option<Arc<Published>>,
+}
+
+impl Connection {
+    fn detach(&mut self) {
+        if let Some(published) = self.attached.take() {
+            lock(&published.watchers).retain(|outbox| !Arc::ptr_eq(outbox, &self.outbox));
+        }
+    }
+
+    /// Run `f` on the attached session's handle.
+    fn with_handle(
+        &self,
+        f: impl FnOnce(&PortablePty) -> Result<(), &'static str>,
+    ) -> Result<(), &'static str> {
+        let published = self.attached.as_ref().ok_or("not attached")?;
+        let live = published
+            .live
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        if !*live {
+            return Err("session closed");
+        }
+        f(published.handle.get())
+    }
+
+    /// Carry out a request and queue its reply.
+    fn request(&mut self, kind: u8, payload: &[u8]) -> bool {
+        let result = match kind {
+            LIST => {
+                let names = lock(&PUBLISHED)
+                    .iter()
+                    .map(|p| p.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                return self.outbox.push(frame(OK, names.as_bytes()));
+            }
+            ATTACH => return self.attach(payload),
+            DETACH => {
+                self.detach();
+                Ok(())
+            }
+            WRITE => self.with_handle(|pty| pty.write_input(payload).map_err(|_| "write failed")),
+            RESIZE => match *payload {
+                [r0, r1, c0, c1] => self.with_handle(|pty| {
+                    let rows = u16::from_be_bytes([r0, r1]);
+                    let cols = u16::from_be_bytes([c0, c1]);
+                    match pty.resize(rows, cols) {
+                        PortablePtyResult::Ok => Ok(()),
+                        _ => Err("resize failed"),
+                    }
+                }),
+                _ => Err("malformed request"),
+            },
+            _ => Err("unknown request"),
+        };
+        match result {
+            Ok(()) => self.outbox.push(frame(OK, &[])),
+            Err(message) => self.outbox.push(frame(ERROR, message.as_bytes())),
+        }
+    }
+
+    fn attach(&mut self, name: &[u8]) -> bool {
+        self.detach();
+        let published = lock(&PUBLISHED)
+            .iter()
+            .find(|p| p.name.as_bytes() == name)
+            .cloned();
+        let Some(published) = published else {
+            return self.outbox.push(frame(ERROR, b"no such session"));
+        };
+        // Reply before any output can be queued behind it.
+        let mut watchers = lock(&published.watchers);
+        if !*published
+            .live
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+        {
+            return self.outbox.push(frame(ERROR, b"session closed"));
+        }
+        let ok = self.outbox.push(frame(OK, &[]));
+        watchers.push(Arc::clone(&self.outbox));
+        drop(watchers);
+        self.attached = Some(published);
+        ok
+    }
+}
+
+/// Handle one connection's requests until it disconnects.
+fn serve_connection(mut stream: impl Read, outbox: Arc<Outbox>) {
+    let mut connection = Connection {
+        outbox,
+        attached: None,
+    };
+    while let Ok((kind, payload)) = read_frame(&mut stream) {
+        if !connection.request(kind, &payload) {
+            break;
+        }
+    }
+    connection.detach();
+    connection.outbox.close();
+}
+
+/// Send a connection's queued frames until its outbox closes.
+fn send(mut stream: impl Write, outbox: Arc<Outbox>) {
+    while let Some(frame) = outbox.pop() {
+        if stream.write_all(&frame).is_err() {
+            break;
+        }
+    }
+    outbox.close();
+}
+
+#[cfg(unix)]
+mod socket {
+    use super::{lock, send, serve_connection, Outbox};
+    use crate::lifecycle::ThreadGroup;
+    use std::io::{self, ErrorKind};
+    use std::net::Shutdown;
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    /// How often the listener checks whether it should stop.
+    const ACCEPT_POLL: Duration = Duration::from_millis(50);
+
+    /// State shared between the server and its threads.
+    struct Shared {
+        /// Outboxes of open connections, and their streams.
+        connections: Mutex<Vec<(Arc<Outbox>, UnixStream)>>,
+        /// Set, with `connections` locked, once the server is stopping.
+        stopping: AtomicBool,
+        threads: Arc<ThreadGroup>,
+    }
+
+    pub(super) struct Server {
+        path: PathBuf,
+        shared: Arc<Shared>,
+    }
+
+    impl Server {
+        pub(super) fn start(path: PathBuf) -> io::Result<Server> {
+            let listener = UnixListener::bind(&path)?;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+            listener.set_nonblocking(true)?;
+            let shared = Arc::new(Shared {
+                connections: Mutex::new(Vec::new()),
+                stopping: AtomicBool::new(false),
+                threads: Arc::default(),
+            });
+            let listening = Arc::clone(&shared);
+            shared
+                .threads
+                .spawn("portable-pty-control", move || listen(listener, listening))?;
+            Ok(Server { path, shared })
+        }
+
+        /// Disconnect every client, wait for the server's threads to end
+        /// and remove the socket.
+        pub(super) fn stop(self) {
+            {
+                let mut connections = lock(&self.shared.connections);
+                self.shared.stopping.store(true, Ordering::Release);
+                for (outbox, stream) in connections.drain(..) {
+                    outbox.close();
+                    // Unblocks a send stuck on a client that stopped reading.
+                    let _ = stream.shutdown(Shutdown::Both);
+                }
+            }
+            self.shared.threads.wait();
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    fn listen(listener: UnixListener, shared: Arc<Shared>) {
+        while !shared.stopping.load(Ordering::Acquire) {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    let _ = connect(stream, &shared);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => std::thread::sleep(ACCEPT_POLL),
+                Err(_) => {}
+            }
+        }
+    }
+
+    fn connect(stream: UnixStream, shared: &Arc<Shared>) -> io::Result<()> {
+        stream.set_nonblocking(false)?;
+        let reader = stream.try_clone()?;
+        let stopper = stream.try_clone()?;
+        let outbox = Arc::new(Outbox::default());
+        {
+            let mut connections = lock(&shared.connections);
+            if shared.stopping.load(Ordering::Acquire) {
+                return Ok(());
+            }
+            connections.push((Arc::clone(&outbox), stopper));
+        }
+
+        let sending = Arc::clone(&outbox);
+        let result = shared.threads.spawn("portable-pty-control", move || {
+            send(&stream, sending);
+            // Ends the reads below, if they haven't ended already.
+            let _ = stream.shutdown(Shutdown::Both);
+        });
+        if result.is_err() {
+            outbox.close();
+            return result;
+        }
+        let shared_ = Arc::clone(shared);
+        shared.threads.spawn("portable-pty-control", move || {
+            serve_connection(reader, Arc::clone(&outbox));
+            lock(&shared_.connections).retain(|(o, _)| !Arc::ptr_eq(o, &outbox));
+        })
+    }
+}
+
+#[cfg(unix)]
+static SERVER: Mutex<Option<socket::Server>> = Mutex::new(None);
+
+/// Stop the control socket, if it's running.
+pub(crate) fn stop() {
+    #[cfg(unix)]
+    if let Some(server) = lock(&SERVER).take() {
+        server.stop();
+    }
+}
+
+/// Start listening for control connections on a Unix-domain socket.
+///
+/// - `path`: null-terminated path of the socket to create.
+///
+/// Returns `ErrOpen` if the socket is already running or `path` can't be
+/// bound (including when a file already exists there), and
+/// `ErrUnsupported` on Windows.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_control_start(path: *const c_char) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        if path.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+        start(unsafe { CStr::from_ptr(path) })
+    })
+}
+
+#[cfg(unix)]
+fn start(path: &CStr) -> PortablePtyResult {
+    use std::os::unix::ffi::OsStrExt;
+
+    let mut server = lock(&SERVER);
+    if server.is_some() {
+        return PortablePtyResult::ErrOpen;
+    }
+    let path = std::ffi::OsStr::from_bytes(path.to_bytes());
+    match socket::Server::start(path.into()) {
+        Ok(s) => {
+            *server = Some(s);
+            PortablePtyResult::Ok
+        }
+        Err(_) => PortablePtyResult::ErrOpen,
+    }
+}
+
+#[cfg(not(unix))]
+fn start(_path: &CStr) -> PortablePtyResult {
+    PortablePtyResult::ErrUnsupported
+}
+
+/// Stop the control socket, disconnecting every client and removing the
+/// socket file. Harmless if it isn't running.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_control_stop() -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        stop();
+        PortablePtyResult::Ok
+    })
+}
+
+/// Make a session available on the control socket under `name`.
+///
+/// - `name`: null-terminated UTF-8 name clients attach by; no newlines.
+///
+/// Sessions can be published whether or not the socket is running. A
+/// closed handle is unpublished automatically. Returns `ErrOpen` if the
+/// handle is already published or the name is taken or invalid.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_control_publish(
+    handle: *const PortablePty,
+    name: *const c_char,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        if name.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+        let name = match unsafe { CStr::from_ptr(name) }.to_str() {
+            Ok(name) if !name.is_empty() && !name.contains('\n') => name,
+            _ => return PortablePtyResult::ErrOpen,
+        };
+
+        let mut slot = lock(&pty.published);
+        let mut published = lock(&PUBLISHED);
+        if slot.is_some() || published.iter().any(|p| p.name == name) {
+            return PortablePtyResult::ErrOpen;
+        }
+        let entry = Arc::new(Published {
+            name: name.to_owned(),
+            handle: HandleRef::new(pty),
+            live: RwLock::new(true),
+            watchers: Mutex::new(Vec::new()),
+        });
+        published.push(Arc::clone(&entry));
+        *slot = Some(entry);
+        PortablePtyResult::Ok
+    })
+}
+
+/// Withdraw a session from the control socket. Attached clients get
+/// `CLOSED`. Harmless if it isn't published.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_control_unpublish(handle: *const PortablePty) -> PortablePtyResult {
+    crate::ffi::guard(|| match unsafe { handle.as_ref() } {
+        Some(pty) => {
+            unpublish(pty);
+            PortablePtyResult::Ok
+        }
+        None => PortablePtyResult::ErrNull,
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::loopback::{portable_pty_loopback_read, portable_pty_open_loopback};
+    use std::ffi::CString;
+    use std::os::unix::net::UnixStream;
+
+    fn request(stream: &mut UnixStream, kind: u8, payload: &[u8]) {
+        stream.write_all(&frame(kind, payload)).unwrap();
+    }
+
+    #[test]
+    fn test_attach_write_resize_over_socket() {
+        let path = std::env::temp_dir().join(format!("portable-pty-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+        let result = portable_pty_control_start(c_path.as_ptr());
+        assert!(matches!(result, PortablePtyResult::Ok));
+
+        let mut handle = std::ptr::null_mut();
+        portable_pty_open_loopback(24, 80, &mut handle);
+        let result = portable_pty_control_publish(handle, c"control-test".as_ptr());
+        assert!(matches!(result, PortablePtyResult::Ok));
+        let result = portable_pty_control_publish(handle, c"again".as_ptr());
+        assert!(matches!(result, PortablePtyResult::ErrOpen));
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        client
+            .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+            .unwrap();
+        request(&mut client, LIST, b"");
+        let (kind, names) = read_frame(&mut client).unwrap();
+        assert_eq!(kind, OK);
+        assert!(String::from_utf8(names)
+            .unwrap()
+            .lines()
+            .any(|n| n == "control-test"));
+
+        request(&mut client, WRITE, b"x");
+        assert_eq!(
+            read_frame(&mut client).unwrap(),
+            (ERROR, b"not attached".to_vec())
+        );
+        request(&mut client, ATTACH, b"control-test");
+        assert_eq!(read_frame(&mut client).unwrap(), (OK, vec![]));
+        request(&mut client, WRITE, b"hi");
+        assert_eq!(read_frame(&mut client).unwrap(), (OK, vec![]));
+        let mut buf = [0u8; 2];
+        let n = portable_pty_loopback_read(handle, buf.as_mut_ptr(), buf.len());
+        assert_eq!(&buf[..n as usize], b"hi");
+        request(&mut client, RESIZE, &[0, 30, 0, 100]);
+        assert_eq!(read_frame(&mut client).unwrap(), (OK, vec![]));
+        let (mut rows, mut cols) = (0, 0);
+        crate::loopback::portable_pty_loopback_size(handle, &mut rows, &mut cols);
+        assert_eq!((rows, cols), (30, 100));
+
+        crate::loopback::portable_pty_loopback_write(handle, b"out".as_ptr(), 3);
+        assert_eq!(crate::tests::read_string(handle), "out");
+        assert_eq!(read_frame(&mut client).unwrap(), (OUTPUT, b"out".to_vec()));
+
+        crate::portable_pty_close(handle);
+        assert_eq!(read_frame(&mut client).unwrap(), (CLOSED, vec![]));
+
+        portable_pty_control_stop();
+        assert!(read_frame(&mut client).is_err());
+        assert!(!path.exists());
+    }
+}
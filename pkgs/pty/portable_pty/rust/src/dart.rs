@@ -0,0 +1,359 @@
+//! Output posted straight to a Dart port.
+//!
+//! `portable_pty_attach_dart_port` has the handle's reader thread (see
+//! `pump`) post each chunk of output to a Dart `SendPort` with
+//! `Dart_PostCObject`, instead of a Dart isolate sitting in a blocking
+//! read and copying what it gets. A chunk arrives as a `Uint8List` over
+//! memory the library hands to Dart, freed when Dart collects it. Once
+//! output ends the port gets the child's exit code as an `int`, or `null`
+//! where that isn't known by then, and nothing more.
+//!
+//! The library doesn't link against the Dart VM: it finds
+//! `Dart_PostCObject` through the dynamically linked API, so
+//! `portable_pty_dart_init` has to be given
+//! `NativeApi.initializeApiDLData` first. Needs the `dart-api` feature;
+//! without it the entry points return `ErrUnsupported`.
+
+use crate::{PortablePty, PortablePtyResult};
+use std::ffi::c_void;
+
+#[cfg(feature = "dart-api")]
+mod api {
+    use std::ffi::{c_char, c_int, c_void, CStr};
+    use std::sync::OnceLock;
+
+    /// The version of the dynamically linked API this is written against.
+    const MAJOR_VERSION: c_int = 2;
+
+    /// `DartApiEntry`.
+    #[repr(C)]
+    struct Entry {
+        name: *const c_char,
+        function: *const c_void,
+    }
+
+    /// `DartApi`, what `NativeApi.initializeApiDLData` points to.
+    #[repr(C)]
+    pub(super) struct Api {
+        major: c_int,
+        minor: c_int,
+        functions: *const Entry,
+    }
+
+    // `Dart_CObject_Type` and `Dart_TypedData_Type` values.
+    const NULL: c_int = 0;
+    const INT64: c_int = 3;
+    const EXTERNAL_TYPED_DATA: c_int = 8;
+    const UINT8: c_int = 2;
+
+    type Finalizer = extern "C" fn(*mut c_void, *mut c_void);
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct ExternalTypedData {
+        kind: c_int,
+        length: isize,
+        data: *mut u8,
+        peer: *mut c_void,
+        callback: Finalizer,
+    }
+
+    /// The part of `Dart_CObject`'s value union used here; its largest
+    /// member, so the size matches.
+    #[repr(C)]
+    union Value {
+        as_int64: i64,
+        as_external_typed_data: ExternalTypedData,
+    }
+
+    /// `Dart_CObject`.
+    #[repr(C)]
+    pub(super) struct CObject {
+        kind: c_int,
+        value: Value,
+    }
+
+    pub(super) type PostCObject = extern "C" fn(i64, *mut CObject) -> bool;
+
+    static POST_C_OBJECT: OnceLock<PostCObject> = OnceLock::new();
+
+    /// Find `Dart_PostCObject` in `api`; false if it's the wrong version
+    /// or doesn't have it.
+    pub(super) fn init(api: &Api) -> bool {
+        if api.major != MAJOR_VERSION || api.functions.is_null() {
+            return false;
+        }
+        let mut entry = api.functions;
+        loop {
+            let Entry { name, function } = unsafe { &*entry };
+            if name.is_null() {
+                return false;
+            }
+            if unsafe { CStr::from_ptr(*name) } == c"Dart_PostCObject" {
+                let post: PostCObject = unsafe { std::mem::transmute(*function) };
+                POST_C_OBJECT.get_or_init(|| post);
+                return true;
+            }
+            entry = unsafe { entry.add(1) };
+        }
+    }
+
+    pub(super) fn ready() -> bool {
+        POST_C_OBJECT.get().is_some()
+    }
+
+    fn post(port: i64, mut object: CObject) -> bool {
+        match POST_C_OBJECT.get() {
+            Some(post) => post(port, &mut object),
+            None => false,
+        }
+    }
+
+    extern "C" fn free_chunk(_isolate_data: *mut c_void, peer: *mut c_void) {
+        drop(unsafe { Box::from_raw(peer as *mut Vec<u8>) });
+    }
+
+    /// Post `bytes` to `port` as a `Uint8List` Dart takes over.
+    pub(super) fn post_bytes(port: i64, bytes: Vec<u8>) -> bool {
+        let peer = Box::into_raw(Box::new(bytes));
+        let bytes = unsafe { &mut *peer };
+        let data = ExternalTypedData {
+            kind: UINT8,
+            length: bytes.len() as isize,
+            data: bytes.as_mut_ptr(),
+            peer: peer as *mut c_void,
+            callback: free_chunk,
+        };
+        let object = CObject {
+            kind: EXTERNAL_TYPED_DATA,
+            value: Value {
+                as_external_typed_data: data,
+            },
+        };
+        let posted = post(port, object);
+        if !posted {
+            // Dart never took it.
+            drop(unsafe { Box::from_raw(peer) });
+        }
+        posted
+    }
+
+    /// Post `value` to `port` as an `int`, or `null`.
+    pub(super) fn post_int(port: i64, value: Option<i64>) -> bool {
+        let object = match value {
+            Some(value) => CObject {
+                kind: INT64,
+                value: Value { as_int64: value },
+            },
+            None => CObject {
+                kind: NULL,
+                value: Value { as_int64: 0 },
+            },
+        };
+        post(port, object)
+    }
+
+    #[cfg(test)]
+    pub(super) mod fake {
+        //! A stand-in for the VM's side of the API, recording what's posted.
+
+        use super::*;
+        use std::sync::{Mutex, PoisonError};
+
+        /// A message as Dart would get it.
+        #[derive(Clone, Debug, PartialEq)]
+        pub(crate) enum Message {
+            Bytes(Vec<u8>),
+            Int(Option<i64>),
+        }
+
+        /// What's been posted, and to which port.
+        pub(crate) static POSTED: Mutex<Vec<(i64, Message)>> = Mutex::new(Vec::new());
+
+        extern "C" fn post(port: i64, object: *mut CObject) -> bool {
+            let object = unsafe { &*object };
+            let message = match object.kind {
+                EXTERNAL_TYPED_DATA => {
+                    let data = unsafe { object.value.as_external_typed_data };
+                    let bytes =
+                        unsafe { std::slice::from_raw_parts(data.data, data.length as usize) };
+                    let bytes = bytes.to_vec();
+                    (data.callback)(std::ptr::null_mut(), data.peer);
+                    Message::Bytes(bytes)
+                }
+                INT64 => Message::Int(Some(unsafe { object.value.as_int64 })),
+                _ => Message::Int(None),
+            };
+            let mut posted = POSTED.lock().unwrap_or_else(PoisonError::into_inner);
+            posted.push((port, message));
+            true
+        }
+
+        /// An API table offering the fake `Dart_PostCObject`.
+        pub(crate) fn api() -> Api {
+            let functions = Box::leak(Box::new([
+                Entry {
+                    name: c"Dart_PostCObject".as_ptr(),
+                    function: post as *const c_void,
+                },
+                Entry {
+                    name: std::ptr::null(),
+                    function: std::ptr::null(),
+                },
+            ]));
+            Api {
+                major: MAJOR_VERSION,
+                minor: 0,
+                functions: functions.as_ptr(),
+            }
+        }
+    }
+}
+
+/// The child's exit code once its output has ended, if it's known by then.
+#[cfg(all(unix, feature = "dart-api"))]
+fn exit_code(pty: &PortablePty) -> Option<i64> {
+    let pid = pty.child_pid;
+    if pid <= 0 {
+        return None;
+    }
+    // The SIGCHLD handler may not have run yet.
+    for _ in 0..10 {
+        if let Some(raw) = crate::lookup_cached_status(pid) {
+            return Some(crate::wait_status_code(raw).into());
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    None
+}
+
+/// Set up posting to Dart ports, once per process, before any
+/// `portable_pty_attach_dart_port`. The library doesn't link against the
+/// Dart VM; it finds `Dart_PostCObject` through the API this is given.
+///
+/// - `data`: what `NativeApi.initializeApiDLData` returns.
+///
+/// Returns `ErrUnsupported` if `data` isn't a version of the API this
+/// library knows, or without the `dart-api` feature.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_dart_init(data: *mut c_void) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        if data.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+        #[cfg(feature = "dart-api")]
+        match api::init(unsafe { &*(data as *const api::Api) }) {
+            true => PortablePtyResult::Ok,
+            false => PortablePtyResult::ErrUnsupported,
+        }
+        #[cfg(not(feature = "dart-api"))]
+        PortablePtyResult::ErrUnsupported
+    })
+}
+
+/// Post the handle's output to a Dart port as a background thread reads
+/// it, or with `port` 0 (`ILLEGAL_PORT`), stop.
+///
+/// - `port`: the native port of a `ReceivePort`'s `SendPort`. It gets each
+///   chunk of output as a `Uint8List` over memory freed when Dart collects
+///   it, then once output ends the child's exit code as an `int`, or
+///   `null` if that isn't known by then, and nothing more.
+///
+/// Takes the place of a data callback (see `pump`), and nothing else may
+/// read the handle meanwhile. Returns `ErrUnsupported` before
+/// `portable_pty_dart_init`, where the handle can't be polled, or without
+/// the `dart-api` feature.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_attach_dart_port(
+    handle: *const PortablePty,
+    port: i64,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        if port == 0 {
+            crate::pump::stop(pty);
+            return PortablePtyResult::Ok;
+        }
+        #[cfg(feature = "dart-api")]
+        {
+            if !api::ready() {
+                return PortablePtyResult::ErrUnsupported;
+            }
+            let handle = crate::lifecycle::HandleRef::new(pty);
+            crate::pump::start(
+                pty,
+                Box::new(move |chunk| {
+                    if !chunk.is_empty() {
+                        api::post_bytes(port, chunk.to_vec());
+                        return;
+                    }
+                    #[cfg(unix)]
+                    let code = exit_code(handle.get());
+                    #[cfg(not(unix))]
+                    let code = {
+                        let _ = handle;
+                        None
+                    };
+                    api::post_int(port, code);
+                }),
+            )
+        }
+        #[cfg(not(feature = "dart-api"))]
+        PortablePtyResult::ErrUnsupported
+    })
+}
+
+#[cfg(all(test, unix, feature = "dart-api"))]
+mod tests {
+    use super::*;
+    use crate::tests::open_and_spawn;
+    use api::fake::Message;
+    use std::sync::PoisonError;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_posts_output_and_exit_code() {
+        let mut fake = api::fake::api();
+        assert!(matches!(
+            portable_pty_dart_init(&mut fake as *mut api::Api as *mut c_void),
+            PortablePtyResult::Ok
+        ));
+
+        let handle = open_and_spawn("sh", &["sh", "-c", "echo hello; exit 3"]);
+        let port = 0x5eed;
+        let result = portable_pty_attach_dart_port(handle, port);
+        assert!(matches!(result, PortablePtyResult::Ok));
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let messages = loop {
+            let posted = api::fake::POSTED
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            let messages: Vec<_> = posted
+                .iter()
+                .filter(|(to, _)| *to == port)
+                .map(|(_, message)| message.clone())
+                .collect();
+            if matches!(messages.last(), Some(Message::Int(_))) {
+                break messages;
+            }
+            drop(posted);
+            assert!(Instant::now() < deadline, "no exit code posted");
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        let (end, output) = messages.split_last().unwrap();
+        let output: Vec<u8> = output
+            .iter()
+            .flat_map(|message| match message {
+                Message::Bytes(bytes) => bytes.clone(),
+                Message::Int(_) => panic!("more than one int posted"),
+            })
+            .collect();
+        assert!(String::from_utf8_lossy(&output).contains("hello"));
+        assert_eq!(*end, Message::Int(Some(3)));
+        crate::portable_pty_close(handle);
+    }
+}
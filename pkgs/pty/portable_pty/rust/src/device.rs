@@ -0,0 +1,594 @@
+//! Terminal devices that already exist.
+//!
+//! `portable_pty_open_device` wraps a serial port (`/dev/ttyUSB0`), the
+//! process's controlling terminal (`/dev/tty`) or any other tty in a
+//! handle, for consoles and device debugging. Reads, writes, expect,
+//! events and recording work as for a PTY; there is no child, so waiting
+//! and killing fail and nothing can be spawned. Closing the handle puts
+//! the device's settings back as they were.
+//!
+//! The config, a JSON object, changes only the settings it names:
+//!
+//! | key         | value                                                |
+//! |-------------|------------------------------------------------------|
+//! | `raw`       | true for raw mode (`cfmakeraw`)                      |
+//! | `echo`      | whether the device echoes input                      |
+//! | `baud`      | line speed, e.g. 115200                              |
+//! | `data_bits` | 5 to 8                                               |
+//! | `parity`    | `"none"`, `"even"` or `"odd"`                        |
+//! | `stop_bits` | 1 or 2                                               |
+//! | `flow`      | `"none"`, `"hardware"` (RTS/CTS) or `"software"`     |
+//! | `rows`      | the size reported when the device doesn't know its   |
+//! | `cols`      | own; 24x80 by default                                |
+//!
+//! Changing any setting also turns on `CLOCAL` and `CREAD`, so a serial
+//! line works without modem control.
+//!
+//! `portable_pty_open_stdio` does the same for the process's own stdin
+//! and stdout — inline mode, for command-line apps — putting the terminal
+//! in raw mode by default. Unix only, and only with the `serial` feature;
+//! otherwise opening returns `ErrUnsupported`.
+
+use crate::{PortablePty, PortablePtyResult};
+use serde_json::Value;
+use std::ffi::{c_char, CStr};
+
+#[cfg(all(unix, feature = "serial"))]
+mod tty {
+    use crate::pty::{Child, CommandBuilder, MasterPty, PtyPair, PtySize, SlavePty};
+    use crate::{PortablePty, PortablePtyResult};
+    use serde_json::Value;
+    use std::fs::{File, OpenOptions};
+    use std::io::{self, Read, Write};
+    use std::os::fd::{AsFd, AsRawFd, RawFd};
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::path::PathBuf;
+    use std::sync::{Mutex, PoisonError};
+
+    /// The settings a config asks for; None leaves one as it is.
+    struct Settings {
+        raw: Option<bool>,
+        echo: Option<bool>,
+        baud: Option<libc::speed_t>,
+        data_bits: Option<libc::tcflag_t>,
+        /// `PARENB` and `PARODD`, as they should be.
+        parity: Option<libc::tcflag_t>,
+        two_stop_bits: Option<bool>,
+        flow: Option<Flow>,
+    }
+
+    enum Flow {
+        None,
+        Hardware,
+        Software,
+    }
+
+    impl Settings {
+        /// None if a setting is malformed or unsupported.
+        fn parse(config: &Value) -> Option<Settings> {
+            let flag = |key: &str| match config.get(key) {
+                Some(v) => v.as_bool().map(Some),
+                None => Some(None),
+            };
+            let field = |key: &str| config.get(key);
+            Some(Settings {
+                raw: flag("raw")?,
+                echo: flag("echo")?,
+                baud: match field("baud") {
+                    Some(v) => Some(speed(u32::try_from(v.as_u64()?).ok()?)?),
+                    None => None,
+                },
+                data_bits: match field("data_bits") {
+                    Some(v) => Some(match v.as_u64()? {
+                        5 => libc::CS5,
+                        6 => libc::CS6,
+                        7 => libc::CS7,
+                        8 => libc::CS8,
+                        _ => return None,
+                    }),
+                    None => None,
+                },
+                parity: match field("parity") {
+                    Some(v) => Some(match v.as_str()? {
+                        "none" => 0,
+                        "even" => libc::PARENB,
+                        "odd" => libc::PARENB | libc::PARODD,
+                        _ => return None,
+                    }),
+                    None => None,
+                },
+                two_stop_bits: match field("stop_bits") {
+                    Some(v) => Some(match v.as_u64()? {
+                        1 => false,
+                        2 => true,
+                        _ => return None,
+                    }),
+                    None => None,
+                },
+                flow: match field("flow") {
+                    Some(v) => Some(match v.as_str()? {
+                        "none" => Flow::None,
+                        "hardware" => Flow::Hardware,
+                        "software" => Flow::Software,
+                        _ => return None,
+                    }),
+                    None => None,
+                },
+            })
+        }
+
+        fn is_empty(&self) -> bool {
+            self.raw.is_none()
+                && self.echo.is_none()
+                && self.baud.is_none()
+                && self.data_bits.is_none()
+                && self.parity.is_none()
+                && self.two_stop_bits.is_none()
+                && self.flow.is_none()
+        }
+
+        fn apply(&self, t: &mut libc::termios) {
+            if self.raw == Some(true) {
+                unsafe { libc::cfmakeraw(t) };
+            }
+            if let Some(echo) = self.echo {
+                set(&mut t.c_lflag, libc::ECHO, echo);
+            }
+            if let Some(baud) = self.baud {
+                unsafe {
+                    libc::cfsetispeed(t, baud);
+                    libc::cfsetospeed(t, baud);
+                }
+            }
+            if let Some(bits) = self.data_bits {
+                t.c_cflag = (t.c_cflag & !libc::CSIZE) | bits;
+            }
+            if let Some(parity) = self.parity {
+                t.c_cflag = (t.c_cflag & !(libc::PARENB | libc::PARODD)) | parity;
+            }
+            if let Some(two) = self.two_stop_bits {
+                set(&mut t.c_cflag, libc::CSTOPB, two);
+            }
+            if let Some(flow) = &self.flow {
+                set(
+                    &mut t.c_cflag,
+                    libc::CRTSCTS,
+                    matches!(flow, Flow::Hardware),
+                );
+                set(
+                    &mut t.c_iflag,
+                    libc::IXON | libc::IXOFF,
+                    matches!(flow, Flow::Software),
+                );
+            }
+            t.c_cflag |= libc::CLOCAL | libc::CREAD;
+        }
+    }
+
+    fn set(flags: &mut libc::tcflag_t, bits: libc::tcflag_t, on: bool) {
+        if on {
+            *flags |= bits;
+        } else {
+            *flags &= !bits;
+        }
+    }
+
+    /// The `speed_t` for a baud rate. Linux and Android only have the
+    /// `B*` constants; the BSDs and macOS take the rate itself.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn speed(baud: u32) -> Option<libc::speed_t> {
+        Some(match baud {
+            50 => libc::B50,
+            75 => libc::B75,
+            110 => libc::B110,
+            134 => libc::B134,
+            150 => libc::B150,
+            200 => libc::B200,
+            300 => libc::B300,
+            600 => libc::B600,
+            1200 => libc::B1200,
+            1800 => libc::B1800,
+            2400 => libc::B2400,
+            4800 => libc::B4800,
+            9600 => libc::B9600,
+            19200 => libc::B19200,
+            38400 => libc::B38400,
+            57600 => libc::B57600,
+            115200 => libc::B115200,
+            230400 => libc::B230400,
+            460800 => libc::B460800,
+            500000 => libc::B500000,
+            576000 => libc::B576000,
+            921600 => libc::B921600,
+            1000000 => libc::B1000000,
+            1152000 => libc::B1152000,
+            1500000 => libc::B1500000,
+            2000000 => libc::B2000000,
+            2500000 => libc::B2500000,
+            3000000 => libc::B3000000,
+            3500000 => libc::B3500000,
+            4000000 => libc::B4000000,
+            _ => return None,
+        })
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    fn speed(baud: u32) -> Option<libc::speed_t> {
+        Some(baud as libc::speed_t)
+    }
+
+    fn get_attr(file: &File) -> io::Result<libc::termios> {
+        let mut t = std::mem::MaybeUninit::<libc::termios>::uninit();
+        if unsafe { libc::tcgetattr(file.as_raw_fd(), t.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(unsafe { t.assume_init() })
+    }
+
+    fn set_attr(file: &File, t: &libc::termios) -> io::Result<()> {
+        if unsafe { libc::tcsetattr(file.as_raw_fd(), libc::TCSANOW, t) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Open the tty at `path` with the settings `config` asks for.
+    pub(super) fn open(path: &str, config: &Value) -> Result<Box<PortablePty>, PortablePtyResult> {
+        // Non-blocking so a serial port without carrier doesn't hang the
+        // open; reads and writes block as usual afterwards.
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY | libc::O_NONBLOCK)
+            .open(path)
+            .map_err(|_| PortablePtyResult::ErrOpen)?;
+        let fd = file.as_raw_fd();
+        unsafe {
+            let flags = libc::fcntl(fd, libc::F_GETFL);
+            libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK);
+        }
+        let output = file.try_clone().map_err(|_| PortablePtyResult::ErrOpen)?;
+        wrap(file, output, config, true)
+    }
+
+    /// Wrap the process's own stdin and stdout, with the settings `config`
+    /// asks for applied to stdin if it's a terminal.
+    pub(super) fn open_stdio(config: &Value) -> Result<Box<PortablePty>, PortablePtyResult> {
+        let input = io::stdin().as_fd().try_clone_to_owned();
+        let output = io::stdout().as_fd().try_clone_to_owned();
+        match (input, output) {
+            (Ok(input), Ok(output)) => wrap(input.into(), output.into(), config, false),
+            _ => Err(PortablePtyResult::ErrOpen),
+        }
+    }
+
+    /// Make a handle reading `input` and writing `output`. Unless
+    /// `require_tty`, `input` may be something other than a terminal, in
+    /// which case the settings are ignored.
+    fn wrap(
+        input: File,
+        output: File,
+        config: &Value,
+        require_tty: bool,
+    ) -> Result<Box<PortablePty>, PortablePtyResult> {
+        let settings = Settings::parse(config).ok_or(PortablePtyResult::ErrOpen)?;
+        let size = crate::backend::parse_size(config).ok_or(PortablePtyResult::ErrOpen)?;
+        let restore = match get_attr(&input) {
+            Ok(original) if !settings.is_empty() => {
+                let mut t = original;
+                settings.apply(&mut t);
+                set_attr(&input, &t).map_err(|_| PortablePtyResult::ErrMode)?;
+                Some(original)
+            }
+            Ok(_) => None,
+            Err(_) if require_tty => return Err(PortablePtyResult::ErrOpen),
+            Err(_) => None,
+        };
+
+        let master = DeviceMaster {
+            input,
+            output,
+            restore,
+            size: Mutex::new(size),
+        };
+        PortablePty::from_pair(PtyPair {
+            slave: Box::new(DeviceSlave),
+            master: Box::new(master),
+        })
+    }
+
+    struct DeviceMaster {
+        input: File,
+        output: File,
+        /// Settings to put back on `input` on close, if we changed them.
+        restore: Option<libc::termios>,
+        /// Reported when the terminal doesn't know its own size.
+        size: Mutex<PtySize>,
+    }
+
+    /// The size the terminal on `fd` reports, if it knows it.
+    fn window_size(fd: RawFd) -> Option<PtySize> {
+        let mut ws: libc::winsize = unsafe { std::mem::zeroed() };
+        if unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut ws) } != 0
+            || ws.ws_row == 0
+            || ws.ws_col == 0
+        {
+            return None;
+        }
+        Some(PtySize {
+            rows: ws.ws_row,
+            cols: ws.ws_col,
+            pixel_width: ws.ws_xpixel,
+            pixel_height: ws.ws_ypixel,
+        })
+    }
+
+    impl Drop for DeviceMaster {
+        fn drop(&mut self) {
+            if let Some(original) = &self.restore {
+                let _ = set_attr(&self.input, original);
+            }
+        }
+    }
+
+    impl MasterPty for DeviceMaster {
+        // The size belongs to the terminal (or the serial line's far end),
+        // not to us; a resize only changes the fallback.
+        fn resize(&self, size: PtySize) -> anyhow::Result<()> {
+            *self.size.lock().unwrap_or_else(PoisonError::into_inner) = size;
+            Ok(())
+        }
+
+        fn get_size(&self) -> anyhow::Result<PtySize> {
+            let known = window_size(self.output.as_raw_fd())
+                .or_else(|| window_size(self.input.as_raw_fd()));
+            Ok(known.unwrap_or(*self.size.lock().unwrap_or_else(PoisonError::into_inner)))
+        }
+
+        fn try_clone_reader(&self) -> anyhow::Result<Box<dyn Read + Send>> {
+            Ok(Box::new(self.input.try_clone()?))
+        }
+
+        fn take_writer(&self) -> anyhow::Result<Box<dyn Write + Send>> {
+            Ok(Box::new(self.output.try_clone()?))
+        }
+
+        fn process_group_leader(&self) -> Option<libc::pid_t> {
+            match unsafe { libc::tcgetpgrp(self.input.as_raw_fd()) } {
+                pid if pid > 0 => Some(pid),
+                _ => None,
+            }
+        }
+
+        fn as_raw_fd(&self) -> Option<RawFd> {
+            Some(self.input.as_raw_fd())
+        }
+
+        fn tty_name(&self) -> Option<PathBuf> {
+            None
+        }
+    }
+
+    struct DeviceSlave;
+
+    impl SlavePty for DeviceSlave {
+        fn spawn_command(
+            &self,
+            _cmd: CommandBuilder,
+        ) -> anyhow::Result<Box<dyn Child + Send + Sync>> {
+            anyhow::bail!("device handles can't spawn processes")
+        }
+    }
+}
+
+/// Open the tty at `path`; see the module docs for `config`.
+#[cfg(all(unix, feature = "serial"))]
+fn open(path: &str, config: &Value) -> Result<Box<PortablePty>, PortablePtyResult> {
+    tty::open(path, config)
+}
+
+#[cfg(not(all(unix, feature = "serial")))]
+fn open(_path: &str, _config: &Value) -> Result<Box<PortablePty>, PortablePtyResult> {
+    Err(PortablePtyResult::ErrUnsupported)
+}
+
+#[cfg(all(unix, feature = "serial"))]
+fn open_stdio(config: &Value) -> Result<Box<PortablePty>, PortablePtyResult> {
+    tty::open_stdio(config)
+}
+
+#[cfg(not(all(unix, feature = "serial")))]
+fn open_stdio(_config: &Value) -> Result<Box<PortablePty>, PortablePtyResult> {
+    Err(PortablePtyResult::ErrUnsupported)
+}
+
+/// Parse a config argument: a JSON object, or `default` for NULL.
+fn parse_config(config: *const c_char, default: Value) -> Option<Value> {
+    let config = if config.is_null() {
+        Some(default)
+    } else {
+        unsafe { CStr::from_ptr(config) }
+            .to_str()
+            .ok()
+            .and_then(|json| serde_json::from_str(json).ok())
+    };
+    config.filter(Value::is_object)
+}
+
+fn hand_out(
+    opened: Result<Box<PortablePty>, PortablePtyResult>,
+    out: *mut *mut PortablePty,
+) -> PortablePtyResult {
+    match opened {
+        Ok(handle) => {
+            unsafe {
+                *out = crate::lifecycle::register(handle);
+            }
+            PortablePtyResult::Ok
+        }
+        Err(e) => e,
+    }
+}
+
+/// Open the `device` backend: `path` plus the settings.
+pub(crate) fn open_config(config: &Value) -> Result<Box<PortablePty>, PortablePtyResult> {
+    let path = config.get("path").and_then(Value::as_str);
+    open(path.ok_or(PortablePtyResult::ErrOpen)?, config)
+}
+
+/// Wrap an existing terminal device in a handle.
+///
+/// - `path`: null-terminated path of the device, e.g. `/dev/ttyUSB0`.
+/// - `config`: null-terminated JSON object of settings to change, or NULL
+///   to leave the device as it is: `raw` (`cfmakeraw`) and `echo`
+///   booleans, `baud` (e.g. 115200), `data_bits` (5 to 8), `parity`
+///   (`"none"`, `"even"` or `"odd"`), `stop_bits` (1 or 2), `flow`
+///   (`"none"`, `"hardware"` or `"software"`), and `rows` and `cols` for
+///   the size reported if the device doesn't know its own (24x80 by
+///   default). Changing any setting also turns on `CLOCAL` and `CREAD`.
+/// - `out`: receives the new handle; close it with `portable_pty_close`.
+///
+/// There's no child, so waiting, killing and spawning fail. Closing the
+/// handle puts the device's settings back as they were.
+///
+/// Returns `ErrOpen` if the device can't be opened, isn't a terminal or
+/// the config is malformed (including an unsupported baud rate),
+/// `ErrMode` if the device refuses the settings, and `ErrUnsupported` on
+/// Windows or without the `serial` feature.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_open_device(
+    path: *const c_char,
+    config: *const c_char,
+    out: *mut *mut PortablePty,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        if path.is_null() || out.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+        let Ok(path) = unsafe { CStr::from_ptr(path) }.to_str() else {
+            return PortablePtyResult::ErrOpen;
+        };
+        let Some(config) = parse_config(config, Value::Object(Default::default())) else {
+            return PortablePtyResult::ErrOpen;
+        };
+        hand_out(open(path, &config), out)
+    })
+}
+
+/// Wrap the calling process's own stdin and stdout in a handle.
+///
+/// For command-line apps that drive a full-screen UI through the same API
+/// as a PTY: reads come from stdin, writes go to stdout, and the size is
+/// the real terminal's. Nothing reports when the user resizes it; watch
+/// for `SIGWINCH` and ask `portable_pty_get_size` again.
+///
+/// - `config`: null-terminated JSON object of settings for stdin, as for
+///   `portable_pty_open_device`, or NULL for `{"raw": true}`. Ignored if
+///   stdin isn't a terminal.
+/// - `out`: receives the new handle; closing it restores the terminal.
+///
+/// Returns `ErrOpen` if the config is malformed, `ErrMode` if the terminal
+/// refuses the settings, and `ErrUnsupported` on Windows or
+/// without the `serial` feature.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_open_stdio(
+    config: *const c_char,
+    out: *mut *mut PortablePty,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        if out.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+        let Some(config) = parse_config(config, serde_json::json!({ "raw": true })) else {
+            return PortablePtyResult::ErrOpen;
+        };
+        hand_out(open_stdio(&config), out)
+    })
+}
+
+#[cfg(all(test, unix, feature = "serial"))]
+mod tests {
+    use super::*;
+    use crate::pty::{native_pty_system, PtySize};
+    use std::ffi::CString;
+    use std::io::{Read, Write};
+
+    fn echo_enabled(path: &str) -> bool {
+        let file = std::fs::File::open(path).unwrap();
+        let mut t = std::mem::MaybeUninit::<libc::termios>::uninit();
+        use std::os::fd::AsRawFd;
+        assert_eq!(
+            unsafe { libc::tcgetattr(file.as_raw_fd(), t.as_mut_ptr()) },
+            0
+        );
+        unsafe { t.assume_init() }.c_lflag & libc::ECHO != 0
+    }
+
+    #[test]
+    fn test_reads_writes_and_restores_device() {
+        // A PTY's slave side stands in for the device.
+        let pair = native_pty_system().openpty(PtySize::default()).unwrap();
+        let tty = pair.master.tty_name().unwrap();
+        let tty = tty.to_str().unwrap();
+        assert!(echo_enabled(tty));
+
+        let c_path = CString::new(tty).unwrap();
+        let mut handle = std::ptr::null_mut();
+        let result = portable_pty_open_device(
+            c_path.as_ptr(),
+            c"{\"raw\": true, \"baud\": 115200}".as_ptr(),
+            &mut handle,
+        );
+        assert!(matches!(result, PortablePtyResult::Ok));
+        assert!(!echo_enabled(tty));
+
+        let mut far_end = pair.master.take_writer().unwrap();
+        far_end.write_all(b"from device").unwrap();
+        assert_eq!(crate::tests::read_string(handle), "from device");
+        crate::portable_pty_write(handle, b"to device".as_ptr(), 9);
+        let mut buf = [0u8; 9];
+        pair.master
+            .try_clone_reader()
+            .unwrap()
+            .read_exact(&mut buf)
+            .unwrap();
+        assert_eq!(&buf, b"to device");
+
+        crate::portable_pty_close(handle);
+        assert!(echo_enabled(tty));
+    }
+
+    #[test]
+    fn test_rejects_bad_devices_and_settings() {
+        let mut handle = std::ptr::null_mut();
+        let result = portable_pty_open_device(c"/dev/null".as_ptr(), std::ptr::null(), &mut handle);
+        assert!(matches!(result, PortablePtyResult::ErrOpen));
+
+        let pair = native_pty_system().openpty(PtySize::default()).unwrap();
+        let tty = CString::new(pair.master.tty_name().unwrap().to_str().unwrap()).unwrap();
+        let result =
+            portable_pty_open_device(tty.as_ptr(), c"{\"data_bits\": 9}".as_ptr(), &mut handle);
+        assert!(matches!(result, PortablePtyResult::ErrOpen));
+        let result = portable_pty_open_device(
+            tty.as_ptr(),
+            c"{\"parity\": \"mark\"}".as_ptr(),
+            &mut handle,
+        );
+        assert!(matches!(result, PortablePtyResult::ErrOpen));
+    }
+
+    #[test]
+    fn test_opens_stdio() {
+        // No settings: leave whatever terminal the tests run in alone.
+        let mut handle = std::ptr::null_mut();
+        let result = portable_pty_open_stdio(c"{}".as_ptr(), &mut handle);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        let (mut rows, mut cols, mut width, mut height) = (0, 0, 0, 0);
+        crate::portable_pty_get_size(handle, &mut rows, &mut cols, &mut width, &mut height);
+        assert!(rows > 0 && cols > 0);
+        crate::portable_pty_close(handle);
+
+        let result = portable_pty_open_stdio(c"{\"echo\": 1}".as_ptr(), &mut handle);
+        assert!(matches!(result, PortablePtyResult::ErrOpen));
+    }
+}
@@ -0,0 +1,469 @@
+//! The environment and directory a process on the terminal is really
+//! running with.
+//!
+//! `portable_pty_spawned_command` says what the library handed the child;
+//! `portable_pty_child_environ` asks the system what a process actually
+//! has, for working out why `PATH` or the locale isn't what it should be
+//! in a terminal. The system keeps the environment a process was started
+//! with: a shell's own `export`s don't show in it, but they do in what the
+//! commands it runs inherited, so asking about the foreground process
+//! instead of the shell shows those.
+//!
+//! `portable_pty_child_cwd` is the foreground process's current
+//! directory, which unlike the environment follows every `cd`. It's what
+//! a "new tab here" can open in when the shell doesn't report its
+//! directory with OSC 7.
+//!
+//! The environment is read from `/proc/<pid>/environ` on Linux and
+//! Android, and with `sysctl` on macOS, iOS and FreeBSD. The directory is
+//! read from `/proc/<pid>/cwd` on Linux and Android, with `libproc` on
+//! macOS, with `sysctl` on FreeBSD and from the process's parameter block
+//! on Windows. Other systems return `ErrUnsupported`.
+
+use crate::{PortablePty, PortablePtyBuffer, PortablePtyResult};
+use serde_json::{Map, Value};
+use std::io;
+
+/// The `NUL`-separated `KEY=value` entries of process `pid`'s environment.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn read_environ(pid: i32) -> io::Result<Vec<u8>> {
+    std::fs::read(format!("/proc/{pid}/environ"))
+}
+
+/// Fill `buf` from the `sysctl` named by `mib`, returning what was written.
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd"))]
+fn sysctl(mib: &mut [libc::c_int], buf: &mut [u8]) -> io::Result<usize> {
+    let mut size = buf.len();
+    let ret = unsafe {
+        libc::sysctl(
+            mib.as_mut_ptr(),
+            mib.len() as libc::c_uint,
+            buf.as_mut_ptr().cast(),
+            &mut size,
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(size)
+}
+
+/// As for Linux, taken from `KERN_PROCARGS2`: the argument count, the
+/// executable's path, then `argv` and the environment, all `NUL`-separated.
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+fn read_environ(pid: i32) -> io::Result<Vec<u8>> {
+    let mut arg_max: libc::c_int = 0;
+    let size = sysctl(&mut [libc::CTL_KERN, libc::KERN_ARGMAX], unsafe {
+        std::slice::from_raw_parts_mut((&mut arg_max as *mut libc::c_int).cast(), 4)
+    })?;
+    if size != 4 || arg_max <= 4 {
+        return Err(io::ErrorKind::InvalidData.into());
+    }
+    let mut buf = vec![0u8; arg_max as usize];
+    let size = sysctl(&mut [libc::CTL_KERN, libc::KERN_PROCARGS2, pid], &mut buf)?;
+    buf.truncate(size);
+
+    let argc = buf
+        .get(..4)
+        .map(|n| i32::from_ne_bytes(n.try_into().unwrap()))
+        .ok_or(io::ErrorKind::InvalidData)?;
+    let mut rest = &buf[4..];
+    // The path is padded with extra NULs up to the first argument.
+    rest = skip_string(rest);
+    while let [0, tail @ ..] = rest {
+        rest = tail;
+    }
+    for _ in 0..argc {
+        rest = skip_string(rest);
+    }
+    Ok(rest.to_vec())
+}
+
+/// What follows the first `NUL` in `bytes`.
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+fn skip_string(bytes: &[u8]) -> &[u8] {
+    match bytes.iter().position(|&b| b == 0) {
+        Some(end) => &bytes[end + 1..],
+        None => &[],
+    }
+}
+
+#[cfg(target_os = "freebsd")]
+fn read_environ(pid: i32) -> io::Result<Vec<u8>> {
+    let mut mib = [libc::CTL_KERN, libc::KERN_PROC, libc::KERN_PROC_ENV, pid];
+    // Sized by asking with no buffer; the environment can't grow after
+    // exec, so that's enough.
+    let mut size = 0;
+    let ret = unsafe {
+        libc::sysctl(
+            mib.as_ptr(),
+            mib.len() as libc::c_uint,
+            std::ptr::null_mut(),
+            &mut size,
+            std::ptr::null(),
+            0,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut buf = vec![0u8; size];
+    let size = sysctl(&mut mib, &mut buf)?;
+    buf.truncate(size);
+    Ok(buf)
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd"
+)))]
+fn read_environ(_pid: i32) -> io::Result<Vec<u8>> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Process `pid`'s current directory.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn read_cwd(pid: i32) -> io::Result<Vec<u8>> {
+    use std::os::unix::ffi::OsStringExt;
+    Ok(std::fs::read_link(format!("/proc/{pid}/cwd"))?
+        .into_os_string()
+        .into_vec())
+}
+
+#[cfg(target_os = "macos")]
+fn read_cwd(pid: i32) -> io::Result<Vec<u8>> {
+    let mut info: libc::proc_vnodepathinfo = unsafe { std::mem::zeroed() };
+    let size = std::mem::size_of::<libc::proc_vnodepathinfo>() as libc::c_int;
+    let n = unsafe {
+        libc::proc_pidinfo(
+            pid,
+            libc::PROC_PIDVNODEPATHINFO,
+            0,
+            &mut info as *mut _ as *mut libc::c_void,
+            size,
+        )
+    };
+    if n != size {
+        return Err(io::Error::last_os_error());
+    }
+    let path = info.pvi_cdir.vip_path.as_flattened();
+    let path = unsafe { std::ffi::CStr::from_ptr(path.as_ptr()) };
+    Ok(path.to_bytes().to_vec())
+}
+
+#[cfg(target_os = "freebsd")]
+fn read_cwd(pid: i32) -> io::Result<Vec<u8>> {
+    let mut mib = [libc::CTL_KERN, libc::KERN_PROC, libc::KERN_PROC_CWD, pid];
+    let mut file: libc::kinfo_file = unsafe { std::mem::zeroed() };
+    sysctl(&mut mib, unsafe {
+        std::slice::from_raw_parts_mut(
+            (&mut file as *mut libc::kinfo_file).cast(),
+            std::mem::size_of::<libc::kinfo_file>(),
+        )
+    })?;
+    let path = unsafe { std::ffi::CStr::from_ptr(file.kf_path.as_ptr()) };
+    Ok(path.to_bytes().to_vec())
+}
+
+/// As UTF-8, from `CurrentDirectory` in the process parameters the
+/// process environment block points to. Reading those of a 64-bit process
+/// from a 32-bit one isn't supported.
+#[cfg(windows)]
+fn read_cwd(pid: i32) -> io::Result<Vec<u8>> {
+    use std::ffi::c_void;
+    use std::mem::size_of;
+    use std::os::windows::io::{AsRawHandle, FromRawHandle, OwnedHandle};
+    use winapi::um::memoryapi::ReadProcessMemory;
+    use winapi::um::processthreadsapi::OpenProcess;
+    use winapi::um::winnt::{HANDLE, PROCESS_QUERY_INFORMATION, PROCESS_VM_READ};
+
+    /// `PROCESS_BASIC_INFORMATION`.
+    #[repr(C)]
+    struct BasicInformation {
+        exit_status: i32,
+        peb_base_address: usize,
+        affinity_mask: usize,
+        base_priority: i32,
+        unique_process_id: usize,
+        inherited_from_unique_process_id: usize,
+    }
+
+    /// `UNICODE_STRING`.
+    #[repr(C)]
+    #[derive(Default)]
+    struct UnicodeString {
+        length: u16,
+        maximum_length: u16,
+        buffer: usize,
+    }
+
+    #[link(name = "ntdll")]
+    unsafe extern "system" {
+        fn NtQueryInformationProcess(
+            process: HANDLE,
+            class: u32,
+            information: *mut c_void,
+            length: u32,
+            return_length: *mut u32,
+        ) -> i32;
+    }
+    const PROCESS_BASIC_INFORMATION: u32 = 0;
+    // Offsets, in pointers, of `ProcessParameters` in the PEB and past the
+    // 16 bytes of flags of `CurrentDirectory` in the parameters.
+    const PROCESS_PARAMETERS: usize = 4;
+    const CURRENT_DIRECTORY: usize = 5;
+
+    let process =
+        unsafe { OpenProcess(PROCESS_QUERY_INFORMATION | PROCESS_VM_READ, 0, pid as u32) };
+    if process.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    let process = unsafe { OwnedHandle::from_raw_handle(process as _) };
+    let raw = process.as_raw_handle() as HANDLE;
+    let read = |address: usize, into: *mut c_void, len: usize| -> io::Result<()> {
+        let ok = unsafe { ReadProcessMemory(raw, address as _, into, len, std::ptr::null_mut()) };
+        if ok == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    };
+
+    let mut basic: BasicInformation = unsafe { std::mem::zeroed() };
+    let status = unsafe {
+        NtQueryInformationProcess(
+            raw,
+            PROCESS_BASIC_INFORMATION,
+            (&mut basic as *mut BasicInformation).cast(),
+            size_of::<BasicInformation>() as u32,
+            std::ptr::null_mut(),
+        )
+    };
+    if status < 0 || basic.peb_base_address == 0 {
+        return Err(io::ErrorKind::PermissionDenied.into());
+    }
+    let mut parameters = 0usize;
+    read(
+        basic.peb_base_address + PROCESS_PARAMETERS * size_of::<usize>(),
+        (&mut parameters as *mut usize).cast(),
+        size_of::<usize>(),
+    )?;
+    let mut dir = UnicodeString::default();
+    read(
+        parameters + 16 + CURRENT_DIRECTORY * size_of::<usize>(),
+        (&mut dir as *mut UnicodeString).cast(),
+        size_of::<UnicodeString>(),
+    )?;
+    let mut path = vec![0u16; dir.length as usize / 2];
+    read(dir.buffer, path.as_mut_ptr().cast(), path.len() * 2)?;
+    // It ends in a backslash, which only a root like `C:\` keeps.
+    if path.len() > 3 && path.last() == Some(&(b'\\' as u16)) {
+        path.pop();
+    }
+    Ok(String::from_utf16_lossy(&path).into_bytes())
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "freebsd",
+    windows
+)))]
+fn read_cwd(_pid: i32) -> io::Result<Vec<u8>> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// `NUL`-separated `KEY=value` entries as a JSON object. An empty entry
+/// ends them, as one does after the environment on macOS.
+fn to_json(environ: &[u8]) -> Value {
+    let env: Map<_, _> = environ
+        .split(|&b| b == 0)
+        .take_while(|entry| !entry.is_empty())
+        .filter_map(|entry| {
+            let at = entry.iter().position(|&b| b == b'=')?;
+            let text = |bytes: &[u8]| String::from_utf8_lossy(bytes).into_owned();
+            Some((text(&entry[..at]), Value::from(text(&entry[at + 1..]))))
+        })
+        .collect();
+    Value::Object(env)
+}
+
+/// The child, or with `foreground` the process group leader in the
+/// terminal's foreground. Off Unix, that's the child.
+fn target_pid(pty: &PortablePty, foreground: bool) -> Result<i32, PortablePtyResult> {
+    #[cfg(unix)]
+    if foreground {
+        return match pty.master.process_group_leader() {
+            Some(pid) if pid > 0 => Ok(pid),
+            _ => Err(PortablePtyResult::ErrProcessGroup),
+        };
+    }
+    #[cfg(not(unix))]
+    let _ = foreground;
+    match pty.child_pid {
+        pid if pid > 0 => Ok(pid),
+        _ => Err(PortablePtyResult::ErrWait),
+    }
+}
+
+/// The result for failing to read about a process.
+fn read_error(e: io::Error) -> PortablePtyResult {
+    match e.kind() {
+        io::ErrorKind::Unsupported => PortablePtyResult::ErrUnsupported,
+        io::ErrorKind::PermissionDenied => PortablePtyResult::ErrDenied,
+        _ => PortablePtyResult::ErrWait,
+    }
+}
+
+/// Read the environment the system has for the handle's child, or for
+/// the terminal's foreground process. That's what the process was started
+/// with: a shell's own `export`s don't show in it, but they do in the
+/// environment of the commands it runs, so ask about the foreground
+/// process to see those.
+///
+/// - `foreground`: false for the child, true for the process group leader
+///   in the foreground, which is the child itself while nothing else is.
+/// - `out_json`: receives a UTF-8 JSON object of the environment, to free
+///   with `portable_pty_buffer_free`. Bytes that aren't UTF-8 are replaced
+///   with U+FFFD.
+///
+/// Returns `ErrWait` if there's no child or it has gone,
+/// `ErrProcessGroup` if there's no foreground process to ask about,
+/// `ErrDenied` if the system won't say (another user's process, say) and
+/// `ErrUnsupported` on systems it can't be read on.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_child_environ(
+    handle: *const PortablePty,
+    foreground: bool,
+    out_json: *mut PortablePtyBuffer,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        if out_json.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+        let environ = match target_pid(pty, foreground)
+            .and_then(|pid| read_environ(pid).map_err(read_error))
+        {
+            Ok(environ) => environ,
+            Err(e) => return e,
+        };
+        let json = to_json(&environ).to_string();
+        unsafe { *out_json = PortablePtyBuffer::from_vec(json.into_bytes()) };
+        PortablePtyResult::Ok
+    })
+}
+
+/// Find the current directory of the terminal's foreground process, or
+/// off Unix of the child. It follows every `cd`, so it's what a "new tab
+/// here" can open in when the shell doesn't report its directory.
+///
+/// - `out_path`: receives the path, to free with `portable_pty_buffer_free`.
+///   It's the system's bytes on Unix and UTF-8 on Windows, and isn't
+///   NUL-terminated.
+///
+/// Returns `ErrWait` if there's no child or it has gone,
+/// `ErrProcessGroup` if there's no foreground process to ask about,
+/// `ErrDenied` if the system won't say and `ErrUnsupported` on systems it
+/// can't be read on.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_child_cwd(
+    handle: *const PortablePty,
+    out_path: *mut PortablePtyBuffer,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        if out_path.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+        match target_pid(pty, true).and_then(|pid| read_cwd(pid).map_err(read_error)) {
+            Ok(path) => {
+                unsafe { *out_path = PortablePtyBuffer::from_vec(path) };
+                PortablePtyResult::Ok
+            }
+            Err(e) => e,
+        }
+    })
+}
+
+#[cfg(all(
+    test,
+    any(target_os = "linux", target_os = "android", target_os = "macos")
+))]
+mod tests {
+    use super::*;
+    use crate::tests::{open_and_spawn, read_string};
+    use std::os::unix::ffi::OsStringExt;
+
+    fn environ(handle: *const PortablePty, foreground: bool) -> Value {
+        let mut out = PortablePtyBuffer::from_vec(Vec::new());
+        let result = portable_pty_child_environ(handle, foreground, &mut out);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        let bytes = unsafe { std::slice::from_raw_parts(out.data, out.len) };
+        let json = serde_json::from_slice(bytes).unwrap();
+        crate::portable_pty_buffer_free(out);
+        json
+    }
+
+    #[test]
+    fn test_child_environ() {
+        // The shell exports a variable, then runs a command that inherits
+        // it.
+        let script = "export EXPORTED=later; echo ready; exec sleep 5";
+        let handle = open_and_spawn("sh", &["sh", "-c", script]);
+        let mut output = String::new();
+        while !output.contains("ready") {
+            output += &read_string(handle);
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        // `sleep` replaced the shell, so the child and the foreground
+        // process are one and the same.
+        for foreground in [false, true] {
+            let env = environ(handle, foreground);
+            assert_eq!(env["EXPORTED"], "later", "{env}");
+            assert!(env["PATH"].is_string(), "{env}");
+        }
+        crate::portable_pty_close(handle);
+    }
+
+    #[test]
+    fn test_child_cwd() {
+        let dir = std::fs::canonicalize(std::env::temp_dir()).unwrap();
+        let script = format!("cd '{}' && echo ready && sleep 5", dir.display());
+        let handle = open_and_spawn("sh", &["sh", "-c", &script]);
+        let mut output = String::new();
+        while !output.contains("ready") {
+            output += &read_string(handle);
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        let mut out = PortablePtyBuffer::from_vec(Vec::new());
+        let result = portable_pty_child_cwd(handle, &mut out);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        let path = unsafe { std::slice::from_raw_parts(out.data, out.len) }.to_vec();
+        crate::portable_pty_buffer_free(out);
+        assert_eq!(path, dir.into_os_string().into_vec());
+        crate::portable_pty_close(handle);
+    }
+
+    #[test]
+    fn test_parses_entries() {
+        let env = to_json(b"A=1\0B=x=y\0junk\0C=\xff\0\0ignored=1\0");
+        assert_eq!(
+            env,
+            serde_json::json!({ "A": "1", "B": "x=y", "C": "\u{fffd}" })
+        );
+    }
+}
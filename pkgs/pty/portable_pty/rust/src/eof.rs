@@ -0,0 +1,208 @@
+//! When reads reach end of file.
+//!
+//! The master only reports end of file once every holder of the slave end
+//! has closed it, and the handle holds one itself. What that means for
+//! reads is chosen per handle:
+//!
+//! - `PORTABLE_PTY_EOF_CHILD_EXIT`, the default on Unix: the handle keeps
+//!   its slave, and reads end once the child has exited and everything
+//!   queued has been read. Output from descendants still running after
+//!   that isn't waited for.
+//! - `PORTABLE_PTY_EOF_NEVER`: the handle keeps its slave and reads never
+//!   end; the caller closes the handle when it's done. This is what
+//!   handles on Windows and on the other backends do.
+//! - `PORTABLE_PTY_EOF_ALL_EXIT`: the handle closes its slave once the
+//!   child is spawned, so reads end when the child and everything it
+//!   started have closed the terminal. On Linux, output still queued when
+//!   the last of them does may be lost; the child-exit policy exists to
+//!   avoid that. Nothing more can be spawned on the handle afterwards.
+//!
+//! Policies other than the handle's own need a local PTY on Unix; elsewhere
+//! setting them returns `ErrUnsupported`.
+
+use crate::{PortablePty, PortablePtyResult};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Reads end once the child has exited and its output has been read.
+pub const PORTABLE_PTY_EOF_CHILD_EXIT: u32 = 0;
+/// Reads never end.
+pub const PORTABLE_PTY_EOF_NEVER: u32 = 1;
+/// Reads end once nothing holds the terminal open any more.
+pub const PORTABLE_PTY_EOF_ALL_EXIT: u32 = 2;
+
+/// A handle's policy.
+pub(crate) struct EofPolicy {
+    policy: AtomicU32,
+    /// Whether the handle is a local Unix PTY, which can follow any.
+    local: bool,
+}
+
+impl EofPolicy {
+    /// The policy a handle starts with: child exit on a local Unix PTY,
+    /// never elsewhere.
+    pub(crate) fn new(local: bool) -> Self {
+        let local = cfg!(unix) && local;
+        let policy = if local {
+            PORTABLE_PTY_EOF_CHILD_EXIT
+        } else {
+            PORTABLE_PTY_EOF_NEVER
+        };
+        EofPolicy {
+            policy: AtomicU32::new(policy),
+            local,
+        }
+    }
+
+    pub(crate) fn get(&self) -> u32 {
+        self.policy.load(Ordering::Relaxed)
+    }
+}
+
+/// Choose when reads on the handle reach end of file.
+///
+/// - `policy`: `PORTABLE_PTY_EOF_CHILD_EXIT`, the default on Unix, to end
+///   once the child has exited and its queued output has been read,
+///   without waiting for descendants still running;
+///   `PORTABLE_PTY_EOF_NEVER`, what other handles do, for reads never to
+///   end; or `PORTABLE_PTY_EOF_ALL_EXIT` to end when the child and
+///   everything it started have closed the terminal. That closes the
+///   handle's slave, so nothing more can be spawned, and on Linux output
+///   still queued at the end may be lost.
+///
+/// Returns `ErrUnsupported` for an unknown policy, or one the handle can't
+/// follow: policies other than its own need a local PTY on Unix.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_set_eof_policy(
+    handle: *mut PortablePty,
+    policy: u32,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_mut() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        let known = matches!(
+            policy,
+            PORTABLE_PTY_EOF_CHILD_EXIT | PORTABLE_PTY_EOF_NEVER | PORTABLE_PTY_EOF_ALL_EXIT
+        );
+        if !known || (!pty.eof_policy.local && policy != pty.eof_policy.get()) {
+            return PortablePtyResult::ErrUnsupported;
+        }
+        pty.eof_policy.policy.store(policy, Ordering::Relaxed);
+        if policy == PORTABLE_PTY_EOF_ALL_EXIT && pty.child.is_some() {
+            pty.slave = None;
+        }
+        PortablePtyResult::Ok
+    })
+}
+
+/// Query when reads on the handle reach end of file.
+///
+/// - `out_policy`: receives one of the `PORTABLE_PTY_EOF_*` constants.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_eof_policy(
+    handle: *const PortablePty,
+    out_policy: *mut u32,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        if out_policy.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+        unsafe { *out_policy = pty.eof_policy.get() };
+        PortablePtyResult::Ok
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::portable_pty_read;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    /// Read until the end, on another thread; None if it doesn't come.
+    fn read_to_end(handle: *mut PortablePty, timeout: Duration) -> Option<String> {
+        let address = handle as usize;
+        let (done, ended) = mpsc::channel();
+        std::thread::spawn(move || {
+            let (mut output, mut buf) = (Vec::new(), [0u8; 4096]);
+            loop {
+                let n = portable_pty_read(address as *mut PortablePty, buf.as_mut_ptr(), 4096);
+                if n <= 0 {
+                    let _ = done.send(String::from_utf8_lossy(&output).into_owned());
+                    return;
+                }
+                output.extend_from_slice(&buf[..n as usize]);
+            }
+        });
+        ended.recv_timeout(timeout).ok()
+    }
+
+    fn policy(handle: *mut PortablePty) -> u32 {
+        let mut policy = u32::MAX;
+        assert!(matches!(
+            portable_pty_eof_policy(handle, &mut policy),
+            PortablePtyResult::Ok
+        ));
+        policy
+    }
+
+    #[test]
+    fn test_all_exit_waits_for_descendants() {
+        let mut handle = std::ptr::null_mut();
+        crate::portable_pty_open(24, 80, &mut handle);
+        assert_eq!(policy(handle), PORTABLE_PTY_EOF_CHILD_EXIT);
+        let result = portable_pty_set_eof_policy(handle, PORTABLE_PTY_EOF_ALL_EXIT);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        assert_eq!(policy(handle), PORTABLE_PTY_EOF_ALL_EXIT);
+
+        let script = c"(trap '' HUP; sleep 0.3; echo late) & echo early";
+        let argv = [
+            c"sh".as_ptr(),
+            c"-c".as_ptr(),
+            script.as_ptr(),
+            std::ptr::null(),
+        ];
+        let cmd = c"sh".as_ptr();
+        let result = crate::portable_pty_spawn(handle, cmd, argv.as_ptr(), std::ptr::null());
+        assert!(matches!(result, PortablePtyResult::Ok));
+        let output = read_to_end(handle, Duration::from_secs(10)).unwrap();
+        assert!(
+            output.contains("early") && output.contains("late"),
+            "{output:?}"
+        );
+
+        let result = crate::portable_pty_spawn(handle, cmd, argv.as_ptr(), std::ptr::null());
+        assert!(matches!(result, PortablePtyResult::ErrUnsupported));
+        crate::portable_pty_close(handle);
+    }
+
+    #[test]
+    fn test_never_keeps_reads_open() {
+        let handle = crate::tests::open_and_spawn("sh", &["sh", "-c", "exit 0"]);
+        let mut status = -1;
+        crate::portable_pty_wait_blocking(handle, &mut status);
+        let pty = unsafe { &*handle };
+        // The end counts as readable...
+        assert!(pty.wait_readable(Some(Duration::ZERO)).unwrap());
+        let result = portable_pty_set_eof_policy(handle, PORTABLE_PTY_EOF_NEVER);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        // ...until there's no end.
+        assert!(!pty.wait_readable(Some(Duration::from_millis(200))).unwrap());
+
+        let result = portable_pty_set_eof_policy(handle, 7);
+        assert!(matches!(result, PortablePtyResult::ErrUnsupported));
+        crate::portable_pty_close(handle);
+
+        let mut loopback = std::ptr::null_mut();
+        crate::loopback::portable_pty_open_loopback(24, 80, &mut loopback);
+        assert_eq!(policy(loopback), PORTABLE_PTY_EOF_NEVER);
+        let result = portable_pty_set_eof_policy(loopback, PORTABLE_PTY_EOF_CHILD_EXIT);
+        assert!(matches!(result, PortablePtyResult::ErrUnsupported));
+        crate::portable_pty_close(loopback);
+    }
+}
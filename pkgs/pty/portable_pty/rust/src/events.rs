@@ -0,0 +1,194 @@
+//! Per-handle event delivery.
+//!
+//! Subsystems that notice something asynchronously (a pattern match, …)
+//! post a [`PortablePtyEvent`] on the handle. Embedders either poll the
+//! queue with `portable_pty_next_event` or register a callback with
+//! `portable_pty_set_event_callback`, in which case events are delivered on
+//! the thread that produced them and never queued.
+
+use crate::{PortablePty, PortablePtyBuffer, PortablePtyResult};
+use std::collections::VecDeque;
+use std::ffi::c_void;
+use std::sync::{Mutex, PoisonError};
+
+/// A registered pattern matched (`id` = pattern ID, `value` = offset of the
+/// match in the output stream, `data` = matched bytes).
+pub const PORTABLE_PTY_EVENT_MATCH: u32 = 1;
+/// A queued command finished (`id` = command ID, `value` = exit status or
+/// -1 if unknown, `data` = captured output).
+pub const PORTABLE_PTY_EVENT_COMMAND_DONE: u32 = 2;
+/// The child went over a resource threshold (`id` = one of the
+/// `PORTABLE_PTY_RESOURCE_*` constants, `value` = the sampled value).
+pub const PORTABLE_PTY_EVENT_RESOURCE: u32 = 3;
+/// The child was stopped or continued (`id` = the
+/// `PORTABLE_PTY_CHILD_*` state it's now in, `value` = the signal that
+/// stopped it, or 0).
+pub const PORTABLE_PTY_EVENT_JOB: u32 = 4;
+/// No output has been read for the handle's idle timeout (`value` = how
+/// long it has been quiet, in milliseconds).
+pub const PORTABLE_PTY_EVENT_IDLE: u32 = 5;
+/// Output was read after none had been for the handle's activity
+/// threshold (`value` = how long it had been quiet, in milliseconds).
+pub const PORTABLE_PTY_EVENT_ACTIVITY: u32 = 6;
+
+/// Queued events beyond this are dropped oldest-first.
+const MAX_QUEUED_EVENTS: usize = 1024;
+
+/// An event delivered by `portable_pty_next_event` or the event callback.
+///
+/// Events returned by `portable_pty_next_event` own `data`; release it with
+/// `portable_pty_event_free`. Events passed to a callback are only valid for
+/// the duration of the call.
+#[repr(C)]
+pub struct PortablePtyEvent {
+    /// One of the `PORTABLE_PTY_EVENT_*` constants.
+    pub kind: u32,
+    /// Kind-specific identifier.
+    pub id: u64,
+    /// Kind-specific value.
+    pub value: i64,
+    /// Kind-specific payload; empty when unused.
+    pub data: PortablePtyBuffer,
+}
+
+/// Event callback: `(userdata, event)`.
+pub type PortablePtyEventCallback = extern "C" fn(*mut c_void, *const PortablePtyEvent);
+
+struct Callback {
+    func: PortablePtyEventCallback,
+    userdata: *mut c_void,
+}
+
+// The userdata pointer is opaque to us; the embedder promises it may be
+// used from whichever thread produces events.
+unsafe impl Send for Callback {}
+
+#[derive(Default)]
+pub(crate) struct EventQueue {
+    queue: Mutex<VecDeque<PortablePtyEvent>>,
+    callback: Mutex<Option<Callback>>,
+}
+
+// `PortablePtyEvent` holds a raw pointer, but queued events exclusively own
+// their buffer.
+unsafe impl Send for PortablePtyEvent {}
+
+impl EventQueue {
+    /// Deliver an event to the callback, or queue it if none is set.
+    ///
+    /// Must not be called with any handle lock held: the callback may call
+    /// back into the library.
+    pub(crate) fn post(&self, kind: u32, id: u64, value: i64, data: Vec<u8>) {
+        let mut event = PortablePtyEvent {
+            kind,
+            id,
+            value,
+            data: PortablePtyBuffer::from_vec(data),
+        };
+
+        // Copy the callback out so it can re-register without deadlocking.
+        let callback = self
+            .callback
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_ref()
+            .map(|cb| (cb.func, cb.userdata));
+        if let Some((func, userdata)) = callback {
+            func(userdata, &event);
+            event.data.release();
+            return;
+        }
+
+        let mut queue = self.queue.lock().unwrap_or_else(PoisonError::into_inner);
+        if queue.len() == MAX_QUEUED_EVENTS {
+            if let Some(mut oldest) = queue.pop_front() {
+                oldest.data.release();
+            }
+        }
+        queue.push_back(event);
+    }
+
+    fn pop(&self) -> Option<PortablePtyEvent> {
+        self.queue
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .pop_front()
+    }
+}
+
+impl Drop for EventQueue {
+    fn drop(&mut self) {
+        let queue = self.queue.get_mut().unwrap_or_else(PoisonError::into_inner);
+        for event in queue.iter_mut() {
+            event.data.release();
+        }
+    }
+}
+
+/// Pop the oldest queued event into `*out_event`.
+///
+/// Returns `true` if an event was written. The caller owns the event's
+/// `data` and must release it with `portable_pty_event_free`.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_next_event(
+    handle: *const PortablePty,
+    out_event: *mut PortablePtyEvent,
+) -> bool {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return false,
+        };
+        if out_event.is_null() {
+            return false;
+        }
+
+        match pty.events.pop() {
+            Some(event) => {
+                unsafe {
+                    *out_event = event;
+                }
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+/// Release the payload of an event returned by `portable_pty_next_event`.
+/// Safe to call with NULL.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_event_free(event: *mut PortablePtyEvent) {
+    crate::ffi::guard(|| {
+        if let Some(event) = unsafe { event.as_mut() } {
+            event.data.release();
+        }
+    })
+}
+
+/// Register (or with a NULL `callback`, remove) the event callback.
+///
+/// While a callback is set, events are passed to it on the thread that
+/// produced them (e.g. the thread calling `portable_pty_read`) instead of
+/// being queued. Events already queued stay queued.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_set_event_callback(
+    handle: *const PortablePty,
+    callback: Option<extern "C" fn(*mut c_void, *const PortablePtyEvent)>,
+    userdata: *mut c_void,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+
+        let mut slot = pty
+            .events
+            .callback
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        *slot = callback.map(|func| Callback { func, userdata });
+        PortablePtyResult::Ok
+    })
+}
@@ -0,0 +1,291 @@
+//! Expect-style waiting on child output.
+//!
+//! `portable_pty_expect` reads from the master until a pattern shows up,
+//! then hands back the match and everything before it. Output past the end
+//! of the match (and everything read on timeout or EOF) is kept on the
+//! handle and returned by the next `portable_pty_read`, so scripted
+//! interaction never drops bytes — up to a point. While searching, only the
+//! last `WINDOW_SIZE` bytes (1 MiB) are kept and older output is dropped,
+//! so a chatty child and a pattern that never shows up can't take memory
+//! without end. A match has to fit in that window, and what comes before
+//! it is at most the rest of it; the match counts what was dropped.
+
+use crate::{PortablePty, PortablePtyBuffer, PortablePtyResult};
+use regex::bytes::Regex;
+use std::ffi::{c_char, CStr};
+use std::time::{Duration, Instant};
+
+/// Bytes pulled from the master per read while searching.
+const READ_CHUNK: usize = 4096;
+
+/// Output kept while searching; older bytes are dropped.
+const WINDOW_SIZE: usize = 1 << 20;
+
+/// Result of a successful `portable_pty_expect`.
+///
+/// Release with `portable_pty_expect_match_free`.
+#[repr(C)]
+pub struct PortablePtyExpectMatch {
+    /// Output consumed before the match: at most the last 1 MiB of it,
+    /// less the match.
+    pub before: PortablePtyBuffer,
+    /// The matched bytes.
+    pub matched: PortablePtyBuffer,
+    /// Bytes of output dropped before `before` to keep within 1 MiB; 0
+    /// if `before` is all of it.
+    pub dropped: usize,
+}
+
+/// Compile `pattern` as a byte regex, escaping it first for literal search.
+pub(crate) fn compile(pattern: &str, is_regex: bool) -> Result<Regex, regex::Error> {
+    if is_regex {
+        Regex::new(pattern)
+    } else {
+        Regex::new(&regex::escape(pattern))
+    }
+}
+
+/// Convert a C timeout (negative = forever) into an optional deadline.
+pub(crate) fn deadline_after(timeout_ms: i32) -> Option<Instant> {
+    u64::try_from(timeout_ms)
+        .ok()
+        .map(|ms| Instant::now() + Duration::from_millis(ms))
+}
+
+/// Time left until `deadline`, or `Some(ZERO)` once it has passed.
+pub(crate) fn remaining(deadline: Option<Instant>) -> Option<Duration> {
+    deadline.map(|d| d.saturating_duration_since(Instant::now()))
+}
+
+/// Consume output until `pattern` matches or `timeout_ms` elapses.
+///
+/// - `pattern`: null-terminated UTF-8 pattern.
+/// - `is_regex`: treat `pattern` as a regular expression (`regex` crate
+///   syntax, matched against raw bytes) instead of a literal string.
+/// - `timeout_ms`: maximum time to wait; negative waits indefinitely.
+/// - `out_match`: receives the match and the output preceding it.
+///
+/// Returns `Ok` on a match, `ErrTimeout` if the deadline passed, `ErrEof`
+/// if the child side closed first, and `ErrPattern` for an invalid pattern.
+/// Only the last 1 MiB of output is searched and kept, so a match must fit
+/// in it; older output is dropped, and counted in the match's `dropped`.
+/// On any failure the output read so far, or the last 1 MiB of it, stays
+/// buffered on the handle for the next `portable_pty_read` or
+/// `portable_pty_expect`.
+///
+/// Timeouts rely on polling the master and are only honored on Unix.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_expect(
+    handle: *mut PortablePty,
+    pattern: *const c_char,
+    is_regex: bool,
+    timeout_ms: i32,
+    out_match: *mut PortablePtyExpectMatch,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        if pattern.is_null() || out_match.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+
+        let pattern = match unsafe { CStr::from_ptr(pattern) }.to_str() {
+            Ok(s) => s,
+            Err(_) => return PortablePtyResult::ErrPattern,
+        };
+        let regex = match compile(pattern, is_regex) {
+            Ok(r) => r,
+            Err(_) => return PortablePtyResult::ErrPattern,
+        };
+
+        let deadline = deadline_after(timeout_ms);
+        let mut buffer = pty.take_pending();
+        let mut dropped = 0;
+        let mut chunk = [0u8; READ_CHUNK];
+
+        let result = loop {
+            if let Some(m) = regex.find(&buffer) {
+                let (start, end) = (m.start(), m.end());
+                pty.unread(buffer.split_off(end));
+                let matched = buffer.split_off(start);
+                unsafe {
+                    *out_match = PortablePtyExpectMatch {
+                        before: PortablePtyBuffer::from_vec(buffer),
+                        matched: PortablePtyBuffer::from_vec(matched),
+                        dropped,
+                    };
+                }
+                return PortablePtyResult::Ok;
+            }
+
+            match pty.wait_readable(remaining(deadline)) {
+                Ok(true) => {}
+                Ok(false) => break PortablePtyResult::ErrTimeout,
+                Err(_) => break PortablePtyResult::ErrRead,
+            }
+            match pty.read_master(&mut chunk) {
+                Ok(0) => break PortablePtyResult::ErrEof,
+                Ok(n) => {
+                    buffer.extend_from_slice(&chunk[..n]);
+                    if buffer.len() > WINDOW_SIZE {
+                        let over = buffer.len() - WINDOW_SIZE;
+                        buffer.drain(..over);
+                        dropped += over;
+                    }
+                }
+                // Linux's end of file once the slave is closed everywhere.
+                #[cfg(unix)]
+                Err(e) if e.raw_os_error() == Some(libc::EIO) => break PortablePtyResult::ErrEof,
+                Err(_) => break PortablePtyResult::ErrRead,
+            }
+        };
+
+        pty.unread(buffer);
+        result
+    })
+}
+
+/// Free the buffers held by a `PortablePtyExpectMatch`. Safe to call with
+/// NULL or on an already-freed match.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_expect_match_free(m: *mut PortablePtyExpectMatch) {
+    crate::ffi::guard(|| {
+        if let Some(m) = unsafe { m.as_mut() } {
+            m.before.release();
+            m.matched.release();
+        }
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::portable_pty_close;
+    use crate::tests::{open_and_spawn, read_string};
+    use std::ffi::CString;
+
+    fn expect(
+        handle: *mut PortablePty,
+        pattern: &str,
+        is_regex: bool,
+    ) -> (PortablePtyResult, String, String) {
+        let pattern = CString::new(pattern).unwrap();
+        let mut m = PortablePtyExpectMatch {
+            before: PortablePtyBuffer::EMPTY,
+            matched: PortablePtyBuffer::EMPTY,
+            dropped: 0,
+        };
+        let result = portable_pty_expect(handle, pattern.as_ptr(), is_regex, 2000, &mut m);
+        let text = |b: &PortablePtyBuffer| {
+            if b.data.is_null() {
+                return String::new();
+            }
+            let bytes = unsafe { std::slice::from_raw_parts(b.data, b.len) };
+            String::from_utf8_lossy(bytes).into_owned()
+        };
+        let out = (result, text(&m.before), text(&m.matched));
+        portable_pty_expect_match_free(&mut m);
+        out
+    }
+
+    #[test]
+    fn test_expect_literal_then_regex() {
+        let handle = open_and_spawn("/bin/echo", &["echo", "alpha beta.gamma 42 tail"]);
+
+        let (result, before, matched) = expect(handle, "beta.", false);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        assert_eq!(before, "alpha ");
+        assert_eq!(matched, "beta.");
+
+        let (result, before, matched) = expect(handle, r"\d+", true);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        assert_eq!(before, "gamma ");
+        assert_eq!(matched, "42");
+
+        // Output past the match is still delivered by the plain read path.
+        let rest = read_string(handle);
+        assert!(rest.starts_with(" tail"), "unexpected remainder: {rest:?}");
+
+        portable_pty_close(handle);
+    }
+
+    #[test]
+    fn test_expect_timeout_keeps_output() {
+        let handle = open_and_spawn("/bin/sh", &["sh", "-c", "echo ready; sleep 5"]);
+        let pattern = CString::new("never").unwrap();
+        let mut m = PortablePtyExpectMatch {
+            before: PortablePtyBuffer::EMPTY,
+            matched: PortablePtyBuffer::EMPTY,
+            dropped: 0,
+        };
+        let result = portable_pty_expect(handle, pattern.as_ptr(), false, 300, &mut m);
+        assert!(matches!(result, PortablePtyResult::ErrTimeout));
+
+        let (result, _, matched) = expect(handle, "ready", false);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        assert_eq!(matched, "ready");
+
+        let bad = CString::new("(").unwrap();
+        let result = portable_pty_expect(handle, bad.as_ptr(), true, 0, &mut m);
+        assert!(matches!(result, PortablePtyResult::ErrPattern));
+
+        portable_pty_close(handle);
+    }
+
+    #[test]
+    fn test_expect_eof() {
+        let handle = open_and_spawn("/bin/echo", &["echo", "done"]);
+        let (result, _, _) = expect(handle, "never", false);
+        assert!(matches!(result, PortablePtyResult::ErrEof));
+        assert!(read_string(handle).contains("done"));
+        portable_pty_close(handle);
+
+        // With the slave gone Linux reports the end as EIO, which reaches
+        // here from readers that don't make it a 0 themselves.
+        let handle = open_and_spawn("/bin/echo", &["echo", "done"]);
+        crate::eof::portable_pty_set_eof_policy(handle, crate::eof::PORTABLE_PTY_EOF_ALL_EXIT);
+        crate::eof::portable_pty_set_eof_policy(handle, crate::eof::PORTABLE_PTY_EOF_NEVER);
+        let (result, _, _) = expect(handle, "never", false);
+        assert!(matches!(result, PortablePtyResult::ErrEof));
+        portable_pty_close(handle);
+    }
+
+    #[test]
+    fn test_expect_keeps_only_the_window() {
+        let script = "head -c 3000000 /dev/zero | tr '\\0' x";
+        let handle = open_and_spawn("/bin/sh", &["sh", "-c", script]);
+        let pattern = CString::new("never").unwrap();
+        let mut m = PortablePtyExpectMatch {
+            before: PortablePtyBuffer::EMPTY,
+            matched: PortablePtyBuffer::EMPTY,
+            dropped: 0,
+        };
+        let result = portable_pty_expect(handle, pattern.as_ptr(), false, -1, &mut m);
+        assert!(matches!(result, PortablePtyResult::ErrEof));
+        let kept = unsafe { &*handle }.take_pending();
+        assert!(kept.len() <= WINDOW_SIZE, "{}", kept.len());
+        assert!(kept.len() > WINDOW_SIZE / 2);
+        assert!(kept.iter().all(|&b| b == b'x'));
+        portable_pty_close(handle);
+    }
+
+    #[test]
+    fn test_expect_counts_dropped_output() {
+        let script = "head -c 3000000 /dev/zero | tr '\\0' x; printf END";
+        let handle = open_and_spawn("/bin/sh", &["sh", "-c", script]);
+        let pattern = CString::new("END").unwrap();
+        let mut m = PortablePtyExpectMatch {
+            before: PortablePtyBuffer::EMPTY,
+            matched: PortablePtyBuffer::EMPTY,
+            dropped: 0,
+        };
+        let result = portable_pty_expect(handle, pattern.as_ptr(), false, -1, &mut m);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        assert!(m.dropped > 0);
+        assert_eq!(m.dropped + m.before.len, 3_000_000);
+        portable_pty_expect_match_free(&mut m);
+        portable_pty_close(handle);
+    }
+}
@@ -0,0 +1,73 @@
+//! Keeping panics on our side of the C API.
+//!
+//! Unwinding out of an `extern "C"` function aborts the process, which for
+//! an embedder means the whole app. Every entry point runs its body under
+//! [`guard`], which turns a panic into the function's failure value:
+//! `ErrInternal` where it returns a `PortablePtyResult`, -1 where it
+//! returns a count or descriptor, false, 0 or nothing otherwise. Locks a
+//! panic leaves poisoned are taken over as they are everywhere else, so the
+//! handle stays usable, if possibly in a state the panic interrupted.
+
+use crate::PortablePtyResult;
+use std::panic::{self, AssertUnwindSafe};
+
+/// What an entry point returns when it panicked.
+pub(crate) trait Failure {
+    fn failure() -> Self;
+}
+
+impl Failure for PortablePtyResult {
+    fn failure() -> Self {
+        PortablePtyResult::ErrInternal
+    }
+}
+
+impl Failure for i32 {
+    fn failure() -> Self {
+        -1
+    }
+}
+
+impl Failure for i64 {
+    fn failure() -> Self {
+        -1
+    }
+}
+
+impl Failure for u32 {
+    fn failure() -> Self {
+        0
+    }
+}
+
+impl Failure for bool {
+    fn failure() -> Self {
+        false
+    }
+}
+
+impl Failure for () {
+    fn failure() -> Self {}
+}
+
+/// Run the body of an entry point, returning its failure value if it
+/// panics.
+pub(crate) fn guard<T: Failure>(body: impl FnOnce() -> T) -> T {
+    // Nothing observes the closure's captures after a panic but the
+    // handles themselves, which recover from poisoning.
+    panic::catch_unwind(AssertUnwindSafe(body)).unwrap_or_else(|_| T::failure())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_panics_become_failures() {
+        let result = guard(|| -> PortablePtyResult { panic!("broken invariant") });
+        assert!(matches!(result, PortablePtyResult::ErrInternal));
+        assert_eq!(guard(|| -> i64 { panic!() }), -1);
+        assert!(!guard(|| -> bool { panic!() }));
+        assert_eq!(guard(|| 7i64), 7);
+    }
+}
@@ -0,0 +1,229 @@
+//! Read-side flow control.
+//!
+//! A UI that can't draw output as fast as the child writes it would
+//! otherwise go on reading and queue what it hasn't drawn, without bound.
+//! Instead it can stop the handle reading: output then waits in the
+//! terminal's own buffer, and once that's full the child blocks writing,
+//! as it would on a slow terminal.
+//!
+//! `portable_pty_pause_output` and `portable_pty_resume_output` stop and
+//! restart reading outright. With a watermark set by
+//! `portable_pty_set_output_watermark`, the handle also counts the output
+//! `portable_pty_read` has handed out that the consumer hasn't yet
+//! acknowledged with `portable_pty_output_consumed`. Reading stops when
+//! that reaches the watermark and restarts once acknowledgements bring it
+//! down to half, so a consumer that's caught up doesn't stall on every
+//! chunk.
+//!
+//! While reading is stopped `portable_pty_read` blocks, output already
+//! taken off the terminal included, until it restarts; it isn't released
+//! by the child exiting, so resume before tearing the reader down. Reads
+//! the library makes itself for `expect`, queries and the like aren't held
+//! back.
+
+use crate::{PortablePty, PortablePtyResult};
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+#[derive(Default)]
+struct State {
+    /// Stopped by `portable_pty_pause_output`.
+    paused: bool,
+    /// Unacknowledged output at which reading stops; 0 for no watermark.
+    high: usize,
+    /// Output handed out and not yet acknowledged.
+    outstanding: usize,
+    /// Stopped by the watermark, until `outstanding` is down to half.
+    held: bool,
+}
+
+impl State {
+    fn stopped(&self) -> bool {
+        self.paused || self.held
+    }
+}
+
+/// A handle's flow control.
+#[derive(Default)]
+pub(crate) struct Flow {
+    state: Mutex<State>,
+    restarted: Condvar,
+}
+
+impl Flow {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Block while reading is stopped.
+    pub(crate) fn wait(&self) {
+        let mut state = self.lock();
+        while state.stopped() {
+            state = self
+                .restarted
+                .wait(state)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    /// Block while reading is stopped, for at most `timeout` (`None` for
+    /// as long as it takes); false if it's still stopped then.
+    pub(crate) fn wait_for(&self, timeout: Option<Duration>) -> bool {
+        let Some(timeout) = timeout else {
+            self.wait();
+            return true;
+        };
+        let (state, _) = self
+            .restarted
+            .wait_timeout_while(self.lock(), timeout, |state| state.stopped())
+            .unwrap_or_else(PoisonError::into_inner);
+        !state.stopped()
+    }
+
+    /// Count `n` bytes handed to the consumer.
+    pub(crate) fn delivered(&self, n: usize) {
+        let mut state = self.lock();
+        if state.high == 0 {
+            return;
+        }
+        state.outstanding += n;
+        if state.outstanding >= state.high {
+            state.held = true;
+        }
+    }
+
+    /// Change the state with `f`, waking readers if that restarted reading.
+    fn update(&self, f: impl FnOnce(&mut State)) {
+        let mut state = self.lock();
+        f(&mut state);
+        if !state.stopped() {
+            self.restarted.notify_all();
+        }
+    }
+}
+
+/// Run `f` on the handle's flow control.
+fn with_flow(handle: *const PortablePty, f: impl FnOnce(&mut State)) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        pty.flow.update(f);
+        PortablePtyResult::Ok
+    })
+}
+
+/// Stop reading from the terminal until `portable_pty_resume_output`.
+/// Output waits in the terminal's own buffer meanwhile, and once that's
+/// full the child blocks writing. `portable_pty_read` blocks until reading
+/// restarts, even if the child exits, so resume before tearing the reader
+/// down; reads the library makes itself aren't held back.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_pause_output(handle: *const PortablePty) -> PortablePtyResult {
+    with_flow(handle, |state| state.paused = true)
+}
+
+/// Undo `portable_pty_pause_output`. Reading restarts unless the watermark
+/// still holds it back.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_resume_output(handle: *const PortablePty) -> PortablePtyResult {
+    with_flow(handle, |state| state.paused = false)
+}
+
+/// Stop reading while `bytes` of output or more are unacknowledged.
+/// Output `portable_pty_read` hands out counts until it's acknowledged
+/// with `portable_pty_output_consumed`; reading stops, as with
+/// `portable_pty_pause_output`, when the count reaches the watermark, and
+/// restarts once it's down to half.
+///
+/// - `bytes`: the watermark, or 0 to stop counting. The count starts
+///   afresh at 0 whenever the watermark is set.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_set_output_watermark(
+    handle: *const PortablePty,
+    bytes: usize,
+) -> PortablePtyResult {
+    with_flow(handle, |state| {
+        state.high = bytes;
+        state.outstanding = 0;
+        state.held = false;
+    })
+}
+
+/// Acknowledge `bytes` of output read earlier as dealt with, against the
+/// watermark. Acknowledging more than is outstanding is harmless.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_output_consumed(
+    handle: *const PortablePty,
+    bytes: usize,
+) -> PortablePtyResult {
+    with_flow(handle, |state| {
+        state.outstanding = state.outstanding.saturating_sub(bytes);
+        if state.held && state.outstanding <= state.high / 2 {
+            state.held = false;
+        }
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::portable_pty_read;
+    use crate::tests::open_and_spawn;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    /// Start reading 64-byte chunks on another thread, sending each count.
+    fn reader(handle: *mut PortablePty) -> mpsc::Receiver<i64> {
+        let (tx, rx) = mpsc::channel();
+        let shared = handle as usize;
+        std::thread::spawn(move || loop {
+            let mut buf = [0u8; 64];
+            let n = portable_pty_read(shared as *mut PortablePty, buf.as_mut_ptr(), buf.len());
+            if tx.send(n).is_err() || n <= 0 {
+                break;
+            }
+        });
+        rx
+    }
+
+    const SHORT: Duration = Duration::from_millis(300);
+
+    #[test]
+    fn test_pause_and_resume() {
+        let handle = open_and_spawn("sh", &["sh", "-c", "sleep 0.2; echo hello; sleep 5"]);
+        portable_pty_pause_output(handle);
+        let rx = reader(handle);
+        assert!(rx.recv_timeout(Duration::from_secs(1)).is_err());
+
+        portable_pty_resume_output(handle);
+        assert!(rx.recv_timeout(Duration::from_secs(5)).unwrap() > 0);
+        crate::portable_pty_kill(handle, libc::SIGKILL);
+        while rx.recv_timeout(Duration::from_secs(5)).unwrap() > 0 {}
+        crate::portable_pty_close(handle);
+    }
+
+    #[test]
+    fn test_watermark_holds_until_half_is_consumed() {
+        let handle = open_and_spawn("sh", &["sh", "-c", "yes"]);
+        portable_pty_set_output_watermark(handle, 256);
+        let rx = reader(handle);
+        let mut read = 0;
+        while read < 256 {
+            read += rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        assert!(rx.recv_timeout(SHORT).is_err(), "read past the watermark");
+
+        // Not yet down to half.
+        portable_pty_output_consumed(handle, (read - 129) as usize);
+        assert!(rx.recv_timeout(SHORT).is_err(), "restarted above half");
+        portable_pty_output_consumed(handle, 1);
+        assert!(rx.recv_timeout(Duration::from_secs(5)).unwrap() > 0);
+
+        portable_pty_set_output_watermark(handle, 0);
+        crate::portable_pty_kill(handle, libc::SIGKILL);
+        while rx.recv_timeout(Duration::from_secs(5)).unwrap() > 0 {}
+        crate::portable_pty_close(handle);
+    }
+}
@@ -0,0 +1,143 @@
+//! Length-prefixed frames, as spoken on the control socket and between a
+//! handle and its session daemon.
+//!
+//! A frame is a 4-byte big-endian length of what follows, a 1-byte type,
+//! then the payload. What the types mean is up to each protocol.
+
+use crate::pool::Chunk;
+use std::collections::VecDeque;
+use std::io::{self, Read};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+
+/// Largest frame accepted from the other end.
+const MAX_FRAME: usize = 1 << 20;
+
+/// Frames queued for a connection before it counts as too slow.
+const MAX_QUEUED: usize = 256;
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+pub(crate) fn frame(kind: u8, payload: &[u8]) -> Arc<Chunk> {
+    let len = u32::try_from(payload.len() + 1).unwrap_or(u32::MAX);
+    Chunk::build(payload.len() + 5, |frame| {
+        frame.extend_from_slice(&len.to_be_bytes());
+        frame.push(kind);
+        frame.extend_from_slice(payload);
+    })
+}
+
+/// Read one frame: its type and payload.
+pub(crate) fn read_frame(stream: &mut impl Read) -> io::Result<(u8, Vec<u8>)> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len == 0 || len > MAX_FRAME {
+        return Err(io::ErrorKind::InvalidData.into());
+    }
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body)?;
+    let kind = body.remove(0);
+    Ok((kind, body))
+}
+
+#[derive(Default)]
+struct OutboxState {
+    frames: VecDeque<Arc<Chunk>>,
+    /// No more frames are accepted.
+    closed: bool,
+    /// Frames already queued are still sent once closed.
+    drain: bool,
+}
+
+/// Frames waiting to go out on a connection.
+#[derive(Default)]
+pub(crate) struct Outbox {
+    state: Mutex<OutboxState>,
+    ready: Condvar,
+}
+
+impl Outbox {
+    /// Queue a frame, or close the outbox if too many are waiting. False
+    /// once closed.
+    pub(crate) fn push(&self, frame: Arc<Chunk>) -> bool {
+        let mut state = lock(&self.state);
+        if state.frames.len() >= MAX_QUEUED {
+            state.closed = true;
+            state.drain = false;
+        }
+        if !state.closed {
+            state.frames.push_back(frame);
+        }
+        self.ready.notify_all();
+        !state.closed
+    }
+
+    /// Close the outbox, dropping whatever is still queued.
+    pub(crate) fn close(&self) {
+        let mut state = lock(&self.state);
+        state.closed = true;
+        state.drain = false;
+        self.ready.notify_all();
+    }
+
+    /// Close the outbox once what is already queued has been sent.
+    pub(crate) fn finish(&self) {
+        let mut state = lock(&self.state);
+        if !state.closed {
+            state.closed = true;
+            state.drain = true;
+        }
+        self.ready.notify_all();
+    }
+
+    /// The next frame to send; None once closed.
+    pub(crate) fn pop(&self) -> Option<Arc<Chunk>> {
+        let mut state = lock(&self.state);
+        loop {
+            if state.closed && !state.drain {
+                return None;
+            }
+            if let Some(frame) = state.frames.pop_front() {
+                return Some(frame);
+            }
+            if state.closed {
+                return None;
+            }
+            state = self
+                .ready
+                .wait(state)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frames_round_trip() {
+        let encoded = frame(4, b"ls\r");
+        assert_eq!(&encoded[..], b"\0\0\0\x04\x04ls\r");
+        let (kind, payload) = read_frame(&mut &encoded[..]).unwrap();
+        assert_eq!((kind, payload.as_slice()), (4, &b"ls\r"[..]));
+        assert!(read_frame(&mut &b"\0\0\0\0"[..]).is_err());
+    }
+
+    #[test]
+    fn test_finished_outbox_drains() {
+        let outbox = Outbox::default();
+        outbox.push(frame(1, b"a"));
+        outbox.finish();
+        assert!(!outbox.push(frame(1, b"b")));
+        assert_eq!(&outbox.pop().unwrap()[..], &frame(1, b"a")[..]);
+        assert!(outbox.pop().is_none());
+
+        let outbox = Outbox::default();
+        outbox.push(frame(1, b"a"));
+        outbox.close();
+        assert!(outbox.pop().is_none());
+    }
+}
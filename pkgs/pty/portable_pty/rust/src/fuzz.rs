@@ -0,0 +1,82 @@
+//! Entry points for the fuzz targets in `fuzz/`.
+//!
+//! Built only under `cfg(fuzzing)`, which `cargo fuzz` sets. Each takes
+//! arbitrary bytes and drives one of the parsers that sees input from
+//! outside: the argv and envp arrays callers hand over, output from the
+//! child (which may be a remote system) and recording files.
+
+use crate::modes::ModeTracker;
+use std::ffi::{c_char, CString};
+
+/// Null-terminated array of pointers to `strings`.
+fn c_array(strings: &[CString]) -> Vec<*const c_char> {
+    strings
+        .iter()
+        .map(|s| s.as_ptr())
+        .chain([std::ptr::null()])
+        .collect()
+}
+
+/// Marshal a command as `portable_pty_spawn` does, without spawning it.
+///
+/// `data` is split at NULs: the command, then arguments up to an empty
+/// entry, then environment entries.
+pub fn command(data: &[u8]) {
+    let mut entries = data
+        .split(|&b| b == 0)
+        .map(|entry| CString::new(entry).unwrap());
+    let Some(cmd) = entries.next() else {
+        return;
+    };
+    let argv: Vec<CString> = entries
+        .by_ref()
+        .take_while(|entry| !entry.is_empty())
+        .collect();
+    let envp: Vec<CString> = entries.collect();
+    let (argv, envp) = (c_array(&argv), c_array(&envp));
+
+    unsafe {
+        let _ = crate::c_string_array(argv.as_ptr());
+        let _ = crate::c_string_array(envp.as_ptr());
+    }
+    let _ = crate::command_builder(cmd.as_ptr(), argv.as_ptr(), envp.as_ptr());
+}
+
+/// Scan child output as a handle does, and play it on the screen model.
+///
+/// The first two bytes give the screen's rows and columns; the rest is
+/// fed in two chunks with a resize between them.
+pub fn output(data: &[u8]) {
+    let [rows, cols, output @ ..] = data else {
+        return;
+    };
+    let (first, second) = output.split_at(output.len() / 2);
+
+    let mut tracker = ModeTracker::default();
+    tracker.feed(first);
+    tracker.feed(second);
+
+    #[cfg(feature = "screen")]
+    {
+        let mut screen = crate::screen::Screen::new((*rows).into(), (*cols).into(), Some(100));
+        screen.feed(first);
+        screen.resize((*cols).into(), (*rows).into());
+        screen.feed(second);
+        let _ = screen.text();
+        let _ = screen.command_texts();
+    }
+    #[cfg(not(feature = "screen"))]
+    let _ = (rows, cols);
+}
+
+/// Load a recording file and render its transcript.
+pub fn recording(data: &[u8]) {
+    use crate::replay::transcript;
+
+    for mode in [
+        transcript::PORTABLE_PTY_TRANSCRIPT_SCREEN,
+        transcript::PORTABLE_PTY_TRANSCRIPT_COMMANDS,
+    ] {
+        let _ = transcript::from_bytes(data, mode);
+    }
+}
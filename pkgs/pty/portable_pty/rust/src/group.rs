@@ -0,0 +1,220 @@
+//! Killing the child along with what it started.
+//!
+//! `portable_pty_kill` signals the child alone, so what it started lives
+//! on: the `sleep` of `bash -c 'sleep 1000'`, a shell's background jobs.
+//! `portable_pty_kill_group` reaches those too. On Unix the child leads a
+//! process group of its own, which what it starts stays in unless moved
+//! out, and the signal goes to that group, and to the terminal's
+//! foreground group where a job-control shell has given a job its own. A
+//! background job in a group of its own isn't reached, and neither is the
+//! child's group once the child has been reaped, as its ID is free to be
+//! reused for an unrelated one.
+//!
+//! Windows has no process groups to signal. Instead each child is put in
+//! a job object as it's spawned, which what it starts then joins, and the
+//! whole job is terminated. A process the child starts in the moment
+//! before it's put in the job is left out.
+
+use crate::{PortablePty, PortablePtyResult};
+use std::ffi::c_int;
+
+/// The job object the child was put in, on Windows.
+#[derive(Default)]
+pub(crate) struct Group {
+    #[cfg(windows)]
+    job: Option<std::os::windows::io::OwnedHandle>,
+}
+
+impl Group {
+    /// Take in the child just spawned as `pid`.
+    pub(crate) fn adopt(&mut self, pid: i32) {
+        #[cfg(windows)]
+        {
+            self.job = (pid > 0).then(|| job_for(pid as u32).ok()).flatten();
+        }
+        #[cfg(not(windows))]
+        let _ = pid;
+    }
+}
+
+/// A new job object holding the process `pid`.
+#[cfg(windows)]
+fn job_for(pid: u32) -> std::io::Result<std::os::windows::io::OwnedHandle> {
+    use std::os::windows::io::{AsRawHandle, FromRawHandle, OwnedHandle};
+    use winapi::um::jobapi2::{AssignProcessToJobObject, CreateJobObjectW};
+    use winapi::um::processthreadsapi::OpenProcess;
+    use winapi::um::winnt::{PROCESS_SET_QUOTA, PROCESS_TERMINATE};
+
+    let job = unsafe { CreateJobObjectW(std::ptr::null_mut(), std::ptr::null()) };
+    if job.is_null() {
+        return Err(std::io::Error::last_os_error());
+    }
+    let job = unsafe { OwnedHandle::from_raw_handle(job as _) };
+    let process = unsafe { OpenProcess(PROCESS_SET_QUOTA | PROCESS_TERMINATE, 0, pid) };
+    if process.is_null() {
+        return Err(std::io::Error::last_os_error());
+    }
+    let process = unsafe { OwnedHandle::from_raw_handle(process as _) };
+    let assigned =
+        unsafe { AssignProcessToJobObject(job.as_raw_handle() as _, process.as_raw_handle() as _) };
+    match assigned {
+        0 => Err(std::io::Error::last_os_error()),
+        _ => Ok(job),
+    }
+}
+
+/// Send `signal` to the process group `pgid`; true if it went or there's
+/// no such group any more.
+#[cfg(unix)]
+fn signal_group(pgid: libc::pid_t, signal: c_int) -> bool {
+    unsafe { libc::killpg(pgid, signal) == 0 || crate::get_errno() == libc::ESRCH }
+}
+
+/// Kill the child and what it started. On Unix the signal goes to the
+/// child's process group, which what it starts stays in unless moved out,
+/// and to the terminal's foreground group; a background job in a group of
+/// its own isn't reached. On Windows the child's job object, which what
+/// it starts joins, is terminated.
+///
+/// - `signal`: as for `portable_pty_kill`. Ignored on Windows, where they
+///   are all terminated with exit code 1.
+///
+/// Unlike `portable_pty_kill` this goes ahead once the child has exited,
+/// for what it left running. On Unix, once the child has been reaped its
+/// process group ID may belong to someone else, so only the terminal's
+/// foreground group is signalled then, if it has one. Returns `Ok` if
+/// there's nothing left to kill, and `ErrUnsupported` on Windows if the
+/// child couldn't be put in a job.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_kill_group(
+    handle: *mut PortablePty,
+    signal: c_int,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_mut() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        let Some(child) = pty.child.as_mut() else {
+            return PortablePtyResult::ErrKill;
+        };
+        if pty.child_pid <= 0 {
+            // No process behind the handle (replay, mock): stop the
+            // stand-in child instead.
+            return match child.kill() {
+                Ok(()) => PortablePtyResult::Ok,
+                Err(_) => PortablePtyResult::ErrKill,
+            };
+        }
+
+        #[cfg(unix)]
+        {
+            let pgid = pty.child_pid;
+            if let Some(raw_status) = crate::lookup_cached_status(pgid) {
+                pty.exited_with(raw_status);
+            }
+            // Once the child is reaped its ID is free for reuse, maybe as
+            // someone else's group: leave it be.
+            let reaped = pty.cached_exit_code.is_some();
+            if !reaped && !signal_group(pgid, signal) {
+                return PortablePtyResult::ErrKill;
+            }
+            let foreground = pty.master.process_group_leader();
+            if let Some(foreground) = foreground.filter(|&fg| fg > 0 && (reaped || fg != pgid)) {
+                if !signal_group(foreground, signal) {
+                    return PortablePtyResult::ErrKill;
+                }
+            }
+            PortablePtyResult::Ok
+        }
+
+        #[cfg(windows)]
+        {
+            use std::os::windows::io::AsRawHandle;
+            use winapi::um::jobapi2::TerminateJobObject;
+
+            let _ = signal;
+            let Some(job) = &pty.group.job else {
+                return PortablePtyResult::ErrUnsupported;
+            };
+            match unsafe { TerminateJobObject(job.as_raw_handle() as _, 1) } {
+                0 => PortablePtyResult::ErrKill,
+                _ => PortablePtyResult::Ok,
+            }
+        }
+
+        #[cfg(not(any(unix, windows)))]
+        {
+            let _ = signal;
+            PortablePtyResult::ErrUnsupported
+        }
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::tests::{open_and_spawn, read_string};
+    use std::time::{Duration, Instant};
+
+    /// Whether `pid` is gone, or only a zombie left for someone to reap.
+    fn gone(pid: libc::pid_t) -> bool {
+        let stat = std::fs::read_to_string(format!("/proc/{pid}/stat"));
+        let alive = unsafe { libc::kill(pid, 0) } == 0;
+        !alive || stat.is_ok_and(|stat| stat.contains(") Z "))
+    }
+
+    #[test]
+    fn test_kill_group_reaches_grandchildren() {
+        // Deaf to the hangup the child's exit sends its terminal's
+        // foreground group, as a nohup'd job would be.
+        let script = "trap '' HUP; sleep 1000 & echo \"bg $!\"; wait";
+        let handle = open_and_spawn("sh", &["sh", "-c", script]);
+        let mut output = String::new();
+        while !output.contains('\n') {
+            output += &read_string(handle);
+        }
+        let grandchild: libc::pid_t = output
+            .split_whitespace()
+            .skip_while(|word| *word != "bg")
+            .nth(1)
+            .and_then(|pid| pid.parse().ok())
+            .unwrap_or_else(|| panic!("{output:?}"));
+
+        let result = portable_pty_kill_group(handle, libc::SIGTERM);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !gone(grandchild) {
+            assert!(Instant::now() < deadline, "sleep survived");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        // Nothing left: still fine.
+        let result = portable_pty_kill_group(handle, libc::SIGTERM);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        crate::portable_pty_close(handle);
+    }
+
+    #[test]
+    fn test_kill_group_leaves_reaped_childs_group_alone() {
+        // The sleep keeps the group, but once the child is reaped the
+        // handle can't know that: its ID could be anyone's by then.
+        let script = "trap '' HUP; sleep 1000 & echo \"bg $!\"";
+        let handle = open_and_spawn("sh", &["sh", "-c", script]);
+        let output = read_string(handle);
+        let grandchild: libc::pid_t = output
+            .split_whitespace()
+            .skip_while(|word| *word != "bg")
+            .nth(1)
+            .and_then(|pid| pid.parse().ok())
+            .unwrap_or_else(|| panic!("{output:?}"));
+        let mut status = 0;
+        crate::portable_pty_wait_blocking(handle, &mut status);
+
+        let result = portable_pty_kill_group(handle, libc::SIGTERM);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(!gone(grandchild));
+        unsafe { libc::kill(grandchild, libc::SIGKILL) };
+        crate::portable_pty_close(handle);
+    }
+}
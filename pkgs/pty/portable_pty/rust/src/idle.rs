@@ -0,0 +1,286 @@
+//! Telling when output goes quiet.
+//!
+//! `portable_pty_set_idle_timeout` posts a `PORTABLE_PTY_EVENT_IDLE` event
+//! once no output has come for a while: a guess that a command has
+//! finished where the shell doesn't mark its prompts (see `commands`), or
+//! a cue for a UI to stop a spinner. It fires once per quiet spell, and
+//! the next output starts another. Output counts when it's read off the
+//! handle, so the embedder has to be reading for this to mean anything.
+//!
+//! The other way round, `portable_pty_set_activity_threshold` posts a
+//! `PORTABLE_PTY_EVENT_ACTIVITY` event when output comes after a quiet
+//! spell at least that long, so a background tab can light up its badge
+//! without the embedder timing every read. It fires once per quiet spell,
+//! with the first output that ends it.
+
+use crate::events::{EventQueue, PORTABLE_PTY_EVENT_ACTIVITY, PORTABLE_PTY_EVENT_IDLE};
+use crate::lifecycle::{HandleRef, ThreadGroup};
+use crate::{PortablePty, PortablePtyResult};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+struct State {
+    /// When output was last read, while watched.
+    last_output: Option<Instant>,
+    /// How long a quiet spell lasts before it's reported, while watched.
+    timeout: Option<Duration>,
+    /// Whether the current quiet spell has been reported.
+    reported: bool,
+    /// How long a quiet spell must last for the output ending it to be
+    /// reported.
+    activity_after: Option<Duration>,
+    /// Asks the watcher thread to end.
+    stop: bool,
+}
+
+/// A handle's output timing.
+pub(crate) struct Idle {
+    state: Mutex<State>,
+    changed: Condvar,
+    /// The watcher thread, while one is running.
+    watcher: Mutex<Option<Arc<ThreadGroup>>>,
+}
+
+impl Default for Idle {
+    fn default() -> Self {
+        Idle {
+            state: Mutex::new(State {
+                last_output: None,
+                timeout: None,
+                reported: false,
+                activity_after: None,
+                stop: false,
+            }),
+            changed: Condvar::new(),
+            watcher: Mutex::new(None),
+        }
+    }
+}
+
+impl Idle {
+    /// Note output read off the handle, posting an activity event to
+    /// `events` if it ends a long enough quiet spell.
+    ///
+    /// Must not be called with any handle lock held, as for
+    /// [`EventQueue::post`].
+    pub(crate) fn output(&self, events: &EventQueue) {
+        let mut state = lock(&self.state);
+        // Some WebAssembly targets have no clock to read.
+        if state.timeout.is_none() && state.activity_after.is_none() {
+            return;
+        }
+        let now = Instant::now();
+        let quiet = state.last_output.map(|then| now.duration_since(then));
+        state.last_output = Some(now);
+        if std::mem::replace(&mut state.reported, false) {
+            self.changed.notify_all();
+        }
+        let resumed =
+            quiet.filter(|&quiet| state.activity_after.is_some_and(|after| quiet >= after));
+        drop(state);
+        if let Some(quiet) = resumed {
+            let quiet_ms = i64::try_from(quiet.as_millis()).unwrap_or(i64::MAX);
+            events.post(PORTABLE_PTY_EVENT_ACTIVITY, 0, quiet_ms, Vec::new());
+        }
+    }
+}
+
+/// Report quiet spells on the handle until stopped.
+fn watch(handle: HandleRef) {
+    let pty = handle.get();
+    let idle = &pty.idle;
+    let mut state = lock(&idle.state);
+    loop {
+        let timeout = match state.timeout {
+            Some(timeout) if !state.stop => timeout,
+            _ => return,
+        };
+        if state.reported {
+            state = idle
+                .changed
+                .wait(state)
+                .unwrap_or_else(PoisonError::into_inner);
+            continue;
+        }
+        let quiet = state.last_output.map_or(Duration::ZERO, |t| t.elapsed());
+        if quiet < timeout {
+            state = idle
+                .changed
+                .wait_timeout(state, timeout - quiet)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+            continue;
+        }
+        state.reported = true;
+        // Out of the lock: the event callback may read.
+        drop(state);
+        let quiet_ms = i64::try_from(quiet.as_millis()).unwrap_or(i64::MAX);
+        pty.events
+            .post(PORTABLE_PTY_EVENT_IDLE, 0, quiet_ms, Vec::new());
+        state = lock(&idle.state);
+    }
+}
+
+/// Stop watching the handle for quiet spells, if it is.
+pub(crate) fn stop(pty: &PortablePty) {
+    let watcher = lock(&pty.idle.watcher).take();
+    if let Some(threads) = watcher {
+        lock(&pty.idle.state).stop = true;
+        pty.idle.changed.notify_all();
+        threads.wait();
+        lock(&pty.idle.state).stop = false;
+    }
+}
+
+/// Post a `PORTABLE_PTY_EVENT_IDLE` event whenever no output has been
+/// read for `idle_ms`, once per quiet spell; the next output starts
+/// another. Output counts when it's read off the handle, so the embedder
+/// has to be reading.
+///
+/// - `idle_ms`: how long output must stop for; 0 to stop watching.
+///
+/// The first quiet spell is timed from this call. Replaces any timeout
+/// already set on the handle.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_set_idle_timeout(
+    handle: *const PortablePty,
+    idle_ms: u32,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        stop(pty);
+        {
+            let mut state = lock(&pty.idle.state);
+            state.timeout = (idle_ms > 0).then(|| Duration::from_millis(idle_ms.into()));
+            if idle_ms > 0 {
+                state.last_output = Some(Instant::now());
+            }
+            state.reported = false;
+        }
+        if idle_ms == 0 {
+            return PortablePtyResult::Ok;
+        }
+
+        let threads: Arc<ThreadGroup> = Arc::default();
+        let name = crate::lifecycle::thread_name(pty, "idle");
+        let handle = HandleRef::new(pty);
+        if threads.spawn(&name, move || watch(handle)).is_err() {
+            lock(&pty.idle.state).timeout = None;
+            return PortablePtyResult::ErrUnsupported;
+        }
+        *lock(&pty.idle.watcher) = Some(threads);
+        PortablePtyResult::Ok
+    })
+}
+
+/// Post a `PORTABLE_PTY_EVENT_ACTIVITY` event when output is read after
+/// none has been for at least `quiet_ms`: once per quiet spell, with the
+/// first output that ends it.
+///
+/// - `quiet_ms`: how long output must have stopped for; 0 to stop
+///   reporting.
+///
+/// A quiet spell already under way when this is called is timed from the
+/// last output read, or from the call if there's been none since the
+/// handle stopped timing it.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_set_activity_threshold(
+    handle: *const PortablePty,
+    quiet_ms: u32,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        let mut state = lock(&pty.idle.state);
+        state.activity_after = (quiet_ms > 0).then(|| Duration::from_millis(quiet_ms.into()));
+        if quiet_ms > 0 && state.last_output.is_none() {
+            state.last_output = Some(Instant::now());
+        }
+        PortablePtyResult::Ok
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::events::{portable_pty_event_free, portable_pty_next_event, PortablePtyEvent};
+    use crate::tests::{open_and_spawn, read_string};
+
+    /// The quiet time of each `kind` event posted within `within`.
+    fn posted(handle: *mut PortablePty, kind: u32, within: Duration) -> Vec<i64> {
+        let deadline = Instant::now() + within;
+        let mut events = Vec::new();
+        while Instant::now() < deadline {
+            let mut event: PortablePtyEvent = unsafe { std::mem::zeroed() };
+            if portable_pty_next_event(handle, &mut event) {
+                portable_pty_event_free(&mut event);
+                if event.kind == kind {
+                    events.push(event.value);
+                }
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        events
+    }
+
+    #[test]
+    fn test_reports_each_quiet_spell_once() {
+        let handle = open_and_spawn(
+            "sh",
+            &["sh", "-c", "echo one; sleep 0.5; echo two; sleep 5"],
+        );
+        assert!(matches!(
+            portable_pty_set_idle_timeout(handle, 150),
+            PortablePtyResult::Ok
+        ));
+        let mut output = String::new();
+        while !output.contains("one") {
+            output += &read_string(handle);
+        }
+        let events = posted(handle, PORTABLE_PTY_EVENT_IDLE, Duration::from_millis(400));
+        assert!(matches!(events[..], [quiet] if quiet >= 150), "{events:?}");
+
+        while !output.contains("two") {
+            output += &read_string(handle);
+        }
+        let events = posted(handle, PORTABLE_PTY_EVENT_IDLE, Duration::from_millis(400));
+        assert_eq!(events.len(), 1, "{events:?}");
+
+        assert!(matches!(
+            portable_pty_set_idle_timeout(handle, 0),
+            PortablePtyResult::Ok
+        ));
+        crate::portable_pty_close(handle);
+    }
+
+    #[test]
+    fn test_reports_output_after_quiet_spell() {
+        let script = "echo one; sleep 0.1; echo two; sleep 0.4; echo three; sleep 5";
+        let handle = open_and_spawn("sh", &["sh", "-c", script]);
+        assert!(matches!(
+            portable_pty_set_activity_threshold(handle, 300),
+            PortablePtyResult::Ok
+        ));
+        let mut output = String::new();
+        while !output.contains("three") {
+            output += &read_string(handle);
+        }
+        // Only "three" came after a long enough gap.
+        let events = posted(
+            handle,
+            PORTABLE_PTY_EVENT_ACTIVITY,
+            Duration::from_millis(50),
+        );
+        assert!(matches!(events[..], [quiet] if quiet >= 300), "{events:?}");
+        crate::portable_pty_close(handle);
+    }
+}
@@ -0,0 +1,233 @@
+//! Sending the child the keys a terminal would.
+//!
+//! Interrupt, end of file and suspend are whatever characters the
+//! terminal's line discipline has been told they are, which `stty` can
+//! change from the usual Ctrl-C, Ctrl-D and Ctrl-Z. On a local Unix PTY the
+//! senders look the character up in the terminal's settings; elsewhere —
+//! on Windows, where ConPTY turns Ctrl-C into `CTRL_C_EVENT`, and for
+//! remote backends, whose far end has its own settings — they send the
+//! usual one.
+//!
+//! Piped handles have no terminal to interpret them: end of file closes
+//! the child's stdin, and on Unix interrupt and suspend signal its
+//! process group (see `piped`).
+//!
+//! `portable_pty_send_control` sends a control character by its key
+//! instead, as typed, whatever the terminal makes of it.
+
+use crate::{PortablePty, PortablePtyResult};
+use std::ffi::{c_char, CStr};
+
+/// A character the line discipline treats specially.
+#[derive(Clone, Copy)]
+enum Special {
+    Interrupt,
+    Eof,
+    Suspend,
+}
+
+impl Special {
+    /// The character a terminal uses unless told otherwise.
+    fn default_char(self) -> u8 {
+        match self {
+            Special::Interrupt => 0x03,
+            Special::Eof => 0x04,
+            Special::Suspend => 0x1a,
+        }
+    }
+
+    #[cfg(unix)]
+    fn index(self) -> usize {
+        match self {
+            Special::Interrupt => libc::VINTR,
+            Special::Eof => libc::VEOF,
+            Special::Suspend => libc::VSUSP,
+        }
+    }
+}
+
+/// Do on a piped handle what the terminal would do for `special`.
+fn send_piped(pty: &PortablePty, special: Special) -> PortablePtyResult {
+    if let Special::Eof = special {
+        return crate::piped::close_input(pty);
+    }
+    #[cfg(unix)]
+    if pty.child_pid > 0 {
+        let signal = match special {
+            Special::Suspend => libc::SIGTSTP,
+            _ => libc::SIGINT,
+        };
+        return match unsafe { libc::killpg(pty.child_pid, signal) } {
+            0 => PortablePtyResult::Ok,
+            _ => PortablePtyResult::ErrKill,
+        };
+    }
+    PortablePtyResult::ErrUnsupported
+}
+
+/// The character the handle's terminal uses for `special`; None if it has
+/// none set.
+fn special_char(pty: &PortablePty, special: Special) -> Result<Option<u8>, PortablePtyResult> {
+    #[cfg(unix)]
+    if let Some(fd) = pty
+        .master
+        .as_raw_fd()
+        .filter(|_| pty.master.tty_name().is_some())
+    {
+        let mut t = std::mem::MaybeUninit::<libc::termios>::uninit();
+        if unsafe { libc::tcgetattr(fd, t.as_mut_ptr()) } != 0 {
+            return Err(PortablePtyResult::ErrMode);
+        }
+        let c = unsafe { t.assume_init() }.c_cc[special.index()];
+        // Unset is 0 on Linux and 0xff on the BSDs.
+        return Ok(Some(c).filter(|&c| c != 0 && c != 0xff));
+    }
+    let _ = pty;
+    Ok(Some(special.default_char()))
+}
+
+fn send_special(handle: *const PortablePty, special: Special) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        if crate::piped::is_piped(pty) {
+            return send_piped(pty, special);
+        }
+        match special_char(pty, special) {
+            Ok(Some(c)) => match pty.write_input(&[c]) {
+                Ok(()) => PortablePtyResult::Ok,
+                Err(_) => PortablePtyResult::ErrWrite,
+            },
+            Ok(None) => PortablePtyResult::ErrUnsupported,
+            Err(e) => e,
+        }
+    })
+}
+
+/// Send the terminal's interrupt character, Ctrl-C unless remapped.
+///
+/// Returns `ErrUnsupported` if the terminal has none set, and `ErrMode` if
+/// its settings can't be read. On a piped handle, returns `ErrKill` if the
+/// child can't be signalled and `ErrUnsupported` off Unix or before a
+/// spawn.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_send_interrupt(handle: *const PortablePty) -> PortablePtyResult {
+    send_special(handle, Special::Interrupt)
+}
+
+/// Send the terminal's end-of-file character, Ctrl-D unless remapped.
+///
+/// Returns as `portable_pty_send_interrupt` does.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_send_eof(handle: *const PortablePty) -> PortablePtyResult {
+    send_special(handle, Special::Eof)
+}
+
+/// Send the terminal's suspend character, Ctrl-Z unless remapped.
+///
+/// Returns as `portable_pty_send_interrupt` does.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_send_suspend(handle: *const PortablePty) -> PortablePtyResult {
+    send_special(handle, Special::Suspend)
+}
+
+/// The control character typed as Ctrl and `key`: a letter, in either
+/// case, or one of `@[\]^_?`.
+fn control_char(key: &[u8]) -> Option<u8> {
+    match *key {
+        [c @ (b'@'..=b'_' | b'a'..=b'z')] => Some(c & 0x1f),
+        [b'?'] => Some(0x7f),
+        _ => None,
+    }
+}
+
+/// Send the control character for Ctrl and `key`.
+///
+/// - `key`: NUL-terminated key name: a letter, in either case, or one of
+///   `@`, `[`, `\`, `]`, `^`, `_` and `?` (DEL). So `"C"` sends 0x03,
+///   `"\\"` 0x1c and `"["` escape.
+///
+/// The character is sent as it is; it interrupts the child only if it's
+/// the terminal's interrupt character (see `portable_pty_send_interrupt`
+/// to send whichever that is). ConPTY turns a Ctrl-C into `CTRL_C_EVENT`
+/// for console programs that haven't turned processed input off, as a
+/// keypress in a console window would be.
+///
+/// Returns `ErrUnsupported` for any other key name.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_send_control(
+    handle: *const PortablePty,
+    key: *const c_char,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        if key.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+        let Some(c) = control_char(unsafe { CStr::from_ptr(key) }.to_bytes()) else {
+            return PortablePtyResult::ErrUnsupported;
+        };
+        match pty.write_input(&[c]) {
+            Ok(()) => PortablePtyResult::Ok,
+            Err(_) => PortablePtyResult::ErrWrite,
+        }
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::tests::{open_and_spawn, read_string};
+
+    #[test]
+    fn test_interrupt_follows_remapped_character() {
+        // With Ctrl-C remapped to Ctrl-G, only the new character interrupts.
+        let script = "stty intr ^G; trap 'echo caught; exit 0' INT; echo ready; sleep 5";
+        let handle = open_and_spawn("sh", &["sh", "-c", script]);
+        let mut output = String::new();
+        while !output.contains("ready") {
+            output += &read_string(handle);
+        }
+        let pty = unsafe { &*handle };
+        assert!(matches!(
+            special_char(pty, Special::Interrupt),
+            Ok(Some(0x07))
+        ));
+
+        assert!(matches!(
+            portable_pty_send_interrupt(handle),
+            PortablePtyResult::Ok
+        ));
+        let mut output = String::new();
+        while !output.contains("caught") {
+            output += &read_string(handle);
+        }
+        crate::portable_pty_close(handle);
+    }
+
+    #[test]
+    fn test_send_control() {
+        let script = "stty raw -echo; echo ready; head -c 4 | od -An -tx1";
+        let handle = open_and_spawn("sh", &["sh", "-c", script]);
+        let mut output = String::new();
+        while !output.contains("ready") {
+            output += &read_string(handle);
+        }
+        for key in [c"C", c"z", c"\\", c"?"] {
+            let result = portable_pty_send_control(handle, key.as_ptr());
+            assert!(matches!(result, PortablePtyResult::Ok));
+        }
+        let mut output = String::new();
+        while !output.contains("03 1a 1c 7f") {
+            output += &read_string(handle);
+        }
+        let result = portable_pty_send_control(handle, c"F1".as_ptr());
+        assert!(matches!(result, PortablePtyResult::ErrUnsupported));
+        crate::portable_pty_close(handle);
+    }
+}
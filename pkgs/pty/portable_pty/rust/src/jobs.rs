@@ -0,0 +1,245 @@
+//! Job control: noticing the child stop and continue.
+//!
+//! When the child is stopped — Ctrl+Z in a shell whose job control has
+//! been turned off, or a `SIGSTOP` — or continued, the `SIGCHLD` handler
+//! records it against the child's PID (see the crate docs) and wakes a
+//! background thread, which posts a `PORTABLE_PTY_EVENT_JOB` event on the
+//! handle. `portable_pty_child_state` tells the same at any time, for a UI
+//! showing a "suspended" badge.
+//!
+//! A stop and continue too close together for the thread to see in between
+//! post nothing: only changes of state are reported. Windows has no job
+//! control; there children only ever run and exit.
+
+use crate::{PortablePty, PortablePtyResult};
+
+/// The child is running (and in an event, has been continued).
+pub const PORTABLE_PTY_CHILD_RUNNING: u32 = 0;
+/// The child is stopped (`value` in an event = the signal that stopped it).
+pub const PORTABLE_PTY_CHILD_STOPPED: u32 = 1;
+/// The child has exited.
+pub const PORTABLE_PTY_CHILD_EXITED: u32 = 2;
+
+#[cfg(unix)]
+mod watch {
+    use super::*;
+    use crate::events::{EventQueue, PORTABLE_PTY_EVENT_JOB};
+    use std::sync::atomic::{AtomicI32, Ordering};
+    use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+    /// Write end of the pipe the `SIGCHLD` handler wakes the watcher
+    /// through, or -1 while there's no watcher.
+    static WAKE: AtomicI32 = AtomicI32::new(-1);
+
+    struct Watched {
+        pid: i32,
+        events: Arc<EventQueue>,
+        /// The stop signal last reported, 0 for running.
+        reported: i32,
+    }
+
+    static WATCHED: Mutex<Vec<Watched>> = Mutex::new(Vec::new());
+
+    fn lock() -> MutexGuard<'static, Vec<Watched>> {
+        WATCHED.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Wake the watcher. Async-signal-safe: called from the `SIGCHLD`
+    /// handler.
+    pub(crate) fn wake() {
+        let fd = WAKE.load(Ordering::Relaxed);
+        if fd >= 0 {
+            // A full pipe means a wake-up is pending already.
+            unsafe { libc::write(fd, [0u8].as_ptr().cast(), 1) };
+        }
+    }
+
+    /// Post an event for every watched child whose state has changed.
+    fn dispatch() {
+        let mut changed = Vec::new();
+        for watched in lock().iter_mut() {
+            let signal = crate::lookup_stop_signal(watched.pid);
+            if signal != watched.reported {
+                watched.reported = signal;
+                changed.push((watched.events.clone(), signal));
+            }
+        }
+        // Posted unlocked: the callback may close the handle.
+        for (events, signal) in changed {
+            let state = match signal {
+                0 => PORTABLE_PTY_CHILD_RUNNING,
+                _ => PORTABLE_PTY_CHILD_STOPPED,
+            };
+            events.post(
+                PORTABLE_PTY_EVENT_JOB,
+                state.into(),
+                signal.into(),
+                Vec::new(),
+            );
+        }
+    }
+
+    /// Start the watcher if it isn't running.
+    fn start() {
+        if WAKE.load(Ordering::Relaxed) >= 0 {
+            return;
+        }
+        let mut fds = [-1; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return;
+        }
+        let [read, write] = fds;
+        for fd in fds {
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+        }
+        unsafe { libc::fcntl(write, libc::F_SETFL, libc::O_NONBLOCK) };
+
+        let started = crate::lifecycle::spawn_thread("portable-pty-jobs", move || {
+            let mut buf = [0u8; 64];
+            loop {
+                let n = unsafe { libc::read(read, buf.as_mut_ptr().cast(), buf.len()) };
+                match n {
+                    0 => break,
+                    n if n < 0 && crate::get_errno() == libc::EINTR => continue,
+                    n if n < 0 => break,
+                    _ => dispatch(),
+                }
+            }
+            unsafe { libc::close(read) };
+        });
+        if started.is_ok() {
+            WAKE.store(write, Ordering::Relaxed);
+        } else {
+            unsafe {
+                libc::close(read);
+                libc::close(write);
+            }
+        }
+    }
+
+    /// Report the job state of `pid`, a freshly spawned child, on `events`.
+    pub(crate) fn watch(pid: i32, events: &Arc<EventQueue>) {
+        let mut watched = lock();
+        start();
+        watched.push(Watched {
+            pid,
+            events: events.clone(),
+            reported: 0,
+        });
+    }
+
+    pub(crate) fn unwatch(pid: i32) {
+        lock().retain(|watched| watched.pid != pid);
+    }
+
+    /// Stop the watcher, for `portable_pty_deinit`.
+    pub(crate) fn stop() {
+        let _watched = lock();
+        let fd = WAKE.swap(-1, Ordering::Relaxed);
+        if fd >= 0 {
+            // The watcher sees end of file and finishes.
+            unsafe { libc::close(fd) };
+        }
+    }
+}
+
+#[cfg(unix)]
+pub(crate) use watch::{stop, unwatch, wake, watch};
+
+/// Query whether the child is running, stopped or has exited.
+///
+/// - `out_state`: receives one of the `PORTABLE_PTY_CHILD_*` constants.
+///
+/// Returns `ErrWait` if nothing has been spawned.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_child_state(
+    handle: *mut PortablePty,
+    out_state: *mut u32,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pid = match unsafe { handle.as_ref() } {
+            Some(pty) if pty.child.is_none() => return PortablePtyResult::ErrWait,
+            Some(pty) => pty.child_pid,
+            None => return PortablePtyResult::ErrNull,
+        };
+        if out_state.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+
+        let exited = crate::portable_pty_wait(handle, std::ptr::null_mut());
+        #[cfg(unix)]
+        let stopped = crate::lookup_stop_signal(pid) != 0;
+        #[cfg(not(unix))]
+        let stopped = {
+            let _ = pid;
+            false
+        };
+        let state = if matches!(exited, PortablePtyResult::Ok) {
+            PORTABLE_PTY_CHILD_EXITED
+        } else if stopped {
+            PORTABLE_PTY_CHILD_STOPPED
+        } else {
+            PORTABLE_PTY_CHILD_RUNNING
+        };
+        unsafe { *out_state = state };
+        PortablePtyResult::Ok
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::events::{
+        portable_pty_event_free, portable_pty_next_event, PortablePtyEvent, PORTABLE_PTY_EVENT_JOB,
+    };
+    use std::time::{Duration, Instant};
+
+    fn next_job_event(handle: *mut PortablePty) -> (u64, i64) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut event: PortablePtyEvent = unsafe { std::mem::zeroed() };
+        while Instant::now() < deadline {
+            if portable_pty_next_event(handle, &mut event) {
+                portable_pty_event_free(&mut event);
+                if event.kind == PORTABLE_PTY_EVENT_JOB {
+                    return (event.id, event.value);
+                }
+            } else {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        }
+        panic!("no job event");
+    }
+
+    fn state(handle: *mut PortablePty) -> u32 {
+        let mut state = u32::MAX;
+        let result = portable_pty_child_state(handle, &mut state);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        state
+    }
+
+    #[test]
+    fn test_reports_stop_and_continue() {
+        let script = "kill -STOP $$; echo resumed; read line";
+        let handle = crate::tests::open_and_spawn("sh", &["sh", "-c", script]);
+
+        let stopped = (PORTABLE_PTY_CHILD_STOPPED.into(), libc::SIGSTOP.into());
+        assert_eq!(next_job_event(handle), stopped);
+        assert_eq!(state(handle), PORTABLE_PTY_CHILD_STOPPED);
+
+        crate::portable_pty_kill(handle, libc::SIGCONT);
+        assert_eq!(
+            next_job_event(handle),
+            (PORTABLE_PTY_CHILD_RUNNING.into(), 0)
+        );
+        assert_eq!(state(handle), PORTABLE_PTY_CHILD_RUNNING);
+        assert!(crate::tests::read_string(handle).contains("resumed"));
+
+        crate::portable_pty_kill(handle, libc::SIGKILL);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while state(handle) != PORTABLE_PTY_CHILD_EXITED {
+            assert!(Instant::now() < deadline);
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        crate::portable_pty_close(handle);
+    }
+}
@@ -0,0 +1,255 @@
+//! Special-key encoding.
+//!
+//! Turns a cursor, editing, function or keypad key into the bytes xterm
+//! sends for it, following the cursor-key and keypad modes the child has
+//! requested (see [`crate::modes`]): full-screen programs like vim and
+//! less switch the arrows to SS3 sequences and expect them that way. It's
+//! for embedders without a key encoder of their own; text and control
+//! characters are sent as they are.
+//!
+//! Modifiers follow xterm: a cursor, editing or function key with any
+//! gets a CSI sequence with the modifier parameter, whatever the modes.
+//! Keypad keys ignore them.
+
+use crate::modes::Modes;
+use crate::mouse::{PORTABLE_PTY_MOD_ALT, PORTABLE_PTY_MOD_CTRL, PORTABLE_PTY_MOD_SHIFT};
+use crate::{PortablePty, PortablePtyResult};
+
+pub const PORTABLE_PTY_KEY_UP: u32 = 0;
+pub const PORTABLE_PTY_KEY_DOWN: u32 = 1;
+pub const PORTABLE_PTY_KEY_RIGHT: u32 = 2;
+pub const PORTABLE_PTY_KEY_LEFT: u32 = 3;
+pub const PORTABLE_PTY_KEY_HOME: u32 = 4;
+pub const PORTABLE_PTY_KEY_END: u32 = 5;
+pub const PORTABLE_PTY_KEY_INSERT: u32 = 6;
+pub const PORTABLE_PTY_KEY_DELETE: u32 = 7;
+pub const PORTABLE_PTY_KEY_PAGE_UP: u32 = 8;
+pub const PORTABLE_PTY_KEY_PAGE_DOWN: u32 = 9;
+/// F1; F2 to F12 follow it, up to `PORTABLE_PTY_KEY_F1 + 11`.
+pub const PORTABLE_PTY_KEY_F1: u32 = 16;
+/// Keypad 0; keypad 1 to 9 follow it, up to `PORTABLE_PTY_KEY_KP_0 + 9`.
+pub const PORTABLE_PTY_KEY_KP_0: u32 = 32;
+pub const PORTABLE_PTY_KEY_KP_DECIMAL: u32 = 42;
+pub const PORTABLE_PTY_KEY_KP_ENTER: u32 = 43;
+pub const PORTABLE_PTY_KEY_KP_PLUS: u32 = 44;
+pub const PORTABLE_PTY_KEY_KP_MINUS: u32 = 45;
+pub const PORTABLE_PTY_KEY_KP_MULTIPLY: u32 = 46;
+pub const PORTABLE_PTY_KEY_KP_DIVIDE: u32 = 47;
+
+/// Longest sequence `encode` can produce (`CSI 24;8~`).
+const MAX_SEQUENCE_LEN: usize = 8;
+
+/// How a key is encoded.
+enum Kind {
+    /// `CSI x`, or `SS3 x` in application mode; F1 to F4 are always SS3.
+    Final { byte: u8, ss3_always: bool },
+    /// `CSI n ~`.
+    Tilde(u8),
+    /// `SS3 x` in application keypad mode, else the character.
+    Keypad { byte: u8, normal: u8 },
+}
+
+fn kind(key: u32) -> Option<Kind> {
+    let final_byte = |byte| Kind::Final {
+        byte,
+        ss3_always: false,
+    };
+    let kind = match key {
+        PORTABLE_PTY_KEY_UP => final_byte(b'A'),
+        PORTABLE_PTY_KEY_DOWN => final_byte(b'B'),
+        PORTABLE_PTY_KEY_RIGHT => final_byte(b'C'),
+        PORTABLE_PTY_KEY_LEFT => final_byte(b'D'),
+        PORTABLE_PTY_KEY_HOME => final_byte(b'H'),
+        PORTABLE_PTY_KEY_END => final_byte(b'F'),
+        PORTABLE_PTY_KEY_INSERT => Kind::Tilde(2),
+        PORTABLE_PTY_KEY_DELETE => Kind::Tilde(3),
+        PORTABLE_PTY_KEY_PAGE_UP => Kind::Tilde(5),
+        PORTABLE_PTY_KEY_PAGE_DOWN => Kind::Tilde(6),
+        f if (PORTABLE_PTY_KEY_F1..PORTABLE_PTY_KEY_F1 + 4).contains(&f) => Kind::Final {
+            byte: b'P' + (f - PORTABLE_PTY_KEY_F1) as u8,
+            ss3_always: true,
+        },
+        f if (PORTABLE_PTY_KEY_F1 + 4..PORTABLE_PTY_KEY_F1 + 12).contains(&f) => {
+            Kind::Tilde([15, 17, 18, 19, 20, 21, 23, 24][(f - PORTABLE_PTY_KEY_F1 - 4) as usize])
+        }
+        d if (PORTABLE_PTY_KEY_KP_0..PORTABLE_PTY_KEY_KP_0 + 10).contains(&d) => {
+            let digit = (d - PORTABLE_PTY_KEY_KP_0) as u8;
+            Kind::Keypad {
+                byte: b'p' + digit,
+                normal: b'0' + digit,
+            }
+        }
+        PORTABLE_PTY_KEY_KP_DECIMAL => Kind::Keypad {
+            byte: b'n',
+            normal: b'.',
+        },
+        PORTABLE_PTY_KEY_KP_ENTER => Kind::Keypad {
+            byte: b'M',
+            normal: b'\r',
+        },
+        PORTABLE_PTY_KEY_KP_PLUS => Kind::Keypad {
+            byte: b'k',
+            normal: b'+',
+        },
+        PORTABLE_PTY_KEY_KP_MINUS => Kind::Keypad {
+            byte: b'm',
+            normal: b'-',
+        },
+        PORTABLE_PTY_KEY_KP_MULTIPLY => Kind::Keypad {
+            byte: b'j',
+            normal: b'*',
+        },
+        PORTABLE_PTY_KEY_KP_DIVIDE => Kind::Keypad {
+            byte: b'o',
+            normal: b'/',
+        },
+        _ => return None,
+    };
+    Some(kind)
+}
+
+/// Encode one key press for the given modes; None for an unknown key.
+pub(crate) fn encode(modes: Modes, key: u32, modifiers: u32) -> Option<Vec<u8>> {
+    let mask = PORTABLE_PTY_MOD_SHIFT | PORTABLE_PTY_MOD_ALT | PORTABLE_PTY_MOD_CTRL;
+    // xterm's modifier parameter: 1 plus the bits.
+    let modifier = match modifiers & mask {
+        0 => None,
+        bits => Some(1 + bits),
+    };
+    let seq = match (kind(key)?, modifier) {
+        (Kind::Final { byte, .. }, Some(m)) => format!("\x1b[1;{m}{}", byte as char),
+        (Kind::Final { byte, ss3_always }, None) => {
+            let ss3 = ss3_always || modes.application_cursor;
+            format!("\x1b{}{}", if ss3 { 'O' } else { '[' }, byte as char)
+        }
+        (Kind::Tilde(n), Some(m)) => format!("\x1b[{n};{m}~"),
+        (Kind::Tilde(n), None) => format!("\x1b[{n}~"),
+        (Kind::Keypad { byte, .. }, _) if modes.application_keypad => {
+            format!("\x1bO{}", byte as char)
+        }
+        (Kind::Keypad { normal, .. }, _) => (normal as char).to_string(),
+    };
+    debug_assert!(seq.len() <= MAX_SEQUENCE_LEN);
+    Some(seq.into_bytes())
+}
+
+/// Encode a key press using the child's current cursor-key and keypad
+/// modes.
+///
+/// - `key`: one of the `PORTABLE_PTY_KEY_*` constants.
+/// - `modifiers`: bitwise OR of `PORTABLE_PTY_MOD_*`.
+///
+/// Writes the sequence to `out_buf` and returns its length, or -1 on error
+/// (an unknown key, or an `out_buf` too small for the sequence; 8 bytes is
+/// always enough). Modes are tracked from output returned by
+/// `portable_pty_read`.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_encode_key(
+    handle: *const PortablePty,
+    key: u32,
+    modifiers: u32,
+    out_buf: *mut u8,
+    out_len: usize,
+) -> i64 {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return -1,
+        };
+        if out_buf.is_null() {
+            return -1;
+        }
+
+        let modes = match pty.modes.lock() {
+            Ok(tracker) => tracker.modes(),
+            Err(_) => return -1,
+        };
+        let Some(seq) = encode(modes, key, modifiers) else {
+            return -1;
+        };
+        if seq.len() > out_len {
+            return -1;
+        }
+
+        unsafe {
+            std::ptr::copy_nonoverlapping(seq.as_ptr(), out_buf, seq.len());
+        }
+        seq.len() as i64
+    })
+}
+
+/// Send a key press to the child, encoded as `portable_pty_encode_key`
+/// does.
+///
+/// Returns `ErrUnsupported` for an unknown key.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_send_key(
+    handle: *const PortablePty,
+    key: u32,
+    modifiers: u32,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        let modes = match pty.modes.lock() {
+            Ok(tracker) => tracker.modes(),
+            Err(_) => return PortablePtyResult::ErrMode,
+        };
+        let Some(seq) = encode(modes, key, modifiers) else {
+            return PortablePtyResult::ErrUnsupported;
+        };
+        match pty.write_input(&seq) {
+            Ok(()) => PortablePtyResult::Ok,
+            Err(_) => PortablePtyResult::ErrWrite,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn modes(application_cursor: bool, application_keypad: bool) -> Modes {
+        Modes {
+            application_cursor,
+            application_keypad,
+            ..Modes::default()
+        }
+    }
+
+    #[test]
+    fn test_encodes_for_modes() {
+        let normal = modes(false, false);
+        let application = modes(true, true);
+        let up = |modes, mods| encode(modes, PORTABLE_PTY_KEY_UP, mods).unwrap();
+        assert_eq!(up(normal, 0), b"\x1b[A");
+        assert_eq!(up(application, 0), b"\x1bOA");
+        assert_eq!(up(application, PORTABLE_PTY_MOD_CTRL), b"\x1b[1;5A");
+
+        let f = |n, mods| encode(normal, PORTABLE_PTY_KEY_F1 + n, mods).unwrap();
+        assert_eq!(f(0, 0), b"\x1bOP");
+        assert_eq!(f(0, PORTABLE_PTY_MOD_SHIFT), b"\x1b[1;2P");
+        assert_eq!(f(4, 0), b"\x1b[15~");
+        assert_eq!(
+            f(
+                11,
+                PORTABLE_PTY_MOD_SHIFT | PORTABLE_PTY_MOD_ALT | PORTABLE_PTY_MOD_CTRL
+            ),
+            b"\x1b[24;8~"
+        );
+        assert_eq!(
+            encode(normal, PORTABLE_PTY_KEY_DELETE, 0).unwrap(),
+            b"\x1b[3~"
+        );
+
+        let kp = |modes, key| encode(modes, key, 0).unwrap();
+        assert_eq!(kp(normal, PORTABLE_PTY_KEY_KP_0 + 7), b"7");
+        assert_eq!(kp(application, PORTABLE_PTY_KEY_KP_0 + 7), b"\x1bOw");
+        assert_eq!(kp(normal, PORTABLE_PTY_KEY_KP_ENTER), b"\r");
+        assert_eq!(kp(application, PORTABLE_PTY_KEY_KP_ENTER), b"\x1bOM");
+
+        assert!(encode(normal, PORTABLE_PTY_KEY_F1 + 12, 0).is_none());
+    }
+}
@@ -1,8 +1,14 @@
 //! libportable-pty — Cross-platform PTY + process-spawn library.
 //!
-//! Exposes a C API wrapping the `portable-pty` crate from wezterm.
-//! Supports Linux, macOS, Windows (ConPTY), Android, and iOS targets where the
-//! platform sandbox permits local process spawning.
+//! Exposes a C API wrapping the `portable-pty` crate from wezterm, or,
+//! built without the default `portable-pty` feature for a smaller library,
+//! its own `openpty` and ConPTY code in the same shape (see `shim`).
+//! Supports Linux, macOS, FreeBSD, OpenBSD, Windows (ConPTY, or WinPTY
+//! before Windows 10 1809) and Android.
+//! On iOS, where apps can't run local processes, `portable_pty_open`
+//! returns `ErrUnsupported` while the loopback, mock, replay and SSH
+//! backends keep working; see [`capabilities`]. WebAssembly builds behave
+//! the same, minus SSH.
 //!
 //! ## SIGCHLD handling
 //!
@@ -15,57 +21,182 @@
 //! `waitpid(pid, WNOHANG)` for each tracked PTY child **before** chaining to
 //! the previous handler (Dart's). Exit statuses are cached in a lock-free
 //! global registry using atomics (all operations are async-signal-safe).
+//! Children stopping and continuing are noted there too, for `jobs`.
+//!
+//! ## Panics
+//!
+//! No entry point unwinds into its caller: a panic inside one is caught and
+//! reported as `ErrInternal` (or the function's usual failure value), see
+//! `ffi`.
 
-#[cfg(unix)]
-use libc;
-use portable_pty::{native_pty_system, Child, CommandBuilder, MasterPty, PtySize, SlavePty};
-use std::ffi::{c_char, c_int, CStr};
-#[cfg(target_os = "android")]
-use std::fs::OpenOptions;
-use std::io::{Read, Write};
-#[cfg(target_os = "android")]
-use std::os::fd::AsRawFd;
-#[cfg(unix)]
-use std::sync::atomic::{AtomicI32, Ordering};
-use std::sync::Mutex;
+// Every entry point takes raw pointers from C callers; the null checks at the
+// top of each function are the contract, not an `unsafe fn` signature.
+#![allow(clippy::not_unsafe_ptr_arg_deref)]
 
-/// Helper to get the current errno value on Unix platforms.
+use pty::{native_pty_system, Child, CommandBuilder, MasterPty, PtyPair, PtySize, SlavePty};
+use std::ffi::{c_char, c_int, CStr, OsString};
+use std::io::{self, Read, Write};
 #[cfg(unix)]
-fn get_errno() -> c_int {
-    std::io::Error::last_os_error().raw_os_error().unwrap_or(0)
+use std::sync::atomic::AtomicI32;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+pub mod accounting;
+#[cfg(any(target_os = "android", all(test, unix)))]
+mod android;
+pub mod audit;
+pub mod backend;
+pub mod broadcast;
+pub mod capabilities;
+pub mod clipboard;
+pub mod cmd;
+pub mod coalesce;
+pub mod commands;
+pub mod conpty;
+pub mod control;
+pub mod dart;
+pub mod device;
+pub mod environ;
+pub mod eof;
+pub mod events;
+pub mod expect;
+mod ffi;
+pub mod flow;
+#[cfg_attr(not(unix), allow(dead_code))]
+mod frames;
+#[cfg(fuzzing)]
+#[doc(hidden)]
+pub mod fuzz;
+pub mod group;
+pub mod idle;
+pub mod input;
+pub mod jobs;
+pub mod keys;
+pub mod lifecycle;
+#[cfg_attr(not(feature = "screen"), allow(dead_code))]
+pub mod links;
+pub mod loopback;
+pub mod matcher;
+pub mod metrics;
+pub mod mock;
+mod modes;
+pub mod monitor;
+pub mod mouse;
+pub mod nonblocking;
+pub mod open;
+pub mod persist;
+pub mod piped;
+pub mod policy;
+mod pool;
+pub mod pump;
+pub mod query;
+pub mod record;
+pub mod replay;
+pub mod run;
+#[cfg(feature = "screen")]
+mod screen;
+pub mod selftest;
+pub mod serve;
+pub mod shell;
+#[cfg(any(target_family = "wasm", not(feature = "portable-pty")))]
+mod shim;
+pub mod spawn;
+pub mod spawned;
+pub mod ssh;
+pub mod vectored;
+pub mod view;
+#[cfg(target_family = "wasm")]
+mod wasm;
+pub mod wide;
+#[cfg_attr(not(feature = "screen"), allow(dead_code))]
+pub mod words;
+pub mod wsl;
+
+// `portable-pty`, or our stand-in without it, and OS pipes, or in-memory
+// ones on WebAssembly.
+#[cfg(all(not(target_family = "wasm"), feature = "portable-pty"))]
+use portable_pty as pty;
+#[cfg(any(target_family = "wasm", not(feature = "portable-pty")))]
+use shim as pty;
+#[cfg(not(target_family = "wasm"))]
+use std::io::{pipe, PipeReader, PipeWriter};
+#[cfg(target_family = "wasm")]
+use wasm::{pipe, PipeReader, PipeWriter};
+
+use commands::CommandQueue;
+use events::EventQueue;
+use matcher::MatcherSet;
+use modes::ModeTracker;
+use record::Recorder;
+
+/// Collect a null-terminated array of null-terminated strings. Returns
+/// `None` if an entry isn't valid UTF-8.
+///
+/// # Safety
+///
+/// `list` must be non-null and point to a null-terminated array of valid
+/// C strings.
+pub(crate) unsafe fn c_string_array(list: *const *const c_char) -> Option<Vec<String>> {
+    let mut strings = Vec::new();
+    let mut i = 0;
+    loop {
+        let entry = unsafe { *list.add(i) };
+        if entry.is_null() {
+            return Some(strings);
+        }
+        strings.push(unsafe { CStr::from_ptr(entry) }.to_str().ok()?.to_owned());
+        i += 1;
+    }
 }
 
-#[cfg(target_os = "android")]
-fn normalize_android_pty_termios(master: &Box<dyn MasterPty + Send>) {
-    let Some(tty_path) = master.tty_name() else {
-        return;
-    };
+/// Take `bytes` as an argument, path or environment string: any bytes on
+/// Unix, where that's what they are, and UTF-8 elsewhere. Returns `None`
+/// if they aren't.
+#[cfg(unix)]
+pub(crate) fn os_string(bytes: &[u8]) -> Option<OsString> {
+    use std::os::unix::ffi::OsStrExt;
+    Some(std::ffi::OsStr::from_bytes(bytes).to_owned())
+}
 
-    let Ok(tty) = OpenOptions::new().read(true).write(true).open(&tty_path) else {
-        return;
-    };
+#[cfg(not(unix))]
+pub(crate) fn os_string(bytes: &[u8]) -> Option<OsString> {
+    std::str::from_utf8(bytes).ok().map(OsString::from)
+}
 
-    let fd = tty.as_raw_fd();
-    let mut termios = std::mem::MaybeUninit::<libc::termios>::uninit();
-    if unsafe { libc::tcgetattr(fd, termios.as_mut_ptr()) } != 0 {
-        return;
+/// [`c_string_array`] for arguments and the like, taken by [`os_string`].
+///
+/// # Safety
+///
+/// As for [`c_string_array`].
+pub(crate) unsafe fn os_string_array(list: *const *const c_char) -> Option<Vec<OsString>> {
+    let mut strings = Vec::new();
+    let mut i = 0;
+    loop {
+        let entry = unsafe { *list.add(i) };
+        if entry.is_null() {
+            return Some(strings);
+        }
+        strings.push(os_string(unsafe { CStr::from_ptr(entry) }.to_bytes())?);
+        i += 1;
     }
-    let mut termios = unsafe { termios.assume_init() };
+}
 
-    termios.c_iflag |= libc::BRKINT | libc::ICRNL | libc::IXON;
-    #[cfg(any(target_os = "android", target_os = "linux"))]
-    {
-        termios.c_iflag |= libc::IUTF8;
-    }
-    termios.c_oflag |= libc::OPOST | libc::ONLCR;
-    termios.c_cflag |= libc::CREAD;
-    termios.c_lflag |=
-        libc::ECHO | libc::ECHOE | libc::ECHOK | libc::ICANON | libc::IEXTEN | libc::ISIG;
-    termios.c_cc[libc::VMIN] = 1;
-    termios.c_cc[libc::VTIME] = 0;
+/// Helper to get the current errno value on Unix platforms.
+#[cfg(unix)]
+fn get_errno() -> c_int {
+    std::io::Error::last_os_error().raw_os_error().unwrap_or(0)
+}
 
+/// Block `SIGPIPE` on the calling thread, so writing to a pipe whose reader
+/// has gone away fails with `EPIPE` instead of killing the process.
+#[cfg(unix)]
+fn block_sigpipe() {
     unsafe {
-        let _ = libc::tcsetattr(fd, libc::TCSANOW, &termios);
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGPIPE);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
     }
 }
 
@@ -98,6 +229,8 @@ struct PidSlot {
     pid: AtomicI32,
     /// Raw `waitpid` status word, or SLOT_RUNNING / SLOT_EMPTY.
     status: AtomicI32,
+    /// The signal that stopped the child, or 0 while it isn't stopped.
+    stopped_by: AtomicI32,
 }
 
 #[cfg(unix)]
@@ -106,6 +239,7 @@ impl PidSlot {
         PidSlot {
             pid: AtomicI32::new(0),
             status: AtomicI32::new(SLOT_EMPTY),
+            stopped_by: AtomicI32::new(0),
         }
     }
 }
@@ -113,11 +247,7 @@ impl PidSlot {
 // We can't use Vec or HashMap in a signal handler. A fixed-size array of
 // atomics is async-signal-safe.
 #[cfg(unix)]
-static PID_REGISTRY: [PidSlot; MAX_TRACKED_PIDS] = {
-    // const array init
-    const EMPTY: PidSlot = PidSlot::new();
-    [EMPTY; MAX_TRACKED_PIDS]
-};
+static PID_REGISTRY: [PidSlot; MAX_TRACKED_PIDS] = [const { PidSlot::new() }; MAX_TRACKED_PIDS];
 
 /// Previous SIGCHLD handler action, saved so we can chain to it.
 #[cfg(unix)]
@@ -140,6 +270,7 @@ fn register_pid(pid: i32) {
             .is_ok()
         {
             slot.status.store(SLOT_RUNNING, Ordering::Relaxed);
+            slot.stopped_by.store(0, Ordering::Relaxed);
             return;
         }
     }
@@ -147,6 +278,18 @@ fn register_pid(pid: i32) {
     // The ECHILD fallback in portable_pty_wait will still handle it.
 }
 
+/// How many children the SIGCHLD registry is tracking, whether the
+/// handler is installed, and how many children it can track.
+#[cfg(unix)]
+fn pid_registry_usage() -> (usize, bool, usize) {
+    let tracked = PID_REGISTRY
+        .iter()
+        .filter(|slot| slot.pid.load(Ordering::Relaxed) != 0)
+        .count();
+    let installed = SIGCHLD_INSTALLED.load(Ordering::Relaxed) != 0;
+    (tracked, installed, MAX_TRACKED_PIDS)
+}
+
 /// Unregister a child PID (called on close).
 #[cfg(unix)]
 fn unregister_pid(pid: i32) {
@@ -162,10 +305,11 @@ fn unregister_pid(pid: i32) {
     }
 }
 
-/// Look up a cached exit code from the SIGCHLD handler registry.
+/// Look up a cached status from the SIGCHLD handler registry.
 ///
-/// Returns `Some(exit_code)` if the handler already captured the child's exit,
-/// or `None` if the child is still running (or not tracked).
+/// Returns `Some(raw_status)`, the `waitpid` status word, if the handler
+/// already captured the child's exit, or `None` if the child is still
+/// running (or not tracked).
 #[cfg(unix)]
 fn lookup_cached_status(pid: i32) -> Option<c_int> {
     for slot in PID_REGISTRY.iter() {
@@ -174,20 +318,84 @@ fn lookup_cached_status(pid: i32) -> Option<c_int> {
             if raw == SLOT_RUNNING || raw == SLOT_EMPTY {
                 return None;
             }
-            // Decode the raw waitpid status word.
-            let code = if libc::WIFEXITED(raw) {
-                libc::WEXITSTATUS(raw)
-            } else if libc::WIFSIGNALED(raw) {
-                128 + libc::WTERMSIG(raw)
-            } else {
-                -1
-            };
-            return Some(code);
+            return Some(raw);
         }
     }
     None
 }
 
+/// Like `lookup_cached_status`, for a child `waitpid` says is already
+/// reaped.
+///
+/// The `SIGCHLD` handler reaps a tracked child before storing its status,
+/// so a wait in between finds neither the child nor a status. A tracked
+/// child's status is waited for briefly rather than given up on.
+#[cfg(unix)]
+fn reaped_status(pid: i32) -> Option<c_int> {
+    let tracked = PID_REGISTRY
+        .iter()
+        .any(|slot| slot.pid.load(Ordering::Relaxed) == pid);
+    let deadline = std::time::Instant::now() + Duration::from_millis(100);
+    loop {
+        let status = lookup_cached_status(pid);
+        if status.is_some() || !tracked || std::time::Instant::now() >= deadline {
+            return status;
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+/// The signal that stopped a tracked child, or 0 if it isn't stopped (or
+/// isn't tracked).
+#[cfg(unix)]
+fn lookup_stop_signal(pid: i32) -> c_int {
+    PID_REGISTRY
+        .iter()
+        .find(|slot| slot.pid.load(Ordering::Relaxed) == pid)
+        .map_or(0, |slot| slot.stopped_by.load(Ordering::Relaxed))
+}
+
+/// Note a tracked child stopping (`signal` > 0) or continuing (0), and wake
+/// the job watcher. Async-signal-safe.
+#[cfg(unix)]
+fn record_stop(pid: i32, signal: c_int) {
+    if let Some(slot) = PID_REGISTRY
+        .iter()
+        .find(|slot| slot.pid.load(Ordering::Relaxed) == pid)
+    {
+        slot.stopped_by.store(signal, Ordering::Relaxed);
+        jobs::wake();
+    }
+}
+
+/// Exit code for a raw `waitpid` status word: the exit status, 128 + the
+/// signal number for a signalled child, or -1 for anything else.
+#[cfg(unix)]
+fn wait_status_code(raw: c_int) -> c_int {
+    if libc::WIFEXITED(raw) {
+        libc::WEXITSTATUS(raw)
+    } else if libc::WIFSIGNALED(raw) {
+        128 + libc::WTERMSIG(raw)
+    } else {
+        -1
+    }
+}
+
+/// Rebuild the `waitpid` status word from a `SIGCHLD` siginfo's `si_code`
+/// and `si_status`, or None if it doesn't describe a terminated child.
+///
+/// Linux, macOS and the BSDs share the status layout: exit status in bits
+/// 8-15, terminating signal in bits 0-6, core-dump flag in bit 7.
+#[cfg(unix)]
+fn siginfo_wait_status(si_code: c_int, si_status: c_int) -> Option<c_int> {
+    match si_code {
+        libc::CLD_EXITED => Some((si_status & 0xff) << 8),
+        libc::CLD_KILLED => Some(si_status & 0x7f),
+        libc::CLD_DUMPED => Some((si_status & 0x7f) | 0x80),
+        _ => None,
+    }
+}
+
 /// The actual SIGCHLD handler. This runs in signal context so only
 /// async-signal-safe functions may be called (waitpid, atomic loads/stores).
 ///
@@ -198,6 +406,10 @@ fn lookup_cached_status(pid: i32) -> Option<c_int> {
 /// kernel regardless of whether another thread has already reaped the child),
 /// and THEN try `waitpid` for any other registered PIDs that might have
 /// exited due to signal coalescing.
+///
+/// Not every kernel fills in the child fields for `SIGCHLD` (OpenBSD
+/// delivers it with a plain `SI_USER` siginfo); there the first step finds
+/// nothing and the `waitpid` pass does all the work.
 #[cfg(unix)]
 extern "C" fn sigchld_handler(sig: c_int, info: *mut libc::siginfo_t, ctx: *mut libc::c_void) {
     // Step 1: Extract exit info from siginfo_t. This tells us which PID
@@ -209,23 +421,15 @@ extern "C" fn sigchld_handler(sig: c_int, info: *mut libc::siginfo_t, ctx: *mut
         let si_code = si.si_code;
         let si_status = unsafe { si.si_status() };
 
-        if si_pid > 0
-            && (si_code == libc::CLD_EXITED
-                || si_code == libc::CLD_KILLED
-                || si_code == libc::CLD_DUMPED)
-        {
-            // Convert to a waitpid-style status word so lookup_cached_status
-            // can decode it uniformly.
-            let raw_status = if si_code == libc::CLD_EXITED {
-                // Normal exit: encode as WIFEXITED status word
-                (si_status & 0xff) << 8
-            } else {
-                // Killed by signal: encode as WIFSIGNALED status word
-                // If core dumped (CLD_DUMPED), set bit 7
-                let core_bit = if si_code == libc::CLD_DUMPED { 0x80 } else { 0 };
-                (si_status & 0x7f) | core_bit
-            };
-
+        if si_pid > 0 {
+            match si_code {
+                libc::CLD_STOPPED => record_stop(si_pid, si_status),
+                libc::CLD_CONTINUED => record_stop(si_pid, 0),
+                _ => {}
+            }
+        }
+        let raw_status = siginfo_wait_status(si_code, si_status).filter(|_| si_pid > 0);
+        if let Some(raw_status) = raw_status {
             // Store in registry if this PID is tracked.
             for slot in PID_REGISTRY.iter() {
                 let slot_pid = slot.pid.load(Ordering::Relaxed);
@@ -244,8 +448,8 @@ extern "C" fn sigchld_handler(sig: c_int, info: *mut libc::siginfo_t, ctx: *mut
     }
 
     // Step 2: Handle signal coalescing — multiple children may have exited
-    // but only one SIGCHLD was delivered. Try waitpid for all tracked PIDs
-    // that are still marked as SLOT_RUNNING.
+    // (or stopped, or continued) but only one SIGCHLD was delivered. Try
+    // waitpid for all tracked PIDs that are still marked as SLOT_RUNNING.
     for slot in PID_REGISTRY.iter() {
         let pid = slot.pid.load(Ordering::Relaxed);
         if pid <= 0 {
@@ -255,19 +459,35 @@ extern "C" fn sigchld_handler(sig: c_int, info: *mut libc::siginfo_t, ctx: *mut
             continue;
         }
         let mut status: c_int = 0;
-        let ret = unsafe { libc::waitpid(pid, &mut status, libc::WNOHANG) };
+        let flags = libc::WNOHANG | libc::WUNTRACED | libc::WCONTINUED;
+        let ret = unsafe { libc::waitpid(pid, &mut status, flags) };
         if ret == pid {
-            slot.status.store(status, Ordering::Relaxed);
+            if libc::WIFSTOPPED(status) {
+                record_stop(pid, libc::WSTOPSIG(status));
+            } else if libc::WIFCONTINUED(status) {
+                record_stop(pid, 0);
+            } else {
+                slot.status.store(status, Ordering::Relaxed);
+            }
         }
         // ret == 0: still running. ret == -1: ECHILD (Dart's thread reaped it,
         // but we may have already captured status from siginfo_t above or in
         // a previous signal delivery).
     }
 
-    // Chain to the previous handler.
+    // Chain to the previous handler, unless it asked not to hear about
+    // children stopping and continuing, which we do.
     unsafe {
-        let prev = &*(&raw const PREV_SIGCHLD_ACTION);
+        let prev = (&raw const PREV_SIGCHLD_ACTION).read();
         let flags = prev.sa_flags;
+        let job_control = !info.is_null()
+            && matches!(
+                (*info).si_code,
+                libc::CLD_STOPPED | libc::CLD_CONTINUED | libc::CLD_TRAPPED
+            );
+        if job_control && flags & libc::SA_NOCLDSTOP != 0 {
+            return;
+        }
         if flags & libc::SA_SIGINFO != 0 {
             // SA_SIGINFO handler: void (*)(int, siginfo_t*, void*)
             let handler = prev.sa_sigaction;
@@ -299,15 +519,16 @@ fn ensure_sigchld_handler() {
         let mut current: libc::sigaction = std::mem::zeroed();
         libc::sigaction(libc::SIGCHLD, std::ptr::null(), &mut current);
 
-        if current.sa_sigaction == sigchld_handler as usize {
+        if current.sa_sigaction == sigchld_handler as *const () as usize {
             // Our handler is still installed — nothing to do.
             return;
         }
 
         // Either first install or someone overwrote us. (Re-)install.
         let mut sa: libc::sigaction = std::mem::zeroed();
-        sa.sa_sigaction = sigchld_handler as usize;
-        sa.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART | libc::SA_NOCLDSTOP;
+        sa.sa_sigaction = sigchld_handler as *const () as usize;
+        // Not SA_NOCLDSTOP: stops and continues are reported (see `jobs`).
+        sa.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
         libc::sigemptyset(&mut sa.sa_mask);
 
         // Save the current handler (Dart's or whoever overwrote us) for chaining.
@@ -316,6 +537,28 @@ fn ensure_sigchld_handler() {
     }
 }
 
+/// Put back the `SIGCHLD` handler we replaced, unless someone has replaced
+/// ours since, and forget every tracked PID.
+#[cfg(unix)]
+fn remove_sigchld_handler() {
+    unsafe {
+        let mut current: libc::sigaction = std::mem::zeroed();
+        libc::sigaction(libc::SIGCHLD, std::ptr::null(), &mut current);
+        if current.sa_sigaction == sigchld_handler as *const () as usize {
+            libc::sigaction(
+                libc::SIGCHLD,
+                &raw const PREV_SIGCHLD_ACTION,
+                std::ptr::null_mut(),
+            );
+        }
+    }
+    SIGCHLD_INSTALLED.store(0, Ordering::Relaxed);
+    for slot in PID_REGISTRY.iter() {
+        slot.pid.store(0, Ordering::Relaxed);
+        slot.status.store(SLOT_EMPTY, Ordering::Relaxed);
+    }
+}
+
 // ---------------------------------------------------------------------------
 // Result enum
 // ---------------------------------------------------------------------------
@@ -335,6 +578,17 @@ pub enum PortablePtyResult {
     ErrSize = 10,
     ErrWaitBlocking = 11,
     ErrProcessGroup = 12,
+    ErrTimeout = 13,
+    ErrPattern = 14,
+    ErrEof = 15,
+    ErrRecord = 16,
+    ErrReplay = 17,
+    ErrBackend = 18,
+    ErrAuth = 19,
+    ErrUnsupported = 20,
+    ErrDenied = 21,
+    ErrPolicy = 22,
+    ErrInternal = 23,
 }
 
 // ---------------------------------------------------------------------------
@@ -343,298 +597,1055 @@ pub enum PortablePtyResult {
 
 pub struct PortablePty {
     master: Box<dyn MasterPty + Send>,
-    slave: Box<dyn SlavePty + Send>,
+    /// The slave end, unless the EOF policy has closed it.
+    slave: Option<Box<dyn SlavePty + Send>>,
     reader: Mutex<Box<dyn Read + Send>>,
-    writer: Mutex<Box<dyn Write + Send>>,
+    /// Input for the child; holds what's written while auto-flush is off.
+    writer: Mutex<io::BufWriter<Box<dyn Write + Send>>>,
+    /// Whether every `portable_pty_write` is flushed straight away.
+    auto_flush: AtomicBool,
+    /// Whether `portable_pty_write` only takes what fits (see `nonblocking`).
+    nonblocking_writes: AtomicBool,
+    /// Whether the embedder has made the master non-blocking (see
+    /// `nonblocking`).
+    nonblocking: AtomicBool,
+    /// Holds auto-flushed writes back briefly, if asked to.
+    coalescer: coalesce::Coalescer,
     child: Option<Box<dyn Child + Send + Sync>>,
     child_pid: i32,
+    /// What the child started, for killing with it.
+    group: group::Group,
+    /// The command the child was spawned with.
+    spawned: Option<spawned::Spawned>,
     /// Cached exit code — once we detect the child has exited, we store the
     /// result here so that repeated `tryWait` / `wait` calls return the same
     /// value even after the process has been reaped.
     cached_exit_code: Option<c_int>,
+    /// The status the exit code was taken from, where there was one: the
+    /// `waitpid` status word on Unix, the exit code itself on Windows.
+    cached_raw_status: Option<c_int>,
+    /// Terminal modes requested by the child, tracked from read output.
+    modes: Mutex<ModeTracker>,
+    /// Whether wheel events turn into arrow keys on the alternate screen.
+    alternate_scroll: AtomicBool,
+    /// Output already taken off the master but not yet handed to the caller
+    /// (e.g. bytes past an `expect` match). Served before any new reads.
+    pending: Mutex<Vec<u8>>,
+    /// Persistent patterns scanned against read output.
+    matchers: Mutex<MatcherSet>,
+    /// Commands waiting for the shell to return to a prompt.
+    commands: Mutex<CommandQueue>,
+    /// Session recording, while one is running.
+    recorder: Mutex<Option<Recorder>>,
+    /// TCP server sharing the session, while one is running.
+    server: Mutex<Option<serve::Server>>,
+    /// The session's entry on the control socket, while published.
+    published: Mutex<Option<Arc<control::Published>>>,
+    /// Bytes read off the handle and written to the child so far.
+    io_counters: accounting::IoCounters,
+    /// Resource monitor watching the child, while one is running.
+    monitor: Mutex<Option<monitor::Monitor>>,
+    /// When output was last read, for idle events.
+    idle: idle::Idle,
+    /// The embedder has promised `portable_pty_read` is the handle's only
+    /// reader, so it can skip the locks.
+    exclusive_reader: AtomicBool,
+    /// What the handle's background threads are named for, if set.
+    thread_label: Mutex<Option<String>>,
+    /// ConPTY's own sequences removed from the output, if asked for.
+    output_filter: conpty::filter::OutputFilter,
+    /// Whether `portable_pty_read` may read, and how far the consumer is
+    /// behind.
+    flow: flow::Flow,
+    /// The screen model fed with the output, while one is tracked.
+    view: view::View,
+    /// The thread reading the handle in the background, while one runs.
+    pump: Mutex<Option<pump::Pump>>,
+    events: Arc<EventQueue>,
+    /// When reads reach end of file.
+    eof_policy: eof::EofPolicy,
 }
 
-// ---------------------------------------------------------------------------
-// C API
-// ---------------------------------------------------------------------------
+/// How often a wait for output checks whether the child has exited.
+#[cfg(unix)]
+const EXIT_CHECK_INTERVAL: Duration = Duration::from_millis(50);
 
-/// Open a new PTY with the given dimensions.
-///
-/// On success, writes the opaque handle to `*out` and returns `Ok`.
-#[unsafe(no_mangle)]
-pub extern "C" fn portable_pty_open(
-    rows: u16,
-    cols: u16,
-    out: *mut *mut PortablePty,
-) -> PortablePtyResult {
-    if out.is_null() {
-        return PortablePtyResult::ErrNull;
-    }
+/// How long output the child wrote just before exiting may take to reach
+/// the master.
+#[cfg(unix)]
+const DRAIN_GRACE: Duration = Duration::from_millis(20);
 
-    let pty_system = native_pty_system();
-    let size = PtySize {
-        rows,
-        cols,
-        pixel_width: 0,
-        pixel_height: 0,
+/// Poll `fd` for `events`; false if `timeout` (`None` for none) passed
+/// first.
+#[cfg(unix)]
+fn poll_fd(
+    fd: std::os::fd::RawFd,
+    events: libc::c_short,
+    timeout: Option<Duration>,
+) -> io::Result<bool> {
+    let timeout_ms: c_int = match timeout {
+        // Round up so short timeouts don't turn into a busy poll.
+        Some(t) => t
+            .as_nanos()
+            .div_ceil(1_000_000)
+            .try_into()
+            .unwrap_or(c_int::MAX),
+        None => -1,
     };
-
-    let pair = match pty_system.openpty(size) {
-        Ok(pair) => pair,
-        Err(_) => return PortablePtyResult::ErrOpen,
+    let mut pfd = libc::pollfd {
+        fd,
+        events,
+        revents: 0,
     };
-
-    #[cfg(target_os = "android")]
-    normalize_android_pty_termios(&pair.master);
-
-    let reader = match pair.master.try_clone_reader() {
-        Ok(r) => r,
-        Err(_) => {
-            return PortablePtyResult::ErrOpen;
+    loop {
+        let ret = unsafe { libc::poll(&mut pfd, 1, timeout_ms) };
+        if ret >= 0 {
+            return Ok(ret > 0);
         }
-    };
-    let writer = match pair.master.take_writer() {
-        Ok(w) => w,
-        Err(_) => {
-            return PortablePtyResult::ErrOpen;
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
         }
-    };
-
-    let handle = Box::new(PortablePty {
-        master: pair.master,
-        slave: pair.slave,
-        reader: Mutex::new(reader),
-        writer: Mutex::new(writer),
-        child: None,
-        child_pid: -1,
-        cached_exit_code: None,
-    });
+    }
+}
 
-    unsafe {
-        *out = Box::into_raw(handle);
+/// Whether the child `pid` has exited, leaving it to be reaped.
+#[cfg(unix)]
+fn process_exited(pid: libc::pid_t) -> bool {
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios"
+    ))]
+    {
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        let flags = libc::WEXITED | libc::WNOHANG | libc::WNOWAIT;
+        if unsafe { libc::waitid(libc::P_PID, pid as libc::id_t, &mut info, flags) } == 0 {
+            return unsafe { info.si_pid() } != 0;
+        }
     }
-    PortablePtyResult::Ok
+    // Reaped by someone else, or can't be looked at without reaping.
+    let gone = unsafe { libc::kill(pid, 0) } == -1;
+    gone && get_errno() == libc::ESRCH
 }
 
-/// Spawn a child process attached to the PTY.
-///
-/// - `cmd`: null-terminated executable path.
-/// - `argv`: null-terminated array of null-terminated argument strings,
-///   or NULL to use `cmd` as the sole argument.
-/// - `envp`: null-terminated array of `"KEY=VALUE"` strings, or NULL to
-///   inherit the current environment.
-#[unsafe(no_mangle)]
-pub extern "C" fn portable_pty_spawn(
-    handle: *mut PortablePty,
-    cmd: *const c_char,
-    argv: *const *const c_char,
-    envp: *const *const c_char,
-) -> PortablePtyResult {
-    let pty = match unsafe { handle.as_mut() } {
-        Some(p) => p,
-        None => return PortablePtyResult::ErrNull,
-    };
-    if cmd.is_null() {
-        return PortablePtyResult::ErrNull;
+/// Move as much of `pending` as fits into `buf`, returning how much.
+fn serve_pending(pending: &mut Vec<u8>, buf: &mut [u8]) -> usize {
+    let n = pending.len().min(buf.len());
+    buf[..n].copy_from_slice(&pending[..n]);
+    pending.drain(..n);
+    n
+}
+
+impl PortablePty {
+    /// Wrap an opened master/slave pair in a handle with no child yet.
+    fn from_pair(pair: PtyPair) -> Result<Box<PortablePty>, PortablePtyResult> {
+        let reader = pair
+            .master
+            .try_clone_reader()
+            .map_err(|_| PortablePtyResult::ErrOpen)?;
+        let writer = pair
+            .master
+            .take_writer()
+            .map_err(|_| PortablePtyResult::ErrOpen)?;
+
+        #[cfg(unix)]
+        let local = pair.master.tty_name().is_some();
+        #[cfg(not(unix))]
+        let local = false;
+
+        Ok(Box::new(PortablePty {
+            master: pair.master,
+            slave: Some(pair.slave),
+            reader: Mutex::new(reader),
+            writer: Mutex::new(io::BufWriter::new(writer)),
+            auto_flush: AtomicBool::new(true),
+            nonblocking_writes: AtomicBool::new(false),
+            nonblocking: AtomicBool::new(false),
+            coalescer: Default::default(),
+            child: None,
+            child_pid: -1,
+            group: Default::default(),
+            spawned: None,
+            cached_exit_code: None,
+            cached_raw_status: None,
+            modes: Mutex::new(ModeTracker::default()),
+            alternate_scroll: AtomicBool::new(false),
+            pending: Mutex::new(Vec::new()),
+            matchers: Mutex::new(MatcherSet::default()),
+            commands: Mutex::new(CommandQueue::default()),
+            recorder: Mutex::new(None),
+            server: Mutex::new(None),
+            published: Mutex::new(None),
+            io_counters: Default::default(),
+            monitor: Mutex::new(None),
+            idle: Default::default(),
+            exclusive_reader: AtomicBool::new(false),
+            thread_label: Mutex::new(None),
+            output_filter: Default::default(),
+            flow: Default::default(),
+            view: Default::default(),
+            pump: Mutex::new(None),
+            events: Default::default(),
+            eof_policy: eof::EofPolicy::new(local),
+        }))
     }
 
-    let cmd_str = unsafe { CStr::from_ptr(cmd) };
-    let cmd_str = match cmd_str.to_str() {
-        Ok(s) => s,
-        Err(_) => return PortablePtyResult::ErrSpawn,
-    };
+    /// Spawn `builder` on the slave side and adopt it as the child.
+    pub(crate) fn spawn(&mut self, builder: CommandBuilder) -> PortablePtyResult {
+        self.spawn_with(builder, &spawn::SpawnConfig::default())
+    }
 
-    let mut builder = CommandBuilder::new(cmd_str);
+    /// Spawn `builder` as `config` asks and adopt it as the child.
+    pub(crate) fn spawn_with(
+        &mut self,
+        mut builder: CommandBuilder,
+        config: &spawn::SpawnConfig,
+    ) -> PortablePtyResult {
+        // Closed by the EOF policy: the terminal is the last child's.
+        let Some(slave) = self.slave.as_deref() else {
+            return PortablePtyResult::ErrUnsupported;
+        };
+        config.prepare(&mut builder);
+        let allowed = policy::check(&mut builder);
+        if !matches!(allowed, PortablePtyResult::Ok) {
+            return allowed;
+        }
+        #[cfg(target_os = "android")]
+        let builder = android::prepare(builder);
 
-    // Parse argv
-    if !argv.is_null() {
-        let mut args = Vec::new();
-        unsafe {
-            let mut i = 0;
-            loop {
-                let arg = *argv.add(i);
-                if arg.is_null() {
-                    break;
-                }
-                match CStr::from_ptr(arg).to_str() {
-                    Ok(s) => args.push(s.to_owned()),
-                    Err(_) => return PortablePtyResult::ErrSpawn,
-                }
-                i += 1;
+        let allowed = audit::check(self, &builder);
+        if !matches!(allowed, PortablePtyResult::Ok) {
+            return allowed;
+        }
+
+        // Block SIGCHLD around spawn+register so the child can't be reaped
+        // before we've registered its PID in the SIGCHLD handler registry.
+        #[cfg(unix)]
+        let mut old_mask: libc::sigset_t = unsafe { std::mem::zeroed() };
+        #[cfg(unix)]
+        {
+            ensure_sigchld_handler();
+            let mut block_set: libc::sigset_t = unsafe { std::mem::zeroed() };
+            unsafe {
+                libc::sigemptyset(&mut block_set);
+                libc::sigaddset(&mut block_set, libc::SIGCHLD);
+                libc::sigprocmask(libc::SIG_BLOCK, &block_set, &mut old_mask);
             }
         }
-        // CommandBuilder::new already sets argv[0], so skip it if present
-        if args.len() > 1 {
-            builder.args(&args[1..]);
+
+        #[cfg(unix)]
+        let local = self.master.tty_name().is_some();
+        #[cfg(not(unix))]
+        let local = false;
+        let mut spawned_command = spawned::Spawned::new(&builder, local);
+        if let Some(argv0) = config.argv0() {
+            spawned_command.rename(argv0);
         }
-    }
 
-    // Parse envp
-    if !envp.is_null() {
-        // Clear inherited env and set only what's provided
-        builder.env_clear();
-        unsafe {
-            let mut i = 0;
-            loop {
-                let entry = *envp.add(i);
-                if entry.is_null() {
-                    break;
+        // Spawn the child on the slave side, or ourselves where the config
+        // has to act in the child.
+        let spawned = if config.needs_pre_exec() {
+            spawn::spawn(self.master.as_ref(), &builder, config)
+        } else {
+            (config.inherit(|| slave.spawn_command(builder)))
+                .map_err(|_| PortablePtyResult::ErrSpawn)
+        };
+        match spawned {
+            Ok(child) => {
+                let pid = child.process_id().map(|p| p as i32).unwrap_or(-1);
+                self.child = Some(child);
+                self.spawned = Some(spawned_command);
+                if self.eof_policy.get() == eof::PORTABLE_PTY_EOF_ALL_EXIT {
+                    self.slave = None;
                 }
-                if let Ok(s) = CStr::from_ptr(entry).to_str() {
-                    if let Some((key, val)) = s.split_once('=') {
-                        builder.env(key, val);
+                self.child_pid = pid;
+                self.group.adopt(pid);
+                // Register this PID with the SIGCHLD handler so we capture
+                // exit status before the Dart VM's handler reaps the child.
+                #[cfg(unix)]
+                {
+                    if pid > 0 {
+                        register_pid(pid);
+                        jobs::watch(pid, &self.events);
+                    }
+                    unsafe {
+                        libc::sigprocmask(libc::SIG_SETMASK, &old_mask, std::ptr::null_mut());
                     }
                 }
-                i += 1;
+                PortablePtyResult::Ok
+            }
+            Err(e) => {
+                // Unblock SIGCHLD on error path too.
+                #[cfg(unix)]
+                unsafe {
+                    libc::sigprocmask(libc::SIG_SETMASK, &old_mask, std::ptr::null_mut());
+                }
+                e
             }
         }
     }
 
-    // Block SIGCHLD around spawn+register so the child can't be reaped
-    // before we've registered its PID in the SIGCHLD handler registry.
-    #[cfg(unix)]
-    let mut old_mask: libc::sigset_t = unsafe { std::mem::zeroed() };
-    #[cfg(unix)]
-    {
-        ensure_sigchld_handler();
-        let mut block_set: libc::sigset_t = unsafe { std::mem::zeroed() };
-        unsafe {
-            libc::sigemptyset(&mut block_set);
-            libc::sigaddset(&mut block_set, libc::SIGCHLD);
-            libc::sigprocmask(libc::SIG_BLOCK, &block_set, &mut old_mask);
+    /// Read fresh output from the master and run it through the trackers.
+    ///
+    /// Doesn't look at `pending`; callers that hand bytes to the embedder
+    /// must drain that first.
+    ///
+    /// The slave end this handle holds keeps the master from ever reporting
+    /// end of file, and letting it go would lose output still queued when
+    /// the child exits (Linux fails reads with `EIO` then). So under the
+    /// default EOF policy, once the child has exited, reads return what it
+    /// left and then 0. See `eof` for the others.
+    fn read_master(&self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.wait_for_output()? {
+            return Ok(0);
+        }
+        let n = loop {
+            let mut reader = self
+                .reader
+                .lock()
+                .map_err(|_| io::Error::other("reader lock poisoned"))?;
+            let result = self.output_filter.read(&mut **reader, buf);
+            if !self.retry_read(&result)? {
+                break self.end_of_file(result)?;
+            }
+        };
+        if n > 0 {
+            self.observe_output(&buf[..n]);
+        }
+        Ok(n)
+    }
+
+    /// Hand out output as `portable_pty_read` does: what an earlier call
+    /// (e.g. expect) already consumed first, then fresh output, once flow
+    /// control lets it through.
+    fn read_output(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.flow.wait();
+        let served = match self.pending.lock() {
+            Ok(mut pending) => serve_pending(&mut pending, buf),
+            Err(_) => 0,
+        };
+        let n = match served {
+            0 => self.read_master(buf)?,
+            n => n,
+        };
+        self.flow.delivered(n);
+        Ok(n)
+    }
+
+    /// `read_output` for a caller that has promised to be the only reader.
+    fn read_output_exclusive(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.flow.wait();
+        let pending = self
+            .pending
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        let n = match serve_pending(pending, buf) {
+            0 => self.read_master_exclusive(buf)?,
+            n => n,
+        };
+        self.flow.delivered(n);
+        Ok(n)
+    }
+
+    /// `read_master` for a caller that has promised to be the only reader,
+    /// skipping the locks that keep readers apart.
+    fn read_master_exclusive(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.wait_for_output()? {
+            return Ok(0);
+        }
+        let result = loop {
+            let reader = self
+                .reader
+                .get_mut()
+                .map_err(|_| io::Error::other("reader lock poisoned"))?;
+            let result = self.output_filter.read_exclusive(&mut **reader, buf);
+            if !self.retry_read(&result)? {
+                break result;
+            }
+        };
+        let n = self.end_of_file(result)?;
+        if n > 0 {
+            self.observe_output(&buf[..n]);
+        }
+        Ok(n)
+    }
+
+    /// Under the child-exit EOF policy, wait for output or the child's
+    /// exit; false once the child has exited and left nothing to read.
+    /// With the master non-blocking, fails with `WouldBlock` rather than
+    /// wait.
+    fn wait_for_output(&self) -> io::Result<bool> {
+        #[cfg(unix)]
+        if self.eof_policy.get() == eof::PORTABLE_PTY_EOF_CHILD_EXIT
+            && !self.output_filter.has_buffered()
+            && !self.poll_master(libc::POLLIN, Some(Duration::ZERO))?
+        {
+            let timeout = match self.nonblocking.load(Ordering::Relaxed) {
+                true => Some(Duration::ZERO),
+                false => None,
+            };
+            if !self.wait_readable(timeout)? {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            if self.child_exited() && !self.poll_master(libc::POLLIN, Some(DRAIN_GRACE))? {
+                return Ok(false);
+            }
         }
+        Ok(true)
     }
 
-    // Spawn the child on the slave side
-    match pty.slave.as_ref().spawn_command(builder) {
-        Ok(child) => {
-            let pid = child.process_id().map(|p| p as i32).unwrap_or(-1);
-            pty.child = Some(child);
-            pty.child_pid = pid;
-            // Register this PID with the SIGCHLD handler so we capture
-            // exit status before the Dart VM's handler reaps the child.
+    /// Whether a read that came to `result` should be tried again, once
+    /// there's output: a non-blocking write (see `nonblocking`) had the
+    /// master it shares non-blocking at the time, not the embedder.
+    fn retry_read(&self, result: &io::Result<usize>) -> io::Result<bool> {
+        match result {
             #[cfg(unix)]
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock
+                    && !self.nonblocking.load(Ordering::Relaxed) =>
             {
-                if pid > 0 {
-                    register_pid(pid);
-                }
-                unsafe {
-                    libc::sigprocmask(libc::SIG_SETMASK, &old_mask, std::ptr::null_mut());
-                }
+                self.poll_master(libc::POLLIN, None)?;
+                Ok(true)
             }
-            PortablePtyResult::Ok
+            _ => Ok(false),
         }
-        Err(_) => {
-            // Unblock SIGCHLD on error path too.
+    }
+
+    /// A read's result, with the error that means end of file under the
+    /// EOF policy turned into 0.
+    fn end_of_file(&self, result: io::Result<usize>) -> io::Result<usize> {
+        match result {
+            // Linux's end of file once the slave is closed everywhere.
             #[cfg(unix)]
-            unsafe {
-                libc::sigprocmask(libc::SIG_SETMASK, &old_mask, std::ptr::null_mut());
+            Err(e)
+                if e.raw_os_error() == Some(libc::EIO)
+                    && self.eof_policy.get() == eof::PORTABLE_PTY_EOF_ALL_EXIT =>
+            {
+                Ok(0)
             }
-            PortablePtyResult::ErrSpawn
+            result => result,
         }
     }
-}
 
-/// Read bytes from the PTY master side (child's stdout).
-///
-/// Returns number of bytes read, or -1 on error/EOF.
-#[unsafe(no_mangle)]
-pub extern "C" fn portable_pty_read(handle: *mut PortablePty, buf: *mut u8, len: usize) -> i64 {
-    let pty = match unsafe { handle.as_mut() } {
-        Some(p) => p,
-        None => return -1,
-    };
-    if buf.is_null() || len == 0 {
-        return -1;
+    /// Run freshly read output through mode tracking and pattern matching.
+    ///
+    /// Called without the reader lock held, since matches may invoke the
+    /// embedder's event callback.
+    fn observe_output(&self, bytes: &[u8]) {
+        self.io_counters.add_output(bytes.len());
+        self.idle.output(&self.events);
+        let marks = match self.modes.lock() {
+            Ok(mut modes) => modes.feed(bytes),
+            Err(_) => Vec::new(),
+        };
+        self.view.feed(bytes);
+        record::capture(self, record::Event::Output(bytes));
+        serve::broadcast(self, bytes);
+        control::broadcast(self, bytes);
+        matcher::scan(self, bytes);
+        commands::observe(self, bytes, &marks);
     }
 
-    let slice = unsafe { std::slice::from_raw_parts_mut(buf, len) };
-    let mut reader = match pty.reader.lock() {
-        Ok(r) => r,
-        Err(_) => return -1,
-    };
-
-    match reader.read(slice) {
-        Ok(0) => 0, // EOF
-        Ok(n) => n as i64,
-        Err(_) => -1,
+    /// Resize the PTY, recording the change if a recording is running.
+    fn resize(&self, rows: u16, cols: u16) -> PortablePtyResult {
+        let size = PtySize {
+            rows,
+            cols,
+            pixel_width: 0,
+            pixel_height: 0,
+        };
+        match self.master.resize(size) {
+            Ok(()) => {
+                self.output_filter.resize((rows, cols));
+                self.view.resize(rows, cols);
+                record::capture(self, record::Event::Resize { rows, cols });
+                PortablePtyResult::Ok
+            }
+            Err(_) => PortablePtyResult::ErrResize,
+        }
     }
-}
 
-/// Write bytes to the PTY master side (child's stdin).
-///
-/// Returns number of bytes written, or -1 on error.
-#[unsafe(no_mangle)]
-pub extern "C" fn portable_pty_write(handle: *mut PortablePty, buf: *const u8, len: usize) -> i64 {
-    let pty = match unsafe { handle.as_mut() } {
-        Some(p) => p,
-        None => return -1,
-    };
-    if buf.is_null() || len == 0 {
-        return -1;
+    /// Write library-generated input (queued commands, replies) in full.
+    fn write_input(&self, bytes: &[u8]) -> io::Result<()> {
+        let mut writer = self
+            .writer
+            .lock()
+            .map_err(|_| io::Error::other("writer lock poisoned"))?;
+        writer.write_all(bytes)?;
+        writer.flush()?;
+        drop(writer);
+        self.observe_input(bytes);
+        Ok(())
     }
 
-    let slice = unsafe { std::slice::from_raw_parts(buf, len) };
-    let mut writer = match pty.writer.lock() {
-        Ok(w) => w,
-        Err(_) => return -1,
-    };
-
-    match writer.write(slice) {
-        Ok(n) => {
-            let _ = writer.flush();
-            n as i64
+    /// Write all of `parts` in order, with no other write landing between
+    /// them, flushing as `portable_pty_write` would.
+    fn write_whole(&self, parts: &[&[u8]]) -> io::Result<()> {
+        let mut writer = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+        for part in parts {
+            writer.write_all(part)?;
         }
-        Err(_) => -1,
+        if self.auto_flush.load(Ordering::Relaxed) && self.coalescer.wrote(writer.buffer().len()) {
+            writer.flush()?;
+        }
+        drop(writer);
+        for part in parts {
+            self.observe_input(part);
+        }
+        Ok(())
     }
-}
 
-/// Resize the PTY.
-#[unsafe(no_mangle)]
-pub extern "C" fn portable_pty_resize(
-    handle: *mut PortablePty,
-    rows: u16,
-    cols: u16,
-) -> PortablePtyResult {
-    let pty = match unsafe { handle.as_mut() } {
-        Some(p) => p,
-        None => return PortablePtyResult::ErrNull,
-    };
+    /// Account for input written to the child.
+    fn observe_input(&self, bytes: &[u8]) {
+        self.io_counters.add_input(bytes.len());
+        record::capture(self, record::Event::Input(bytes));
+    }
 
-    let size = PtySize {
-        rows,
-        cols,
-        pixel_width: 0,
-        pixel_height: 0,
-    };
+    /// Wait for the master itself to be ready for `events`: `POLLIN` for
+    /// output, or EOF, to read, `POLLOUT` for room to write.
+    #[cfg(unix)]
+    fn poll_master(&self, events: libc::c_short, timeout: Option<Duration>) -> io::Result<bool> {
+        let fd = self
+            .master
+            .as_raw_fd()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::Unsupported))?;
+        poll_fd(fd, events, timeout)
+    }
 
-    match pty.master.resize(size) {
-        Ok(()) => PortablePtyResult::Ok,
-        Err(_) => PortablePtyResult::ErrResize,
+    /// Note that the child exited with `waitpid` status `raw_status`,
+    /// returning its exit code.
+    #[cfg(unix)]
+    fn exited_with(&mut self, raw_status: c_int) -> c_int {
+        let code = wait_status_code(raw_status);
+        self.cached_exit_code = Some(code);
+        self.cached_raw_status = Some(raw_status);
+        code
     }
-}
 
-/// Get the PTY master side file descriptor.
-#[unsafe(no_mangle)]
-pub extern "C" fn portable_pty_master_fd(handle: *mut PortablePty) -> c_int {
-    let pty = match unsafe { handle.as_ref() } {
-        Some(p) => p,
-        None => return -1,
-    };
+    /// Note that the child exited with a status from upstream, returning
+    /// its exit code. Only a Windows exit code is kept as the raw status;
+    /// for stand-in children it means nothing.
+    fn exited_with_upstream(&mut self, status: &pty::ExitStatus) -> c_int {
+        let code: c_int = status.exit_code().try_into().unwrap_or(-1);
+        self.cached_exit_code = Some(code);
+        if cfg!(windows) && self.child_pid > 0 {
+            self.cached_raw_status = Some(status.exit_code() as c_int);
+        }
+        code
+    }
 
+    /// Whether the child this handle spawned has exited. Leaves it for
+    /// `portable_pty_wait` to reap.
     #[cfg(unix)]
-    {
-        pty.master.as_ref().as_raw_fd().unwrap_or(-1)
+    fn child_exited(&self) -> bool {
+        let pid = self.child_pid;
+        if pid <= 0 {
+            return false;
+        }
+        self.cached_exit_code.is_some()
+            || lookup_cached_status(pid).is_some()
+            || process_exited(pid)
     }
 
-    #[cfg(not(unix))]
-    {
-        -1
+    /// Take everything in the pending buffer.
+    fn take_pending(&self) -> Vec<u8> {
+        match self.pending.lock() {
+            Ok(mut pending) => std::mem::take(&mut *pending),
+            Err(_) => Vec::new(),
+        }
     }
-}
 
-/// Get the current PTY size as tracked by the kernel.
-#[unsafe(no_mangle)]
+    /// Put bytes back in front of the pending buffer.
+    fn unread(&self, mut bytes: Vec<u8>) {
+        if bytes.is_empty() {
+            return;
+        }
+        if let Ok(mut pending) = self.pending.lock() {
+            bytes.append(&mut pending);
+            *pending = bytes;
+        }
+    }
+
+    /// Wait until the master has output (or EOF) to read.
+    ///
+    /// `None` waits indefinitely. Returns `Ok(false)` if `timeout` elapsed.
+    /// Pending output, and output the ConPTY filter has ready, counts as
+    /// readable, as does the child having exited (see `read_master`).
+    fn wait_readable(&self, timeout: Option<Duration>) -> io::Result<bool> {
+        if self.pending.lock().map(|p| !p.is_empty()).unwrap_or(false)
+            || self.output_filter.has_buffered()
+        {
+            return Ok(true);
+        }
+
+        #[cfg(unix)]
+        {
+            if self.child_pid <= 0 || self.eof_policy.get() != eof::PORTABLE_PTY_EOF_CHILD_EXIT {
+                return self.poll_master(libc::POLLIN, timeout);
+            }
+            // Nothing wakes a poll when the child exits; look in between.
+            let deadline = timeout.map(|t| std::time::Instant::now() + t);
+            loop {
+                let left = deadline.map(|d| d.saturating_duration_since(std::time::Instant::now()));
+                let slice = left.map_or(EXIT_CHECK_INTERVAL, |l| l.min(EXIT_CHECK_INTERVAL));
+                if self.poll_master(libc::POLLIN, Some(slice))? || self.child_exited() {
+                    return Ok(true);
+                }
+                if left.is_some_and(|l| l <= slice) {
+                    return Ok(false);
+                }
+            }
+        }
+
+        #[cfg(not(unix))]
+        {
+            // ConPTY pipes can't be polled; the following read will block.
+            let _ = timeout;
+            Ok(true)
+        }
+    }
+
+    /// Wait until the child's input has room for a write.
+    ///
+    /// `None` waits indefinitely. Returns `Ok(false)` if `timeout` elapsed.
+    /// Only a local Unix PTY can be full; anything else counts as writable.
+    fn wait_writable(&self, timeout: Option<Duration>) -> io::Result<bool> {
+        #[cfg(unix)]
+        if self.master.tty_name().is_some() {
+            return self.poll_master(libc::POLLOUT, timeout);
+        }
+        let _ = timeout;
+        Ok(true)
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Owned buffers
+// ---------------------------------------------------------------------------
+
+/// A byte buffer allocated by this library and owned by the caller.
+///
+/// Release it with `portable_pty_buffer_free`. An empty buffer has a NULL
+/// `data` pointer.
+#[repr(C)]
+pub struct PortablePtyBuffer {
+    pub data: *mut u8,
+    pub len: usize,
+}
+
+impl PortablePtyBuffer {
+    const EMPTY: PortablePtyBuffer = PortablePtyBuffer {
+        data: std::ptr::null_mut(),
+        len: 0,
+    };
+
+    fn from_vec(bytes: Vec<u8>) -> Self {
+        if bytes.is_empty() {
+            return Self::EMPTY;
+        }
+        let boxed = bytes.into_boxed_slice();
+        let len = boxed.len();
+        PortablePtyBuffer {
+            data: Box::into_raw(boxed) as *mut u8,
+            len,
+        }
+    }
+
+    /// Free the allocation (if any) and reset to empty.
+    fn release(&mut self) {
+        if !self.data.is_null() {
+            let slice = std::ptr::slice_from_raw_parts_mut(self.data, self.len);
+            drop(unsafe { Box::from_raw(slice) });
+        }
+        *self = Self::EMPTY;
+    }
+}
+
+/// Free a buffer returned by this library. Safe to call on an empty buffer.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_buffer_free(buffer: PortablePtyBuffer) {
+    ffi::guard(|| {
+        let mut buffer = buffer;
+        buffer.release();
+    })
+}
+
+// ---------------------------------------------------------------------------
+// C API
+// ---------------------------------------------------------------------------
+
+/// Open a new PTY with the given dimensions.
+///
+/// On success, writes the opaque handle to `*out` and returns `Ok`.
+/// Returns `ErrUnsupported` on platforms without local processes (iOS).
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_open(
+    rows: u16,
+    cols: u16,
+    out: *mut *mut PortablePty,
+) -> PortablePtyResult {
+    ffi::guard(|| {
+        if out.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+        let size = PtySize {
+            rows,
+            cols,
+            pixel_width: 0,
+            pixel_height: 0,
+        };
+        match open_native(size) {
+            Ok(handle) => {
+                unsafe {
+                    *out = lifecycle::register(handle);
+                }
+                PortablePtyResult::Ok
+            }
+            Err(e) => e,
+        }
+    })
+}
+
+/// Open a local PTY of `size`, with no child yet.
+pub(crate) fn open_native(size: PtySize) -> Result<Box<PortablePty>, PortablePtyResult> {
+    if !capabilities::LOCAL_PROCESSES {
+        return Err(PortablePtyResult::ErrUnsupported);
+    }
+    // portable-pty can't open anything on Windows before ConPTY.
+    if cfg!(windows) && !conpty::available() {
+        return conpty::open_winpty(size);
+    }
+    let pair = native_pty_system()
+        .openpty(size)
+        .map_err(|_| PortablePtyResult::ErrOpen)?;
+
+    #[cfg(target_os = "android")]
+    android::normalize_pty_termios(pair.master.as_ref());
+
+    PortablePty::from_pair(pair)
+}
+
+/// Spawn a child process attached to the PTY.
+///
+/// - `cmd`: null-terminated executable path.
+/// - `argv`: null-terminated array of null-terminated argument strings,
+///   or NULL to use `cmd` as the sole argument.
+/// - `envp`: null-terminated array of `"KEY=VALUE"` strings, or NULL to
+///   inherit the current environment.
+///
+/// On Unix these are taken as the bytes they are, UTF-8 or not; elsewhere
+/// they must be UTF-8, and `ErrSpawn` is returned for a `cmd` or `argv`
+/// entry that isn't.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_spawn(
+    handle: *mut PortablePty,
+    cmd: *const c_char,
+    argv: *const *const c_char,
+    envp: *const *const c_char,
+) -> PortablePtyResult {
+    ffi::guard(|| {
+        let pty = match unsafe { handle.as_mut() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        match command_builder(cmd, argv, envp) {
+            Ok(builder) => pty.spawn(builder),
+            Err(e) => e,
+        }
+    })
+}
+
+/// Build the command `portable_pty_spawn` describes with `cmd`, `argv`
+/// and `envp`.
+pub(crate) fn command_builder(
+    cmd: *const c_char,
+    argv: *const *const c_char,
+    envp: *const *const c_char,
+) -> Result<CommandBuilder, PortablePtyResult> {
+    if cmd.is_null() {
+        return Err(PortablePtyResult::ErrNull);
+    }
+
+    let cmd = match os_string(unsafe { CStr::from_ptr(cmd) }.to_bytes()) {
+        Some(s) => s,
+        None => return Err(PortablePtyResult::ErrSpawn),
+    };
+
+    // Parse argv
+    let args = if argv.is_null() {
+        None
+    } else {
+        match unsafe { os_string_array(argv) } {
+            Some(args) => Some(args),
+            None => return Err(PortablePtyResult::ErrSpawn),
+        }
+    };
+
+    // Parse envp
+    let env = (!envp.is_null()).then(|| {
+        let mut env = Vec::new();
+        unsafe {
+            let mut i = 0;
+            loop {
+                let entry = *envp.add(i);
+                if entry.is_null() {
+                    break;
+                }
+                let entry = CStr::from_ptr(entry).to_bytes();
+                if let Some(at) = entry.iter().position(|&b| b == b'=') {
+                    if let (Some(key), Some(val)) =
+                        (os_string(&entry[..at]), os_string(&entry[at + 1..]))
+                    {
+                        env.push((key, val));
+                    }
+                }
+                i += 1;
+            }
+        }
+        env
+    });
+
+    Ok(build_command(cmd, args, env))
+}
+
+/// Build a command from `cmd`, the arguments `args` (`argv[0]` included)
+/// and the environment `env`, which replaces the inherited one.
+pub(crate) fn build_command(
+    cmd: OsString,
+    args: Option<Vec<OsString>>,
+    env: Option<Vec<(OsString, OsString)>>,
+) -> CommandBuilder {
+    let mut builder = CommandBuilder::new(cmd);
+
+    // CommandBuilder::new already sets argv[0], so skip it if present
+    if let Some(args) = args.filter(|args| args.len() > 1) {
+        builder.args(&args[1..]);
+    }
+
+    if let Some(env) = env {
+        // Clear inherited env and set only what's provided
+        builder.env_clear();
+        for (key, val) in env {
+            builder.env(key, val);
+        }
+    }
+
+    builder
+}
+
+/// Read bytes from the PTY master side (child's stdout).
+///
+/// Returns number of bytes read, 0 at end of file, or -1 on error. On Unix
+/// the end comes, by default, once the child has exited and everything it
+/// wrote has been read; `portable_pty_set_eof_policy` changes that. While
+/// output is paused, or held back by the watermark, it blocks until
+/// reading restarts (see `flow`). With the master made non-blocking, it
+/// returns `PORTABLE_PTY_READ_WOULD_BLOCK` rather than wait for output
+/// (see `nonblocking`).
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_read(handle: *mut PortablePty, buf: *mut u8, len: usize) -> i64 {
+    ffi::guard(|| {
+        let pty = match unsafe { handle.as_mut() } {
+            Some(p) => p,
+            None => return -1,
+        };
+        if buf.is_null() || len == 0 {
+            return -1;
+        }
+
+        let slice = unsafe { std::slice::from_raw_parts_mut(buf, len) };
+        let result = match pty.exclusive_reader.load(Ordering::Relaxed) {
+            true => pty.read_output_exclusive(slice),
+            false => pty.read_output(slice),
+        };
+        match result {
+            Ok(n) => n as i64, // 0 at EOF
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                nonblocking::PORTABLE_PTY_READ_WOULD_BLOCK
+            }
+            Err(_) => -1,
+        }
+    })
+}
+
+/// Write bytes to the PTY master side (child's stdin).
+///
+/// Returns number of bytes written, or -1 on error. Unless auto-flush has
+/// been turned off, they're on their way to the child by then. With
+/// non-blocking writes on, fewer may be written, or none, returning
+/// `PORTABLE_PTY_WRITE_WOULD_BLOCK` (see `nonblocking`).
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_write(handle: *mut PortablePty, buf: *const u8, len: usize) -> i64 {
+    ffi::guard(|| {
+        let pty = match unsafe { handle.as_mut() } {
+            Some(p) => p,
+            None => return -1,
+        };
+        if buf.is_null() || len == 0 {
+            return -1;
+        }
+
+        let slice = unsafe { std::slice::from_raw_parts(buf, len) };
+        if pty.nonblocking_writes.load(Ordering::Relaxed) || pty.nonblocking.load(Ordering::Relaxed)
+        {
+            return nonblocking::write(pty, slice);
+        }
+        let mut writer = match pty.writer.lock() {
+            Ok(w) => w,
+            Err(_) => return -1,
+        };
+
+        match writer.write(slice) {
+            Ok(n) => {
+                if pty.auto_flush.load(Ordering::Relaxed)
+                    && pty.coalescer.wrote(writer.buffer().len())
+                {
+                    let _ = writer.flush();
+                }
+                drop(writer);
+                pty.observe_input(&slice[..n]);
+                n as i64
+            }
+            Err(_) => -1,
+        }
+    })
+}
+
+/// Send the child whatever has been written but not yet flushed.
+///
+/// Returns `ErrWrite` if it couldn't all be written.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_flush(handle: *const PortablePty) -> PortablePtyResult {
+    ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        let mut writer = pty.writer.lock().unwrap_or_else(PoisonError::into_inner);
+        match writer.flush() {
+            Ok(()) => PortablePtyResult::Ok,
+            Err(_) => PortablePtyResult::ErrWrite,
+        }
+    })
+}
+
+/// Promise that only `portable_pty_read` reads the handle, from one thread
+/// at a time, so it can skip the locks that keep readers apart.
+///
+/// For embedders reading heavy output on a dedicated thread, where those
+/// locks show up in profiles. While it's on, nothing else may read the
+/// handle: no `portable_pty_expect`, `portable_pty_run` or other call
+/// that waits for output, and no second thread calling
+/// `portable_pty_read`. Breaking the promise is undefined behavior. Turn
+/// it on before the reading thread starts and off after it stops.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_set_exclusive_reader(
+    handle: *const PortablePty,
+    exclusive: bool,
+) -> PortablePtyResult {
+    ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        pty.exclusive_reader.store(exclusive, Ordering::Relaxed);
+        PortablePtyResult::Ok
+    })
+}
+
+/// Choose whether each `portable_pty_write` is flushed as it's made (the
+/// default).
+///
+/// With auto-flush off, writes collect in the handle's buffer and only go
+/// to the child once it fills or on `portable_pty_flush`, so a paste sent
+/// in chunks goes out in large writes. Input the library writes itself,
+/// such as queued commands and replies to queries, still goes straight
+/// out, after anything buffered. Turning auto-flush back on flushes.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_set_auto_flush(
+    handle: *const PortablePty,
+    enabled: bool,
+) -> PortablePtyResult {
+    ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        pty.auto_flush.store(enabled, Ordering::Relaxed);
+        if enabled {
+            return portable_pty_flush(handle);
+        }
+        PortablePtyResult::Ok
+    })
+}
+
+/// Resize the PTY.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_resize(
+    handle: *mut PortablePty,
+    rows: u16,
+    cols: u16,
+) -> PortablePtyResult {
+    ffi::guard(|| match unsafe { handle.as_ref() } {
+        Some(pty) => pty.resize(rows, cols),
+        None => PortablePtyResult::ErrNull,
+    })
+}
+
+/// Wait until the child's input has room for a write, for callers doing
+/// their own flow control before large writes.
+///
+/// - `timeout_ms`: maximum time to wait; negative waits indefinitely.
+///
+/// Returns `Ok` once a write would make progress, `ErrTimeout` if
+/// `timeout_ms` elapsed first, and `ErrWrite` if the PTY can't be polled.
+/// Only a local Unix PTY fills up; on Windows, and for the other backends,
+/// this returns `Ok` straight away.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_wait_writable(
+    handle: *const PortablePty,
+    timeout_ms: i32,
+) -> PortablePtyResult {
+    ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        let timeout = u64::try_from(timeout_ms).ok().map(Duration::from_millis);
+        match pty.wait_writable(timeout) {
+            Ok(true) => PortablePtyResult::Ok,
+            Ok(false) => PortablePtyResult::ErrTimeout,
+            Err(_) => PortablePtyResult::ErrWrite,
+        }
+    })
+}
+
+/// Get the PTY master side file descriptor.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_master_fd(handle: *mut PortablePty) -> c_int {
+    ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return -1,
+        };
+
+        #[cfg(unix)]
+        {
+            pty.master.as_ref().as_raw_fd().unwrap_or(-1)
+        }
+
+        #[cfg(not(unix))]
+        {
+            let _ = pty;
+            -1
+        }
+    })
+}
+
+/// Get the current PTY size as tracked by the kernel.
+#[unsafe(no_mangle)]
 pub extern "C" fn portable_pty_get_size(
     handle: *mut PortablePty,
     out_rows: *mut u16,
@@ -642,40 +1653,42 @@ pub extern "C" fn portable_pty_get_size(
     out_pixel_width: *mut u16,
     out_pixel_height: *mut u16,
 ) -> PortablePtyResult {
-    let pty = match unsafe { handle.as_mut() } {
-        Some(p) => p,
-        None => return PortablePtyResult::ErrNull,
-    };
+    ffi::guard(|| {
+        let pty = match unsafe { handle.as_mut() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
 
-    if out_rows.is_null()
-        || out_cols.is_null()
-        || out_pixel_width.is_null()
-        || out_pixel_height.is_null()
-    {
-        return PortablePtyResult::ErrNull;
-    }
+        if out_rows.is_null()
+            || out_cols.is_null()
+            || out_pixel_width.is_null()
+            || out_pixel_height.is_null()
+        {
+            return PortablePtyResult::ErrNull;
+        }
 
-    let size = match pty.master.get_size() {
-        Ok(size) => size,
-        Err(_) => return PortablePtyResult::ErrSize,
-    };
+        let size = match pty.master.get_size() {
+            Ok(size) => size,
+            Err(_) => return PortablePtyResult::ErrSize,
+        };
 
-    unsafe {
-        *out_rows = size.rows;
-        *out_cols = size.cols;
-        *out_pixel_width = size.pixel_width;
-        *out_pixel_height = size.pixel_height;
-    }
-    PortablePtyResult::Ok
+        unsafe {
+            *out_rows = size.rows;
+            *out_cols = size.cols;
+            *out_pixel_width = size.pixel_width;
+            *out_pixel_height = size.pixel_height;
+        }
+        PortablePtyResult::Ok
+    })
 }
 
 /// Get the child PID, or -1 if no child has been spawned.
 #[unsafe(no_mangle)]
 pub extern "C" fn portable_pty_child_pid(handle: *const PortablePty) -> i32 {
-    match unsafe { handle.as_ref() } {
+    ffi::guard(|| match unsafe { handle.as_ref() } {
         Some(pty) => pty.child_pid,
         None => -1,
-    }
+    })
 }
 
 /// Non-blocking wait for child exit.
@@ -693,31 +1706,14 @@ pub extern "C" fn portable_pty_wait(
     handle: *mut PortablePty,
     out_status: *mut c_int,
 ) -> PortablePtyResult {
-    let pty = match unsafe { handle.as_mut() } {
-        Some(p) => p,
-        None => return PortablePtyResult::ErrNull,
-    };
-
-    // Return cached exit code if we already detected exit.
-    if let Some(code) = pty.cached_exit_code {
-        if !out_status.is_null() {
-            unsafe {
-                *out_status = code;
-            }
-        }
-        return PortablePtyResult::Ok;
-    }
-
-    if pty.child.is_none() {
-        return PortablePtyResult::ErrWait;
-    }
+    ffi::guard(|| {
+        let pty = match unsafe { handle.as_mut() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
 
-    // Check the SIGCHLD registry — our handler may have already captured
-    // the exit status before the Dart VM's handler could reap the child.
-    #[cfg(unix)]
-    if pty.child_pid > 0 {
-        if let Some(code) = lookup_cached_status(pty.child_pid) {
-            pty.cached_exit_code = Some(code);
+        // Return cached exit code if we already detected exit.
+        if let Some(code) = pty.cached_exit_code {
             if !out_status.is_null() {
                 unsafe {
                     *out_status = code;
@@ -726,125 +1722,129 @@ pub extern "C" fn portable_pty_wait(
             return PortablePtyResult::Ok;
         }
 
-        // Pre-emptive waitpid: try to reap the child directly before
-        // portable-pty's try_wait() which may fail with ECHILD if the
-        // Dart VM's SIGCHLD handler already reaped it.
-        let mut raw_status: c_int = 0;
-        let ret = unsafe { libc::waitpid(pty.child_pid, &mut raw_status, libc::WNOHANG) };
-        if ret == pty.child_pid {
-            let code = if libc::WIFEXITED(raw_status) {
-                libc::WEXITSTATUS(raw_status)
-            } else if libc::WIFSIGNALED(raw_status) {
-                128 + libc::WTERMSIG(raw_status)
-            } else {
-                -1
-            };
-            pty.cached_exit_code = Some(code);
-            if !out_status.is_null() {
-                unsafe {
-                    *out_status = code;
+        if pty.child.is_none() {
+            return PortablePtyResult::ErrWait;
+        }
+
+        // Check the SIGCHLD registry — our handler may have already captured
+        // the exit status before the Dart VM's handler could reap the child.
+        #[cfg(unix)]
+        if pty.child_pid > 0 {
+            if let Some(raw_status) = lookup_cached_status(pty.child_pid) {
+                let code = pty.exited_with(raw_status);
+                if !out_status.is_null() {
+                    unsafe {
+                        *out_status = code;
+                    }
                 }
+                return PortablePtyResult::Ok;
             }
-            return PortablePtyResult::Ok;
-        }
-        if ret == -1 {
-            // Already reaped by another thread
-        }
-        // ret == 0: still running, proceed to try_wait
-        // ret == -1: already reaped, proceed to try_wait (will get ECHILD)
-    }
 
-    // Try the upstream `try_wait()` first — works when the Dart VM hasn't
-    // reaped the child yet.
-    let child = pty.child.as_mut().unwrap();
-    match child.try_wait() {
-        Ok(Some(status)) => {
-            let code: c_int = status.exit_code().try_into().unwrap_or(-1);
-            pty.cached_exit_code = Some(code);
-            if !out_status.is_null() {
-                unsafe {
-                    *out_status = code;
+            // Pre-emptive waitpid: try to reap the child directly before
+            // portable-pty's try_wait() which may fail with ECHILD if the
+            // Dart VM's SIGCHLD handler already reaped it.
+            let mut raw_status: c_int = 0;
+            let ret = unsafe { libc::waitpid(pty.child_pid, &mut raw_status, libc::WNOHANG) };
+            if ret == pty.child_pid {
+                let code = pty.exited_with(raw_status);
+                if !out_status.is_null() {
+                    unsafe {
+                        *out_status = code;
+                    }
                 }
+                return PortablePtyResult::Ok;
             }
-            return PortablePtyResult::Ok;
-        }
-        Ok(None) => {
-            // Child is genuinely still running.
-            return PortablePtyResult::ErrWait;
-        }
-        Err(_) => {
-            // Likely ECHILD — Dart VM already reaped the child.
-            // Fall through to manual detection below.
+            if ret == 0 {
+                // Still running. Not asking upstream: if the child exits in
+                // between, `try_wait()` would report a signal as exit code 1.
+                return PortablePtyResult::ErrWait;
+            }
+            // ret == -1: already reaped, proceed to try_wait (will get ECHILD)
         }
-    }
 
-    // --- Fallback: manual detection for already-reaped children ---
-    #[cfg(unix)]
-    {
-        let pid = pty.child_pid;
-        if pid <= 0 {
-            return PortablePtyResult::ErrWait;
+        // Try the upstream `try_wait()` first — works when the Dart VM hasn't
+        // reaped the child yet.
+        let child = pty.child.as_mut().unwrap();
+        match child.try_wait() {
+            Ok(Some(status)) => {
+                let code = pty.exited_with_upstream(&status);
+                if !out_status.is_null() {
+                    unsafe {
+                        *out_status = code;
+                    }
+                }
+                return PortablePtyResult::Ok;
+            }
+            Ok(None) => {
+                // Child is genuinely still running.
+                return PortablePtyResult::ErrWait;
+            }
+            Err(_) => {
+                // Likely ECHILD — Dart VM already reaped the child.
+                // Fall through to manual detection below.
+            }
         }
 
-        // Try waitpid directly — might succeed if there's still a zombie.
-        let mut status: c_int = 0;
-        let ret = unsafe { libc::waitpid(pid, &mut status, libc::WNOHANG) };
-        if ret == pid {
-            // We managed to reap it ourselves.
-            let code = if libc::WIFEXITED(status) {
-                libc::WEXITSTATUS(status)
-            } else if libc::WIFSIGNALED(status) {
-                // Convention: 128 + signal number
-                128 + libc::WTERMSIG(status)
-            } else {
-                -1
-            };
-            pty.cached_exit_code = Some(code);
-            if !out_status.is_null() {
-                unsafe {
-                    *out_status = code;
+        // --- Fallback: manual detection for already-reaped children ---
+        #[cfg(unix)]
+        {
+            let pid = pty.child_pid;
+            if pid <= 0 {
+                return PortablePtyResult::ErrWait;
+            }
+
+            // Try waitpid directly — might succeed if there's still a zombie.
+            let mut status: c_int = 0;
+            let ret = unsafe { libc::waitpid(pid, &mut status, libc::WNOHANG) };
+            if ret == pid {
+                // We managed to reap it ourselves.
+                let code = pty.exited_with(status);
+                if !out_status.is_null() {
+                    unsafe {
+                        *out_status = code;
+                    }
                 }
+                return PortablePtyResult::Ok;
+            } else if ret == 0 {
+                // waitpid returned 0 with WNOHANG — child is still running.
+                return PortablePtyResult::ErrWait;
             }
-            return PortablePtyResult::Ok;
-        } else if ret == 0 {
-            // waitpid returned 0 with WNOHANG — child is still running.
-            return PortablePtyResult::ErrWait;
-        }
-        // ret == -1: waitpid failed (ECHILD = already reaped by someone else).
-        // Re-check the SIGCHLD registry — our handler may have reaped the
-        // child between the initial registry check and now.
-        if let Some(code) = lookup_cached_status(pid) {
-            pty.cached_exit_code = Some(code);
-            if !out_status.is_null() {
-                unsafe {
-                    *out_status = code;
+            // ret == -1: waitpid failed (ECHILD = already reaped by someone else).
+            // Re-check the SIGCHLD registry — our handler may have reaped the
+            // child between the initial registry check and now.
+            if let Some(raw_status) = reaped_status(pid) {
+                let code = pty.exited_with(raw_status);
+                if !out_status.is_null() {
+                    unsafe {
+                        *out_status = code;
+                    }
                 }
+                return PortablePtyResult::Ok;
             }
-            return PortablePtyResult::Ok;
-        }
-        // Check if the process still exists.
-        let kill_ret = unsafe { libc::kill(pid, 0) };
-        if kill_ret == -1 && get_errno() == libc::ESRCH {
-            // Process doesn't exist — it exited and was reaped but our
-            // handler didn't capture it. Report 0 as fallback.
-            let code = 0;
-            pty.cached_exit_code = Some(code);
-            if !out_status.is_null() {
-                unsafe {
-                    *out_status = code;
+            // Check if the process still exists.
+            let kill_ret = unsafe { libc::kill(pid, 0) };
+            if kill_ret == -1 && get_errno() == libc::ESRCH {
+                // Process doesn't exist — it exited and was reaped but our
+                // handler didn't capture it. Report 0 as fallback.
+                let code = 0;
+                pty.cached_exit_code = Some(code);
+                if !out_status.is_null() {
+                    unsafe {
+                        *out_status = code;
+                    }
                 }
+                return PortablePtyResult::Ok;
             }
-            return PortablePtyResult::Ok;
+            // Process exists but we can't wait on it (shouldn't happen, but be safe).
+            PortablePtyResult::ErrWait
         }
-        // Process exists but we can't wait on it (shouldn't happen, but be safe).
-        return PortablePtyResult::ErrWait;
-    }
 
-    #[cfg(not(unix))]
-    {
-        // On non-POSIX platforms we have no fallback.
-        return PortablePtyResult::ErrWait;
-    }
+        #[cfg(not(unix))]
+        {
+            // On non-POSIX platforms we have no fallback.
+            PortablePtyResult::ErrWait
+        }
+    })
 }
 
 /// Block until the child exits and return its exit code.
@@ -856,30 +1856,14 @@ pub extern "C" fn portable_pty_wait_blocking(
     handle: *mut PortablePty,
     out_status: *mut c_int,
 ) -> PortablePtyResult {
-    let pty = match unsafe { handle.as_mut() } {
-        Some(p) => p,
-        None => return PortablePtyResult::ErrNull,
-    };
-
-    // Return cached exit code if we already detected exit.
-    if let Some(code) = pty.cached_exit_code {
-        if !out_status.is_null() {
-            unsafe {
-                *out_status = code;
-            }
-        }
-        return PortablePtyResult::Ok;
-    }
-
-    if pty.child.is_none() {
-        return PortablePtyResult::ErrWait;
-    }
+    ffi::guard(|| {
+        let pty = match unsafe { handle.as_mut() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
 
-    // Check the SIGCHLD registry first.
-    #[cfg(unix)]
-    if pty.child_pid > 0 {
-        if let Some(code) = lookup_cached_status(pty.child_pid) {
-            pty.cached_exit_code = Some(code);
+        // Return cached exit code if we already detected exit.
+        if let Some(code) = pty.cached_exit_code {
             if !out_status.is_null() {
                 unsafe {
                     *out_status = code;
@@ -887,164 +1871,357 @@ pub extern "C" fn portable_pty_wait_blocking(
             }
             return PortablePtyResult::Ok;
         }
-    }
 
-    // Try the upstream blocking `wait()` first.
-    let child = pty.child.as_mut().unwrap();
-    match child.wait() {
-        Ok(status) => {
-            let code: c_int = status.exit_code().try_into().unwrap_or(-1);
-            pty.cached_exit_code = Some(code);
-            if !out_status.is_null() {
-                unsafe {
-                    *out_status = code;
+        if pty.child.is_none() {
+            return PortablePtyResult::ErrWait;
+        }
+
+        // Check the SIGCHLD registry first.
+        #[cfg(unix)]
+        if pty.child_pid > 0 {
+            if let Some(raw_status) = lookup_cached_status(pty.child_pid) {
+                let code = pty.exited_with(raw_status);
+                if !out_status.is_null() {
+                    unsafe {
+                        *out_status = code;
+                    }
+                }
+                return PortablePtyResult::Ok;
+            }
+        }
+
+        // Stand-in children (and every child off Unix) use the upstream
+        // `wait()`. Real Unix children are waited for below instead: upstream
+        // reports a signalled child as exit code 1, losing the signal number.
+        if !cfg!(unix) || pty.child_pid <= 0 {
+            let child = pty.child.as_mut().unwrap();
+            match child.wait() {
+                Ok(status) => {
+                    let code = pty.exited_with_upstream(&status);
+                    if !out_status.is_null() {
+                        unsafe {
+                            *out_status = code;
+                        }
+                    }
+                    return PortablePtyResult::Ok;
+                }
+                Err(_) => {
+                    // Likely ECHILD — fall through to manual detection.
+                }
+            }
+        }
+
+        // --- Fallback: manual detection for already-reaped children ---
+        #[cfg(unix)]
+        {
+            let pid = pty.child_pid;
+            if pid <= 0 {
+                return PortablePtyResult::ErrWaitBlocking;
+            }
+
+            // Try waitpid (blocking) — will fail immediately with ECHILD if already reaped.
+            let mut status: c_int = 0;
+            let ret = unsafe { libc::waitpid(pid, &mut status, 0) };
+            if ret == pid {
+                let code = pty.exited_with(status);
+                if !out_status.is_null() {
+                    unsafe {
+                        *out_status = code;
+                    }
+                }
+                return PortablePtyResult::Ok;
+            }
+            // ret == -1 (ECHILD): already reaped. Re-check registry.
+            if let Some(raw_status) = reaped_status(pid) {
+                let code = pty.exited_with(raw_status);
+                if !out_status.is_null() {
+                    unsafe {
+                        *out_status = code;
+                    }
+                }
+                return PortablePtyResult::Ok;
+            }
+            // Check if process is gone.
+            let kill_ret = unsafe { libc::kill(pid, 0) };
+            if kill_ret == -1 && get_errno() == libc::ESRCH {
+                let code = 0;
+                pty.cached_exit_code = Some(code);
+                if !out_status.is_null() {
+                    unsafe {
+                        *out_status = code;
+                    }
                 }
+                return PortablePtyResult::Ok;
             }
-            return PortablePtyResult::Ok;
+            PortablePtyResult::ErrWaitBlocking
         }
-        Err(_) => {
-            // Likely ECHILD — fall through to manual detection.
+
+        #[cfg(not(unix))]
+        {
+            PortablePtyResult::ErrWaitBlocking
         }
-    }
+    })
+}
 
-    // --- Fallback: manual detection for already-reaped children ---
-    #[cfg(unix)]
-    {
-        let pid = pty.child_pid;
-        if pid <= 0 {
-            return PortablePtyResult::ErrWaitBlocking;
+/// Block until the child exits, then take everything it left unread.
+///
+/// Waiting and reading separately races: the exit can be seen before the
+/// last of the output has been read. This returns only once that output
+/// is in hand, including anything an earlier expect or query held back.
+/// Like anything read, it's seen by the handle's pattern matchers,
+/// recording and clients, so with `out_output` NULL it still reaches those
+/// and is otherwise dropped.
+///
+/// - `out_status`: receives the exit code, as for
+///   `portable_pty_wait_blocking`.
+/// - `out_output`: receives the remaining output; release with
+///   `portable_pty_buffer_free`. May be NULL.
+///
+/// On Unix, reading stops at end of file under the EOF policy or once no
+/// more output arrives for a moment, so descendants that keep the terminal
+/// busy after the child exits keep this reading too. The ConPTY's pipe
+/// can't be polled, so off Unix only output the library already holds is
+/// returned.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_wait_drained(
+    handle: *mut PortablePty,
+    out_status: *mut c_int,
+    out_output: *mut PortablePtyBuffer,
+) -> PortablePtyResult {
+    ffi::guard(|| {
+        let result = portable_pty_wait_blocking(handle, out_status);
+        if !matches!(result, PortablePtyResult::Ok) {
+            return result;
         }
+        let pty = unsafe { &*handle };
 
-        // Try waitpid (blocking) — will fail immediately with ECHILD if already reaped.
-        let mut status: c_int = 0;
-        let ret = unsafe { libc::waitpid(pid, &mut status, 0) };
-        if ret == pid {
-            let code = if libc::WIFEXITED(status) {
-                libc::WEXITSTATUS(status)
-            } else if libc::WIFSIGNALED(status) {
-                128 + libc::WTERMSIG(status)
-            } else {
-                -1
-            };
-            pty.cached_exit_code = Some(code);
-            if !out_status.is_null() {
-                unsafe {
-                    *out_status = code;
-                }
+        let mut output =
+            std::mem::take(&mut *pty.pending.lock().unwrap_or_else(PoisonError::into_inner));
+        let mut chunk = [0u8; 4096];
+        loop {
+            #[cfg(unix)]
+            let more = matches!(pty.wait_readable(Some(DRAIN_GRACE)), Ok(true));
+            #[cfg(not(unix))]
+            let more = pty.output_filter.has_buffered();
+            if !more {
+                break;
             }
-            return PortablePtyResult::Ok;
-        }
-        // ret == -1 (ECHILD): already reaped. Re-check registry.
-        if let Some(code) = lookup_cached_status(pid) {
-            pty.cached_exit_code = Some(code);
-            if !out_status.is_null() {
-                unsafe {
-                    *out_status = code;
-                }
+            match pty.read_master(&mut chunk) {
+                Ok(n) if n > 0 => output.extend_from_slice(&chunk[..n]),
+                _ => break,
             }
-            return PortablePtyResult::Ok;
         }
-        // Check if process is gone.
-        let kill_ret = unsafe { libc::kill(pid, 0) };
-        if kill_ret == -1 && get_errno() == libc::ESRCH {
-            let code = 0;
-            pty.cached_exit_code = Some(code);
-            if !out_status.is_null() {
-                unsafe {
-                    *out_status = code;
-                }
+
+        if !out_output.is_null() {
+            unsafe {
+                *out_output = PortablePtyBuffer::from_vec(output);
             }
-            return PortablePtyResult::Ok;
         }
-        return PortablePtyResult::ErrWaitBlocking;
-    }
+        PortablePtyResult::Ok
+    })
+}
 
-    #[cfg(not(unix))]
-    {
-        return PortablePtyResult::ErrWaitBlocking;
-    }
+/// Get the status the child's exit code was taken from, for callers that
+/// decode it themselves.
+///
+/// The wait functions report a child killed by a signal as 128 plus the
+/// signal, and a Windows exit code that doesn't fit a C int as -1. This is
+/// what they started from: on Unix the `waitpid` status word, to take
+/// apart with `WIFSIGNALED`, `WCOREDUMP` and the rest; on Windows the whole
+/// exit code, as unsigned bits.
+///
+/// - `out_raw`: receives the status.
+///
+/// Returns `ErrWait` until a wait has seen the child exit, and
+/// `ErrUnsupported` if there's no status to give: the child was reaped
+/// elsewhere without its status being seen, or isn't a local process.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_raw_status(
+    handle: *const PortablePty,
+    out_raw: *mut c_int,
+) -> PortablePtyResult {
+    ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        if out_raw.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+        match (pty.cached_exit_code, pty.cached_raw_status) {
+            (_, Some(raw)) => {
+                unsafe { *out_raw = raw };
+                PortablePtyResult::Ok
+            }
+            (Some(_), None) => PortablePtyResult::ErrUnsupported,
+            (None, None) => PortablePtyResult::ErrWait,
+        }
+    })
 }
 
 /// Kill the child process.
 ///
 /// On POSIX, `signal` is the signal number (e.g. 15 for SIGTERM).
-/// On Windows, `signal` is ignored — the process is terminated.
+/// On Windows, `signal` is ignored — the process is terminated with exit
+/// code 1 (`portable_pty_kill_with_exit_code` chooses another).
 ///
 /// If the child has already exited (or been reaped), returns `Ok` rather
 /// than failing.
 #[unsafe(no_mangle)]
 pub extern "C" fn portable_pty_kill(handle: *mut PortablePty, signal: c_int) -> PortablePtyResult {
-    let pty = match unsafe { handle.as_mut() } {
-        Some(p) => p,
-        None => return PortablePtyResult::ErrNull,
-    };
-
-    // If we already know the child exited, killing is a no-op.
-    if pty.cached_exit_code.is_some() {
-        return PortablePtyResult::Ok;
-    }
+    ffi::guard(|| {
+        let pty = match unsafe { handle.as_mut() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
 
-    // Check the SIGCHLD registry — child may have exited already.
-    #[cfg(unix)]
-    if pty.child_pid > 0 {
-        if let Some(code) = lookup_cached_status(pty.child_pid) {
-            pty.cached_exit_code = Some(code);
+        // If we already know the child exited, killing is a no-op.
+        if pty.cached_exit_code.is_some() {
             return PortablePtyResult::Ok;
         }
-    }
 
-    if pty.child.is_none() {
-        return PortablePtyResult::ErrKill;
-    }
+        // Check the SIGCHLD registry — child may have exited already.
+        #[cfg(unix)]
+        if pty.child_pid > 0 {
+            if let Some(raw_status) = lookup_cached_status(pty.child_pid) {
+                pty.exited_with(raw_status);
+                return PortablePtyResult::Ok;
+            }
+        }
 
-    #[cfg(unix)]
-    {
-        let pid = pty.child_pid;
-        if pid <= 0 {
+        if pty.child.is_none() {
             return PortablePtyResult::ErrKill;
         }
 
-        let ret = unsafe { libc::kill(pid, signal) };
-        if ret == 0 {
-            return PortablePtyResult::Ok;
+        #[cfg(unix)]
+        {
+            let pid = pty.child_pid;
+            if pid <= 0 {
+                // No process behind the handle (replay, mock): stop the
+                // stand-in child instead.
+                return match pty.child.as_mut().unwrap().kill() {
+                    Ok(()) => PortablePtyResult::Ok,
+                    Err(_) => PortablePtyResult::ErrKill,
+                };
+            }
+
+            let ret = unsafe { libc::kill(pid, signal) };
+            if ret == 0 {
+                return PortablePtyResult::Ok;
+            }
+            // kill failed — check if the process is already dead (ESRCH).
+            if get_errno() == libc::ESRCH {
+                // Process already exited — treat as success.
+                return PortablePtyResult::Ok;
+            }
+            PortablePtyResult::ErrKill
         }
-        // kill failed — check if the process is already dead (ESRCH).
-        if get_errno() == libc::ESRCH {
-            // Process already exited — treat as success.
-            return PortablePtyResult::Ok;
+
+        #[cfg(not(unix))]
+        {
+            // On Windows, fall back to the upstream `child.kill()` which calls
+            // TerminateProcess; there's no signal to send.
+            let _ = signal;
+            let child = pty.child.as_mut().unwrap();
+            match child.kill() {
+                Ok(()) => PortablePtyResult::Ok,
+                Err(_) => PortablePtyResult::ErrKill,
+            }
         }
-        return PortablePtyResult::ErrKill;
+    })
+}
+
+/// Terminate the process `pid` with `exit_code`.
+#[cfg(windows)]
+fn terminate(pid: i32, exit_code: u32) -> io::Result<()> {
+    use std::os::windows::io::{AsRawHandle, FromRawHandle, OwnedHandle};
+    use winapi::um::processthreadsapi::{OpenProcess, TerminateProcess};
+    use winapi::um::synchapi::WaitForSingleObject;
+    use winapi::um::winbase::WAIT_OBJECT_0;
+    use winapi::um::winnt::{PROCESS_TERMINATE, SYNCHRONIZE};
+
+    let process = unsafe { OpenProcess(PROCESS_TERMINATE | SYNCHRONIZE, 0, pid as u32) };
+    if process.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    let process = unsafe { OwnedHandle::from_raw_handle(process as _) };
+    let raw = process.as_raw_handle() as _;
+    if unsafe { TerminateProcess(raw, exit_code) } != 0 {
+        return Ok(());
     }
+    let err = io::Error::last_os_error();
+    // Access is denied to terminating a process that has already exited.
+    match unsafe { WaitForSingleObject(raw, 0) } == WAIT_OBJECT_0 {
+        true => Ok(()),
+        false => Err(err),
+    }
+}
 
-    #[cfg(not(unix))]
-    {
-        // On Windows, fall back to the upstream `child.kill()` which calls
-        // TerminateProcess.
-        let child = pty.child.as_mut().unwrap();
-        match child.kill() {
-            Ok(()) => PortablePtyResult::Ok,
-            Err(_) => PortablePtyResult::ErrKill,
+/// Kill the child with `TerminateProcess`, making `exit_code` its exit
+/// code, which the wait functions then report. A supervisor can pick a
+/// code of its own to tell a child it killed from one that crashed;
+/// `portable_pty_kill` always uses 1.
+///
+/// Windows only: a child killed by a signal on Unix already reports the
+/// signal. Returns `ErrUnsupported` elsewhere and for children that aren't
+/// local processes, and `Ok` if the child has already exited, whose exit
+/// code then stays as it was.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_kill_with_exit_code(
+    handle: *mut PortablePty,
+    exit_code: u32,
+) -> PortablePtyResult {
+    ffi::guard(|| {
+        let pty = match unsafe { handle.as_mut() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        if pty.cached_exit_code.is_some() {
+            return PortablePtyResult::Ok;
         }
-    }
+        if pty.child.is_none() {
+            return PortablePtyResult::ErrKill;
+        }
+
+        #[cfg(windows)]
+        {
+            if pty.child_pid <= 0 {
+                return PortablePtyResult::ErrUnsupported;
+            }
+            match terminate(pty.child_pid, exit_code) {
+                Ok(()) => PortablePtyResult::Ok,
+                Err(_) => PortablePtyResult::ErrKill,
+            }
+        }
+
+        #[cfg(not(windows))]
+        {
+            let _ = exit_code;
+            PortablePtyResult::ErrUnsupported
+        }
+    })
 }
 
 /// Return the master process group ID (POSIX) or -1 when unsupported.
 #[unsafe(no_mangle)]
 pub extern "C" fn portable_pty_process_group_leader(handle: *const PortablePty) -> c_int {
-    let pty = match unsafe { handle.as_ref() } {
-        Some(p) => p,
-        None => return -1,
-    };
+    ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return -1,
+        };
 
-    #[cfg(unix)]
-    {
-        pty.master.process_group_leader().unwrap_or(-1)
-    }
+        #[cfg(unix)]
+        {
+            pty.master.process_group_leader().unwrap_or(-1)
+        }
 
-    #[cfg(not(unix))]
-    {
-        -1
-    }
+        #[cfg(not(unix))]
+        {
+            let _ = pty;
+            -1
+        }
+    })
 }
 
 /// Get the current terminal mode flags.
@@ -1057,57 +2234,86 @@ pub extern "C" fn portable_pty_get_mode(
     out_canonical: *mut bool,
     out_echo: *mut bool,
 ) -> PortablePtyResult {
-    let pty = match unsafe { handle.as_mut() } {
-        Some(p) => p,
-        None => return PortablePtyResult::ErrNull,
-    };
-    if out_canonical.is_null() || out_echo.is_null() {
-        return PortablePtyResult::ErrNull;
-    }
-
-    #[cfg(unix)]
-    {
-        let fd = match pty.master.as_ref().as_raw_fd() {
-            Some(fd) => fd,
-            None => return PortablePtyResult::ErrMode,
+    ffi::guard(|| {
+        let pty = match unsafe { handle.as_mut() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
         };
-
-        let mut termios = std::mem::MaybeUninit::<libc::termios>::uninit();
-        if unsafe { libc::tcgetattr(fd, termios.as_mut_ptr()) } != 0 {
-            return PortablePtyResult::ErrMode;
+        if out_canonical.is_null() || out_echo.is_null() {
+            return PortablePtyResult::ErrNull;
         }
-        let termios = unsafe { termios.assume_init() };
-        let flags = termios.c_lflag;
 
-        unsafe {
-            *out_canonical = (flags & libc::ICANON) != 0;
-            *out_echo = (flags & libc::ECHO) != 0;
-        }
+        #[cfg(unix)]
+        {
+            let fd = match pty.master.as_ref().as_raw_fd() {
+                Some(fd) => fd,
+                None => return PortablePtyResult::ErrMode,
+            };
 
-        PortablePtyResult::Ok
-    }
+            let mut termios = std::mem::MaybeUninit::<libc::termios>::uninit();
+            if unsafe { libc::tcgetattr(fd, termios.as_mut_ptr()) } != 0 {
+                return PortablePtyResult::ErrMode;
+            }
+            let termios = unsafe { termios.assume_init() };
+            let flags = termios.c_lflag;
 
-    #[cfg(not(unix))]
-    {
-        PortablePtyResult::ErrMode
-    }
+            unsafe {
+                *out_canonical = (flags & libc::ICANON) != 0;
+                *out_echo = (flags & libc::ECHO) != 0;
+            }
+
+            PortablePtyResult::Ok
+        }
+
+        #[cfg(not(unix))]
+        {
+            let _ = pty;
+            PortablePtyResult::ErrMode
+        }
+    })
 }
 
 /// Close the PTY and free all resources.
 ///
-/// Kills the child process if still running. Safe to call with NULL.
+/// Kills the child process if still running. Safe to call with NULL or
+/// with a handle that is already closed.
 /// Handles the case where the child was already reaped by the Dart VM.
+///
+/// On Windows this waits (up to five seconds) for the console's last
+/// output to be read off and the console to shut down; see
+/// `portable_pty_close_async` to do that without blocking.
 #[unsafe(no_mangle)]
 pub extern "C" fn portable_pty_close(handle: *mut PortablePty) {
-    if handle.is_null() {
-        return;
-    }
+    ffi::guard(|| {
+        if handle.is_null() {
+            return;
+        }
+        if let Some(pty) = lifecycle::take(handle) {
+            destroy(pty);
+        }
+    })
+}
+
+/// How long closing a Windows console waits for its output to end.
+const CONSOLE_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
 
-    let mut pty = unsafe { Box::from_raw(handle) };
+/// Kill the handle's child (if still running) and free the handle.
+///
+/// Returns `ErrTimeout` if a console's output didn't end in time; the
+/// handle is freed regardless.
+fn destroy(mut pty: Box<PortablePty>) -> PortablePtyResult {
+    // Their client threads use the handle; they must be done with it first.
+    pump::stop(&pty);
+    serve::stop(&pty);
+    control::unpublish(&pty);
+    monitor::stop(&pty);
+    idle::stop(&pty);
+    coalesce::stop(&pty);
 
     // Unregister from the SIGCHLD registry before cleanup.
     #[cfg(unix)]
     if pty.child_pid > 0 {
+        jobs::unwatch(pty.child_pid);
         unregister_pid(pty.child_pid);
     }
 
@@ -1130,7 +2336,59 @@ pub extern "C" fn portable_pty_close(handle: *mut PortablePty) {
         }
     }
 
-    // pty is dropped here, closing file descriptors
+    release(pty, cfg!(windows))
+}
+
+/// Free the terminal behind a handle whose child is gone.
+///
+/// Closing a pseudo console flushes its last frame into the output pipe
+/// and, before Windows 11, blocks until that's been read; closing the
+/// output pipe first loses the frame, and closing the console with a read
+/// pending on the same pipe can deadlock. With `drain`, the documented
+/// order is kept: input closed, then the console closed while another
+/// thread reads the output to its end, then the output closed. Elsewhere
+/// closing the master never waits on readers, so everything is simply
+/// dropped.
+fn release(pty: Box<PortablePty>, drain: bool) -> PortablePtyResult {
+    // The writer sends the child EOF as it's dropped, which would block for
+    // good on input that's full and that nothing reads any more.
+    #[cfg(unix)]
+    if let Some(fd) = pty.master.as_raw_fd() {
+        unsafe {
+            let flags = libc::fcntl(fd, libc::F_GETFL);
+            libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK);
+        }
+    }
+    if !drain {
+        return PortablePtyResult::Ok;
+    }
+    let PortablePty {
+        master,
+        slave,
+        reader,
+        writer,
+        ..
+    } = *pty;
+    drop(writer);
+
+    let mut reader = reader.into_inner().unwrap_or_else(|e| e.into_inner());
+    let (done, ended) = std::sync::mpsc::channel();
+    let drained = std::thread::Builder::new()
+        .name("portable-pty-drain".into())
+        .spawn(move || {
+            let mut buf = [0u8; 4096];
+            while matches!(reader.read(&mut buf), Ok(n) if n > 0) {}
+            let _ = done.send(());
+        });
+
+    drop(slave);
+    drop(master);
+    match drained {
+        Ok(_) if ended.recv_timeout(CONSOLE_DRAIN_TIMEOUT).is_err() => {
+            PortablePtyResult::ErrTimeout
+        }
+        _ => PortablePtyResult::Ok,
+    }
 }
 
 // ---------------------------------------------------------------------------
@@ -1138,10 +2396,39 @@ pub extern "C" fn portable_pty_close(handle: *mut PortablePty) {
 // ---------------------------------------------------------------------------
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     use std::ptr;
 
+    /// Open a 24x80 PTY and spawn `cmd` with `argv`, panicking on failure.
+    pub(crate) fn open_and_spawn(cmd: &str, argv: &[&str]) -> *mut PortablePty {
+        use std::ffi::CString;
+
+        let mut handle: *mut PortablePty = ptr::null_mut();
+        let result = portable_pty_open(24, 80, &mut handle);
+        assert!(matches!(result, PortablePtyResult::Ok));
+
+        let cmd = CString::new(cmd).unwrap();
+        let args: Vec<CString> = argv.iter().map(|a| CString::new(*a).unwrap()).collect();
+        let mut ptrs: Vec<*const c_char> = args.iter().map(|a| a.as_ptr()).collect();
+        ptrs.push(ptr::null());
+        let result = portable_pty_spawn(handle, cmd.as_ptr(), ptrs.as_ptr(), ptr::null());
+        assert!(
+            matches!(result, PortablePtyResult::Ok),
+            "portable_pty_spawn returned: {}",
+            result as u32,
+        );
+        handle
+    }
+
+    /// One `portable_pty_read`, lossily decoded.
+    pub(crate) fn read_string(handle: *mut PortablePty) -> String {
+        let mut buf = [0u8; 4096];
+        let n = portable_pty_read(handle, buf.as_mut_ptr(), buf.len());
+        assert!(n >= 0, "portable_pty_read failed");
+        String::from_utf8_lossy(&buf[..n as usize]).into_owned()
+    }
+
     #[test]
     fn test_open_and_close() {
         let mut handle: *mut PortablePty = ptr::null_mut();
@@ -1155,6 +2442,65 @@ mod tests {
         portable_pty_close(handle);
     }
 
+    #[cfg(unix)]
+    #[test]
+    fn test_wait_drained_keeps_last_output() {
+        let script = "i=0; while [ $i -lt 200 ]; do echo line$i; i=$((i+1)); done; exit 3";
+        let handle = open_and_spawn("sh", &["sh", "-c", script]);
+        let mut status = 0;
+        let mut output = PortablePtyBuffer::EMPTY;
+        let result = portable_pty_wait_drained(handle, &mut status, &mut output);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        assert_eq!(status, 3);
+        let bytes = unsafe { std::slice::from_raw_parts(output.data, output.len) };
+        let text = String::from_utf8_lossy(bytes).into_owned();
+        portable_pty_buffer_free(output);
+        assert!(text.starts_with("line0\r\n"), "{text:?}");
+        assert!(text.ends_with("line199\r\n"), "{text:?}");
+
+        // Everything was taken; reads are at the end.
+        assert_eq!(read_string(handle), "");
+        portable_pty_close(handle);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_raw_status_keeps_the_signal() {
+        let handle = open_and_spawn("sh", &["sh", "-c", "kill -TERM $$"]);
+        let mut raw = 0;
+        let result = portable_pty_raw_status(handle, &mut raw);
+        assert!(matches!(result, PortablePtyResult::ErrWait));
+
+        let mut status = 0;
+        portable_pty_wait_blocking(handle, &mut status);
+        assert_eq!(status, 128 + libc::SIGTERM);
+        let result = portable_pty_raw_status(handle, &mut raw);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        assert!(libc::WIFSIGNALED(raw));
+        assert_eq!(libc::WTERMSIG(raw), libc::SIGTERM);
+        portable_pty_close(handle);
+    }
+
+    #[test]
+    fn test_kill_with_exit_code() {
+        let argv: &[&str] = if cfg!(windows) {
+            &["cmd.exe", "/c", "pause"]
+        } else {
+            &["sleep", "5"]
+        };
+        let handle = open_and_spawn(argv[0], argv);
+        let result = portable_pty_kill_with_exit_code(handle, 42);
+        if cfg!(windows) {
+            assert!(matches!(result, PortablePtyResult::Ok));
+            let mut status = 0;
+            portable_pty_wait_blocking(handle, &mut status);
+            assert_eq!(status, 42);
+        } else {
+            assert!(matches!(result, PortablePtyResult::ErrUnsupported));
+        }
+        portable_pty_close(handle);
+    }
+
     #[test]
     fn test_null_handle() {
         let result = portable_pty_open(24, 80, ptr::null_mut());
@@ -1206,4 +2552,232 @@ mod tests {
 
         portable_pty_close(handle);
     }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_spawn_passes_non_utf8_bytes() {
+        let script = c"printf '%s %s' \"$1\" \"$LATIN1\" | od -An -tx1";
+        let name = c"caf\xe9";
+        let argv = [
+            c"sh".as_ptr(),
+            c"-c".as_ptr(),
+            script.as_ptr(),
+            c"sh".as_ptr(),
+            name.as_ptr(),
+            ptr::null(),
+        ];
+        let envp = [
+            c"LATIN1=\xff".as_ptr(),
+            c"PATH=/bin:/usr/bin".as_ptr(),
+            ptr::null(),
+        ];
+
+        let mut handle: *mut PortablePty = ptr::null_mut();
+        portable_pty_open(24, 80, &mut handle);
+        let result = portable_pty_spawn(handle, c"sh".as_ptr(), argv.as_ptr(), envp.as_ptr());
+        assert!(matches!(result, PortablePtyResult::Ok));
+        let output = read_string(handle);
+        assert!(output.contains("63 61 66 e9 20 ff"), "{output:?}");
+        portable_pty_close(handle);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_wait_writable_until_input_fills() {
+        // Raw, so input that doesn't fit isn't dropped, as it is a line.
+        let script = "stty raw -echo; echo ready; sleep 5";
+        let handle = open_and_spawn("sh", &["sh", "-c", script]);
+        assert!(read_string(handle).contains("ready"));
+        assert!(matches!(
+            portable_pty_wait_writable(handle, 0),
+            PortablePtyResult::Ok
+        ));
+
+        // Fill the child's input, which nothing reads.
+        let fd = portable_pty_master_fd(handle);
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) };
+        let chunk = [b'x'; 1024];
+        while unsafe { libc::write(fd, chunk.as_ptr().cast(), chunk.len()) } > 0 {}
+        assert_eq!(get_errno(), libc::EAGAIN);
+        unsafe { libc::fcntl(fd, libc::F_SETFL, flags) };
+
+        assert!(matches!(
+            portable_pty_wait_writable(handle, 100),
+            PortablePtyResult::ErrTimeout
+        ));
+        portable_pty_close(handle);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_writes_wait_for_flush_without_auto_flush() {
+        let handle = open_and_spawn("sh", &["sh", "-c", "read line; echo \"got $line\""]);
+        assert!(matches!(
+            portable_pty_set_auto_flush(handle, false),
+            PortablePtyResult::Ok
+        ));
+        for chunk in [&b"bat"[..], b"ched\r"] {
+            assert_eq!(
+                portable_pty_write(handle, chunk.as_ptr(), chunk.len()),
+                chunk.len() as i64
+            );
+        }
+        // Not even echoed yet.
+        let pty = unsafe { &*handle };
+        assert!(!pty.wait_readable(Some(Duration::from_millis(200))).unwrap());
+
+        assert!(matches!(portable_pty_flush(handle), PortablePtyResult::Ok));
+        let mut output = String::new();
+        while !output.contains("got batched") {
+            output += &read_string(handle);
+        }
+        portable_pty_close(handle);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_exclusive_reader_reads_to_end() {
+        let handle = open_and_spawn("/bin/echo", &["echo", "fast path"]);
+        assert!(matches!(
+            portable_pty_set_exclusive_reader(handle, true),
+            PortablePtyResult::Ok
+        ));
+        unsafe { &*handle }
+            .pending
+            .lock()
+            .unwrap()
+            .extend_from_slice(b"held ");
+        let mut output = String::new();
+        loop {
+            let chunk = read_string(handle);
+            if chunk.is_empty() {
+                break;
+            }
+            output += &chunk;
+        }
+        assert!(output.starts_with("held "), "{output:?}");
+        assert!(output.contains("fast path"), "{output:?}");
+        portable_pty_close(handle);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_siginfo_matches_waitpid_layout() {
+        let exited = siginfo_wait_status(libc::CLD_EXITED, 3).unwrap();
+        assert_eq!(wait_status_code(exited), 3);
+        let killed = siginfo_wait_status(libc::CLD_KILLED, libc::SIGKILL).unwrap();
+        assert_eq!(wait_status_code(killed), 128 + libc::SIGKILL);
+        let dumped = siginfo_wait_status(libc::CLD_DUMPED, libc::SIGSEGV).unwrap();
+        assert!(libc::WCOREDUMP(dumped));
+        assert_eq!(wait_status_code(dumped), 128 + libc::SIGSEGV);
+        assert_eq!(siginfo_wait_status(libc::CLD_STOPPED, libc::SIGSTOP), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_wait_decodes_exit_and_signal() {
+        for (script, expected) in [("exit 7", 7), ("kill -KILL $$", 128 + libc::SIGKILL)] {
+            let handle = open_and_spawn("/bin/sh", &["sh", "-c", script]);
+            let mut status = -1;
+            let result = portable_pty_wait_blocking(handle, &mut status);
+            assert!(matches!(result, PortablePtyResult::Ok));
+            assert_eq!(status, expected, "{script}");
+            portable_pty_close(handle);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_read_ends_after_exit_with_all_output() {
+        let script = "i=0; while [ $i -lt 2000 ]; do i=$((i+1)); echo line$i; done; exit 3";
+        let handle = open_and_spawn("/bin/sh", &["sh", "-c", script]);
+        let address = handle as usize;
+        let (done, finished) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let handle = address as *mut PortablePty;
+            let (mut output, mut buf) = (Vec::new(), [0u8; 4096]);
+            loop {
+                match portable_pty_read(handle, buf.as_mut_ptr(), buf.len()) {
+                    n if n > 0 => output.extend_from_slice(&buf[..n as usize]),
+                    n => return done.send((n, output)).unwrap(),
+                }
+            }
+        });
+        let (last, output) = finished.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(last, 0);
+        assert!(String::from_utf8_lossy(&output).ends_with("line2000\r\n"));
+
+        // Looking for the exit left the status for wait.
+        let mut status = -1;
+        let result = portable_pty_wait(handle, &mut status);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        assert_eq!(status, 3);
+        portable_pty_close(handle);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_get_mode_follows_stty() {
+        let handle = open_and_spawn("/bin/sh", &["sh", "-c", "stty -echo; echo off; sleep 5"]);
+        let mut output = String::new();
+        while !output.contains("off") {
+            output.push_str(&read_string(handle));
+        }
+        let (mut canonical, mut echo) = (false, true);
+        let result = portable_pty_get_mode(handle, &mut canonical, &mut echo);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        assert!(canonical);
+        assert!(!echo);
+        portable_pty_close(handle);
+    }
+
+    /// C that passes each of the optional callbacks and NULL in its place.
+    const HEADER_USES: &str = r#"
+        static void on_data(void *userdata, const uint8_t *data, uintptr_t len) {
+            (void)userdata, (void)data, (void)len;
+        }
+        static void on_event(void *userdata, const struct PortablePtyEvent *event) {
+            (void)userdata, (void)event;
+        }
+        static bool on_spawn(void *userdata, const struct PortablePtySpawnInfo *info) {
+            (void)userdata, (void)info;
+            return true;
+        }
+        static bool on_policy(void *userdata, const char *program, const char *const *argv,
+                              uintptr_t argc, struct PortablePtyPolicyRewrite *rewrite) {
+            (void)userdata, (void)program, (void)argv, (void)argc, (void)rewrite;
+            return true;
+        }
+        void uses(struct PortablePty *h) {
+            portable_pty_set_data_callback(h, on_data, 0);
+            portable_pty_set_data_callback(h, 0, 0);
+            portable_pty_set_event_callback(h, on_event, 0);
+            portable_pty_set_event_callback(h, 0, 0);
+            portable_pty_set_spawn_audit(on_spawn, 0);
+            portable_pty_set_spawn_audit(0, 0);
+            portable_pty_set_spawn_policy(on_policy, 0);
+            portable_pty_set_spawn_policy(0, 0);
+        }
+    "#;
+
+    #[test]
+    fn test_header_compiles_as_c() {
+        let dir = std::env::temp_dir().join(format!("portable-pty-header-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("uses.c");
+        let header = concat!(env!("CARGO_MANIFEST_DIR"), "/bindings.h");
+        std::fs::write(&source, format!("#include \"{header}\"\n{HEADER_USES}")).unwrap();
+        let compiled = std::process::Command::new("cc")
+            .args(["-std=c11", "-Wall", "-Werror", "-fsyntax-only"])
+            .arg(&source)
+            .output();
+        std::fs::remove_dir_all(&dir).unwrap();
+        // Nothing to check with where there's no C compiler.
+        let Ok(compiled) = compiled else {
+            return;
+        };
+        let errors = String::from_utf8_lossy(&compiled.stderr);
+        assert!(compiled.status.success(), "{errors}");
+    }
 }
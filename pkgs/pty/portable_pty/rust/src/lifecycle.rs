@@ -0,0 +1,492 @@
+//! Explicit setup and teardown of the library's global state.
+//!
+//! Loaded as a dynamic library, everything is set up on first use and left
+//! for process exit to clean up. Linked statically into an AOT executable
+//! that isn't good enough: the host wants the `SIGCHLD` handler in place
+//! before it starts children of its own, and gone again — along with every
+//! handle and background thread — when it's done with the library.
+//! `portable_pty_init` and `portable_pty_deinit` bracket the library's use
+//! for that. Both are optional; without them nothing changes.
+//!
+//! `portable_pty_close_async` closes a single handle without blocking the
+//! caller on its child or, on Windows, its console shutting down.
+//!
+//! Background threads are named for what they do and, where they serve a
+//! handle, for the handle (`portable-pty-idle-<label>`), so a profiler or
+//! crash dump says whose they are; `portable_pty_set_thread_label` picks
+//! the label, which is otherwise the child's process ID. Each lives as long
+//! as the feature that started it, so rather than pooling them
+//! `portable_pty_set_max_threads` caps how many run at once: past the cap,
+//! starting a feature fails as if the thread couldn't be created.
+
+use crate::{PortablePty, PortablePtyResult};
+use std::ffi::{c_char, c_void, CStr};
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+/// How long `portable_pty_deinit` waits for background threads to finish.
+const THREAD_EXIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The open handles and running background threads.
+pub(crate) struct Runtime {
+    /// Addresses of the handles given out and not yet closed.
+    handles: Mutex<Vec<usize>>,
+    threads: Mutex<usize>,
+    threads_done: Condvar,
+    /// Most background threads running at once; 0 for no limit.
+    max_threads: AtomicUsize,
+}
+
+static RUNTIME: Runtime = Runtime::new();
+
+/// Decrements the thread count when a background thread ends, however it
+/// ends.
+struct ThreadGuard(&'static Runtime);
+
+impl Drop for ThreadGuard {
+    fn drop(&mut self) {
+        let mut threads = lock(&self.0.threads);
+        *threads -= 1;
+        if *threads == 0 {
+            self.0.threads_done.notify_all();
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl Runtime {
+    const fn new() -> Self {
+        Runtime {
+            handles: Mutex::new(Vec::new()),
+            threads: Mutex::new(0),
+            threads_done: Condvar::new(),
+            max_threads: AtomicUsize::new(0),
+        }
+    }
+
+    fn register(&self, handle: Box<PortablePty>) -> *mut PortablePty {
+        let raw = Box::into_raw(handle);
+        lock(&self.handles).push(raw as usize);
+        raw
+    }
+
+    fn take(&self, handle: *mut PortablePty) -> Option<Box<PortablePty>> {
+        let mut handles = lock(&self.handles);
+        let index = handles.iter().position(|&h| h == handle as usize)?;
+        handles.swap_remove(index);
+        Some(unsafe { Box::from_raw(handle) })
+    }
+
+    fn spawn_thread<F>(&'static self, name: &str, f: F) -> io::Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        {
+            let mut threads = lock(&self.threads);
+            let max = self.max_threads.load(Ordering::Relaxed);
+            if max > 0 && *threads >= max {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "background thread limit reached",
+                ));
+            }
+            *threads += 1;
+        }
+        let guard = ThreadGuard(self);
+        std::thread::Builder::new()
+            .name(name.into())
+            .spawn(move || {
+                let _guard = guard;
+                f();
+            })
+            .map(drop)
+    }
+
+    /// Close every open handle.
+    fn close_all(&self) {
+        let handles = std::mem::take(&mut *lock(&self.handles));
+        for handle in handles {
+            crate::destroy(unsafe { Box::from_raw(handle as *mut PortablePty) });
+        }
+    }
+
+    /// Wait up to `timeout` for the background threads to finish.
+    fn wait_for_threads(&self, timeout: Duration) -> PortablePtyResult {
+        let deadline = Instant::now() + timeout;
+        let mut threads = lock(&self.threads);
+        while *threads > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                return PortablePtyResult::ErrTimeout;
+            }
+            threads = self
+                .threads_done
+                .wait_timeout(threads, deadline - now)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+        PortablePtyResult::Ok
+    }
+}
+
+/// Background threads a subsystem starts and must wait for before it
+/// tears down.
+#[derive(Default)]
+pub(crate) struct ThreadGroup {
+    running: Mutex<usize>,
+    done: Condvar,
+}
+
+/// Ends a thread's membership of its group, however the thread ends.
+struct GroupGuard(Arc<ThreadGroup>);
+
+impl Drop for GroupGuard {
+    fn drop(&mut self) {
+        let mut running = lock(&self.0.running);
+        *running -= 1;
+        if *running == 0 {
+            self.0.done.notify_all();
+        }
+    }
+}
+
+impl ThreadGroup {
+    /// Start a named background thread in the group (and, like every
+    /// thread, one `portable_pty_deinit` waits for).
+    pub(crate) fn spawn<F>(self: &Arc<Self>, name: &str, f: F) -> io::Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        *lock(&self.running) += 1;
+        let guard = GroupGuard(Arc::clone(self));
+        spawn_thread(name, move || {
+            let _guard = guard;
+            f();
+        })
+    }
+
+    /// Wait for every thread in the group to end.
+    pub(crate) fn wait(&self) {
+        let mut running = lock(&self.running);
+        while *running > 0 {
+            running = self
+                .done
+                .wait(running)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+/// A handle lent to background threads. Whoever starts them must stop them
+/// before the handle is destroyed.
+#[derive(Clone, Copy)]
+pub(crate) struct HandleRef(*const PortablePty);
+
+unsafe impl Send for HandleRef {}
+unsafe impl Sync for HandleRef {}
+
+impl HandleRef {
+    pub(crate) fn new(pty: &PortablePty) -> Self {
+        HandleRef(pty)
+    }
+
+    pub(crate) fn get(&self) -> &PortablePty {
+        unsafe { &*self.0 }
+    }
+}
+
+/// Hand out `handle` to the caller, tracking it until it's closed.
+pub(crate) fn register(handle: Box<PortablePty>) -> *mut PortablePty {
+    RUNTIME.register(handle)
+}
+
+/// Take back a handle being closed; None if it isn't open.
+pub(crate) fn take(handle: *mut PortablePty) -> Option<Box<PortablePty>> {
+    RUNTIME.take(handle)
+}
+
+/// Run `f` on each open handle. None can be closed meanwhile, so `f` must
+/// not close one.
+pub(crate) fn for_each_handle(mut f: impl FnMut(&PortablePty)) {
+    for &handle in lock(&RUNTIME.handles).iter() {
+        f(unsafe { &*(handle as *const PortablePty) });
+    }
+}
+
+/// How many background threads are running.
+pub(crate) fn thread_count() -> usize {
+    *lock(&RUNTIME.threads)
+}
+
+/// The name for a background thread doing `role` for `pty`.
+pub(crate) fn thread_name(pty: &PortablePty, role: &str) -> String {
+    let label = lock(&pty.thread_label).clone();
+    match label {
+        Some(label) => format!("portable-pty-{role}-{label}"),
+        None if pty.child_pid > 0 => format!("portable-pty-{role}-{}", pty.child_pid),
+        None => format!("portable-pty-{role}"),
+    }
+}
+
+/// Start a named background thread that `portable_pty_deinit` waits for.
+pub(crate) fn spawn_thread<F>(name: &str, f: F) -> io::Result<()>
+where
+    F: FnOnce() + Send + 'static,
+{
+    RUNTIME.spawn_thread(name, f)
+}
+
+/// Close callback: `(userdata, result)`.
+pub type PortablePtyCloseCallback = extern "C" fn(*mut c_void, PortablePtyResult);
+
+/// A handle being closed by `portable_pty_close_async`, torn down when
+/// dropped: on its thread, or on the caller's if the thread can't start.
+struct Closing {
+    pty: Option<Box<PortablePty>>,
+    callback: PortablePtyCloseCallback,
+    userdata: *mut c_void,
+}
+
+// The handle is no longer reachable by the caller, and the userdata
+// pointer is theirs to make usable from our thread.
+unsafe impl Send for Closing {}
+
+impl Drop for Closing {
+    fn drop(&mut self) {
+        if let Some(pty) = self.pty.take() {
+            let result = crate::destroy(pty);
+            (self.callback)(self.userdata, result);
+        }
+    }
+}
+
+/// Close the PTY on a background thread, reporting when it's done.
+///
+/// The handle is gone as soon as this returns and must not be used again.
+/// Killing and reaping the child and, on Windows, letting the console
+/// flush its last output and shut down (see `portable_pty_close`) happen
+/// afterwards. `portable_pty_deinit` waits for closes still in progress.
+///
+/// - `callback`: called once teardown has fully completed, on the
+///   background thread, with `userdata` and `Ok`, or `ErrTimeout` if the
+///   console's output never ended (the handle is freed either way).
+///
+/// Returns `ErrNull` if `handle` is NULL or not open, in which case the
+/// callback isn't called.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_close_async(
+    handle: *mut PortablePty,
+    callback: PortablePtyCloseCallback,
+    userdata: *mut c_void,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let Some(pty) = take(handle) else {
+            return PortablePtyResult::ErrNull;
+        };
+        let closing = Closing {
+            pty: Some(pty),
+            callback,
+            userdata,
+        };
+        let _ = spawn_thread("portable-pty-close", move || drop(closing));
+        PortablePtyResult::Ok
+    })
+}
+
+/// Label the handle's background threads from now on. Their names are
+/// what they do plus the label, as in `portable-pty-idle-<label>`, so a
+/// profiler or crash dump says whose they are.
+///
+/// - `label`: NUL-terminated label, such as a tab's title; NULL to go
+///   back to the child's process ID.
+///
+/// Threads already running keep their names. Some platforms cut names
+/// short (Linux at 15 bytes), so a short label is best.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_set_thread_label(
+    handle: *const PortablePty,
+    label: *const c_char,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        let label = (!label.is_null()).then(|| {
+            unsafe { CStr::from_ptr(label) }
+                .to_string_lossy()
+                .into_owned()
+        });
+        *lock(&pty.thread_label) = label;
+        PortablePtyResult::Ok
+    })
+}
+
+/// Cap the background threads running at once, across every handle.
+///
+/// - `max_threads`: the cap; 0 for none, the default.
+///
+/// Threads already running are left alone. Past the cap, whatever needs a
+/// new thread fails as it would if the thread couldn't be created, and
+/// `portable_pty_close_async` closes the handle before returning.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_set_max_threads(max_threads: u32) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        RUNTIME
+            .max_threads
+            .store(max_threads as usize, Ordering::Relaxed);
+        PortablePtyResult::Ok
+    })
+}
+
+/// Set up the library's global state ahead of first use.
+///
+/// Installs the `SIGCHLD` handler now rather than on the first spawn, so
+/// it's in place before the host starts children of its own. It reaps the
+/// library's children and caches their statuses before chaining to the
+/// handler it replaced. Calling it again is harmless.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_init() -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        #[cfg(unix)]
+        crate::ensure_sigchld_handler();
+        PortablePtyResult::Ok
+    })
+}
+
+/// Tear down the library's global state.
+///
+/// Closes every handle still open (killing their children), stops the
+/// control socket, restores the `SIGCHLD` handler that was in place before
+/// ours (unless someone has replaced ours since), and waits for background
+/// threads to finish.
+/// Pointers to the closed handles must not be used again.
+///
+/// Returns `ErrTimeout` if some thread was still running after five
+/// seconds. The library can be used again afterwards; it sets itself up
+/// as on first use.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_deinit() -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        // Children are gone once their handles are, so nothing is left for
+        // the SIGCHLD handler to catch.
+        RUNTIME.close_all();
+        crate::control::stop();
+        #[cfg(unix)]
+        {
+            crate::jobs::stop();
+            crate::remove_sigchld_handler();
+        }
+        RUNTIME.wait_for_threads(THREAD_EXIT_TIMEOUT)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    // A private runtime each: the global one is shared with every other
+    // test running in parallel.
+    fn runtime() -> &'static Runtime {
+        Box::leak(Box::new(Runtime::new()))
+    }
+
+    #[cfg(unix)]
+    extern "C" fn closed(userdata: *mut c_void, result: PortablePtyResult) {
+        let done = unsafe { &*(userdata as *const mpsc::Sender<PortablePtyResult>) };
+        let _ = done.send(result);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_close_async_reports_completion() {
+        let result = portable_pty_close_async(std::ptr::null_mut(), closed, std::ptr::null_mut());
+        assert!(matches!(result, PortablePtyResult::ErrNull));
+
+        let handle = crate::tests::open_and_spawn("sleep", &["sleep", "10"]);
+        let (tx, rx) = mpsc::channel();
+        let userdata = &tx as *const mpsc::Sender<PortablePtyResult> as *mut c_void;
+        let result = portable_pty_close_async(handle, closed, userdata);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        let result = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(matches!(result, PortablePtyResult::Ok));
+
+        // Closed already, so nothing more is reported.
+        let result = portable_pty_close_async(handle, closed, userdata);
+        assert!(matches!(result, PortablePtyResult::ErrNull));
+    }
+
+    #[test]
+    fn test_drain_reads_output_to_its_end() {
+        let mut handle = std::ptr::null_mut();
+        let result = crate::loopback::portable_pty_open_loopback(24, 80, &mut handle);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        let data = b"last frame";
+        crate::loopback::portable_pty_loopback_write(handle, data.as_ptr(), data.len());
+        crate::loopback::portable_pty_loopback_exit(handle, 0);
+        let result = crate::release(take(handle).unwrap(), true);
+        assert!(matches!(result, PortablePtyResult::Ok));
+    }
+
+    #[test]
+    fn test_closes_handles_and_waits_for_threads() {
+        let runtime = runtime();
+        let mut handle = std::ptr::null_mut();
+        let result = crate::loopback::portable_pty_open_loopback(24, 80, &mut handle);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        // Move it from the global table to ours.
+        let handle = runtime.register(take(handle).unwrap());
+
+        let (tx, rx) = mpsc::channel::<()>();
+        runtime
+            .spawn_thread("test", move || {
+                let _ = rx.recv();
+            })
+            .unwrap();
+        runtime.close_all();
+        assert!(runtime.take(handle).is_none());
+        let result = runtime.wait_for_threads(Duration::from_millis(50));
+        assert!(matches!(result, PortablePtyResult::ErrTimeout));
+
+        drop(tx);
+        let result = runtime.wait_for_threads(Duration::from_secs(5));
+        assert!(matches!(result, PortablePtyResult::Ok));
+    }
+
+    #[test]
+    fn test_thread_limit() {
+        let runtime = runtime();
+        runtime.max_threads.store(1, Ordering::Relaxed);
+        let (tx, rx) = mpsc::channel::<()>();
+        runtime
+            .spawn_thread("test", move || {
+                let _ = rx.recv();
+            })
+            .unwrap();
+        assert!(runtime.spawn_thread("test", || {}).is_err());
+
+        drop(tx);
+        let result = runtime.wait_for_threads(Duration::from_secs(5));
+        assert!(matches!(result, PortablePtyResult::Ok));
+        runtime.spawn_thread("test", || {}).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_threads_named_for_handle() {
+        let handle = crate::tests::open_and_spawn("sleep", &["sleep", "10"]);
+        let pty = unsafe { &*handle };
+        let pid = pty.child_pid;
+        assert_eq!(thread_name(pty, "idle"), format!("portable-pty-idle-{pid}"));
+        let result = portable_pty_set_thread_label(handle, c"tab1".as_ptr());
+        assert!(matches!(result, PortablePtyResult::Ok));
+        assert_eq!(thread_name(pty, "idle"), "portable-pty-idle-tab1");
+        crate::portable_pty_close(handle);
+    }
+}
@@ -0,0 +1,263 @@
+//! Links in plain output.
+//!
+//! Output that marks its links with OSC 8 says where they are; most
+//! doesn't. `portable_pty_screen_links` finds them in the text of the
+//! tracked screen (see `view`), for the UI to make clickable, scanning
+//! only the lines it asks about — the ones it's redrawing — rather than
+//! the embedder rescanning every frame. Two kinds are found:
+//!
+//! | kind   | what                                                    |
+//! |--------|---------------------------------------------------------|
+//! | `url`  | `http(s)`, `ftp`, `file`, `ssh`, `git` and `ws(s)` URLs |
+//! |        | and `mailto:` addresses                                 |
+//! | `path` | `/abs`, `~/home`, `./rel` and `../rel` paths, and       |
+//! |        | `dir/file.ext`                                          |
+//!
+//! A path may end in `:line` or `:line:column`, as compilers print them.
+//! Punctuation ending a sentence isn't part of a link, nor is a closing
+//! bracket the link didn't open. A link wrapped onto the next line is
+//! found whole, with a range on each line it covers.
+
+use crate::{PortablePty, PortablePtyBuffer, PortablePtyResult};
+use regex::Regex;
+use std::ops::Range;
+use std::sync::OnceLock;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Kind {
+    Url,
+    Path,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Url => "url",
+            Kind::Path => "path",
+        }
+    }
+}
+
+fn url_regex() -> &'static Regex {
+    static REGEX: OnceLock<Regex> = OnceLock::new();
+    REGEX.get_or_init(|| {
+        Regex::new(r#"(?i)\b(?:(?:https?|ftp|file|ssh|git|wss?)://|mailto:)[^\s<>"'`]+"#).unwrap()
+    })
+}
+
+fn path_regex() -> &'static Regex {
+    static REGEX: OnceLock<Regex> = OnceLock::new();
+    REGEX.get_or_init(|| {
+        Regex::new(concat!(
+            r"(?:(?:~|\.\.?)?(?:/[\w.@+~%-]+)+/?",
+            r"|[\w@+%-][\w.@+%-]*(?:/[\w.@+%-]+)*/[\w@+%-][\w@+%-]*\.[A-Za-z0-9]+)",
+            r"(?::\d+(?::\d+)?)?",
+        ))
+        .unwrap()
+    })
+}
+
+/// `range` of `text` without the punctuation that would end a sentence
+/// or close a bracket opened before it.
+fn trim(text: &str, mut range: Range<usize>) -> Range<usize> {
+    while let Some(c) = text[range.clone()].chars().next_back() {
+        let link = &text[range.clone()];
+        let unopened = |open, close| link.matches(open).count() < link.matches(close).count();
+        let trailing = match c {
+            '.' | ',' | ';' | ':' | '!' | '?' | '\'' | '"' => true,
+            ')' => unopened('(', ')'),
+            ']' => unopened('[', ']'),
+            '}' => unopened('{', '}'),
+            _ => false,
+        };
+        if !trailing {
+            break;
+        }
+        range.end -= c.len_utf8();
+    }
+    range
+}
+
+/// The links in `text`, by byte range.
+pub(crate) fn find(text: &str) -> Vec<(Range<usize>, Kind)> {
+    let mut links: Vec<(Range<usize>, Kind)> = url_regex()
+        .find_iter(text)
+        .map(|m| (trim(text, m.range()), Kind::Url))
+        .collect();
+    for m in path_regex().find_iter(text) {
+        // Not the tail of a longer word, nor part of a URL.
+        let before = text[..m.start()].chars().next_back();
+        if before.is_some_and(|c| c.is_alphanumeric() || "_/.~@%+-".contains(c)) {
+            continue;
+        }
+        if links
+            .iter()
+            .any(|(url, _)| url.start < m.end() && m.start() < url.end)
+        {
+            continue;
+        }
+        let range = trim(text, m.range());
+        // A bare slash or dots aren't a path.
+        if text[range.clone()].chars().any(|c| c.is_alphanumeric()) {
+            links.push((range, Kind::Path));
+        }
+    }
+    links.sort_by_key(|(range, _)| range.start);
+    links
+}
+
+/// The links on lines `lines` of `screen`, as a JSON array.
+#[cfg(feature = "screen")]
+fn scan(screen: &crate::screen::Screen, lines: Range<usize>) -> serde_json::Value {
+    let kept = screen.first_line()..screen.first_line() + screen.line_count();
+    let lines = lines.start.max(kept.start)..lines.end.min(kept.end);
+    let mut out = Vec::new();
+    let mut n = lines.start;
+    while n < lines.end {
+        let (text, placed) = screen.logical_text(n);
+        for (range, kind) in find(&text) {
+            // The link's part on each line: line, start and end column.
+            let mut spans: Vec<(usize, usize, usize)> = Vec::new();
+            for c in placed.iter().filter(|c| range.contains(&c.at)) {
+                match spans.last_mut() {
+                    Some((line, _, end)) if *line == c.line => *end = c.col + c.width,
+                    _ => spans.push((c.line, c.col, c.col + c.width)),
+                }
+            }
+            for (line, start, end) in spans {
+                if lines.contains(&line) {
+                    out.push(serde_json::json!({
+                        "line": line,
+                        "start": start,
+                        "end": end,
+                        "kind": kind.name(),
+                        "text": &text[range.clone()],
+                    }));
+                }
+            }
+        }
+        n = screen.logical_line(n).end;
+    }
+    out.into()
+}
+
+/// Find the links on lines `first` to `first + count - 1` of the tracked
+/// screen. A `url` is an `http(s)`, `ftp`, `file`, `ssh`, `git` or `ws(s)`
+/// URL or a `mailto:` address; a `path` is `/abs`, `~/home`, `./rel`,
+/// `../rel` or `dir/file.ext`, optionally ending in `:line` or
+/// `:line:column`. Punctuation ending a sentence, and a closing bracket
+/// the link didn't open, are left out. A link wrapped onto the next line
+/// is found whole, with a range on each line it covers.
+///
+/// - `first`, `count`: line numbers as `portable_pty_screen_lines` gives
+///   them. Lines no longer kept are skipped.
+/// - `out_json`: receives a UTF-8 JSON array, in order, of objects with
+///   `line`, the `start` and `end` columns of the link's part on that line
+///   (end exclusive, wide characters taking two), its `kind` and its whole
+///   `text`. Free with `portable_pty_buffer_free`.
+///
+/// Returns `ErrUnsupported` if the screen isn't tracked.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_screen_links(
+    handle: *const PortablePty,
+    first: u64,
+    count: u64,
+    out_json: *mut PortablePtyBuffer,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        if out_json.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+        #[cfg(feature = "screen")]
+        {
+            let first = usize::try_from(first).unwrap_or(usize::MAX);
+            let end = first.saturating_add(usize::try_from(count).unwrap_or(usize::MAX));
+            match pty.view.with(|screen| scan(screen, first..end)) {
+                Ok(links) => {
+                    let json = links.to_string().into_bytes();
+                    unsafe { *out_json = PortablePtyBuffer::from_vec(json) };
+                    PortablePtyResult::Ok
+                }
+                Err(e) => e,
+            }
+        }
+        #[cfg(not(feature = "screen"))]
+        {
+            let _ = (pty, first, count);
+            PortablePtyResult::ErrUnsupported
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn links(text: &str) -> Vec<(&str, Kind)> {
+        find(text)
+            .into_iter()
+            .map(|(range, kind)| (&text[range], kind))
+            .collect()
+    }
+
+    #[test]
+    fn test_finds_urls_and_paths() {
+        assert_eq!(
+            links("see https://example.com/a?b=c#d. or mailto:me@example.com"),
+            [
+                ("https://example.com/a?b=c#d", Kind::Url),
+                ("mailto:me@example.com", Kind::Url),
+            ]
+        );
+        assert_eq!(
+            links("error: src/lib.rs:42:5, (in ~/code/x) and /etc/hosts."),
+            [
+                ("src/lib.rs:42:5", Kind::Path),
+                ("~/code/x", Kind::Path),
+                ("/etc/hosts", Kind::Path),
+            ]
+        );
+        assert_eq!(
+            links("(https://en.wikipedia.org/wiki/Rust_(language))"),
+            [("https://en.wikipedia.org/wiki/Rust_(language)", Kind::Url)]
+        );
+        assert!(links("and/or 1/2 a / b ../ 3.5").is_empty());
+    }
+
+    #[cfg(all(unix, feature = "screen"))]
+    #[test]
+    fn test_links_on_the_tracked_screen() {
+        use crate::tests::read_string;
+
+        let mut handle = std::ptr::null_mut();
+        crate::loopback::portable_pty_open_loopback(3, 12, &mut handle);
+        crate::view::portable_pty_screen_start(handle, 100);
+        let output = "go to https://a.io/xyz now\r\n字 ./run.sh";
+        crate::loopback::portable_pty_loopback_write(handle, output.as_ptr(), output.len());
+        read_string(handle);
+
+        let scan = |first, count| {
+            let mut out = PortablePtyBuffer::EMPTY;
+            let result = portable_pty_screen_links(handle, first, count, &mut out);
+            assert!(matches!(result, PortablePtyResult::Ok));
+            let bytes = unsafe { std::slice::from_raw_parts(out.data, out.len) };
+            let json: serde_json::Value = serde_json::from_slice(bytes).unwrap();
+            crate::portable_pty_buffer_free(out);
+            json
+        };
+        // The URL wraps from the first line onto the second.
+        assert_eq!(
+            scan(0, 4),
+            serde_json::json!([
+                {"line": 0, "start": 6, "end": 12, "kind": "url", "text": "https://a.io/xyz"},
+                {"line": 1, "start": 0, "end": 10, "kind": "url", "text": "https://a.io/xyz"},
+                {"line": 3, "start": 3, "end": 11, "kind": "path", "text": "./run.sh"},
+            ])
+        );
+        crate::portable_pty_close(handle);
+    }
+}
@@ -0,0 +1,456 @@
+//! In-memory loopback backend.
+//!
+//! `portable_pty_open_loopback` opens a handle backed by two in-process
+//! pipes instead of a PTY. The "child" end is driven by the companion
+//! `portable_pty_loopback_*` functions: what the handle writes comes out of
+//! `portable_pty_loopback_read`, what goes into `portable_pty_loopback_write`
+//! is read by the handle, and `portable_pty_loopback_exit` plays the child
+//! exiting. Nothing touches `/dev/ptmx` or ConPTY, so tests of the
+//! read/write/resize/event plumbing run anywhere.
+//!
+//! There's no line discipline in between: no echo, no newline translation,
+//! no signals from control characters.
+
+use crate::mock::KILLED_STATUS;
+use crate::pty::{
+    Child, ChildKiller, CommandBuilder, ExitStatus, MasterPty, PtyPair, PtySize, SlavePty,
+};
+use crate::{PipeReader, PipeWriter, PortablePty, PortablePtyResult};
+use std::io::{Read, Write};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+
+/// The child's end of the loopback.
+struct Loopback {
+    size: Mutex<PtySize>,
+    /// Read end of what the handle writes.
+    input: Mutex<PipeReader>,
+    /// Write end of what the handle reads; dropped when the child exits.
+    output: Mutex<Option<PipeWriter>>,
+    exit: Mutex<Option<u32>>,
+    /// Signalled when `exit` is set.
+    exited: Condvar,
+}
+
+impl Loopback {
+    /// Record the exit status (the first one wins) and close the output.
+    fn exit(&self, code: u32) {
+        self.exit
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get_or_insert(code);
+        self.output
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        self.exited.notify_all();
+    }
+}
+
+struct LoopbackMaster {
+    loopback: Arc<Loopback>,
+    reader: PipeReader,
+    writer: PipeWriter,
+}
+
+impl MasterPty for LoopbackMaster {
+    fn resize(&self, size: PtySize) -> anyhow::Result<()> {
+        *self
+            .loopback
+            .size
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = size;
+        Ok(())
+    }
+
+    fn get_size(&self) -> anyhow::Result<PtySize> {
+        Ok(*self
+            .loopback
+            .size
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner))
+    }
+
+    fn try_clone_reader(&self) -> anyhow::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(self.reader.try_clone()?))
+    }
+
+    fn take_writer(&self) -> anyhow::Result<Box<dyn Write + Send>> {
+        Ok(Box::new(self.writer.try_clone()?))
+    }
+
+    #[cfg(unix)]
+    fn process_group_leader(&self) -> Option<libc::pid_t> {
+        None
+    }
+
+    #[cfg(unix)]
+    fn as_raw_fd(&self) -> Option<std::os::fd::RawFd> {
+        use std::os::fd::AsRawFd;
+        Some(self.reader.as_raw_fd())
+    }
+
+    #[cfg(unix)]
+    fn tty_name(&self) -> Option<std::path::PathBuf> {
+        None
+    }
+}
+
+struct LoopbackSlave;
+
+impl SlavePty for LoopbackSlave {
+    fn spawn_command(&self, _cmd: CommandBuilder) -> anyhow::Result<Box<dyn Child + Send + Sync>> {
+        anyhow::bail!("loopback handles can't spawn processes")
+    }
+}
+
+/// Stands in for a child process: exits on `portable_pty_loopback_exit`.
+#[derive(Clone)]
+struct LoopbackChild {
+    loopback: Arc<Loopback>,
+}
+
+impl std::fmt::Debug for LoopbackChild {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LoopbackChild").finish_non_exhaustive()
+    }
+}
+
+impl ChildKiller for LoopbackChild {
+    fn kill(&mut self) -> std::io::Result<()> {
+        self.loopback.exit(KILLED_STATUS);
+        Ok(())
+    }
+
+    fn clone_killer(&self) -> Box<dyn ChildKiller + Send + Sync> {
+        Box::new(self.clone())
+    }
+}
+
+impl Child for LoopbackChild {
+    fn try_wait(&mut self) -> std::io::Result<Option<ExitStatus>> {
+        let exit = *self
+            .loopback
+            .exit
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        Ok(exit.map(ExitStatus::with_exit_code))
+    }
+
+    fn wait(&mut self) -> std::io::Result<ExitStatus> {
+        let mut exit = self
+            .loopback
+            .exit
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        loop {
+            if let Some(code) = *exit {
+                return Ok(ExitStatus::with_exit_code(code));
+            }
+            exit = self
+                .loopback
+                .exited
+                .wait(exit)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    fn process_id(&self) -> Option<u32> {
+        None
+    }
+
+    #[cfg(windows)]
+    fn as_raw_handle(&self) -> Option<std::os::windows::io::RawHandle> {
+        None
+    }
+}
+
+/// The child end of a loopback handle; `ErrBackend` for other handles.
+fn loopback(handle: *const PortablePty) -> Result<Arc<Loopback>, PortablePtyResult> {
+    let pty = match unsafe { handle.as_ref() } {
+        Some(p) => p,
+        None => return Err(PortablePtyResult::ErrNull),
+    };
+    match pty
+        .master
+        .as_ref()
+        .as_any()
+        .downcast_ref::<LoopbackMaster>()
+    {
+        Some(master) => Ok(Arc::clone(&master.loopback)),
+        None => Err(PortablePtyResult::ErrBackend),
+    }
+}
+
+/// Open a loopback handle of `rows` x `cols`.
+///
+/// The handle already has its stand-in child; `portable_pty_spawn` fails
+/// on it.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_open_loopback(
+    rows: u16,
+    cols: u16,
+    out: *mut *mut PortablePty,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        if out.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+        let size = PtySize {
+            rows,
+            cols,
+            pixel_width: 0,
+            pixel_height: 0,
+        };
+        match open(size) {
+            Ok(handle) => {
+                unsafe {
+                    *out = crate::lifecycle::register(handle);
+                }
+                PortablePtyResult::Ok
+            }
+            Err(e) => e,
+        }
+    })
+}
+
+/// Open a loopback handle of `size`, stand-in child included.
+pub(crate) fn open(size: PtySize) -> Result<Box<PortablePty>, PortablePtyResult> {
+    let (Ok((output_reader, output_writer)), Ok((input_reader, input_writer))) =
+        (crate::pipe(), crate::pipe())
+    else {
+        return Err(PortablePtyResult::ErrOpen);
+    };
+
+    let loopback = Arc::new(Loopback {
+        size: Mutex::new(size),
+        input: Mutex::new(input_reader),
+        output: Mutex::new(Some(output_writer)),
+        exit: Mutex::new(None),
+        exited: Condvar::new(),
+    });
+    let pair = PtyPair {
+        slave: Box::new(LoopbackSlave),
+        master: Box::new(LoopbackMaster {
+            loopback: Arc::clone(&loopback),
+            reader: output_reader,
+            writer: input_writer,
+        }),
+    };
+    let mut handle = PortablePty::from_pair(pair)?;
+    handle.child = Some(Box::new(LoopbackChild { loopback }));
+    Ok(handle)
+}
+
+/// Write `len` bytes from the child end, for the handle to read.
+///
+/// Returns the number of bytes written, or -1 on error (including after
+/// the child has exited).
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_loopback_write(
+    handle: *const PortablePty,
+    buf: *const u8,
+    len: usize,
+) -> i64 {
+    crate::ffi::guard(|| {
+        let loopback = match loopback(handle) {
+            Ok(l) => l,
+            Err(_) => return -1,
+        };
+        if buf.is_null() || len == 0 {
+            return 0;
+        }
+        let slice = unsafe { std::slice::from_raw_parts(buf, len) };
+        let mut output = loopback
+            .output
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        match output.as_mut().map(|w| w.write_all(slice)) {
+            Some(Ok(())) => len as i64,
+            _ => -1,
+        }
+    })
+}
+
+/// Read what the handle has written, from the child end.
+///
+/// Blocks until data is available. Returns the number of bytes read, or
+/// -1 on error.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_loopback_read(
+    handle: *const PortablePty,
+    buf: *mut u8,
+    len: usize,
+) -> i64 {
+    crate::ffi::guard(|| {
+        let loopback = match loopback(handle) {
+            Ok(l) => l,
+            Err(_) => return -1,
+        };
+        if buf.is_null() || len == 0 {
+            return 0;
+        }
+        let slice = unsafe { std::slice::from_raw_parts_mut(buf, len) };
+        let mut input = loopback
+            .input
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        match input.read(slice) {
+            Ok(n) => n as i64,
+            Err(_) => -1,
+        }
+    })
+}
+
+/// Get the size as the child end sees it, i.e. after any
+/// `portable_pty_resize`.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_loopback_size(
+    handle: *const PortablePty,
+    out_rows: *mut u16,
+    out_cols: *mut u16,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        if out_rows.is_null() || out_cols.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+        let loopback = match loopback(handle) {
+            Ok(l) => l,
+            Err(e) => return e,
+        };
+        let size = *loopback.size.lock().unwrap_or_else(PoisonError::into_inner);
+        unsafe {
+            *out_rows = size.rows;
+            *out_cols = size.cols;
+        }
+        PortablePtyResult::Ok
+    })
+}
+
+/// Make the stand-in child exit with `status`.
+///
+/// The handle reads EOF once it has drained what was written, and
+/// `portable_pty_wait` reports `status`. Has no effect if the child has
+/// already exited.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_loopback_exit(
+    handle: *const PortablePty,
+    status: u32,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| match loopback(handle) {
+        Ok(loopback) => {
+            loopback.exit(status);
+            PortablePtyResult::Ok
+        }
+        Err(e) => e,
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::commands::portable_pty_queue_command;
+    use crate::events::{
+        portable_pty_event_free, portable_pty_next_event, PortablePtyEvent,
+        PORTABLE_PTY_EVENT_COMMAND_DONE,
+    };
+    use crate::tests::read_string;
+    use crate::{
+        portable_pty_close, portable_pty_read, portable_pty_resize, portable_pty_wait_blocking,
+        portable_pty_write, PortablePtyBuffer,
+    };
+
+    fn open() -> *mut PortablePty {
+        let mut handle = std::ptr::null_mut();
+        let result = portable_pty_open_loopback(24, 80, &mut handle);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        handle
+    }
+
+    fn child_write(handle: *mut PortablePty, text: &str) {
+        let n = portable_pty_loopback_write(handle, text.as_ptr(), text.len());
+        assert_eq!(n, text.len() as i64);
+    }
+
+    #[test]
+    fn test_round_trip_and_resize() {
+        let handle = open();
+        let n = portable_pty_write(handle, b"hello".as_ptr(), 5);
+        assert_eq!(n, 5);
+        let mut buf = [0u8; 16];
+        let n = portable_pty_loopback_read(handle, buf.as_mut_ptr(), buf.len());
+        assert_eq!(&buf[..n as usize], b"hello");
+
+        child_write(handle, "world");
+        assert_eq!(read_string(handle), "world");
+
+        assert!(matches!(
+            portable_pty_resize(handle, 40, 120),
+            PortablePtyResult::Ok
+        ));
+        let (mut rows, mut cols) = (0, 0);
+        portable_pty_loopback_size(handle, &mut rows, &mut cols);
+        assert_eq!((rows, cols), (40, 120));
+        portable_pty_close(handle);
+    }
+
+    #[test]
+    fn test_exit_gives_eof_and_status() {
+        let handle = open();
+        child_write(handle, "bye");
+        assert!(matches!(
+            portable_pty_loopback_exit(handle, 7),
+            PortablePtyResult::Ok
+        ));
+        assert_eq!(read_string(handle), "bye");
+        let mut buf = [0u8; 16];
+        assert_eq!(portable_pty_read(handle, buf.as_mut_ptr(), buf.len()), 0);
+        assert_eq!(portable_pty_loopback_write(handle, b"x".as_ptr(), 1), -1);
+
+        let mut status = -1;
+        let result = portable_pty_wait_blocking(handle, &mut status);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        assert_eq!(status, 7);
+        portable_pty_close(handle);
+    }
+
+    #[test]
+    fn test_output_reaches_event_plumbing() {
+        let handle = open();
+        child_write(handle, "\x1b]133;A\x07$ ");
+        assert_eq!(read_string(handle), "\x1b]133;A\x07$ ");
+
+        // Command completion events fire off the child end's output.
+        let command = std::ffi::CString::new("true").unwrap();
+        let mut id = 0;
+        let result = portable_pty_queue_command(handle, command.as_ptr(), &mut id);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        let mut buf = [0u8; 16];
+        let n = portable_pty_loopback_read(handle, buf.as_mut_ptr(), buf.len());
+        assert_eq!(&buf[..n as usize], b"true\r");
+
+        child_write(handle, "\x1b]133;D;0\x07\x1b]133;A\x07$ ");
+        read_string(handle);
+        let mut event = PortablePtyEvent {
+            kind: 0,
+            id: 0,
+            value: 0,
+            data: PortablePtyBuffer::EMPTY,
+        };
+        assert!(portable_pty_next_event(handle, &mut event));
+        assert_eq!(event.kind, PORTABLE_PTY_EVENT_COMMAND_DONE);
+        assert_eq!((event.id, event.value), (id, 0));
+        portable_pty_event_free(&mut event);
+        portable_pty_close(handle);
+    }
+
+    #[test]
+    fn test_companions_reject_other_handles() {
+        let handle = crate::tests::open_and_spawn("/bin/echo", &["echo"]);
+        assert!(matches!(
+            portable_pty_loopback_exit(handle, 0),
+            PortablePtyResult::ErrBackend
+        ));
+        assert_eq!(portable_pty_loopback_write(handle, b"x".as_ptr(), 1), -1);
+        portable_pty_close(handle);
+    }
+}
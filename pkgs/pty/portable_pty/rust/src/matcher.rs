@@ -0,0 +1,254 @@
+//! Persistent output patterns.
+//!
+//! Patterns registered on a handle are checked against every chunk of
+//! output as it is read, and each match posts a
+//! `PORTABLE_PTY_EVENT_MATCH` event. Scanning is incremental: a short tail
+//! of recent output is kept so matches that straddle two reads are still
+//! found, and each pattern resumes after its previous match so nothing is
+//! reported twice. Matches longer than the retained window may be missed or
+//! reported in pieces.
+
+use crate::events::PORTABLE_PTY_EVENT_MATCH;
+use crate::expect::compile;
+use crate::{PortablePty, PortablePtyResult};
+use regex::bytes::Regex;
+use std::ffi::{c_char, CStr};
+use std::sync::PoisonError;
+
+/// Output retained between reads for cross-chunk matches.
+const WINDOW_SIZE: usize = 4096;
+
+struct Pattern {
+    id: u64,
+    regex: Regex,
+    /// Window offset where scanning resumes for this pattern.
+    resume: usize,
+}
+
+/// A match found while scanning: `(pattern id, stream offset, bytes)`.
+pub(crate) type Match = (u64, u64, Vec<u8>);
+
+#[derive(Default)]
+pub(crate) struct MatcherSet {
+    patterns: Vec<Pattern>,
+    next_id: u64,
+    window: Vec<u8>,
+    /// Stream offset of `window[0]`.
+    window_start: u64,
+}
+
+impl MatcherSet {
+    fn add(&mut self, regex: Regex) -> u64 {
+        self.next_id += 1;
+        self.patterns.push(Pattern {
+            id: self.next_id,
+            regex,
+            resume: self.window.len(),
+        });
+        self.next_id
+    }
+
+    fn remove(&mut self, id: u64) -> bool {
+        let before = self.patterns.len();
+        self.patterns.retain(|p| p.id != id);
+        self.patterns.len() != before
+    }
+
+    /// Scan a new chunk of output, returning matches in pattern order.
+    pub(crate) fn feed(&mut self, bytes: &[u8]) -> Vec<Match> {
+        if self.patterns.is_empty() {
+            // Nothing to scan for; just keep the stream offset current.
+            self.window_start += (self.window.len() + bytes.len()) as u64;
+            self.window.clear();
+            return Vec::new();
+        }
+
+        self.window.extend_from_slice(bytes);
+        let mut found = Vec::new();
+        for pattern in &mut self.patterns {
+            let mut at = pattern.resume;
+            while let Some(m) = pattern.regex.find_at(&self.window, at) {
+                if m.is_empty() {
+                    // Don't spin on empty matches.
+                    break;
+                }
+                found.push((
+                    pattern.id,
+                    self.window_start + m.start() as u64,
+                    m.as_bytes().to_vec(),
+                ));
+                at = m.end();
+            }
+            pattern.resume = at;
+        }
+
+        if self.window.len() > WINDOW_SIZE {
+            let excess = self.window.len() - WINDOW_SIZE;
+            self.window.drain(..excess);
+            self.window_start += excess as u64;
+            for pattern in &mut self.patterns {
+                pattern.resume = pattern.resume.saturating_sub(excess);
+            }
+        }
+        found
+    }
+}
+
+/// Register a persistent pattern on the handle.
+///
+/// Every match in subsequently read output posts a
+/// `PORTABLE_PTY_EVENT_MATCH` event carrying the pattern ID. On success the
+/// new ID is written to `*out_id`.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_add_pattern(
+    handle: *const PortablePty,
+    pattern: *const c_char,
+    is_regex: bool,
+    out_id: *mut u64,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        if pattern.is_null() || out_id.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+
+        let pattern = match unsafe { CStr::from_ptr(pattern) }.to_str() {
+            Ok(s) => s,
+            Err(_) => return PortablePtyResult::ErrPattern,
+        };
+        let regex = match compile(pattern, is_regex) {
+            Ok(r) => r,
+            Err(_) => return PortablePtyResult::ErrPattern,
+        };
+
+        let id = pty
+            .matchers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .add(regex);
+        unsafe {
+            *out_id = id;
+        }
+        PortablePtyResult::Ok
+    })
+}
+
+/// Remove a pattern registered with `portable_pty_add_pattern`.
+///
+/// Returns `ErrPattern` if no pattern has that ID.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_remove_pattern(
+    handle: *const PortablePty,
+    id: u64,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+
+        let removed = pty
+            .matchers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(id);
+        if removed {
+            PortablePtyResult::Ok
+        } else {
+            PortablePtyResult::ErrPattern
+        }
+    })
+}
+
+/// Post match events for a chunk of output.
+pub(crate) fn scan(pty: &PortablePty, bytes: &[u8]) {
+    let found = pty
+        .matchers
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .feed(bytes);
+    for (id, offset, matched) in found {
+        pty.events
+            .post(PORTABLE_PTY_EVENT_MATCH, id, offset as i64, matched);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches_across_chunks_once() {
+        let mut set = MatcherSet::default();
+        let error = set.add(compile("ERROR", false).unwrap());
+        let number = set.add(compile(r"code=\d+;", true).unwrap());
+
+        assert!(set.feed(b"ok\nERR").is_empty());
+        let found = set.feed(b"OR code=4");
+        assert_eq!(found, vec![(error, 3, b"ERROR".to_vec())]);
+
+        let found = set.feed(b"2; ERROR");
+        assert_eq!(
+            found,
+            vec![
+                (error, 18, b"ERROR".to_vec()),
+                (number, 9, b"code=42;".to_vec())
+            ]
+        );
+
+        // Nothing new: earlier matches aren't reported again.
+        assert!(set.feed(b"\n").is_empty());
+
+        assert!(set.remove(error));
+        assert!(!set.remove(error));
+        assert!(set.feed(b"ERROR").is_empty());
+    }
+
+    #[test]
+    fn test_window_is_bounded() {
+        let mut set = MatcherSet::default();
+        let id = set.add(compile("needle", false).unwrap());
+        let filler = vec![b'x'; WINDOW_SIZE * 3];
+        assert!(set.feed(&filler).is_empty());
+        assert!(set.window.len() <= WINDOW_SIZE);
+
+        let found = set.feed(b"needle");
+        assert_eq!(found, vec![(id, filler.len() as u64, b"needle".to_vec())]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_match_events_from_read_path() {
+        use crate::events::{portable_pty_event_free, portable_pty_next_event, PortablePtyEvent};
+        use crate::tests::{open_and_spawn, read_string};
+        use crate::{portable_pty_close, PortablePtyBuffer};
+        use std::ffi::CString;
+
+        let handle = open_and_spawn("/bin/sh", &["sh", "-c", "sleep 0.2; echo one ERROR two"]);
+        let pattern = CString::new("ERROR").unwrap();
+        let mut id = 0;
+        let result = portable_pty_add_pattern(handle, pattern.as_ptr(), false, &mut id);
+        assert!(matches!(result, PortablePtyResult::Ok));
+
+        let output = read_string(handle);
+        assert!(output.contains("ERROR"), "unexpected output: {output:?}");
+
+        let mut event = PortablePtyEvent {
+            kind: 0,
+            id: 0,
+            value: 0,
+            data: PortablePtyBuffer::EMPTY,
+        };
+        assert!(portable_pty_next_event(handle, &mut event));
+        assert_eq!(event.kind, PORTABLE_PTY_EVENT_MATCH);
+        assert_eq!(event.id, id);
+        assert_eq!(event.value, 4);
+        portable_pty_event_free(&mut event);
+        assert!(!portable_pty_next_event(handle, &mut event));
+
+        portable_pty_close(handle);
+    }
+}
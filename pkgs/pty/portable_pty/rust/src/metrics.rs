@@ -0,0 +1,129 @@
+//! A snapshot of the whole library, for diagnostics.
+//!
+//! `portable_pty_metrics_json` reports across every open handle at once,
+//! for an app's diagnostics screen or a server's monitoring:
+//!
+//! | key            | value                                            |
+//! |----------------|--------------------------------------------------|
+//! | `handles`      | handles open                                     |
+//! | `children`     | their children, by state                         |
+//! | `output_bytes` | output read off every handle so far              |
+//! | `input_bytes`  | input written to every handle so far             |
+//! | `threads`      | background threads running                       |
+//! | `sigchld`      | the `SIGCHLD` handler's state on Unix; else null |
+//!
+//! `children` counts `running`, `stopped` and `exited`; a child counts as
+//! exited until its handle is closed. The byte counts include handles
+//! closed since. `sigchld` has `installed`, whether our handler is, and
+//! `tracked`, how many children it's following, of `capacity`.
+//!
+//! Children beyond the `SIGCHLD` registry's capacity still work, but their
+//! exit can be missed if the host reaps them first (see the crate docs),
+//! so `tracked` nearing `capacity` is worth watching.
+
+use crate::jobs::{
+    PORTABLE_PTY_CHILD_EXITED, PORTABLE_PTY_CHILD_RUNNING, PORTABLE_PTY_CHILD_STOPPED,
+};
+use crate::{PortablePty, PortablePtyBuffer, PortablePtyResult};
+use serde_json::{json, Value};
+
+/// What the handle's child is doing, as `portable_pty_child_state` would
+/// say but without reaping it; None if it has none.
+fn child_state(pty: &PortablePty) -> Option<u32> {
+    pty.child.as_ref()?;
+    #[cfg(unix)]
+    let state = if pty.child_exited() {
+        PORTABLE_PTY_CHILD_EXITED
+    } else if crate::lookup_stop_signal(pty.child_pid) != 0 {
+        PORTABLE_PTY_CHILD_STOPPED
+    } else {
+        PORTABLE_PTY_CHILD_RUNNING
+    };
+    #[cfg(not(unix))]
+    let state = if pty.cached_exit_code.is_some() {
+        PORTABLE_PTY_CHILD_EXITED
+    } else {
+        PORTABLE_PTY_CHILD_RUNNING
+    };
+    Some(state)
+}
+
+fn snapshot() -> Value {
+    let (mut handles, mut running, mut stopped, mut exited) = (0, 0, 0, 0);
+    crate::lifecycle::for_each_handle(|pty| {
+        handles += 1;
+        match child_state(pty) {
+            Some(PORTABLE_PTY_CHILD_RUNNING) => running += 1,
+            Some(PORTABLE_PTY_CHILD_STOPPED) => stopped += 1,
+            Some(_) => exited += 1,
+            None => {}
+        }
+    });
+    let (output_bytes, input_bytes) = crate::accounting::totals();
+
+    #[cfg(unix)]
+    let sigchld = {
+        let (tracked, installed, capacity) = crate::pid_registry_usage();
+        json!({ "installed": installed, "tracked": tracked, "capacity": capacity })
+    };
+    #[cfg(not(unix))]
+    let sigchld = Value::Null;
+
+    json!({
+        "handles": handles,
+        "children": { "running": running, "stopped": stopped, "exited": exited },
+        "output_bytes": output_bytes,
+        "input_bytes": input_bytes,
+        "threads": crate::lifecycle::thread_count(),
+        "sigchld": sigchld,
+    })
+}
+
+/// Take a snapshot of the whole library, across every open handle.
+///
+/// - `out_json`: receives a UTF-8 JSON object, to free with
+///   `portable_pty_buffer_free`: `handles` open; `children`, counting
+///   their children `running`, `stopped` and `exited` (until the handle
+///   is closed); `output_bytes` read and `input_bytes` written so far,
+///   closed handles included; background `threads` running; and on Unix
+///   `sigchld`, with `installed`, whether the library's handler is, and
+///   `tracked`, how many children it's following, of `capacity` (null
+///   elsewhere). Past `capacity`, a child's exit can be missed if the host
+///   reaps it first.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_metrics_json(out_json: *mut PortablePtyBuffer) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        if out_json.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+        let json = snapshot().to_string();
+        unsafe { *out_json = PortablePtyBuffer::from_vec(json.into_bytes()) };
+        PortablePtyResult::Ok
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics_json() {
+        let handle = crate::tests::open_and_spawn("sleep", &["sleep", "10"]);
+        let mut out = PortablePtyBuffer::from_vec(Vec::new());
+        let result = portable_pty_metrics_json(&mut out);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        let bytes = unsafe { std::slice::from_raw_parts(out.data, out.len) };
+        let metrics: Value = serde_json::from_slice(bytes).unwrap();
+        crate::portable_pty_buffer_free(out);
+
+        // Other tests' handles are open too.
+        assert!(metrics["handles"].as_u64().unwrap() >= 1, "{metrics}");
+        assert!(metrics["children"]["running"].as_u64().unwrap() >= 1);
+        assert!(metrics["threads"].is_u64());
+        let sigchld = &metrics["sigchld"];
+        assert!(sigchld["installed"].is_boolean());
+        assert!(sigchld["tracked"].as_u64().unwrap() >= 1);
+        assert!(sigchld["tracked"].as_u64() <= sigchld["capacity"].as_u64());
+        crate::portable_pty_close(handle);
+    }
+}
@@ -0,0 +1,566 @@
+//! Scripted mock backend for tests.
+//!
+//! `portable_pty_open_mock` opens a handle whose "child" follows a script
+//! instead of being a real process. The script is a JSON object:
+//!
+//! ```json
+//! {
+//!   "rows": 24, "cols": 80,
+//!   "steps": [
+//!     {"output": "$ "},
+//!     {"input": "ls\r", "timeout_ms": 1000},
+//!     {"output": "a.txt\r\n$ ", "delay_ms": 20},
+//!     {"exit": 0}
+//!   ]
+//! }
+//! ```
+//!
+//! Steps run in order, each after an optional `delay_ms`:
+//!
+//! - `output`: text the handle reads, as if the child printed it.
+//! - `input`: wait until the handle has written this text (anything written
+//!   before it is skipped over). If `timeout_ms` passes first, the script
+//!   ends and the child exits with status 124.
+//! - `exit`: end the script with this status.
+//!
+//! A script that runs out of steps exits with status 0; a killed one exits
+//! with 137. Reads then return EOF. Everything written to the handle is
+//! kept for `portable_pty_mock_take_input`, so tests can assert on what
+//! was sent.
+//! Output goes through the normal read path — expect, pattern events and
+//! mode tracking all see it — and with no real process involved the
+//! result doesn't depend on the machine it runs on.
+
+use crate::pty::{
+    Child, ChildKiller, CommandBuilder, ExitStatus, MasterPty, PtyPair, PtySize, SlavePty,
+};
+use crate::{PipeReader, PipeWriter, PortablePty, PortablePtyBuffer, PortablePtyResult};
+use std::ffi::{c_char, CStr};
+use std::io::{Read, Write};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Exit status when an `input` step times out, as with `timeout(1)`.
+const INPUT_TIMEOUT_STATUS: u32 = 124;
+
+/// Exit status after the child is killed, following the 128 + signal
+/// convention for `SIGKILL`.
+pub(crate) const KILLED_STATUS: u32 = 137;
+
+enum Action {
+    Output(Vec<u8>),
+    Input {
+        expected: Vec<u8>,
+        timeout: Option<Duration>,
+    },
+    Exit(u32),
+}
+
+struct Step {
+    delay: Duration,
+    action: Action,
+}
+
+struct Script {
+    size: PtySize,
+    steps: Vec<Step>,
+}
+
+fn parse_script(script: &serde_json::Value) -> Option<Script> {
+    let size = crate::backend::parse_size(script)?;
+
+    let mut steps = Vec::new();
+    for step in script.get("steps")?.as_array()? {
+        let millis = |key: &str| match step.get(key) {
+            Some(v) => v.as_u64().map(|ms| Some(Duration::from_millis(ms))),
+            None => Some(None),
+        };
+        let delay = millis("delay_ms")?.unwrap_or_default();
+        let action = if let Some(text) = step.get("output") {
+            Action::Output(text.as_str()?.as_bytes().to_vec())
+        } else if let Some(text) = step.get("input") {
+            Action::Input {
+                expected: text.as_str()?.as_bytes().to_vec(),
+                timeout: millis("timeout_ms")?,
+            }
+        } else if let Some(code) = step.get("exit") {
+            Action::Exit(code.as_u64()?.try_into().ok()?)
+        } else {
+            return None;
+        };
+        steps.push(Step { delay, action });
+    }
+    Some(Script { size, steps })
+}
+
+struct State {
+    /// Everything written to the handle since the last take.
+    captured: Vec<u8>,
+    /// Written bytes the script hasn't consumed with an `input` step yet.
+    unmatched: Vec<u8>,
+    exit: Option<ExitStatus>,
+    /// The script was stopped (handle closed or child killed).
+    stopped: bool,
+}
+
+/// State shared between the script thread and the handle.
+struct Shared {
+    state: Mutex<State>,
+    /// Signalled whenever `state` changes.
+    changed: Condvar,
+    size: Mutex<PtySize>,
+}
+
+impl Shared {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn update(&self, f: impl FnOnce(&mut State)) {
+        f(&mut self.state());
+        self.changed.notify_all();
+    }
+
+    fn stop(&self) {
+        self.update(|s| {
+            s.stopped = true;
+            s.exit
+                .get_or_insert_with(|| ExitStatus::with_exit_code(KILLED_STATUS));
+        });
+    }
+
+    /// Block until `ready` holds or `deadline` passes. Returns the guard
+    /// and whether `ready` held.
+    fn wait_for(
+        &self,
+        deadline: Option<Instant>,
+        mut ready: impl FnMut(&mut State) -> bool,
+    ) -> (MutexGuard<'_, State>, bool) {
+        let mut state = self.state();
+        loop {
+            if ready(&mut state) {
+                return (state, true);
+            }
+            state = match deadline {
+                None => self.changed.wait(state),
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left.is_zero() {
+                        return (state, false);
+                    }
+                    self.changed
+                        .wait_timeout(state, left)
+                        .map(|(guard, _)| guard)
+                        .map_err(|e| PoisonError::new(e.into_inner().0))
+                }
+            }
+            .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+/// Run `steps`, writing their output into `out`.
+fn run(shared: Arc<Shared>, steps: Vec<Step>, mut out: PipeWriter) {
+    // If the handle goes away mid-write, get EPIPE rather than a
+    // process-wide SIGPIPE.
+    #[cfg(unix)]
+    crate::block_sigpipe();
+
+    let mut status = 0;
+    for step in steps {
+        if !step.delay.is_zero() {
+            let (state, _) = shared.wait_for(Some(Instant::now() + step.delay), |s| s.stopped);
+            if state.stopped {
+                return;
+            }
+        }
+        match step.action {
+            Action::Output(bytes) => {
+                if out.write_all(&bytes).is_err() {
+                    break;
+                }
+            }
+            Action::Input { expected, timeout } => {
+                let deadline = timeout.map(|t| Instant::now() + t);
+                let (mut state, matched) = shared.wait_for(deadline, |s| {
+                    s.stopped || find(&s.unmatched, &expected).is_some()
+                });
+                if state.stopped {
+                    return;
+                }
+                if !matched {
+                    status = INPUT_TIMEOUT_STATUS;
+                    break;
+                }
+                let end = find(&state.unmatched, &expected).unwrap() + expected.len();
+                state.unmatched.drain(..end);
+            }
+            Action::Exit(code) => {
+                status = code;
+                break;
+            }
+        }
+    }
+
+    // Dropping `out` gives the reader EOF.
+    drop(out);
+    shared.update(|s| {
+        s.exit
+            .get_or_insert_with(|| ExitStatus::with_exit_code(status));
+    });
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if needle.is_empty() {
+        return Some(0);
+    }
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+struct MockMaster {
+    shared: Arc<Shared>,
+    reader: PipeReader,
+}
+
+impl Drop for MockMaster {
+    fn drop(&mut self) {
+        self.shared.stop();
+    }
+}
+
+impl MasterPty for MockMaster {
+    fn resize(&self, size: PtySize) -> anyhow::Result<()> {
+        *self
+            .shared
+            .size
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = size;
+        Ok(())
+    }
+
+    fn get_size(&self) -> anyhow::Result<PtySize> {
+        Ok(*self
+            .shared
+            .size
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner))
+    }
+
+    fn try_clone_reader(&self) -> anyhow::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(self.reader.try_clone()?))
+    }
+
+    fn take_writer(&self) -> anyhow::Result<Box<dyn Write + Send>> {
+        Ok(Box::new(MockWriter {
+            shared: Arc::clone(&self.shared),
+        }))
+    }
+
+    #[cfg(unix)]
+    fn process_group_leader(&self) -> Option<libc::pid_t> {
+        None
+    }
+
+    #[cfg(unix)]
+    fn as_raw_fd(&self) -> Option<std::os::fd::RawFd> {
+        use std::os::fd::AsRawFd;
+        Some(self.reader.as_raw_fd())
+    }
+
+    #[cfg(unix)]
+    fn tty_name(&self) -> Option<std::path::PathBuf> {
+        None
+    }
+}
+
+/// Captures input for assertions and for the script's `input` steps.
+struct MockWriter {
+    shared: Arc<Shared>,
+}
+
+impl Write for MockWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.shared.update(|s| {
+            s.captured.extend_from_slice(buf);
+            s.unmatched.extend_from_slice(buf);
+        });
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+struct MockSlave;
+
+impl SlavePty for MockSlave {
+    fn spawn_command(&self, _cmd: CommandBuilder) -> anyhow::Result<Box<dyn Child + Send + Sync>> {
+        anyhow::bail!("mock handles can't spawn processes")
+    }
+}
+
+/// Stands in for a child process: exits when the script ends.
+#[derive(Clone)]
+struct MockChild {
+    shared: Arc<Shared>,
+}
+
+impl std::fmt::Debug for MockChild {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MockChild").finish_non_exhaustive()
+    }
+}
+
+impl ChildKiller for MockChild {
+    fn kill(&mut self) -> std::io::Result<()> {
+        self.shared.stop();
+        Ok(())
+    }
+
+    fn clone_killer(&self) -> Box<dyn ChildKiller + Send + Sync> {
+        Box::new(self.clone())
+    }
+}
+
+impl Child for MockChild {
+    fn try_wait(&mut self) -> std::io::Result<Option<ExitStatus>> {
+        Ok(self.shared.state().exit.clone())
+    }
+
+    fn wait(&mut self) -> std::io::Result<ExitStatus> {
+        let (state, _) = self.shared.wait_for(None, |s| s.exit.is_some());
+        Ok(state.exit.clone().unwrap())
+    }
+
+    fn process_id(&self) -> Option<u32> {
+        None
+    }
+
+    #[cfg(windows)]
+    fn as_raw_handle(&self) -> Option<std::os::windows::io::RawHandle> {
+        None
+    }
+}
+
+/// Parse `script` (see the module docs) and start running it.
+pub(crate) fn open_script(
+    script: &serde_json::Value,
+) -> Result<Box<PortablePty>, PortablePtyResult> {
+    open(parse_script(script).ok_or(PortablePtyResult::ErrOpen)?)
+}
+
+/// Start running `script` and wrap it in a handle.
+fn open(script: Script) -> Result<Box<PortablePty>, PortablePtyResult> {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            captured: Vec::new(),
+            unmatched: Vec::new(),
+            exit: None,
+            stopped: false,
+        }),
+        changed: Condvar::new(),
+        size: Mutex::new(script.size),
+    });
+    let (reader, writer) = crate::pipe().map_err(|_| PortablePtyResult::ErrOpen)?;
+
+    let pair = PtyPair {
+        slave: Box::new(MockSlave),
+        master: Box::new(MockMaster {
+            shared: Arc::clone(&shared),
+            reader,
+        }),
+    };
+    let mut handle = PortablePty::from_pair(pair)?;
+    handle.child = Some(Box::new(MockChild {
+        shared: Arc::clone(&shared),
+    }));
+
+    let steps = script.steps;
+    crate::lifecycle::spawn_thread("portable-pty-mock", move || run(shared, steps, writer))
+        .map_err(|_| PortablePtyResult::ErrOpen)?;
+    Ok(handle)
+}
+
+/// Open a handle whose child follows a JSON script instead of being a
+/// real process.
+///
+/// - `script`: null-terminated UTF-8 JSON: an object with optional `rows`
+///   and `cols` and a `steps` array, run in order, each after an optional
+///   `delay_ms`. `{"output": text}` gives the handle text to read.
+///   `{"input": text, "timeout_ms": n}` waits until the handle has written
+///   `text`, skipping anything written before it; on timeout the child
+///   exits with status 124. `{"exit": status}` ends the script.
+/// - `out`: receives the new handle; close it with `portable_pty_close`.
+///
+/// The script starts running immediately. Running out of steps exits with
+/// status 0, and killing the child with 137; reads then return EOF.
+/// Everything written is kept for `portable_pty_mock_take_input`. Returns
+/// `ErrOpen` if the script is malformed.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_open_mock(
+    script: *const c_char,
+    out: *mut *mut PortablePty,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        if script.is_null() || out.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+        match unsafe { CStr::from_ptr(script) }
+            .to_str()
+            .ok()
+            .and_then(|json| serde_json::from_str(json).ok())
+        {
+            Some(script) => match open_script(&script) {
+                Ok(handle) => {
+                    unsafe {
+                        *out = crate::lifecycle::register(handle);
+                    }
+                    PortablePtyResult::Ok
+                }
+                Err(e) => e,
+            },
+            None => PortablePtyResult::ErrOpen,
+        }
+    })
+}
+
+/// Take everything written to a mock handle since the last call.
+///
+/// - `out_input`: receives the bytes; free with `portable_pty_buffer_free`.
+///
+/// Returns `ErrBackend` if the handle isn't a mock.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_mock_take_input(
+    handle: *const PortablePty,
+    out_input: *mut PortablePtyBuffer,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        if out_input.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+        let master = match pty.master.as_ref().as_any().downcast_ref::<MockMaster>() {
+            Some(m) => m,
+            None => return PortablePtyResult::ErrBackend,
+        };
+        let input = std::mem::take(&mut master.shared.state().captured);
+        unsafe {
+            *out_input = PortablePtyBuffer::from_vec(input);
+        }
+        PortablePtyResult::Ok
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::tests::read_string;
+    use crate::{
+        portable_pty_buffer_free, portable_pty_close, portable_pty_kill, portable_pty_read,
+        portable_pty_wait_blocking, portable_pty_write,
+    };
+    use std::ffi::CString;
+
+    fn open_script(json: &str) -> *mut PortablePty {
+        let json = CString::new(json).unwrap();
+        let mut handle = std::ptr::null_mut();
+        let result = portable_pty_open_mock(json.as_ptr(), &mut handle);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        handle
+    }
+
+    fn read_to_end(handle: *mut PortablePty) -> String {
+        let mut out = String::new();
+        let mut buf = [0u8; 256];
+        loop {
+            let n = portable_pty_read(handle, buf.as_mut_ptr(), buf.len());
+            if n <= 0 {
+                break;
+            }
+            out.push_str(std::str::from_utf8(&buf[..n as usize]).unwrap());
+        }
+        out
+    }
+
+    fn wait_status(handle: *mut PortablePty) -> i32 {
+        let mut status = -1;
+        let result = portable_pty_wait_blocking(handle, &mut status);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        status
+    }
+
+    fn take_input(handle: *mut PortablePty) -> Vec<u8> {
+        let mut buffer = PortablePtyBuffer::EMPTY;
+        let result = portable_pty_mock_take_input(handle, &mut buffer);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        if buffer.len == 0 {
+            return Vec::new();
+        }
+        let bytes = unsafe { std::slice::from_raw_parts(buffer.data, buffer.len) }.to_vec();
+        portable_pty_buffer_free(buffer);
+        bytes
+    }
+
+    #[test]
+    fn test_script_drives_reads_and_captures_writes() {
+        let handle = open_script(
+            r#"{"steps": [
+                {"output": "$ "},
+                {"input": "ls\r", "timeout_ms": 5000},
+                {"output": "a.txt\r\n", "delay_ms": 10},
+                {"exit": 3}
+            ]}"#,
+        );
+        assert_eq!(read_string(handle), "$ ");
+
+        for chunk in ["echo hi\r", "ls\r"] {
+            let n = portable_pty_write(handle, chunk.as_ptr(), chunk.len());
+            assert_eq!(n, chunk.len() as i64);
+        }
+        assert_eq!(read_to_end(handle), "a.txt\r\n");
+        assert_eq!(wait_status(handle), 3);
+        assert_eq!(take_input(handle), b"echo hi\rls\r");
+        assert!(take_input(handle).is_empty());
+        portable_pty_close(handle);
+    }
+
+    #[test]
+    fn test_input_timeout_ends_script() {
+        let handle = open_script(
+            r#"{"steps": [{"input": "y", "timeout_ms": 50}, {"output": "unreachable"}]}"#,
+        );
+        assert_eq!(read_to_end(handle), "");
+        assert_eq!(wait_status(handle), INPUT_TIMEOUT_STATUS as i32);
+        portable_pty_close(handle);
+    }
+
+    #[test]
+    fn test_kill_stops_script() {
+        let handle = open_script(r#"{"steps": [{"output": "late", "delay_ms": 60000}]}"#);
+        let result = portable_pty_kill(handle, libc::SIGKILL);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        assert_eq!(read_to_end(handle), "");
+        assert_eq!(wait_status(handle), KILLED_STATUS as i32);
+        portable_pty_close(handle);
+    }
+
+    #[test]
+    fn test_rejects_bad_scripts_and_live_handles() {
+        for script in ["", "{}", r#"{"steps": [{"sleep": 1}]}"#] {
+            let script = CString::new(script).unwrap();
+            let mut handle = std::ptr::null_mut();
+            let result = portable_pty_open_mock(script.as_ptr(), &mut handle);
+            assert!(matches!(result, PortablePtyResult::ErrOpen));
+        }
+
+        let handle = crate::tests::open_and_spawn("/bin/echo", &["echo"]);
+        let mut buffer = PortablePtyBuffer::EMPTY;
+        let result = portable_pty_mock_take_input(handle, &mut buffer);
+        assert!(matches!(result, PortablePtyResult::ErrBackend));
+        portable_pty_close(handle);
+    }
+}
@@ -0,0 +1,434 @@
+//! Terminal mode tracking.
+//!
+//! A small incremental scanner over the child's output that follows the
+//! DEC private modes an embedder needs in order to encode input correctly.
+//! It is not a terminal emulator: everything other than mode set/reset
+//! sequences is skipped, and string sequences (OSC, DCS, …) are consumed
+//! without interpretation so their payloads can't be mistaken for controls.
+//!
+//! The one string sequence that *is* interpreted is OSC 133 (the FinalTerm
+//! "semantic prompt" marks emitted by shells with terminal integration),
+//! which `feed` reports back so callers can tell when the shell is sitting
+//! at a prompt and when a command has finished.
+
+/// Upper bound on CSI parameters we keep; extra parameters are dropped.
+const MAX_PARAMS: usize = 16;
+
+/// OSC payload bytes kept for interpretation; longer payloads (titles,
+/// hyperlinks, clipboard data) are skipped without being retained.
+const MAX_OSC_PAYLOAD: usize = 64;
+
+/// An OSC 133 shell-integration mark.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum PromptMark {
+    /// `A` — the prompt is about to be drawn.
+    PromptStart,
+    /// `B` — the prompt ended; the user is typing a command.
+    CommandStart,
+    /// `C` — the command was submitted and its output follows.
+    OutputStart,
+    /// `D[;status]` — the command finished, with its exit status if given.
+    CommandEnd(Option<i32>),
+}
+
+/// A mark seen by `feed`, with the span of its sequence in that chunk.
+///
+/// `start` is 0 when the sequence began in an earlier chunk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct MarkAt {
+    pub mark: PromptMark,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Mouse reporting level requested by the child (DECSET 9/1000/1002/1003).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum MouseTracking {
+    #[default]
+    Off,
+    /// `?9` — button presses only, no modifiers.
+    X10,
+    /// `?1000` — presses and releases.
+    Normal,
+    /// `?1002` — adds motion while a button is held.
+    ButtonEvent,
+    /// `?1003` — adds all motion.
+    AnyEvent,
+}
+
+/// Coordinate encoding requested by the child (DECSET 1005/1006/1015).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum MouseEncoding {
+    #[default]
+    Default,
+    Utf8,
+    Sgr,
+    Urxvt,
+}
+
+/// Snapshot of the modes tracked so far.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Modes {
+    pub mouse_tracking: MouseTracking,
+    pub mouse_encoding: MouseEncoding,
+    /// `?1` (DECCKM) — cursor keys send SS3 sequences.
+    pub application_cursor: bool,
+    /// `ESC =` (DECKPAM) or `?66` — the keypad sends SS3 sequences.
+    pub application_keypad: bool,
+    /// `?47`, `?1047` or `?1049` — the alternate screen is showing.
+    pub alternate_screen: bool,
+}
+
+impl Modes {
+    fn set_private(&mut self, mode: u16, enabled: bool) {
+        match mode {
+            1 => {
+                self.application_cursor = enabled;
+                return;
+            }
+            66 => {
+                self.application_keypad = enabled;
+                return;
+            }
+            47 | 1047 | 1049 => {
+                self.alternate_screen = enabled;
+                return;
+            }
+            _ => {}
+        }
+        let tracking = match mode {
+            9 => Some(MouseTracking::X10),
+            1000 => Some(MouseTracking::Normal),
+            1002 => Some(MouseTracking::ButtonEvent),
+            1003 => Some(MouseTracking::AnyEvent),
+            _ => None,
+        };
+        if let Some(tracking) = tracking {
+            // Like xterm, resetting any tracking mode turns reporting off.
+            self.mouse_tracking = if enabled {
+                tracking
+            } else {
+                MouseTracking::Off
+            };
+            return;
+        }
+
+        let encoding = match mode {
+            1005 => Some(MouseEncoding::Utf8),
+            1006 => Some(MouseEncoding::Sgr),
+            1015 => Some(MouseEncoding::Urxvt),
+            _ => None,
+        };
+        if let Some(encoding) = encoding {
+            if enabled {
+                self.mouse_encoding = encoding;
+            } else if self.mouse_encoding == encoding {
+                self.mouse_encoding = MouseEncoding::Default;
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    Csi,
+    /// Inside OSC/DCS/APC/PM/SOS; runs until BEL or ST.
+    String,
+    /// Saw ESC inside a string; `\` terminates it.
+    StringEscape,
+}
+
+/// Incremental parser state. Sequences may be split across reads.
+#[derive(Debug)]
+pub(crate) struct ModeTracker {
+    state: State,
+    private: bool,
+    intermediate: bool,
+    params: Vec<u16>,
+    current: Option<u16>,
+    /// OSC payload collected so far; `None` for other strings or overflow.
+    osc: Option<Vec<u8>>,
+    /// Index in the current chunk of the ESC that began the sequence.
+    seq_start: usize,
+    modes: Modes,
+}
+
+impl Default for ModeTracker {
+    fn default() -> Self {
+        ModeTracker {
+            state: State::Ground,
+            private: false,
+            intermediate: false,
+            params: Vec::with_capacity(MAX_PARAMS),
+            current: None,
+            osc: None,
+            seq_start: 0,
+            modes: Modes::default(),
+        }
+    }
+}
+
+impl ModeTracker {
+    pub(crate) fn modes(&self) -> Modes {
+        self.modes
+    }
+
+    /// Feed a chunk of child output through the scanner, returning any
+    /// prompt marks it contained.
+    pub(crate) fn feed(&mut self, bytes: &[u8]) -> Vec<MarkAt> {
+        let mut marks = Vec::new();
+        self.seq_start = 0;
+        for (i, &b) in bytes.iter().enumerate() {
+            if let Some(mark) = self.advance(b) {
+                marks.push(MarkAt {
+                    mark,
+                    start: self.seq_start,
+                    end: i + 1,
+                });
+            }
+            if self.state == State::Escape && b == 0x1b {
+                self.seq_start = i;
+            }
+        }
+        marks
+    }
+
+    fn advance(&mut self, b: u8) -> Option<PromptMark> {
+        match self.state {
+            State::Ground => {
+                if b == 0x1b {
+                    self.state = State::Escape;
+                }
+            }
+            State::Escape => match b {
+                b'[' => {
+                    self.private = false;
+                    self.intermediate = false;
+                    self.params.clear();
+                    self.current = None;
+                    self.state = State::Csi;
+                }
+                b']' => {
+                    self.osc = Some(Vec::new());
+                    self.state = State::String;
+                }
+                b'P' | b'X' | b'^' | b'_' => {
+                    self.osc = None;
+                    self.state = State::String;
+                }
+                b'c' => {
+                    // RIS: full reset.
+                    self.modes = Modes::default();
+                    self.state = State::Ground;
+                }
+                b'=' | b'>' => {
+                    // DECKPAM / DECKPNM.
+                    self.modes.application_keypad = b == b'=';
+                    self.state = State::Ground;
+                }
+                0x1b => {}
+                _ => self.state = State::Ground,
+            },
+            State::Csi => match b {
+                b'0'..=b'9' => {
+                    let digit = u16::from(b - b'0');
+                    let value = self.current.unwrap_or(0);
+                    self.current = Some(value.saturating_mul(10).saturating_add(digit));
+                }
+                b';' | b':' => self.push_param(),
+                b'?' if self.params.is_empty() && self.current.is_none() => {
+                    self.private = true;
+                }
+                0x3c..=0x3f => {
+                    // Other private markers (`<`, `=`, `>`) — not DEC modes.
+                    self.intermediate = true;
+                }
+                0x20..=0x2f => self.intermediate = true,
+                0x40..=0x7e => {
+                    self.push_param();
+                    self.dispatch_csi(b);
+                    self.state = State::Ground;
+                }
+                0x1b => self.state = State::Escape,
+                0x18 | 0x1a => self.state = State::Ground,
+                _ => {}
+            },
+            State::String => match b {
+                0x07 => {
+                    self.state = State::Ground;
+                    return self.dispatch_osc();
+                }
+                0x1b => self.state = State::StringEscape,
+                0x18 | 0x1a => self.state = State::Ground,
+                _ => {
+                    if let Some(payload) = self.osc.as_mut() {
+                        if payload.len() < MAX_OSC_PAYLOAD {
+                            payload.push(b);
+                        } else {
+                            self.osc = None;
+                        }
+                    }
+                }
+            },
+            State::StringEscape => {
+                if b == b'\\' {
+                    self.state = State::Ground;
+                    return self.dispatch_osc();
+                }
+                self.state = State::String;
+            }
+        }
+        None
+    }
+
+    fn dispatch_osc(&mut self) -> Option<PromptMark> {
+        let payload = self.osc.take()?;
+        let rest = payload.strip_prefix(b"133;")?;
+        let (kind, args) = match rest.split_first()? {
+            (kind, [b';', args @ ..]) => (*kind, Some(args)),
+            (kind, _) => (*kind, None),
+        };
+        match kind {
+            b'A' => Some(PromptMark::PromptStart),
+            b'B' => Some(PromptMark::CommandStart),
+            b'C' => Some(PromptMark::OutputStart),
+            b'D' => {
+                let status = args
+                    .map(|a| a.split(|&c| c == b';').next().unwrap_or(a))
+                    .and_then(|a| std::str::from_utf8(a).ok())
+                    .and_then(|a| a.parse().ok());
+                Some(PromptMark::CommandEnd(status))
+            }
+            _ => None,
+        }
+    }
+
+    fn push_param(&mut self) {
+        if self.params.len() < MAX_PARAMS {
+            self.params.push(self.current.unwrap_or(0));
+        }
+        self.current = None;
+    }
+
+    fn dispatch_csi(&mut self, action: u8) {
+        if !self.private || self.intermediate {
+            return;
+        }
+        let enabled = match action {
+            b'h' => true,
+            b'l' => false,
+            _ => return,
+        };
+        for &mode in &self.params {
+            self.modes.set_private(mode, enabled);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tracks_mouse_modes() {
+        let mut tracker = ModeTracker::default();
+        tracker.feed(b"hello\x1b[?1000;1006h world");
+        let modes = tracker.modes();
+        assert_eq!(modes.mouse_tracking, MouseTracking::Normal);
+        assert_eq!(modes.mouse_encoding, MouseEncoding::Sgr);
+
+        tracker.feed(b"\x1b[?1003h");
+        assert_eq!(tracker.modes().mouse_tracking, MouseTracking::AnyEvent);
+
+        tracker.feed(b"\x1b[?1000l\x1b[?1015l");
+        let modes = tracker.modes();
+        assert_eq!(modes.mouse_tracking, MouseTracking::Off);
+        assert_eq!(modes.mouse_encoding, MouseEncoding::Sgr);
+    }
+
+    #[test]
+    fn test_tracks_key_modes() {
+        let mut tracker = ModeTracker::default();
+        tracker.feed(b"\x1b[?1h\x1b=");
+        let modes = tracker.modes();
+        assert!(modes.application_cursor && modes.application_keypad);
+
+        tracker.feed(b"\x1b[?1l\x1b>");
+        let modes = tracker.modes();
+        assert!(!modes.application_cursor && !modes.application_keypad);
+
+        tracker.feed(b"\x1b[?66h");
+        assert!(tracker.modes().application_keypad);
+    }
+
+    #[test]
+    fn test_tracks_alternate_screen() {
+        let mut tracker = ModeTracker::default();
+        tracker.feed(b"\x1b[?1049h");
+        assert!(tracker.modes().alternate_screen);
+        tracker.feed(b"\x1b[?1049l\x1b[?47h");
+        assert!(tracker.modes().alternate_screen);
+        tracker.feed(b"\x1b[?1047l");
+        assert!(!tracker.modes().alternate_screen);
+    }
+
+    #[test]
+    fn test_sequences_split_across_reads() {
+        let mut tracker = ModeTracker::default();
+        for chunk in [&b"\x1b"[..], b"[?10", b"02", b"h"] {
+            tracker.feed(chunk);
+        }
+        assert_eq!(tracker.modes().mouse_tracking, MouseTracking::ButtonEvent);
+    }
+
+    #[test]
+    fn test_ignores_strings_and_non_private_csi() {
+        let mut tracker = ModeTracker::default();
+        // Mode sequences embedded in an OSC title, and ANSI (non-DEC) SM.
+        tracker.feed(b"\x1b]0;\x1b[?1000h\x07\x1b[1000h");
+        assert_eq!(tracker.modes().mouse_tracking, MouseTracking::Off);
+
+        tracker.feed(b"\x1b[?1000h\x1bc");
+        assert_eq!(tracker.modes(), Modes::default());
+    }
+
+    #[test]
+    fn test_reports_prompt_marks() {
+        let mut tracker = ModeTracker::default();
+        let marks = tracker.feed(b"out\x1b]133;D;2\x07\x1b]133;A\x1b\\$ ");
+        assert_eq!(
+            marks,
+            vec![
+                MarkAt {
+                    mark: PromptMark::CommandEnd(Some(2)),
+                    start: 3,
+                    end: 13,
+                },
+                MarkAt {
+                    mark: PromptMark::PromptStart,
+                    start: 13,
+                    end: 22,
+                },
+            ]
+        );
+
+        // Split mark: starts in the previous chunk.
+        assert!(tracker.feed(b"ls\x1b]13").is_empty());
+        let marks = tracker.feed(b"3;C\x07x");
+        assert_eq!(
+            marks,
+            vec![MarkAt {
+                mark: PromptMark::OutputStart,
+                start: 0,
+                end: 4,
+            }]
+        );
+
+        // Other OSCs and a bare D.
+        let marks = tracker.feed(b"\x1b]0;title\x07\x1b]133;D\x07");
+        assert_eq!(marks[0].mark, PromptMark::CommandEnd(None));
+        assert_eq!(marks.len(), 1);
+    }
+}
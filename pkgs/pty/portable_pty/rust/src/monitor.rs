@@ -0,0 +1,403 @@
+//! Watching the child's resource use.
+//!
+//! `portable_pty_monitor` samples the child's resident memory and CPU time
+//! on a background thread and posts a `PORTABLE_PTY_EVENT_RESOURCE` event
+//! when either goes over its threshold, so a hosted terminal can warn
+//! about a runaway process or kill it. An event fires on the way over, not
+//! on every sample above: a threshold has to be dropped back under before
+//! it fires again. CPU use is a percentage of one core averaged since the
+//! previous sample, so a process busy on several cores goes over 100.
+//!
+//! Only the child itself is measured, not processes it has started.
+//! Sampling works on Linux, Android, macOS, iOS and Windows;
+//! `portable_pty_resource_usage` takes a single sample on demand.
+
+use crate::lifecycle::{HandleRef, ThreadGroup};
+use crate::{PortablePty, PortablePtyResult};
+use std::io;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+/// `id` of a resource event for resident memory (`value` in bytes).
+pub const PORTABLE_PTY_RESOURCE_RSS: u64 = 1;
+/// `id` of a resource event for CPU use (`value` in percent of a core).
+pub const PORTABLE_PTY_RESOURCE_CPU: u64 = 2;
+
+/// Sampling interval when the caller passes 0.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(1);
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// One sample of a process's resource use.
+#[derive(Clone, Copy)]
+struct Usage {
+    /// Resident memory in bytes.
+    rss: u64,
+    /// User plus system CPU time since the process started.
+    cpu: Duration,
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn sample(pid: i32) -> io::Result<Usage> {
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/stat"))?;
+    // The command name may contain anything, spaces and parentheses
+    // included; the fields after it don't.
+    let fields: Vec<&str> = stat
+        .rsplit_once(')')
+        .ok_or(io::ErrorKind::InvalidData)?
+        .1
+        .split_whitespace()
+        .collect();
+    // Counting from the state, field 3 in proc(5).
+    let field = |n: usize| -> io::Result<u64> {
+        fields
+            .get(n - 3)
+            .and_then(|f| f.parse().ok())
+            .ok_or(io::ErrorKind::InvalidData.into())
+    };
+    let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as u64;
+    let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(1) as u64;
+    let cpu = field(14)? + field(15)?;
+    Ok(Usage {
+        rss: field(24)? * page,
+        cpu: Duration::from_nanos(cpu * 1_000_000_000 / ticks),
+    })
+}
+
+// libc would have the Mach timebase taken from the mach2 crate; it's the
+// only thing used from it.
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+#[allow(deprecated)]
+fn sample(pid: i32) -> io::Result<Usage> {
+    let mut info: libc::proc_taskinfo = unsafe { std::mem::zeroed() };
+    let size = std::mem::size_of::<libc::proc_taskinfo>() as libc::c_int;
+    let n = unsafe {
+        libc::proc_pidinfo(
+            pid,
+            libc::PROC_PIDTASKINFO,
+            0,
+            &mut info as *mut _ as *mut libc::c_void,
+            size,
+        )
+    };
+    if n != size {
+        return Err(io::Error::last_os_error());
+    }
+    // CPU times are in Mach time units, which are only nanoseconds on
+    // Intel.
+    let mut timebase = libc::mach_timebase_info { numer: 0, denom: 0 };
+    unsafe { libc::mach_timebase_info(&mut timebase) };
+    let units = (info.pti_total_user + info.pti_total_system) as u128;
+    let nanos = units * timebase.numer.max(1) as u128 / timebase.denom.max(1) as u128;
+    Ok(Usage {
+        rss: info.pti_resident_size,
+        cpu: Duration::from_nanos(nanos as u64),
+    })
+}
+
+#[cfg(windows)]
+fn sample(pid: i32) -> io::Result<Usage> {
+    use std::os::windows::io::{AsRawHandle, FromRawHandle, OwnedHandle};
+    use winapi::shared::minwindef::FILETIME;
+    use winapi::um::processthreadsapi::{GetProcessTimes, OpenProcess};
+    use winapi::um::psapi::{GetProcessMemoryInfo, PROCESS_MEMORY_COUNTERS};
+    use winapi::um::winnt::{PROCESS_QUERY_LIMITED_INFORMATION, PROCESS_VM_READ};
+
+    let process = unsafe {
+        OpenProcess(
+            PROCESS_QUERY_LIMITED_INFORMATION | PROCESS_VM_READ,
+            0,
+            pid as u32,
+        )
+    };
+    if process.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    let process = unsafe { OwnedHandle::from_raw_handle(process as _) };
+    let raw = process.as_raw_handle() as _;
+
+    let mut times: [FILETIME; 4] = unsafe { std::mem::zeroed() };
+    let [created, exited, kernel, user] = &mut times;
+    if unsafe { GetProcessTimes(raw, created, exited, kernel, user) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut counters: PROCESS_MEMORY_COUNTERS = unsafe { std::mem::zeroed() };
+    let size = std::mem::size_of::<PROCESS_MEMORY_COUNTERS>() as u32;
+    if unsafe { GetProcessMemoryInfo(raw, &mut counters, size) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // FILETIMEs count 100ns intervals.
+    let ticks = |t: &FILETIME| (t.dwHighDateTime as u64) << 32 | t.dwLowDateTime as u64;
+    Ok(Usage {
+        rss: counters.WorkingSetSize as u64,
+        cpu: Duration::from_nanos((ticks(kernel) + ticks(user)) * 100),
+    })
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    windows
+)))]
+fn sample(_pid: i32) -> io::Result<Usage> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Thresholds, 0 where unused.
+#[derive(Clone, Copy)]
+struct Thresholds {
+    rss: u64,
+    cpu: u32,
+}
+
+/// Note `value` against `threshold` (0 for none), returning true if it
+/// has just gone over. `over` is whether the last value was.
+fn crossed(over: &mut bool, value: u64, threshold: u64) -> bool {
+    let was = std::mem::replace(over, threshold > 0 && value > threshold);
+    *over && !was
+}
+
+/// A running monitor, kept on the handle.
+pub(crate) struct Monitor {
+    /// Dropped to stop the thread.
+    stop: Sender<()>,
+    threads: Arc<ThreadGroup>,
+}
+
+impl Monitor {
+    fn stop(self) {
+        drop(self.stop);
+        self.threads.wait();
+    }
+}
+
+/// Sample `pid` every `interval` until stopped or the process is gone.
+fn watch(
+    handle: HandleRef,
+    pid: i32,
+    interval: Duration,
+    thresholds: Thresholds,
+    stopped: mpsc::Receiver<()>,
+) {
+    let pty = handle.get();
+    let (mut rss_over, mut cpu_over) = (false, false);
+    let mut previous: Option<(Instant, Duration)> = None;
+    loop {
+        let Ok(usage) = sample(pid) else {
+            return;
+        };
+        let now = Instant::now();
+        if crossed(&mut rss_over, usage.rss, thresholds.rss) {
+            post(pty, PORTABLE_PTY_RESOURCE_RSS, usage.rss);
+        }
+        if let Some((then, cpu)) = previous {
+            let wall = now.duration_since(then).as_secs_f64();
+            let busy = usage.cpu.saturating_sub(cpu).as_secs_f64();
+            let percent = if wall > 0.0 { busy * 100.0 / wall } else { 0.0 };
+            let percent = percent.round() as u64;
+            if crossed(&mut cpu_over, percent, thresholds.cpu.into()) {
+                post(pty, PORTABLE_PTY_RESOURCE_CPU, percent);
+            }
+        }
+        previous = Some((now, usage.cpu));
+
+        match stopped.recv_timeout(interval) {
+            Err(RecvTimeoutError::Timeout) => {}
+            _ => return,
+        }
+    }
+}
+
+fn post(pty: &PortablePty, id: u64, value: u64) {
+    let value = i64::try_from(value).unwrap_or(i64::MAX);
+    pty.events.post(
+        crate::events::PORTABLE_PTY_EVENT_RESOURCE,
+        id,
+        value,
+        Vec::new(),
+    );
+}
+
+/// Stop monitoring the handle, if it is.
+pub(crate) fn stop(pty: &PortablePty) {
+    // Out of the lock: the thread may be posting an event through it.
+    let monitor = lock(&pty.monitor).take();
+    if let Some(monitor) = monitor {
+        monitor.stop();
+    }
+}
+
+/// Watch the child's resource use, posting `PORTABLE_PTY_EVENT_RESOURCE`
+/// events when it goes over a threshold.
+///
+/// - `interval_ms`: time between samples; 0 for one second.
+/// - `max_rss_bytes`: resident memory threshold, or 0 for none.
+/// - `max_cpu_percent`: CPU threshold in percent of one core, or 0 for
+///   none.
+///
+/// Replaces any monitor already running on the handle. It stops by itself
+/// once the child is gone. Returns `ErrWait` if no child has been spawned
+/// and `ErrUnsupported` where the child can't be sampled.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_monitor(
+    handle: *const PortablePty,
+    interval_ms: u32,
+    max_rss_bytes: u64,
+    max_cpu_percent: u32,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        if pty.child_pid <= 0 {
+            return PortablePtyResult::ErrWait;
+        }
+        if let Err(e) = sample(pty.child_pid) {
+            if e.kind() == io::ErrorKind::Unsupported {
+                return PortablePtyResult::ErrUnsupported;
+            }
+        }
+        stop(pty);
+
+        let interval = match interval_ms {
+            0 => DEFAULT_INTERVAL,
+            ms => Duration::from_millis(ms.into()),
+        };
+        let thresholds = Thresholds {
+            rss: max_rss_bytes,
+            cpu: max_cpu_percent,
+        };
+        let (stop, stopped) = mpsc::channel();
+        let threads: Arc<ThreadGroup> = Arc::default();
+        let handle = HandleRef::new(pty);
+        let pid = pty.child_pid;
+        let name = crate::lifecycle::thread_name(pty, "monitor");
+        if threads
+            .spawn(&name, move || {
+                watch(handle, pid, interval, thresholds, stopped)
+            })
+            .is_err()
+        {
+            return PortablePtyResult::ErrUnsupported;
+        }
+        *lock(&pty.monitor) = Some(Monitor { stop, threads });
+        PortablePtyResult::Ok
+    })
+}
+
+/// Stop watching the child's resource use. Harmless if the handle isn't
+/// being monitored.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_monitor_stop(handle: *const PortablePty) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        stop(pty);
+        PortablePtyResult::Ok
+    })
+}
+
+/// Sample the child's resource use now.
+///
+/// - `out_rss_bytes`: receives its resident memory; may be NULL.
+/// - `out_cpu_ms`: receives the CPU time it has used, user and system,
+///   in milliseconds; may be NULL.
+///
+/// Returns `ErrWait` if no child has been spawned or it has been reaped,
+/// and `ErrUnsupported` where the child can't be sampled.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_resource_usage(
+    handle: *const PortablePty,
+    out_rss_bytes: *mut u64,
+    out_cpu_ms: *mut u64,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        if pty.child_pid <= 0 {
+            return PortablePtyResult::ErrWait;
+        }
+        let usage = match sample(pty.child_pid) {
+            Ok(usage) => usage,
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+                return PortablePtyResult::ErrUnsupported;
+            }
+            Err(_) => return PortablePtyResult::ErrWait,
+        };
+        unsafe {
+            if let Some(out) = out_rss_bytes.as_mut() {
+                *out = usage.rss;
+            }
+            if let Some(out) = out_cpu_ms.as_mut() {
+                *out = usage.cpu.as_millis() as u64;
+            }
+        }
+        PortablePtyResult::Ok
+    })
+}
+
+#[cfg(all(test, any(target_os = "linux", target_os = "macos")))]
+mod tests {
+    use super::*;
+    use crate::events::{portable_pty_next_event, PortablePtyEvent, PORTABLE_PTY_EVENT_RESOURCE};
+    use crate::tests::open_and_spawn;
+
+    /// Poll for a resource event with `id`, for up to five seconds.
+    fn next_resource_event(handle: *mut PortablePty, id: u64) -> Option<i64> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            let mut event: PortablePtyEvent = unsafe { std::mem::zeroed() };
+            if portable_pty_next_event(handle, &mut event) {
+                crate::events::portable_pty_event_free(&mut event);
+                if event.kind == PORTABLE_PTY_EVENT_RESOURCE && event.id == id {
+                    return Some(event.value);
+                }
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        None
+    }
+
+    #[test]
+    fn test_reports_usage_over_thresholds() {
+        let handle = open_and_spawn("sh", &["sh", "-c", "while :; do :; done"]);
+        // Nothing is resident the moment the child has exec'd.
+        let (mut rss, mut cpu) = (0, 0);
+        for _ in 0..100 {
+            let result = portable_pty_resource_usage(handle, &mut rss, &mut cpu);
+            assert!(matches!(result, PortablePtyResult::Ok));
+            if rss > 0 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(rss > 0);
+
+        let result = portable_pty_monitor(handle, 50, 1, 10);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        assert!(next_resource_event(handle, PORTABLE_PTY_RESOURCE_RSS).unwrap() > 1);
+        assert!(next_resource_event(handle, PORTABLE_PTY_RESOURCE_CPU).unwrap() > 10);
+        crate::portable_pty_close(handle);
+    }
+
+    #[test]
+    fn test_thresholds_fire_once_per_crossing() {
+        let mut over = false;
+        assert!(!crossed(&mut over, 5, 10));
+        assert!(crossed(&mut over, 11, 10));
+        assert!(!crossed(&mut over, 12, 10));
+        assert!(!crossed(&mut over, 9, 10));
+        assert!(crossed(&mut over, 11, 10));
+        assert!(!crossed(&mut over, 11, 0));
+    }
+}
@@ -0,0 +1,484 @@
+//! Mouse event encoding.
+//!
+//! Turns a UI-level mouse event into the bytes the child expects, based on
+//! the mouse tracking and coordinate-encoding modes it has requested (see
+//! [`crate::modes`]). Events the child hasn't asked for encode to nothing.
+//!
+//! With alternate scroll on (`portable_pty_set_alternate_scroll`), a wheel
+//! event over a child on the alternate screen that isn't reporting the
+//! mouse encodes to the arrow key it points at instead, as xterm does, so
+//! the wheel scrolls less, man and vim rather than nothing.
+
+use crate::keys::{
+    PORTABLE_PTY_KEY_DOWN, PORTABLE_PTY_KEY_LEFT, PORTABLE_PTY_KEY_RIGHT, PORTABLE_PTY_KEY_UP,
+};
+use crate::modes::{Modes, MouseEncoding, MouseTracking};
+use crate::{PortablePty, PortablePtyResult};
+use std::sync::atomic::Ordering;
+
+pub const PORTABLE_PTY_MOUSE_LEFT: u32 = 0;
+pub const PORTABLE_PTY_MOUSE_MIDDLE: u32 = 1;
+pub const PORTABLE_PTY_MOUSE_RIGHT: u32 = 2;
+/// No button held (motion-only events).
+pub const PORTABLE_PTY_MOUSE_NONE: u32 = 3;
+pub const PORTABLE_PTY_MOUSE_WHEEL_UP: u32 = 4;
+pub const PORTABLE_PTY_MOUSE_WHEEL_DOWN: u32 = 5;
+pub const PORTABLE_PTY_MOUSE_WHEEL_LEFT: u32 = 6;
+pub const PORTABLE_PTY_MOUSE_WHEEL_RIGHT: u32 = 7;
+
+pub const PORTABLE_PTY_MOUSE_PRESS: u32 = 0;
+pub const PORTABLE_PTY_MOUSE_RELEASE: u32 = 1;
+pub const PORTABLE_PTY_MOUSE_MOTION: u32 = 2;
+
+pub const PORTABLE_PTY_MOD_SHIFT: u32 = 1;
+pub const PORTABLE_PTY_MOD_ALT: u32 = 2;
+pub const PORTABLE_PTY_MOD_CTRL: u32 = 4;
+
+pub const PORTABLE_PTY_MOUSE_TRACKING_OFF: u32 = 0;
+pub const PORTABLE_PTY_MOUSE_TRACKING_X10: u32 = 1;
+pub const PORTABLE_PTY_MOUSE_TRACKING_NORMAL: u32 = 2;
+pub const PORTABLE_PTY_MOUSE_TRACKING_BUTTON_EVENT: u32 = 3;
+pub const PORTABLE_PTY_MOUSE_TRACKING_ANY_EVENT: u32 = 4;
+
+pub const PORTABLE_PTY_MOUSE_ENCODING_DEFAULT: u32 = 0;
+pub const PORTABLE_PTY_MOUSE_ENCODING_UTF8: u32 = 1;
+pub const PORTABLE_PTY_MOUSE_ENCODING_SGR: u32 = 2;
+pub const PORTABLE_PTY_MOUSE_ENCODING_URXVT: u32 = 3;
+
+/// Longest sequence `encode` can produce (SGR with two 5-digit coordinates).
+const MAX_SEQUENCE_LEN: usize = 32;
+
+/// Encode one mouse event for the given modes.
+///
+/// `x`/`y` are zero-based cell coordinates. Returns `None` when the event
+/// isn't reported in the current mode or can't be represented by the
+/// active encoding (e.g. coordinates past 223 in the default encoding).
+pub(crate) fn encode(
+    modes: Modes,
+    button: u32,
+    x: u32,
+    y: u32,
+    event: u32,
+    modifiers: u32,
+) -> Option<Vec<u8>> {
+    let tracking = modes.mouse_tracking;
+    let is_wheel = (PORTABLE_PTY_MOUSE_WHEEL_UP..=PORTABLE_PTY_MOUSE_WHEEL_RIGHT).contains(&button);
+
+    let reported = match (tracking, event) {
+        (MouseTracking::Off, _) => false,
+        (_, PORTABLE_PTY_MOUSE_PRESS) => button != PORTABLE_PTY_MOUSE_NONE,
+        (MouseTracking::X10, _) => false,
+        (_, PORTABLE_PTY_MOUSE_RELEASE) => !is_wheel,
+        (MouseTracking::ButtonEvent, PORTABLE_PTY_MOUSE_MOTION) => {
+            button != PORTABLE_PTY_MOUSE_NONE && !is_wheel
+        }
+        (MouseTracking::AnyEvent, PORTABLE_PTY_MOUSE_MOTION) => !is_wheel,
+        _ => false,
+    };
+    if !reported || button > PORTABLE_PTY_MOUSE_WHEEL_RIGHT {
+        return None;
+    }
+
+    let sgr = modes.mouse_encoding == MouseEncoding::Sgr;
+    let mut code = if is_wheel {
+        64 + (button - PORTABLE_PTY_MOUSE_WHEEL_UP)
+    } else if event == PORTABLE_PTY_MOUSE_RELEASE && !sgr {
+        // Only SGR can say which button was released.
+        3
+    } else {
+        button
+    };
+    if event == PORTABLE_PTY_MOUSE_MOTION {
+        code += 32;
+    }
+    if tracking != MouseTracking::X10 {
+        if modifiers & PORTABLE_PTY_MOD_SHIFT != 0 {
+            code += 4;
+        }
+        if modifiers & PORTABLE_PTY_MOD_ALT != 0 {
+            code += 8;
+        }
+        if modifiers & PORTABLE_PTY_MOD_CTRL != 0 {
+            code += 16;
+        }
+    }
+
+    let col = x.checked_add(1)?;
+    let row = y.checked_add(1)?;
+    let mut out = Vec::with_capacity(MAX_SEQUENCE_LEN);
+    match modes.mouse_encoding {
+        MouseEncoding::Sgr => {
+            let action = if event == PORTABLE_PTY_MOUSE_RELEASE {
+                'm'
+            } else {
+                'M'
+            };
+            out.extend_from_slice(format!("\x1b[<{code};{col};{row}{action}").as_bytes());
+        }
+        MouseEncoding::Urxvt => {
+            out.extend_from_slice(format!("\x1b[{};{col};{row}M", code + 32).as_bytes());
+        }
+        MouseEncoding::Default => {
+            let [cb, cx, cy] = [code, col, row].map(|v| v + 32);
+            if cx > 255 || cy > 255 {
+                return None;
+            }
+            out.extend_from_slice(b"\x1b[M");
+            out.extend_from_slice(&[cb as u8, cx as u8, cy as u8]);
+        }
+        MouseEncoding::Utf8 => {
+            out.extend_from_slice(b"\x1b[M");
+            for value in [code, col, row].map(|v| v + 32) {
+                // xterm caps UTF-8 coordinates at 2047 (two-byte sequences).
+                if value > 2047 {
+                    return None;
+                }
+                let ch = char::from_u32(value)?;
+                let mut tmp = [0u8; 4];
+                out.extend_from_slice(ch.encode_utf8(&mut tmp).as_bytes());
+            }
+        }
+    }
+    Some(out)
+}
+
+/// Encode a wheel press as the arrow key it scrolls with, for a child on
+/// the alternate screen that isn't reporting the mouse; `None` otherwise.
+pub(crate) fn encode_alternate_scroll(modes: Modes, button: u32, event: u32) -> Option<Vec<u8>> {
+    if !modes.alternate_screen
+        || modes.mouse_tracking != MouseTracking::Off
+        || event != PORTABLE_PTY_MOUSE_PRESS
+    {
+        return None;
+    }
+    let key = match button {
+        PORTABLE_PTY_MOUSE_WHEEL_UP => PORTABLE_PTY_KEY_UP,
+        PORTABLE_PTY_MOUSE_WHEEL_DOWN => PORTABLE_PTY_KEY_DOWN,
+        PORTABLE_PTY_MOUSE_WHEEL_LEFT => PORTABLE_PTY_KEY_LEFT,
+        PORTABLE_PTY_MOUSE_WHEEL_RIGHT => PORTABLE_PTY_KEY_RIGHT,
+        _ => return None,
+    };
+    crate::keys::encode(modes, key, 0)
+}
+
+/// Encode a mouse event using the child's currently active mouse modes.
+///
+/// - `button`: one of the `PORTABLE_PTY_MOUSE_*` button constants.
+/// - `x`, `y`: zero-based cell column and row.
+/// - `event_type`: `PORTABLE_PTY_MOUSE_PRESS`, `_RELEASE`, or `_MOTION`.
+/// - `modifiers`: bitwise OR of `PORTABLE_PTY_MOD_*`.
+///
+/// Writes the sequence to `out_buf` and returns its length, `0` when the
+/// child isn't interested in this event, or -1 on error (including an
+/// `out_buf` too small for the sequence). Pass the bytes to
+/// `portable_pty_write` to deliver them. With alternate scroll on, a wheel
+/// press the child wouldn't get may encode to an arrow key instead (see
+/// `portable_pty_set_alternate_scroll`).
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_encode_mouse(
+    handle: *const PortablePty,
+    button: u32,
+    x: u32,
+    y: u32,
+    event_type: u32,
+    modifiers: u32,
+    out_buf: *mut u8,
+    out_len: usize,
+) -> i64 {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return -1,
+        };
+        if out_buf.is_null() {
+            return -1;
+        }
+
+        let modes = match pty.modes.lock() {
+            Ok(tracker) => tracker.modes(),
+            Err(_) => return -1,
+        };
+        let alternate_scroll = pty.alternate_scroll.load(Ordering::Relaxed);
+        let seq = encode(modes, button, x, y, event_type, modifiers).or_else(|| {
+            alternate_scroll
+                .then(|| encode_alternate_scroll(modes, button, event_type))
+                .flatten()
+        });
+        let Some(seq) = seq else {
+            return 0;
+        };
+        if seq.len() > out_len {
+            return -1;
+        }
+
+        unsafe {
+            std::ptr::copy_nonoverlapping(seq.as_ptr(), out_buf, seq.len());
+        }
+        seq.len() as i64
+    })
+}
+
+/// Report the mouse modes the child has requested.
+///
+/// Writes one of the `PORTABLE_PTY_MOUSE_TRACKING_*` constants to
+/// `*out_tracking` and one of the `PORTABLE_PTY_MOUSE_ENCODING_*` constants
+/// to `*out_encoding`. Modes are tracked from output returned by
+/// `portable_pty_read`.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_mouse_mode(
+    handle: *const PortablePty,
+    out_tracking: *mut u32,
+    out_encoding: *mut u32,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        if out_tracking.is_null() || out_encoding.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+
+        let modes = match pty.modes.lock() {
+            Ok(tracker) => tracker.modes(),
+            Err(_) => return PortablePtyResult::ErrMode,
+        };
+        let tracking = match modes.mouse_tracking {
+            MouseTracking::Off => PORTABLE_PTY_MOUSE_TRACKING_OFF,
+            MouseTracking::X10 => PORTABLE_PTY_MOUSE_TRACKING_X10,
+            MouseTracking::Normal => PORTABLE_PTY_MOUSE_TRACKING_NORMAL,
+            MouseTracking::ButtonEvent => PORTABLE_PTY_MOUSE_TRACKING_BUTTON_EVENT,
+            MouseTracking::AnyEvent => PORTABLE_PTY_MOUSE_TRACKING_ANY_EVENT,
+        };
+        let encoding = match modes.mouse_encoding {
+            MouseEncoding::Default => PORTABLE_PTY_MOUSE_ENCODING_DEFAULT,
+            MouseEncoding::Utf8 => PORTABLE_PTY_MOUSE_ENCODING_UTF8,
+            MouseEncoding::Sgr => PORTABLE_PTY_MOUSE_ENCODING_SGR,
+            MouseEncoding::Urxvt => PORTABLE_PTY_MOUSE_ENCODING_URXVT,
+        };
+
+        unsafe {
+            *out_tracking = tracking;
+            *out_encoding = encoding;
+        }
+        PortablePtyResult::Ok
+    })
+}
+
+/// Choose whether the wheel scrolls a child on the alternate screen that
+/// isn't reporting the mouse, by having `portable_pty_encode_mouse` turn
+/// wheel presses into Up, Down, Left and Right keys. Off by default.
+///
+/// Which screen the child is on and whether it reports the mouse are
+/// tracked from output returned by `portable_pty_read`; the arrows follow
+/// its cursor-key mode as `portable_pty_encode_key` does. On the normal
+/// screen the wheel is the embedder's to scroll its scrollback with.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_set_alternate_scroll(
+    handle: *const PortablePty,
+    enabled: bool,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        pty.alternate_scroll.store(enabled, Ordering::Relaxed);
+        PortablePtyResult::Ok
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn modes(tracking: MouseTracking, encoding: MouseEncoding) -> Modes {
+        Modes {
+            mouse_tracking: tracking,
+            mouse_encoding: encoding,
+            ..Modes::default()
+        }
+    }
+
+    #[test]
+    fn test_encode_sgr() {
+        let m = modes(MouseTracking::Normal, MouseEncoding::Sgr);
+        let press = encode(
+            m,
+            PORTABLE_PTY_MOUSE_LEFT,
+            9,
+            4,
+            PORTABLE_PTY_MOUSE_PRESS,
+            0,
+        );
+        assert_eq!(press.as_deref(), Some(&b"\x1b[<0;10;5M"[..]));
+
+        let release = encode(
+            m,
+            PORTABLE_PTY_MOUSE_RIGHT,
+            0,
+            0,
+            PORTABLE_PTY_MOUSE_RELEASE,
+            PORTABLE_PTY_MOD_CTRL,
+        );
+        assert_eq!(release.as_deref(), Some(&b"\x1b[<18;1;1m"[..]));
+
+        let wheel = encode(
+            m,
+            PORTABLE_PTY_MOUSE_WHEEL_DOWN,
+            0,
+            0,
+            PORTABLE_PTY_MOUSE_PRESS,
+            0,
+        );
+        assert_eq!(wheel.as_deref(), Some(&b"\x1b[<65;1;1M"[..]));
+    }
+
+    #[test]
+    fn test_encode_default_and_urxvt() {
+        let m = modes(MouseTracking::Normal, MouseEncoding::Default);
+        let press = encode(
+            m,
+            PORTABLE_PTY_MOUSE_LEFT,
+            0,
+            0,
+            PORTABLE_PTY_MOUSE_PRESS,
+            0,
+        );
+        assert_eq!(press.as_deref(), Some(&b"\x1b[M !!"[..]));
+        let release = encode(
+            m,
+            PORTABLE_PTY_MOUSE_LEFT,
+            0,
+            0,
+            PORTABLE_PTY_MOUSE_RELEASE,
+            0,
+        );
+        assert_eq!(release.as_deref(), Some(&b"\x1b[M#!!"[..]));
+        // Out of range for the legacy encoding.
+        assert_eq!(
+            encode(
+                m,
+                PORTABLE_PTY_MOUSE_LEFT,
+                300,
+                0,
+                PORTABLE_PTY_MOUSE_PRESS,
+                0
+            ),
+            None
+        );
+
+        let m = modes(MouseTracking::Normal, MouseEncoding::Urxvt);
+        let press = encode(
+            m,
+            PORTABLE_PTY_MOUSE_LEFT,
+            299,
+            0,
+            PORTABLE_PTY_MOUSE_PRESS,
+            0,
+        );
+        assert_eq!(press.as_deref(), Some(&b"\x1b[32;300;1M"[..]));
+    }
+
+    #[test]
+    fn test_mode_filters_events() {
+        let off = modes(MouseTracking::Off, MouseEncoding::Sgr);
+        assert_eq!(encode(off, 0, 0, 0, PORTABLE_PTY_MOUSE_PRESS, 0), None);
+
+        let x10 = modes(MouseTracking::X10, MouseEncoding::Sgr);
+        assert_eq!(encode(x10, 0, 0, 0, PORTABLE_PTY_MOUSE_RELEASE, 0), None);
+        let press = encode(
+            x10,
+            0,
+            0,
+            0,
+            PORTABLE_PTY_MOUSE_PRESS,
+            PORTABLE_PTY_MOD_SHIFT,
+        );
+        assert_eq!(press.as_deref(), Some(&b"\x1b[<0;1;1M"[..]));
+
+        let button = modes(MouseTracking::ButtonEvent, MouseEncoding::Sgr);
+        let none = PORTABLE_PTY_MOUSE_NONE;
+        assert_eq!(
+            encode(button, none, 0, 0, PORTABLE_PTY_MOUSE_MOTION, 0),
+            None
+        );
+        let drag = encode(button, 0, 1, 1, PORTABLE_PTY_MOUSE_MOTION, 0);
+        assert_eq!(drag.as_deref(), Some(&b"\x1b[<32;2;2M"[..]));
+
+        let any = modes(MouseTracking::AnyEvent, MouseEncoding::Sgr);
+        let hover = encode(any, none, 1, 1, PORTABLE_PTY_MOUSE_MOTION, 0);
+        assert_eq!(hover.as_deref(), Some(&b"\x1b[<35;2;2M"[..]));
+    }
+
+    #[test]
+    fn test_alternate_scroll() {
+        let alternate = Modes {
+            alternate_screen: true,
+            ..Modes::default()
+        };
+        let scroll = |modes, button, event| encode_alternate_scroll(modes, button, event);
+        let up = scroll(
+            alternate,
+            PORTABLE_PTY_MOUSE_WHEEL_UP,
+            PORTABLE_PTY_MOUSE_PRESS,
+        );
+        assert_eq!(up.as_deref(), Some(&b"\x1b[A"[..]));
+        let application = Modes {
+            application_cursor: true,
+            ..alternate
+        };
+        let right = scroll(
+            application,
+            PORTABLE_PTY_MOUSE_WHEEL_RIGHT,
+            PORTABLE_PTY_MOUSE_PRESS,
+        );
+        assert_eq!(right.as_deref(), Some(&b"\x1bOC"[..]));
+
+        let down = PORTABLE_PTY_MOUSE_WHEEL_DOWN;
+        assert_eq!(scroll(alternate, PORTABLE_PTY_MOUSE_LEFT, 0), None);
+        assert_eq!(scroll(alternate, down, PORTABLE_PTY_MOUSE_RELEASE), None);
+        assert_eq!(
+            scroll(Modes::default(), down, PORTABLE_PTY_MOUSE_PRESS),
+            None
+        );
+        let reporting = modes(MouseTracking::Normal, MouseEncoding::Sgr);
+        let reporting = Modes {
+            alternate_screen: true,
+            ..reporting
+        };
+        assert_eq!(scroll(reporting, down, PORTABLE_PTY_MOUSE_PRESS), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_encode_mouse_with_alternate_scroll() {
+        let mut handle = std::ptr::null_mut();
+        crate::loopback::portable_pty_open_loopback(24, 80, &mut handle);
+        let output = b"\x1b[?1049h";
+        crate::loopback::portable_pty_loopback_write(handle, output.as_ptr(), output.len());
+        crate::tests::read_string(handle);
+
+        let wheel_down = || {
+            let mut out = [0u8; MAX_SEQUENCE_LEN];
+            let len = portable_pty_encode_mouse(
+                handle,
+                PORTABLE_PTY_MOUSE_WHEEL_DOWN,
+                0,
+                0,
+                PORTABLE_PTY_MOUSE_PRESS,
+                0,
+                out.as_mut_ptr(),
+                out.len(),
+            );
+            out[..len as usize].to_vec()
+        };
+        assert!(wheel_down().is_empty());
+        assert!(matches!(
+            portable_pty_set_alternate_scroll(handle, true),
+            PortablePtyResult::Ok
+        ));
+        assert_eq!(wheel_down(), b"\x1b[B");
+        crate::portable_pty_close(handle);
+    }
+}
@@ -0,0 +1,443 @@
+//! Reading and writing without blocking.
+//!
+//! `portable_pty_read` waits for output, which ties up the calling thread
+//! — for Dart, a whole isolate — however long the child stays quiet.
+//! `portable_pty_try_read` returns straight away instead, so output can be
+//! polled for from a timer on the thread that draws it;
+//! `portable_pty_read_timeout` waits only so long, for drivers that go
+//! back to something else when the child has nothing to say; and
+//! `portable_pty_wait_readable` waits without reading, for callers that
+//! read once there's something to.
+//!
+//! They only know output is there by polling the master, so they need a
+//! backend with a file descriptor to poll: a local Unix PTY, a piped or
+//! loopback handle. On Windows, and for the other backends, they fail
+//! unless output the handle already holds is waiting. They keep to their
+//! time as long as they're the handle's only reader; another thread
+//! reading the same output between the poll and the read leaves them
+//! waiting for more.
+//!
+//! Writes block too, once the child stops reading its input and that
+//! fills up. After `portable_pty_set_nonblocking_writes`,
+//! `portable_pty_write` takes only what fits and returns
+//! `PORTABLE_PTY_WRITE_WOULD_BLOCK` if nothing does, so a frontend typing
+//! into a stuck child keeps drawing; it can wait for room with
+//! `portable_pty_wait_writable`. Such writes go straight to the child,
+//! past any coalescing. Only a local Unix PTY fills up, so elsewhere
+//! writes are as they always are.
+//!
+//! An embedder with its own event loop, watching the master's descriptor
+//! (`portable_pty_master_fd`), can instead make the master itself
+//! non-blocking with `portable_pty_set_nonblocking`. Then reads return
+//! `PORTABLE_PTY_READ_WOULD_BLOCK` when there's nothing to read, and writes
+//! are non-blocking as above. Windows has no equivalent: the backend opens
+//! ConPTY's pipes for synchronous I/O, and a pipe can't be switched to
+//! overlapped I/O once open.
+
+use crate::expect::remaining;
+use crate::{PortablePty, PortablePtyResult};
+use std::io::{self, Write};
+use std::sync::atomic::Ordering;
+use std::sync::TryLockError;
+use std::time::{Duration, Instant};
+
+/// What `portable_pty_try_read` returns at end of file, 0 meaning there's
+/// nothing to read yet.
+pub const PORTABLE_PTY_READ_EOF: i64 = -2;
+
+/// What `portable_pty_read_timeout` returns when nothing came in time.
+pub const PORTABLE_PTY_READ_TIMEOUT: i64 = -3;
+
+/// What `portable_pty_write` returns, with non-blocking writes on, when
+/// the child's input has no room for any of it.
+pub const PORTABLE_PTY_WRITE_WOULD_BLOCK: i64 = -4;
+
+/// What `portable_pty_read` returns, with the master non-blocking, when
+/// there's nothing to read; the same as `PORTABLE_PTY_WRITE_WOULD_BLOCK`.
+pub const PORTABLE_PTY_READ_WOULD_BLOCK: i64 = -4;
+
+/// Set or clear `O_NONBLOCK` on `fd`.
+#[cfg(unix)]
+fn set_nonblocking(fd: std::os::fd::RawFd, enabled: bool) -> io::Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let flags = match enabled {
+        true => flags | libc::O_NONBLOCK,
+        false => flags & !libc::O_NONBLOCK,
+    };
+    if unsafe { libc::fcntl(fd, libc::F_SETFL, flags) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Run `f` with `fd`, the master of `pty`, non-blocking, leaving it
+/// blocking after unless the embedder has made it non-blocking since.
+#[cfg(unix)]
+fn without_blocking<T>(pty: &PortablePty, fd: std::os::fd::RawFd, f: impl FnOnce() -> T) -> T {
+    let _ = set_nonblocking(fd, true);
+    let result = f();
+    if !pty.nonblocking.load(Ordering::Relaxed) {
+        let _ = set_nonblocking(fd, false);
+    }
+    result
+}
+
+/// `portable_pty_write` with non-blocking writes on: send what's held
+/// back, then what of `bytes` the child's input has room for.
+pub(crate) fn write(pty: &PortablePty, bytes: &[u8]) -> i64 {
+    // Whoever holds the writer may be stuck on a full input already.
+    let mut writer = match pty.writer.try_lock() {
+        Ok(writer) => writer,
+        Err(TryLockError::WouldBlock) => return PORTABLE_PTY_WRITE_WOULD_BLOCK,
+        Err(TryLockError::Poisoned(_)) => return -1,
+    };
+    let mut write = || {
+        writer.flush()?;
+        writer.get_mut().write(bytes)
+    };
+    #[cfg(unix)]
+    let result = match (pty.master.tty_name(), pty.master.as_raw_fd()) {
+        // The writer shares the master's flags; reads retry meanwhile.
+        (Some(_), Some(fd)) => without_blocking(pty, fd, write),
+        _ => write(),
+    };
+    #[cfg(not(unix))]
+    let result = write();
+    drop(writer);
+    match result {
+        Ok(n) => {
+            pty.observe_input(&bytes[..n]);
+            n as i64
+        }
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => PORTABLE_PTY_WRITE_WOULD_BLOCK,
+        Err(_) => -1,
+    }
+}
+
+/// Wait at most `timeout` for `pty` to have output, or end of file, to
+/// read; false if it didn't.
+fn readable(pty: &PortablePty, timeout: Option<Duration>) -> io::Result<bool> {
+    #[cfg(unix)]
+    {
+        pty.wait_readable(timeout)
+    }
+    #[cfg(not(unix))]
+    {
+        // `wait_readable` can't tell an idle ConPTY from a busy one.
+        let _ = timeout;
+        let held = pty.pending.lock().map(|p| !p.is_empty()).unwrap_or(false)
+            || pty.output_filter.has_buffered();
+        match held {
+            true => Ok(true),
+            false => Err(io::ErrorKind::Unsupported.into()),
+        }
+    }
+}
+
+/// Wait at most `timeout` until a read of `pty` wouldn't block: output is
+/// flowing (see `flow`) and there's some, or end of file, to read.
+pub(crate) fn ready(pty: &PortablePty, timeout: Option<Duration>) -> io::Result<bool> {
+    let deadline = timeout.map(|t| Instant::now() + t);
+    if !pty.flow.wait_for(timeout) {
+        return Ok(false);
+    }
+    readable(pty, remaining(deadline))
+}
+
+/// `portable_pty_read`, once output is there within `timeout`; `None` if
+/// it isn't.
+fn read_within(
+    handle: *mut PortablePty,
+    buf: *mut u8,
+    len: usize,
+    timeout: Duration,
+) -> Option<i64> {
+    let pty = unsafe { handle.as_ref() }?;
+    match ready(pty, Some(timeout)) {
+        Ok(true) => Some(crate::portable_pty_read(handle, buf, len)),
+        Ok(false) => None,
+        Err(_) => Some(-1),
+    }
+}
+
+/// Read what output is available without waiting for more, so output can
+/// be polled for from a timer on the thread that draws it.
+///
+/// Returns the number of bytes read, 0 if there's nothing to read yet or
+/// output is paused (see `flow`), `PORTABLE_PTY_READ_EOF` at end of file,
+/// or -1 on error. That includes handles that can't be polled — on Windows,
+/// and any but a local Unix PTY, piped or loopback handle — unless output
+/// the handle already holds is waiting.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_try_read(handle: *mut PortablePty, buf: *mut u8, len: usize) -> i64 {
+    crate::ffi::guard(|| {
+        if handle.is_null() || buf.is_null() || len == 0 {
+            return -1;
+        }
+        match read_within(handle, buf, len, Duration::ZERO) {
+            None | Some(PORTABLE_PTY_READ_WOULD_BLOCK) => 0,
+            Some(0) => PORTABLE_PTY_READ_EOF,
+            Some(n) => n,
+        }
+    })
+}
+
+/// Read output as `portable_pty_read` does, waiting at most `timeout_ms`
+/// for some to come. The time is kept as long as this is the handle's
+/// only reader.
+///
+/// - `timeout_ms`: the longest to wait, paused output included; negative
+///   waits indefinitely, as `portable_pty_read` does.
+///
+/// Returns the number of bytes read, 0 at end of file,
+/// `PORTABLE_PTY_READ_TIMEOUT` if there was nothing to read in time, or
+/// -1 on error. Waiting polls the master, so on Windows and on any handle
+/// but a local Unix PTY, piped or loopback one that's an error too, unless
+/// output the handle already holds is waiting.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_read_timeout(
+    handle: *mut PortablePty,
+    buf: *mut u8,
+    len: usize,
+    timeout_ms: i32,
+) -> i64 {
+    crate::ffi::guard(|| {
+        if handle.is_null() || buf.is_null() || len == 0 {
+            return -1;
+        }
+        let Ok(timeout_ms) = u64::try_from(timeout_ms) else {
+            return crate::portable_pty_read(handle, buf, len);
+        };
+        let timeout = Duration::from_millis(timeout_ms);
+        read_within(handle, buf, len, timeout).unwrap_or(PORTABLE_PTY_READ_TIMEOUT)
+    })
+}
+
+/// Wait until `portable_pty_read` has something to return, for callers
+/// that wait first and read after.
+///
+/// - `timeout_ms`: the longest to wait; negative waits indefinitely.
+///
+/// Returns `Ok` once output, or end of file, is there to read and isn't
+/// paused (see `flow`), `ErrTimeout` if `timeout_ms` elapsed first,
+/// `ErrUnsupported` where the handle can't be polled — on Windows, and
+/// any but a local Unix PTY, piped or loopback handle, unless it already
+/// holds output — and `ErrRead` if polling failed.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_wait_readable(
+    handle: *const PortablePty,
+    timeout_ms: i32,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        let timeout = u64::try_from(timeout_ms).ok().map(Duration::from_millis);
+        match ready(pty, timeout) {
+            Ok(true) => PortablePtyResult::Ok,
+            Ok(false) => PortablePtyResult::ErrTimeout,
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => PortablePtyResult::ErrUnsupported,
+            Err(_) => PortablePtyResult::ErrRead,
+        }
+    })
+}
+
+/// Have `portable_pty_write` take only what the child's input has room
+/// for, rather than wait for room for all of it, returning
+/// `PORTABLE_PTY_WRITE_WOULD_BLOCK` if nothing fits;
+/// `portable_pty_wait_writable` waits for room. Such writes go straight to
+/// the child, past any coalescing. Only a local Unix PTY fills up, so
+/// elsewhere writes are as they always are.
+///
+/// - `enabled`: true for non-blocking writes, false to block again.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_set_nonblocking_writes(
+    handle: *const PortablePty,
+    enabled: bool,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        pty.nonblocking_writes.store(enabled, Ordering::Relaxed);
+        PortablePtyResult::Ok
+    })
+}
+
+/// Make the master non-blocking, or blocking again, for an embedder
+/// watching `portable_pty_master_fd` from its own event loop. Reads then
+/// return `PORTABLE_PTY_READ_WOULD_BLOCK` when there's nothing to read,
+/// and writes are as after `portable_pty_set_nonblocking_writes`.
+///
+/// - `enabled`: true to set `O_NONBLOCK`, false to clear it.
+///
+/// Returns `ErrUnsupported` on Windows, where ConPTY's pipes can't be made
+/// non-blocking once open, and for backends without a file descriptor,
+/// and `ErrMode` if the flag couldn't be changed.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_set_nonblocking(
+    handle: *const PortablePty,
+    enabled: bool,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        #[cfg(unix)]
+        {
+            let Some(fd) = pty.master.as_raw_fd() else {
+                return PortablePtyResult::ErrUnsupported;
+            };
+            pty.nonblocking.store(enabled, Ordering::Relaxed);
+            match set_nonblocking(fd, enabled) {
+                Ok(()) => PortablePtyResult::Ok,
+                Err(_) => PortablePtyResult::ErrMode,
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = (pty, enabled);
+            PortablePtyResult::ErrUnsupported
+        }
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::tests::open_and_spawn;
+
+    #[test]
+    fn test_try_read_does_not_wait() {
+        let handle = open_and_spawn("sh", &["sh", "-c", "read line; echo got $line"]);
+        let mut buf = [0u8; 256];
+        let try_read = |buf: &mut [u8]| portable_pty_try_read(handle, buf.as_mut_ptr(), buf.len());
+        assert_eq!(try_read(&mut buf), 0);
+
+        crate::portable_pty_write(handle, b"x\n".as_ptr(), 2);
+        let mut output = Vec::new();
+        loop {
+            match try_read(&mut buf) {
+                PORTABLE_PTY_READ_EOF => break,
+                -1 => panic!("portable_pty_try_read failed"),
+                n => output.extend_from_slice(&buf[..n as usize]),
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(String::from_utf8_lossy(&output).contains("got x"));
+        crate::portable_pty_close(handle);
+
+        let result = portable_pty_try_read(std::ptr::null_mut(), buf.as_mut_ptr(), 1);
+        assert_eq!(result, -1);
+    }
+
+    #[test]
+    fn test_read_timeout_and_wait_readable() {
+        let handle = open_and_spawn("sh", &["sh", "-c", "read line; echo got $line"]);
+        let mut buf = [0u8; 256];
+        let read = |buf: &mut [u8], timeout_ms| {
+            portable_pty_read_timeout(handle, buf.as_mut_ptr(), buf.len(), timeout_ms)
+        };
+        let start = Instant::now();
+        assert_eq!(read(&mut buf, 100), PORTABLE_PTY_READ_TIMEOUT);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert!(matches!(
+            portable_pty_wait_readable(handle, 0),
+            PortablePtyResult::ErrTimeout
+        ));
+
+        crate::portable_pty_write(handle, b"x\n".as_ptr(), 2);
+        assert!(matches!(
+            portable_pty_wait_readable(handle, 5000),
+            PortablePtyResult::Ok
+        ));
+        let mut output = Vec::new();
+        loop {
+            match read(&mut buf, 5000) {
+                0 => break,
+                n if n > 0 => output.extend_from_slice(&buf[..n as usize]),
+                n => panic!("portable_pty_read_timeout returned {n}"),
+            }
+        }
+        assert!(String::from_utf8_lossy(&output).contains("got x"));
+
+        // Paused output counts against the timeout too.
+        crate::flow::portable_pty_pause_output(handle);
+        assert_eq!(read(&mut buf, 50), PORTABLE_PTY_READ_TIMEOUT);
+        assert!(matches!(
+            portable_pty_wait_readable(handle, 50),
+            PortablePtyResult::ErrTimeout
+        ));
+        crate::portable_pty_close(handle);
+    }
+
+    #[test]
+    fn test_nonblocking_writes_stop_when_input_fills() {
+        // Raw, so input that doesn't fit isn't dropped, as it is a line.
+        let script = "stty raw -echo; echo ready; sleep 5";
+        let handle = open_and_spawn("sh", &["sh", "-c", script]);
+        assert!(crate::tests::read_string(handle).contains("ready"));
+        portable_pty_set_nonblocking_writes(handle, true);
+
+        let chunk = [b'x'; 1024];
+        let write = || crate::portable_pty_write(handle, chunk.as_ptr(), chunk.len());
+        let mut written = 0;
+        let n = loop {
+            match write() {
+                n if n > 0 => written += n,
+                n => break n,
+            }
+        };
+        assert_eq!(n, PORTABLE_PTY_WRITE_WOULD_BLOCK);
+        assert!(written > 0);
+        assert!(matches!(
+            crate::portable_pty_wait_writable(handle, 0),
+            PortablePtyResult::ErrTimeout
+        ));
+
+        // The master is left blocking for everyone else.
+        let fd = crate::portable_pty_master_fd(handle);
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        assert_eq!(flags & libc::O_NONBLOCK, 0);
+        crate::portable_pty_close(handle);
+    }
+
+    #[test]
+    fn test_set_nonblocking() {
+        let handle = open_and_spawn("sh", &["sh", "-c", "read line; echo got $line"]);
+        let fd = crate::portable_pty_master_fd(handle);
+        let nonblocking = || unsafe { libc::fcntl(fd, libc::F_GETFL) } & libc::O_NONBLOCK != 0;
+        assert!(matches!(
+            portable_pty_set_nonblocking(handle, true),
+            PortablePtyResult::Ok
+        ));
+        assert!(nonblocking());
+
+        let mut buf = [0u8; 256];
+        let read = |buf: &mut [u8]| crate::portable_pty_read(handle, buf.as_mut_ptr(), buf.len());
+        assert_eq!(read(&mut buf), PORTABLE_PTY_READ_WOULD_BLOCK);
+        assert_eq!(crate::portable_pty_write(handle, b"x\n".as_ptr(), 2), 2);
+        let mut output = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !String::from_utf8_lossy(&output).contains("got x") {
+            match read(&mut buf) {
+                PORTABLE_PTY_READ_WOULD_BLOCK => std::thread::sleep(Duration::from_millis(10)),
+                n if n > 0 => output.extend_from_slice(&buf[..n as usize]),
+                n => panic!("portable_pty_read returned {n}"),
+            }
+            assert!(Instant::now() < deadline, "no output");
+        }
+        // A write leaves it non-blocking.
+        assert!(nonblocking());
+
+        portable_pty_set_nonblocking(handle, false);
+        assert!(!nonblocking());
+        crate::portable_pty_close(handle);
+    }
+}
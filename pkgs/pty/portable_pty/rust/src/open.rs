@@ -0,0 +1,395 @@
+//! Opening a handle from a config struct.
+//!
+//! `portable_pty_open_ex` takes everything about a new handle in one
+//! [`PortablePtyOpenConfig`]. The struct starts with its own size, so
+//! options can be added at its end without breaking callers built against
+//! an older header: fields past the size a caller passes take their
+//! defaults, and fields this build doesn't know yet are ignored. Zero is
+//! the default for every field.
+//!
+//! `portable_pty_launch` opens the same way and spawns the child in one
+//! call, for the usual open-then-spawn.
+
+use crate::pty::PtySize;
+use crate::{PortablePty, PortablePtyResult};
+use serde_json::Value;
+use std::ffi::{c_char, CStr};
+use std::io;
+use std::sync::atomic::Ordering;
+use std::sync::PoisonError;
+
+/// Put the terminal in raw mode (`cfmakeraw`) before anything runs in it.
+pub const PORTABLE_PTY_OPEN_RAW: u32 = 1;
+/// Turn the terminal's echo off.
+pub const PORTABLE_PTY_OPEN_NO_ECHO: u32 = 2;
+/// Start with auto-flush off; see `portable_pty_set_auto_flush`.
+pub const PORTABLE_PTY_OPEN_NO_AUTO_FLUSH: u32 = 4;
+
+const KNOWN_FLAGS: u32 =
+    PORTABLE_PTY_OPEN_RAW | PORTABLE_PTY_OPEN_NO_ECHO | PORTABLE_PTY_OPEN_NO_AUTO_FLUSH;
+
+/// How to open a handle. Set `struct_size` to `sizeof` the struct and
+/// zero what you don't use.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct PortablePtyOpenConfig {
+    /// `sizeof(PortablePtyOpenConfig)` as the caller was built with.
+    pub struct_size: u32,
+    /// Size in cells; 0 for 24 rows or 80 columns.
+    pub rows: u16,
+    pub cols: u16,
+    /// Size in pixels, for programs that draw images; 0 if unknown.
+    pub pixel_width: u16,
+    pub pixel_height: u16,
+    /// `PORTABLE_PTY_OPEN_*` flags.
+    pub flags: u32,
+    /// Null-terminated name of a backend, as `portable_pty_open_backend`
+    /// takes, or NULL for a local PTY as `portable_pty_open` opens.
+    pub backend: *const c_char,
+    /// Null-terminated UTF-8 JSON config for `backend`, or NULL for `{}`.
+    /// `rows` and `cols` above fill in for those it doesn't give.
+    pub backend_config: *const c_char,
+    /// Capacity of the buffer holding input while auto-flush is off; 0
+    /// for the default.
+    pub write_buffer_size: usize,
+}
+
+/// The size of the struct's first version, the least a caller may pass.
+const FIRST_VERSION_SIZE: usize = std::mem::size_of::<PortablePtyOpenConfig>();
+
+impl PortablePtyOpenConfig {
+    /// Copy the caller's struct, as much of it as they and this build know
+    /// about, with the rest zero.
+    ///
+    /// # Safety
+    ///
+    /// `config` must be non-null and point to at least `struct_size`
+    /// readable bytes.
+    unsafe fn read(config: *const PortablePtyOpenConfig) -> Option<Self> {
+        let size = unsafe { (*config).struct_size } as usize;
+        if size < FIRST_VERSION_SIZE {
+            return None;
+        }
+        let mut copy = unsafe { std::mem::zeroed::<PortablePtyOpenConfig>() };
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                config.cast::<u8>(),
+                (&raw mut copy).cast::<u8>(),
+                size.min(std::mem::size_of::<PortablePtyOpenConfig>()),
+            );
+        }
+        Some(copy)
+    }
+
+    fn size(&self) -> PtySize {
+        PtySize {
+            rows: if self.rows == 0 { 24 } else { self.rows },
+            cols: if self.cols == 0 { 80 } else { self.cols },
+            pixel_width: self.pixel_width,
+            pixel_height: self.pixel_height,
+        }
+    }
+}
+
+/// Open the handle `config` describes.
+pub(crate) fn open(config: &PortablePtyOpenConfig) -> Result<Box<PortablePty>, PortablePtyResult> {
+    if config.flags & !KNOWN_FLAGS != 0 {
+        return Err(PortablePtyResult::ErrUnsupported);
+    }
+    let size = config.size();
+    let mut pty = if config.backend.is_null() {
+        crate::open_native(size)?
+    } else {
+        let name = unsafe { CStr::from_ptr(config.backend) }
+            .to_str()
+            .map_err(|_| PortablePtyResult::ErrBackend)?;
+        let mut backend_config = if config.backend_config.is_null() {
+            Value::Object(Default::default())
+        } else {
+            unsafe { CStr::from_ptr(config.backend_config) }
+                .to_str()
+                .ok()
+                .and_then(|json| serde_json::from_str(json).ok())
+                .filter(Value::is_object)
+                .ok_or(PortablePtyResult::ErrOpen)?
+        };
+        if let Some(fields) = backend_config.as_object_mut() {
+            for (key, given, value) in [
+                ("rows", config.rows, size.rows),
+                ("cols", config.cols, size.cols),
+            ] {
+                if given != 0 {
+                    fields.entry(key).or_insert(value.into());
+                }
+            }
+        }
+        let pty = crate::backend::open(name, &backend_config)?;
+        if size.pixel_width != 0 || size.pixel_height != 0 {
+            if let Ok(opened) = pty.master.get_size() {
+                let with_pixels = PtySize {
+                    pixel_width: size.pixel_width,
+                    pixel_height: size.pixel_height,
+                    ..opened
+                };
+                pty.master
+                    .resize(with_pixels)
+                    .map_err(|_| PortablePtyResult::ErrResize)?;
+            }
+        }
+        pty
+    };
+
+    if config.flags & (PORTABLE_PTY_OPEN_RAW | PORTABLE_PTY_OPEN_NO_ECHO) != 0 {
+        set_modes(&pty, config.flags)?;
+    }
+    if config.flags & PORTABLE_PTY_OPEN_NO_AUTO_FLUSH != 0 {
+        pty.auto_flush.store(false, Ordering::Relaxed);
+    }
+    if config.write_buffer_size != 0 {
+        // Nothing has been written yet to lose.
+        let writer = pty.writer.get_mut().unwrap_or_else(PoisonError::into_inner);
+        let inner = std::mem::replace(writer.get_mut(), Box::new(io::sink()));
+        *writer = io::BufWriter::with_capacity(config.write_buffer_size, inner);
+    }
+    Ok(pty)
+}
+
+/// Apply the terminal modes `flags` ask for. Only a local Unix PTY has
+/// them.
+#[cfg(unix)]
+fn set_modes(pty: &PortablePty, flags: u32) -> Result<(), PortablePtyResult> {
+    let fd = match pty.master.as_raw_fd() {
+        Some(fd) if pty.master.tty_name().is_some() => fd,
+        _ => return Err(PortablePtyResult::ErrUnsupported),
+    };
+    let mut t = std::mem::MaybeUninit::<libc::termios>::uninit();
+    if unsafe { libc::tcgetattr(fd, t.as_mut_ptr()) } != 0 {
+        return Err(PortablePtyResult::ErrMode);
+    }
+    let mut t = unsafe { t.assume_init() };
+    if flags & PORTABLE_PTY_OPEN_RAW != 0 {
+        unsafe { libc::cfmakeraw(&mut t) };
+    }
+    if flags & PORTABLE_PTY_OPEN_NO_ECHO != 0 {
+        t.c_lflag &= !libc::ECHO;
+    }
+    if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &t) } != 0 {
+        return Err(PortablePtyResult::ErrMode);
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_modes(_pty: &PortablePty, _flags: u32) -> Result<(), PortablePtyResult> {
+    Err(PortablePtyResult::ErrUnsupported)
+}
+
+/// Open a handle as `config` describes.
+///
+/// - `config`: the options; see [`PortablePtyOpenConfig`].
+/// - `out`: receives the new handle; close it with `portable_pty_close`.
+///
+/// Returns `ErrOpen` if `struct_size` is smaller than the first version of
+/// the struct or the backend config is malformed, `ErrUnsupported` for
+/// flags this build doesn't know or terminal modes asked of a handle
+/// without them (anything but a local Unix PTY), and `ErrMode` if the
+/// terminal refuses them. Otherwise as `portable_pty_open` or
+/// `portable_pty_open_backend`.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_open_ex(
+    config: *const PortablePtyOpenConfig,
+    out: *mut *mut PortablePty,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        if config.is_null() || out.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+        let Some(config) = (unsafe { PortablePtyOpenConfig::read(config) }) else {
+            return PortablePtyResult::ErrOpen;
+        };
+        match open(&config) {
+            Ok(handle) => {
+                unsafe {
+                    *out = crate::lifecycle::register(handle);
+                }
+                PortablePtyResult::Ok
+            }
+            Err(e) => e,
+        }
+    })
+}
+
+/// Open a handle as `config` describes and spawn a child in it, in one
+/// call: the handle is only handed out once the child is running.
+///
+/// - `config`: as for `portable_pty_open_ex`, or NULL for a 24x80 local
+///   PTY.
+/// - `cmd`, `argv`, `envp`: as for `portable_pty_spawn`.
+/// - `out`: receives the new handle; close it with `portable_pty_close`.
+///
+/// Returns what `portable_pty_open_ex` or `portable_pty_spawn` would; on
+/// any error nothing is left open and `*out` is untouched.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_launch(
+    config: *const PortablePtyOpenConfig,
+    cmd: *const c_char,
+    argv: *const *const c_char,
+    envp: *const *const c_char,
+    out: *mut *mut PortablePty,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        if out.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+        let config = if config.is_null() {
+            PortablePtyOpenConfig {
+                struct_size: FIRST_VERSION_SIZE as u32,
+                ..unsafe { std::mem::zeroed() }
+            }
+        } else {
+            match unsafe { PortablePtyOpenConfig::read(config) } {
+                Some(config) => config,
+                None => return PortablePtyResult::ErrOpen,
+            }
+        };
+        let builder = match crate::command_builder(cmd, argv, envp) {
+            Ok(builder) => builder,
+            Err(e) => return e,
+        };
+
+        let mut pty = match open(&config) {
+            Ok(pty) => pty,
+            Err(e) => return e,
+        };
+        let spawned = pty.spawn(builder);
+        if !matches!(spawned, PortablePtyResult::Ok) {
+            crate::destroy(pty);
+            return spawned;
+        }
+        unsafe {
+            *out = crate::lifecycle::register(pty);
+        }
+        PortablePtyResult::Ok
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn config() -> PortablePtyOpenConfig {
+        PortablePtyOpenConfig {
+            struct_size: std::mem::size_of::<PortablePtyOpenConfig>() as u32,
+            ..unsafe { std::mem::zeroed() }
+        }
+    }
+
+    fn size(handle: *mut PortablePty) -> (u16, u16, u16, u16) {
+        let (mut rows, mut cols, mut width, mut height) = (0, 0, 0, 0);
+        let result =
+            crate::portable_pty_get_size(handle, &mut rows, &mut cols, &mut width, &mut height);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        (rows, cols, width, height)
+    }
+
+    #[test]
+    fn test_open_ex_local() {
+        let config = PortablePtyOpenConfig {
+            rows: 30,
+            pixel_width: 800,
+            pixel_height: 600,
+            flags: PORTABLE_PTY_OPEN_RAW | PORTABLE_PTY_OPEN_NO_ECHO,
+            write_buffer_size: 64 * 1024,
+            ..config()
+        };
+        let mut handle = std::ptr::null_mut();
+        let result = portable_pty_open_ex(&config, &mut handle);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        assert_eq!(size(handle), (30, 80, 800, 600));
+
+        let fd = crate::portable_pty_master_fd(handle);
+        let mut t = unsafe { std::mem::zeroed::<libc::termios>() };
+        assert_eq!(unsafe { libc::tcgetattr(fd, &mut t) }, 0);
+        assert_eq!(t.c_lflag & (libc::ICANON | libc::ECHO), 0);
+        crate::portable_pty_close(handle);
+    }
+
+    #[test]
+    fn test_open_ex_backend() {
+        let config = PortablePtyOpenConfig {
+            cols: 132,
+            backend: c"loopback".as_ptr(),
+            backend_config: c"{\"rows\": 50}".as_ptr(),
+            flags: PORTABLE_PTY_OPEN_NO_AUTO_FLUSH,
+            ..config()
+        };
+        let mut handle = std::ptr::null_mut();
+        let result = portable_pty_open_ex(&config, &mut handle);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        assert_eq!(size(handle).0, 50);
+        assert_eq!(size(handle).1, 132);
+        assert!(!unsafe { &*handle }.auto_flush.load(Ordering::Relaxed));
+        crate::portable_pty_close(handle);
+
+        // A loopback has no terminal modes.
+        let config = PortablePtyOpenConfig {
+            flags: PORTABLE_PTY_OPEN_RAW,
+            ..config
+        };
+        let result = portable_pty_open_ex(&config, &mut handle);
+        assert!(matches!(result, PortablePtyResult::ErrUnsupported));
+    }
+
+    #[test]
+    fn test_open_ex_versions() {
+        let mut handle = std::ptr::null_mut();
+        let older = PortablePtyOpenConfig {
+            struct_size: 8,
+            ..config()
+        };
+        let result = portable_pty_open_ex(&older, &mut handle);
+        assert!(matches!(result, PortablePtyResult::ErrOpen));
+
+        let newer_flag = PortablePtyOpenConfig {
+            flags: 1 << 31,
+            ..config()
+        };
+        let result = portable_pty_open_ex(&newer_flag, &mut handle);
+        assert!(matches!(result, PortablePtyResult::ErrUnsupported));
+    }
+
+    #[test]
+    fn test_launch() {
+        let argv = [
+            c"sh".as_ptr(),
+            c"-c".as_ptr(),
+            c"echo launched".as_ptr(),
+            std::ptr::null(),
+        ];
+        let mut handle = std::ptr::null_mut();
+        let result = portable_pty_launch(
+            std::ptr::null(),
+            c"sh".as_ptr(),
+            argv.as_ptr(),
+            std::ptr::null(),
+            &mut handle,
+        );
+        assert!(matches!(result, PortablePtyResult::Ok));
+        assert!(crate::portable_pty_child_pid(handle) > 0);
+        assert!(crate::tests::read_string(handle).contains("launched"));
+        crate::portable_pty_close(handle);
+
+        let mut handle = std::ptr::null_mut();
+        let missing = c"/nonexistent/program";
+        let argv = [missing.as_ptr(), std::ptr::null()];
+        let result = portable_pty_launch(
+            &config(),
+            missing.as_ptr(),
+            argv.as_ptr(),
+            std::ptr::null(),
+            &mut handle,
+        );
+        assert!(matches!(result, PortablePtyResult::ErrSpawn));
+        assert!(handle.is_null());
+    }
+}
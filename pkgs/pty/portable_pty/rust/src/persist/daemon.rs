@@ -0,0 +1,406 @@
+//! The session daemon: owns one PTY and its child, keeps the scrollback,
+//! and serves handles that attach over a Unix-domain socket.
+
+use super::{ATTACH, EXIT, KILL, OK, OUTPUT, RESIZE, WRITE};
+use crate::frames::{frame, read_frame, Outbox};
+use crate::lifecycle::ThreadGroup;
+use crate::pty::{native_pty_system, ChildKiller, CommandBuilder, MasterPty, PtySize, SlavePty};
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::Shutdown;
+use std::os::fd::RawFd;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+/// How often the listener checks whether the session has ended.
+const ACCEPT_POLL: Duration = Duration::from_millis(50);
+
+/// How long a client may take to accept a frame before it's dropped.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Scrollback is replayed in frames of at most this much.
+const REPLAY_CHUNK: usize = 256 * 1024;
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// What the daemon runs, from its command line.
+pub(super) struct Options {
+    pub(super) socket: PathBuf,
+    pub(super) size: PtySize,
+    pub(super) scrollback: usize,
+    pub(super) cwd: Option<OsString>,
+    pub(super) argv: Vec<OsString>,
+}
+
+impl Options {
+    /// Parse `--socket PATH [--rows N] [--cols N] [--scrollback BYTES]
+    /// [--cwd DIR] -- PROGRAM [ARGS...]`.
+    pub(super) fn parse(args: impl IntoIterator<Item = OsString>) -> Option<Options> {
+        let mut args = args.into_iter();
+        let mut socket = None;
+        let mut size = PtySize::default();
+        let mut scrollback = super::DEFAULT_SCROLLBACK;
+        let mut cwd = None;
+        loop {
+            let flag = args.next()?;
+            if flag == "--" {
+                break;
+            }
+            let value = args.next()?;
+            match flag.to_str()? {
+                "--socket" => socket = Some(PathBuf::from(&value)),
+                "--rows" => size.rows = number(&value)?,
+                "--cols" => size.cols = number(&value)?,
+                "--scrollback" => scrollback = number(&value)?,
+                "--cwd" => cwd = Some(value),
+                _ => return None,
+            }
+        }
+        let argv: Vec<OsString> = args.collect();
+        if argv.is_empty() {
+            return None;
+        }
+        Some(Options {
+            socket: socket?,
+            size,
+            scrollback: scrollback.min(super::MAX_SCROLLBACK),
+            cwd,
+            argv,
+        })
+    }
+}
+
+fn number<T: std::str::FromStr>(value: &std::ffi::OsStr) -> Option<T> {
+    value.to_str()?.parse().ok()
+}
+
+/// Output kept for clients that attach later, and who's attached now.
+struct State {
+    scrollback: VecDeque<u8>,
+    limit: usize,
+    clients: Vec<Arc<Outbox>>,
+    /// The child's exit code, once it has exited.
+    exit: Option<i32>,
+}
+
+/// The PTY and child the daemon runs.
+pub(super) struct Session {
+    state: Mutex<State>,
+    master: Mutex<Box<dyn MasterPty + Send>>,
+    writer: Mutex<Box<dyn Write + Send>>,
+    killer: Mutex<Box<dyn ChildKiller + Send + Sync>>,
+    /// Held open so the master never reads as closed: see `pump`.
+    _slave: Mutex<Box<dyn SlavePty + Send>>,
+    pid: libc::pid_t,
+}
+
+impl Session {
+    /// Open the PTY and start the child.
+    pub(super) fn start(options: &Options) -> io::Result<(Arc<Session>, Box<dyn Read + Send>)> {
+        let pair = native_pty_system()
+            .openpty(options.size)
+            .map_err(io::Error::other)?;
+        let mut cmd = CommandBuilder::from_argv(options.argv.clone());
+        if let Some(cwd) = &options.cwd {
+            cmd.cwd(cwd);
+        }
+        let child = pair.slave.spawn_command(cmd).map_err(io::Error::other)?;
+        let pid = child
+            .process_id()
+            .ok_or_else(|| io::Error::other("child has no pid"))?;
+        let reader = pair.master.try_clone_reader().map_err(io::Error::other)?;
+        let writer = pair.master.take_writer().map_err(io::Error::other)?;
+        let session = Session {
+            state: Mutex::new(State {
+                scrollback: VecDeque::new(),
+                limit: options.scrollback,
+                clients: Vec::new(),
+                exit: None,
+            }),
+            master: Mutex::new(pair.master),
+            writer: Mutex::new(writer),
+            killer: Mutex::new(child.clone_killer()),
+            _slave: Mutex::new(pair.slave),
+            pid: pid as libc::pid_t,
+        };
+        Ok((Arc::new(session), reader))
+    }
+
+    fn output(&self, bytes: &[u8]) {
+        let mut state = lock(&self.state);
+        state.scrollback.extend(bytes);
+        let excess = state.scrollback.len().saturating_sub(state.limit);
+        state.scrollback.drain(..excess);
+        let output = frame(OUTPUT, bytes);
+        state
+            .clients
+            .retain(|outbox| outbox.push(Arc::clone(&output)));
+    }
+
+    fn exited(&self, code: i32) {
+        let mut state = lock(&self.state);
+        state.exit = Some(code);
+        let exit = frame(EXIT, &code.to_be_bytes());
+        for outbox in state.clients.drain(..) {
+            outbox.push(Arc::clone(&exit));
+            outbox.finish();
+        }
+    }
+
+    fn has_exited(&self) -> bool {
+        lock(&self.state).exit.is_some()
+    }
+
+    /// Reply to an attach, replay the scrollback and start sending output.
+    fn attach(&self, outbox: &Arc<Outbox>) {
+        let mut state = lock(&self.state);
+        state.clients.retain(|o| !Arc::ptr_eq(o, outbox));
+        outbox.push(frame(OK, &[]));
+        let (front, back) = state.scrollback.as_slices();
+        let scrollback = [front, back].concat();
+        for chunk in scrollback.chunks(REPLAY_CHUNK) {
+            outbox.push(frame(OUTPUT, chunk));
+        }
+        match state.exit {
+            Some(code) => {
+                outbox.push(frame(EXIT, &code.to_be_bytes()));
+                outbox.finish();
+            }
+            None => state.clients.push(Arc::clone(outbox)),
+        }
+    }
+
+    fn detach(&self, outbox: &Arc<Outbox>) {
+        lock(&self.state)
+            .clients
+            .retain(|o| !Arc::ptr_eq(o, outbox));
+    }
+
+    /// Carry out one request from a client.
+    fn request(&self, kind: u8, payload: &[u8], outbox: &Arc<Outbox>) {
+        match kind {
+            ATTACH => self.attach(outbox),
+            WRITE => {
+                let _ = lock(&self.writer).write_all(payload);
+            }
+            RESIZE => {
+                if let [r0, r1, c0, c1] = *payload {
+                    let _ = lock(&self.master).resize(PtySize {
+                        rows: u16::from_be_bytes([r0, r1]),
+                        cols: u16::from_be_bytes([c0, c1]),
+                        pixel_width: 0,
+                        pixel_height: 0,
+                    });
+                }
+            }
+            KILL => {
+                let _ = lock(&self.killer).kill();
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Whether the master has output to read within `timeout`. Without an fd
+/// to poll, a read is left to block.
+fn readable(master: Option<RawFd>, timeout: Duration) -> bool {
+    master.is_none_or(|fd| crate::poll_fd(fd, libc::POLLIN, Some(timeout)).unwrap_or(true))
+}
+
+/// Copy the child's output to the scrollback and clients until it has
+/// exited and everything it wrote has been read.
+///
+/// The session holds the slave, as a handle does: were the child's the
+/// only one, Linux would fail reads with `EIO` once it exited, and output
+/// still queued would be lost. So reading never ends of itself, and the
+/// child's exit is looked for whenever there's nothing to read.
+fn pump(session: Arc<Session>, mut reader: Box<dyn Read + Send>) {
+    let master = lock(&session.master).as_raw_fd();
+    let mut buf = [0u8; 16 * 1024];
+    loop {
+        if !readable(master, crate::EXIT_CHECK_INTERVAL) {
+            if crate::process_exited(session.pid) && !readable(master, crate::DRAIN_GRACE) {
+                break;
+            }
+            continue;
+        }
+        match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => session.output(&buf[..n]),
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(_) => break,
+        }
+    }
+    let mut status = 0;
+    let code = match unsafe { libc::waitpid(session.pid, &mut status, 0) } {
+        -1 => -1,
+        _ => crate::wait_status_code(status),
+    };
+    session.exited(code);
+}
+
+/// Serve `session` on `listener` until its child exits and every client
+/// has been sent the exit status.
+pub(super) fn serve(
+    listener: UnixListener,
+    session: Arc<Session>,
+    reader: Box<dyn Read + Send>,
+) -> io::Result<()> {
+    listener.set_nonblocking(true)?;
+    let threads: Arc<ThreadGroup> = Arc::default();
+    let pumping = Arc::clone(&session);
+    threads.spawn("portable-pty-daemon", move || pump(pumping, reader))?;
+    while !session.has_exited() {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                let _ = connect(stream, &session, &threads);
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => std::thread::sleep(ACCEPT_POLL),
+            Err(_) => {}
+        }
+    }
+    // Clients attached before the exit have been sent it; anyone who
+    // connected since gets it on attaching.
+    drop(listener);
+    threads.wait();
+    Ok(())
+}
+
+fn connect(
+    stream: UnixStream,
+    session: &Arc<Session>,
+    threads: &Arc<ThreadGroup>,
+) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    let mut reader = stream.try_clone()?;
+    let outbox = Arc::new(Outbox::default());
+
+    let sending = Arc::clone(&outbox);
+    threads.spawn("portable-pty-daemon", move || {
+        while let Some(frame) = sending.pop() {
+            if (&stream).write_all(&frame).is_err() {
+                break;
+            }
+        }
+        sending.close();
+        // Ends the reads below, if they haven't ended already.
+        let _ = stream.shutdown(Shutdown::Both);
+    })?;
+    let session = Arc::clone(session);
+    threads.spawn("portable-pty-daemon", move || {
+        while let Ok((kind, payload)) = read_frame(&mut reader) {
+            session.request(kind, &payload, &outbox);
+        }
+        session.detach(&outbox);
+        outbox.finish();
+    })
+}
+
+/// Bind the socket, readable and writable by the owner only.
+pub(super) fn bind(path: &std::path::Path) -> io::Result<UnixListener> {
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// Tell whoever started the daemon how it went. They read stdout to EOF.
+fn report(message: std::fmt::Arguments) {
+    let mut stdout = io::stdout();
+    let _ = stdout.write_fmt(message);
+    let _ = stdout.write_all(b"\n");
+    let _ = stdout.flush();
+}
+
+/// Point stdin, stdout and stderr at `/dev/null`.
+fn silence_stdio() {
+    unsafe {
+        let null = libc::open(c"/dev/null".as_ptr(), libc::O_RDWR);
+        if null >= 0 {
+            for fd in 0..3 {
+                libc::dup2(null, fd);
+            }
+            if null > 2 {
+                libc::close(null);
+            }
+        }
+    }
+}
+
+/// Run the daemon: bind the socket, leave the caller's session, start the
+/// child, report on stdout, then serve until the child exits.
+pub(super) fn run(options: Options) -> i32 {
+    let listener = match bind(&options.socket) {
+        Ok(listener) => listener,
+        Err(e) => {
+            report(format_args!("error: {e}"));
+            return 1;
+        }
+    };
+    // Detach from the caller: the first child leads a new session and the
+    // process that started us is free to go.
+    match unsafe { libc::fork() } {
+        -1 => {
+            report(format_args!("error: {}", io::Error::last_os_error()));
+            let _ = std::fs::remove_file(&options.socket);
+            return 1;
+        }
+        0 => {}
+        _ => return 0,
+    }
+    unsafe {
+        libc::setsid();
+        libc::signal(libc::SIGHUP, libc::SIG_IGN);
+        libc::signal(libc::SIGPIPE, libc::SIG_IGN);
+    }
+    let code = match Session::start(&options) {
+        Ok((session, reader)) => {
+            report(format_args!("ready"));
+            silence_stdio();
+            match serve(listener, session, reader) {
+                Ok(()) => 0,
+                Err(_) => 1,
+            }
+        }
+        Err(e) => {
+            report(format_args!("error: {e}"));
+            1
+        }
+    };
+    let _ = std::fs::remove_file(&options.socket);
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pump_keeps_output_queued_at_exit() {
+        let script = "i=0; while [ $i -lt 2000 ]; do echo line$i; i=$((i+1)); done";
+        let options = Options::parse(
+            ["--socket", "unused", "--", "/bin/sh", "-c", script].map(OsString::from),
+        )
+        .unwrap();
+        let (session, reader) = Session::start(&options).unwrap();
+        // The child has exited, its output still queued, before reading.
+        std::thread::sleep(Duration::from_millis(300));
+        pump(Arc::clone(&session), reader);
+
+        let state = lock(&session.state);
+        let (front, back) = state.scrollback.as_slices();
+        let output = String::from_utf8_lossy(&[front, back].concat()).into_owned();
+        assert!(
+            output.ends_with("line1999\r\n"),
+            "{:?}",
+            &output[output.len().saturating_sub(40)..]
+        );
+        assert_eq!(state.exit, Some(0));
+    }
+}
@@ -0,0 +1,715 @@
+//! Sessions that outlive the handle, and the app.
+//!
+//! The `persistent` backend doesn't own a PTY. It attaches to a session
+//! daemon — the `portable-pty-daemon` helper built alongside this library —
+//! which runs the child, keeps its scrollback and listens on a Unix-domain
+//! socket named after the session. If nothing answers there yet, opening
+//! starts the daemon first. Config:
+//!
+//! | key          | meaning                                              |
+//! |--------------|------------------------------------------------------|
+//! | `name`       | required; the session's name: no `/`, not hidden     |
+//! | `rows`       | `cols` and `rows` as elsewhere; a reattached session |
+//! | `cols`       | is resized to them                                   |
+//! | `command`    | argv to run in a new session; `$SHELL` by default    |
+//! | `cwd`        | working directory of a new session                   |
+//! | `env`        | object of variables added to a new session's         |
+//! | `scrollback` | bytes of output a new session keeps; 1 MiB default   |
+//! | `dir`        | where sockets live; see below                        |
+//! | `daemon`     | path of the helper; see below                        |
+//!
+//! The new-session keys are ignored when reattaching. Sockets live in
+//! `$XDG_RUNTIME_DIR/portable-pty`, or `portable-pty-<uid>` in the temp
+//! directory, created private to the user. Opening refuses a socket
+//! directory, the default or `dir`, unless it's a real directory owned by
+//! the user with mode 0700: in a shared one, such as the temp directory,
+//! another user could make it first and plant sockets in it to attach the
+//! handle to. The helper is taken from the config, else from
+//! `$PORTABLE_PTY_DAEMON`, else from next to the running executable, else
+//! from `PATH`.
+//!
+//! Attaching replays the scrollback before the live output. The handle
+//! otherwise behaves like any other: closing it ends the session, killing
+//! the child. `portable_pty_detach` closes it and leaves the session
+//! running to be reattached by name — from this process or a later one.
+//! A session whose child exits is gone once the exit is reported.
+//!
+//! Daemon and handle speak frames (see `frames`): `ATTACH` (2, no
+//! payload), `WRITE` (4, input), `RESIZE` (5, rows and cols as big-endian
+//! `u16`) and `KILL` (6, no payload) one way; `OK` (0x80) in reply to
+//! `ATTACH`, then `OUTPUT` (0x82) and finally `EXIT` (0x84, big-endian
+//! `i32` exit code) the other. Unix only; elsewhere opening returns
+//! `ErrUnsupported`.
+
+#![cfg_attr(not(unix), allow(dead_code))]
+
+#[cfg(unix)]
+mod daemon;
+
+use crate::{PortablePty, PortablePtyBuffer, PortablePtyResult};
+use std::ffi::{c_char, CStr};
+
+const ATTACH: u8 = 2;
+const WRITE: u8 = 4;
+const RESIZE: u8 = 5;
+const KILL: u8 = 6;
+const OK: u8 = 0x80;
+const OUTPUT: u8 = 0x82;
+const EXIT: u8 = 0x84;
+
+/// Scrollback a new session keeps unless the config says otherwise.
+const DEFAULT_SCROLLBACK: usize = 1 << 20;
+
+/// Most scrollback a session may keep.
+const MAX_SCROLLBACK: usize = 64 << 20;
+
+/// Entry point of the `portable-pty-daemon` helper; `args` excludes the
+/// program name. Returns the process exit code.
+#[doc(hidden)]
+pub fn daemon_main(args: impl IntoIterator<Item = std::ffi::OsString>) -> i32 {
+    #[cfg(unix)]
+    match daemon::Options::parse(args) {
+        Some(options) => daemon::run(options),
+        None => {
+            eprintln!(
+                "usage: portable-pty-daemon --socket PATH [--rows N] [--cols N] \
+                 [--scrollback BYTES] [--cwd DIR] -- PROGRAM [ARGS...]"
+            );
+            2
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        drop(args);
+        eprintln!("portable-pty-daemon: not supported on this platform");
+        1
+    }
+}
+
+#[cfg(unix)]
+mod client {
+    use super::{ATTACH, DEFAULT_SCROLLBACK, EXIT, KILL, OK, OUTPUT, RESIZE, WRITE};
+    use crate::frames::{frame, read_frame};
+    use crate::mock::KILLED_STATUS;
+    use crate::pty::{
+        Child, ChildKiller, CommandBuilder, ExitStatus, MasterPty, PtyPair, PtySize, SlavePty,
+    };
+    use crate::{PipeReader, PipeWriter, PortablePty, PortablePtyResult};
+    use serde_json::Value;
+    use std::ffi::OsString;
+    use std::io::{self, ErrorKind, Read, Write};
+    use std::net::Shutdown;
+    use std::os::unix::fs::DirBuilderExt;
+    use std::os::unix::net::UnixStream;
+    use std::path::{Path, PathBuf};
+    use std::process::{Command, Stdio};
+    use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+    use std::time::Duration;
+
+    /// How long the daemon has to answer an attach.
+    const ATTACH_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Input is sent in frames of at most this much.
+    const WRITE_CHUNK: usize = 64 * 1024;
+
+    fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+        mutex.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Where session sockets live unless the config says otherwise.
+    fn default_dir() -> PathBuf {
+        match std::env::var_os("XDG_RUNTIME_DIR") {
+            Some(dir) if !dir.is_empty() => Path::new(&dir).join("portable-pty"),
+            _ => {
+                let uid = unsafe { libc::getuid() };
+                std::env::temp_dir().join(format!("portable-pty-{uid}"))
+            }
+        }
+    }
+
+    /// The socket directory from a config, or the default.
+    pub(super) fn dir(config: Option<&Value>) -> Result<PathBuf, PortablePtyResult> {
+        match config.and_then(|c| c.get("dir")) {
+            Some(dir) => Ok(dir.as_str().ok_or(PortablePtyResult::ErrOpen)?.into()),
+            None => Ok(default_dir()),
+        }
+    }
+
+    /// Create the socket directory `dir` if need be, and check it's a
+    /// directory of ours that no one else can reach into.
+    fn private_dir(dir: &Path) -> Result<(), PortablePtyResult> {
+        use std::os::unix::fs::MetadataExt;
+
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)
+            .map_err(|_| PortablePtyResult::ErrOpen)?;
+        // Not followed: a link could point anywhere.
+        let metadata = std::fs::symlink_metadata(dir).map_err(|_| PortablePtyResult::ErrOpen)?;
+        let ours = metadata.uid() == unsafe { libc::getuid() };
+        match metadata.is_dir() && ours && metadata.mode() & 0o777 == 0o700 {
+            true => Ok(()),
+            false => Err(PortablePtyResult::ErrOpen),
+        }
+    }
+
+    fn valid_name(name: &str) -> bool {
+        !name.is_empty() && !name.starts_with('.') && !name.contains(['/', '\0'])
+    }
+
+    /// The names of the sessions answering in `dir`, sorted.
+    pub(super) fn list(dir: &Path) -> Vec<String> {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return Vec::new();
+        };
+        let mut names: Vec<String> = entries
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                let name = path.file_name()?.to_str()?.strip_suffix(".sock")?;
+                UnixStream::connect(&path).ok()?;
+                Some(name.to_owned())
+            })
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// The helper to start sessions with.
+    fn daemon_path(config: &Value) -> Result<PathBuf, PortablePtyResult> {
+        if let Some(path) = config.get("daemon") {
+            return Ok(path.as_str().ok_or(PortablePtyResult::ErrOpen)?.into());
+        }
+        if let Some(path) = std::env::var_os("PORTABLE_PTY_DAEMON") {
+            return Ok(path.into());
+        }
+        let beside_exe = std::env::current_exe()
+            .ok()
+            .and_then(|exe| Some(exe.parent()?.join("portable-pty-daemon")))
+            .filter(|path| path.is_file());
+        Ok(beside_exe.unwrap_or_else(|| "portable-pty-daemon".into()))
+    }
+
+    /// Start a daemon for a new session on `socket` and wait until it's
+    /// listening.
+    fn start_daemon(config: &Value, socket: &Path, size: PtySize) -> Result<(), PortablePtyResult> {
+        let argv: Vec<OsString> = match config.get("command") {
+            Some(command) => command
+                .as_array()
+                .and_then(|args| args.iter().map(|a| Some(a.as_str()?.into())).collect())
+                .filter(|argv: &Vec<OsString>| !argv.is_empty())
+                .ok_or(PortablePtyResult::ErrOpen)?,
+            None => vec![std::env::var_os("SHELL").unwrap_or_else(|| "/bin/sh".into())],
+        };
+        let scrollback = match config.get("scrollback") {
+            Some(n) => n.as_u64().ok_or(PortablePtyResult::ErrOpen)?,
+            None => DEFAULT_SCROLLBACK as u64,
+        };
+
+        let mut command = Command::new(daemon_path(config)?);
+        command
+            .arg("--socket")
+            .arg(socket)
+            .args(["--rows", &size.rows.to_string()])
+            .args(["--cols", &size.cols.to_string()])
+            .args(["--scrollback", &scrollback.to_string()]);
+        if let Some(cwd) = config.get("cwd") {
+            command
+                .arg("--cwd")
+                .arg(cwd.as_str().ok_or(PortablePtyResult::ErrOpen)?);
+        }
+        if let Some(env) = config.get("env") {
+            let env = env.as_object().ok_or(PortablePtyResult::ErrOpen)?;
+            for (key, value) in env {
+                command.env(key, value.as_str().ok_or(PortablePtyResult::ErrOpen)?);
+            }
+        }
+        command
+            .arg("--")
+            .args(argv)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null());
+
+        let mut daemon = command.spawn().map_err(|_| PortablePtyResult::ErrSpawn)?;
+        // The daemon reports once its child is running, and closes stdout.
+        let mut report = String::new();
+        if let Some(mut stdout) = daemon.stdout.take() {
+            let _ = stdout.read_to_string(&mut report);
+        }
+        // Its first process exits at once. The host may have reaped it
+        // already; either way there's nothing to learn from it.
+        let _ = daemon.wait();
+        match report.lines().next() {
+            Some("ready") => Ok(()),
+            _ => Err(PortablePtyResult::ErrSpawn),
+        }
+    }
+
+    /// Connect to the session on `socket` and attach, sized `size`.
+    fn attach(socket: &Path, size: PtySize) -> io::Result<UnixStream> {
+        let mut stream = UnixStream::connect(socket)?;
+        stream.set_read_timeout(Some(ATTACH_TIMEOUT))?;
+        stream.write_all(&frame(ATTACH, &[]))?;
+        match read_frame(&mut stream)? {
+            (OK, _) => {}
+            _ => return Err(ErrorKind::InvalidData.into()),
+        }
+        stream.set_read_timeout(None)?;
+        stream.write_all(&frame(RESIZE, &resize_payload(size)))?;
+        Ok(stream)
+    }
+
+    fn resize_payload(size: PtySize) -> [u8; 4] {
+        let [r0, r1] = size.rows.to_be_bytes();
+        let [c0, c1] = size.cols.to_be_bytes();
+        [r0, r1, c0, c1]
+    }
+
+    /// Open the `persistent` backend: attach to the named session,
+    /// starting it if it isn't running.
+    pub(super) fn open_config(config: &Value) -> Result<Box<PortablePty>, PortablePtyResult> {
+        let name = config.get("name").and_then(Value::as_str);
+        let name = name
+            .filter(|n| valid_name(n))
+            .ok_or(PortablePtyResult::ErrOpen)?;
+        let size = crate::backend::parse_size(config).ok_or(PortablePtyResult::ErrOpen)?;
+        let dir = dir(Some(config))?;
+        private_dir(&dir)?;
+        let socket = dir.join(format!("{name}.sock"));
+
+        let stream = match attach(&socket, size) {
+            Ok(stream) => stream,
+            Err(_) => {
+                // Nothing answering: whatever is left there is stale.
+                let _ = std::fs::remove_file(&socket);
+                start_daemon(config, &socket, size)?;
+                attach(&socket, size).map_err(|_| PortablePtyResult::ErrOpen)?
+            }
+        };
+
+        let frames = stream.try_clone().map_err(|_| PortablePtyResult::ErrOpen)?;
+        let connection = Arc::new(Connection {
+            stream,
+            sending: Mutex::new(()),
+            size: Mutex::new(size),
+            exit: Mutex::new(None),
+            exited: Condvar::new(),
+        });
+        let (reader, output) = crate::pipe().map_err(|_| PortablePtyResult::ErrOpen)?;
+        let pair = PtyPair {
+            slave: Box::new(PersistSlave),
+            master: Box::new(PersistMaster {
+                connection: Arc::clone(&connection),
+                reader,
+            }),
+        };
+        let mut handle = PortablePty::from_pair(pair)?;
+        handle.child = Some(Box::new(PersistChild {
+            connection: Arc::clone(&connection),
+        }));
+        crate::lifecycle::spawn_thread("portable-pty-persist", move || {
+            pump(connection, frames, output)
+        })
+        .map_err(|_| PortablePtyResult::ErrOpen)?;
+        Ok(handle)
+    }
+
+    /// The handle's connection to its daemon.
+    struct Connection {
+        stream: UnixStream,
+        /// Held while a frame is sent, so frames go out whole.
+        sending: Mutex<()>,
+        size: Mutex<PtySize>,
+        exit: Mutex<Option<u32>>,
+        /// Signalled when `exit` is set.
+        exited: Condvar,
+    }
+
+    impl Connection {
+        fn send(&self, kind: u8, payload: &[u8]) -> io::Result<()> {
+            let _sending = lock(&self.sending);
+            (&self.stream).write_all(&frame(kind, payload))
+        }
+
+        fn exit(&self) -> Option<u32> {
+            *lock(&self.exit)
+        }
+
+        fn set_exit(&self, code: u32) {
+            lock(&self.exit).get_or_insert(code);
+            self.exited.notify_all();
+        }
+    }
+
+    /// Copy output frames into `output` until the session exits or the
+    /// connection drops, then record the exit status.
+    fn pump(connection: Arc<Connection>, mut frames: UnixStream, mut output: PipeWriter) {
+        // If the handle goes away mid-write, get EPIPE rather than a
+        // process-wide SIGPIPE.
+        crate::block_sigpipe();
+
+        // A connection lost without an exit code counts as killed.
+        let mut code = KILLED_STATUS;
+        while let Ok((kind, payload)) = read_frame(&mut frames) {
+            let written = match (kind, payload.as_slice()) {
+                (OUTPUT, bytes) => output.write_all(bytes),
+                (EXIT, &[a, b, c, d]) => {
+                    code = i32::from_be_bytes([a, b, c, d]) as u32;
+                    break;
+                }
+                _ => Ok(()),
+            };
+            if written.is_err() {
+                break;
+            }
+        }
+        // Dropping `output` gives the reader EOF.
+        drop(output);
+        connection.set_exit(code);
+    }
+
+    pub(super) struct PersistMaster {
+        connection: Arc<Connection>,
+        reader: PipeReader,
+    }
+
+    impl Drop for PersistMaster {
+        // Only the connection ends; the daemon carries on.
+        fn drop(&mut self) {
+            let _ = self.connection.stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    impl MasterPty for PersistMaster {
+        fn resize(&self, size: PtySize) -> anyhow::Result<()> {
+            self.connection.send(RESIZE, &resize_payload(size))?;
+            *lock(&self.connection.size) = size;
+            Ok(())
+        }
+
+        fn get_size(&self) -> anyhow::Result<PtySize> {
+            Ok(*lock(&self.connection.size))
+        }
+
+        fn try_clone_reader(&self) -> anyhow::Result<Box<dyn Read + Send>> {
+            Ok(Box::new(self.reader.try_clone()?))
+        }
+
+        fn take_writer(&self) -> anyhow::Result<Box<dyn Write + Send>> {
+            Ok(Box::new(PersistWriter {
+                connection: Arc::clone(&self.connection),
+            }))
+        }
+
+        fn process_group_leader(&self) -> Option<libc::pid_t> {
+            None
+        }
+
+        fn as_raw_fd(&self) -> Option<std::os::fd::RawFd> {
+            use std::os::fd::AsRawFd;
+            Some(self.reader.as_raw_fd())
+        }
+
+        fn tty_name(&self) -> Option<PathBuf> {
+            None
+        }
+    }
+
+    /// Sends input to the daemon as `WRITE` frames.
+    struct PersistWriter {
+        connection: Arc<Connection>,
+    }
+
+    impl Write for PersistWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.connection.exit().is_some() {
+                return Err(ErrorKind::BrokenPipe.into());
+            }
+            let n = buf.len().min(WRITE_CHUNK);
+            self.connection.send(WRITE, &buf[..n])?;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct PersistSlave;
+
+    impl SlavePty for PersistSlave {
+        fn spawn_command(
+            &self,
+            _cmd: CommandBuilder,
+        ) -> anyhow::Result<Box<dyn Child + Send + Sync>> {
+            anyhow::bail!("persistent sessions can't spawn processes")
+        }
+    }
+
+    /// Stands in for the daemon's child: exits when it does.
+    #[derive(Clone)]
+    struct PersistChild {
+        connection: Arc<Connection>,
+    }
+
+    impl std::fmt::Debug for PersistChild {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("PersistChild").finish_non_exhaustive()
+        }
+    }
+
+    impl ChildKiller for PersistChild {
+        fn kill(&mut self) -> io::Result<()> {
+            if self.connection.exit().is_none() {
+                self.connection.send(KILL, &[])?;
+            }
+            Ok(())
+        }
+
+        fn clone_killer(&self) -> Box<dyn ChildKiller + Send + Sync> {
+            Box::new(self.clone())
+        }
+    }
+
+    impl Child for PersistChild {
+        fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+            Ok(self.connection.exit().map(ExitStatus::with_exit_code))
+        }
+
+        fn wait(&mut self) -> io::Result<ExitStatus> {
+            let mut exit = lock(&self.connection.exit);
+            loop {
+                if let Some(code) = *exit {
+                    return Ok(ExitStatus::with_exit_code(code));
+                }
+                exit = self
+                    .connection
+                    .exited
+                    .wait(exit)
+                    .unwrap_or_else(PoisonError::into_inner);
+            }
+        }
+
+        fn process_id(&self) -> Option<u32> {
+            None
+        }
+    }
+}
+
+/// Open the `persistent` backend; see the module docs.
+#[cfg(unix)]
+pub(crate) fn open_config(
+    config: &serde_json::Value,
+) -> Result<Box<PortablePty>, PortablePtyResult> {
+    client::open_config(config)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn open_config(
+    _config: &serde_json::Value,
+) -> Result<Box<PortablePty>, PortablePtyResult> {
+    Err(PortablePtyResult::ErrUnsupported)
+}
+
+#[cfg(unix)]
+fn is_persistent(pty: &PortablePty) -> bool {
+    pty.master
+        .as_ref()
+        .as_any()
+        .downcast_ref::<client::PersistMaster>()
+        .is_some()
+}
+
+#[cfg(not(unix))]
+fn is_persistent(_pty: &PortablePty) -> bool {
+    false
+}
+
+/// Close a persistent session's handle, leaving the session running.
+///
+/// The session can be reattached by opening the `persistent` backend with
+/// the same name. Returns `ErrBackend` if the handle isn't a persistent
+/// session; it stays open then.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_detach(handle: *mut PortablePty) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        if !is_persistent(pty) {
+            return PortablePtyResult::ErrBackend;
+        }
+        if let Some(mut pty) = crate::lifecycle::take(handle) {
+            // With no child to kill, closing only drops the connection.
+            pty.child = None;
+            crate::destroy(pty);
+        }
+        PortablePtyResult::Ok
+    })
+}
+
+/// List the persistent sessions that are running.
+///
+/// - `dir`: null-terminated socket directory, or NULL for the default
+///   (the `dir` of the backend's config).
+/// - `out_names`: receives the names, one per line; free with
+///   `portable_pty_buffer_free`. Empty if there are none.
+///
+/// Returns `ErrUnsupported` on Windows.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_persistent_list(
+    dir: *const c_char,
+    out_names: *mut PortablePtyBuffer,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        if out_names.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+        let dir = (!dir.is_null()).then(|| unsafe { CStr::from_ptr(dir) });
+        match list(dir) {
+            Ok(names) => {
+                unsafe {
+                    *out_names = PortablePtyBuffer::from_vec(names.join("\n").into_bytes());
+                }
+                PortablePtyResult::Ok
+            }
+            Err(e) => e,
+        }
+    })
+}
+
+#[cfg(unix)]
+fn list(dir: Option<&CStr>) -> Result<Vec<String>, PortablePtyResult> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let dir = match dir {
+        Some(dir) => std::ffi::OsStr::from_bytes(dir.to_bytes()).into(),
+        None => client::dir(None)?,
+    };
+    Ok(client::list(&dir))
+}
+
+#[cfg(not(unix))]
+fn list(_dir: Option<&CStr>) -> Result<Vec<String>, PortablePtyResult> {
+    Err(PortablePtyResult::ErrUnsupported)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::expect::PortablePtyExpectMatch;
+    use crate::expect::{portable_pty_expect, portable_pty_expect_match_free};
+    use std::ffi::CString;
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    fn expect(handle: *mut PortablePty, pattern: &str) -> PortablePtyResult {
+        let pattern = CString::new(pattern).unwrap();
+        let mut m = PortablePtyExpectMatch {
+            before: PortablePtyBuffer::EMPTY,
+            matched: PortablePtyBuffer::EMPTY,
+            dropped: 0,
+        };
+        let result = portable_pty_expect(handle, pattern.as_ptr(), false, 5000, &mut m);
+        portable_pty_expect_match_free(&mut m);
+        result
+    }
+
+    fn open(config: &str) -> (PortablePtyResult, *mut PortablePty) {
+        let config = CString::new(config).unwrap();
+        let mut handle = std::ptr::null_mut();
+        let result = crate::backend::portable_pty_open_backend(
+            c"persistent".as_ptr(),
+            config.as_ptr(),
+            &mut handle,
+        );
+        (result, handle)
+    }
+
+    #[test]
+    fn test_detach_and_reattach_with_scrollback() {
+        let dir = std::env::temp_dir().join(format!("portable-pty-persist-{}", std::process::id()));
+        std::fs::DirBuilder::new().mode(0o700).create(&dir).unwrap();
+        // Run the daemon's side in-process rather than via the helper.
+        let options = daemon::Options::parse(
+            [
+                "--socket",
+                dir.join("work.sock").to_str().unwrap(),
+                "--",
+                "/bin/sh",
+                "-c",
+                "echo started; exec cat",
+            ]
+            .map(std::ffi::OsString::from),
+        )
+        .unwrap();
+        let listener = daemon::bind(&options.socket).unwrap();
+        let (session, reader) = daemon::Session::start(&options).unwrap();
+        let server = std::thread::spawn(move || daemon::serve(listener, session, reader));
+
+        let config = format!(r#"{{"name": "work", "dir": "{}"}}"#, dir.display());
+        let (result, handle) = open(&config);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        assert!(matches!(expect(handle, "started"), PortablePtyResult::Ok));
+        let input = b"marker\n";
+        crate::portable_pty_write(handle, input.as_ptr(), input.len());
+        assert!(matches!(expect(handle, "marker"), PortablePtyResult::Ok));
+        assert!(matches!(portable_pty_detach(handle), PortablePtyResult::Ok));
+
+        let c_dir = CString::new(dir.to_str().unwrap()).unwrap();
+        let mut names = PortablePtyBuffer::EMPTY;
+        portable_pty_persistent_list(c_dir.as_ptr(), &mut names);
+        let listed = unsafe { std::slice::from_raw_parts(names.data, names.len) };
+        assert_eq!(listed, b"work");
+        crate::portable_pty_buffer_free(names);
+
+        // Everything from before the detach is replayed.
+        let (result, handle) = open(&config);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        assert!(matches!(expect(handle, "started"), PortablePtyResult::Ok));
+        assert!(matches!(expect(handle, "marker"), PortablePtyResult::Ok));
+
+        // Closing ends the session.
+        crate::portable_pty_close(handle);
+        server.join().unwrap().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_detach_rejects_other_handles() {
+        let mut handle = std::ptr::null_mut();
+        crate::loopback::portable_pty_open_loopback(24, 80, &mut handle);
+        assert!(matches!(
+            portable_pty_detach(handle),
+            PortablePtyResult::ErrBackend
+        ));
+        crate::portable_pty_close(handle);
+
+        let (result, _) = open(r#"{"name": "../escape"}"#);
+        assert!(matches!(result, PortablePtyResult::ErrOpen));
+    }
+
+    #[test]
+    fn test_open_refuses_shared_socket_dir() {
+        let dir = std::env::temp_dir().join(format!("portable-pty-shared-{}", std::process::id()));
+        std::fs::create_dir(&dir).unwrap();
+        // As if another user had made it first, open to all.
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o777)).unwrap();
+        let config = format!(r#"{{"name": "work", "dir": "{}"}}"#, dir.display());
+        let (result, _) = open(&config);
+        assert!(matches!(result, PortablePtyResult::ErrOpen));
+        // Nothing was started in it.
+        assert!(!dir.join("work.sock").exists());
+
+        // Nor through a link to a private one.
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700)).unwrap();
+        let link = dir.with_extension("link");
+        std::os::unix::fs::symlink(&dir, &link).unwrap();
+        let config = format!(r#"{{"name": "work", "dir": "{}"}}"#, link.display());
+        let (result, _) = open(&config);
+        assert!(matches!(result, PortablePtyResult::ErrOpen));
+        std::fs::remove_file(&link).unwrap();
+        std::fs::remove_dir(&dir).unwrap();
+    }
+}
@@ -0,0 +1,260 @@
+//! Children on pipes instead of a terminal.
+//!
+//! `portable_pty_open_piped` opens a handle whose child gets plain pipes
+//! for its standard streams: what the handle writes is the child's stdin,
+//! and its stdout and stderr, merged as with `2>&1`, are what the handle
+//! reads. Spawning, reading, writing, waiting, killing, expect, events and
+//! recording all go through the usual calls, for tools that behave better
+//! off a terminal — no echo, no line editing, no colour, no pager.
+//!
+//! With no terminal in between there's no line discipline either:
+//! `portable_pty_send_eof` closes the child's stdin, after which writes
+//! fail, and on Unix `portable_pty_send_interrupt` and
+//! `portable_pty_send_suspend` signal the child's process group, which it
+//! leads. The size is only stored, for `portable_pty_get_size`. Reads
+//! end once everything holding the output pipe has closed it, which
+//! includes anything the child left running in the background.
+//!
+//! A handle spawns one child; a second spawn returns `ErrSpawn`. Spawn
+//! configs that need a terminal (see `spawn`) return `ErrUnsupported`.
+
+use crate::pty::{Child, CommandBuilder, MasterPty, PtyPair, PtySize, SlavePty};
+use crate::{PortablePty, PortablePtyResult};
+use std::io::{self, PipeReader, PipeWriter, Read, Write};
+use std::sync::{Mutex, PoisonError};
+
+struct PipedMaster {
+    size: Mutex<PtySize>,
+    /// The child's stdout and stderr.
+    output: PipeReader,
+    /// The child's stdin, until the handle takes it.
+    input: Mutex<Option<PipeWriter>>,
+}
+
+impl MasterPty for PipedMaster {
+    fn resize(&self, size: PtySize) -> anyhow::Result<()> {
+        *self.size.lock().unwrap_or_else(PoisonError::into_inner) = size;
+        Ok(())
+    }
+
+    fn get_size(&self) -> anyhow::Result<PtySize> {
+        Ok(*self.size.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    fn try_clone_reader(&self) -> anyhow::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(self.output.try_clone()?))
+    }
+
+    fn take_writer(&self) -> anyhow::Result<Box<dyn Write + Send>> {
+        // Moved out rather than cloned, so that dropping the handle's
+        // writer is what closes the child's stdin.
+        let mut input = self.input.lock().unwrap_or_else(PoisonError::into_inner);
+        match input.take() {
+            Some(writer) => Ok(Box::new(writer)),
+            None => anyhow::bail!("the writer was already taken"),
+        }
+    }
+
+    #[cfg(unix)]
+    fn process_group_leader(&self) -> Option<libc::pid_t> {
+        None
+    }
+
+    #[cfg(unix)]
+    fn as_raw_fd(&self) -> Option<std::os::fd::RawFd> {
+        use std::os::fd::AsRawFd;
+        Some(self.output.as_raw_fd())
+    }
+
+    #[cfg(unix)]
+    fn tty_name(&self) -> Option<std::path::PathBuf> {
+        None
+    }
+}
+
+struct PipedSlave {
+    /// The child's ends of the pipes, until it's spawned.
+    ends: Mutex<Option<(PipeReader, PipeWriter)>>,
+}
+
+impl SlavePty for PipedSlave {
+    fn spawn_command(&self, cmd: CommandBuilder) -> anyhow::Result<Box<dyn Child + Send + Sync>> {
+        let mut ends = self.ends.lock().unwrap_or_else(PoisonError::into_inner);
+        let Some((stdin, stdout)) = ends.take() else {
+            anyhow::bail!("piped handles spawn one child");
+        };
+        let stderr = stdout.try_clone()?;
+        let stdio = [stdin.into(), stdout.into(), stderr.into()];
+        Ok(crate::spawn::spawn_piped(&cmd, stdio)?)
+    }
+}
+
+/// Whether `pty` is a piped handle.
+pub(crate) fn is_piped(pty: &PortablePty) -> bool {
+    pty.master.as_any().downcast_ref::<PipedMaster>().is_some()
+}
+
+/// Close the child's stdin, flushing what's been written first.
+pub(crate) fn close_input(pty: &PortablePty) -> PortablePtyResult {
+    let mut writer = pty.writer.lock().unwrap_or_else(PoisonError::into_inner);
+    if writer.flush().is_err() {
+        return PortablePtyResult::ErrWrite;
+    }
+    // Unbuffered, so writes fail straight away rather than on a flush.
+    *writer = io::BufWriter::with_capacity(0, Box::new(Closed));
+    PortablePtyResult::Ok
+}
+
+/// Stands in for the child's stdin once it's closed.
+struct Closed;
+
+impl Write for Closed {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::ErrorKind::BrokenPipe.into())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Open a piped handle of `size`, with nothing spawned yet.
+pub(crate) fn open(size: PtySize) -> Result<Box<PortablePty>, PortablePtyResult> {
+    let (Ok((output, output_writer)), Ok((input_reader, input))) =
+        (std::io::pipe(), std::io::pipe())
+    else {
+        return Err(PortablePtyResult::ErrOpen);
+    };
+    PortablePty::from_pair(PtyPair {
+        slave: Box::new(PipedSlave {
+            ends: Mutex::new(Some((input_reader, output_writer))),
+        }),
+        master: Box::new(PipedMaster {
+            size: Mutex::new(size),
+            output,
+            input: Mutex::new(Some(input)),
+        }),
+    })
+}
+
+/// Open a handle whose child runs on pipes instead of a terminal: what the
+/// handle writes is the child's stdin, and its stdout and stderr, merged,
+/// are what the handle reads. `portable_pty_send_eof` closes the child's
+/// stdin, and on Unix `portable_pty_send_interrupt` and
+/// `portable_pty_send_suspend` signal its process group. Reads end once
+/// everything holding the output pipe has closed it. One child per
+/// handle; spawn configs that need a terminal return `ErrUnsupported`.
+///
+/// - `rows`, `cols`: the size `portable_pty_get_size` reports; the child
+///   never sees it.
+/// - `out`: receives the new handle; spawn into it with
+///   `portable_pty_spawn` and close it with `portable_pty_close`.
+///
+/// Returns `ErrOpen` if the pipes can't be made.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_open_piped(
+    rows: u16,
+    cols: u16,
+    out: *mut *mut PortablePty,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        if out.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+        let size = PtySize {
+            rows,
+            cols,
+            pixel_width: 0,
+            pixel_height: 0,
+        };
+        match open(size) {
+            Ok(handle) => {
+                unsafe {
+                    *out = crate::lifecycle::register(handle);
+                }
+                PortablePtyResult::Ok
+            }
+            Err(e) => e,
+        }
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::tests::read_string;
+    use crate::{portable_pty_close, portable_pty_wait_blocking, portable_pty_write};
+    use std::ffi::OsString;
+
+    fn open_and_spawn(argv: &[&str]) -> *mut PortablePty {
+        let mut handle = std::ptr::null_mut();
+        let result = portable_pty_open_piped(24, 80, &mut handle);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        let args: Vec<OsString> = argv.iter().map(OsString::from).collect();
+        let builder = crate::build_command(args[0].clone(), Some(args), None);
+        let result = unsafe { &mut *handle }.spawn(builder);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        handle
+    }
+
+    /// Everything the handle reads, to the end.
+    fn read_all(handle: *mut PortablePty) -> String {
+        let mut output = String::new();
+        loop {
+            match read_string(handle) {
+                s if s.is_empty() => return output,
+                s => output.push_str(&s),
+            }
+        }
+    }
+
+    #[test]
+    fn test_child_has_pipes() {
+        let script = "[ -t 0 ] || [ -t 1 ] && echo tty || echo pipes; echo oops >&2";
+        let handle = open_and_spawn(&["sh", "-c", script]);
+        assert_eq!(read_all(handle), "pipes\noops\n");
+        let mut status = -1;
+        let result = portable_pty_wait_blocking(handle, &mut status);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        assert_eq!(status, 0);
+
+        // One child per handle.
+        let builder = crate::build_command("true".into(), None, None);
+        let result = unsafe { &mut *handle }.spawn(builder);
+        assert!(matches!(result, PortablePtyResult::ErrSpawn));
+        portable_pty_close(handle);
+    }
+
+    #[test]
+    fn test_send_eof_closes_stdin() {
+        let handle = open_and_spawn(&["cat"]);
+        assert_eq!(portable_pty_write(handle, b"hello\n".as_ptr(), 6), 6);
+        assert!(matches!(
+            crate::input::portable_pty_send_eof(handle),
+            PortablePtyResult::Ok
+        ));
+        // No echo, and cat sees the end of its input.
+        assert_eq!(read_all(handle), "hello\n");
+        assert_eq!(portable_pty_write(handle, b"x".as_ptr(), 1), -1);
+        portable_pty_close(handle);
+    }
+
+    #[test]
+    fn test_send_interrupt_signals_the_child() {
+        let handle = open_and_spawn(&[
+            "sh",
+            "-c",
+            "trap 'echo caught; exit 3' INT; echo ready; while :; do sleep 0.05; done",
+        ]);
+        assert_eq!(read_string(handle), "ready\n");
+        assert!(matches!(
+            crate::input::portable_pty_send_interrupt(handle),
+            PortablePtyResult::Ok
+        ));
+        assert_eq!(read_all(handle), "caught\n");
+        let mut status = -1;
+        portable_pty_wait_blocking(handle, &mut status);
+        assert_eq!(status, 3);
+        portable_pty_close(handle);
+    }
+}
@@ -0,0 +1,184 @@
+//! A process-wide policy on what may be spawned.
+//!
+//! `portable_pty_set_spawn_policy` registers a callback that decides on
+//! each command before it starts, for kiosk terminals and the like that
+//! only run a fixed set of programs. It may let the command run, refuse it
+//! — the spawn then returns `ErrPolicy` and nothing runs — or replace its
+//! arguments with `portable_pty_policy_rewrite`, to pin a program to its
+//! full path, say. The policy sees commands before the audit hook does, so
+//! audit logs show what actually runs.
+
+use crate::audit::c_string;
+use crate::pty::CommandBuilder;
+use crate::{os_string_array, PortablePtyResult};
+use std::ffi::{c_char, c_void, CString, OsString};
+use std::sync::{Mutex, PoisonError};
+
+/// A command being decided on. Changed with `portable_pty_policy_rewrite`
+/// during the callback.
+pub struct PortablePtyPolicyRewrite {
+    argv: Option<Vec<OsString>>,
+}
+
+/// Spawn policy callback: `(userdata, program, argv, argc, rewrite)`, with
+/// `argv` holding `argc` arguments, `argv[0]` included, then NULL. All are
+/// only valid for the duration of the call. Returns false to refuse the
+/// spawn.
+pub type PortablePtySpawnPolicyCallback = extern "C" fn(
+    *mut c_void,
+    *const c_char,
+    *const *const c_char,
+    usize,
+    *mut PortablePtyPolicyRewrite,
+) -> bool;
+
+struct Policy {
+    func: PortablePtySpawnPolicyCallback,
+    userdata: *mut c_void,
+}
+
+// The userdata pointer is opaque to us; the embedder promises it may be
+// used from whichever thread spawns.
+unsafe impl Send for Policy {}
+
+static POLICY: Mutex<Option<Policy>> = Mutex::new(None);
+
+/// Put `builder` to the policy, if one is registered, and apply what it
+/// rewrites.
+///
+/// Returns `ErrPolicy` if the policy refuses it.
+pub(crate) fn check(builder: &mut CommandBuilder) -> PortablePtyResult {
+    // Copied out so the policy can re-register without deadlocking.
+    let policy = POLICY
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .as_ref()
+        .map(|policy| (policy.func, policy.userdata));
+    let Some((func, userdata)) = policy else {
+        return PortablePtyResult::Ok;
+    };
+
+    let args: Vec<CString> = builder.get_argv().iter().map(|a| c_string(a)).collect();
+    let mut argv: Vec<*const c_char> = args.iter().map(|a| a.as_ptr()).collect();
+    argv.push(std::ptr::null());
+    let program = args.first().map_or(std::ptr::null(), |a| a.as_ptr());
+    let mut rewrite = PortablePtyPolicyRewrite { argv: None };
+    if !func(userdata, program, argv.as_ptr(), args.len(), &mut rewrite) {
+        return PortablePtyResult::ErrPolicy;
+    }
+    if let Some(args) = rewrite.argv {
+        *builder.get_argv_mut() = args;
+    }
+    PortablePtyResult::Ok
+}
+
+/// Replace the arguments of the command being decided on. Only valid from
+/// within the policy callback, on the `rewrite` it was given.
+///
+/// - `argv`: NULL-terminated arguments, `argv[0]` being the program to
+///   run; at least one. Taken as `portable_pty_spawn` takes them, and
+///   copied before this returns.
+///
+/// Returns `ErrSpawn` if `argv` is empty or, off Unix, not UTF-8, leaving
+/// the command as it was.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_policy_rewrite(
+    rewrite: *mut PortablePtyPolicyRewrite,
+    argv: *const *const c_char,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let rewrite = match unsafe { rewrite.as_mut() } {
+            Some(r) => r,
+            None => return PortablePtyResult::ErrNull,
+        };
+        if argv.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+        match unsafe { os_string_array(argv) } {
+            Some(args) if !args.is_empty() => {
+                rewrite.argv = Some(args);
+                PortablePtyResult::Ok
+            }
+            _ => PortablePtyResult::ErrSpawn,
+        }
+    })
+}
+
+/// Register (or with a NULL `callback`, remove) the spawn policy.
+///
+/// The policy applies to every handle, from the next spawn on, and is
+/// called before each child starts; returning false refuses it with
+/// `ErrPolicy`.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_set_spawn_policy(
+    callback: Option<
+        extern "C" fn(
+            *mut c_void,
+            *const c_char,
+            *const *const c_char,
+            usize,
+            *mut PortablePtyPolicyRewrite,
+        ) -> bool,
+    >,
+    userdata: *mut c_void,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        *POLICY.lock().unwrap_or_else(PoisonError::into_inner) =
+            callback.map(|func| Policy { func, userdata });
+        PortablePtyResult::Ok
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::tests::read_string;
+    use std::ffi::CStr;
+
+    /// Refuses `kiosk-refused` and turns `kiosk-greet` into an `echo`;
+    /// lets the other tests' children by.
+    extern "C" fn policy(
+        _userdata: *mut c_void,
+        program: *const c_char,
+        argv: *const *const c_char,
+        argc: usize,
+        rewrite: *mut PortablePtyPolicyRewrite,
+    ) -> bool {
+        let program = unsafe { CStr::from_ptr(program) };
+        if program == c"kiosk-greet" {
+            assert_eq!(argc, 2);
+            let name = unsafe { CStr::from_ptr(*argv.add(1)) }.to_str().unwrap();
+            let script = CString::new(format!("echo hello {name}")).unwrap();
+            let argv = [
+                c"sh".as_ptr(),
+                c"-c".as_ptr(),
+                script.as_ptr(),
+                std::ptr::null(),
+            ];
+            let result = portable_pty_policy_rewrite(rewrite, argv.as_ptr());
+            assert!(matches!(result, PortablePtyResult::Ok));
+        }
+        program != c"kiosk-refused"
+    }
+
+    #[test]
+    fn test_policy_rewrites_and_refuses() {
+        portable_pty_set_spawn_policy(Some(policy), std::ptr::null_mut());
+
+        let handle = crate::tests::open_and_spawn("kiosk-greet", &["kiosk-greet", "kiosk"]);
+        let output = read_string(handle);
+        assert!(output.contains("hello kiosk"), "{output:?}");
+        crate::portable_pty_close(handle);
+
+        let mut handle = std::ptr::null_mut();
+        crate::portable_pty_open(24, 80, &mut handle);
+        let argv = [c"kiosk-refused".as_ptr(), std::ptr::null()];
+        let cmd = c"kiosk-refused".as_ptr();
+        let result = crate::portable_pty_spawn(handle, cmd, argv.as_ptr(), std::ptr::null());
+        assert!(matches!(result, PortablePtyResult::ErrPolicy));
+        assert_eq!(crate::portable_pty_child_pid(handle), -1);
+        crate::portable_pty_close(handle);
+
+        portable_pty_set_spawn_policy(None, std::ptr::null_mut());
+    }
+}
@@ -0,0 +1,104 @@
+//! Reused buffers for output fanned out to other readers.
+//!
+//! Output copied for a TCP client, a control-socket watcher or a session
+//! daemon's clients used to cost an allocation per chunk, which adds up
+//! under a build log's worth of output. Those copies now come from here,
+//! and go back when the last reader is done with them. The pool keeps a
+//! few buffers of modest size; anything past that is freed as before.
+
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+/// Most buffers kept for reuse.
+const MAX_KEPT: usize = 32;
+
+/// Largest buffer kept for reuse; a rare bigger one isn't worth holding.
+const MAX_KEPT_CAPACITY: usize = 64 * 1024;
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Buffers free for reuse.
+struct Pool {
+    free: Mutex<Vec<Vec<u8>>>,
+}
+
+static POOL: Pool = Pool::new();
+
+impl Pool {
+    const fn new() -> Self {
+        Pool {
+            free: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// An empty buffer with room for at least `capacity` bytes.
+    fn take(&self, capacity: usize) -> Vec<u8> {
+        let mut buffer = lock(&self.free).pop().unwrap_or_default();
+        buffer.reserve(capacity);
+        buffer
+    }
+
+    fn give(&self, mut buffer: Vec<u8>) {
+        if buffer.capacity() == 0 || buffer.capacity() > MAX_KEPT_CAPACITY {
+            return;
+        }
+        buffer.clear();
+        let mut free = lock(&self.free);
+        if free.len() < MAX_KEPT {
+            free.push(buffer);
+        }
+    }
+}
+
+/// Bytes shared between readers, their buffer returned to the pool when
+/// the last one drops them.
+pub(crate) struct Chunk(Vec<u8>);
+
+impl Chunk {
+    /// A chunk filled by `fill`, from a pooled buffer with room for
+    /// `capacity` bytes.
+    pub(crate) fn build(capacity: usize, fill: impl FnOnce(&mut Vec<u8>)) -> Arc<Chunk> {
+        let mut buffer = POOL.take(capacity);
+        fill(&mut buffer);
+        Arc::new(Chunk(buffer))
+    }
+}
+
+impl Deref for Chunk {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Drop for Chunk {
+    fn drop(&mut self) {
+        POOL.give(std::mem::take(&mut self.0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reuses_buffers() {
+        // A private pool: the global one is shared with every other test.
+        let pool = Pool::new();
+        let mut buffer = pool.take(100);
+        buffer.extend_from_slice(b"output");
+        let address = buffer.as_ptr();
+        pool.give(buffer);
+
+        let buffer = pool.take(10);
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.as_ptr(), address);
+        pool.give(buffer);
+
+        pool.give(Vec::with_capacity(MAX_KEPT_CAPACITY + 1));
+        assert_eq!(lock(&pool.free).len(), 1);
+    }
+}
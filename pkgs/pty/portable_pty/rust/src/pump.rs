@@ -0,0 +1,435 @@
+//! Reading on a background thread.
+//!
+//! `portable_pty_set_data_callback` starts a thread that reads the handle
+//! and passes each chunk of output to a C callback as it arrives, so an
+//! event-driven binding needn't keep a thread of its own blocked in
+//! `portable_pty_read`. The output is read as `portable_pty_read` reads
+//! it — tracked, recorded, matched and held back by flow control — and
+//! the thread is then the handle's reader: nothing else may read it while
+//! the callback is set.
+//!
+//! `portable_pty_pump_start` starts the same thread with a ring buffer
+//! for it to fill instead, which `portable_pty_drain` empties whenever
+//! the embedder gets round to it. The child never blocks on a full
+//! terminal waiting for a slow reader; if the embedder falls behind by
+//! more than the buffer holds, the oldest output is dropped, and
+//! `portable_pty_pump_dropped` says how much.
+//!
+//! The thread polls the master so it can be stopped, which needs a backend
+//! with a file descriptor to poll: a local Unix PTY, a piped or loopback
+//! handle. On Windows, and for the other backends, starting it returns
+//! `ErrUnsupported`.
+
+use crate::lifecycle::{HandleRef, ThreadGroup};
+use crate::nonblocking::PORTABLE_PTY_READ_EOF;
+use crate::{PortablePty, PortablePtyResult};
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::ffi::c_void;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+/// Most output passed to the callback at once.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Ring buffer size when the caller passes 0.
+const DEFAULT_RING_CAPACITY: usize = 1024 * 1024;
+
+/// How long the thread waits for output before looking whether it's been
+/// stopped.
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Data callback: `(userdata, data, len)`.
+pub type PortablePtyDataCallback = extern "C" fn(*mut c_void, *const u8, usize);
+
+/// Where the thread puts what it reads; an empty chunk is end of file.
+pub(crate) type Sink = Box<dyn FnMut(&[u8]) + Send>;
+
+thread_local! {
+    /// Whether this thread is a pump's, so stopping it from its own sink
+    /// doesn't wait on itself.
+    static ON_PUMP: Cell<bool> = const { Cell::new(false) };
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Output read by the thread and not yet drained.
+struct Ring {
+    bytes: VecDeque<u8>,
+    capacity: usize,
+    /// Bytes dropped to make room.
+    dropped: u64,
+    /// Output has ended.
+    ended: bool,
+}
+
+impl Ring {
+    fn push(&mut self, chunk: &[u8]) {
+        let over = (self.bytes.len() + chunk.len()).saturating_sub(self.capacity);
+        self.dropped += over as u64;
+        // Older output goes first, then the start of the chunk itself.
+        let held = over.min(self.bytes.len());
+        self.bytes.drain(..held);
+        self.bytes.extend(&chunk[over - held..]);
+    }
+
+    fn take(&mut self, buf: &mut [u8]) -> usize {
+        let n = self.bytes.len().min(buf.len());
+        for (to, from) in buf.iter_mut().zip(self.bytes.drain(..n)) {
+            *to = from;
+        }
+        n
+    }
+}
+
+/// A handle's reader thread, while one is running.
+pub(crate) struct Pump {
+    stopped: Arc<AtomicBool>,
+    threads: Arc<ThreadGroup>,
+    /// What it fills, if it was started by `portable_pty_pump_start`.
+    ring: Option<Arc<Mutex<Ring>>>,
+}
+
+impl Pump {
+    fn stop(self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        if !ON_PUMP.get() {
+            self.threads.wait();
+        }
+    }
+}
+
+/// Read `handle` into `sink` until end of file or until stopped.
+fn pump(handle: HandleRef, stopped: Arc<AtomicBool>, mut sink: Sink) {
+    ON_PUMP.set(true);
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    // The handle may be gone once `stopped` is set, so it's checked before
+    // every use.
+    while !stopped.load(Ordering::Relaxed) {
+        let pty = handle.get();
+        let n = match crate::nonblocking::ready(pty, Some(STOP_CHECK_INTERVAL)) {
+            Ok(false) => continue,
+            Ok(true) => pty.read_output(&mut buf).unwrap_or(0),
+            Err(_) => 0,
+        };
+        sink(&buf[..n]);
+        if n == 0 {
+            return;
+        }
+    }
+}
+
+/// Stop the handle's reader thread, if it has one, and wait for it to end
+/// unless that's the caller.
+pub(crate) fn stop(pty: &PortablePty) {
+    let pump = lock(&pty.pump).take();
+    if let Some(pump) = pump {
+        pump.stop();
+    }
+}
+
+/// Start a reader thread feeding `sink`, replacing any the handle has.
+pub(crate) fn start(pty: &PortablePty, sink: Sink) -> PortablePtyResult {
+    start_with(pty, sink, None)
+}
+
+/// `start`, noting the ring buffer `sink` fills, if it's one.
+fn start_with(pty: &PortablePty, sink: Sink, ring: Option<Arc<Mutex<Ring>>>) -> PortablePtyResult {
+    #[cfg(unix)]
+    let pollable = pty.master.as_raw_fd().is_some();
+    #[cfg(not(unix))]
+    let pollable = false;
+    if !pollable {
+        return PortablePtyResult::ErrUnsupported;
+    }
+    stop(pty);
+
+    let stopped = Arc::new(AtomicBool::new(false));
+    let threads: Arc<ThreadGroup> = Arc::default();
+    let handle = HandleRef::new(pty);
+    let name = crate::lifecycle::thread_name(pty, "pump");
+    let flag = Arc::clone(&stopped);
+    if threads
+        .spawn(&name, move || pump(handle, flag, sink))
+        .is_err()
+    {
+        return PortablePtyResult::ErrUnsupported;
+    }
+    *lock(&pty.pump) = Some(Pump {
+        stopped,
+        threads,
+        ring,
+    });
+    PortablePtyResult::Ok
+}
+
+/// The callback and its userdata, for the reader thread.
+struct Callback {
+    func: PortablePtyDataCallback,
+    userdata: *mut c_void,
+}
+
+// The userdata pointer is opaque to us; the embedder promises it may be
+// used from the reader thread.
+unsafe impl Send for Callback {}
+
+/// Register (or with a NULL `callback`, remove) a callback that gets the
+/// handle's output as a background thread reads it. Output is read as
+/// `portable_pty_read` reads it, tracked, recorded and held back by flow
+/// control, and nothing else may read the handle while this is set.
+///
+/// - `callback`: called on the reader thread with `userdata` and each
+///   chunk of output, valid only for the call; then once with a length of
+///   0 at end of file, or if reading fails, after which the thread ends.
+///   It may set or remove the callback, and close the handle.
+///
+/// Replaces any callback already set. Removing it waits for the thread to
+/// end, so the callback isn't called afterwards unless that's being done
+/// from inside it. Returns `ErrUnsupported` where the handle can't be
+/// polled, on Windows and any but a local Unix PTY, piped or loopback
+/// handle, or the thread can't start.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_set_data_callback(
+    handle: *const PortablePty,
+    callback: Option<extern "C" fn(*mut c_void, *const u8, usize)>,
+    userdata: *mut c_void,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        let Some(func) = callback else {
+            stop(pty);
+            return PortablePtyResult::Ok;
+        };
+        let callback = Callback { func, userdata };
+        start(
+            pty,
+            Box::new(move |chunk| {
+                let callback = &callback;
+                (callback.func)(callback.userdata, chunk.as_ptr(), chunk.len())
+            }),
+        )
+    })
+}
+
+/// The ring buffer of the handle's reader thread, if it has one.
+fn ring(pty: &PortablePty) -> Option<Arc<Mutex<Ring>>> {
+    lock(&pty.pump).as_ref()?.ring.clone()
+}
+
+/// Start reading the handle on a background thread into a ring buffer,
+/// for `portable_pty_drain` to empty. The child never waits on a slow
+/// embedder: past the buffer's capacity the oldest output is dropped, and
+/// `portable_pty_pump_dropped` says how much.
+///
+/// - `capacity`: the most output held undrained, in bytes; 0 for 1 MiB.
+///
+/// Replaces any reader thread or data callback the handle has. Returns
+/// `ErrUnsupported` where the handle can't be polled, on Windows and any
+/// but a local Unix PTY, piped or loopback handle, or the thread can't
+/// start.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_pump_start(
+    handle: *const PortablePty,
+    capacity: usize,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        let capacity = match capacity {
+            0 => DEFAULT_RING_CAPACITY,
+            n => n,
+        };
+        let ring = Arc::new(Mutex::new(Ring {
+            bytes: VecDeque::new(),
+            capacity,
+            dropped: 0,
+            ended: false,
+        }));
+        let filled = Arc::clone(&ring);
+        let sink: Sink = Box::new(move |chunk| {
+            let mut ring = lock(&filled);
+            match chunk {
+                [] => ring.ended = true,
+                chunk => ring.push(chunk),
+            }
+        });
+        start_with(pty, sink, Some(ring))
+    })
+}
+
+/// Stop the handle's reader thread, whether it was started by
+/// `portable_pty_pump_start` or for a data callback, and wait for it to
+/// end. Output not yet drained is discarded. Harmless if there's none.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_pump_stop(handle: *const PortablePty) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        stop(pty);
+        PortablePtyResult::Ok
+    })
+}
+
+/// Take output the reader thread started by `portable_pty_pump_start` has
+/// read, without waiting for more.
+///
+/// Returns the number of bytes copied into `buf`, 0 if there are none
+/// yet, `PORTABLE_PTY_READ_EOF` once output has ended and everything has
+/// been drained, or -1 on error, including if the thread isn't running.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_drain(handle: *const PortablePty, buf: *mut u8, len: usize) -> i64 {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return -1,
+        };
+        if buf.is_null() || len == 0 {
+            return -1;
+        }
+        let Some(ring) = ring(pty) else {
+            return -1;
+        };
+        let mut ring = lock(&ring);
+        let slice = unsafe { std::slice::from_raw_parts_mut(buf, len) };
+        match ring.take(slice) {
+            0 if ring.ended => PORTABLE_PTY_READ_EOF,
+            n => n as i64,
+        }
+    })
+}
+
+/// Get how much output the reader thread started by
+/// `portable_pty_pump_start` has dropped for want of room, in bytes.
+///
+/// Returns `ErrUnsupported` if the thread isn't running.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_pump_dropped(
+    handle: *const PortablePty,
+    out_bytes: *mut u64,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        if out_bytes.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+        let Some(ring) = ring(pty) else {
+            return PortablePtyResult::ErrUnsupported;
+        };
+        unsafe { *out_bytes = lock(&ring).dropped };
+        PortablePtyResult::Ok
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::tests::open_and_spawn;
+    use std::sync::mpsc::{self, Sender};
+
+    extern "C" fn forward(userdata: *mut c_void, data: *const u8, len: usize) {
+        let sender = unsafe { &*(userdata as *const Mutex<Sender<Vec<u8>>>) };
+        let chunk = unsafe { std::slice::from_raw_parts(data, len) }.to_vec();
+        let _ = lock(sender).send(chunk);
+    }
+
+    #[test]
+    fn test_data_callback_gets_output() {
+        let handle = open_and_spawn("sh", &["sh", "-c", "read line; echo got $line"]);
+        let (sender, received) = mpsc::channel::<Vec<u8>>();
+        let sender = Mutex::new(sender);
+        let userdata = &sender as *const _ as *mut c_void;
+        let result = portable_pty_set_data_callback(handle, Some(forward), userdata);
+        assert!(matches!(result, PortablePtyResult::Ok));
+
+        crate::portable_pty_write(handle, b"x\n".as_ptr(), 2);
+        let mut output = Vec::new();
+        loop {
+            let chunk = received.recv_timeout(Duration::from_secs(5)).unwrap();
+            if chunk.is_empty() {
+                break;
+            }
+            output.extend_from_slice(&chunk);
+        }
+        assert!(String::from_utf8_lossy(&output).contains("got x"));
+
+        let result = portable_pty_set_data_callback(handle, None, std::ptr::null_mut());
+        assert!(matches!(result, PortablePtyResult::Ok));
+        crate::portable_pty_close(handle);
+    }
+
+    #[test]
+    fn test_close_stops_the_reader() {
+        let handle = open_and_spawn("cat", &["cat"]);
+        let (sender, received) = mpsc::channel::<Vec<u8>>();
+        let sender = Mutex::new(sender);
+        let userdata = &sender as *const _ as *mut c_void;
+        portable_pty_set_data_callback(handle, Some(forward), userdata);
+        crate::portable_pty_write(handle, b"hi\n".as_ptr(), 3);
+        assert!(!received
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap()
+            .is_empty());
+        crate::portable_pty_close(handle);
+
+        // Closing waited for the thread, which never saw end of file.
+        while let Ok(chunk) = received.try_recv() {
+            assert!(!chunk.is_empty());
+        }
+    }
+
+    /// Drain `handle` until output ends.
+    fn drain_all(handle: *mut PortablePty) -> Vec<u8> {
+        let mut output = Vec::new();
+        let mut buf = [0u8; 3];
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        loop {
+            match portable_pty_drain(handle, buf.as_mut_ptr(), buf.len()) {
+                PORTABLE_PTY_READ_EOF => return output,
+                -1 => panic!("portable_pty_drain failed"),
+                0 => std::thread::sleep(Duration::from_millis(10)),
+                n => output.extend_from_slice(&buf[..n as usize]),
+            }
+            assert!(std::time::Instant::now() < deadline, "output never ended");
+        }
+    }
+
+    #[test]
+    fn test_drain_pumped_output() {
+        let handle = open_and_spawn("sh", &["sh", "-c", "sleep 0.2; echo one; echo two"]);
+        let mut buf = [0u8; 16];
+        assert_eq!(portable_pty_drain(handle, buf.as_mut_ptr(), buf.len()), -1);
+        let result = portable_pty_pump_start(handle, 0);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        assert_eq!(drain_all(handle), b"one\r\ntwo\r\n");
+        portable_pty_pump_stop(handle);
+        crate::portable_pty_close(handle);
+    }
+
+    #[test]
+    fn test_full_ring_drops_oldest() {
+        let handle = open_and_spawn("printf", &["printf", "abcdefgh"]);
+        portable_pty_pump_start(handle, 4);
+        // Nothing drained until the child is done.
+        while !lock(&ring(unsafe { &*handle }).unwrap()).ended {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(drain_all(handle), b"efgh");
+        let mut dropped = 0;
+        portable_pty_pump_dropped(handle, &mut dropped);
+        assert_eq!(dropped, 4);
+        crate::portable_pty_close(handle);
+    }
+}
@@ -0,0 +1,145 @@
+//! Terminal queries answered through the read stream.
+//!
+//! When the other end of the handle is a terminal (or something emulating
+//! one), it answers control-sequence queries inline with its ordinary
+//! output. The helpers here send a query, pick the reply out of the stream
+//! and leave every other byte buffered for the next `portable_pty_read`, so
+//! output that arrives around the reply isn't lost or reordered.
+
+use crate::expect::{deadline_after, remaining};
+use crate::{PortablePty, PortablePtyResult};
+use regex::bytes::Regex;
+
+/// Device Status Report: request the cursor position.
+const DSR_CURSOR_POSITION: &[u8] = b"\x1b[6n";
+
+/// Cursor Position Report: `ESC [ row ; col R`.
+const CPR_PATTERN: &str = r"\x1b\[(\d+);(\d+)R";
+
+/// Ask the peer terminal for the cursor position.
+///
+/// Writes `ESC[6n` and reads until a `ESC[row;colR` reply arrives or
+/// `timeout_ms` elapses (negative waits indefinitely). Other output read in
+/// the meantime, before or after the reply, stays buffered on the handle in
+/// order. `row` and `col` are 1-based.
+///
+/// Returns `ErrTimeout` if no reply arrived, `ErrEof` if the peer closed
+/// first. Note that a modified F3 key (`ESC[1;<mod>R`) is indistinguishable
+/// from a reply.
+///
+/// Timeouts rely on polling the master and are only honored on Unix.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_query_cursor_position(
+    handle: *mut PortablePty,
+    timeout_ms: i32,
+    out_row: *mut u16,
+    out_col: *mut u16,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        if out_row.is_null() || out_col.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+
+        let deadline = deadline_after(timeout_ms);
+        if pty.write_input(DSR_CURSOR_POSITION).is_err() {
+            return PortablePtyResult::ErrWrite;
+        }
+
+        let cpr = Regex::new(CPR_PATTERN).expect("valid CPR pattern");
+        let mut buffer = pty.take_pending();
+        let mut chunk = [0u8; 4096];
+
+        let result = loop {
+            if let Some(caps) = cpr.captures(&buffer) {
+                let whole = caps.get(0).unwrap().range();
+                let number = |i: usize| {
+                    std::str::from_utf8(&caps[i])
+                        .ok()
+                        .and_then(|s| s.parse::<u16>().ok())
+                        .unwrap_or(u16::MAX)
+                };
+                let (row, col) = (number(1), number(2));
+                buffer.drain(whole);
+                unsafe {
+                    *out_row = row;
+                    *out_col = col;
+                }
+                break PortablePtyResult::Ok;
+            }
+
+            match pty.wait_readable(remaining(deadline)) {
+                Ok(true) => {}
+                Ok(false) => break PortablePtyResult::ErrTimeout,
+                Err(_) => break PortablePtyResult::ErrRead,
+            }
+            match pty.read_master(&mut chunk) {
+                Ok(0) => break PortablePtyResult::ErrEof,
+                Ok(n) => buffer.extend_from_slice(&chunk[..n]),
+                Err(_) => break PortablePtyResult::ErrRead,
+            }
+        };
+
+        pty.unread(buffer);
+        result
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::expect::{
+        portable_pty_expect, portable_pty_expect_match_free, PortablePtyExpectMatch,
+    };
+    use crate::tests::{open_and_spawn, read_string};
+    use crate::{portable_pty_close, PortablePtyBuffer};
+    use std::ffi::CString;
+
+    #[test]
+    fn test_cursor_position_reply_is_extracted() {
+        // Stand-in terminal: answers the 4-byte query with a CPR wrapped in
+        // ordinary output.
+        let handle = open_and_spawn(
+            "/bin/sh",
+            &[
+                "sh",
+                "-c",
+                r"stty raw -echo; printf ready; dd bs=1 count=4 >/dev/null 2>&1; printf 'mid\033[12;34Rafter'; sleep 5",
+            ],
+        );
+        let ready = CString::new("ready").unwrap();
+        let mut m = PortablePtyExpectMatch {
+            before: PortablePtyBuffer::EMPTY,
+            matched: PortablePtyBuffer::EMPTY,
+            dropped: 0,
+        };
+        let result = portable_pty_expect(handle, ready.as_ptr(), false, 2000, &mut m);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        portable_pty_expect_match_free(&mut m);
+
+        let (mut row, mut col) = (0, 0);
+        let result = portable_pty_query_cursor_position(handle, 2000, &mut row, &mut col);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        assert_eq!((row, col), (12, 34));
+
+        let mut rest = read_string(handle);
+        while !rest.contains("after") {
+            rest.push_str(&read_string(handle));
+        }
+        assert_eq!(rest, "midafter");
+
+        portable_pty_close(handle);
+    }
+
+    #[test]
+    fn test_cursor_position_timeout() {
+        let handle = open_and_spawn("/bin/sh", &["sh", "-c", "stty -echo; sleep 5"]);
+        let (mut row, mut col) = (0, 0);
+        let result = portable_pty_query_cursor_position(handle, 200, &mut row, &mut col);
+        assert!(matches!(result, PortablePtyResult::ErrTimeout));
+        portable_pty_close(handle);
+    }
+}
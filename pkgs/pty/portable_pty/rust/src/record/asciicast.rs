@@ -0,0 +1,161 @@
+//! asciicast v2 writer.
+//!
+//! The file is a JSON header line followed by one `[time, code, data]`
+//! array per event. The header is written up front with trailing padding so
+//! that `finish` can overwrite it in place with the final duration (not
+//! possible for compressed files, which go without).
+
+use super::sink::Sink;
+use super::{Event, RecordWriter};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Room left in the header line for `, "duration": <secs>`.
+const HEADER_RESERVE: usize = 32;
+
+pub(super) struct AsciicastWriter {
+    file: Sink,
+    rows: u16,
+    cols: u16,
+    timestamp: u64,
+    /// Length of the header line, padding included.
+    header_len: usize,
+    /// Incomplete UTF-8 sequences held back until the next chunk.
+    output_tail: Vec<u8>,
+    input_tail: Vec<u8>,
+}
+
+impl AsciicastWriter {
+    pub(super) fn new(file: Sink, rows: u16, cols: u16) -> io::Result<Self> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let mut writer = AsciicastWriter {
+            file,
+            rows,
+            cols,
+            timestamp,
+            header_len: 0,
+            output_tail: Vec::new(),
+            input_tail: Vec::new(),
+        };
+        let header = writer.header(None);
+        writer.header_len = header.len();
+        if matches!(writer.file, Sink::Plain(_)) {
+            writer.header_len += HEADER_RESERVE;
+        }
+        writeln!(writer.file, "{header:<0$}", writer.header_len)?;
+        writer.file.end_event()?;
+        Ok(writer)
+    }
+
+    fn header(&self, duration: Option<Duration>) -> String {
+        let mut header = format!(
+            r#"{{"version": 2, "width": {}, "height": {}, "timestamp": {}"#,
+            self.cols, self.rows, self.timestamp
+        );
+        if let Some(d) = duration {
+            header.push_str(&format!(r#", "duration": {:.6}"#, d.as_secs_f64()));
+        }
+        header.push('}');
+        header
+    }
+
+    fn write_event(&mut self, elapsed: Duration, code: &str, data: &str) -> io::Result<()> {
+        let data = serde_json::to_string(data).map_err(io::Error::other)?;
+        writeln!(
+            self.file,
+            "[{:.6}, \"{code}\", {data}]",
+            elapsed.as_secs_f64()
+        )?;
+        self.file.end_event()
+    }
+}
+
+impl RecordWriter for AsciicastWriter {
+    fn event(&mut self, elapsed: Duration, event: Event) -> io::Result<()> {
+        let (code, text) = match event {
+            Event::Output(bytes) => ("o", decode_utf8(&mut self.output_tail, bytes)),
+            Event::Input(bytes) => ("i", decode_utf8(&mut self.input_tail, bytes)),
+            Event::Resize { rows, cols } => ("r", format!("{cols}x{rows}")),
+        };
+        if text.is_empty() {
+            return Ok(());
+        }
+        self.write_event(elapsed, code, &text)
+    }
+
+    fn finish(mut self: Box<Self>, elapsed: Duration) -> io::Result<()> {
+        // Flush sequences that never completed as replacement characters.
+        let tails = [
+            ("o", std::mem::take(&mut self.output_tail)),
+            ("i", std::mem::take(&mut self.input_tail)),
+        ];
+        for (code, tail) in tails {
+            if !tail.is_empty() {
+                self.write_event(elapsed, code, &String::from_utf8_lossy(&tail))?;
+            }
+        }
+
+        let header = self.header(Some(elapsed));
+        let header_len = self.header_len;
+        let Some(mut file) = self.file.finish()? else {
+            return Ok(());
+        };
+        if header.len() <= header_len {
+            file.seek(SeekFrom::Start(0))?;
+            write!(file, "{header:<0$}", header_len)?;
+        }
+        file.sync_all()
+    }
+}
+
+/// Decode `bytes` after any held-back `tail`, holding back a trailing
+/// incomplete sequence and replacing invalid bytes with U+FFFD.
+fn decode_utf8(tail: &mut Vec<u8>, bytes: &[u8]) -> String {
+    let mut buf = std::mem::take(tail);
+    buf.extend_from_slice(bytes);
+
+    let mut text = String::new();
+    let mut rest = &buf[..];
+    loop {
+        match std::str::from_utf8(rest) {
+            Ok(s) => {
+                text.push_str(s);
+                break;
+            }
+            Err(e) => {
+                let (valid, after) = rest.split_at(e.valid_up_to());
+                text.push_str(std::str::from_utf8(valid).unwrap_or_default());
+                match e.error_len() {
+                    Some(n) => {
+                        text.push('\u{FFFD}');
+                        rest = &after[n..];
+                    }
+                    None => {
+                        *tail = after.to_vec();
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_holds_back_split_sequences() {
+        let mut tail = Vec::new();
+        let e_acute = "é".as_bytes();
+        assert_eq!(decode_utf8(&mut tail, &[b'a', e_acute[0]]), "a");
+        assert_eq!(tail, vec![e_acute[0]]);
+        assert_eq!(decode_utf8(&mut tail, &[e_acute[1], b'b']), "éb");
+        assert!(tail.is_empty());
+        assert_eq!(decode_utf8(&mut tail, b"\xffc"), "\u{FFFD}c");
+    }
+}
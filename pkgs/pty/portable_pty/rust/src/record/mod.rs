@@ -0,0 +1,366 @@
+//! Session recording.
+//!
+//! A handle can record its session to a file while it is used normally:
+//! output is captured as it is read, input as it is written, and resizes as
+//! they happen, each with the time elapsed since recording started. The
+//! on-disk layout is chosen per recording from the `PORTABLE_PTY_RECORD_*`
+//! formats.
+//!
+//! Builds without the `recorder` feature leave the formats out, and
+//! starting a recording returns `ErrUnsupported`.
+
+#[cfg(feature = "recorder")]
+mod asciicast;
+#[cfg(feature = "recorder")]
+mod sink;
+#[cfg(feature = "recorder")]
+mod ttyrec;
+
+use crate::pty::PtySize;
+use crate::{PortablePty, PortablePtyResult};
+use std::ffi::{c_char, CStr};
+use std::io;
+use std::path::Path;
+use std::sync::PoisonError;
+use std::time::{Duration, Instant};
+
+/// asciinema's asciicast v2 (newline-delimited JSON).
+pub const PORTABLE_PTY_RECORD_ASCIICAST_V2: u32 = 1;
+/// ttyrec, as read by `ttyplay` and `ipbt`. Records output only.
+pub const PORTABLE_PTY_RECORD_TTYREC: u32 = 2;
+/// Flag OR'd into a format to zstd-compress the file as it is written.
+/// Needs the `zstd` feature.
+pub const PORTABLE_PTY_RECORD_ZSTD: u32 = 0x100;
+
+/// Something that happened on the handle while recording.
+#[cfg_attr(not(feature = "recorder"), allow(dead_code))]
+pub(crate) enum Event<'a> {
+    Output(&'a [u8]),
+    Input(&'a [u8]),
+    Resize { rows: u16, cols: u16 },
+}
+
+/// Writes one recording format.
+trait RecordWriter: Send {
+    fn event(&mut self, elapsed: Duration, event: Event) -> io::Result<()>;
+
+    /// Complete the file once recording stops.
+    fn finish(self: Box<Self>, elapsed: Duration) -> io::Result<()>;
+}
+
+pub(crate) struct Recorder {
+    writer: Box<dyn RecordWriter>,
+    started: Instant,
+    record_input: bool,
+    /// A write failed; further events are dropped and stop reports it.
+    failed: bool,
+}
+
+impl Recorder {
+    fn record(&mut self, event: Event) {
+        if self.failed || (matches!(event, Event::Input(_)) && !self.record_input) {
+            return;
+        }
+        if self.writer.event(self.started.elapsed(), event).is_err() {
+            self.failed = true;
+        }
+    }
+
+    fn finish(self) -> io::Result<()> {
+        let failed = self.failed;
+        self.writer.finish(self.started.elapsed())?;
+        if failed {
+            return Err(io::Error::other("recording write failed"));
+        }
+        Ok(())
+    }
+}
+
+/// Record an event if the handle has a recording running.
+pub(crate) fn capture(pty: &PortablePty, event: Event) {
+    if let Some(recorder) = pty
+        .recorder
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .as_mut()
+    {
+        recorder.record(event);
+    }
+}
+
+/// Start recording the session to `path`.
+///
+/// - `format`: one of the `PORTABLE_PTY_RECORD_*` formats, optionally with
+///   `PORTABLE_PTY_RECORD_ZSTD` set.
+/// - `record_input`: also record what is written to the child (ignored by
+///   formats that only carry output).
+///
+/// The file is created (or truncated) immediately. Returns `ErrRecord` if
+/// a recording is already running, the format is unknown, or the file
+/// can't be written, and `ErrUnsupported` in a build without the `recorder`
+/// feature or if compression was requested in a build without it.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_record_start(
+    handle: *const PortablePty,
+    path: *const c_char,
+    format: u32,
+    record_input: bool,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        if path.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+        match unsafe { CStr::from_ptr(path) }.to_str() {
+            Ok(path) => start(pty, Path::new(path), format, record_input),
+            Err(_) => PortablePtyResult::ErrRecord,
+        }
+    })
+}
+
+/// Start recording `pty` to `path`, as `portable_pty_record_start` does.
+pub(crate) fn start(
+    pty: &PortablePty,
+    path: &Path,
+    format: u32,
+    record_input: bool,
+) -> PortablePtyResult {
+    let mut slot = pty.recorder.lock().unwrap_or_else(PoisonError::into_inner);
+    if slot.is_some() {
+        return PortablePtyResult::ErrRecord;
+    }
+    let size = match pty.master.get_size() {
+        Ok(size) => size,
+        Err(_) => return PortablePtyResult::ErrSize,
+    };
+
+    let compress = format & PORTABLE_PTY_RECORD_ZSTD != 0;
+    let format = format & !PORTABLE_PTY_RECORD_ZSTD;
+    if !matches!(
+        format,
+        PORTABLE_PTY_RECORD_ASCIICAST_V2 | PORTABLE_PTY_RECORD_TTYREC
+    ) {
+        return PortablePtyResult::ErrRecord;
+    }
+    if compress && !cfg!(feature = "zstd") {
+        return PortablePtyResult::ErrUnsupported;
+    }
+    let writer = match create(path, format, compress, size) {
+        Ok(w) => w,
+        Err(e) => return e,
+    };
+
+    *slot = Some(Recorder {
+        writer,
+        started: Instant::now(),
+        record_input,
+        failed: false,
+    });
+    PortablePtyResult::Ok
+}
+
+/// Create the file at `path` and a writer for `format` to it.
+#[cfg(feature = "recorder")]
+fn create(
+    path: &Path,
+    format: u32,
+    compress: bool,
+    size: PtySize,
+) -> Result<Box<dyn RecordWriter>, PortablePtyResult> {
+    let sink = std::fs::File::create(path)
+        .and_then(|f| sink::Sink::new(f, compress))
+        .map_err(|_| PortablePtyResult::ErrRecord)?;
+    Ok(match format {
+        PORTABLE_PTY_RECORD_ASCIICAST_V2 => Box::new(
+            asciicast::AsciicastWriter::new(sink, size.rows, size.cols)
+                .map_err(|_| PortablePtyResult::ErrRecord)?,
+        ),
+        _ => Box::new(ttyrec::TtyrecWriter::new(sink)),
+    })
+}
+
+#[cfg(not(feature = "recorder"))]
+fn create(
+    _path: &Path,
+    _format: u32,
+    _compress: bool,
+    _size: PtySize,
+) -> Result<Box<dyn RecordWriter>, PortablePtyResult> {
+    Err(PortablePtyResult::ErrUnsupported)
+}
+
+/// Stop the running recording and finalize the file.
+///
+/// Returns `ErrRecord` if nothing was being recorded or if any part of the
+/// recording could not be written.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_record_stop(handle: *const PortablePty) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+
+        let recorder = pty
+            .recorder
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        match recorder.map(Recorder::finish) {
+            Some(Ok(())) => PortablePtyResult::Ok,
+            _ => PortablePtyResult::ErrRecord,
+        }
+    })
+}
+
+#[cfg(all(test, unix, feature = "recorder"))]
+mod tests {
+    use super::*;
+    use crate::tests::{open_and_spawn, read_string};
+    use crate::{portable_pty_close, portable_pty_resize, portable_pty_write};
+    use std::ffi::CString;
+
+    #[test]
+    fn test_records_asciicast_session() {
+        let path = std::env::temp_dir().join(format!("portable_pty_{}.cast", std::process::id()));
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+
+        let handle = open_and_spawn("/bin/sh", &["sh", "-c", "printf 'héllo'; sleep 5"]);
+        let result = portable_pty_record_start(
+            handle,
+            c_path.as_ptr(),
+            PORTABLE_PTY_RECORD_ASCIICAST_V2,
+            true,
+        );
+        assert!(matches!(result, PortablePtyResult::Ok));
+        let again = portable_pty_record_start(
+            handle,
+            c_path.as_ptr(),
+            PORTABLE_PTY_RECORD_ASCIICAST_V2,
+            true,
+        );
+        assert!(matches!(again, PortablePtyResult::ErrRecord));
+
+        assert_eq!(read_string(handle), "héllo");
+        assert_eq!(portable_pty_write(handle, b"x".as_ptr(), 1), 1);
+        assert!(matches!(
+            portable_pty_resize(handle, 30, 100),
+            PortablePtyResult::Ok
+        ));
+        assert!(matches!(
+            portable_pty_record_stop(handle),
+            PortablePtyResult::Ok
+        ));
+        assert!(matches!(
+            portable_pty_record_stop(handle),
+            PortablePtyResult::ErrRecord
+        ));
+        portable_pty_close(handle);
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<serde_json::Value> = text
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+
+        assert_eq!(lines[0]["version"], 2);
+        assert_eq!(lines[0]["width"], 80);
+        assert_eq!(lines[0]["height"], 24);
+        assert!(lines[0]["duration"].is_f64());
+        let events: Vec<(&str, &str)> = lines[1..]
+            .iter()
+            .map(|e| (e[1].as_str().unwrap(), e[2].as_str().unwrap()))
+            .collect();
+        assert_eq!(events, vec![("o", "héllo"), ("i", "x"), ("r", "100x30")]);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_compressed_recording_replays() {
+        use crate::replay::{portable_pty_open_replay, portable_pty_replay_set_instant};
+        use crate::{portable_pty_read, PortablePty};
+
+        let path =
+            std::env::temp_dir().join(format!("portable_pty_{}.cast.zst", std::process::id()));
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+
+        let handle = open_and_spawn("/bin/echo", &["echo", "squeezed"]);
+        let format = PORTABLE_PTY_RECORD_ASCIICAST_V2 | PORTABLE_PTY_RECORD_ZSTD;
+        let result = portable_pty_record_start(handle, c_path.as_ptr(), format, false);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        let recorded = read_string(handle);
+        assert!(matches!(
+            portable_pty_record_stop(handle),
+            PortablePtyResult::Ok
+        ));
+        portable_pty_close(handle);
+
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(bytes[..4], [0x28, 0xb5, 0x2f, 0xfd]);
+
+        let mut replay: *mut PortablePty = std::ptr::null_mut();
+        let result = portable_pty_open_replay(c_path.as_ptr(), &mut replay);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        portable_pty_replay_set_instant(replay, true);
+        let mut replayed = Vec::new();
+        let mut buf = [0u8; 256];
+        loop {
+            let n = portable_pty_read(replay, buf.as_mut_ptr(), buf.len());
+            if n <= 0 {
+                break;
+            }
+            replayed.extend_from_slice(&buf[..n as usize]);
+        }
+        portable_pty_close(replay);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(String::from_utf8(replayed).unwrap(), recorded);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_compressed_recording_cut_short_replays() {
+        use crate::replay::parse::{parse, FrameKind};
+        use crate::replay::{portable_pty_open_replay, portable_pty_replay_set_instant};
+        use crate::{portable_pty_read, PortablePty};
+        use std::time::{Duration, Instant};
+
+        let path =
+            std::env::temp_dir().join(format!("portable_pty_{}.cut.zst", std::process::id()));
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+
+        // Output, then quiet, with the recording still running as if the
+        // process had died there.
+        let handle = open_and_spawn("/bin/sh", &["sh", "-c", "printf before; sleep 10"]);
+        let format = PORTABLE_PTY_RECORD_ASCIICAST_V2 | PORTABLE_PTY_RECORD_ZSTD;
+        let result = portable_pty_record_start(handle, c_path.as_ptr(), format, false);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        assert_eq!(read_string(handle), "before");
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let frames = loop {
+            let bytes = std::fs::read(&path).unwrap();
+            let frames = parse(&bytes).map(|r| r.frames).unwrap_or_default();
+            if !frames.is_empty() || Instant::now() > deadline {
+                break frames;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        };
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].kind, FrameKind::Output(b"before".to_vec()));
+
+        let mut replay: *mut PortablePty = std::ptr::null_mut();
+        let result = portable_pty_open_replay(c_path.as_ptr(), &mut replay);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        portable_pty_replay_set_instant(replay, true);
+        let mut buf = [0u8; 256];
+        let n = portable_pty_read(replay, buf.as_mut_ptr(), buf.len());
+        assert_eq!(&buf[..n.max(0) as usize], b"before");
+        portable_pty_close(replay);
+        portable_pty_close(handle);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
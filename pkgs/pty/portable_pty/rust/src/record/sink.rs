@@ -0,0 +1,220 @@
+//! Output files for recordings, optionally zstd-compressed.
+//!
+//! Writers mark the end of each event with `end_event`. Compressed output
+//! reaches the encoder only whole events at a time, and a thread flushes
+//! it at most `FLUSH_INTERVAL` after an event, output going quiet or not,
+//! so a recording cut short (crash, power loss) is readable up to an event
+//! boundary no older than that.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+#[cfg(feature = "zstd")]
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+#[cfg(feature = "zstd")]
+use std::time::{Duration, Instant};
+
+/// Compression level for recordings: fast, and terminal output compresses
+/// well even at low levels.
+#[cfg(feature = "zstd")]
+const ZSTD_LEVEL: i32 = 3;
+
+/// Longest an event written stays unflushed in compressed output.
+#[cfg(feature = "zstd")]
+const FLUSH_INTERVAL: Duration = Duration::from_secs(2);
+
+pub(super) enum Sink {
+    Plain(BufWriter<File>),
+    #[cfg(feature = "zstd")]
+    Zstd(Compressor),
+}
+
+/// The writing end of a compressed recording.
+#[cfg(feature = "zstd")]
+pub(super) struct Compressor {
+    /// The event being written, held back until it's whole.
+    event: Vec<u8>,
+    shared: Arc<Compressed>,
+}
+
+/// What the writer and the flushing thread share.
+#[cfg(feature = "zstd")]
+struct Compressed {
+    state: Mutex<CompressedState>,
+    /// Signalled when an event lands with none unflushed, and at the end.
+    changed: Condvar,
+}
+
+#[cfg(feature = "zstd")]
+struct CompressedState {
+    /// None once the recording is over.
+    encoder: Option<zstd::stream::write::Encoder<'static, File>>,
+    /// Events have been written since the last flush.
+    unflushed: bool,
+    /// A flush failed; reported by the next event or `finish`.
+    failed: Option<io::Error>,
+}
+
+#[cfg(feature = "zstd")]
+impl Compressed {
+    fn lock(&self) -> MutexGuard<'_, CompressedState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Flush events `FLUSH_INTERVAL` after the first unflushed one lands,
+    /// until the recording is over.
+    fn flush_periodically(&self) {
+        let mut state = self.lock();
+        loop {
+            while state.encoder.is_some() && !state.unflushed {
+                state = self
+                    .changed
+                    .wait(state)
+                    .unwrap_or_else(PoisonError::into_inner);
+            }
+            // Give later events the interval to join it.
+            let deadline = Instant::now() + FLUSH_INTERVAL;
+            while state.encoder.is_some() {
+                let Some(left) = deadline.checked_duration_since(Instant::now()) else {
+                    break;
+                };
+                state = self
+                    .changed
+                    .wait_timeout(state, left)
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .0;
+            }
+            let Some(encoder) = state.encoder.as_mut() else {
+                return;
+            };
+            if let Err(e) = encoder.flush() {
+                state.failed = Some(e);
+                return;
+            }
+            state.unflushed = false;
+        }
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl Compressor {
+    fn new(file: File) -> io::Result<Self> {
+        let shared = Arc::new(Compressed {
+            state: Mutex::new(CompressedState {
+                encoder: Some(zstd::stream::write::Encoder::new(file, ZSTD_LEVEL)?),
+                unflushed: false,
+                failed: None,
+            }),
+            changed: Condvar::new(),
+        });
+        let flusher = Arc::clone(&shared);
+        crate::lifecycle::spawn_thread("portable-pty-record", move || {
+            flusher.flush_periodically()
+        })?;
+        Ok(Compressor {
+            event: Vec::new(),
+            shared,
+        })
+    }
+
+    /// Hand the event written to the encoder.
+    fn end_event(&mut self) -> io::Result<()> {
+        let mut state = self.shared.lock();
+        if let Some(e) = state.failed.take() {
+            return Err(e);
+        }
+        let encoder = state.encoder.as_mut().ok_or(io::ErrorKind::BrokenPipe)?;
+        encoder.write_all(&self.event)?;
+        self.event.clear();
+        if !state.unflushed {
+            state.unflushed = true;
+            self.shared.changed.notify_all();
+        }
+        Ok(())
+    }
+
+    /// End the recording, stopping the flushing thread.
+    fn take_encoder(&mut self) -> io::Result<zstd::stream::write::Encoder<'static, File>> {
+        let mut state = self.shared.lock();
+        let encoder = state.encoder.take();
+        self.shared.changed.notify_all();
+        if let Some(e) = state.failed.take() {
+            return Err(e);
+        }
+        encoder.ok_or_else(|| io::ErrorKind::BrokenPipe.into())
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.end_event()?;
+        self.take_encoder()?.finish()?.sync_all()
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl Drop for Compressor {
+    // Dropped without `finish`: keep what whole events there are.
+    fn drop(&mut self) {
+        if let Ok(mut encoder) = self.take_encoder() {
+            let _ = encoder.flush();
+        }
+    }
+}
+
+impl Sink {
+    pub(super) fn new(file: File, compress: bool) -> io::Result<Sink> {
+        if !compress {
+            return Ok(Sink::Plain(BufWriter::new(file)));
+        }
+        #[cfg(feature = "zstd")]
+        {
+            Ok(Sink::Zstd(Compressor::new(file)?))
+        }
+        #[cfg(not(feature = "zstd"))]
+        {
+            Err(io::Error::from(io::ErrorKind::Unsupported))
+        }
+    }
+
+    /// Mark the end of an event: what was written since is whole.
+    pub(super) fn end_event(&mut self) -> io::Result<()> {
+        match self {
+            Sink::Plain(_) => Ok(()),
+            #[cfg(feature = "zstd")]
+            Sink::Zstd(compressor) => compressor.end_event(),
+        }
+    }
+
+    /// Flush and return the file if it can be rewritten in place.
+    ///
+    /// Compressed output is finalized and closed instead, returning `None`.
+    pub(super) fn finish(self) -> io::Result<Option<File>> {
+        match self {
+            Sink::Plain(writer) => writer.into_inner().map(Some).map_err(|e| e.into_error()),
+            #[cfg(feature = "zstd")]
+            Sink::Zstd(compressor) => {
+                compressor.finish()?;
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Sink::Plain(writer) => writer.write(buf),
+            #[cfg(feature = "zstd")]
+            Sink::Zstd(compressor) => {
+                compressor.event.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Sink::Plain(writer) => writer.flush(),
+            #[cfg(feature = "zstd")]
+            Sink::Zstd(_) => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,81 @@
+//! ttyrec writer.
+//!
+//! Each frame is a 12-byte header (wall-clock seconds, microseconds and
+//! payload length, all little-endian `u32`) followed by the raw output.
+//! The format only carries output, so input and resize events are dropped.
+
+use super::sink::Sink;
+use super::{Event, RecordWriter};
+use std::io::{self, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub(super) struct TtyrecWriter {
+    file: Sink,
+    /// Wall-clock time recording started, since the epoch.
+    epoch_start: Duration,
+}
+
+impl TtyrecWriter {
+    pub(super) fn new(file: Sink) -> Self {
+        TtyrecWriter {
+            file,
+            epoch_start: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl RecordWriter for TtyrecWriter {
+    fn event(&mut self, elapsed: Duration, event: Event) -> io::Result<()> {
+        let Event::Output(bytes) = event else {
+            return Ok(());
+        };
+        let len = u32::try_from(bytes.len()).map_err(io::Error::other)?;
+        let at = self.epoch_start + elapsed;
+        let mut header = [0u8; 12];
+        header[..4].copy_from_slice(&(at.as_secs() as u32).to_le_bytes());
+        header[4..8].copy_from_slice(&at.subsec_micros().to_le_bytes());
+        header[8..].copy_from_slice(&len.to_le_bytes());
+        self.file.write_all(&header)?;
+        self.file.write_all(bytes)?;
+        self.file.end_event()
+    }
+
+    fn finish(self: Box<Self>, _elapsed: Duration) -> io::Result<()> {
+        match self.file.finish()? {
+            Some(file) => file.sync_all(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_writes_output_frames_only() {
+        let path = std::env::temp_dir().join(format!("portable_pty_{}.ttyrec", std::process::id()));
+        let sink = Sink::new(std::fs::File::create(&path).unwrap(), false).unwrap();
+        let mut writer = Box::new(TtyrecWriter::new(sink));
+        writer.epoch_start = Duration::from_secs(1_000);
+
+        let at = Duration::from_micros(2_500_000);
+        writer.event(at, Event::Output(b"hi")).unwrap();
+        writer.event(at, Event::Input(b"ignored")).unwrap();
+        writer
+            .event(at, Event::Resize { rows: 1, cols: 1 })
+            .unwrap();
+        writer.finish(at).unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut expected = Vec::new();
+        expected.extend_from_slice(&1_002u32.to_le_bytes());
+        expected.extend_from_slice(&500_000u32.to_le_bytes());
+        expected.extend_from_slice(&2u32.to_le_bytes());
+        expected.extend_from_slice(b"hi");
+        assert_eq!(bytes, expected);
+    }
+}
@@ -0,0 +1,398 @@
+//! Replay of recorded sessions.
+//!
+//! `portable_pty_open_replay` opens a recording (asciicast v2 or ttyrec, as
+//! written by the recorder) as an ordinary handle. Its output is produced
+//! by a playback thread at the recorded pace and goes through the normal
+//! read path — expect, pattern events and mode tracking all work — so a
+//! terminal view can render a recording exactly like a live session. A
+//! recording cut short, by a crash mid-write say, plays up to the last
+//! event written whole.
+//!
+//! The handle has a stand-in child that "exits" with status 0 when
+//! playback ends; reads then return EOF. Input written to the handle is
+//! discarded, and nothing can be spawned on it.
+
+pub(crate) mod parse;
+mod player;
+pub(crate) mod transcript;
+
+use crate::pty::{
+    Child, ChildKiller, CommandBuilder, ExitStatus, MasterPty, PtyPair, PtySize, SlavePty,
+};
+use crate::{PipeReader, PortablePty, PortablePtyResult};
+use parse::Recording;
+use player::{Control, Shared};
+use std::ffi::{c_char, CStr};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::{Arc, PoisonError};
+use std::time::Duration;
+
+struct ReplayMaster {
+    shared: Arc<Shared>,
+    reader: PipeReader,
+}
+
+impl Drop for ReplayMaster {
+    fn drop(&mut self) {
+        self.shared.update(Control::stop);
+    }
+}
+
+impl MasterPty for ReplayMaster {
+    fn resize(&self, size: PtySize) -> anyhow::Result<()> {
+        *self
+            .shared
+            .size
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = size;
+        Ok(())
+    }
+
+    fn get_size(&self) -> anyhow::Result<PtySize> {
+        Ok(*self
+            .shared
+            .size
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner))
+    }
+
+    fn try_clone_reader(&self) -> anyhow::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(self.reader.try_clone()?))
+    }
+
+    fn take_writer(&self) -> anyhow::Result<Box<dyn Write + Send>> {
+        Ok(Box::new(std::io::sink()))
+    }
+
+    #[cfg(unix)]
+    fn process_group_leader(&self) -> Option<libc::pid_t> {
+        None
+    }
+
+    #[cfg(unix)]
+    fn as_raw_fd(&self) -> Option<std::os::fd::RawFd> {
+        use std::os::fd::AsRawFd;
+        Some(self.reader.as_raw_fd())
+    }
+
+    #[cfg(unix)]
+    fn tty_name(&self) -> Option<std::path::PathBuf> {
+        None
+    }
+}
+
+struct ReplaySlave;
+
+impl SlavePty for ReplaySlave {
+    fn spawn_command(&self, _cmd: CommandBuilder) -> anyhow::Result<Box<dyn Child + Send + Sync>> {
+        anyhow::bail!("replay handles can't spawn processes")
+    }
+}
+
+/// Stands in for a child process: exits when playback ends.
+#[derive(Clone)]
+struct ReplayChild {
+    shared: Arc<Shared>,
+}
+
+impl std::fmt::Debug for ReplayChild {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ReplayChild").finish_non_exhaustive()
+    }
+}
+
+impl ChildKiller for ReplayChild {
+    fn kill(&mut self) -> std::io::Result<()> {
+        self.shared.update(Control::stop);
+        Ok(())
+    }
+
+    fn clone_killer(&self) -> Box<dyn ChildKiller + Send + Sync> {
+        Box::new(self.clone())
+    }
+}
+
+impl Child for ReplayChild {
+    fn try_wait(&mut self) -> std::io::Result<Option<ExitStatus>> {
+        let done = self.shared.control().done();
+        Ok(done.then(|| ExitStatus::with_exit_code(0)))
+    }
+
+    fn wait(&mut self) -> std::io::Result<ExitStatus> {
+        self.shared.wait_done();
+        Ok(ExitStatus::with_exit_code(0))
+    }
+
+    fn process_id(&self) -> Option<u32> {
+        None
+    }
+
+    #[cfg(windows)]
+    fn as_raw_handle(&self) -> Option<std::os::windows::io::RawHandle> {
+        None
+    }
+}
+
+/// Start playing `recording` and wrap it in a handle.
+fn open(recording: Recording) -> Result<Box<PortablePty>, PortablePtyResult> {
+    let duration = recording.frames.last().map(|f| f.at).unwrap_or_default();
+    let shared = Arc::new(Shared::new(recording.size, duration));
+    let (reader, writer) = crate::pipe().map_err(|_| PortablePtyResult::ErrOpen)?;
+
+    let pair = PtyPair {
+        slave: Box::new(ReplaySlave),
+        master: Box::new(ReplayMaster {
+            shared: Arc::clone(&shared),
+            reader,
+        }),
+    };
+    let mut handle = PortablePty::from_pair(pair)?;
+    handle.child = Some(Box::new(ReplayChild {
+        shared: Arc::clone(&shared),
+    }));
+
+    crate::lifecycle::spawn_thread("portable-pty-replay", move || {
+        player::run(shared, recording, writer)
+    })
+    .map_err(|_| PortablePtyResult::ErrOpen)?;
+    Ok(handle)
+}
+
+/// The playback state of a replay handle; `ErrReplay` for other handles.
+fn shared(handle: *const PortablePty) -> Result<Arc<Shared>, PortablePtyResult> {
+    let pty = match unsafe { handle.as_ref() } {
+        Some(p) => p,
+        None => return Err(PortablePtyResult::ErrNull),
+    };
+    match pty.master.as_ref().as_any().downcast_ref::<ReplayMaster>() {
+        Some(master) => Ok(Arc::clone(&master.shared)),
+        None => Err(PortablePtyResult::ErrReplay),
+    }
+}
+
+fn control(handle: *const PortablePty, f: impl FnOnce(&mut Control)) -> PortablePtyResult {
+    match shared(handle) {
+        Ok(shared) => {
+            shared.update(f);
+            PortablePtyResult::Ok
+        }
+        Err(e) => e,
+    }
+}
+
+/// Open a recording for playback.
+///
+/// The format (asciicast v2 or ttyrec) is detected from the file. Playback
+/// starts immediately at normal speed. Returns `ErrOpen` if the file can't
+/// be read and `ErrRecord` if it isn't a recording.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_open_replay(
+    path: *const c_char,
+    out: *mut *mut PortablePty,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        if path.is_null() || out.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+        let path = match unsafe { CStr::from_ptr(path) }.to_str() {
+            Ok(s) => s,
+            Err(_) => return PortablePtyResult::ErrOpen,
+        };
+
+        match open_file(Path::new(path)) {
+            Ok(handle) => {
+                unsafe {
+                    *out = crate::lifecycle::register(handle);
+                }
+                PortablePtyResult::Ok
+            }
+            Err(e) => e,
+        }
+    })
+}
+
+/// Read the recording at `path` and start playing it.
+pub(crate) fn open_file(path: &Path) -> Result<Box<PortablePty>, PortablePtyResult> {
+    let bytes = std::fs::read(path).map_err(|_| PortablePtyResult::ErrOpen)?;
+    open(parse::parse(&bytes).ok_or(PortablePtyResult::ErrRecord)?)
+}
+
+/// Set the playback speed multiplier (1.0 = recorded pace).
+///
+/// Returns `ErrReplay` if the handle isn't a replay or `speed` isn't a
+/// positive finite number.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_replay_set_speed(
+    handle: *const PortablePty,
+    speed: f64,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        if !(speed.is_finite() && speed > 0.0) {
+            return PortablePtyResult::ErrReplay;
+        }
+        control(handle, |c| c.set_speed(speed))
+    })
+}
+
+/// Pause or resume playback.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_replay_set_paused(
+    handle: *const PortablePty,
+    paused: bool,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| control(handle, |c| c.set_paused(paused)))
+}
+
+/// In instant mode the rest of the recording is emitted as fast as it is
+/// read, ignoring timing (pausing still applies).
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_replay_set_instant(
+    handle: *const PortablePty,
+    instant: bool,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| control(handle, |c| c.set_instant(instant)))
+}
+
+/// Jump to `position_ms` into the recording.
+///
+/// Output up to the target is emitted at once. Seeking backwards first
+/// emits a terminal reset (`ESC c`) and replays from the start, so the
+/// screen ends up as it was at the target. Has no effect once playback has
+/// ended.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_replay_seek(
+    handle: *const PortablePty,
+    position_ms: u64,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| control(handle, |c| c.seek(Duration::from_millis(position_ms))))
+}
+
+/// Get the playback position and total length of the recording, in
+/// milliseconds.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_replay_position(
+    handle: *const PortablePty,
+    out_position_ms: *mut u64,
+    out_duration_ms: *mut u64,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        if out_position_ms.is_null() || out_duration_ms.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+        let shared = match shared(handle) {
+            Ok(s) => s,
+            Err(e) => return e,
+        };
+        let position = shared.control().position().min(shared.duration);
+        unsafe {
+            *out_position_ms = position.as_millis().try_into().unwrap_or(u64::MAX);
+            *out_duration_ms = shared.duration.as_millis().try_into().unwrap_or(u64::MAX);
+        }
+        PortablePtyResult::Ok
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::{portable_pty_close, portable_pty_read, portable_pty_wait_blocking};
+    use parse::{Frame, FrameKind};
+
+    /// Start playing output `frames` of `(ms, text)`.
+    fn start(frames: &[(u64, &str)]) -> *mut PortablePty {
+        let recording = Recording {
+            size: PtySize {
+                rows: 24,
+                cols: 80,
+                pixel_width: 0,
+                pixel_height: 0,
+            },
+            frames: frames
+                .iter()
+                .map(|(ms, text)| Frame {
+                    at: Duration::from_millis(*ms),
+                    kind: FrameKind::Output(text.as_bytes().to_vec()),
+                })
+                .collect(),
+        };
+        match open(recording) {
+            Ok(handle) => crate::lifecycle::register(handle),
+            Err(e) => panic!("open failed: {}", e as u32),
+        }
+    }
+
+    fn read_to_end(handle: *mut PortablePty) -> String {
+        let mut out = Vec::new();
+        let mut buf = [0u8; 256];
+        loop {
+            let n = portable_pty_read(handle, buf.as_mut_ptr(), buf.len());
+            if n <= 0 {
+                break;
+            }
+            out.extend_from_slice(&buf[..n as usize]);
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_replays_through_read_path() {
+        let handle = start(&[(0, "one "), (50, "two")]);
+        assert_eq!(read_to_end(handle), "one two");
+
+        let mut status = -1;
+        let result = portable_pty_wait_blocking(handle, &mut status);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        assert_eq!(status, 0);
+        portable_pty_close(handle);
+    }
+
+    #[test]
+    fn test_instant_mode_and_controls() {
+        let handle = start(&[(0, "a"), (60_000, "b")]);
+        assert!(matches!(
+            portable_pty_replay_set_speed(handle, 0.0),
+            PortablePtyResult::ErrReplay
+        ));
+
+        let (mut position, mut duration) = (0, 0);
+        portable_pty_replay_position(handle, &mut position, &mut duration);
+        assert_eq!(duration, 60_000);
+
+        // The minute-long gap is skipped.
+        portable_pty_replay_set_instant(handle, true);
+        assert_eq!(read_to_end(handle), "ab");
+        portable_pty_close(handle);
+    }
+
+    #[test]
+    fn test_seek_backwards_resets_and_replays() {
+        let handle = start(&[(0, "a"), (1000, "b"), (60_000, "c")]);
+        portable_pty_replay_seek(handle, 2000);
+        let mut seen = String::new();
+        let mut buf = [0u8; 16];
+        while seen != "ab" {
+            let n = portable_pty_read(handle, buf.as_mut_ptr(), buf.len());
+            seen.push_str(std::str::from_utf8(&buf[..n as usize]).unwrap());
+        }
+
+        portable_pty_replay_seek(handle, 500);
+        let mut replayed = Vec::new();
+        while replayed.len() < 3 {
+            let n = portable_pty_read(handle, buf.as_mut_ptr(), buf.len());
+            replayed.extend_from_slice(&buf[..n as usize]);
+        }
+        assert_eq!(replayed, b"\x1bca");
+        portable_pty_close(handle);
+    }
+
+    #[test]
+    fn test_controls_reject_live_handles() {
+        let handle = crate::tests::open_and_spawn("/bin/echo", &["echo"]);
+        assert!(matches!(
+            portable_pty_replay_set_paused(handle, true),
+            PortablePtyResult::ErrReplay
+        ));
+        portable_pty_close(handle);
+    }
+}
@@ -0,0 +1,216 @@
+//! Reading recordings back into timed frames.
+
+use crate::pty::PtySize;
+use std::time::Duration;
+
+/// One step of a recording, at `at` since its start.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Frame {
+    pub(crate) at: Duration,
+    pub(crate) kind: FrameKind,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum FrameKind {
+    Output(Vec<u8>),
+    Resize(PtySize),
+}
+
+/// A parsed recording.
+pub(crate) struct Recording {
+    /// Terminal size at the start of the recording.
+    pub(crate) size: PtySize,
+    pub(crate) frames: Vec<Frame>,
+}
+
+/// Size assumed when the format doesn't store one.
+const DEFAULT_SIZE: PtySize = PtySize {
+    rows: 24,
+    cols: 80,
+    pixel_width: 0,
+    pixel_height: 0,
+};
+
+/// Frame magic number at the start of zstd-compressed files.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Parse a recording, detecting its format from the content.
+///
+/// Compressed files are unpacked first. asciicast files start with a JSON
+/// object; anything else is read as ttyrec.
+pub(crate) fn parse(bytes: &[u8]) -> Option<Recording> {
+    if bytes.starts_with(&ZSTD_MAGIC) {
+        return parse(&decompress(bytes)?);
+    }
+    match bytes.iter().find(|b| !b.is_ascii_whitespace()) {
+        Some(b'{') => parse_asciicast(bytes),
+        _ => parse_ttyrec(bytes),
+    }
+}
+
+/// Unpack a zstd stream, keeping what was decoded before any truncation
+/// (a recording cut short ends at its last flush point).
+#[cfg(feature = "zstd")]
+fn decompress(bytes: &[u8]) -> Option<Vec<u8>> {
+    use std::io::Read;
+
+    let mut decoder = zstd::stream::read::Decoder::new(bytes).ok()?;
+    let mut plain = Vec::new();
+    let mut chunk = [0u8; 16 * 1024];
+    loop {
+        match decoder.read(&mut chunk) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => plain.extend_from_slice(&chunk[..n]),
+        }
+    }
+    Some(plain)
+}
+
+#[cfg(not(feature = "zstd"))]
+fn decompress(_bytes: &[u8]) -> Option<Vec<u8>> {
+    None
+}
+
+fn pty_size(rows: u64, cols: u64) -> Option<PtySize> {
+    Some(PtySize {
+        rows: rows.try_into().ok()?,
+        cols: cols.try_into().ok()?,
+        pixel_width: 0,
+        pixel_height: 0,
+    })
+}
+
+/// asciicast v2: a header line, then `[time, code, data]` lines. Input and
+/// marker events are skipped, as is a last line cut short.
+fn parse_asciicast(bytes: &[u8]) -> Option<Recording> {
+    let complete = bytes.ends_with(b"\n");
+    let mut lines = bytes
+        .split(|&b| b == b'\n')
+        .filter(|l| !l.trim_ascii().is_empty())
+        .peekable();
+
+    let header: serde_json::Value = serde_json::from_slice(lines.next()?).ok()?;
+    if header["version"].as_u64() != Some(2) {
+        return None;
+    }
+    let size = pty_size(header["height"].as_u64()?, header["width"].as_u64()?)?;
+
+    let mut frames = Vec::new();
+    while let Some(line) = lines.next() {
+        match asciicast_event(line) {
+            Some(Some(frame)) => frames.push(frame),
+            Some(None) => {}
+            // The recording stopped partway through writing it.
+            None if !complete && lines.peek().is_none() => break,
+            None => return None,
+        }
+    }
+    Some(Recording { size, frames })
+}
+
+/// One asciicast event line: None if it's malformed, `Some(None)` if it
+/// isn't one replayed.
+fn asciicast_event(line: &[u8]) -> Option<Option<Frame>> {
+    let event: serde_json::Value = serde_json::from_slice(line).ok()?;
+    let at = Duration::try_from_secs_f64(event[0].as_f64()?).ok()?;
+    let data = event[2].as_str()?;
+    let kind = match event[1].as_str()? {
+        "o" => FrameKind::Output(data.as_bytes().to_vec()),
+        "r" => {
+            let (cols, rows) = data.split_once('x')?;
+            FrameKind::Resize(pty_size(rows.parse().ok()?, cols.parse().ok()?)?)
+        }
+        _ => return Some(None),
+    };
+    Some(Some(Frame { at, kind }))
+}
+
+/// ttyrec: `sec, usec, len` little-endian headers, each followed by `len`
+/// bytes of output. Times are made relative to the first frame. A last
+/// frame cut short is skipped, unless there's nothing before it to tell
+/// the file is a recording at all.
+fn parse_ttyrec(bytes: &[u8]) -> Option<Recording> {
+    let mut frames = Vec::new();
+    let mut first: Option<Duration> = None;
+    let mut rest = bytes;
+    while !rest.is_empty() {
+        let Some(header) = rest.get(..12) else {
+            break;
+        };
+        let word = |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().unwrap());
+        let at = Duration::from_secs(word(0).into()) + Duration::from_micros(word(4).into());
+        let len = word(8) as usize;
+        let Some(data) = rest.get(12..12 + len) else {
+            break;
+        };
+        rest = &rest[12 + len..];
+
+        let start = *first.get_or_insert(at);
+        frames.push(Frame {
+            at: at.saturating_sub(start),
+            kind: FrameKind::Output(data.to_vec()),
+        });
+    }
+    if !rest.is_empty() && frames.is_empty() {
+        return None;
+    }
+    Some(Recording {
+        size: DEFAULT_SIZE,
+        frames,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parses_asciicast() {
+        let cast = concat!(
+            "{\"version\": 2, \"width\": 100, \"height\": 30}    \n",
+            "[0.5, \"o\", \"h\\u00e9\"]\n",
+            "[0.75, \"i\", \"x\"]\n",
+            "[1.0, \"r\", \"90x20\"]\n",
+        );
+        let rec = parse(cast.as_bytes()).unwrap();
+        assert_eq!((rec.size.rows, rec.size.cols), (30, 100));
+        // A last line cut short is left out; a bad one elsewhere isn't.
+        let cut = parse(&cast.as_bytes()[..cast.len() - 5]).unwrap();
+        assert_eq!(cut.frames.len(), 1);
+        let bad = cast.replacen("[0.75", "[0.75,", 1);
+        assert!(parse(bad.as_bytes()).is_none());
+        assert_eq!(
+            rec.frames,
+            vec![
+                Frame {
+                    at: Duration::from_millis(500),
+                    kind: FrameKind::Output("hé".as_bytes().to_vec()),
+                },
+                Frame {
+                    at: Duration::from_secs(1),
+                    kind: FrameKind::Resize(pty_size(20, 90).unwrap()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parses_ttyrec_relative_to_first_frame() {
+        let mut bytes = Vec::new();
+        for (sec, usec, data) in [(100u32, 0u32, &b"a"[..]), (101, 250_000, b"bc")] {
+            bytes.extend_from_slice(&sec.to_le_bytes());
+            bytes.extend_from_slice(&usec.to_le_bytes());
+            bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(data);
+        }
+        let rec = parse(&bytes).unwrap();
+        let times: Vec<Duration> = rec.frames.iter().map(|f| f.at).collect();
+        assert_eq!(times, vec![Duration::ZERO, Duration::from_millis(1250)]);
+
+        // A frame cut short is left out, and a file that's nothing but one
+        // isn't a recording.
+        let rec = parse(&bytes[..bytes.len() - 1]).unwrap();
+        assert_eq!(rec.frames.len(), 1);
+        assert!(parse(&bytes[..12]).is_none());
+    }
+}
@@ -0,0 +1,244 @@
+//! Playback thread and its controls.
+//!
+//! The player writes a recording's output into a pipe at the recorded pace;
+//! the handle reads the other end like any PTY master. Controls adjust a
+//! shared clock that maps wall time onto the recording's timeline.
+
+use super::parse::{Frame, FrameKind, Recording};
+use crate::pty::PtySize;
+use crate::PipeWriter;
+use std::io::Write;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Emitted before re-playing from the start on a backwards seek, so the
+/// terminal forgets what was drawn after the target position.
+const RESET: &[u8] = b"\x1bc";
+
+pub(crate) struct Control {
+    speed: f64,
+    paused: bool,
+    instant: bool,
+    seek: Option<Duration>,
+    /// Playback was stopped (handle closed or child killed).
+    stopped: bool,
+    /// Every frame has been written.
+    finished: bool,
+    /// Recording position at `anchor_wall`.
+    anchor_pos: Duration,
+    anchor_wall: Instant,
+}
+
+impl Control {
+    /// Current position on the recording's timeline.
+    pub(crate) fn position(&self) -> Duration {
+        if self.paused {
+            return self.anchor_pos;
+        }
+        self.anchor_pos + self.anchor_wall.elapsed().mul_f64(self.speed)
+    }
+
+    /// Pin the clock to "now" before changing how it advances.
+    fn rebase(&mut self) {
+        self.anchor_pos = self.position();
+        self.anchor_wall = Instant::now();
+    }
+
+    pub(crate) fn set_speed(&mut self, speed: f64) {
+        self.rebase();
+        self.speed = speed;
+    }
+
+    pub(crate) fn set_paused(&mut self, paused: bool) {
+        self.rebase();
+        self.paused = paused;
+    }
+
+    pub(crate) fn set_instant(&mut self, instant: bool) {
+        self.instant = instant;
+    }
+
+    pub(crate) fn seek(&mut self, to: Duration) {
+        self.seek = Some(to);
+    }
+
+    pub(crate) fn stop(&mut self) {
+        self.stopped = true;
+    }
+
+    /// Playback is over, either because it ran out or was stopped.
+    pub(crate) fn done(&self) -> bool {
+        self.finished || self.stopped
+    }
+}
+
+/// State shared between the player thread and the handle.
+pub(crate) struct Shared {
+    control: Mutex<Control>,
+    /// Signalled whenever `control` changes.
+    wake: Condvar,
+    pub(crate) size: Mutex<PtySize>,
+    initial_size: PtySize,
+    /// Timestamp of the last frame.
+    pub(crate) duration: Duration,
+}
+
+impl Shared {
+    pub(crate) fn new(size: PtySize, duration: Duration) -> Self {
+        Shared {
+            control: Mutex::new(Control {
+                speed: 1.0,
+                paused: false,
+                instant: false,
+                seek: None,
+                stopped: false,
+                finished: false,
+                anchor_pos: Duration::ZERO,
+                anchor_wall: Instant::now(),
+            }),
+            wake: Condvar::new(),
+            size: Mutex::new(size),
+            initial_size: size,
+            duration,
+        }
+    }
+
+    pub(crate) fn control(&self) -> MutexGuard<'_, Control> {
+        self.control.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Apply a change to the controls and wake the player.
+    pub(crate) fn update(&self, f: impl FnOnce(&mut Control)) {
+        f(&mut self.control());
+        self.wake.notify_all();
+    }
+
+    /// Block until playback is over.
+    pub(crate) fn wait_done(&self) {
+        let mut control = self.control();
+        while !control.done() {
+            control = self
+                .wake
+                .wait(control)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+/// Run playback of `recording` into `out` until it ends or is stopped.
+pub(crate) fn run(shared: Arc<Shared>, recording: Recording, mut out: PipeWriter) {
+    // If the handle goes away mid-write, get EPIPE rather than a
+    // process-wide SIGPIPE.
+    #[cfg(unix)]
+    crate::block_sigpipe();
+
+    let frames = recording.frames;
+    let mut next = 0;
+    while let Some(due) = next_batch(&shared, &frames, &mut next) {
+        for step in due {
+            let written = match step {
+                Step::Reset => {
+                    *shared.size.lock().unwrap_or_else(PoisonError::into_inner) =
+                        shared.initial_size;
+                    out.write_all(RESET)
+                }
+                Step::Frame(frame) => match &frame.kind {
+                    FrameKind::Output(bytes) => out.write_all(bytes),
+                    FrameKind::Resize(size) => {
+                        *shared.size.lock().unwrap_or_else(PoisonError::into_inner) = *size;
+                        Ok(())
+                    }
+                },
+            };
+            if written.is_err() {
+                shared.update(Control::stop);
+                return;
+            }
+        }
+    }
+
+    // Dropping `out` gives the reader EOF.
+    shared.update(|c| c.finished = true);
+}
+
+enum Step<'a> {
+    Reset,
+    Frame(&'a Frame),
+}
+
+/// Wait for the next frames to fall due. Returns `None` once playback is
+/// over.
+fn next_batch<'a>(shared: &Shared, frames: &'a [Frame], next: &mut usize) -> Option<Vec<Step<'a>>> {
+    let mut control = shared.control();
+    loop {
+        if control.stopped {
+            return None;
+        }
+
+        let mut due = Vec::new();
+        if let Some(target) = control.seek.take() {
+            let emitted = next.checked_sub(1).map(|i| frames[i].at);
+            if emitted.is_some_and(|at| target < at) {
+                due.push(Step::Reset);
+                *next = 0;
+            }
+            control.anchor_pos = target;
+            control.anchor_wall = Instant::now();
+        }
+
+        let position = if control.instant && !control.paused {
+            Duration::MAX
+        } else {
+            control.position()
+        };
+        while let Some(frame) = frames.get(*next).filter(|f| f.at <= position) {
+            due.push(Step::Frame(frame));
+            *next += 1;
+        }
+        if !due.is_empty() {
+            return Some(due);
+        }
+
+        let upcoming = frames.get(*next)?;
+        control = if control.paused {
+            shared.wake.wait(control)
+        } else {
+            let wait = (upcoming.at - position).div_f64(control.speed);
+            shared
+                .wake
+                .wait_timeout(control, wait)
+                .map(|(guard, _)| guard)
+                .map_err(|e| PoisonError::new(e.into_inner().0))
+        }
+        .unwrap_or_else(PoisonError::into_inner);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clock_follows_speed_and_pause() {
+        let shared = Shared::new(
+            PtySize {
+                rows: 24,
+                cols: 80,
+                pixel_width: 0,
+                pixel_height: 0,
+            },
+            Duration::from_secs(10),
+        );
+        shared.update(|c| c.set_paused(true));
+        let paused_at = shared.control().position();
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(shared.control().position(), paused_at);
+
+        shared.update(|c| {
+            c.set_speed(4.0);
+            c.set_paused(false);
+        });
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(shared.control().position() >= paused_at + Duration::from_millis(200));
+    }
+}
@@ -0,0 +1,147 @@
+//! Plain-text transcripts of recordings.
+
+use super::parse;
+#[cfg(feature = "screen")]
+use super::parse::FrameKind;
+#[cfg(feature = "screen")]
+use crate::screen::Screen;
+use crate::{PortablePtyBuffer, PortablePtyResult};
+use std::ffi::{c_char, CStr};
+use std::path::Path;
+
+/// Everything left on the primary screen and in its scrollback.
+pub const PORTABLE_PTY_TRANSCRIPT_SCREEN: u32 = 1;
+/// A JSON array of strings, one per shell prompt (split at OSC 133 `A`
+/// marks), each holding the prompt, the command and its output.
+pub const PORTABLE_PTY_TRANSCRIPT_COMMANDS: u32 = 2;
+
+/// Render a recording to readable text.
+///
+/// The recording is played instantly through a headless screen model, so
+/// progress bars, cursor movement and full-screen programs leave only what
+/// a user would have seen on the normal screen. Soft-wrapped lines are
+/// joined and trailing blanks trimmed.
+///
+/// - `path`: recording in any format the replay engine reads.
+/// - `mode`: one of the `PORTABLE_PTY_TRANSCRIPT_*` constants.
+/// - `out_text`: receives UTF-8 text; release with
+///   `portable_pty_buffer_free`.
+///
+/// Returns `ErrOpen` if the file can't be read, `ErrRecord` if it isn't a
+/// recording or `mode` is unknown, and `ErrUnsupported` in builds without
+/// the `screen` feature.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_recording_to_text(
+    path: *const c_char,
+    mode: u32,
+    out_text: *mut PortablePtyBuffer,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        if path.is_null() || out_text.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+        match unsafe { CStr::from_ptr(path) }.to_str() {
+            Ok(path) => match to_text(Path::new(path), mode) {
+                Ok(text) => {
+                    unsafe {
+                        *out_text = PortablePtyBuffer::from_vec(text.into_bytes());
+                    }
+                    PortablePtyResult::Ok
+                }
+                Err(e) => e,
+            },
+            Err(_) => PortablePtyResult::ErrOpen,
+        }
+    })
+}
+
+/// Render the recording at `path`, as `portable_pty_recording_to_text`
+/// does.
+pub(crate) fn to_text(path: &Path, mode: u32) -> Result<String, PortablePtyResult> {
+    if !matches!(
+        mode,
+        PORTABLE_PTY_TRANSCRIPT_SCREEN | PORTABLE_PTY_TRANSCRIPT_COMMANDS
+    ) {
+        return Err(PortablePtyResult::ErrRecord);
+    }
+    let bytes = std::fs::read(path).map_err(|_| PortablePtyResult::ErrOpen)?;
+    from_bytes(&bytes, mode)
+}
+
+/// Render the recording held in `bytes`.
+pub(crate) fn from_bytes(bytes: &[u8], mode: u32) -> Result<String, PortablePtyResult> {
+    match parse::parse(bytes) {
+        Some(recording) => render(recording, mode),
+        None => Err(PortablePtyResult::ErrRecord),
+    }
+}
+
+#[cfg(feature = "screen")]
+fn render(recording: parse::Recording, mode: u32) -> Result<String, PortablePtyResult> {
+    let screen = play(recording);
+    Ok(if mode == PORTABLE_PTY_TRANSCRIPT_SCREEN {
+        screen.text()
+    } else {
+        serde_json::Value::from(screen.command_texts()).to_string()
+    })
+}
+
+#[cfg(not(feature = "screen"))]
+fn render(_recording: parse::Recording, _mode: u32) -> Result<String, PortablePtyResult> {
+    Err(PortablePtyResult::ErrUnsupported)
+}
+
+#[cfg(feature = "screen")]
+fn play(recording: parse::Recording) -> Screen {
+    let mut screen = Screen::new(recording.size.rows, recording.size.cols, None);
+    for frame in recording.frames {
+        match frame.kind {
+            FrameKind::Output(bytes) => screen.feed(&bytes),
+            FrameKind::Resize(size) => screen.resize(size.rows, size.cols),
+        }
+    }
+    screen
+}
+
+#[cfg(all(test, feature = "screen"))]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+
+    fn transcript(cast: &str, mode: u32) -> String {
+        let path =
+            std::env::temp_dir().join(format!("portable_pty_{}_{mode}.cast", std::process::id()));
+        std::fs::write(&path, cast).unwrap();
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+
+        let mut out = PortablePtyBuffer::EMPTY;
+        let result = portable_pty_recording_to_text(c_path.as_ptr(), mode, &mut out);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(result, PortablePtyResult::Ok));
+        let bytes = unsafe { std::slice::from_raw_parts(out.data, out.len) }.to_vec();
+        out.release();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    const CAST: &str = concat!(
+        "{\"version\": 2, \"width\": 20, \"height\": 4}\n",
+        "[0.1, \"o\", \"\\u001b]133;A\\u0007$ make\\r\\n\"]\n",
+        "[0.2, \"o\", \"[  0%]\\r[100%]\\r\\n\"]\n",
+        "[0.3, \"o\", \"\\u001b]133;A\\u0007$ \"]\n",
+    );
+
+    #[test]
+    fn test_screen_transcript() {
+        assert_eq!(
+            transcript(CAST, PORTABLE_PTY_TRANSCRIPT_SCREEN),
+            "$ make\n[100%]\n$\n"
+        );
+    }
+
+    #[test]
+    fn test_command_transcript() {
+        let json = transcript(CAST, PORTABLE_PTY_TRANSCRIPT_COMMANDS);
+        let sections: Vec<String> = serde_json::from_str(&json).unwrap();
+        assert_eq!(sections, vec!["$ make\n[100%]\n", "$\n"]);
+    }
+}
@@ -0,0 +1,191 @@
+//! One-shot run-and-capture.
+//!
+//! `portable_pty_run` covers the common case of running a single command
+//! under a PTY (so it sees a terminal, emits colors, …) and collecting
+//! everything it printed, without the caller managing a handle.
+
+use crate::expect::{deadline_after, remaining};
+use crate::{
+    portable_pty_close, portable_pty_kill, portable_pty_open, portable_pty_spawn,
+    portable_pty_wait, portable_pty_wait_blocking, PortablePty, PortablePtyBuffer,
+    PortablePtyResult,
+};
+use std::ffi::{c_char, c_int};
+use std::time::{Duration, Instant};
+
+/// Terminal size the command sees.
+const RUN_ROWS: u16 = 24;
+const RUN_COLS: u16 = 80;
+
+/// How often to check for child exit while no output arrives.
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Signal sent to a child that outlives its timeout.
+#[cfg(unix)]
+const TIMEOUT_SIGNAL: c_int = libc::SIGKILL;
+#[cfg(not(unix))]
+const TIMEOUT_SIGNAL: c_int = 9;
+
+/// Run a command in a fresh PTY, collect its output and wait for it to exit.
+///
+/// - `cmd`, `argv`, `envp`: as for `portable_pty_spawn`.
+/// - `timeout_ms`: maximum run time; negative waits indefinitely.
+/// - `out_output`: receives everything the command wrote to the terminal.
+///   Release with `portable_pty_buffer_free`.
+/// - `out_exit`: receives the exit code.
+///
+/// Returns `ErrTimeout` if the command was still running at the deadline;
+/// it is killed, `*out_output` holds the output collected so far and
+/// `*out_exit` is left untouched. Spawn and open failures are returned as-is
+/// with nothing written.
+///
+/// Timeouts rely on polling the master and are only honored on Unix.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_run(
+    cmd: *const c_char,
+    argv: *const *const c_char,
+    envp: *const *const c_char,
+    timeout_ms: i32,
+    out_output: *mut PortablePtyBuffer,
+    out_exit: *mut c_int,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        if cmd.is_null() || out_output.is_null() || out_exit.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+
+        let mut handle: *mut PortablePty = std::ptr::null_mut();
+        let result = portable_pty_open(RUN_ROWS, RUN_COLS, &mut handle);
+        if !matches!(result, PortablePtyResult::Ok) {
+            return result;
+        }
+        let result = portable_pty_spawn(handle, cmd, argv, envp);
+        if !matches!(result, PortablePtyResult::Ok) {
+            portable_pty_close(handle);
+            return result;
+        }
+
+        let mut output = Vec::new();
+        let result = collect(handle, deadline_after(timeout_ms), &mut output, out_exit);
+        portable_pty_close(handle);
+
+        if matches!(
+            result,
+            PortablePtyResult::Ok | PortablePtyResult::ErrTimeout
+        ) {
+            unsafe {
+                *out_output = PortablePtyBuffer::from_vec(output);
+            }
+        }
+        result
+    })
+}
+
+/// Read until the child has exited and its output is drained.
+///
+/// The handle keeps the slave open, so the master usually never reports
+/// EOF; exit is detected by polling between reads instead.
+fn collect(
+    handle: *mut PortablePty,
+    deadline: Option<Instant>,
+    output: &mut Vec<u8>,
+    out_exit: *mut c_int,
+) -> PortablePtyResult {
+    let pty = unsafe { &*handle };
+    let mut chunk = [0u8; 4096];
+
+    let code = loop {
+        let wait = match remaining(deadline) {
+            Some(left) if left.is_zero() => {
+                portable_pty_kill(handle, TIMEOUT_SIGNAL);
+                return PortablePtyResult::ErrTimeout;
+            }
+            Some(left) => left.min(EXIT_POLL_INTERVAL),
+            None => EXIT_POLL_INTERVAL,
+        };
+
+        match pty.wait_readable(Some(wait)) {
+            Ok(true) => match pty.read_master(&mut chunk) {
+                Ok(n) if n > 0 => output.extend_from_slice(&chunk[..n]),
+                // EOF (or EIO on Linux): the child side is gone.
+                _ => {
+                    let mut code: c_int = -1;
+                    portable_pty_wait_blocking(handle, &mut code);
+                    break code;
+                }
+            },
+            Ok(false) => {
+                let mut code: c_int = 0;
+                if matches!(portable_pty_wait(handle, &mut code), PortablePtyResult::Ok) {
+                    break code;
+                }
+            }
+            Err(_) => return PortablePtyResult::ErrRead,
+        }
+    };
+
+    // Take whatever the child wrote just before exiting.
+    while let Ok(true) = pty.wait_readable(Some(Duration::ZERO)) {
+        match pty.read_master(&mut chunk) {
+            Ok(n) if n > 0 => output.extend_from_slice(&chunk[..n]),
+            _ => break,
+        }
+    }
+
+    unsafe {
+        *out_exit = code;
+    }
+    PortablePtyResult::Ok
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use std::ptr;
+
+    fn run(argv: &[&str], timeout_ms: i32) -> (PortablePtyResult, String, c_int) {
+        let cmd = CString::new(argv[0]).unwrap();
+        let args: Vec<CString> = argv.iter().map(|a| CString::new(*a).unwrap()).collect();
+        let mut ptrs: Vec<*const c_char> = args.iter().map(|a| a.as_ptr()).collect();
+        ptrs.push(ptr::null());
+
+        let mut output = PortablePtyBuffer::EMPTY;
+        let mut exit: c_int = i32::MIN;
+        let result = portable_pty_run(
+            cmd.as_ptr(),
+            ptrs.as_ptr(),
+            ptr::null(),
+            timeout_ms,
+            &mut output,
+            &mut exit,
+        );
+        let text = if output.data.is_null() {
+            String::new()
+        } else {
+            let bytes = unsafe { std::slice::from_raw_parts(output.data, output.len) };
+            String::from_utf8_lossy(bytes).into_owned()
+        };
+        output.release();
+        (result, text, exit)
+    }
+
+    #[test]
+    fn test_run_captures_output_and_exit() {
+        let (result, output, exit) = run(
+            &["/bin/sh", "-c", "test -t 1 && echo on-a-tty; exit 3"],
+            5000,
+        );
+        assert!(matches!(result, PortablePtyResult::Ok));
+        assert_eq!(output, "on-a-tty\r\n");
+        assert_eq!(exit, 3);
+    }
+
+    #[test]
+    fn test_run_times_out() {
+        let (result, output, exit) = run(&["/bin/sh", "-c", "echo started; sleep 5"], 300);
+        assert!(matches!(result, PortablePtyResult::ErrTimeout));
+        assert!(output.contains("started"), "unexpected output: {output:?}");
+        assert_eq!(exit, i32::MIN, "exit code untouched on timeout");
+    }
+}
@@ -0,0 +1,907 @@
+//! Headless screen model.
+//!
+//! A minimal terminal emulator that applies output to a character grid with
+//! scrollback, for features that need to know what is on screen rather
+//! than what bytes went past (transcripts, text extraction). It tracks text
+//! and cursor only: attributes and colors are ignored, and combining marks
+//! are dropped. Soft wraps are remembered per line so wrapped text can be
+//! joined back into logical lines, and rewrapped when the width changes.
+
+use std::collections::VecDeque;
+use unicode_width::UnicodeWidthChar;
+
+/// Placeholder in the cell after a double-width character.
+const WIDE_TAIL: char = '\0';
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Line {
+    pub(crate) cells: Vec<char>,
+    /// The line continues on the next one (soft wrap at the right margin).
+    pub(crate) wrapped: bool,
+}
+
+impl Line {
+    fn blank(cols: usize) -> Line {
+        Line {
+            cells: vec![' '; cols],
+            wrapped: false,
+        }
+    }
+
+    fn resize(&mut self, cols: usize) {
+        self.cells.resize(cols, ' ');
+        // A wide character cut in half at the new margin is blanked.
+        if let Some(last) = self.cells.last_mut() {
+            if last.width() == Some(2) {
+                *last = ' ';
+            }
+        }
+    }
+
+    fn is_blank(&self) -> bool {
+        !self.wrapped && self.cells.iter().all(|&c| c == ' ')
+    }
+
+    /// Cell text with wide-character placeholders removed.
+    pub(crate) fn text(&self) -> String {
+        self.cells.iter().filter(|&&c| c != WIDE_TAIL).collect()
+    }
+}
+
+/// A character of a line's text, and where it's drawn.
+#[derive(Clone, Copy)]
+pub(crate) struct Placed {
+    /// Byte offset in the text.
+    pub(crate) at: usize,
+    pub(crate) line: usize,
+    pub(crate) col: usize,
+    /// Columns taken: 2 for a wide character.
+    pub(crate) width: usize,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Cursor {
+    row: usize,
+    col: usize,
+    /// The last column was written; the next printable wraps first.
+    wrap_pending: bool,
+}
+
+struct Grid {
+    lines: Vec<Line>,
+    cursor: Cursor,
+    saved: Cursor,
+    /// Scroll region, inclusive.
+    top: usize,
+    bottom: usize,
+}
+
+impl Grid {
+    fn new(rows: usize, cols: usize) -> Grid {
+        Grid {
+            lines: vec![Line::blank(cols); rows],
+            cursor: Cursor::default(),
+            saved: Cursor::default(),
+            top: 0,
+            bottom: rows - 1,
+        }
+    }
+}
+
+struct State {
+    rows: usize,
+    cols: usize,
+    primary: Grid,
+    /// The alternate screen, while active.
+    alternate: Option<Grid>,
+    autowrap: bool,
+    scrollback: VecDeque<Line>,
+    scrollback_limit: Option<usize>,
+    /// Lines dropped off the front of the scrollback so far.
+    dropped: usize,
+    /// Absolute line numbers (see `Screen::lines`) of OSC 133 prompt marks.
+    prompts: Vec<usize>,
+}
+
+/// A screen fed with terminal output.
+pub(crate) struct Screen {
+    parser: vte::Parser,
+    state: State,
+}
+
+impl Screen {
+    /// A blank `rows` x `cols` screen. `scrollback_limit` of `None` keeps
+    /// every line that scrolls off.
+    pub(crate) fn new(rows: u16, cols: u16, scrollback_limit: Option<usize>) -> Screen {
+        let (rows, cols) = (usize::from(rows.max(1)), usize::from(cols.max(1)));
+        Screen {
+            parser: vte::Parser::new(),
+            state: State {
+                rows,
+                cols,
+                primary: Grid::new(rows, cols),
+                alternate: None,
+                autowrap: true,
+                scrollback: VecDeque::new(),
+                scrollback_limit,
+                dropped: 0,
+                prompts: Vec::new(),
+            },
+        }
+    }
+
+    pub(crate) fn feed(&mut self, bytes: &[u8]) {
+        self.parser.advance(&mut self.state, bytes);
+    }
+
+    /// Change the screen size.
+    ///
+    /// The primary screen and scrollback are reflowed: soft-wrapped lines
+    /// are joined and wrapped again at the new width, and the cursor stays
+    /// on the same character. The screen then shows the last lines, or
+    /// starts at the cursor's if that would push it off the top; lines
+    /// above go to the scrollback, and growing taller brings them back.
+    /// The alternate screen is cut or padded, as its program redraws it.
+    pub(crate) fn resize(&mut self, rows: u16, cols: u16) {
+        self.state
+            .resize(usize::from(rows.max(1)), usize::from(cols.max(1)));
+    }
+
+    /// Scrollback followed by the primary screen, oldest first.
+    pub(crate) fn lines(&self) -> impl Iterator<Item = &Line> {
+        self.state
+            .scrollback
+            .iter()
+            .chain(self.state.primary.lines.iter())
+    }
+
+    /// Number of the oldest line kept. Lines are numbered from the first
+    /// the screen had, so each keeps its number as the scrollback moves.
+    pub(crate) fn first_line(&self) -> usize {
+        self.state.dropped
+    }
+
+    /// How many lines are kept, scrollback and screen.
+    pub(crate) fn line_count(&self) -> usize {
+        self.state.scrollback.len() + self.state.rows
+    }
+
+    /// Line `n`, numbered as for `first_line`, from the scrollback or the
+    /// screen on display: the alternate one while it's active.
+    pub(crate) fn line(&self, n: usize) -> Option<&Line> {
+        let i = n.checked_sub(self.state.dropped)?;
+        let scrollback = &self.state.scrollback;
+        match i.checked_sub(scrollback.len()) {
+            None => scrollback.get(i),
+            Some(row) => {
+                let grid = self.state.alternate.as_ref();
+                grid.unwrap_or(&self.state.primary).lines.get(row)
+            }
+        }
+    }
+
+    /// The numbers of the lines making up the logical line that line `n`
+    /// is part of, soft wraps joined.
+    pub(crate) fn logical_line(&self, n: usize) -> std::ops::Range<usize> {
+        let mut start = n;
+        while start > 0 && self.line(start - 1).is_some_and(|line| line.wrapped) {
+            start -= 1;
+        }
+        let mut end = n;
+        while self.line(end).is_some_and(|line| line.wrapped) {
+            end += 1;
+        }
+        start..end + 1
+    }
+
+    /// The text of the logical line that line `n` is part of, and where
+    /// each of its characters is drawn.
+    pub(crate) fn logical_text(&self, n: usize) -> (String, Vec<Placed>) {
+        let mut text = String::new();
+        let mut placed = Vec::new();
+        for number in self.logical_line(n) {
+            let Some(line) = self.line(number) else {
+                break;
+            };
+            for (col, &c) in line.cells.iter().enumerate() {
+                if let Some(width @ 1..) = c.width() {
+                    placed.push(Placed {
+                        at: text.len(),
+                        line: number,
+                        col,
+                        width,
+                    });
+                    text.push(c);
+                }
+            }
+        }
+        (text, placed)
+    }
+
+    /// The primary screen and scrollback as plain text.
+    ///
+    /// Soft-wrapped lines are joined, trailing blanks trimmed, and trailing
+    /// empty lines dropped.
+    pub(crate) fn text(&self) -> String {
+        join_lines(self.lines())
+    }
+
+    /// The text split at each shell prompt (OSC 133 `A` mark), so each
+    /// section holds one prompt, its command and the command's output.
+    /// Text before the first prompt is its own section if not blank.
+    pub(crate) fn command_texts(&self) -> Vec<String> {
+        let lines: Vec<&Line> = self.lines().collect();
+        let mut starts: Vec<usize> = self
+            .state
+            .prompts
+            .iter()
+            .filter_map(|p| p.checked_sub(self.state.dropped))
+            .filter(|&p| p < lines.len())
+            .collect();
+        starts.sort_unstable();
+        starts.dedup();
+        if starts.first() != Some(&0) {
+            starts.insert(0, 0);
+        }
+
+        let mut sections = Vec::new();
+        for (i, &start) in starts.iter().enumerate() {
+            let end = starts.get(i + 1).copied().unwrap_or(lines.len());
+            let text = join_lines(lines[start..end].iter().copied());
+            if i > 0 || !text.is_empty() {
+                sections.push(text);
+            }
+        }
+        sections
+    }
+}
+
+fn join_lines<'a>(lines: impl Iterator<Item = &'a Line>) -> String {
+    let mut out = String::new();
+    let mut logical = String::new();
+    for line in lines {
+        logical.push_str(&line.text());
+        if !line.wrapped {
+            out.push_str(logical.trim_end_matches(' '));
+            out.push('\n');
+            logical.clear();
+        }
+    }
+    if !logical.is_empty() {
+        out.push_str(logical.trim_end_matches(' '));
+        out.push('\n');
+    }
+    let trimmed = out.trim_end_matches('\n').len();
+    out.truncate(trimmed);
+    if !out.is_empty() {
+        out.push('\n');
+    }
+    out
+}
+
+impl State {
+    fn grid(&mut self) -> &mut Grid {
+        self.alternate.as_mut().unwrap_or(&mut self.primary)
+    }
+
+    fn blank(&self) -> Line {
+        Line::blank(self.cols)
+    }
+
+    fn push_scrollback(&mut self, line: Line) {
+        self.scrollback.push_back(line);
+        if let Some(limit) = self.scrollback_limit {
+            while self.scrollback.len() > limit {
+                self.scrollback.pop_front();
+                self.dropped += 1;
+            }
+        }
+    }
+
+    /// Scroll the region up `n` lines. Lines leaving the top of a
+    /// full-height primary region go to the scrollback.
+    fn scroll_up(&mut self, n: usize) {
+        let blank = self.blank();
+        let to_scrollback = self.alternate.is_none() && self.primary.top == 0;
+        for _ in 0..n {
+            let grid = self.grid();
+            let (top, bottom) = (grid.top, grid.bottom);
+            let line = grid.lines.remove(top);
+            grid.lines.insert(bottom, blank.clone());
+            if to_scrollback {
+                self.push_scrollback(line);
+            }
+        }
+    }
+
+    fn scroll_down(&mut self, n: usize) {
+        let blank = self.blank();
+        let grid = self.grid();
+        for _ in 0..n {
+            grid.lines.remove(grid.bottom);
+            grid.lines.insert(grid.top, blank.clone());
+        }
+    }
+
+    fn linefeed(&mut self) {
+        let rows = self.rows;
+        let grid = self.grid();
+        grid.cursor.wrap_pending = false;
+        if grid.cursor.row == grid.bottom {
+            self.scroll_up(1);
+        } else if grid.cursor.row + 1 < rows {
+            grid.cursor.row += 1;
+        }
+    }
+
+    fn reverse_index(&mut self) {
+        let grid = self.grid();
+        grid.cursor.wrap_pending = false;
+        if grid.cursor.row == grid.top {
+            self.scroll_down(1);
+        } else {
+            grid.cursor.row = grid.cursor.row.saturating_sub(1);
+        }
+    }
+
+    fn print(&mut self, c: char) {
+        let width = match c.width() {
+            Some(w) if w > 0 => w,
+            _ => return,
+        };
+        let (cols, autowrap) = (self.cols, self.autowrap);
+        if width > cols {
+            return;
+        }
+
+        let grid = self.grid();
+        if grid.cursor.wrap_pending || grid.cursor.col + width > cols {
+            if autowrap {
+                let row = grid.cursor.row;
+                grid.lines[row].wrapped = true;
+                grid.cursor.col = 0;
+                self.linefeed();
+            } else {
+                grid.cursor.col = cols - width;
+            }
+        }
+
+        let grid = self.grid();
+        let Cursor { row, col, .. } = grid.cursor;
+        let line = &mut grid.lines[row];
+        // Overwriting half of a wide character blanks the other half.
+        if line.cells[col] == WIDE_TAIL && col > 0 {
+            line.cells[col - 1] = ' ';
+        }
+        if col + width < cols && line.cells[col + width] == WIDE_TAIL {
+            line.cells[col + width] = ' ';
+        }
+        line.cells[col] = c;
+        if width == 2 {
+            line.cells[col + 1] = WIDE_TAIL;
+        }
+
+        if col + width >= cols {
+            grid.cursor.col = cols - 1;
+            grid.cursor.wrap_pending = true;
+        } else {
+            grid.cursor.col = col + width;
+        }
+    }
+
+    fn goto(&mut self, row: usize, col: usize) {
+        let (rows, cols) = (self.rows, self.cols);
+        let grid = self.grid();
+        grid.cursor = Cursor {
+            row: row.min(rows - 1),
+            col: col.min(cols - 1),
+            wrap_pending: false,
+        };
+    }
+
+    fn erase_in_display(&mut self, mode: u16) {
+        let blank = self.blank();
+        let grid = self.grid();
+        let Cursor { row, col, .. } = grid.cursor;
+        match mode {
+            0 => {
+                grid.lines[row].cells[col..].fill(' ');
+                grid.lines[row].wrapped = false;
+                grid.lines[row + 1..].fill(blank);
+            }
+            1 => {
+                grid.lines[..row].fill(blank);
+                grid.lines[row].cells[..=col].fill(' ');
+            }
+            2 => grid.lines.fill(blank),
+            3 => {
+                self.dropped += self.scrollback.len();
+                self.scrollback.clear();
+            }
+            _ => {}
+        }
+    }
+
+    fn erase_in_line(&mut self, mode: u16) {
+        let grid = self.grid();
+        let Cursor { row, col, .. } = grid.cursor;
+        let line = &mut grid.lines[row];
+        match mode {
+            0 => {
+                line.cells[col..].fill(' ');
+                line.wrapped = false;
+            }
+            1 => line.cells[..=col].fill(' '),
+            2 => {
+                line.cells.fill(' ');
+                line.wrapped = false;
+            }
+            _ => {}
+        }
+    }
+
+    fn set_alternate(&mut self, on: bool, save_cursor: bool) {
+        if on && self.alternate.is_none() {
+            if save_cursor {
+                self.primary.saved = self.primary.cursor;
+            }
+            self.alternate = Some(Grid::new(self.rows, self.cols));
+        } else if !on && self.alternate.is_some() {
+            self.alternate = None;
+            if save_cursor {
+                self.primary.cursor = self.primary.saved;
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        let limit = self.scrollback_limit;
+        self.dropped += self.scrollback.len();
+        let (dropped, prompts) = (self.dropped, std::mem::take(&mut self.prompts));
+        *self = State {
+            rows: self.rows,
+            cols: self.cols,
+            primary: Grid::new(self.rows, self.cols),
+            alternate: None,
+            autowrap: true,
+            scrollback: VecDeque::new(),
+            scrollback_limit: limit,
+            dropped: dropped + self.rows,
+            prompts,
+        };
+    }
+
+    fn resize(&mut self, rows: usize, cols: usize) {
+        self.reflow(rows, cols);
+        if let Some(grid) = &mut self.alternate {
+            for line in &mut grid.lines {
+                line.resize(cols);
+            }
+            // Drop lines from the top while the cursor stays on screen, then
+            // from the bottom.
+            let excess = grid.lines.len().saturating_sub(rows);
+            let from_top = excess.min(grid.cursor.row);
+            grid.lines.drain(..from_top);
+            grid.cursor.row -= from_top;
+            grid.lines.resize(rows, Line::blank(cols));
+            grid.cursor.col = grid.cursor.col.min(cols - 1);
+            grid.cursor.wrap_pending = false;
+            grid.saved.row = grid.saved.row.min(rows - 1);
+            grid.saved.col = grid.saved.col.min(cols - 1);
+            grid.top = 0;
+            grid.bottom = rows - 1;
+        }
+        self.rows = rows;
+        self.cols = cols;
+    }
+
+    /// Rewrap the scrollback and primary screen at `cols` and fit them to
+    /// `rows` (see `Screen::resize`).
+    fn reflow(&mut self, rows: usize, cols: usize) {
+        let cursor = self.primary.cursor;
+        let cursor_line = self.scrollback.len() + cursor.row;
+        let mut old: Vec<Line> = self.scrollback.drain(..).collect();
+        old.append(&mut self.primary.lines);
+        // Blank lines below the cursor are unused screen, not content.
+        while old.len() > cursor_line + 1 && old.last().is_some_and(Line::is_blank) {
+            old.pop();
+        }
+
+        let count = old.len();
+        let mut lines = Vec::new();
+        // Where each old line's logical line starts among the new ones;
+        // nothing is added to `lines` until a logical line ends.
+        let mut starts = Vec::with_capacity(count);
+        let mut logical = Vec::new();
+        let mut cursor_offset = None;
+        let mut new_cursor = None;
+        for (i, line) in old.into_iter().enumerate() {
+            if i == cursor_line {
+                cursor_offset = Some(logical.len() + cursor.col + usize::from(cursor.wrap_pending));
+            }
+            logical.extend(line.cells);
+            starts.push(lines.len());
+            if line.wrapped && i + 1 < count {
+                continue;
+            }
+            let wrapped = rewrap(
+                &mut lines,
+                std::mem::take(&mut logical),
+                cols,
+                cursor_offset.take(),
+            );
+            new_cursor = new_cursor.or(wrapped);
+        }
+        let new_cursor = new_cursor.unwrap_or_default();
+
+        // Prompt marks follow their line's new position.
+        let dropped = self.dropped;
+        for prompt in &mut self.prompts {
+            if let Some(i) = prompt.checked_sub(dropped) {
+                *prompt = dropped + starts.get(i).copied().unwrap_or(lines.len());
+            }
+        }
+
+        let start = lines.len().saturating_sub(rows).min(new_cursor.row);
+        let mut screen = lines.split_off(start);
+        screen.truncate(rows);
+        screen.resize(rows, Line::blank(cols));
+        for line in lines {
+            self.push_scrollback(line);
+        }
+        let grid = &mut self.primary;
+        grid.lines = screen;
+        grid.cursor = Cursor {
+            row: new_cursor.row - start,
+            ..new_cursor
+        };
+        grid.saved.row = grid.saved.row.min(rows - 1);
+        grid.saved.col = grid.saved.col.min(cols - 1);
+        grid.top = 0;
+        grid.bottom = rows - 1;
+    }
+}
+
+/// Wrap the logical line `cells` at `cols`, appending the result to
+/// `lines`. Returns where the character `cursor` cells in ends up, if
+/// given.
+fn rewrap(
+    lines: &mut Vec<Line>,
+    mut cells: Vec<char>,
+    cols: usize,
+    cursor: Option<usize>,
+) -> Option<Cursor> {
+    let used = cells.iter().rposition(|&c| c != ' ').map_or(0, |i| i + 1);
+    cells.truncate(used.max(cursor.unwrap_or(0)));
+    // Too narrow for wide characters, as when printing.
+    if cols < 2 {
+        for c in &mut cells {
+            if *c == WIDE_TAIL || c.width() == Some(2) {
+                *c = ' ';
+            }
+        }
+    }
+
+    let mut at = None;
+    let mut pos = 0;
+    loop {
+        let mut end = (pos + cols).min(cells.len());
+        // A wide character that doesn't fit moves to the next line whole.
+        if end < cells.len() && cells[end] == WIDE_TAIL {
+            end -= 1;
+        }
+        if let Some(offset) = cursor.filter(|&offset| offset >= pos) {
+            at = Some((lines.len(), offset - pos));
+        }
+        let mut line = Line {
+            cells: cells[pos..end].to_vec(),
+            wrapped: end < cells.len(),
+        };
+        line.cells.resize(cols, ' ');
+        lines.push(line);
+        pos = end;
+        if pos >= cells.len() {
+            break;
+        }
+    }
+    // Just past a full last line is the margin with a wrap pending.
+    at.map(|(row, col)| Cursor {
+        row,
+        col: col.min(cols - 1),
+        wrap_pending: col >= cols,
+    })
+}
+
+/// Parameter `i`, with 0 or missing replaced by `default`.
+fn param(params: &vte::Params, i: usize, default: u16) -> u16 {
+    match params.iter().nth(i).and_then(|p| p.first()) {
+        Some(&0) | None => default,
+        Some(&v) => v,
+    }
+}
+
+impl vte::Perform for State {
+    fn print(&mut self, c: char) {
+        State::print(self, c);
+    }
+
+    fn execute(&mut self, byte: u8) {
+        match byte {
+            b'\n' | 0x0b | 0x0c => self.linefeed(),
+            b'\r' => {
+                let grid = self.grid();
+                grid.cursor.col = 0;
+                grid.cursor.wrap_pending = false;
+            }
+            0x08 => {
+                let grid = self.grid();
+                grid.cursor.col = grid.cursor.col.saturating_sub(1);
+                grid.cursor.wrap_pending = false;
+            }
+            b'\t' => {
+                let cols = self.cols;
+                let grid = self.grid();
+                grid.cursor.col = ((grid.cursor.col / 8 + 1) * 8).min(cols - 1);
+                grid.cursor.wrap_pending = false;
+            }
+            _ => {}
+        }
+    }
+
+    fn osc_dispatch(&mut self, params: &[&[u8]], _bell_terminated: bool) {
+        if params.first() == Some(&&b"133"[..])
+            && params.get(1) == Some(&&b"A"[..])
+            && self.alternate.is_none()
+        {
+            let line = self.dropped + self.scrollback.len() + self.primary.cursor.row;
+            self.prompts.push(line);
+        }
+    }
+
+    fn esc_dispatch(&mut self, intermediates: &[u8], _ignore: bool, byte: u8) {
+        if !intermediates.is_empty() {
+            return;
+        }
+        match byte {
+            b'c' => self.reset(),
+            b'D' => self.linefeed(),
+            b'E' => {
+                self.linefeed();
+                self.grid().cursor.col = 0;
+            }
+            b'M' => self.reverse_index(),
+            b'7' => {
+                let grid = self.grid();
+                grid.saved = grid.cursor;
+            }
+            b'8' => {
+                let grid = self.grid();
+                grid.cursor = grid.saved;
+            }
+            _ => {}
+        }
+    }
+
+    fn csi_dispatch(
+        &mut self,
+        params: &vte::Params,
+        intermediates: &[u8],
+        _ignore: bool,
+        action: char,
+    ) {
+        if intermediates == b"?" {
+            let on = match action {
+                'h' => true,
+                'l' => false,
+                _ => return,
+            };
+            for p in params.iter() {
+                match p.first() {
+                    Some(7) => self.autowrap = on,
+                    Some(47) | Some(1047) => self.set_alternate(on, false),
+                    Some(1049) => self.set_alternate(on, true),
+                    _ => {}
+                }
+            }
+            return;
+        }
+        if !intermediates.is_empty() {
+            return;
+        }
+
+        let n = usize::from(param(params, 0, 1));
+        let (rows, cols) = (self.rows, self.cols);
+        let Cursor { row, col, .. } = self.grid().cursor;
+        match action {
+            'A' => {
+                let top = if row >= self.grid().top {
+                    self.grid().top
+                } else {
+                    0
+                };
+                self.goto(row.saturating_sub(n).max(top), col);
+            }
+            'B' | 'e' => {
+                let bottom = if row <= self.grid().bottom {
+                    self.grid().bottom
+                } else {
+                    rows - 1
+                };
+                self.goto((row + n).min(bottom), col);
+            }
+            'C' | 'a' => self.goto(row, col + n),
+            'D' => self.goto(row, col.saturating_sub(n)),
+            'E' => self.goto(row + n, 0),
+            'F' => self.goto(row.saturating_sub(n), 0),
+            'G' | '`' => self.goto(row, n - 1),
+            'd' => self.goto(n - 1, col),
+            'H' | 'f' => {
+                let c = usize::from(param(params, 1, 1));
+                self.goto(n - 1, c - 1);
+            }
+            'J' => self.erase_in_display(param(params, 0, 0)),
+            'K' => self.erase_in_line(param(params, 0, 0)),
+            'L' | 'M' => {
+                let blank = self.blank();
+                let grid = self.grid();
+                if row < grid.top || row > grid.bottom {
+                    return;
+                }
+                for _ in 0..n.min(grid.bottom - row + 1) {
+                    if action == 'L' {
+                        grid.lines.remove(grid.bottom);
+                        grid.lines.insert(row, blank.clone());
+                    } else {
+                        grid.lines.remove(row);
+                        grid.lines.insert(grid.bottom, blank.clone());
+                    }
+                }
+                grid.cursor.col = 0;
+                grid.cursor.wrap_pending = false;
+            }
+            'P' => {
+                let cells = &mut self.grid().lines[row].cells;
+                let n = n.min(cols - col);
+                cells.drain(col..col + n);
+                cells.resize(cols, ' ');
+            }
+            '@' => {
+                let cells = &mut self.grid().lines[row].cells;
+                let n = n.min(cols - col);
+                cells.truncate(cols - n);
+                cells.splice(col..col, std::iter::repeat_n(' ', n));
+            }
+            'X' => {
+                let end = (col + n).min(cols);
+                self.grid().lines[row].cells[col..end].fill(' ');
+            }
+            'S' => self.scroll_up(n),
+            'T' => self.scroll_down(n),
+            'r' => {
+                let top = usize::from(param(params, 0, 1)) - 1;
+                let bottom = usize::from(param(params, 1, rows as u16)).min(rows) - 1;
+                if top < bottom {
+                    let grid = self.grid();
+                    grid.top = top;
+                    grid.bottom = bottom;
+                    self.goto(0, 0);
+                }
+            }
+            's' => {
+                let grid = self.grid();
+                grid.saved = grid.cursor;
+            }
+            'u' => {
+                let grid = self.grid();
+                grid.cursor = grid.saved;
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn screen(rows: u16, cols: u16, output: &str) -> Screen {
+        let mut screen = Screen::new(rows, cols, None);
+        screen.feed(output.as_bytes());
+        screen
+    }
+
+    #[test]
+    fn test_text_with_scrollback_and_wraps() {
+        let s = screen(3, 10, "one\r\ntwo\r\nthree\r\nfour is longer\r\n");
+        assert_eq!(s.text(), "one\ntwo\nthree\nfour is longer\n");
+        assert_eq!(s.state.scrollback.len(), 3);
+        assert!(s.lines().nth(3).unwrap().wrapped);
+    }
+
+    #[test]
+    fn test_overwrites_and_erases() {
+        let s = screen(
+            3,
+            20,
+            "progress 10%\rprogress 99%\r\nabc\x1b[2Dx\x1b[K\r\nwide 字!",
+        );
+        assert_eq!(s.text(), "progress 99%\nax\nwide 字!\n");
+
+        let s = screen(3, 20, "gone\x1b[2J\x1b[Hkept");
+        assert_eq!(s.text(), "kept\n");
+    }
+
+    #[test]
+    fn test_alternate_screen_is_not_in_transcript() {
+        let s = screen(3, 20, "$ vim\r\n\x1b[?1049h\x1b[Hediting\x1b[?1049l$ ");
+        assert_eq!(s.text(), "$ vim\n$\n");
+    }
+
+    #[test]
+    fn test_splits_at_prompt_marks() {
+        let s = screen(
+            5,
+            20,
+            "motd\r\n\x1b]133;A\x07$ ls\r\na b\r\n\x1b]133;A\x07$ true\r\n\x1b]133;A\x07$ ",
+        );
+        assert_eq!(
+            s.command_texts(),
+            vec!["motd\n", "$ ls\na b\n", "$ true\n", "$\n"]
+        );
+    }
+
+    #[test]
+    fn test_resize_keeps_cursor_line() {
+        let mut s = screen(4, 10, "a\r\nb\r\nc\r\nd");
+        s.resize(2, 5);
+        assert_eq!(s.state.scrollback.len(), 2);
+        assert_eq!(s.text(), "a\nb\nc\nd\n");
+    }
+
+    fn rows(s: &Screen) -> Vec<String> {
+        s.lines().map(Line::text).collect()
+    }
+
+    #[test]
+    fn test_resize_reflows_wrapped_lines() {
+        let mut s = screen(3, 10, "hello world\r\n$ ");
+        s.resize(3, 5);
+        assert_eq!(rows(&s), ["hello", " worl", "d    ", "$    "]);
+        assert_eq!(s.text(), "hello world\n$\n");
+        let cursor = s.state.primary.cursor;
+        assert_eq!((cursor.row, cursor.col), (2, 2));
+
+        // Wider again brings the lines back from the scrollback.
+        s.resize(3, 20);
+        assert!(s.state.scrollback.is_empty());
+        assert_eq!(s.text(), "hello world\n$\n");
+        s.feed(b"ls");
+        assert_eq!(s.text(), "hello world\n$ ls\n");
+    }
+
+    #[test]
+    fn test_reflow_keeps_the_cursor_on_its_character() {
+        let mut s = screen(2, 10, "abcdefghijkl");
+        s.resize(3, 6);
+        assert_eq!(rows(&s), ["abcdef", "ghijkl", "      "]);
+        s.feed(b"m");
+        assert_eq!(s.text(), "abcdefghijklm\n");
+
+        // A wide character doesn't straddle the new margin.
+        let mut s = screen(2, 10, "abc字");
+        s.resize(2, 4);
+        assert_eq!(rows(&s), ["abc ", "字  "]);
+        assert_eq!(s.text(), "abc 字\n");
+    }
+
+    #[test]
+    fn test_reflow_moves_prompt_marks() {
+        let mut s = screen(
+            4,
+            12,
+            "\x1b]133;A\x07$ echo longer\r\nlonger\r\n\x1b]133;A\x07$ ",
+        );
+        s.resize(2, 4);
+        assert_eq!(s.command_texts(), vec!["$ echo longer\nlonger\n", "$\n"]);
+    }
+}
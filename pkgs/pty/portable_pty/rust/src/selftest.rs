@@ -0,0 +1,220 @@
+//! Measuring the library on the machine it runs on.
+//!
+//! `portable_pty_selftest` times the local PTY paths end to end, through
+//! the same calls an app makes, so a slow session in the field can be
+//! put down to the library or to the device:
+//!
+//! | key               | value                                             |
+//! |-------------------|---------------------------------------------------|
+//! | `spawn_ms`        | opening a PTY and spawning a child, median of 5   |
+//! | `round_trip_ms`   | the same through to seeing the child exit         |
+//! | `read_mib_per_s`  | output read from a child writing flat out         |
+//! | `write_mib_per_s` | input written to a child discarding it            |
+//!
+//! The throughputs move 4 MiB each and are null off Unix. Spawning goes
+//! through the spawn policy and audit hook like any other, so a policy
+//! that refuses the test's commands makes the test fail.
+
+use crate::{PortablePty, PortablePtyBuffer, PortablePtyResult};
+use serde_json::{json, Value};
+use std::ffi::OsString;
+use std::time::{Duration, Instant};
+
+/// Spawns timed for the latencies.
+const SPAWN_RUNS: usize = 5;
+
+/// Bytes moved for each throughput.
+#[cfg(unix)]
+const THROUGHPUT_BYTES: usize = 4 << 20;
+
+/// Closes the handle when dropped.
+struct Session(*mut PortablePty);
+
+impl Session {
+    /// Open a PTY and spawn `argv` in it.
+    fn spawn(argv: &[&str]) -> Result<Session, PortablePtyResult> {
+        let mut handle = std::ptr::null_mut();
+        let opened = crate::portable_pty_open(24, 80, &mut handle);
+        if !matches!(opened, PortablePtyResult::Ok) {
+            return Err(opened);
+        }
+        let session = Session(handle);
+        let args: Vec<OsString> = argv.iter().map(OsString::from).collect();
+        let builder = crate::build_command(args[0].clone(), Some(args), None);
+        match unsafe { &mut *handle }.spawn(builder) {
+            PortablePtyResult::Ok => Ok(session),
+            e => Err(e),
+        }
+    }
+
+    /// One read, as `portable_pty_read`; 0 at the end or on error.
+    #[cfg(unix)]
+    fn read(&self, buf: &mut [u8]) -> usize {
+        crate::portable_pty_read(self.0, buf.as_mut_ptr(), buf.len()).max(0) as usize
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        crate::portable_pty_close(self.0);
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[cfg(unix)]
+fn mib_per_s(bytes: usize, elapsed: Duration) -> f64 {
+    bytes as f64 / (1 << 20) as f64 / elapsed.as_secs_f64().max(1e-6)
+}
+
+fn median(mut samples: Vec<f64>) -> f64 {
+    samples.sort_by(f64::total_cmp);
+    samples[samples.len() / 2]
+}
+
+/// Median time to spawn a child, and to see it exit.
+fn spawn_latency() -> Result<(f64, f64), PortablePtyResult> {
+    let argv: &[&str] = if cfg!(windows) {
+        &["cmd.exe", "/c", "exit"]
+    } else {
+        &["true"]
+    };
+    let (mut spawned, mut exited) = (Vec::new(), Vec::new());
+    for _ in 0..SPAWN_RUNS {
+        let start = Instant::now();
+        let session = Session::spawn(argv)?;
+        spawned.push(millis(start.elapsed()));
+        let mut status = 0;
+        let waited = crate::portable_pty_wait_blocking(session.0, &mut status);
+        if !matches!(waited, PortablePtyResult::Ok) {
+            return Err(waited);
+        }
+        exited.push(millis(start.elapsed()));
+    }
+    Ok((median(spawned), median(exited)))
+}
+
+/// How fast output from a child is read, from its first byte to the end.
+#[cfg(unix)]
+fn read_throughput() -> Result<Value, PortablePtyResult> {
+    let script = format!(
+        "dd if=/dev/zero bs=65536 count={} 2>/dev/null",
+        THROUGHPUT_BYTES / 65536
+    );
+    let session = Session::spawn(&["sh", "-c", &script])?;
+    let mut buf = vec![0u8; 65536];
+    let mut total = session.read(&mut buf);
+    let start = Instant::now();
+    loop {
+        match session.read(&mut buf) {
+            0 => break,
+            n => total += n,
+        }
+    }
+    if total < THROUGHPUT_BYTES {
+        return Err(PortablePtyResult::ErrRead);
+    }
+    Ok(mib_per_s(total, start.elapsed()).into())
+}
+
+/// How fast input is written to a child that reads and discards it.
+#[cfg(unix)]
+fn write_throughput() -> Result<Value, PortablePtyResult> {
+    let script = "stty raw -echo; echo ready; exec cat >/dev/null";
+    let session = Session::spawn(&["sh", "-c", script])?;
+    let mut output = Vec::new();
+    let mut buf = [0u8; 256];
+    while !output.windows(5).any(|w| w == b"ready") {
+        match session.read(&mut buf) {
+            0 => return Err(PortablePtyResult::ErrRead),
+            n => output.extend_from_slice(&buf[..n]),
+        }
+    }
+
+    let chunk = vec![b'x'; 65536];
+    let start = Instant::now();
+    let mut written = 0;
+    while written < THROUGHPUT_BYTES {
+        match crate::portable_pty_write(session.0, chunk.as_ptr(), chunk.len()) {
+            n if n > 0 => written += n as usize,
+            _ => return Err(PortablePtyResult::ErrWrite),
+        }
+    }
+    Ok(mib_per_s(written, start.elapsed()).into())
+}
+
+#[cfg(not(unix))]
+fn read_throughput() -> Result<Value, PortablePtyResult> {
+    Ok(Value::Null)
+}
+
+#[cfg(not(unix))]
+fn write_throughput() -> Result<Value, PortablePtyResult> {
+    Ok(Value::Null)
+}
+
+fn measure() -> Result<Value, PortablePtyResult> {
+    let (spawn_ms, round_trip_ms) = spawn_latency()?;
+    Ok(json!({
+        "spawn_ms": spawn_ms,
+        "round_trip_ms": round_trip_ms,
+        "read_mib_per_s": read_throughput()?,
+        "write_mib_per_s": write_throughput()?,
+    }))
+}
+
+/// Measure spawn latency and throughput on this machine, through the same
+/// calls an app makes. Takes around a second, longer on a slow device.
+///
+/// - `out_json`: receives a UTF-8 JSON object, to free with
+///   `portable_pty_buffer_free`: `spawn_ms`, the median of 5 opens and
+///   spawns; `round_trip_ms`, the same through to seeing the child exit;
+///   and `read_mib_per_s` and `write_mib_per_s`, moving 4 MiB each way,
+///   null off Unix.
+///
+/// Returns `ErrUnsupported` where local processes can't be run, and
+/// otherwise the error of whichever step failed. Spawns go through the
+/// spawn policy and audit hook, so a policy refusing them fails the test.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_selftest(out_json: *mut PortablePtyBuffer) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        if out_json.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+        match measure() {
+            Ok(results) => {
+                let json = results.to_string();
+                unsafe { *out_json = PortablePtyBuffer::from_vec(json.into_bytes()) };
+                PortablePtyResult::Ok
+            }
+            Err(e) => e,
+        }
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_selftest() {
+        let mut out = PortablePtyBuffer::from_vec(Vec::new());
+        let result = portable_pty_selftest(&mut out);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        let bytes = unsafe { std::slice::from_raw_parts(out.data, out.len) };
+        let results: Value = serde_json::from_slice(bytes).unwrap();
+        crate::portable_pty_buffer_free(out);
+
+        for key in [
+            "spawn_ms",
+            "round_trip_ms",
+            "read_mib_per_s",
+            "write_mib_per_s",
+        ] {
+            assert!(results[key].as_f64().unwrap() > 0.0, "{results}");
+        }
+        assert!(results["round_trip_ms"].as_f64() >= results["spawn_ms"].as_f64());
+    }
+}
@@ -0,0 +1,501 @@
+//! Sharing a session over TCP.
+//!
+//! `portable_pty_serve` listens on a port and bridges every client that
+//! connects to the handle: clients see the output the handle reads from
+//! then on, and what they type is written to the child. The embedder keeps
+//! reading as usual — output reaches clients as a side effect of its
+//! reads, as with recording — so the local view and the remote ones stay
+//! in step. A client too slow to keep up is disconnected rather than
+//! holding up reads.
+//!
+//! With `PORTABLE_PTY_SERVE_TELNET` the server speaks telnet: it asks the
+//! client for character-at-a-time, 8-bit input and for window size reports
+//! (NAWS), each of which resizes the handle as `portable_pty_resize` would.
+//! Otherwise bytes pass through unchanged, for `nc` and the like.
+
+use crate::lifecycle::{HandleRef, ThreadGroup};
+use crate::pool::Chunk;
+use crate::{PortablePty, PortablePtyResult};
+use std::ffi::{c_char, CStr};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+/// Speak telnet to clients instead of passing raw bytes.
+pub const PORTABLE_PTY_SERVE_TELNET: u32 = 1 << 0;
+/// Ignore clients' input and window sizes; they only watch.
+pub const PORTABLE_PTY_SERVE_READ_ONLY: u32 = 1 << 1;
+
+/// Output chunks queued for a client before it counts as too slow.
+const CLIENT_QUEUE: usize = 256;
+
+/// How often the listener checks whether it should stop.
+const ACCEPT_POLL: Duration = Duration::from_millis(50);
+
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+const OPT_BINARY: u8 = 0;
+const OPT_ECHO: u8 = 1;
+const OPT_SGA: u8 = 3;
+const OPT_NAWS: u8 = 31;
+
+/// Sent to telnet clients on connect: we echo and don't send go-aheads (so
+/// they send keys as typed), both directions are 8-bit, and please report
+/// the window size.
+const NEGOTIATION: [u8; 18] = [
+    IAC, WILL, OPT_ECHO, IAC, WILL, OPT_SGA, IAC, DO, OPT_SGA, IAC, WILL, OPT_BINARY, IAC, DO,
+    OPT_BINARY, IAC, DO, OPT_NAWS,
+];
+
+/// Longest subnegotiation kept; NAWS needs 5 bytes.
+const MAX_SUBNEGOTIATION: usize = 64;
+
+#[derive(Clone, Copy, Default)]
+enum State {
+    #[default]
+    Data,
+    /// After a CR, which NVT follows with LF or NUL.
+    Cr,
+    Iac,
+    /// After WILL, WONT, DO or DONT, awaiting the option.
+    Option,
+    Sub,
+    SubIac,
+}
+
+/// Strips telnet commands from client input.
+#[derive(Default)]
+struct Telnet {
+    state: State,
+    sub: Vec<u8>,
+}
+
+impl Telnet {
+    /// Append the data in `bytes` to `data`. Returns the last window size
+    /// reported, as (rows, cols).
+    fn feed(&mut self, bytes: &[u8], data: &mut Vec<u8>) -> Option<(u16, u16)> {
+        let mut size = None;
+        for &b in bytes {
+            self.byte(b, data, &mut size);
+        }
+        size
+    }
+
+    fn byte(&mut self, b: u8, data: &mut Vec<u8>, size: &mut Option<(u16, u16)>) {
+        self.state = match (self.state, b) {
+            // The terminal wants Enter as a lone CR.
+            (State::Cr, 0 | b'\n') => State::Data,
+            (State::Cr, _) => {
+                self.state = State::Data;
+                return self.byte(b, data, size);
+            }
+            (State::Data, IAC) => State::Iac,
+            (State::Data, b'\r') => {
+                data.push(b);
+                State::Cr
+            }
+            (State::Data, _) => {
+                data.push(b);
+                State::Data
+            }
+            (State::Iac, IAC) => {
+                data.push(IAC);
+                State::Data
+            }
+            (State::Iac, WILL | WONT | DO | DONT) => State::Option,
+            (State::Iac, SB) => {
+                self.sub.clear();
+                State::Sub
+            }
+            // NOP, go-ahead, are-you-there and the rest.
+            (State::Iac, _) | (State::Option, _) => State::Data,
+            (State::Sub, IAC) => State::SubIac,
+            (State::Sub, _) | (State::SubIac, IAC) => {
+                if self.sub.len() < MAX_SUBNEGOTIATION {
+                    self.sub.push(b);
+                }
+                State::Sub
+            }
+            (State::SubIac, SE) => {
+                if let [OPT_NAWS, w0, w1, h0, h1] = self.sub[..] {
+                    let cols = u16::from_be_bytes([w0, w1]);
+                    let rows = u16::from_be_bytes([h0, h1]);
+                    if rows > 0 && cols > 0 {
+                        *size = Some((rows, cols));
+                    }
+                }
+                State::Data
+            }
+            (State::SubIac, _) => State::Data,
+        };
+    }
+}
+
+/// Double every IAC in output, as telnet requires.
+fn escape(bytes: &[u8], escaped: &mut Vec<u8>) {
+    for &b in bytes {
+        escaped.push(b);
+        if b == IAC {
+            escaped.push(IAC);
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+struct Client {
+    id: u64,
+    stream: TcpStream,
+    output: SyncSender<Arc<Chunk>>,
+}
+
+/// State shared between the handle and the server's threads.
+struct Shared {
+    clients: Mutex<Vec<Client>>,
+    /// Set, with `clients` locked, once the server is stopping.
+    stopping: AtomicBool,
+    next_id: AtomicU64,
+    threads: Arc<ThreadGroup>,
+}
+
+impl Shared {
+    fn remove(&self, id: u64) {
+        lock(&self.clients).retain(|client| {
+            if client.id == id {
+                let _ = client.stream.shutdown(Shutdown::Both);
+            }
+            client.id != id
+        });
+    }
+}
+
+/// A running server, kept on the handle.
+pub(crate) struct Server {
+    shared: Arc<Shared>,
+    telnet: bool,
+}
+
+impl Server {
+    /// Disconnect every client and wait for the server's threads to end.
+    fn stop(self) {
+        {
+            let mut clients = lock(&self.shared.clients);
+            self.shared.stopping.store(true, Ordering::Release);
+            for client in clients.drain(..) {
+                let _ = client.stream.shutdown(Shutdown::Both);
+            }
+        }
+        self.shared.threads.wait();
+    }
+}
+
+/// Accept clients until the server stops.
+fn listen(handle: HandleRef, listener: TcpListener, shared: Arc<Shared>, flags: u32) {
+    while !shared.stopping.load(Ordering::Acquire) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                let _ = connect(handle, stream, &shared, flags);
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => std::thread::sleep(ACCEPT_POLL),
+            Err(_) => {}
+        }
+    }
+}
+
+fn connect(
+    handle: HandleRef,
+    stream: TcpStream,
+    shared: &Arc<Shared>,
+    flags: u32,
+) -> io::Result<()> {
+    // Accepted sockets inherit the listener's non-blocking mode on some
+    // platforms.
+    stream.set_nonblocking(false)?;
+    let _ = stream.set_nodelay(true);
+    let telnet = flags & PORTABLE_PTY_SERVE_TELNET != 0;
+    if telnet {
+        (&stream).write_all(&NEGOTIATION)?;
+    }
+
+    let id = shared.next_id.fetch_add(1, Ordering::Relaxed);
+    let (output, queued) = mpsc::sync_channel(CLIENT_QUEUE);
+    let writer = stream.try_clone()?;
+    let reader = stream.try_clone()?;
+    {
+        let mut clients = lock(&shared.clients);
+        if shared.stopping.load(Ordering::Acquire) {
+            return Ok(());
+        }
+        clients.push(Client { id, stream, output });
+    }
+
+    let threads = &shared.threads;
+    let name = crate::lifecycle::thread_name(handle.get(), "serve");
+    threads.spawn(&name, move || send(writer, queued))?;
+    let shared_ = Arc::clone(shared);
+    let read_only = flags & PORTABLE_PTY_SERVE_READ_ONLY != 0;
+    let result = threads.spawn(&name, move || {
+        receive(handle, reader, telnet, read_only);
+        shared_.remove(id);
+    });
+    if result.is_err() {
+        shared.remove(id);
+    }
+    result
+}
+
+/// Send queued output to a client until it's removed.
+fn send(mut stream: TcpStream, queued: Receiver<Arc<Chunk>>) {
+    for chunk in queued {
+        if stream.write_all(&chunk).is_err() {
+            break;
+        }
+    }
+    let _ = stream.shutdown(Shutdown::Both);
+}
+
+/// Apply a client's input to the handle until it disconnects.
+fn receive(handle: HandleRef, mut stream: TcpStream, telnet: bool, read_only: bool) {
+    let pty = handle.get();
+    let mut parser = Telnet::default();
+    let mut buf = [0u8; 4096];
+    let mut data = Vec::new();
+    loop {
+        let n = match stream.read(&mut buf) {
+            Ok(0) | Err(_) => return,
+            Ok(n) => n,
+        };
+        if read_only {
+            continue;
+        }
+        data.clear();
+        let size = if telnet {
+            parser.feed(&buf[..n], &mut data)
+        } else {
+            data.extend_from_slice(&buf[..n]);
+            None
+        };
+        if let Some((rows, cols)) = size {
+            pty.resize(rows, cols);
+        }
+        if !data.is_empty() && pty.write_input(&data).is_err() {
+            return;
+        }
+    }
+}
+
+/// Pass output the handle has read on to its clients, if it's being served.
+pub(crate) fn broadcast(pty: &PortablePty, bytes: &[u8]) {
+    let server = lock(&pty.server);
+    let Some(server) = server.as_ref() else {
+        return;
+    };
+    let chunk = Chunk::build(bytes.len(), |chunk| {
+        if server.telnet {
+            escape(bytes, chunk);
+        } else {
+            chunk.extend_from_slice(bytes);
+        }
+    });
+    lock(&server.shared.clients).retain(|client| {
+        let sent = client.output.try_send(Arc::clone(&chunk)).is_ok();
+        if !sent {
+            let _ = client.stream.shutdown(Shutdown::Both);
+        }
+        sent
+    });
+}
+
+/// Stop serving the handle, if it is.
+pub(crate) fn stop(pty: &PortablePty) {
+    // Out of the lock: the client threads may be reading through it.
+    let server = lock(&pty.server).take();
+    if let Some(server) = server {
+        server.stop();
+    }
+}
+
+/// Share the session with clients connecting to `addr` over TCP.
+///
+/// - `addr`: null-terminated `host:port` to listen on, such as
+///   `"127.0.0.1:0"`. Port 0 picks a free port.
+/// - `flags`: `PORTABLE_PTY_SERVE_*` flags.
+/// - `out_port`: receives the port listened on; may be NULL.
+///
+/// Clients see output as the handle reads it, so the embedder must keep
+/// reading. There is no authentication: anyone who can reach the address
+/// gets the terminal, so listen on loopback unless that is what's wanted.
+/// Returns `ErrOpen` if the handle is already being served or `addr` can't
+/// be listened on.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_serve(
+    handle: *const PortablePty,
+    addr: *const c_char,
+    flags: u32,
+    out_port: *mut u16,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        if addr.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+        let Ok(addr) = unsafe { CStr::from_ptr(addr) }.to_str() else {
+            return PortablePtyResult::ErrOpen;
+        };
+
+        let mut slot = lock(&pty.server);
+        if slot.is_some() {
+            return PortablePtyResult::ErrOpen;
+        }
+        let listener = match TcpListener::bind(addr) {
+            Ok(l) => l,
+            Err(_) => return PortablePtyResult::ErrOpen,
+        };
+        let port = match listener.local_addr() {
+            Ok(local) => local.port(),
+            Err(_) => return PortablePtyResult::ErrOpen,
+        };
+        if listener.set_nonblocking(true).is_err() {
+            return PortablePtyResult::ErrOpen;
+        }
+
+        let shared = Arc::new(Shared {
+            clients: Mutex::new(Vec::new()),
+            stopping: AtomicBool::new(false),
+            next_id: AtomicU64::new(0),
+            threads: Arc::default(),
+        });
+        let name = crate::lifecycle::thread_name(pty, "serve");
+        let handle = HandleRef::new(pty);
+        let listening = Arc::clone(&shared);
+        if shared
+            .threads
+            .spawn(&name, move || listen(handle, listener, listening, flags))
+            .is_err()
+        {
+            return PortablePtyResult::ErrOpen;
+        }
+        *slot = Some(Server {
+            shared,
+            telnet: flags & PORTABLE_PTY_SERVE_TELNET != 0,
+        });
+
+        if !out_port.is_null() {
+            unsafe {
+                *out_port = port;
+            }
+        }
+        PortablePtyResult::Ok
+    })
+}
+
+/// Stop serving the handle, disconnecting every client. Harmless if it
+/// isn't being served.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_serve_stop(handle: *const PortablePty) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        stop(pty);
+        PortablePtyResult::Ok
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loopback::{
+        portable_pty_loopback_read, portable_pty_loopback_size, portable_pty_loopback_write,
+        portable_pty_open_loopback,
+    };
+
+    #[test]
+    fn test_telnet_strips_commands() {
+        let mut telnet = Telnet::default();
+        let mut data = Vec::new();
+        let size = telnet.feed(b"ls\r\0a\xff\xff\xff\xfb\x01b\r", &mut data);
+        assert_eq!(size, None);
+        // Split across reads: CR LF, then a NAWS report of 100x30.
+        let size = telnet.feed(b"\n\xff\xfa\x1f\x00\x64\x00\x1e\xff\xf0c", &mut data);
+        assert_eq!(size, Some((30, 100)));
+        assert_eq!(data, b"ls\ra\xffb\rc");
+
+        let mut escaped = Vec::new();
+        escape(b"a\xffb", &mut escaped);
+        assert_eq!(escaped, b"a\xff\xffb");
+    }
+
+    fn open_served(flags: u32) -> (*mut PortablePty, TcpStream) {
+        let mut handle = std::ptr::null_mut();
+        let result = portable_pty_open_loopback(24, 80, &mut handle);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        let addr = c"127.0.0.1:0";
+        let mut port = 0;
+        let result = portable_pty_serve(handle, addr.as_ptr(), flags, &mut port);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        let client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        (handle, client)
+    }
+
+    #[test]
+    fn test_bridges_client_to_handle() {
+        let (handle, mut client) = open_served(0);
+        client.write_all(b"hi").unwrap();
+        let mut buf = [0u8; 2];
+        let n = portable_pty_loopback_read(handle, buf.as_mut_ptr(), buf.len());
+        assert_eq!(&buf[..n as usize], b"hi");
+
+        // Output reaches the client once the handle reads it.
+        portable_pty_loopback_write(handle, b"out".as_ptr(), 3);
+        assert_eq!(crate::tests::read_string(handle), "out");
+        let mut buf = [0u8; 3];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"out");
+
+        crate::portable_pty_close(handle);
+        assert_eq!(client.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_telnet_client_resizes() {
+        let (handle, mut client) = open_served(PORTABLE_PTY_SERVE_TELNET);
+        let mut negotiation = [0u8; NEGOTIATION.len()];
+        client.read_exact(&mut negotiation).unwrap();
+        assert_eq!(negotiation, NEGOTIATION);
+
+        client
+            .write_all(b"\xff\xfa\x1f\x00\x64\x00\x1e\xff\xf0x")
+            .unwrap();
+        let mut buf = [0u8; 1];
+        let n = portable_pty_loopback_read(handle, buf.as_mut_ptr(), buf.len());
+        assert_eq!(&buf[..n as usize], b"x");
+        let (mut rows, mut cols) = (0, 0);
+        portable_pty_loopback_size(handle, &mut rows, &mut cols);
+        assert_eq!((rows, cols), (30, 100));
+
+        assert!(matches!(
+            portable_pty_serve_stop(handle),
+            PortablePtyResult::Ok
+        ));
+        assert_eq!(client.read(&mut buf).unwrap(), 0);
+        crate::portable_pty_close(handle);
+    }
+}
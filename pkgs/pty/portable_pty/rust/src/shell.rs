@@ -0,0 +1,94 @@
+//! Spawning the user's shell.
+//!
+//! `portable_pty_spawn_shell` runs the shell a terminal window would,
+//! without the embedder working out which: on Unix `$SHELL` if it can be
+//! run, else the one the password database gives the user, else `/bin/sh`;
+//! on Windows `%ComSpec%`, else `cmd.exe`. A login shell is started as
+//! `login` would start it, with `-` before its name in `argv[0]` (`-bash`),
+//! so it reads the profile; Windows has no such thing.
+//!
+//! The shell inherits this process's environment, but a GUI app rarely has
+//! a `TERM` that fits, so an unset, empty or `dumb` one becomes
+//! `xterm-256color`, what the library's terminal handling speaks.
+
+use crate::pty::CommandBuilder;
+use crate::{PortablePty, PortablePtyResult};
+use std::ffi::OsStr;
+
+/// `TERM` for a shell that hasn't inherited a usable one.
+const DEFAULT_TERM: &str = "xterm-256color";
+
+/// The command running the user's shell, as a login shell if `login`.
+pub(crate) fn command(login: bool) -> CommandBuilder {
+    // portable-pty runs its default program as a login shell.
+    let mut builder = CommandBuilder::new_default_prog();
+    if !login || cfg!(windows) {
+        builder = CommandBuilder::new(builder.get_shell());
+    }
+    let term = builder.get_env("TERM").and_then(OsStr::to_str);
+    if term.is_none_or(|term| term.is_empty() || term == "dumb") {
+        builder.env("TERM", DEFAULT_TERM);
+    }
+    builder
+}
+
+/// Spawn the user's shell attached to the PTY: on Unix `$SHELL` if it can
+/// be run, else the user's shell from the password database, else
+/// `/bin/sh`; on Windows `%ComSpec%`, else `cmd.exe`. It inherits this
+/// process's environment, with an unset, empty or `dumb` `TERM` replaced
+/// by `xterm-256color`.
+///
+/// - `login`: true to start it as a login shell, with `-` before its name
+///   in `argv[0]` so it reads the profile. Ignored on Windows.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_spawn_shell(
+    handle: *mut PortablePty,
+    login: bool,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_mut() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        pty.spawn(command(login))
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_login_and_plain_shells() {
+        let login = command(true);
+        assert!(login.is_default_prog());
+        let plain = command(false);
+        assert_eq!(plain.get_argv().len(), 1);
+        assert_eq!(plain.get_argv()[0], OsStr::new(&login.get_shell()));
+        let term = plain.get_env("TERM").and_then(OsStr::to_str).unwrap();
+        assert!(!term.is_empty() && term != "dumb");
+    }
+
+    #[test]
+    fn test_spawn_login_shell() {
+        let mut handle = std::ptr::null_mut();
+        crate::portable_pty_open(24, 80, &mut handle);
+        assert!(matches!(
+            portable_pty_spawn_shell(handle, true),
+            PortablePtyResult::Ok
+        ));
+        let mut out = crate::PortablePtyBuffer::EMPTY;
+        crate::spawned::portable_pty_spawned_command(handle, &mut out);
+        let bytes = unsafe { std::slice::from_raw_parts(out.data, out.len) };
+        let spawned: serde_json::Value = serde_json::from_slice(bytes).unwrap();
+        crate::portable_pty_buffer_free(out);
+        let program = spawned["program"].as_str().unwrap();
+        let name = program.rsplit('/').next().unwrap();
+        assert_eq!(spawned["argv"], serde_json::json!([format!("-{name}")]));
+        assert_eq!(
+            spawned["env"]["TERM"],
+            command(true).get_env("TERM").unwrap().to_str().unwrap()
+        );
+        crate::portable_pty_close(handle);
+    }
+}
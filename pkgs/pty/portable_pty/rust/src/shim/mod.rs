@@ -0,0 +1,299 @@
+//! The part of portable-pty's API the crate is written against, for builds
+//! without it.
+//!
+//! `portable-pty` doesn't build for WebAssembly, and embedders who care
+//! about the size of the native library in a mobile app bundle can leave it
+//! out by building without the default `portable-pty` feature. Either way
+//! the rest of the crate is written against these stand-ins instead, and
+//! `native_pty_system` opens PTYs itself: with `openpty` on Unix (`unix`),
+//! and with the crate's own ConPTY, or WinPTY where there's none, on
+//! Windows (see [`crate::conpty`]). WebAssembly has no PTYs to open.
+//!
+//! Spawning is the crate's own too, as for a spawn config (see
+//! [`crate::spawn`]), so it finds programs, sets up the environment and
+//! hands the child its terminal as portable-pty would.
+
+// The traits mirror portable-pty's whole, which not every caller uses.
+#![allow(dead_code)]
+
+#[cfg(all(unix, not(target_family = "wasm")))]
+mod unix;
+
+use std::any::Any;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::{OsStr, OsString};
+use std::io::{self, Read, Write};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PtySize {
+    pub rows: u16,
+    pub cols: u16,
+    pub pixel_width: u16,
+    pub pixel_height: u16,
+}
+
+impl Default for PtySize {
+    fn default() -> Self {
+        PtySize {
+            rows: 24,
+            cols: 80,
+            pixel_width: 0,
+            pixel_height: 0,
+        }
+    }
+}
+
+/// The program, arguments and environment of a command, as in
+/// `portable_pty::CommandBuilder`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandBuilder {
+    args: Vec<OsString>,
+    envs: BTreeMap<OsString, OsString>,
+    /// Which of `envs` came from this process.
+    inherited: BTreeSet<OsString>,
+    cwd: Option<OsString>,
+}
+
+impl CommandBuilder {
+    pub fn new<S: AsRef<OsStr>>(program: S) -> Self {
+        let envs: BTreeMap<_, _> = std::env::vars_os().collect();
+        CommandBuilder {
+            args: vec![program.as_ref().to_owned()],
+            inherited: envs.keys().cloned().collect(),
+            envs,
+            cwd: None,
+        }
+    }
+
+    /// The user's shell, run as a login shell.
+    pub fn new_default_prog() -> Self {
+        let mut builder = CommandBuilder::new("");
+        builder.args.clear();
+        builder
+    }
+
+    pub fn from_argv(args: Vec<OsString>) -> Self {
+        let mut builder = CommandBuilder::new("");
+        builder.args = args;
+        builder
+    }
+
+    pub fn arg<S: AsRef<OsStr>>(&mut self, arg: S) {
+        self.args.push(arg.as_ref().to_owned());
+    }
+
+    pub fn args<I, S>(&mut self, args: I)
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        for arg in args {
+            self.arg(arg);
+        }
+    }
+
+    pub fn get_argv(&self) -> &Vec<OsString> {
+        &self.args
+    }
+
+    pub fn get_argv_mut(&mut self) -> &mut Vec<OsString> {
+        &mut self.args
+    }
+
+    pub fn env<K, V>(&mut self, key: K, value: V)
+    where
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        self.inherited.remove(key.as_ref());
+        self.envs
+            .insert(key.as_ref().to_owned(), value.as_ref().to_owned());
+    }
+
+    pub fn env_remove<K: AsRef<OsStr>>(&mut self, key: K) {
+        self.inherited.remove(key.as_ref());
+        self.envs.remove(key.as_ref());
+    }
+
+    pub fn env_clear(&mut self) {
+        self.inherited.clear();
+        self.envs.clear();
+    }
+
+    pub fn get_env<K: AsRef<OsStr>>(&self, key: K) -> Option<&OsStr> {
+        self.envs.get(key.as_ref()).map(OsString::as_os_str)
+    }
+
+    pub fn cwd<D: AsRef<OsStr>>(&mut self, dir: D) {
+        self.cwd = Some(dir.as_ref().to_owned());
+    }
+
+    pub fn get_cwd(&self) -> Option<&OsString> {
+        self.cwd.as_ref()
+    }
+
+    pub fn is_default_prog(&self) -> bool {
+        self.args.is_empty()
+    }
+
+    pub fn get_controlling_tty(&self) -> bool {
+        true
+    }
+
+    /// `$SHELL` if it can be run, else the password database's, else
+    /// `/bin/sh`.
+    #[cfg(unix)]
+    pub fn get_shell(&self) -> String {
+        use std::ffi::CStr;
+
+        let executable = |shell: &str| {
+            let shell = std::ffi::CString::new(shell).unwrap_or_default();
+            unsafe { libc::access(shell.as_ptr(), libc::X_OK) == 0 }
+        };
+        if let Some(shell) = self.get_env("SHELL").and_then(OsStr::to_str) {
+            if executable(shell) {
+                return shell.into();
+            }
+        }
+        let entry = unsafe { libc::getpwuid(libc::getuid()) };
+        if !entry.is_null() {
+            let shell = unsafe { CStr::from_ptr((*entry).pw_shell) };
+            if let Some(shell) = shell.to_str().ok().filter(|shell| executable(shell)) {
+                return shell.into();
+            }
+        }
+        "/bin/sh".into()
+    }
+
+    /// `%ComSpec%`, else `cmd.exe`.
+    #[cfg(not(unix))]
+    pub fn get_shell(&self) -> String {
+        self.get_env("ComSpec")
+            .and_then(OsStr::to_str)
+            .unwrap_or("cmd.exe")
+            .into()
+    }
+
+    /// Every variable, UTF-8 or not; portable-pty has no equivalent.
+    pub fn iter_full_env(&self) -> impl Iterator<Item = (&OsStr, &OsStr)> {
+        self.envs
+            .iter()
+            .map(|(key, value)| (key.as_os_str(), value.as_os_str()))
+    }
+
+    pub fn iter_full_env_as_str(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.envs
+            .iter()
+            .filter_map(|(key, value)| Some((key.to_str()?, value.to_str()?)))
+    }
+
+    pub fn iter_extra_env_as_str(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.iter_full_env_as_str()
+            .filter(|(key, _)| !self.inherited.contains(OsStr::new(key)))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ExitStatus {
+    code: u32,
+}
+
+impl ExitStatus {
+    pub fn with_exit_code(code: u32) -> Self {
+        ExitStatus { code }
+    }
+
+    pub fn exit_code(&self) -> u32 {
+        self.code
+    }
+
+    pub fn success(&self) -> bool {
+        self.code == 0
+    }
+}
+
+/// What `downcast-rs` gives the `portable-pty` traits: a way back to the
+/// concrete backend type.
+pub trait AsAny: Any {
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<T: Any> AsAny for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+pub trait MasterPty: AsAny + Send {
+    fn resize(&self, size: PtySize) -> anyhow::Result<()>;
+    fn get_size(&self) -> anyhow::Result<PtySize>;
+    fn try_clone_reader(&self) -> anyhow::Result<Box<dyn Read + Send>>;
+    fn take_writer(&self) -> anyhow::Result<Box<dyn Write + Send>>;
+    #[cfg(unix)]
+    fn process_group_leader(&self) -> Option<libc::pid_t>;
+    #[cfg(unix)]
+    fn as_raw_fd(&self) -> Option<std::os::fd::RawFd>;
+    #[cfg(unix)]
+    fn tty_name(&self) -> Option<std::path::PathBuf>;
+}
+
+pub trait SlavePty {
+    fn spawn_command(&self, cmd: CommandBuilder) -> anyhow::Result<Box<dyn Child + Send + Sync>>;
+}
+
+pub trait ChildKiller: std::fmt::Debug + AsAny + Send {
+    fn kill(&mut self) -> io::Result<()>;
+    fn clone_killer(&self) -> Box<dyn ChildKiller + Send + Sync>;
+}
+
+pub trait Child: std::fmt::Debug + ChildKiller + AsAny + Send {
+    fn try_wait(&mut self) -> io::Result<Option<ExitStatus>>;
+    fn wait(&mut self) -> io::Result<ExitStatus>;
+    fn process_id(&self) -> Option<u32>;
+    #[cfg(windows)]
+    fn as_raw_handle(&self) -> Option<std::os::windows::io::RawHandle>;
+}
+
+pub struct PtyPair {
+    pub slave: Box<dyn SlavePty + Send>,
+    pub master: Box<dyn MasterPty + Send>,
+}
+
+pub trait PtySystem {
+    fn openpty(&self, size: PtySize) -> anyhow::Result<PtyPair>;
+}
+
+/// A PTY system that has no PTYs to open.
+#[cfg(not(any(all(unix, not(target_family = "wasm")), windows)))]
+struct NoPtySystem;
+
+#[cfg(not(any(all(unix, not(target_family = "wasm")), windows)))]
+impl PtySystem for NoPtySystem {
+    fn openpty(&self, _size: PtySize) -> anyhow::Result<PtyPair> {
+        anyhow::bail!("no PTYs on this platform")
+    }
+}
+
+#[cfg(not(any(all(unix, not(target_family = "wasm")), windows)))]
+pub fn native_pty_system() -> Box<dyn PtySystem + Send> {
+    Box::new(NoPtySystem)
+}
+
+#[cfg(all(unix, not(target_family = "wasm")))]
+pub use unix::native_pty_system;
+
+/// ConPTY with the flags `portable_pty_open` uses, or WinPTY without it.
+#[cfg(windows)]
+struct ConsolePtySystem;
+
+#[cfg(windows)]
+impl PtySystem for ConsolePtySystem {
+    fn openpty(&self, size: PtySize) -> anyhow::Result<PtyPair> {
+        Ok(crate::conpty::openpty(size)?)
+    }
+}
+
+#[cfg(windows)]
+pub fn native_pty_system() -> Box<dyn PtySystem + Send> {
+    Box::new(ConsolePtySystem)
+}
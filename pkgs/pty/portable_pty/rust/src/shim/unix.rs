@@ -0,0 +1,211 @@
+//! PTYs from `openpty(3)`, and children as `std::process::Child`.
+
+use super::{
+    Child, ChildKiller, CommandBuilder, ExitStatus, MasterPty, PtyPair, PtySize, PtySystem,
+    SlavePty,
+};
+use std::ffi::{CStr, OsStr};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+fn winsize(size: PtySize) -> libc::winsize {
+    libc::winsize {
+        ws_row: size.rows,
+        ws_col: size.cols,
+        ws_xpixel: size.pixel_width,
+        ws_ypixel: size.pixel_height,
+    }
+}
+
+fn cloexec(fd: RawFd) -> io::Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if flags == -1 || unsafe { libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// The path of the terminal open on `fd`.
+fn tty_name(fd: RawFd) -> Option<PathBuf> {
+    let mut name = [0 as libc::c_char; 128];
+    if unsafe { libc::ttyname_r(fd, name.as_mut_ptr(), name.len()) } != 0 {
+        return None;
+    }
+    let name = unsafe { CStr::from_ptr(name.as_ptr()) };
+    Some(OsStr::from_bytes(name.to_bytes()).into())
+}
+
+struct UnixPtySystem;
+
+impl PtySystem for UnixPtySystem {
+    fn openpty(&self, size: PtySize) -> anyhow::Result<PtyPair> {
+        let (mut master, mut slave) = (-1, -1);
+        let size = winsize(size);
+        let opened = unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                &size as *const _ as *mut _,
+            )
+        };
+        if opened != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let master = unsafe { OwnedFd::from_raw_fd(master) };
+        let slave = unsafe { OwnedFd::from_raw_fd(slave) };
+        cloexec(master.as_raw_fd())?;
+        cloexec(slave.as_raw_fd())?;
+        let tty = tty_name(slave.as_raw_fd()).ok_or_else(io::Error::last_os_error)?;
+        Ok(PtyPair {
+            master: Box::new(UnixMaster {
+                fd: master,
+                tty: tty.clone(),
+            }),
+            slave: Box::new(UnixSlave { _fd: slave, tty }),
+        })
+    }
+}
+
+pub fn native_pty_system() -> Box<dyn PtySystem + Send> {
+    Box::new(UnixPtySystem)
+}
+
+struct UnixMaster {
+    fd: OwnedFd,
+    tty: PathBuf,
+}
+
+impl UnixMaster {
+    fn file(&self) -> io::Result<File> {
+        Ok(File::from(self.fd.try_clone()?))
+    }
+}
+
+impl MasterPty for UnixMaster {
+    fn resize(&self, size: PtySize) -> anyhow::Result<()> {
+        let size = winsize(size);
+        if unsafe { libc::ioctl(self.fd.as_raw_fd(), libc::TIOCSWINSZ as _, &size) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+
+    fn get_size(&self) -> anyhow::Result<PtySize> {
+        let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+        if unsafe { libc::ioctl(self.fd.as_raw_fd(), libc::TIOCGWINSZ as _, &mut size) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(PtySize {
+            rows: size.ws_row,
+            cols: size.ws_col,
+            pixel_width: size.ws_xpixel,
+            pixel_height: size.ws_ypixel,
+        })
+    }
+
+    fn try_clone_reader(&self) -> anyhow::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(self.file()?))
+    }
+
+    fn take_writer(&self) -> anyhow::Result<Box<dyn Write + Send>> {
+        Ok(Box::new(self.file()?))
+    }
+
+    fn process_group_leader(&self) -> Option<libc::pid_t> {
+        match unsafe { libc::tcgetpgrp(self.fd.as_raw_fd()) } {
+            pid if pid > 0 => Some(pid),
+            _ => None,
+        }
+    }
+
+    fn as_raw_fd(&self) -> Option<RawFd> {
+        Some(self.fd.as_raw_fd())
+    }
+
+    fn tty_name(&self) -> Option<PathBuf> {
+        Some(self.tty.clone())
+    }
+}
+
+struct UnixSlave {
+    /// Held open so output the child leaves behind outlives it.
+    _fd: OwnedFd,
+    tty: PathBuf,
+}
+
+impl SlavePty for UnixSlave {
+    fn spawn_command(&self, cmd: CommandBuilder) -> anyhow::Result<Box<dyn Child + Send + Sync>> {
+        Ok(Box::new(crate::spawn::spawn_on(&self.tty, &cmd)?))
+    }
+}
+
+impl From<std::process::ExitStatus> for ExitStatus {
+    fn from(status: std::process::ExitStatus) -> Self {
+        // A signal counts as failure, as in portable-pty.
+        ExitStatus::with_exit_code(status.code().map_or(1, |code| code as u32))
+    }
+}
+
+/// Hangs up on a child, for `clone_killer`.
+#[derive(Debug)]
+struct ProcessSignaller {
+    pid: libc::pid_t,
+}
+
+impl ChildKiller for ProcessSignaller {
+    fn kill(&mut self) -> io::Result<()> {
+        if unsafe { libc::kill(self.pid, libc::SIGHUP) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn clone_killer(&self) -> Box<dyn ChildKiller + Send + Sync> {
+        Box::new(ProcessSignaller { pid: self.pid })
+    }
+}
+
+impl ChildKiller for std::process::Child {
+    /// Hang up on the child, then kill it if it hasn't gone within a
+    /// quarter of a second, as portable-pty does.
+    fn kill(&mut self) -> io::Result<()> {
+        if unsafe { libc::kill(self.id() as libc::pid_t, libc::SIGHUP) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        for attempt in 0..5 {
+            if attempt > 0 {
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            if let Ok(Some(_)) = std::process::Child::try_wait(self) {
+                return Ok(());
+            }
+        }
+        std::process::Child::kill(self)
+    }
+
+    fn clone_killer(&self) -> Box<dyn ChildKiller + Send + Sync> {
+        Box::new(ProcessSignaller {
+            pid: self.id() as libc::pid_t,
+        })
+    }
+}
+
+impl Child for std::process::Child {
+    fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        std::process::Child::try_wait(self).map(|status| status.map(ExitStatus::from))
+    }
+
+    fn wait(&mut self) -> io::Result<ExitStatus> {
+        std::process::Child::wait(self).map(ExitStatus::from)
+    }
+
+    fn process_id(&self) -> Option<u32> {
+        Some(self.id())
+    }
+}
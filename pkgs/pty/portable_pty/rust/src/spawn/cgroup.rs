@@ -0,0 +1,38 @@
+//! The cgroup to start the child in.
+//!
+//! The child writes itself into the cgroup's `cgroup.procs` before `exec`,
+//! so the cgroup's limits hold from its first instruction, and everything
+//! it starts is born in there too: a memory or CPU limit set on the cgroup
+//! holds for the whole session. The cgroup has to be there already, set
+//! up as the embedder wants it. The kernel refuses a cgroup v2 group with
+//! controllers enabled for its children, as processes may only be in its
+//! leaves, and a writer without permission on the file; either way the
+//! spawn fails.
+
+use std::ffi::CString;
+use std::io;
+use std::path::Path;
+
+/// The `cgroup.procs` of the cgroup to start in.
+#[derive(Clone)]
+pub(super) struct Cgroup(CString);
+
+impl Cgroup {
+    /// The cgroup at directory `path`. `NotFound` if there isn't one.
+    pub(super) fn new(path: &str) -> io::Result<Self> {
+        let procs = format!("{}/cgroup.procs", path.trim_end_matches('/'));
+        if !Path::new(&procs).is_file() {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        Ok(Cgroup(
+            CString::new(procs).map_err(|_| io::ErrorKind::InvalidInput)?,
+        ))
+    }
+
+    /// Move the calling process into the cgroup. Only async-signal-safe
+    /// calls: this runs in the forked child.
+    pub(super) fn join(&self) -> io::Result<()> {
+        // 0 is whoever writes it.
+        super::lsm::write_attr(&self.0, c"0")
+    }
+}
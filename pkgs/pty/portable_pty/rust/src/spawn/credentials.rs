@@ -0,0 +1,117 @@
+//! The user and groups to run the child as.
+//!
+//! A daemon running as root drops to the user a session is for in the
+//! child, as `login` does: supplementary groups first, then the group,
+//! then the user, since once it's no longer root it can't change the
+//! others. If the kernel refuses any of them, the spawn fails rather than
+//! running the child with what was left. A user given without a group gets
+//! their login group from the password database, looked up in the parent,
+//! rather than keeping root's; a user with no entry there fails the spawn.
+
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+/// IDs to switch the child to; those not given stay as they are.
+#[derive(Clone, Default)]
+pub(super) struct Credentials {
+    uid: Option<libc::uid_t>,
+    gid: Option<libc::gid_t>,
+    groups: Option<Vec<libc::gid_t>>,
+}
+
+/// The login group of `uid`. `NotFound` if it has no password entry.
+fn login_group(uid: libc::uid_t) -> io::Result<libc::gid_t> {
+    let mut buf = vec![0 as libc::c_char; 1024];
+    loop {
+        let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut found = std::ptr::null_mut();
+        let error =
+            unsafe { libc::getpwuid_r(uid, &mut entry, buf.as_mut_ptr(), buf.len(), &mut found) };
+        match error {
+            0 if found.is_null() => return Err(io::ErrorKind::NotFound.into()),
+            0 => return Ok(entry.pw_gid),
+            libc::ERANGE if buf.len() < 1 << 20 => buf.resize(buf.len() * 2, 0),
+            _ => return Err(io::Error::from_raw_os_error(error)),
+        }
+    }
+}
+
+impl Credentials {
+    /// The IDs to switch to, with `uid`'s login group for a missing `gid`.
+    pub(super) fn new(
+        uid: Option<u32>,
+        gid: Option<u32>,
+        groups: Option<Vec<u32>>,
+    ) -> io::Result<Self> {
+        let gid = match (uid, gid) {
+            (Some(uid), None) => Some(login_group(uid)?),
+            _ => gid,
+        };
+        Ok(Credentials { uid, gid, groups })
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.uid.is_none() && self.gid.is_none() && self.groups.is_none()
+    }
+
+    /// Give the terminal at `tty` to the user the child runs as, so it can
+    /// reopen it by name as its own. Runs in the parent. It's given back
+    /// when the result is dropped, unless the spawn `kept` it.
+    pub(super) fn hand_over<'a>(&self, tty: &'a Path) -> io::Result<HandOver<'a>> {
+        let Some(uid) = self.uid else {
+            return Ok(HandOver { tty, owner: None });
+        };
+        let metadata = std::fs::metadata(tty)?;
+        std::os::unix::fs::chown(tty, Some(uid), self.gid)?;
+        Ok(HandOver {
+            tty,
+            owner: Some((metadata.uid(), metadata.gid())),
+        })
+    }
+
+    /// Switch to the IDs. Only async-signal-safe calls: this runs in the
+    /// forked child.
+    pub(super) fn apply(&self) -> io::Result<()> {
+        let check = |result: libc::c_int| match result {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        };
+        match &self.groups {
+            Some(groups) => check(unsafe { libc::setgroups(groups.len() as _, groups.as_ptr()) })?,
+            // Leaving root without naming groups keeps none of root's.
+            None if self.uid.is_some() && unsafe { libc::getuid() } == 0 => {
+                check(unsafe { libc::setgroups(0, std::ptr::null()) })?
+            }
+            None => {}
+        }
+        if let Some(gid) = self.gid {
+            check(unsafe { libc::setgid(gid) })?;
+        }
+        if let Some(uid) = self.uid {
+            check(unsafe { libc::setuid(uid) })?;
+        }
+        Ok(())
+    }
+}
+
+/// A terminal handed over, and who owned it before.
+pub(super) struct HandOver<'a> {
+    tty: &'a Path,
+    owner: Option<(libc::uid_t, libc::gid_t)>,
+}
+
+impl HandOver<'_> {
+    /// Leave the terminal with the child: it was spawned.
+    pub(super) fn keep(mut self) {
+        self.owner = None;
+    }
+}
+
+impl Drop for HandOver<'_> {
+    fn drop(&mut self) {
+        if let Some((uid, gid)) = self.owner {
+            let _ = std::os::unix::fs::chown(self.tty, Some(uid), Some(gid));
+        }
+    }
+}
@@ -0,0 +1,136 @@
+//! Landlock rulesets confining what of the filesystem children may touch.
+//!
+//! The ruleset is built in the parent — building one restricts nothing —
+//! and the child only enforces it before `exec`, which takes two system
+//! calls and no allocation. Everything the kernel's Landlock version can
+//! govern is denied outside the paths given: those under `read` may be
+//! read and executed, those under `write` anything else as well. Files the
+//! child already has open, like its terminal, stay usable.
+
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::Arc;
+
+const CREATE_RULESET_VERSION: u32 = 1 << 0;
+const RULE_PATH_BENEATH: libc::c_int = 1;
+
+const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+/// Removing, making and linking entries: rights on directories alone.
+const ACCESS_FS_DIR_V1: u64 = 0x1ff0;
+const ACCESS_FS_REFER: u64 = 1 << 13;
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+
+const ACCESS_READ: u64 = ACCESS_FS_EXECUTE | ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+/// What can be granted on a file rather than a directory.
+const ACCESS_FILE: u64 =
+    ACCESS_FS_EXECUTE | ACCESS_FS_WRITE_FILE | ACCESS_FS_READ_FILE | ACCESS_FS_TRUNCATE;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+/// The rights this kernel's Landlock governs. `Unsupported` if it has none.
+fn handled_access() -> io::Result<u64> {
+    let version = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<RulesetAttr>(),
+            0usize,
+            CREATE_RULESET_VERSION,
+        )
+    };
+    let v1 = ACCESS_READ | ACCESS_FS_WRITE_FILE | ACCESS_FS_DIR_V1;
+    match version {
+        ..=0 => Err(io::ErrorKind::Unsupported.into()),
+        1 => Ok(v1),
+        2 => Ok(v1 | ACCESS_FS_REFER),
+        _ => Ok(v1 | ACCESS_FS_REFER | ACCESS_FS_TRUNCATE),
+    }
+}
+
+/// A ruleset ready to enforce in the child.
+#[derive(Clone)]
+pub(super) struct Ruleset(Arc<OwnedFd>);
+
+impl Ruleset {
+    /// Build a ruleset allowing `read` and `write` paths. `Unsupported` if
+    /// the kernel lacks Landlock; any other error if a path can't be
+    /// opened.
+    pub(super) fn new(read: &[&str], write: &[&str]) -> io::Result<Self> {
+        let handled = handled_access()?;
+        let attr = RulesetAttr {
+            handled_access_fs: handled,
+        };
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr,
+                size_of::<RulesetAttr>(),
+                0u32,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let ruleset = unsafe { OwnedFd::from_raw_fd(fd as libc::c_int) };
+
+        let rules = read.iter().map(|p| (p, ACCESS_READ & handled));
+        for (path, access) in rules.chain(write.iter().map(|p| (p, handled))) {
+            add_rule(&ruleset, path, access)?;
+        }
+        Ok(Ruleset(Arc::new(ruleset)))
+    }
+
+    /// Confine this process to the ruleset. Only async-signal-safe calls:
+    /// this runs in the forked child.
+    pub(super) fn enforce(&self) -> io::Result<()> {
+        // Landlock won't confine a process that could regain privileges.
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = self.0.as_raw_fd();
+        if unsafe { libc::syscall(libc::SYS_landlock_restrict_self, fd, 0u32) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+fn add_rule(ruleset: &OwnedFd, path: &str, access: u64) -> io::Result<()> {
+    let c_path = CString::new(path).map_err(|_| io::ErrorKind::InvalidInput)?;
+    let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let file = unsafe { File::from_raw_fd(fd) };
+    let is_dir = file.metadata()?.is_dir();
+    let attr = PathBeneathAttr {
+        allowed_access: if is_dir { access } else { access & ACCESS_FILE },
+        parent_fd: file.as_raw_fd(),
+    };
+    let added = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_add_rule,
+            ruleset.as_raw_fd(),
+            RULE_PATH_BENEATH,
+            &attr,
+            0u32,
+        )
+    };
+    if added != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,69 @@
+//! SELinux contexts and AppArmor profiles to exec children under.
+//!
+//! Both are requests to the kernel for the next `exec` of the calling
+//! thread, written to its `attr` files the way `setexeccon(3)` and
+//! `aa_change_onexec(2)` do. The policy decides whether the transition is
+//! allowed; if it isn't, or the module isn't enabled, the write fails and
+//! so does the spawn.
+
+use std::ffi::{CStr, CString};
+use std::io;
+
+/// Labels to exec the child under, ready to write without allocating.
+#[derive(Clone, Default)]
+pub(super) struct Labels {
+    selinux: Option<CString>,
+    /// `exec <profile>`.
+    apparmor: Option<CString>,
+}
+
+impl Labels {
+    pub(super) fn new(selinux: Option<&str>, apparmor: Option<&str>) -> io::Result<Self> {
+        let c_string = |s: String| CString::new(s).map_err(|_| io::ErrorKind::InvalidInput);
+        Ok(Labels {
+            selinux: selinux.map(|c| c_string(c.to_owned())).transpose()?,
+            apparmor: apparmor
+                .map(|p| c_string(format!("exec {p}")))
+                .transpose()?,
+        })
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.selinux.is_none() && self.apparmor.is_none()
+    }
+
+    /// Ask for the labels on the coming `exec`. Only async-signal-safe
+    /// calls: this runs in the forked child.
+    pub(super) fn apply(&self) -> io::Result<()> {
+        if let Some(context) = &self.selinux {
+            write_attr(c"/proc/thread-self/attr/exec", context)?;
+        }
+        if let Some(request) = &self.apparmor {
+            // The stacking-aware interface, then the one before Linux 5.8.
+            match write_attr(c"/proc/thread-self/attr/apparmor/exec", request) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    write_attr(c"/proc/thread-self/attr/exec", request)?;
+                }
+                result => result?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Write `value` to the file at `path` in one go. Async-signal-safe.
+pub(super) fn write_attr(path: &CStr, value: &CStr) -> io::Result<()> {
+    let fd = unsafe { libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let bytes = value.to_bytes();
+    let written = unsafe { libc::write(fd, bytes.as_ptr().cast(), bytes.len()) };
+    let error = io::Error::last_os_error();
+    unsafe { libc::close(fd) };
+    match written {
+        n if n == bytes.len() as isize => Ok(()),
+        n if n < 0 => Err(error),
+        _ => Err(io::ErrorKind::WriteZero.into()),
+    }
+}
@@ -0,0 +1,1056 @@
+//! Spawning with a config.
+//!
+//! `portable_pty_spawn_config` is `portable_pty_spawn` with a JSON object
+//! of options on top, for where the child starts, what it inherits and
+//! confining it:
+//!
+//! | key                  | value                                          |
+//! |----------------------|------------------------------------------------|
+//! | `cwd`                | directory to start it in                       |
+//! | `inherit_env`        | `true` to lay `envp` over what it inherits     |
+//! | `unset_env`          | names of variables to leave out                |
+//! | `argv0`              | `argv[0]` to run the program under (Unix)      |
+//! | `uid`                | user ID to run it as (Unix)                    |
+//! | `gid`                | group ID to run it as (Unix)                   |
+//! | `groups`             | supplementary group IDs to give it (Unix)      |
+//! | `selinux_context`    | SELinux context to exec the child in (Linux)   |
+//! | `apparmor_profile`   | AppArmor profile to exec it under (Linux)      |
+//! | `sandbox_profile`    | SBPL sandbox profile to run it in (macOS)      |
+//! | `sandbox_parameters` | object of strings for the profile's `param`s   |
+//! | `cgroup`             | cgroup directory to start it in (Linux)        |
+//! | `namespaces`         | namespaces to give it its own of (Linux)       |
+//! | `landlock_read`      | paths it may only read and execute (Linux)     |
+//! | `landlock_write`     | paths it may also change (Linux)               |
+//! | `seccomp_deny`       | `true`, or system calls, to deny it (Linux)    |
+//! | `no_new_privs`       | `true` to bar it gaining privileges (Linux)    |
+//! | `no_core_dumps`      | `true` to keep its crashes from dumping core   |
+//! | `scrub_env`          | `true`, or name patterns, to drop secrets      |
+//!
+//! Giving either Landlock list confines the child to the paths in both;
+//! see `landlock`. Kernels without Landlock get `ErrUnsupported`. Landlock
+//! implies `no_new_privs`, which makes `exec` ignore setuid and setgid bits
+//! and file capabilities for the child and everything it starts.
+//! `no_core_dumps` sets `RLIMIT_CORE` to zero on Unix; on Windows it turns
+//! off the crash dialog and Windows Error Reporting for the child instead.
+//!
+//! `seccomp_deny` installs a filter failing the calls it names with
+//! `EPERM`, for the child and all it starts; see `seccomp`. With `true`
+//! that's calls for administering the machine rather than using it (see
+//! `seccomp::DEFAULT_DENIED`); with an array, names from
+//! `seccomp::SYSCALLS` or this architecture's call numbers. It implies
+//! `no_new_privs` too, and the filter goes on last, so denying `execve`
+//! denies the child itself. Denying `unshare` also denies `clone` making
+//! namespaces, and fails `clone3` with `ENOSYS`.
+//!
+//! `namespaces` names any of `pid`, `mount`, `network`, `ipc` and `uts`
+//! for the child to start in new ones of; see `namespaces`. It's a light
+//! container, with no runtime to set up. In a new PID namespace the child
+//! is init: when it exits, everything left in there is killed, and
+//! signals it has no handler for are ignored, bar `SIGKILL` and `SIGSTOP`
+//! from outside.
+//!
+//! `cgroup` is the path of a cgroup directory, such as one under
+//! `/sys/fs/cgroup`, for the child and everything it starts to run in, so
+//! the cgroup's memory and CPU limits apply to the whole session; see
+//! `cgroup`.
+//!
+//! `scrub_env` drops variables the child would inherit from this process
+//! if their names match: with `true`, tokens, passwords, cloud credentials
+//! and agent sockets (see `scrub::DEFAULT_PATTERNS`); with an array, names
+//! like `"*_TOKEN"` or `"AWS_*"`. It works anywhere, as does `cwd`, which
+//! has to name a directory there is.
+//!
+//! An `envp` replaces the environment the child would inherit. With
+//! `inherit_env` it's laid over it instead: the child gets this process's
+//! variables, bar those `scrub_env` drops, with `envp`'s added or taking
+//! their place. `unset_env` then leaves variables out by name, however the
+//! child would have got them. Both work anywhere too.
+//!
+//! `argv0` is what the program is told its name is, `argv[0]`, in place
+//! of the `cmd` it's run from: `-bash` for a login shell, or the applet a
+//! busybox-style binary should act as.
+//!
+//! `uid`, `gid` and `groups` let a daemon running as root start a session
+//! as someone else; see `credentials`. With `uid` the terminal is handed
+//! to that user too. Without `gid` the child gets the user's login group,
+//! and the spawn fails for a user with no password entry to take it from;
+//! without `groups` a child leaving root keeps none of its supplementary
+//! groups. If the spawn fails the terminal is given back. The environment,
+//! `HOME` and all, is still whatever the spawn gives it, and `cwd` is
+//! entered before the switch.
+//!
+//! Every option is off unless given. Most take effect in the child between
+//! `fork` and `exec`, which portable-pty has no room for, and it can't set
+//! `argv0` either, so a config with any of those is spawned by this crate
+//! (see `unix`); that needs a local PTY, and on other handles the spawn
+//! returns `ErrUnsupported`, as it does for options the platform lacks. If
+//! a step fails in the child — the policy refuses a label, say — the spawn
+//! returns `ErrSpawn` and nothing runs.
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod cgroup;
+#[cfg(unix)]
+mod credentials;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod landlock;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod lsm;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod namespaces;
+#[cfg(target_os = "macos")]
+mod sandbox;
+mod scrub;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod seccomp;
+#[cfg(unix)]
+mod unix;
+#[cfg(windows)]
+mod windows;
+
+use crate::pty::{Child, CommandBuilder, MasterPty};
+use crate::{PortablePty, PortablePtyResult};
+use serde_json::Value;
+use std::ffi::{c_char, CStr};
+
+/// The options of a spawn config, prepared for the child.
+#[derive(Clone, Default)]
+pub(crate) struct SpawnConfig {
+    cwd: Option<String>,
+    inherit_env: bool,
+    unset_env: Vec<String>,
+    #[cfg(unix)]
+    argv0: Option<String>,
+    #[cfg(unix)]
+    credentials: credentials::Credentials,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    labels: lsm::Labels,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    cgroup: Option<cgroup::Cgroup>,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    namespaces: namespaces::Namespaces,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    landlock: Option<landlock::Ruleset>,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    seccomp: Option<seccomp::Filter>,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    no_new_privs: bool,
+    no_core_dumps: bool,
+    scrub: scrub::Scrub,
+    #[cfg(target_os = "macos")]
+    sandbox: Option<sandbox::Profile>,
+}
+
+/// The string under `key`, if present. `ErrSpawn` if it isn't a string.
+fn string<'a>(config: &'a Value, key: &str) -> Result<Option<&'a str>, PortablePtyResult> {
+    match config.get(key) {
+        None => Ok(None),
+        Some(value) => value.as_str().map(Some).ok_or(PortablePtyResult::ErrSpawn),
+    }
+}
+
+/// The boolean under `key`, false if absent. `ErrSpawn` if it isn't a
+/// boolean.
+fn flag(config: &Value, key: &str) -> Result<bool, PortablePtyResult> {
+    match config.get(key) {
+        None => Ok(false),
+        Some(value) => value.as_bool().ok_or(PortablePtyResult::ErrSpawn),
+    }
+}
+
+/// The array of strings under `key`, if present. `ErrSpawn` if it's
+/// anything else.
+fn string_list<'a>(
+    config: &'a Value,
+    key: &str,
+) -> Result<Option<Vec<&'a str>>, PortablePtyResult> {
+    let Some(value) = config.get(key) else {
+        return Ok(None);
+    };
+    let array = value.as_array().ok_or(PortablePtyResult::ErrSpawn)?;
+    array
+        .iter()
+        .map(|value| value.as_str().ok_or(PortablePtyResult::ErrSpawn))
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+/// `value` as a user or group ID, or a system call number. `ErrSpawn` if
+/// it isn't one.
+fn as_u32(value: &Value) -> Result<u32, PortablePtyResult> {
+    value
+        .as_u64()
+        .and_then(|id| u32::try_from(id).ok())
+        .ok_or(PortablePtyResult::ErrSpawn)
+}
+
+/// The user or group ID under `key`, if present. `ErrSpawn` if it isn't
+/// one.
+fn id(config: &Value, key: &str) -> Result<Option<u32>, PortablePtyResult> {
+    config.get(key).map(as_u32).transpose()
+}
+
+/// The array of IDs under `key`, if present. `ErrSpawn` if it's anything
+/// else.
+fn id_list(config: &Value, key: &str) -> Result<Option<Vec<u32>>, PortablePtyResult> {
+    let Some(value) = config.get(key) else {
+        return Ok(None);
+    };
+    let array = value.as_array().ok_or(PortablePtyResult::ErrSpawn)?;
+    array.iter().map(as_u32).collect::<Result<_, _>>().map(Some)
+}
+
+/// The system calls in the array under `key`, names apart from numbers.
+/// `ErrSpawn` if it's anything else.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn syscall_list<'a>(
+    config: &'a Value,
+    key: &str,
+) -> Result<(Vec<&'a str>, Vec<u32>), PortablePtyResult> {
+    let array = config
+        .get(key)
+        .and_then(Value::as_array)
+        .ok_or(PortablePtyResult::ErrSpawn)?;
+    let (mut names, mut numbers) = (Vec::new(), Vec::new());
+    for value in array {
+        match value.as_str() {
+            Some(name) => names.push(name),
+            None => numbers.push(as_u32(value)?),
+        }
+    }
+    Ok((names, numbers))
+}
+
+/// The object of strings under `key`, if present. `ErrSpawn` if it's
+/// anything else.
+fn string_map<'a>(
+    config: &'a Value,
+    key: &str,
+) -> Result<Vec<(&'a str, &'a str)>, PortablePtyResult> {
+    let Some(value) = config.get(key) else {
+        return Ok(Vec::new());
+    };
+    let object = value.as_object().ok_or(PortablePtyResult::ErrSpawn)?;
+    object
+        .iter()
+        .map(|(name, value)| {
+            Ok((
+                name.as_str(),
+                value.as_str().ok_or(PortablePtyResult::ErrSpawn)?,
+            ))
+        })
+        .collect()
+}
+
+impl SpawnConfig {
+    /// Read a config object. `ErrSpawn` if it's malformed and
+    /// `ErrUnsupported` if it asks for what this platform can't do.
+    pub(crate) fn parse(config: &Value) -> Result<Self, PortablePtyResult> {
+        let cwd = string(config, "cwd")?;
+        if cwd.is_some_and(|cwd| !std::path::Path::new(cwd).is_dir()) {
+            return Err(PortablePtyResult::ErrSpawn);
+        }
+        let inherit_env = flag(config, "inherit_env")?;
+        let unset_env = string_list(config, "unset_env")?.unwrap_or_default();
+        let argv0 = string(config, "argv0")?;
+        #[cfg(not(unix))]
+        if argv0.is_some() {
+            return Err(PortablePtyResult::ErrUnsupported);
+        }
+        let (uid, gid, groups) = (
+            id(config, "uid")?,
+            id(config, "gid")?,
+            id_list(config, "groups")?,
+        );
+        #[cfg(unix)]
+        let credentials = credentials::Credentials::new(uid, gid, groups)
+            .map_err(|_| PortablePtyResult::ErrSpawn)?;
+        #[cfg(not(unix))]
+        if uid.is_some() || gid.is_some() || groups.is_some() {
+            return Err(PortablePtyResult::ErrUnsupported);
+        }
+        let selinux = string(config, "selinux_context")?;
+        let apparmor = string(config, "apparmor_profile")?;
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        let labels =
+            lsm::Labels::new(selinux, apparmor).map_err(|_| PortablePtyResult::ErrSpawn)?;
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        if selinux.is_some() || apparmor.is_some() {
+            return Err(PortablePtyResult::ErrUnsupported);
+        }
+        let cgroup = string(config, "cgroup")?;
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        let cgroup = cgroup
+            .map(cgroup::Cgroup::new)
+            .transpose()
+            .map_err(|_| PortablePtyResult::ErrSpawn)?;
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        if cgroup.is_some() {
+            return Err(PortablePtyResult::ErrUnsupported);
+        }
+        let namespaces = string_list(config, "namespaces")?.unwrap_or_default();
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        let namespaces =
+            namespaces::Namespaces::new(&namespaces).map_err(|_| PortablePtyResult::ErrSpawn)?;
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        if !namespaces.is_empty() {
+            return Err(PortablePtyResult::ErrUnsupported);
+        }
+        let read = string_list(config, "landlock_read")?;
+        let write = string_list(config, "landlock_write")?;
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        let landlock = if read.is_some() || write.is_some() {
+            let ruleset = landlock::Ruleset::new(
+                read.as_deref().unwrap_or_default(),
+                write.as_deref().unwrap_or_default(),
+            );
+            Some(ruleset.map_err(|e| match e.kind() {
+                std::io::ErrorKind::Unsupported => PortablePtyResult::ErrUnsupported,
+                _ => PortablePtyResult::ErrSpawn,
+            })?)
+        } else {
+            None
+        };
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        if read.is_some() || write.is_some() {
+            return Err(PortablePtyResult::ErrUnsupported);
+        }
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        let seccomp = match config.get("seccomp_deny") {
+            None | Some(Value::Bool(false)) => None,
+            Some(Value::Bool(true)) => Some(seccomp::Filter::new(seccomp::DEFAULT_DENIED, &[])),
+            Some(_) => {
+                let (names, numbers) = syscall_list(config, "seccomp_deny")?;
+                Some(seccomp::Filter::new(&names, &numbers))
+            }
+        };
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        let seccomp = seccomp.transpose().map_err(|e| match e.kind() {
+            std::io::ErrorKind::Unsupported => PortablePtyResult::ErrUnsupported,
+            _ => PortablePtyResult::ErrSpawn,
+        })?;
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        if config
+            .get("seccomp_deny")
+            .is_some_and(|deny| *deny != Value::Bool(false))
+        {
+            return Err(PortablePtyResult::ErrUnsupported);
+        }
+        let no_new_privs = flag(config, "no_new_privs")?;
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        if no_new_privs {
+            return Err(PortablePtyResult::ErrUnsupported);
+        }
+        let no_core_dumps = flag(config, "no_core_dumps")?;
+        #[cfg(not(any(unix, windows)))]
+        if no_core_dumps {
+            return Err(PortablePtyResult::ErrUnsupported);
+        }
+        let scrub = match config.get("scrub_env") {
+            None | Some(Value::Bool(false)) => scrub::Scrub::default(),
+            Some(Value::Bool(true)) => scrub::Scrub::new(scrub::DEFAULT_PATTERNS.iter().copied()),
+            Some(_) => scrub::Scrub::new(string_list(config, "scrub_env")?.unwrap_or_default()),
+        };
+        let profile = string(config, "sandbox_profile")?;
+        let parameters = string_map(config, "sandbox_parameters")?;
+        #[cfg(target_os = "macos")]
+        let sandbox = profile
+            .map(|source| sandbox::Profile::new(source, parameters))
+            .transpose()
+            .map_err(|_| PortablePtyResult::ErrSpawn)?;
+        #[cfg(not(target_os = "macos"))]
+        if profile.is_some() || !parameters.is_empty() {
+            return Err(PortablePtyResult::ErrUnsupported);
+        }
+
+        Ok(SpawnConfig {
+            cwd: cwd.map(str::to_owned),
+            inherit_env,
+            unset_env: unset_env.into_iter().map(str::to_owned).collect(),
+            #[cfg(unix)]
+            argv0: argv0.map(str::to_owned),
+            #[cfg(unix)]
+            credentials,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            labels,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            cgroup,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            namespaces,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            landlock,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            seccomp,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            no_new_privs,
+            no_core_dumps,
+            scrub,
+            #[cfg(target_os = "macos")]
+            sandbox,
+        })
+    }
+
+    /// Read the config in the NUL-terminated JSON at `config`, or `{}` for
+    /// NULL; as `parse`, and `ErrSpawn` if it isn't an object.
+    pub(crate) fn from_json(config: *const c_char) -> Result<Self, PortablePtyResult> {
+        let config = if config.is_null() {
+            Some(Value::Object(Default::default()))
+        } else {
+            unsafe { CStr::from_ptr(config) }
+                .to_str()
+                .ok()
+                .and_then(|json| serde_json::from_str(json).ok())
+        };
+        match config.filter(Value::is_object) {
+            Some(config) => Self::parse(&config),
+            None => Err(PortablePtyResult::ErrSpawn),
+        }
+    }
+
+    /// Make the config's changes to the command itself.
+    pub(crate) fn prepare(&self, builder: &mut CommandBuilder) {
+        if let Some(cwd) = &self.cwd {
+            builder.cwd(cwd);
+        }
+        if self.inherit_env {
+            // Added as if given, so scrubbed here rather than below.
+            for (name, value) in std::env::vars_os() {
+                let scrubbed = name.to_str().is_some_and(|name| self.scrub.covers(name));
+                if builder.get_env(&name).is_none() && !scrubbed {
+                    builder.env(name, value);
+                }
+            }
+        }
+        for name in &self.unset_env {
+            builder.env_remove(name);
+        }
+        self.scrub.apply(builder);
+    }
+
+    /// The `argv[0]` the program is to be run under, if not its own.
+    pub(crate) fn argv0(&self) -> Option<&str> {
+        #[cfg(unix)]
+        return self.argv0.as_deref();
+        #[cfg(not(unix))]
+        None
+    }
+
+    /// Whether spawning needs to act in the child, or set what portable-pty
+    /// can't, so only this crate can do it.
+    pub(crate) fn needs_pre_exec(&self) -> bool {
+        if self.argv0().is_some() {
+            return true;
+        }
+        #[cfg(unix)]
+        if !self.credentials.is_empty() {
+            return true;
+        }
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if !self.labels.is_empty()
+            || self.cgroup.is_some()
+            || !self.namespaces.is_empty()
+            || self.landlock.is_some()
+            || self.seccomp.is_some()
+            || self.no_new_privs
+        {
+            return true;
+        }
+        #[cfg(target_os = "macos")]
+        if self.sandbox.is_some() {
+            return true;
+        }
+        cfg!(unix) && self.no_core_dumps
+    }
+
+    /// Run `spawn`, a spawn the config doesn't act in, with what the child
+    /// should inherit from this process.
+    pub(crate) fn inherit<T>(&self, spawn: impl FnOnce() -> T) -> T {
+        #[cfg(windows)]
+        if self.no_core_dumps {
+            return windows::without_crash_reports(spawn);
+        }
+        spawn()
+    }
+
+    /// Take the config's steps in the forked child. Only async-signal-safe
+    /// calls, bar the sandbox, which macOS has no other way into.
+    #[cfg(unix)]
+    fn pre_exec(&self) -> std::io::Result<()> {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        self.labels.apply()?;
+        // While it still has the privileges these take.
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if let Some(cgroup) = &self.cgroup {
+            cgroup.join()?;
+        }
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        self.namespaces.enter()?;
+        // After the labels: a process that's changed user may not be
+        // allowed to write its own attr files.
+        self.credentials.apply()?;
+        if self.no_core_dumps {
+            let none = libc::rlimit {
+                rlim_cur: 0,
+                rlim_max: 0,
+            };
+            if unsafe { libc::setrlimit(libc::RLIMIT_CORE, &none) } != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if self.no_new_privs && unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        // After the steps that write files, which it may shut off.
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if let Some(ruleset) = &self.landlock {
+            ruleset.enforce()?;
+        }
+        #[cfg(target_os = "macos")]
+        if let Some(profile) = &self.sandbox {
+            profile.apply()?;
+        }
+        // Last of all, as it may deny the calls the others make.
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if let Some(filter) = &self.seccomp {
+            filter.install()?;
+        }
+        Ok(())
+    }
+}
+
+/// Spawn `builder` on the local PTY behind `master`, as `config` asks.
+/// `ErrUnsupported` if there's no local PTY behind it.
+pub(crate) fn spawn(
+    master: &dyn MasterPty,
+    builder: &CommandBuilder,
+    config: &SpawnConfig,
+) -> Result<Box<dyn Child + Send + Sync>, PortablePtyResult> {
+    #[cfg(unix)]
+    {
+        let tty = master.tty_name().ok_or(PortablePtyResult::ErrUnsupported)?;
+        match unix::spawn(&tty, builder, config.clone()) {
+            Ok(child) => Ok(Box::new(child)),
+            Err(_) => Err(PortablePtyResult::ErrSpawn),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (master, builder, config);
+        Err(PortablePtyResult::ErrUnsupported)
+    }
+}
+
+/// Spawn `builder` with its standard streams on `stdio` rather than a
+/// terminal, for piped handles.
+pub(crate) fn spawn_piped(
+    builder: &CommandBuilder,
+    stdio: [std::process::Stdio; 3],
+) -> std::io::Result<Box<dyn Child + Send + Sync>> {
+    #[cfg(unix)]
+    {
+        Ok(Box::new(unix::spawn_piped(builder, stdio)?))
+    }
+    #[cfg(windows)]
+    {
+        Ok(crate::conpty::adopt(windows::spawn_piped(builder, stdio)?))
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        let _ = (builder, stdio);
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+}
+
+/// Spawn `builder` on the terminal at `tty` as portable-pty would, for
+/// builds without it.
+#[cfg(all(unix, not(feature = "portable-pty")))]
+pub(crate) fn spawn_on(
+    tty: &std::path::Path,
+    builder: &CommandBuilder,
+) -> std::io::Result<std::process::Child> {
+    unix::spawn(tty, builder, SpawnConfig::default())
+}
+
+/// The command spawning `builder` on a local PTY runs, worked out as
+/// portable-pty does; None if its program can't be found.
+#[cfg(unix)]
+pub(crate) fn resolve(builder: &CommandBuilder) -> Option<std::process::Command> {
+    unix::command(builder).ok()
+}
+
+/// Spawn a child process attached to the PTY, with a config.
+///
+/// - `cmd`, `argv`, `envp`: as for `portable_pty_spawn`.
+/// - `config`: null-terminated UTF-8 JSON object of options, or NULL for
+///   `{}`. Every option is off unless given:
+///   - `cwd`: directory to start the child in.
+///   - `inherit_env`: `true` to lay `envp` over the environment the child
+///     would inherit, rather than replace it.
+///   - `unset_env`: names of variables to leave out.
+///   - `scrub_env`: `true` to drop tokens, passwords, cloud credentials
+///     and agent sockets the child would inherit, or an array of name
+///     patterns such as `"*_TOKEN"` to drop.
+///   - `argv0`: `argv[0]` to run the program under (Unix).
+///   - `uid`, `gid`, `groups`: user, group and supplementary group IDs to
+///     run it as (Unix). With `uid` the terminal is handed to that user;
+///     without `gid` it gets the user's login group, and without `groups`
+///     a child leaving root keeps no supplementary groups.
+///   - `selinux_context`, `apparmor_profile`: a context or profile to exec
+///     the child in (Linux).
+///   - `sandbox_profile`, `sandbox_parameters`: an SBPL profile to run it
+///     in and an object of strings for its `param`s (macOS).
+///   - `cgroup`: cgroup directory for it and all it starts (Linux).
+///   - `namespaces`: any of `pid`, `mount`, `network`, `ipc` and `uts`, to
+///     start it in new ones of (Linux).
+///   - `landlock_read`, `landlock_write`: paths it may only read and
+///     execute, and paths it may also change; giving either confines it
+///     to both (Linux).
+///   - `seccomp_deny`: `true` to deny it calls for administering the
+///     machine, or an array of system call names or numbers (Linux).
+///   - `no_new_privs`: `true` to bar it gaining privileges (Linux), which
+///     Landlock and `seccomp_deny` imply.
+///   - `no_core_dumps`: `true` to keep its crashes from dumping core.
+///
+/// Returns `ErrSpawn` if the config is malformed or a step it asks for
+/// fails in the child, and `ErrUnsupported` if the handle or platform
+/// can't honour it: most options need a local PTY.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_spawn_config(
+    handle: *mut PortablePty,
+    cmd: *const c_char,
+    argv: *const *const c_char,
+    envp: *const *const c_char,
+    config: *const c_char,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_mut() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        let builder = match crate::command_builder(cmd, argv, envp) {
+            Ok(builder) => builder,
+            Err(e) => return e,
+        };
+        match SpawnConfig::from_json(config) {
+            Ok(config) => pty.spawn_with(builder, &config),
+            Err(e) => e,
+        }
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::tests::read_string;
+    use std::ffi::CString;
+
+    fn spawn(handle: *mut PortablePty, argv: &[&str], config: &str) -> PortablePtyResult {
+        let args: Vec<CString> = argv.iter().map(|a| CString::new(*a).unwrap()).collect();
+        let mut ptrs: Vec<*const c_char> = args.iter().map(|a| a.as_ptr()).collect();
+        ptrs.push(std::ptr::null());
+        let config = CString::new(config).unwrap();
+        portable_pty_spawn_config(
+            handle,
+            ptrs[0],
+            ptrs.as_ptr(),
+            std::ptr::null(),
+            config.as_ptr(),
+        )
+    }
+
+    fn open() -> *mut PortablePty {
+        let mut handle = std::ptr::null_mut();
+        let result = crate::portable_pty_open(24, 80, &mut handle);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        handle
+    }
+
+    #[test]
+    fn test_plain_config_spawns_as_usual() {
+        let handle = open();
+        let result = spawn(handle, &["sh", "-c", "echo plain"], "{}");
+        assert!(matches!(result, PortablePtyResult::Ok));
+        assert!(read_string(handle).contains("plain"));
+        crate::portable_pty_close(handle);
+    }
+
+    #[test]
+    fn test_rejects_bad_configs() {
+        let handle = open();
+        let result = spawn(handle, &["sh"], "[]");
+        assert!(matches!(result, PortablePtyResult::ErrSpawn));
+        let result = spawn(handle, &["sh"], r#"{"selinux_context": 1}"#);
+        assert!(matches!(result, PortablePtyResult::ErrSpawn));
+        let result = spawn(handle, &["sh"], r#"{"sandbox_parameters": {"A": 1}}"#);
+        assert!(matches!(result, PortablePtyResult::ErrSpawn));
+        #[cfg(not(target_os = "macos"))]
+        {
+            let result = spawn(handle, &["sh"], r#"{"sandbox_profile": "(version 1)"}"#);
+            assert!(matches!(result, PortablePtyResult::ErrUnsupported));
+        }
+        crate::portable_pty_close(handle);
+
+        // Confinement needs a local PTY.
+        let mut handle = std::ptr::null_mut();
+        crate::loopback::portable_pty_open_loopback(24, 80, &mut handle);
+        let result = spawn(handle, &["sh"], r#"{"apparmor_profile": "p"}"#);
+        assert!(matches!(result, PortablePtyResult::ErrUnsupported));
+        crate::portable_pty_close(handle);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_no_new_privs() {
+        let handle = open();
+        let args = ["sh", "-c", "grep NoNewPrivs /proc/self/status"];
+        let result = spawn(handle, &args, r#"{"no_new_privs": true}"#);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        let output = read_string(handle);
+        assert!(output.contains("NoNewPrivs:\t1"), "{output:?}");
+        let result = spawn(handle, &["sh"], r#"{"no_new_privs": "yes"}"#);
+        assert!(matches!(result, PortablePtyResult::ErrSpawn));
+        crate::portable_pty_close(handle);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_seccomp_deny() {
+        let dir = std::env::temp_dir().join(format!("portable-pty-seccomp-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let spawn = |script: &str, config: &str| {
+            let handle = open();
+            let result = spawn(handle, &["sh", "-c", script], config);
+            let output = match result {
+                PortablePtyResult::Ok => Some(read_string(handle)),
+                _ => None,
+            };
+            crate::portable_pty_close(handle);
+            (result, output)
+        };
+
+        let script = "echo \"filters $(grep -c '^Seccomp:.*2' /proc/self/status)\"";
+        let (result, output) = spawn(script, r#"{"seccomp_deny": true}"#);
+        if matches!(result, PortablePtyResult::ErrUnsupported) {
+            return;
+        }
+        let output = output.unwrap();
+        assert!(output.contains("filters 1"), "{output:?}");
+
+        #[allow(unused_mut)]
+        let mut denied = vec![libc::SYS_mkdirat];
+        #[cfg(target_arch = "x86_64")]
+        denied.push(libc::SYS_mkdir);
+        let config = serde_json::json!({ "seccomp_deny": denied }).to_string();
+        let script = format!(
+            "cd {} && mkdir made 2>/dev/null; echo \"status $?\"",
+            dir.display()
+        );
+        let (_, output) = spawn(&script, &config);
+        let output = output.unwrap();
+        assert!(output.contains("status 1"), "{output:?}");
+        assert!(!dir.join("made").exists());
+
+        let (result, _) = spawn("true", r#"{"seccomp_deny": ["unshare", "socket"]}"#);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        for config in [
+            r#"{"seccomp_deny": ["nonexistent"]}"#,
+            r#"{"seccomp_deny": [true]}"#,
+            r#"{"seccomp_deny": "mount"}"#,
+        ] {
+            let (result, _) = spawn("true", config);
+            assert!(matches!(result, PortablePtyResult::ErrSpawn), "{config}");
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_namespaces() {
+        let handle = open();
+        let config = r#"{"namespaces": ["pid", "mount", "network", "ipc", "uts"]}"#;
+        let script = "echo \"pid $$ procs $(ls /proc | grep -c '^[0-9]') \
+            links $(grep -c : /proc/net/dev) up $(ip -o link show lo 2>/dev/null | grep -c LOWER_UP)\"";
+        match spawn(handle, &["sh", "-c", script], config) {
+            // Not allowed to make them here.
+            PortablePtyResult::ErrSpawn => {}
+            result => {
+                assert!(matches!(result, PortablePtyResult::Ok));
+                let output = read_string(handle);
+                assert!(output.contains("pid 1 procs "), "{output:?}");
+                let procs: usize = output.split(' ').nth(3).unwrap().parse().unwrap();
+                assert!(procs < 5, "{output:?}");
+                assert!(output.contains("links 1 up "), "{output:?}");
+                let ip = ["/sbin/ip", "/bin/ip", "/usr/sbin/ip", "/usr/bin/ip"];
+                if ip.iter().any(|ip| std::path::Path::new(ip).exists()) {
+                    assert!(output.contains("up 1\r"), "{output:?}");
+                }
+            }
+        }
+        crate::portable_pty_close(handle);
+
+        let handle = open();
+        for config in [r#"{"namespaces": ["user"]}"#, r#"{"namespaces": "pid"}"#] {
+            let result = spawn(handle, &["sh"], config);
+            assert!(matches!(result, PortablePtyResult::ErrSpawn), "{config}");
+        }
+        crate::portable_pty_close(handle);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_cgroup() {
+        let handle = open();
+        let result = spawn(handle, &["sh"], r#"{"cgroup": "/nonexistent"}"#);
+        assert!(matches!(result, PortablePtyResult::ErrSpawn));
+        crate::portable_pty_close(handle);
+
+        let mounts = std::fs::read_to_string("/proc/self/mounts").unwrap();
+        let Some(root) = mounts.lines().find_map(|mount| {
+            let fields: Vec<&str> = mount.split(' ').collect();
+            (fields.get(2) == Some(&"cgroup2")).then(|| fields[1].to_owned())
+        }) else {
+            return;
+        };
+        let name = format!("portable-pty-cgroup-{}", std::process::id());
+        let dir = std::path::Path::new(&root).join(&name);
+        // Not allowed to make one here.
+        if std::fs::create_dir(&dir).is_err() {
+            return;
+        }
+        let handle = open();
+        let config = serde_json::json!({ "cgroup": dir }).to_string();
+        let result = spawn(
+            handle,
+            &["sh", "-c", "grep ^0:: /proc/self/cgroup"],
+            &config,
+        );
+        assert!(matches!(result, PortablePtyResult::Ok));
+        let output = read_string(handle);
+        assert!(output.contains(&format!("/{name}\r")), "{output:?}");
+        crate::portable_pty_close(handle);
+        // It can only go once it's empty.
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while std::fs::remove_dir(&dir).is_err() {
+            assert!(std::time::Instant::now() < deadline);
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_cwd() {
+        let handle = open();
+        let result = spawn(handle, &["sh", "-c", "echo in $(pwd)"], r#"{"cwd": "/"}"#);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        let output = read_string(handle);
+        assert!(output.contains("in /\r"), "{output:?}");
+        let result = spawn(handle, &["sh"], r#"{"cwd": "/nonexistent"}"#);
+        assert!(matches!(result, PortablePtyResult::ErrSpawn));
+        let result = spawn(handle, &["sh"], r#"{"cwd": 1}"#);
+        assert!(matches!(result, PortablePtyResult::ErrSpawn));
+        crate::portable_pty_close(handle);
+    }
+
+    #[test]
+    fn test_env_overlay() {
+        let spawn = |config: &str| {
+            let handle = open();
+            let argv = [
+                c"/bin/sh",
+                c"-c",
+                c"echo \"[${GREETING-none} ${HOME:+home}]\"",
+            ];
+            let mut argv: Vec<_> = argv.iter().map(|a| a.as_ptr()).collect();
+            argv.push(std::ptr::null());
+            let envp = [c"GREETING=hi".as_ptr(), std::ptr::null()];
+            let config = CString::new(config).unwrap();
+            let result = portable_pty_spawn_config(
+                handle,
+                argv[0],
+                argv.as_ptr(),
+                envp.as_ptr(),
+                config.as_ptr(),
+            );
+            assert!(matches!(result, PortablePtyResult::Ok));
+            let output = read_string(handle);
+            crate::portable_pty_close(handle);
+            output
+        };
+        let output = spawn(r#"{"inherit_env": true}"#);
+        assert!(output.contains("[hi home]"), "{output:?}");
+        let output = spawn(r#"{"inherit_env": true, "unset_env": ["GREETING"]}"#);
+        assert!(output.contains("[none home]"), "{output:?}");
+        let output = spawn(r#"{"inherit_env": true, "scrub_env": ["HOM*"]}"#);
+        assert!(output.contains("[hi ]"), "{output:?}");
+        let output = spawn("{}");
+        assert!(output.contains("[hi ]"), "{output:?}");
+    }
+
+    #[test]
+    fn test_argv0() {
+        let handle = open();
+        let args = ["sh", "-c", "echo \"name $0\""];
+        let result = spawn(handle, &args, r#"{"argv0": "-custom"}"#);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        let output = read_string(handle);
+        assert!(output.contains("name -custom"), "{output:?}");
+        let mut out = crate::PortablePtyBuffer::EMPTY;
+        crate::spawned::portable_pty_spawned_command(handle, &mut out);
+        let bytes = unsafe { std::slice::from_raw_parts(out.data, out.len) };
+        let spawned: Value = serde_json::from_slice(bytes).unwrap();
+        crate::portable_pty_buffer_free(out);
+        assert_eq!(spawned["argv"][0], "-custom");
+        crate::portable_pty_close(handle);
+    }
+
+    #[test]
+    fn test_credentials() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let run = |config: &str| {
+            let handle = open();
+            let args = [
+                "sh",
+                "-c",
+                "echo \"ids $(id -u) $(id -g) $(id -G) $(ls -ln $(tty) | awk '{print $3}')\"",
+            ];
+            let result = spawn(handle, &args, config);
+            let output = match result {
+                PortablePtyResult::Ok => Some(read_string(handle)),
+                _ => None,
+            };
+            crate::portable_pty_close(handle);
+            (result, output)
+        };
+        if unsafe { libc::getuid() } == 0 {
+            let config = r#"{"uid": 65534, "gid": 65534, "groups": [65533]}"#;
+            let (_, output) = run(config);
+            let output = output.unwrap();
+            assert!(
+                output.contains("ids 65534 65534 65534 65533 65534"),
+                "{output:?}"
+            );
+            let (_, output) = run(r#"{"uid": 65534, "gid": 65534}"#);
+            let output = output.unwrap();
+            assert!(output.contains("ids 65534 65534 65534 65534"), "{output:?}");
+
+            // Without a gid, the user's login group rather than root's.
+            let entry = unsafe { libc::getpwuid(65534) };
+            let (result, output) = run(r#"{"uid": 65534}"#);
+            if entry.is_null() {
+                assert!(matches!(result, PortablePtyResult::ErrSpawn));
+            } else {
+                let gid = unsafe { (*entry).pw_gid };
+                let output = output.unwrap();
+                let expected = format!("ids 65534 {gid} {gid} 65534");
+                assert!(output.contains(&expected), "{output:?}");
+            }
+
+            // A spawn that fails gives the terminal back: the program is
+            // found as root but can't be run once the child isn't.
+            let dir =
+                std::env::temp_dir().join(format!("portable-pty-creds-{}", std::process::id()));
+            std::fs::create_dir(&dir).unwrap();
+            let program = dir.join("program");
+            std::fs::write(&program, "#!/bin/sh\n").unwrap();
+            std::fs::set_permissions(&program, std::fs::Permissions::from_mode(0o755)).unwrap();
+            std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700)).unwrap();
+            let handle = open();
+            let tty = unsafe { &*handle }.master.tty_name().unwrap();
+            let owner = std::fs::metadata(&tty).unwrap().uid();
+            let config = r#"{"uid": 65534, "gid": 65534}"#;
+            let result = spawn(handle, &[program.to_str().unwrap()], config);
+            assert!(matches!(result, PortablePtyResult::ErrSpawn));
+            assert_eq!(std::fs::metadata(&tty).unwrap().uid(), owner);
+            crate::portable_pty_close(handle);
+            std::fs::remove_dir_all(&dir).unwrap();
+        } else {
+            // Only root can become someone else.
+            let (result, _) = run(r#"{"uid": 0}"#);
+            assert!(matches!(result, PortablePtyResult::ErrSpawn));
+        }
+        let (result, _) = run(r#"{"uid": -1}"#);
+        assert!(matches!(result, PortablePtyResult::ErrSpawn));
+        let (result, _) = run(r#"{"groups": ["wheel"]}"#);
+        assert!(matches!(result, PortablePtyResult::ErrSpawn));
+    }
+
+    #[test]
+    fn test_no_core_dumps() {
+        let handle = open();
+        let args = ["sh", "-c", "echo core $(ulimit -c)"];
+        let result = spawn(handle, &args, r#"{"no_core_dumps": true}"#);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        let output = read_string(handle);
+        assert!(output.contains("core 0"), "{output:?}");
+        crate::portable_pty_close(handle);
+    }
+
+    #[test]
+    fn test_scrub_env() {
+        let handle = open();
+        let args = ["sh", "-c", "echo home=${HOME-unset}"];
+        let result = spawn(handle, &args, r#"{"scrub_env": ["HOM*"]}"#);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        let output = read_string(handle);
+        assert!(output.contains("home=unset"), "{output:?}");
+        let result = spawn(handle, &["sh"], r#"{"scrub_env": [1]}"#);
+        assert!(matches!(result, PortablePtyResult::ErrSpawn));
+        crate::portable_pty_close(handle);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_landlock_confines_writes() {
+        let dir =
+            std::env::temp_dir().join(format!("portable-pty-landlock-{}", std::process::id()));
+        let allowed = dir.join("allowed");
+        std::fs::create_dir_all(&allowed).unwrap();
+        let config = serde_json::json!({
+            "landlock_read": ["/"],
+            "landlock_write": [allowed],
+        })
+        .to_string();
+        let script = "echo a > allowed/f; a=$?; (echo b > f) 2> allowed/err; echo \"status $a $?\"";
+
+        let handle = open();
+        let args = ["sh", "-c", &format!("cd {} && {script}", dir.display())];
+        match spawn(handle, &args, &config) {
+            PortablePtyResult::ErrUnsupported => {}
+            result => {
+                assert!(matches!(result, PortablePtyResult::Ok));
+                // One line, so a single read gets it.
+                let output = read_string(handle);
+                assert!(
+                    output.contains("status 0 1") || output.contains("status 0 2"),
+                    "{output:?}"
+                );
+                assert!(!dir.join("f").exists());
+            }
+        }
+        let result = spawn(handle, &["sh"], r#"{"landlock_read": ["/nonexistent"]}"#);
+        assert!(matches!(
+            result,
+            PortablePtyResult::ErrSpawn | PortablePtyResult::ErrUnsupported
+        ));
+        crate::portable_pty_close(handle);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_spawns_like_portable_pty() {
+        let handle = open();
+        let pty = unsafe { &*handle };
+        let mut builder = CommandBuilder::new("sh");
+        builder.args(["-c", r#"echo "$0 $(pwd) $FOO"; [ -t 0 ] && echo tty"#]);
+        builder.cwd("/");
+        builder.env("FOO", "bar");
+        let mut child = super::spawn(pty.master.as_ref(), &builder, &SpawnConfig::default())
+            .ok()
+            .unwrap();
+        assert!(child.wait().unwrap().success());
+        let output = read_string(handle);
+        assert!(output.contains("sh / bar"), "{output:?}");
+        assert!(output.contains("tty"), "{output:?}");
+        crate::portable_pty_close(handle);
+    }
+}
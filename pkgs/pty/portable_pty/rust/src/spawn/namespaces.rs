@@ -0,0 +1,122 @@
+//! Linux namespaces to start the child in.
+//!
+//! Each one named gives the child, and all it starts, its own copy of a
+//! part of the system, set apart from the host's: `pid` its own process
+//! IDs, the child being 1, the init of them; `mount` its own mount table,
+//! made private so mounts on neither side reach the other, and with `pid`
+//! a `/proc` of its own; `network` its own interfaces, just a loopback,
+//! brought up; `ipc` its own System V IPC; `uts` its own hostname. Making
+//! them takes `CAP_SYS_ADMIN`.
+//!
+//! A process can't move itself into a new PID namespace, only the children
+//! it goes on to fork, so that one is made by a thread that forks the
+//! child and does nothing else. The rest are made in the child.
+
+use std::io;
+
+const NAMESPACES: &[(&str, libc::c_int)] = &[
+    ("ipc", libc::CLONE_NEWIPC),
+    ("mount", libc::CLONE_NEWNS),
+    ("network", libc::CLONE_NEWNET),
+    ("pid", libc::CLONE_NEWPID),
+    ("uts", libc::CLONE_NEWUTS),
+];
+
+/// The `CLONE_NEW*` flags for the namespaces to make.
+#[derive(Clone, Copy, Default)]
+pub(super) struct Namespaces(libc::c_int);
+
+impl Namespaces {
+    /// The namespaces `names` name. `InvalidInput` for one it doesn't.
+    pub(super) fn new(names: &[&str]) -> io::Result<Self> {
+        let mut flags = 0;
+        for name in names {
+            let known = NAMESPACES.iter().find(|(known, _)| known == name);
+            flags |= known.ok_or(io::ErrorKind::InvalidInput)?.1;
+        }
+        Ok(Namespaces(flags))
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    fn has(&self, flag: libc::c_int) -> bool {
+        self.0 & flag != 0
+    }
+
+    /// Run `spawn`, forking the child, so it starts in the new PID
+    /// namespace if there's to be one.
+    pub(super) fn spawn<T: Send>(
+        &self,
+        spawn: impl FnOnce() -> io::Result<T> + Send,
+    ) -> io::Result<T> {
+        if !self.has(libc::CLONE_NEWPID) {
+            return spawn();
+        }
+        // The thread ends with the spawn, and its namespace for children
+        // with it.
+        std::thread::scope(|scope| {
+            let thread = scope.spawn(|| {
+                if unsafe { libc::unshare(libc::CLONE_NEWPID) } != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                spawn()
+            });
+            thread
+                .join()
+                .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+        })
+    }
+
+    /// Make the other namespaces and set them up. Only async-signal-safe
+    /// calls: this runs in the forked child.
+    pub(super) fn enter(&self) -> io::Result<()> {
+        let check = |result: libc::c_int| match result {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        };
+        let flags = self.0 & !libc::CLONE_NEWPID;
+        if flags != 0 {
+            check(unsafe { libc::unshare(flags) })?;
+        }
+        if self.has(libc::CLONE_NEWNS) {
+            let private = libc::MS_REC | libc::MS_PRIVATE;
+            let null = std::ptr::null();
+            check(unsafe { libc::mount(null, c"/".as_ptr(), null, private, null.cast()) })?;
+            if self.has(libc::CLONE_NEWPID) {
+                let proc = c"proc".as_ptr();
+                let flags = libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC;
+                check(unsafe { libc::mount(proc, c"/proc".as_ptr(), proc, flags, null.cast()) })?;
+            }
+        }
+        if self.has(libc::CLONE_NEWNET) {
+            loopback_up()?;
+        }
+        Ok(())
+    }
+}
+
+/// Bring up the loopback interface, which a new network namespace starts
+/// with down.
+fn loopback_up() -> io::Result<()> {
+    let socket = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if socket < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut request: libc::ifreq = unsafe { std::mem::zeroed() };
+    for (to, from) in request.ifr_name.iter_mut().zip(b"lo") {
+        *to = *from as libc::c_char;
+    }
+    let mut result = unsafe { libc::ioctl(socket, libc::SIOCGIFFLAGS as _, &mut request) };
+    if result == 0 {
+        unsafe { request.ifr_ifru.ifru_flags |= libc::IFF_UP as libc::c_short };
+        result = unsafe { libc::ioctl(socket, libc::SIOCSIFFLAGS as _, &request) };
+    }
+    let error = io::Error::last_os_error();
+    unsafe { libc::close(socket) };
+    match result {
+        0 => Ok(()),
+        _ => Err(error),
+    }
+}
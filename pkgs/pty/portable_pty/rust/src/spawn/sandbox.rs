@@ -0,0 +1,77 @@
+//! macOS sandbox profiles to run children under.
+//!
+//! The child enters the sandbox with `sandbox_init_with_parameters(3)`
+//! just before `exec`, as `sandbox-exec -p` does, so the profile holds for
+//! the program and everything it starts. The profile is SBPL source; its
+//! `(param "NAME")` forms read the parameters given alongside it. If the
+//! profile doesn't compile or can't be applied the spawn fails.
+
+use std::ffi::{c_char, CString};
+use std::io;
+use std::sync::Arc;
+
+unsafe extern "C" {
+    fn sandbox_init_with_parameters(
+        profile: *const c_char,
+        flags: u64,
+        parameters: *const *const c_char,
+        errorbuf: *mut *mut c_char,
+    ) -> i32;
+}
+
+struct Inner {
+    source: CString,
+    /// Keep `pointers` valid.
+    _parameters: Vec<CString>,
+    /// `NAME, value, …, NULL`, as the call takes them.
+    pointers: Vec<*const c_char>,
+}
+
+// The pointers are into `_parameters`, which is never changed.
+unsafe impl Send for Inner {}
+unsafe impl Sync for Inner {}
+
+/// A profile and its parameters, ready to apply without allocating.
+#[derive(Clone)]
+pub(super) struct Profile(Arc<Inner>);
+
+impl Profile {
+    pub(super) fn new<'a>(
+        source: &str,
+        parameters: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> io::Result<Self> {
+        let c_string = |s: &str| CString::new(s).map_err(|_| io::ErrorKind::InvalidInput);
+        let source = c_string(source)?;
+        let mut flat = Vec::new();
+        for (name, value) in parameters {
+            flat.push(c_string(name)?);
+            flat.push(c_string(value)?);
+        }
+        let mut pointers: Vec<*const c_char> = flat.iter().map(|s| s.as_ptr()).collect();
+        pointers.push(std::ptr::null());
+        Ok(Profile(Arc::new(Inner {
+            source,
+            _parameters: flat,
+            pointers,
+        })))
+    }
+
+    /// Enter the sandbox. This runs in the forked child.
+    pub(super) fn apply(&self) -> io::Result<()> {
+        let mut error = std::ptr::null_mut();
+        let result = unsafe {
+            sandbox_init_with_parameters(
+                self.0.source.as_ptr(),
+                0,
+                self.0.pointers.as_ptr(),
+                &mut error,
+            )
+        };
+        // The error text is the child's to lose: it fails and never execs.
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(io::ErrorKind::PermissionDenied.into())
+        }
+    }
+}
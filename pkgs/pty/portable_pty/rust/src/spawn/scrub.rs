@@ -0,0 +1,127 @@
+//! Keeping the host's secrets out of children's environments.
+//!
+//! Only what the child would inherit is scrubbed: variables the caller
+//! passes for the spawn are theirs to pass. Patterns are variable names in
+//! which `*` stands for any run of characters; on Windows, where names
+//! aren't case-sensitive, neither are they.
+
+use crate::pty::CommandBuilder;
+
+/// What `"scrub_env": true` strips.
+pub(super) const DEFAULT_PATTERNS: &[&str] = &[
+    "*_TOKEN",
+    "*_SECRET",
+    "*_PASSWORD",
+    "*_API_KEY",
+    "*_ACCESS_KEY",
+    "AWS_*",
+    "AZURE_*",
+    "GOOGLE_APPLICATION_CREDENTIALS",
+    "SSH_AUTH_SOCK",
+    "GPG_AGENT_INFO",
+];
+
+fn fold(name: &str) -> String {
+    if cfg!(windows) {
+        name.to_ascii_uppercase()
+    } else {
+        name.to_owned()
+    }
+}
+
+/// Whether `name` fits `pattern`.
+fn matches(pattern: &[u8], name: &[u8]) -> bool {
+    // Where to resume after the last `*`: its pattern index, and the
+    // name index it has swallowed up to.
+    let mut star = None;
+    let (mut p, mut n) = (0, 0);
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p + 1;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Patterns of variables to drop from what children inherit.
+#[derive(Clone, Default)]
+pub(super) struct Scrub {
+    patterns: Vec<String>,
+}
+
+impl Scrub {
+    pub(super) fn new<'a>(patterns: impl IntoIterator<Item = &'a str>) -> Self {
+        Scrub {
+            patterns: patterns.into_iter().map(fold).collect(),
+        }
+    }
+
+    /// Whether the variable `name` is one to drop.
+    pub(super) fn covers(&self, name: &str) -> bool {
+        let name = fold(name);
+        self.patterns
+            .iter()
+            .any(|p| matches(p.as_bytes(), name.as_bytes()))
+    }
+
+    /// Remove the inherited variables that match from `builder`.
+    pub(super) fn apply(&self, builder: &mut CommandBuilder) {
+        if self.patterns.is_empty() {
+            return;
+        }
+        let given: Vec<String> = builder
+            .iter_extra_env_as_str()
+            .map(|(name, _)| fold(name))
+            .collect();
+        let scrubbed: Vec<String> = builder
+            .iter_full_env_as_str()
+            .map(|(name, _)| name.to_owned())
+            .filter(|name| !given.contains(&fold(name)) && self.covers(name))
+            .collect();
+        for name in scrubbed {
+            builder.env_remove(name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_patterns() {
+        let fits = |p: &str, n: &str| matches(p.as_bytes(), n.as_bytes());
+        assert!(fits("*_TOKEN", "GITHUB_TOKEN"));
+        assert!(fits("*_TOKEN", "_TOKEN"));
+        assert!(!fits("*_TOKEN", "GITHUB_TOKENS"));
+        assert!(fits("AWS_*", "AWS_SECRET_ACCESS_KEY"));
+        assert!(fits("A*B*C", "AxxBxBxC"));
+        assert!(!fits("A*B*C", "AxxBxCx"));
+        assert!(fits("SSH_AUTH_SOCK", "SSH_AUTH_SOCK"));
+        assert!(!fits("SSH_AUTH_SOCK", "SSH_AUTH_SOCKET"));
+    }
+
+    #[test]
+    fn test_scrubs_only_what_is_inherited() {
+        let mut builder = CommandBuilder::new("sh");
+        builder.env_clear();
+        builder.env("DEPLOY_TOKEN", "mine");
+        Scrub::new(DEFAULT_PATTERNS.iter().copied()).apply(&mut builder);
+        assert_eq!(builder.get_env("DEPLOY_TOKEN"), Some("mine".as_ref()));
+    }
+}
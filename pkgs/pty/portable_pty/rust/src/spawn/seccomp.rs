@@ -0,0 +1,258 @@
+//! Seccomp filters denying the child system calls.
+//!
+//! A filter is a small BPF program the kernel runs on every system call
+//! the child, and everything it starts, makes. This one fails the denied
+//! calls with `EPERM` and lets the rest through. Calls made under another
+//! architecture's numbering — a 32-bit call on a 64-bit kernel, or x32 —
+//! would slip past a list of this one's numbers, so they kill the process
+//! instead. Installing a filter takes `no_new_privs`, which it implies.
+//!
+//! Denying `unshare` denies making namespaces with `clone` too: its flags
+//! are checked for `CLONE_NEW*`. `clone3` takes them in memory a filter
+//! can't read, so it fails with `ENOSYS`, and libc falls back to `clone`.
+//! Denying `umount2` denies the older `umount` too, on 32-bit x86, the
+//! only architecture here still having it.
+
+use std::io;
+
+/// What `seccomp_deny: true` denies: calls for administering the machine
+/// rather than using it (namespaces included, however they're made), for
+/// reaching into other processes, and `io_uring`, whose operations a
+/// filter never sees.
+pub(super) const DEFAULT_DENIED: &[&str] = &[
+    "acct",
+    "add_key",
+    "bpf",
+    "clock_settime",
+    "delete_module",
+    "finit_module",
+    "fsconfig",
+    "fsmount",
+    "fsopen",
+    "fspick",
+    "init_module",
+    "io_uring_enter",
+    "io_uring_register",
+    "io_uring_setup",
+    "kexec_load",
+    "keyctl",
+    "mount",
+    "mount_setattr",
+    "move_mount",
+    "open_by_handle_at",
+    "open_tree",
+    "perf_event_open",
+    "pivot_root",
+    "process_vm_readv",
+    "process_vm_writev",
+    "ptrace",
+    "quotactl",
+    "reboot",
+    "request_key",
+    "setdomainname",
+    "sethostname",
+    "setns",
+    "settimeofday",
+    "swapoff",
+    "swapon",
+    "syslog",
+    "umount2",
+    "unshare",
+    "userfaultfd",
+];
+
+macro_rules! syscalls {
+    ($($name:ident),* $(,)?) => {
+        &[$((stringify!($name), libc::$name as u32)),*]
+    };
+}
+
+/// The calls that can be denied by name, besides any by number: the
+/// defaults, plus a few more a session might do without.
+#[rustfmt::skip]
+const SYSCALLS: &[(&str, u32)] = syscalls![
+    SYS_acct, SYS_add_key, SYS_bpf, SYS_chroot, SYS_clock_settime,
+    SYS_delete_module, SYS_finit_module, SYS_fsconfig, SYS_fsmount,
+    SYS_fsopen, SYS_fspick, SYS_init_module, SYS_io_uring_enter,
+    SYS_io_uring_register, SYS_io_uring_setup, SYS_kcmp, SYS_kexec_load,
+    SYS_keyctl, SYS_mount, SYS_mount_setattr, SYS_move_mount,
+    SYS_name_to_handle_at, SYS_open_by_handle_at, SYS_open_tree,
+    SYS_perf_event_open, SYS_personality, SYS_pivot_root,
+    SYS_process_vm_readv, SYS_process_vm_writev, SYS_ptrace, SYS_quotactl,
+    SYS_reboot, SYS_request_key, SYS_setdomainname, SYS_sethostname,
+    SYS_setns, SYS_settimeofday, SYS_socket, SYS_swapoff, SYS_swapon,
+    SYS_syslog, SYS_umount2, SYS_unshare, SYS_userfaultfd,
+];
+
+/// `AUDIT_ARCH_*` for this target, which the kernel reports with each
+/// call; None where filters aren't supported here.
+const ARCH: Option<u32> = if cfg!(target_arch = "x86_64") {
+    Some(0xc000_003e)
+} else if cfg!(target_arch = "aarch64") {
+    Some(0xc000_00b7)
+} else if cfg!(target_arch = "x86") {
+    Some(0x4000_0003)
+} else if cfg!(target_arch = "arm") {
+    Some(0x4000_0028)
+} else if cfg!(target_arch = "riscv64") {
+    Some(0xc000_00f3)
+} else {
+    None
+};
+
+/// The older call `umount2` replaced, where there still is one.
+#[cfg(target_arch = "x86")]
+const UMOUNT: Option<u32> = Some(libc::SYS_umount as u32);
+#[cfg(not(target_arch = "x86"))]
+const UMOUNT: Option<u32> = None;
+
+/// The `CLONE_NEW*` flags, which not every libc has: `CLONE_NEWTIME`,
+/// `CLONE_NEWNS`, `CLONE_NEWCGROUP`, `CLONE_NEWUTS`, `CLONE_NEWIPC`,
+/// `CLONE_NEWUSER`, `CLONE_NEWPID` and `CLONE_NEWNET`.
+const CLONE_NEW: u32 = 0x7e02_0080;
+
+/// Offset of the low word of `clone`'s first argument, its flags on every
+/// architecture here.
+const CLONE_FLAGS: u32 = std::mem::offset_of!(libc::seccomp_data, args) as u32
+    + if cfg!(target_endian = "big") { 4 } else { 0 };
+
+/// The bit x32 sets in its call numbers.
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+// Classic BPF opcodes, which not every libc has.
+/// `BPF_LD | BPF_W | BPF_ABS`: load a word of the call's `seccomp_data`.
+const LOAD: u16 = 0x20;
+/// `BPF_JMP | BPF_JEQ | BPF_K`.
+const JUMP_IF_EQUAL: u16 = 0x15;
+/// `BPF_JMP | BPF_JGE | BPF_K`.
+const JUMP_IF_AT_LEAST: u16 = 0x35;
+/// `BPF_JMP | BPF_JSET | BPF_K`.
+const JUMP_IF_ANY_SET: u16 = 0x45;
+/// `BPF_RET | BPF_K`.
+const RETURN: u16 = 0x06;
+
+/// A compiled filter, ready to install without allocating.
+#[derive(Clone)]
+pub(super) struct Filter(Vec<libc::sock_filter>);
+
+fn statement(code: u16, k: u32) -> libc::sock_filter {
+    jump(code, k, 0, 0)
+}
+
+fn jump(code: u16, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter { code, jt, jf, k }
+}
+
+impl Filter {
+    /// A filter denying `names`, from those `SYSCALLS` lists, and
+    /// `numbers`. `Unsupported` if the kernel or architecture has no
+    /// filters, `InvalidInput` for a name it doesn't know.
+    pub(super) fn new(names: &[&str], numbers: &[u32]) -> io::Result<Self> {
+        let Some(arch) = ARCH else {
+            return Err(io::ErrorKind::Unsupported.into());
+        };
+        if unsafe { libc::prctl(libc::PR_GET_SECCOMP) } < 0 {
+            return Err(io::ErrorKind::Unsupported.into());
+        }
+        let mut denied = numbers.to_vec();
+        for name in names {
+            let number = SYSCALLS
+                .iter()
+                .find(|(known, _)| known.strip_prefix("SYS_") == Some(*name));
+            denied.push(number.ok_or(io::ErrorKind::InvalidInput)?.1);
+        }
+        if denied.contains(&(libc::SYS_umount2 as u32)) {
+            denied.extend(UMOUNT);
+        }
+        denied.sort_unstable();
+        denied.dedup();
+
+        let kill = statement(RETURN, libc::SECCOMP_RET_KILL_PROCESS);
+        let deny = statement(RETURN, libc::SECCOMP_RET_ERRNO | libc::EPERM as u32);
+        let mut program = vec![
+            statement(LOAD, std::mem::offset_of!(libc::seccomp_data, arch) as u32),
+            jump(JUMP_IF_EQUAL, arch, 1, 0),
+            kill,
+            statement(LOAD, std::mem::offset_of!(libc::seccomp_data, nr) as u32),
+        ];
+        if cfg!(target_arch = "x86_64") {
+            program.extend([jump(JUMP_IF_AT_LEAST, X32_SYSCALL_BIT, 0, 1), kill]);
+        }
+        for &number in &denied {
+            program.extend([jump(JUMP_IF_EQUAL, number, 0, 1), deny]);
+        }
+        if denied.contains(&(libc::SYS_unshare as u32)) {
+            let unsupported = statement(RETURN, libc::SECCOMP_RET_ERRNO | libc::ENOSYS as u32);
+            program.extend([
+                jump(JUMP_IF_EQUAL, libc::SYS_clone3 as u32, 0, 1),
+                unsupported,
+                jump(JUMP_IF_EQUAL, libc::SYS_clone as u32, 0, 3),
+                statement(LOAD, CLONE_FLAGS),
+                jump(JUMP_IF_ANY_SET, CLONE_NEW, 0, 1),
+                deny,
+            ]);
+        }
+        program.push(statement(RETURN, libc::SECCOMP_RET_ALLOW));
+        Ok(Filter(program))
+    }
+
+    /// Install the filter. Only async-signal-safe calls: this runs in the
+    /// forked child.
+    pub(super) fn install(&self) -> io::Result<()> {
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let program = libc::sock_fprog {
+            len: self.0.len() as u16,
+            filter: self.0.as_ptr().cast_mut(),
+        };
+        let mode = libc::SECCOMP_MODE_FILTER as libc::c_ulong;
+        if unsafe { libc::prctl(libc::PR_SET_SECCOMP, mode, &program) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The errno of a raw call that failed, or 0 if it didn't.
+    fn errno(result: libc::c_long) -> i32 {
+        match result {
+            -1 => io::Error::last_os_error().raw_os_error().unwrap_or(0),
+            0 => unsafe { libc::_exit(0) },
+            _ => 0,
+        }
+    }
+
+    #[test]
+    fn test_default_denies_namespaces_through_clone() {
+        let filter = match Filter::new(DEFAULT_DENIED, &[]) {
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => return,
+            filter => filter.unwrap(),
+        };
+        let flags = (libc::CLONE_NEWUSER | libc::SIGCHLD) as libc::c_ulong;
+        // Only async-signal-safe calls in the child.
+        let pid = unsafe { libc::fork() };
+        if pid == 0 {
+            let code = if filter.install().is_err() {
+                1
+            } else if errno(unsafe { libc::syscall(libc::SYS_clone3, 0, 0) }) != libc::ENOSYS {
+                2
+            } else if errno(unsafe { libc::syscall(libc::SYS_clone, flags, 0, 0, 0, 0) })
+                != libc::EPERM
+            {
+                3
+            } else {
+                0
+            };
+            unsafe { libc::_exit(code) };
+        }
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert!(libc::WIFEXITED(status), "{status:#x}");
+        assert_eq!(libc::WEXITSTATUS(status), 0);
+    }
+}
@@ -0,0 +1,211 @@
+//! Spawning on a local PTY ourselves.
+//!
+//! portable-pty gives no way to act in the child between `fork` and
+//! `exec`, which is where confinement has to happen: a label, a sandbox
+//! or a ruleset set up in the parent would confine the host. This does
+//! what its Unix `spawn_command` does — the same program lookup, working
+//! directory, environment, session and controlling terminal — with the
+//! config's steps run last before `exec`.
+
+use super::SpawnConfig;
+use crate::pty::CommandBuilder;
+use std::ffi::{CStr, CString, OsStr, OsString};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// The user's home directory, as portable-pty finds it.
+fn home_dir(builder: &CommandBuilder) -> OsString {
+    if let Some(home) = builder.get_env("HOME") {
+        return home.to_owned();
+    }
+    let entry = unsafe { libc::getpwuid(libc::getuid()) };
+    if entry.is_null() {
+        return "/".into();
+    }
+    let dir = unsafe { CStr::from_ptr((*entry).pw_dir) };
+    OsStr::from_bytes(dir.to_bytes()).to_owned()
+}
+
+fn is_executable(path: &Path) -> bool {
+    let Ok(c_path) = CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    !path.is_dir() && unsafe { libc::access(c_path.as_ptr(), libc::X_OK) } == 0
+}
+
+/// Find `program` as a shell would: relative to `cwd` if it has a slash,
+/// else in the command's `PATH`.
+fn search_path(builder: &CommandBuilder, program: &OsStr, cwd: &Path) -> io::Result<PathBuf> {
+    let path = Path::new(program);
+    if path.is_absolute() {
+        return Ok(path.to_owned());
+    }
+    if program.as_bytes().contains(&b'/') {
+        return Ok(cwd.join(path));
+    }
+    let dirs = builder.get_env("PATH").unwrap_or_default();
+    std::env::split_paths(dirs)
+        .map(|dir| cwd.join(dir).join(program))
+        .find(|candidate| is_executable(candidate))
+        .ok_or_else(|| io::ErrorKind::NotFound.into())
+}
+
+/// Turn `builder` into the command portable-pty would run.
+pub(super) fn command(builder: &CommandBuilder) -> io::Result<Command> {
+    let home = home_dir(builder);
+    let cwd = builder
+        .get_cwd()
+        .filter(|dir| Path::new(dir).is_dir())
+        .unwrap_or(&home);
+    let shell = builder.get_shell();
+
+    let mut command = if builder.is_default_prog() {
+        // A login shell, as portable-pty starts it.
+        let name = shell.rsplit('/').next().unwrap_or(&shell);
+        let mut command = Command::new(&shell);
+        command.arg0(format!("-{name}"));
+        command
+    } else {
+        let argv = builder.get_argv();
+        let program = search_path(builder, &argv[0], Path::new(cwd))?;
+        let mut command = Command::new(program);
+        command.arg0(&argv[0]).args(&argv[1..]);
+        command
+    };
+    command.current_dir(cwd);
+    command.env_clear();
+    command.env("SHELL", &shell);
+    #[cfg(feature = "portable-pty")]
+    command.envs(builder.iter_full_env_as_str());
+    // Without portable-pty, variables that aren't UTF-8 make it through.
+    #[cfg(not(feature = "portable-pty"))]
+    command.envs(builder.iter_full_env());
+    Ok(command)
+}
+
+/// Mark every descriptor past stdio close-on-exec, so the child inherits
+/// none of the host's but `Command` can still report a failed `exec`.
+fn cloexec_from_3() {
+    #[cfg(target_os = "linux")]
+    {
+        const CLOSE_RANGE_CLOEXEC: libc::c_uint = 1 << 2;
+        let done = unsafe {
+            libc::syscall(
+                libc::SYS_close_range,
+                3 as libc::c_uint,
+                libc::c_uint::MAX,
+                CLOSE_RANGE_CLOEXEC,
+            )
+        };
+        if done == 0 {
+            return;
+        }
+    }
+    let max = unsafe { libc::sysconf(libc::_SC_OPEN_MAX) };
+    let max = if max > 0 { max.min(65536) } else { 1024 } as libc::c_int;
+    for fd in 3..max {
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    }
+}
+
+/// Put back the signal dispositions and mask a child starts with, whatever
+/// the host changed. Async-signal-safe, for `pre_exec`.
+fn default_signals() {
+    for signal in [
+        libc::SIGCHLD,
+        libc::SIGHUP,
+        libc::SIGINT,
+        libc::SIGQUIT,
+        libc::SIGTERM,
+        libc::SIGALRM,
+    ] {
+        unsafe { libc::signal(signal, libc::SIG_DFL) };
+    }
+    let empty: libc::sigset_t = unsafe { std::mem::zeroed() };
+    unsafe { libc::sigprocmask(libc::SIG_SETMASK, &empty, std::ptr::null_mut()) };
+}
+
+fn open_tty(tty: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY)
+        .open(tty)
+}
+
+/// Spawn `builder` on the terminal at `tty`, taking the steps `config`
+/// asks for in the child just before `exec`.
+pub(super) fn spawn(
+    tty: &Path,
+    builder: &CommandBuilder,
+    config: SpawnConfig,
+) -> io::Result<std::process::Child> {
+    let mut command = command(builder)?;
+    if let Some(argv0) = config.argv0() {
+        command.arg0(argv0);
+    }
+    let hand_over = config.credentials.hand_over(tty)?;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let namespaces = config.namespaces;
+    let stdin = open_tty(tty)?;
+    let stdout = stdin.try_clone()?;
+    let stderr = stdin.try_clone()?;
+    command
+        .stdin(Stdio::from(stdin))
+        .stdout(Stdio::from(stdout))
+        .stderr(Stdio::from(stderr));
+
+    let controlling_tty = builder.get_controlling_tty();
+    unsafe {
+        command.pre_exec(move || {
+            default_signals();
+            if libc::setsid() == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            #[allow(clippy::cast_lossless)]
+            if controlling_tty && libc::ioctl(0, libc::TIOCSCTTY as _, 0) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            cloexec_from_3();
+            config.pre_exec()
+        });
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let mut child = namespaces.spawn(|| command.spawn())?;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let mut child = command.spawn()?;
+    hand_over.keep();
+    child.stdin.take();
+    child.stdout.take();
+    child.stderr.take();
+    Ok(child)
+}
+
+/// Spawn `builder` with its standard streams on `stdio` instead of a
+/// terminal, in a process group of its own.
+pub(super) fn spawn_piped(
+    builder: &CommandBuilder,
+    stdio: [Stdio; 3],
+) -> io::Result<std::process::Child> {
+    let mut command = command(builder)?;
+    let [stdin, stdout, stderr] = stdio;
+    command
+        .stdin(stdin)
+        .stdout(stdout)
+        .stderr(stderr)
+        .process_group(0);
+    unsafe {
+        command.pre_exec(|| {
+            default_signals();
+            cloexec_from_3();
+            Ok(())
+        });
+    }
+    command.spawn()
+}
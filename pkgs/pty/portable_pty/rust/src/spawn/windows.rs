@@ -0,0 +1,60 @@
+//! What a spawn config does on Windows, and spawning without a console.
+//!
+//! Children inherit their error mode from this process as `CreateProcess`
+//! finds it, so the mode is set for the length of the spawn and put back.
+//! That's process-wide: other threads that fault meanwhile don't get the
+//! crash dialog either.
+
+use crate::pty::CommandBuilder;
+use std::ffi::OsStr;
+use std::io;
+use std::os::windows::process::CommandExt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::{Mutex, PoisonError};
+use winapi::um::errhandlingapi::{GetErrorMode, SetErrorMode};
+use winapi::um::winbase::{CREATE_NO_WINDOW, SEM_FAILCRITICALERRORS, SEM_NOGPFAULTERRORBOX};
+
+/// Held while the error mode is changed, so overlapping spawns don't
+/// restore each other's.
+static ERROR_MODE: Mutex<()> = Mutex::new(());
+
+/// Run `spawn` with crashes reported to the caller instead of Windows
+/// Error Reporting, which would hold the child to show a dialog or write
+/// a dump.
+pub(super) fn without_crash_reports<T>(spawn: impl FnOnce() -> T) -> T {
+    let _guard = ERROR_MODE.lock().unwrap_or_else(PoisonError::into_inner);
+    let previous = unsafe { GetErrorMode() };
+    unsafe { SetErrorMode(previous | SEM_FAILCRITICALERRORS | SEM_NOGPFAULTERRORBOX) };
+    let spawned = spawn();
+    unsafe { SetErrorMode(previous) };
+    spawned
+}
+
+/// Spawn `builder` with its standard streams on `stdio`, and no console
+/// window of its own.
+pub(super) fn spawn_piped(
+    builder: &CommandBuilder,
+    stdio: [Stdio; 3],
+) -> io::Result<std::process::Child> {
+    let argv = builder.get_argv();
+    let mut command = match argv.split_first() {
+        Some((program, args)) if !builder.is_default_prog() => {
+            let mut command = Command::new(program);
+            command.args(args);
+            command
+        }
+        _ => Command::new(builder.get_env("ComSpec").unwrap_or(OsStr::new("cmd.exe"))),
+    };
+    command.env_clear().envs(builder.iter_full_env_as_str());
+    if let Some(dir) = builder.get_cwd().filter(|dir| Path::new(dir).is_dir()) {
+        command.current_dir(dir);
+    }
+    let [stdin, stdout, stderr] = stdio;
+    command
+        .stdin(stdin)
+        .stdout(stdout)
+        .stderr(stderr)
+        .creation_flags(CREATE_NO_WINDOW);
+    command.spawn()
+}
@@ -0,0 +1,177 @@
+//! What the last spawn actually ran.
+//!
+//! A child doesn't always run quite the command it was given: the spawn
+//! config's `scrub_env` drops variables, a spawn policy may rewrite it,
+//! Android swaps in its own shell and home, and on a local Unix PTY the
+//! program is looked up in the command's `PATH`, a working directory that
+//! isn't one falls back to the home directory, and `SHELL` is set.
+//! `portable_pty_spawned_command` returns the outcome, for working out why
+//! a shell behaves differently here than in a terminal.
+//!
+//! Elsewhere — on Windows, and on handles without a local PTY, where the
+//! far end does its own lookup — the command is reported as it was handed
+//! over.
+
+use crate::pty::CommandBuilder;
+use crate::{PortablePty, PortablePtyBuffer, PortablePtyResult};
+use serde_json::{json, Map, Value};
+use std::ffi::{OsStr, OsString};
+
+/// The command a child was spawned with.
+pub(crate) struct Spawned {
+    /// The executable, found in `PATH` where that's known.
+    program: OsString,
+    argv: Vec<OsString>,
+    /// None if it started in this process's working directory.
+    cwd: Option<OsString>,
+    env: Vec<(OsString, OsString)>,
+}
+
+impl Spawned {
+    /// What spawning `builder` runs; `local` if it's spawned on a local
+    /// PTY, as portable-pty or `spawn::unix` would.
+    pub(crate) fn new(builder: &CommandBuilder, local: bool) -> Self {
+        #[cfg(unix)]
+        if let Some(command) = local.then(|| crate::spawn::resolve(builder)).flatten() {
+            let arg0 = match builder.get_argv().first() {
+                Some(arg0) => arg0.clone(),
+                // The default program, a login shell: `-bash`.
+                None => {
+                    let program = std::path::Path::new(command.get_program());
+                    let name = program.file_name().unwrap_or_default();
+                    let mut arg0 = OsString::from("-");
+                    arg0.push(name);
+                    arg0
+                }
+            };
+            let argv = std::iter::once(arg0)
+                .chain(command.get_args().map(OsStr::to_owned))
+                .collect();
+            return Spawned {
+                program: command.get_program().to_owned(),
+                argv,
+                cwd: command.get_current_dir().map(|dir| dir.into()),
+                env: command
+                    .get_envs()
+                    .filter_map(|(key, value)| Some((key.to_owned(), value?.to_owned())))
+                    .collect(),
+            };
+        }
+        let _ = local;
+        let argv = builder.get_argv().clone();
+        Spawned {
+            program: argv.first().cloned().unwrap_or_default(),
+            argv,
+            cwd: builder.get_cwd().cloned(),
+            env: builder
+                .iter_full_env_as_str()
+                .map(|(key, value)| (key.into(), value.into()))
+                .collect(),
+        }
+    }
+
+    /// Note the program was run under `argv0` rather than its own name.
+    pub(crate) fn rename(&mut self, argv0: &str) {
+        match self.argv.first_mut() {
+            Some(arg0) => *arg0 = argv0.into(),
+            None => self.argv.push(argv0.into()),
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        let text = |s: &OsStr| Value::from(s.to_string_lossy());
+        let env: Map<_, _> = self
+            .env
+            .iter()
+            .map(|(key, value)| (key.to_string_lossy().into_owned(), text(value)))
+            .collect();
+        json!({
+            "program": text(&self.program),
+            "argv": self.argv.iter().map(|arg| text(arg)).collect::<Vec<_>>(),
+            "cwd": self.cwd.as_deref().map(text),
+            "env": env,
+        })
+    }
+}
+
+/// Describe the command the handle's last child was actually spawned
+/// with, after `scrub_env`, any spawn policy rewrite, Android's shell and
+/// home, and on a local Unix PTY the `PATH` lookup, the fallback to the
+/// home directory and `SHELL`. Elsewhere it's the command as handed over.
+///
+/// - `out_json`: receives a UTF-8 JSON object, to free with
+///   `portable_pty_buffer_free`: `program`, `argv`, `cwd` (null for this
+///   process's) and `env`, an object of the child's whole environment.
+///   Bytes that aren't UTF-8 are replaced with U+FFFD.
+///
+/// Returns `ErrWait` if nothing has been spawned on the handle.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_spawned_command(
+    handle: *const PortablePty,
+    out_json: *mut PortablePtyBuffer,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        if out_json.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+        let Some(spawned) = pty.spawned.as_ref() else {
+            return PortablePtyResult::ErrWait;
+        };
+        let json = spawned.to_json().to_string();
+        unsafe { *out_json = PortablePtyBuffer::from_vec(json.into_bytes()) };
+        PortablePtyResult::Ok
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn spawned(handle: *const PortablePty) -> Value {
+        let mut out = PortablePtyBuffer::from_vec(Vec::new());
+        let result = portable_pty_spawned_command(handle, &mut out);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        let bytes = unsafe { std::slice::from_raw_parts(out.data, out.len) };
+        let json = serde_json::from_slice(bytes).unwrap();
+        crate::portable_pty_buffer_free(out);
+        json
+    }
+
+    #[test]
+    fn test_spawned_command() {
+        let mut handle = std::ptr::null_mut();
+        crate::portable_pty_open(24, 80, &mut handle);
+        let mut out = PortablePtyBuffer::from_vec(Vec::new());
+        let result = portable_pty_spawned_command(handle, &mut out);
+        assert!(matches!(result, PortablePtyResult::ErrWait));
+
+        let argv = [
+            c"sh".as_ptr(),
+            c"-c".as_ptr(),
+            c"exit 0".as_ptr(),
+            std::ptr::null(),
+        ];
+        let envp = [
+            c"PATH=/bin:/usr/bin".as_ptr(),
+            c"HOME=/".as_ptr(),
+            std::ptr::null(),
+        ];
+        crate::portable_pty_spawn(handle, c"sh".as_ptr(), argv.as_ptr(), envp.as_ptr());
+        let json = spawned(handle);
+        let program = json["program"].as_str().unwrap();
+        assert!(
+            program.starts_with('/') && program.ends_with("/sh"),
+            "{json}"
+        );
+        assert_eq!(json["argv"], json!(["sh", "-c", "exit 0"]));
+        // No directory was asked for, so it's the home directory.
+        assert_eq!(json["cwd"], "/");
+        assert_eq!(json["env"]["PATH"], "/bin:/usr/bin");
+        assert!(json["env"]["SHELL"].is_string(), "{json}");
+        crate::portable_pty_close(handle);
+    }
+}
@@ -0,0 +1,549 @@
+//! The SSH backend proper, on top of libssh2.
+//!
+//! libssh2 sessions aren't safe to drive from several threads at once, so
+//! after spawning a single pump thread owns the session: it copies channel
+//! output into a pipe the handle reads, and applies input, resizes and
+//! kills that the handle queues in [`Shared`]. The handle wakes it through
+//! a second pipe so it can sleep in `poll` on the socket meanwhile.
+
+use super::{shell_join, Config};
+use crate::mock::KILLED_STATUS;
+use crate::pty::{
+    Child, ChildKiller, CommandBuilder, ExitStatus, MasterPty, PtyPair, PtySize, SlavePty,
+};
+use crate::{PortablePty, PortablePtyResult};
+use ssh2::{Channel, CheckResult, ErrorCode, KnownHostFileKind, Session};
+use std::io::{ErrorKind, PipeReader, PipeWriter, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+
+/// libssh2's "would block" error code.
+const EAGAIN: i32 = -37;
+
+/// Terminal type requested when the command doesn't set `TERM`.
+const DEFAULT_TERM: &str = "xterm-256color";
+
+/// Requests queued for the pump thread.
+#[derive(Default)]
+struct Queued {
+    input: Vec<u8>,
+    resize: bool,
+    kill: bool,
+}
+
+/// Everything the pump thread takes over when the command is spawned.
+struct Connection {
+    session: Session,
+    /// Our handle on the session's socket, to poll.
+    socket: TcpStream,
+    output: PipeWriter,
+    wake: PipeReader,
+}
+
+/// State shared between the handle and the pump thread.
+struct Shared {
+    size: Mutex<PtySize>,
+    queued: Mutex<Queued>,
+    /// Set until spawn hands the connection to the pump thread.
+    connection: Mutex<Option<Connection>>,
+    #[cfg_attr(not(unix), allow(dead_code))]
+    wake: PipeWriter,
+    /// A wake-up byte is in the pipe and not yet consumed.
+    woken: AtomicBool,
+    exit: Mutex<Option<u32>>,
+    /// Signalled when `exit` is set.
+    exited: Condvar,
+}
+
+impl Shared {
+    fn queued(&self) -> MutexGuard<'_, Queued> {
+        self.queued.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Queue a request and wake the pump thread.
+    fn queue(&self, f: impl FnOnce(&mut Queued)) {
+        f(&mut self.queued());
+        // Elsewhere the pump thread checks the queue on a timer instead.
+        #[cfg(unix)]
+        if !self.woken.swap(true, Ordering::AcqRel) {
+            let _ = (&self.wake).write_all(&[0]);
+        }
+    }
+
+    fn set_exit(&self, code: u32) {
+        self.exit
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get_or_insert(code);
+        self.exited.notify_all();
+    }
+}
+
+/// Connect, verify the host key and authenticate.
+fn connect(config: &Config) -> Result<(Session, TcpStream), PortablePtyResult> {
+    let addrs = (config.host.as_str(), config.port)
+        .to_socket_addrs()
+        .map_err(|_| PortablePtyResult::ErrOpen)?;
+    let tcp = addrs
+        .into_iter()
+        .find_map(|addr| TcpStream::connect_timeout(&addr, config.timeout).ok())
+        .ok_or(PortablePtyResult::ErrOpen)?;
+
+    let mut session = Session::new().map_err(|_| PortablePtyResult::ErrOpen)?;
+    session.set_tcp_stream(tcp.try_clone().map_err(|_| PortablePtyResult::ErrOpen)?);
+    session.set_timeout(config.timeout.as_millis().try_into().unwrap_or(u32::MAX));
+    session
+        .handshake()
+        .map_err(|_| PortablePtyResult::ErrOpen)?;
+
+    if !host_key_trusted(&session, config) {
+        return Err(PortablePtyResult::ErrAuth);
+    }
+    authenticate(&session, config);
+    if !session.authenticated() {
+        return Err(PortablePtyResult::ErrAuth);
+    }
+    Ok((session, tcp))
+}
+
+fn host_key_trusted(session: &Session, config: &Config) -> bool {
+    let Some((key, _)) = session.host_key() else {
+        return false;
+    };
+    let Ok(mut known) = session.known_hosts() else {
+        return false;
+    };
+    if let Some(path) = &config.known_hosts {
+        // A missing file just means no host is known yet.
+        let _ = known.read_file(path, KnownHostFileKind::OpenSSH);
+    }
+    match known.check_port(&config.host, config.port, key) {
+        CheckResult::Match => true,
+        CheckResult::NotFound => config.accept_unknown_host,
+        CheckResult::Mismatch | CheckResult::Failure => false,
+    }
+}
+
+/// Try each configured credential in turn until one is accepted.
+fn authenticate(session: &Session, config: &Config) {
+    let user = config.user.as_str();
+    if session.userauth_agent(user).is_ok() {
+        return;
+    }
+    if let Some(identity) = &config.identity_file {
+        let passphrase = config.passphrase.as_deref();
+        if session
+            .userauth_pubkey_file(user, None, identity, passphrase)
+            .is_ok()
+        {
+            return;
+        }
+    }
+    if let Some(password) = &config.password {
+        let _ = session.userauth_password(user, password);
+    }
+}
+
+pub(super) fn open(
+    config: &Config,
+    rows: u16,
+    cols: u16,
+) -> Result<Box<PortablePty>, PortablePtyResult> {
+    let (session, tcp) = connect(config)?;
+    let (output_reader, output) = std::io::pipe().map_err(|_| PortablePtyResult::ErrOpen)?;
+    let (wake_reader, wake) = std::io::pipe().map_err(|_| PortablePtyResult::ErrOpen)?;
+
+    let shared = Arc::new(Shared {
+        size: Mutex::new(PtySize {
+            rows,
+            cols,
+            pixel_width: 0,
+            pixel_height: 0,
+        }),
+        queued: Mutex::default(),
+        connection: Mutex::new(Some(Connection {
+            session,
+            socket: tcp,
+            output,
+            wake: wake_reader,
+        })),
+        wake,
+        woken: AtomicBool::new(false),
+        exit: Mutex::new(None),
+        exited: Condvar::new(),
+    });
+    let pair = PtyPair {
+        slave: Box::new(SshSlave {
+            shared: Arc::clone(&shared),
+        }),
+        master: Box::new(SshMaster {
+            shared,
+            reader: output_reader,
+        }),
+    };
+    PortablePty::from_pair(pair)
+}
+
+struct SshMaster {
+    shared: Arc<Shared>,
+    reader: PipeReader,
+}
+
+impl Drop for SshMaster {
+    fn drop(&mut self) {
+        self.shared.queue(|q| q.kill = true);
+    }
+}
+
+impl MasterPty for SshMaster {
+    fn resize(&self, size: PtySize) -> anyhow::Result<()> {
+        *self
+            .shared
+            .size
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = size;
+        self.shared.queue(|q| q.resize = true);
+        Ok(())
+    }
+
+    fn get_size(&self) -> anyhow::Result<PtySize> {
+        Ok(*self
+            .shared
+            .size
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner))
+    }
+
+    fn try_clone_reader(&self) -> anyhow::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(self.reader.try_clone()?))
+    }
+
+    fn take_writer(&self) -> anyhow::Result<Box<dyn Write + Send>> {
+        Ok(Box::new(SshWriter {
+            shared: Arc::clone(&self.shared),
+        }))
+    }
+
+    #[cfg(unix)]
+    fn process_group_leader(&self) -> Option<libc::pid_t> {
+        None
+    }
+
+    #[cfg(unix)]
+    fn as_raw_fd(&self) -> Option<std::os::fd::RawFd> {
+        use std::os::fd::AsRawFd;
+        Some(self.reader.as_raw_fd())
+    }
+
+    #[cfg(unix)]
+    fn tty_name(&self) -> Option<std::path::PathBuf> {
+        None
+    }
+}
+
+/// Queues input for the pump thread to send.
+struct SshWriter {
+    shared: Arc<Shared>,
+}
+
+impl Write for SshWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self
+            .shared
+            .exit
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .is_some()
+        {
+            return Err(ErrorKind::BrokenPipe.into());
+        }
+        self.shared.queue(|q| q.input.extend_from_slice(buf));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+struct SshSlave {
+    shared: Arc<Shared>,
+}
+
+impl SlavePty for SshSlave {
+    fn spawn_command(&self, cmd: CommandBuilder) -> anyhow::Result<Box<dyn Child + Send + Sync>> {
+        let connection = self
+            .shared
+            .connection
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take()
+            .ok_or_else(|| anyhow::anyhow!("a command was already spawned on this session"))?;
+
+        let size = *self
+            .shared
+            .size
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let channel = start_command(&connection.session, &cmd, size)?;
+
+        // Anything typed before the spawn goes first.
+        self.shared.queue(|_| {});
+        let shared = Arc::clone(&self.shared);
+        crate::lifecycle::spawn_thread("portable-pty-ssh", move || {
+            pump(shared, connection, channel)
+        })?;
+        Ok(Box::new(SshChild {
+            shared: Arc::clone(&self.shared),
+        }))
+    }
+}
+
+/// Open a channel with a PTY and run `cmd` on it.
+fn start_command(
+    session: &Session,
+    cmd: &CommandBuilder,
+    size: PtySize,
+) -> anyhow::Result<Channel> {
+    let term = cmd
+        .get_env("TERM")
+        .and_then(|t| t.to_str())
+        .unwrap_or(DEFAULT_TERM);
+
+    let mut channel = session.channel_session()?;
+    channel.request_pty(
+        term,
+        None,
+        Some((
+            size.cols.into(),
+            size.rows.into(),
+            size.pixel_width.into(),
+            size.pixel_height.into(),
+        )),
+    )?;
+    for (key, value) in cmd.iter_extra_env_as_str() {
+        // Servers refuse variables they don't accept; that's not fatal.
+        let _ = channel.setenv(key, value);
+    }
+
+    let argv = cmd
+        .get_argv()
+        .iter()
+        .map(|a| a.to_str())
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| anyhow::anyhow!("command line isn't valid UTF-8"))?;
+    let mut line = shell_join(argv);
+    if let Some(cwd) = cmd.get_cwd() {
+        let cwd = cwd
+            .to_str()
+            .ok_or_else(|| anyhow::anyhow!("working directory isn't valid UTF-8"))?;
+        line = format!("cd {} && exec {line}", shell_join([cwd]));
+    }
+    channel.exec(&line)?;
+    Ok(channel)
+}
+
+fn would_block(e: &ssh2::Error) -> bool {
+    e.code() == ErrorCode::Session(EAGAIN)
+}
+
+/// Shuttle data between the channel and the handle until the remote
+/// command exits or the handle kills it.
+fn pump(shared: Arc<Shared>, connection: Connection, mut channel: Channel) {
+    // If the handle goes away mid-write, get EPIPE rather than a
+    // process-wide SIGPIPE.
+    #[cfg(unix)]
+    crate::block_sigpipe();
+
+    let Connection {
+        session,
+        socket,
+        mut output,
+        mut wake,
+    } = connection;
+    session.set_blocking(false);
+
+    let mut unsent = Vec::new();
+    let mut resize = false;
+    let mut buf = [0u8; 16 * 1024];
+    let status = 'pump: loop {
+        loop {
+            match channel.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    if output.write_all(&buf[..n]).is_err() {
+                        break 'pump KILLED_STATUS;
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(_) => break 'pump remote_status(&session, &mut channel),
+            }
+        }
+        if channel.eof() {
+            break remote_status(&session, &mut channel);
+        }
+
+        shared.woken.store(false, Ordering::Release);
+        let kill = {
+            let mut queued = shared.queued();
+            unsent.append(&mut queued.input);
+            resize |= std::mem::take(&mut queued.resize);
+            queued.kill
+        };
+        if kill {
+            let _ = channel.close();
+            break KILLED_STATUS;
+        }
+        while !unsent.is_empty() {
+            match channel.write(&unsent) {
+                Ok(n) => {
+                    unsent.drain(..n);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(_) => break 'pump remote_status(&session, &mut channel),
+            }
+        }
+        if resize {
+            let size = *shared.size.lock().unwrap_or_else(PoisonError::into_inner);
+            resize = match channel.request_pty_size(
+                size.cols.into(),
+                size.rows.into(),
+                Some(size.pixel_width.into()),
+                Some(size.pixel_height.into()),
+            ) {
+                Err(e) => would_block(&e),
+                Ok(()) => false,
+            };
+        }
+
+        let busy = !unsent.is_empty() || resize;
+        wait(&socket, &mut wake, busy);
+    };
+
+    // Dropping `output` gives the reader EOF.
+    drop(output);
+    shared.set_exit(status);
+}
+
+/// Sleep until the socket or the wake pipe is readable. With `busy`, only
+/// back off briefly: libssh2 doesn't say when a blocked write can resume.
+#[cfg(unix)]
+fn wait(socket: &TcpStream, wake: &mut PipeReader, busy: bool) {
+    use std::os::fd::AsRawFd;
+
+    let mut fds = [
+        libc::pollfd {
+            fd: socket.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        },
+        libc::pollfd {
+            fd: wake.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        },
+    ];
+    let timeout = if busy { 10 } else { 1000 };
+    unsafe {
+        libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout);
+    }
+    if fds[1].revents & libc::POLLIN != 0 {
+        let mut drain = [0u8; 64];
+        let _ = wake.read(&mut drain);
+    }
+}
+
+#[cfg(not(unix))]
+fn wait(_socket: &TcpStream, _wake: &mut PipeReader, _busy: bool) {
+    std::thread::sleep(std::time::Duration::from_millis(10));
+}
+
+/// The remote command's exit status, once the channel has closed.
+fn remote_status(session: &Session, channel: &mut Channel) -> u32 {
+    session.set_blocking(true);
+    let _ = channel.wait_close();
+    if let Some(signal) = channel.exit_signal().ok().and_then(|s| s.exit_signal) {
+        return 128 + signal_number(&signal);
+    }
+    channel.exit_status().map_or(1, |code| code as u32)
+}
+
+/// Number of a signal reported by name, for the 128 + signal convention.
+fn signal_number(name: &str) -> u32 {
+    match name {
+        "HUP" => 1,
+        "INT" => 2,
+        "QUIT" => 3,
+        "ILL" => 4,
+        "ABRT" => 6,
+        "FPE" => 8,
+        "KILL" => 9,
+        "SEGV" => 11,
+        "PIPE" => 13,
+        "ALRM" => 14,
+        "TERM" => 15,
+        _ => 127,
+    }
+}
+
+/// Stands in for the remote process.
+#[derive(Clone)]
+struct SshChild {
+    shared: Arc<Shared>,
+}
+
+impl std::fmt::Debug for SshChild {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SshChild").finish_non_exhaustive()
+    }
+}
+
+impl ChildKiller for SshChild {
+    fn kill(&mut self) -> std::io::Result<()> {
+        self.shared.queue(|q| q.kill = true);
+        Ok(())
+    }
+
+    fn clone_killer(&self) -> Box<dyn ChildKiller + Send + Sync> {
+        Box::new(self.clone())
+    }
+}
+
+impl Child for SshChild {
+    fn try_wait(&mut self) -> std::io::Result<Option<ExitStatus>> {
+        let exit = *self
+            .shared
+            .exit
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        Ok(exit.map(ExitStatus::with_exit_code))
+    }
+
+    fn wait(&mut self) -> std::io::Result<ExitStatus> {
+        let mut exit = self
+            .shared
+            .exit
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        loop {
+            if let Some(code) = *exit {
+                return Ok(ExitStatus::with_exit_code(code));
+            }
+            exit = self
+                .shared
+                .exited
+                .wait(exit)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    fn process_id(&self) -> Option<u32> {
+        None
+    }
+
+    #[cfg(windows)]
+    fn as_raw_handle(&self) -> Option<std::os::windows::io::RawHandle> {
+        None
+    }
+}
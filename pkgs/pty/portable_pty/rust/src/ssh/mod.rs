@@ -0,0 +1,234 @@
+//! Remote sessions over SSH.
+//!
+//! `portable_pty_open_ssh` connects and authenticates to a host and returns
+//! an ordinary handle. `portable_pty_spawn` on it opens a session channel
+//! with a remote PTY and runs the command there; reads, writes, resizes,
+//! waits and kills then map onto the channel, so the rest of the API works
+//! unchanged.
+//!
+//! Needs the `ssh` feature; without it `portable_pty_open_ssh` always fails
+//! with `ErrUnsupported`.
+
+#[cfg(feature = "ssh")]
+mod backend;
+
+use crate::{PortablePty, PortablePtyResult};
+use std::ffi::{c_char, CStr};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Connection settings, parsed from the JSON passed to
+/// `portable_pty_open_ssh`.
+#[cfg_attr(not(feature = "ssh"), allow(dead_code))]
+#[derive(Debug, PartialEq)]
+struct Config {
+    host: String,
+    port: u16,
+    user: String,
+    password: Option<String>,
+    identity_file: Option<PathBuf>,
+    passphrase: Option<String>,
+    /// OpenSSH `known_hosts` file to verify the host key against.
+    known_hosts: Option<PathBuf>,
+    /// Connect to hosts missing from `known_hosts` instead of refusing.
+    accept_unknown_host: bool,
+    timeout: Duration,
+}
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn parse_config(json: &str) -> Option<Config> {
+    config_from_value(&serde_json::from_str(json).ok()?)
+}
+
+fn config_from_value(config: &serde_json::Value) -> Option<Config> {
+    let text = |key: &str| match config.get(key) {
+        Some(v) => v.as_str().map(|s| Some(s.to_owned())),
+        None => Some(None),
+    };
+    let home = || std::env::var_os("HOME").map(PathBuf::from);
+
+    let user = match text("user")? {
+        Some(user) => user,
+        None => std::env::var("USER").ok()?,
+    };
+    let known_hosts = match text("known_hosts")? {
+        Some(path) => Some(PathBuf::from(path)),
+        None => home().map(|h| h.join(".ssh").join("known_hosts")),
+    };
+    let port = match config.get("port") {
+        Some(v) => v.as_u64()?.try_into().ok()?,
+        None => 22,
+    };
+    let timeout = match config.get("timeout_ms") {
+        Some(v) => Duration::from_millis(v.as_u64()?),
+        None => DEFAULT_TIMEOUT,
+    };
+    let accept_unknown_host = match config.get("accept_unknown_host") {
+        Some(v) => v.as_bool()?,
+        None => false,
+    };
+
+    Some(Config {
+        host: text("host")??,
+        port,
+        user,
+        password: text("password")?,
+        identity_file: text("identity_file")?.map(PathBuf::from),
+        passphrase: text("passphrase")?,
+        known_hosts,
+        accept_unknown_host,
+        timeout,
+    })
+}
+
+/// Join `argv` into a command line for the remote shell, quoting each
+/// argument that needs it.
+#[cfg_attr(not(feature = "ssh"), allow(dead_code))]
+fn shell_join<'a>(argv: impl IntoIterator<Item = &'a str>) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "_@%+=:,./-".contains(c);
+    argv.into_iter()
+        .map(|arg| {
+            if !arg.is_empty() && arg.chars().all(safe) {
+                arg.to_owned()
+            } else {
+                format!("'{}'", arg.replace('\'', r"'\''"))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Connect to an SSH server and open a handle for a remote session.
+///
+/// - `config`: null-terminated JSON object:
+///   - `host` (required), `port` (default 22), `user` (default `$USER`).
+///   - `password`, `identity_file`, `passphrase`: credentials. The SSH
+///     agent is tried first, then the identity file, then the password.
+///   - `known_hosts`: OpenSSH known-hosts file to check the host key
+///     against (default `~/.ssh/known_hosts`).
+///   - `accept_unknown_host`: connect to hosts that aren't in the file
+///     (default false). A changed key is always refused.
+///   - `timeout_ms`: limit for connecting and each setup step (default
+///     10000).
+/// - `rows`, `cols`: initial size of the remote PTY.
+/// - `out`: receives the new handle.
+///
+/// Nothing runs until `portable_pty_spawn`, whose command line is quoted
+/// and run by the remote user's shell. Environment entries are sent as
+/// `env` requests, which most servers only accept for `LANG`, `LC_*` and
+/// whatever their `AcceptEnv` allows; `TERM` selects the remote terminal
+/// type. The exit status is the remote command's, or 128 + the signal
+/// number if it was killed by a signal. `portable_pty_kill` closes the
+/// channel, which the server usually delivers as `SIGHUP`.
+///
+/// Returns `ErrOpen` if the config is malformed or the host can't be
+/// reached, `ErrAuth` if the host key was refused or no credential was
+/// accepted, and `ErrUnsupported` in builds without the `ssh` feature.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_open_ssh(
+    config: *const c_char,
+    rows: u16,
+    cols: u16,
+    out: *mut *mut PortablePty,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        if config.is_null() || out.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+        let config = match unsafe { CStr::from_ptr(config) }
+            .to_str()
+            .ok()
+            .and_then(parse_config)
+        {
+            Some(c) => c,
+            None => return PortablePtyResult::ErrOpen,
+        };
+        match open(config, rows, cols) {
+            Ok(handle) => {
+                unsafe {
+                    *out = crate::lifecycle::register(handle);
+                }
+                PortablePtyResult::Ok
+            }
+            Err(e) => e,
+        }
+    })
+}
+
+/// Connect as `config` describes: the settings `portable_pty_open_ssh`
+/// takes, plus `rows` and `cols`.
+pub(crate) fn open_config(
+    config: &serde_json::Value,
+) -> Result<Box<PortablePty>, PortablePtyResult> {
+    let size = crate::backend::parse_size(config).ok_or(PortablePtyResult::ErrOpen)?;
+    let config = config_from_value(config).ok_or(PortablePtyResult::ErrOpen)?;
+    open(config, size.rows, size.cols)
+}
+
+#[cfg(feature = "ssh")]
+fn open(config: Config, rows: u16, cols: u16) -> Result<Box<PortablePty>, PortablePtyResult> {
+    backend::open(&config, rows, cols)
+}
+
+#[cfg(not(feature = "ssh"))]
+fn open(_config: Config, _rows: u16, _cols: u16) -> Result<Box<PortablePty>, PortablePtyResult> {
+    Err(PortablePtyResult::ErrUnsupported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parses_config() {
+        let config = parse_config(
+            r#"{"host": "example.com", "port": 2222, "user": "me",
+                "identity_file": "/keys/id", "known_hosts": "/kh",
+                "accept_unknown_host": true, "timeout_ms": 500}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            Config {
+                host: "example.com".into(),
+                port: 2222,
+                user: "me".into(),
+                password: None,
+                identity_file: Some("/keys/id".into()),
+                passphrase: None,
+                known_hosts: Some("/kh".into()),
+                accept_unknown_host: true,
+                timeout: Duration::from_millis(500),
+            }
+        );
+
+        for bad in ["{}", r#"{"host": 1}"#, r#"{"host": "h", "port": 70000}"#] {
+            assert_eq!(parse_config(bad), None, "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_shell_join_quotes_when_needed() {
+        assert_eq!(
+            shell_join(["ls", "-la", "my dir", "it's", ""]),
+            r"ls -la 'my dir' 'it'\''s' ''"
+        );
+    }
+
+    #[test]
+    fn test_unreachable_host_fails_to_open() {
+        let config = std::ffi::CString::new(
+            r#"{"host": "127.0.0.1", "port": 1, "user": "me", "timeout_ms": 1000}"#,
+        )
+        .unwrap();
+        let mut handle = std::ptr::null_mut();
+        let result = portable_pty_open_ssh(config.as_ptr(), 24, 80, &mut handle);
+        if cfg!(feature = "ssh") {
+            assert!(matches!(result, PortablePtyResult::ErrOpen));
+        } else {
+            assert!(matches!(result, PortablePtyResult::ErrUnsupported));
+        }
+        assert!(handle.is_null());
+    }
+}
@@ -0,0 +1,171 @@
+//! Reads and writes over several buffers at once.
+//!
+//! `portable_pty_readv` and `portable_pty_writev` take an array of buffers,
+//! like `readv(2)` and `writev(2)`, so a binding moving a lot of data, or
+//! data in pieces such as a header and a body, makes one call for it
+//! rather than one per buffer. A read takes one read's worth of output and
+//! spreads it over the buffers in order; a write sends every buffer in
+//! full, in order, before anything else written to the handle.
+
+use crate::PortablePty;
+use std::sync::atomic::Ordering;
+
+/// Most output a single `portable_pty_readv` takes, however large its
+/// buffers.
+const MAX_READ: usize = 1024 * 1024;
+
+/// One buffer of a vectored read or write.
+#[repr(C)]
+pub struct PortablePtyIoVec {
+    /// Start of the buffer; only read from by `portable_pty_writev`. May be
+    /// NULL if `len` is 0.
+    pub data: *mut u8,
+    pub len: usize,
+}
+
+/// The `count` buffers at `iov`, or None if any is NULL with a length.
+fn buffers<'a>(iov: *const PortablePtyIoVec, count: usize) -> Option<&'a [PortablePtyIoVec]> {
+    if iov.is_null() {
+        return None;
+    }
+    let iov = unsafe { std::slice::from_raw_parts(iov, count) };
+    iov.iter()
+        .all(|v| !v.data.is_null() || v.len == 0)
+        .then_some(iov)
+}
+
+/// Read output into the `count` buffers at `iov`, filling each before the
+/// next, as `portable_pty_read` would into one buffer as large as them all
+/// (up to 1 MiB).
+///
+/// Returns the number of bytes read, 0 at end of file, or -1 on error,
+/// including when the buffers have no room at all.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_readv(
+    handle: *mut PortablePty,
+    iov: *const PortablePtyIoVec,
+    count: usize,
+) -> i64 {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_mut() } {
+            Some(p) => p,
+            None => return -1,
+        };
+        let Some(iov) = buffers(iov, count) else {
+            return -1;
+        };
+        let room = iov.iter().map(|v| v.len).sum::<usize>().min(MAX_READ);
+        if room == 0 {
+            return -1;
+        }
+
+        let mut read = vec![0u8; room];
+        let result = match pty.exclusive_reader.load(Ordering::Relaxed) {
+            true => pty.read_output_exclusive(&mut read),
+            false => pty.read_output(&mut read),
+        };
+        let Ok(n) = result else {
+            return -1;
+        };
+        let mut rest = &read[..n];
+        for v in iov {
+            if rest.is_empty() {
+                break;
+            }
+            let (part, after) = rest.split_at(v.len.min(rest.len()));
+            unsafe { std::ptr::copy_nonoverlapping(part.as_ptr(), v.data, part.len()) };
+            rest = after;
+        }
+        n as i64
+    })
+}
+
+/// Write all of the `count` buffers at `iov` to the child, in order.
+///
+/// Nothing else written to the handle lands between them, and they're
+/// flushed together as `portable_pty_write` would flush one write.
+///
+/// Returns the number of bytes written, all of them, or -1 on error, in
+/// which case some of them may have been.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_writev(
+    handle: *const PortablePty,
+    iov: *const PortablePtyIoVec,
+    count: usize,
+) -> i64 {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return -1,
+        };
+        let Some(iov) = buffers(iov, count) else {
+            return -1;
+        };
+        let parts: Vec<&[u8]> = iov
+            .iter()
+            .filter(|v| v.len > 0)
+            .map(|v| unsafe { std::slice::from_raw_parts(v.data, v.len) })
+            .collect();
+        match pty.write_whole(&parts) {
+            Ok(()) => parts.iter().map(|part| part.len() as i64).sum(),
+            Err(_) => -1,
+        }
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::tests::open_and_spawn;
+
+    #[test]
+    fn test_writev_and_readv() {
+        let handle = open_and_spawn("cat", &["cat"]);
+        let (mut head, mut body) = (*b"hello ", *b"world\n");
+        let iov = [
+            PortablePtyIoVec {
+                data: head.as_mut_ptr(),
+                len: head.len(),
+            },
+            PortablePtyIoVec {
+                data: std::ptr::null_mut(),
+                len: 0,
+            },
+            PortablePtyIoVec {
+                data: body.as_mut_ptr(),
+                len: body.len(),
+            },
+        ];
+        assert_eq!(portable_pty_writev(handle, iov.as_ptr(), iov.len()), 12);
+
+        // cat's echo, then its output: "hello world\r\n" twice.
+        let expected = b"hello world\r\nhello world\r\n";
+        let (mut first, mut second) = ([0u8; 4], [0u8; 64]);
+        let mut output = Vec::new();
+        while output.len() < expected.len() {
+            let iov = [
+                PortablePtyIoVec {
+                    data: first.as_mut_ptr(),
+                    len: first.len(),
+                },
+                PortablePtyIoVec {
+                    data: second.as_mut_ptr(),
+                    len: second.len(),
+                },
+            ];
+            let n = portable_pty_readv(handle, iov.as_ptr(), iov.len());
+            assert!(n > 0);
+            let n = n as usize;
+            output.extend_from_slice(&first[..n.min(4)]);
+            output.extend_from_slice(&second[..n.saturating_sub(4)]);
+        }
+        assert_eq!(output, expected);
+
+        let nothing = [PortablePtyIoVec {
+            data: std::ptr::null_mut(),
+            len: 1,
+        }];
+        assert_eq!(portable_pty_readv(handle, nothing.as_ptr(), 1), -1);
+        crate::portable_pty_close(handle);
+    }
+}
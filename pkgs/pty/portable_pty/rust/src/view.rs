@@ -0,0 +1,192 @@
+//! The screen as the handle's output draws it.
+//!
+//! `portable_pty_screen_start` runs everything the handle reads from then
+//! on through the headless screen model (see `screen`), and resizes it
+//! with the handle, so questions about what's on screen — where the links
+//! are, what counts as a word — get the same answer here as in the UI
+//! without the embedder shipping its grid back across FFI.
+//!
+//! Lines are numbered from the first the model had and keep their number
+//! as the scrollback moves; `portable_pty_screen_lines` gives the range
+//! still kept, the screen itself being the last `rows` of it. While the
+//! child is on the alternate screen, that's what the screen lines hold.
+//!
+//! Tracking is off until started, and needs the `screen` feature; without
+//! it the entry points return `ErrUnsupported`.
+
+use crate::{PortablePty, PortablePtyResult};
+#[cfg(feature = "screen")]
+use std::sync::{Mutex, PoisonError};
+
+#[cfg(feature = "screen")]
+use crate::screen::Screen;
+
+/// A handle's screen model, while one is tracked.
+#[derive(Default)]
+pub(crate) struct View {
+    #[cfg(feature = "screen")]
+    screen: Mutex<Option<Screen>>,
+}
+
+impl View {
+    /// Apply output read off the handle.
+    pub(crate) fn feed(&self, bytes: &[u8]) {
+        #[cfg(feature = "screen")]
+        if let Some(screen) = self.lock().as_mut() {
+            screen.feed(bytes);
+        }
+        #[cfg(not(feature = "screen"))]
+        let _ = bytes;
+    }
+
+    /// Follow the handle being resized.
+    pub(crate) fn resize(&self, rows: u16, cols: u16) {
+        #[cfg(feature = "screen")]
+        if let Some(screen) = self.lock().as_mut() {
+            screen.resize(rows, cols);
+        }
+        #[cfg(not(feature = "screen"))]
+        let _ = (rows, cols);
+    }
+
+    #[cfg(feature = "screen")]
+    fn lock(&self) -> std::sync::MutexGuard<'_, Option<Screen>> {
+        self.screen.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Run `f` on the tracked screen; `ErrUnsupported` if there's none.
+    #[cfg(feature = "screen")]
+    pub(crate) fn with<T>(&self, f: impl FnOnce(&Screen) -> T) -> Result<T, PortablePtyResult> {
+        match self.lock().as_ref() {
+            Some(screen) => Ok(f(screen)),
+            None => Err(PortablePtyResult::ErrUnsupported),
+        }
+    }
+}
+
+/// Start tracking the screen, from a blank one of the handle's size.
+/// Starting again starts afresh.
+///
+/// - `scrollback`: lines kept above the screen once they scroll off.
+///
+/// Returns `ErrSize` if the handle's size can't be read, and
+/// `ErrUnsupported` without the `screen` feature.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_screen_start(
+    handle: *const PortablePty,
+    scrollback: usize,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        #[cfg(feature = "screen")]
+        {
+            let Ok(size) = pty.master.get_size() else {
+                return PortablePtyResult::ErrSize;
+            };
+            *pty.view.lock() = Some(Screen::new(size.rows, size.cols, Some(scrollback)));
+            PortablePtyResult::Ok
+        }
+        #[cfg(not(feature = "screen"))]
+        {
+            let _ = (pty, scrollback);
+            PortablePtyResult::ErrUnsupported
+        }
+    })
+}
+
+/// Stop tracking the screen and free it. Harmless if it isn't tracked.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_screen_stop(handle: *const PortablePty) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        #[cfg(feature = "screen")]
+        pty.view.lock().take();
+        #[cfg(not(feature = "screen"))]
+        let _ = pty;
+        PortablePtyResult::Ok
+    })
+}
+
+/// Get the numbers of the lines the tracked screen still keeps.
+///
+/// - `out_first`: receives the number of the oldest line kept.
+/// - `out_count`: receives how many are kept, scrollback and screen.
+///
+/// Returns `ErrUnsupported` if the screen isn't tracked.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_screen_lines(
+    handle: *const PortablePty,
+    out_first: *mut u64,
+    out_count: *mut u64,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        if out_first.is_null() || out_count.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+        #[cfg(feature = "screen")]
+        match pty
+            .view
+            .with(|screen| (screen.first_line(), screen.line_count()))
+        {
+            Ok((first, count)) => {
+                unsafe {
+                    *out_first = first as u64;
+                    *out_count = count as u64;
+                }
+                PortablePtyResult::Ok
+            }
+            Err(e) => e,
+        }
+        #[cfg(not(feature = "screen"))]
+        {
+            let _ = pty;
+            PortablePtyResult::ErrUnsupported
+        }
+    })
+}
+
+#[cfg(all(test, unix, feature = "screen"))]
+mod tests {
+    use super::*;
+    use crate::tests::read_string;
+
+    #[test]
+    fn test_tracks_output_and_size() {
+        let mut handle = std::ptr::null_mut();
+        crate::loopback::portable_pty_open_loopback(3, 10, &mut handle);
+        let (mut first, mut count) = (0, 0);
+        let result = portable_pty_screen_lines(handle, &mut first, &mut count);
+        assert!(matches!(result, PortablePtyResult::ErrUnsupported));
+
+        assert!(matches!(
+            portable_pty_screen_start(handle, 100),
+            PortablePtyResult::Ok
+        ));
+        let output = "one\r\ntwo\r\nthree\r\nfour";
+        crate::loopback::portable_pty_loopback_write(handle, output.as_ptr(), output.len());
+        read_string(handle);
+        portable_pty_screen_lines(handle, &mut first, &mut count);
+        assert_eq!((first, count), (0, 4));
+
+        crate::portable_pty_resize(handle, 2, 10);
+        portable_pty_screen_lines(handle, &mut first, &mut count);
+        assert_eq!((first, count), (0, 4));
+        let text = unsafe { &*handle }.view.with(Screen::text).ok().unwrap();
+        assert_eq!(text, "one\ntwo\nthree\nfour\n");
+
+        portable_pty_screen_stop(handle);
+        let result = portable_pty_screen_lines(handle, &mut first, &mut count);
+        assert!(matches!(result, PortablePtyResult::ErrUnsupported));
+        crate::portable_pty_close(handle);
+    }
+}
@@ -0,0 +1,127 @@
+//! WebAssembly stand-ins.
+//!
+//! There are no PTYs or processes to wrap on WebAssembly; portable-pty's
+//! API comes from [`crate::shim`], as in builds without it. This module
+//! provides in-memory pipes in place of `std::io::pipe`, so every entry
+//! point still exists in a wasm32 build and a single Dart codebase can bind
+//! the same API on web and desktop.
+//!
+//! Local PTYs report `ErrUnsupported` (see [`crate::capabilities`]). The
+//! mock, loopback and replay backends work, though mock and replay run
+//! their scripts on a thread and so need a target with threads, such as
+//! `wasm32-wasip1-threads`; elsewhere opening them fails with `ErrOpen`.
+//! Build with `--no-default-features` where zstd's C sources can't be
+//! compiled.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+
+// ---------------------------------------------------------------------------
+// In-memory pipes
+// ---------------------------------------------------------------------------
+
+#[derive(Default)]
+struct PipeState {
+    buffer: VecDeque<u8>,
+    readers: usize,
+    writers: usize,
+}
+
+#[derive(Default)]
+struct Pipe {
+    state: Mutex<PipeState>,
+    readable: Condvar,
+}
+
+impl Pipe {
+    fn state(&self) -> std::sync::MutexGuard<'_, PipeState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Read end of an in-memory pipe. Reads block until data arrives, and
+/// return 0 once every writer is gone.
+pub struct PipeReader(Arc<Pipe>);
+
+/// Write end of an in-memory pipe. Writes never block, and fail with
+/// `BrokenPipe` once every reader is gone.
+pub struct PipeWriter(Arc<Pipe>);
+
+/// Create an in-memory pipe, in place of `std::io::pipe`.
+pub fn pipe() -> io::Result<(PipeReader, PipeWriter)> {
+    let pipe = Arc::new(Pipe::default());
+    {
+        let mut state = pipe.state();
+        state.readers = 1;
+        state.writers = 1;
+    }
+    Ok((PipeReader(pipe.clone()), PipeWriter(pipe)))
+}
+
+impl PipeReader {
+    pub fn try_clone(&self) -> io::Result<PipeReader> {
+        self.0.state().readers += 1;
+        Ok(PipeReader(self.0.clone()))
+    }
+}
+
+impl PipeWriter {
+    pub fn try_clone(&self) -> io::Result<PipeWriter> {
+        self.0.state().writers += 1;
+        Ok(PipeWriter(self.0.clone()))
+    }
+}
+
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut state = self.0.state();
+        while state.buffer.is_empty() && state.writers > 0 {
+            state = self
+                .0
+                .readable
+                .wait(state)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        let n = buf.len().min(state.buffer.len());
+        for (dst, src) in buf.iter_mut().zip(state.buffer.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.0.state();
+        if state.readers == 0 {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        state.buffer.extend(buf);
+        self.0.readable.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        self.0.state().readers -= 1;
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        let mut state = self.0.state();
+        state.writers -= 1;
+        if state.writers == 0 {
+            self.0.readable.notify_all();
+        }
+    }
+}
@@ -0,0 +1,277 @@
+//! UTF-16 variants of the functions taking strings the OS interprets.
+//!
+//! Windows names are UTF-16, and a caller holding one — a path or user
+//! name with characters outside the active code page, say — would lose it
+//! going through a narrow string. The `_w` functions take NUL-terminated
+//! UTF-16 instead and otherwise behave as their narrow namesakes do. On
+//! Windows any UTF-16 goes, unpaired surrogates included, as it does in
+//! file names there; elsewhere it must be valid, and is returned as the
+//! narrow function's error otherwise.
+
+use crate::replay::transcript;
+use crate::{PortablePty, PortablePtyBuffer, PortablePtyResult};
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+/// The units of the NUL-terminated string at `s`, NUL excluded.
+///
+/// # Safety
+///
+/// `s` must be non-null and point to a NUL-terminated UTF-16 string.
+unsafe fn units<'a>(s: *const u16) -> &'a [u16] {
+    let mut len = 0;
+    while unsafe { *s.add(len) } != 0 {
+        len += 1;
+    }
+    unsafe { std::slice::from_raw_parts(s, len) }
+}
+
+#[cfg(windows)]
+fn os_string(units: &[u16]) -> Option<OsString> {
+    use std::os::windows::ffi::OsStringExt;
+    Some(OsString::from_wide(units))
+}
+
+#[cfg(not(windows))]
+fn os_string(units: &[u16]) -> Option<OsString> {
+    String::from_utf16(units).ok().map(OsString::from)
+}
+
+/// The string at `s`; see the module docs.
+///
+/// # Safety
+///
+/// As for [`units`].
+unsafe fn wide_string(s: *const u16) -> Option<OsString> {
+    os_string(unsafe { units(s) })
+}
+
+/// Collect a NULL-terminated array of UTF-16 strings.
+///
+/// # Safety
+///
+/// `list` must be non-null and point to a NULL-terminated array of valid
+/// UTF-16 strings.
+unsafe fn wide_string_array(list: *const *const u16) -> Option<Vec<OsString>> {
+    let mut strings = Vec::new();
+    let mut i = 0;
+    loop {
+        let entry = unsafe { *list.add(i) };
+        if entry.is_null() {
+            return Some(strings);
+        }
+        strings.push(unsafe { wide_string(entry) }?);
+        i += 1;
+    }
+}
+
+/// `portable_pty_spawn` taking UTF-16.
+///
+/// - `cmd`: NUL-terminated executable path.
+/// - `argv`: NULL-terminated array of arguments, or NULL to use `cmd` as
+///   the sole argument.
+/// - `envp`: NULL-terminated array of `"KEY=VALUE"` strings, or NULL to
+///   inherit the current environment.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_spawn_w(
+    handle: *mut PortablePty,
+    cmd: *const u16,
+    argv: *const *const u16,
+    envp: *const *const u16,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_mut() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        if cmd.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+
+        let Some(cmd) = (unsafe { wide_string(cmd) }) else {
+            return PortablePtyResult::ErrSpawn;
+        };
+        let args = if argv.is_null() {
+            None
+        } else {
+            match unsafe { wide_string_array(argv) } {
+                Some(args) => Some(args),
+                None => return PortablePtyResult::ErrSpawn,
+            }
+        };
+        let env = (!envp.is_null()).then(|| {
+            let mut env = Vec::new();
+            let mut i = 0;
+            loop {
+                let entry = unsafe { *envp.add(i) };
+                if entry.is_null() {
+                    break env;
+                }
+                let entry = unsafe { units(entry) };
+                if let Some(at) = entry.iter().position(|&u| u == u16::from(b'=')) {
+                    if let (Some(key), Some(val)) =
+                        (os_string(&entry[..at]), os_string(&entry[at + 1..]))
+                    {
+                        env.push((key, val));
+                    }
+                }
+                i += 1;
+            }
+        });
+
+        pty.spawn(crate::build_command(cmd, args, env))
+    })
+}
+
+/// `portable_pty_record_start` taking a UTF-16 `path`.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_record_start_w(
+    handle: *const PortablePty,
+    path: *const u16,
+    format: u32,
+    record_input: bool,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        if path.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+        match unsafe { wide_string(path) } {
+            Some(path) => crate::record::start(pty, &PathBuf::from(path), format, record_input),
+            None => PortablePtyResult::ErrRecord,
+        }
+    })
+}
+
+/// `portable_pty_open_replay` taking a UTF-16 `path`.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_open_replay_w(
+    path: *const u16,
+    out: *mut *mut PortablePty,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        if path.is_null() || out.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+        let Some(path) = (unsafe { wide_string(path) }) else {
+            return PortablePtyResult::ErrOpen;
+        };
+
+        match crate::replay::open_file(&PathBuf::from(path)) {
+            Ok(handle) => {
+                unsafe {
+                    *out = crate::lifecycle::register(handle);
+                }
+                PortablePtyResult::Ok
+            }
+            Err(e) => e,
+        }
+    })
+}
+
+/// `portable_pty_recording_to_text` taking a UTF-16 `path`.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_recording_to_text_w(
+    path: *const u16,
+    mode: u32,
+    out_text: *mut PortablePtyBuffer,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        if path.is_null() || out_text.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+        let Some(path) = (unsafe { wide_string(path) }) else {
+            return PortablePtyResult::ErrOpen;
+        };
+
+        match transcript::to_text(&PathBuf::from(path), mode) {
+            Ok(text) => {
+                unsafe {
+                    *out_text = PortablePtyBuffer::from_vec(text.into_bytes());
+                }
+                PortablePtyResult::Ok
+            }
+            Err(e) => e,
+        }
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn wide(s: &str) -> Vec<u16> {
+        s.encode_utf16().chain([0]).collect()
+    }
+
+    #[test]
+    fn test_spawn_w() {
+        let (cmd, dash_c, script) = (wide("sh"), wide("-c"), wide("echo \"$NAME\""));
+        let argv = [
+            cmd.as_ptr(),
+            dash_c.as_ptr(),
+            script.as_ptr(),
+            std::ptr::null(),
+        ];
+        let (name, path) = (wide("NAME=Zoë 日本"), wide("PATH=/bin:/usr/bin"));
+        let envp = [name.as_ptr(), path.as_ptr(), std::ptr::null()];
+
+        let mut handle = std::ptr::null_mut();
+        crate::portable_pty_open(24, 80, &mut handle);
+        let result = portable_pty_spawn_w(handle, cmd.as_ptr(), argv.as_ptr(), envp.as_ptr());
+        assert!(matches!(result, PortablePtyResult::Ok));
+        let output = crate::tests::read_string(handle);
+        assert!(output.contains("Zoë 日本"), "{output:?}");
+
+        // Off Windows, a lone surrogate has nothing to become.
+        let broken = [0xd800, 0];
+        let argv = [broken.as_ptr(), std::ptr::null()];
+        let result = portable_pty_spawn_w(handle, broken.as_ptr(), argv.as_ptr(), std::ptr::null());
+        assert!(matches!(result, PortablePtyResult::ErrSpawn));
+        crate::portable_pty_close(handle);
+    }
+
+    #[test]
+    #[cfg(all(feature = "recorder", feature = "screen"))]
+    fn test_record_and_replay_w() {
+        use crate::record::PORTABLE_PTY_RECORD_ASCIICAST_V2;
+
+        let dir = std::env::temp_dir().join(format!("portable-pty-wide-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("séance.cast");
+        let path_w = wide(path.to_str().unwrap());
+
+        let handle = crate::tests::open_and_spawn("sh", &["sh", "-c", "echo recorded; sleep 0.2"]);
+        let result = portable_pty_record_start_w(
+            handle,
+            path_w.as_ptr(),
+            PORTABLE_PTY_RECORD_ASCIICAST_V2,
+            false,
+        );
+        assert!(matches!(result, PortablePtyResult::Ok));
+        let output = crate::tests::read_string(handle);
+        assert!(output.contains("recorded"), "{output:?}");
+        crate::record::portable_pty_record_stop(handle);
+        crate::portable_pty_close(handle);
+
+        let mut replay = std::ptr::null_mut();
+        let result = portable_pty_open_replay_w(path_w.as_ptr(), &mut replay);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        crate::portable_pty_close(replay);
+
+        let mut text = PortablePtyBuffer::from_vec(Vec::new());
+        let result = portable_pty_recording_to_text_w(
+            path_w.as_ptr(),
+            transcript::PORTABLE_PTY_TRANSCRIPT_SCREEN,
+            &mut text,
+        );
+        assert!(matches!(result, PortablePtyResult::Ok));
+        let bytes = unsafe { std::slice::from_raw_parts(text.data, text.len) };
+        assert!(String::from_utf8_lossy(bytes).contains("recorded"));
+        crate::portable_pty_buffer_free(text);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,271 @@
+//! What a double-click selects.
+//!
+//! `portable_pty_screen_word_at` finds the stretch of the tracked screen
+//! (see `view`) around a cell, so every embedder's double-click and smart
+//! selection agree with each other and with what the library thinks is on
+//! screen. Wrapped lines are one line to it. There are three ways to cut:
+//!
+//! | mode                           | selects                               |
+//! |--------------------------------|---------------------------------------|
+//! | `PORTABLE_PTY_SELECT_WORD`     | letters, digits and `_`; or a run of  |
+//! |                                | one punctuation character             |
+//! | `PORTABLE_PTY_SELECT_PATH`     | the link there (see `links`), else a  |
+//! |                                | word that may hold `/.~-:@+%#?=&`     |
+//! | `PORTABLE_PTY_SELECT_ARGUMENT` | a shell argument: up to unquoted      |
+//! |                                | whitespace, quotes and `\` escapes    |
+//! |                                | included                              |
+//!
+//! On whitespace, every mode selects the run of it, bar an escaped or
+//! quoted space in an argument.
+
+use crate::{PortablePty, PortablePtyBuffer, PortablePtyResult};
+use std::ops::Range;
+
+/// A word: letters, digits and `_`.
+pub const PORTABLE_PTY_SELECT_WORD: u32 = 0;
+/// A URL or path, or a word with the characters they hold.
+pub const PORTABLE_PTY_SELECT_PATH: u32 = 1;
+/// A shell argument, quotes and escapes included.
+pub const PORTABLE_PTY_SELECT_ARGUMENT: u32 = 2;
+
+/// Characters a path-aware word may hold besides a plain word's.
+const PATH_CHARS: &str = "/.~-:@+%#?=&";
+
+#[derive(PartialEq)]
+enum Class {
+    Space,
+    Word,
+    Other(char),
+}
+
+fn class(c: char, extra: &str) -> Class {
+    if c.is_whitespace() {
+        Class::Space
+    } else if c.is_alphanumeric() || c == '_' || extra.contains(c) {
+        Class::Word
+    } else {
+        Class::Other(c)
+    }
+}
+
+/// The run of characters around `at` in the same class as it.
+fn run(text: &str, at: usize, extra: &str) -> Range<usize> {
+    let Some(c) = text[at..].chars().next() else {
+        return at..at;
+    };
+    let class = class(c, extra);
+    let same = |c: &char| self::class(*c, extra) == class;
+    let before: usize = text[..at]
+        .chars()
+        .rev()
+        .take_while(same)
+        .map(char::len_utf8)
+        .sum();
+    let after: usize = text[at..]
+        .chars()
+        .take_while(same)
+        .map(char::len_utf8)
+        .sum();
+    at - before..at + after
+}
+
+/// The shell arguments in `text`, by byte range.
+fn arguments(text: &str) -> Vec<Range<usize>> {
+    let mut arguments = Vec::new();
+    let mut start = None;
+    let mut quote = None;
+    let mut chars = text.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match (quote, c) {
+            (None, c) if c.is_whitespace() => {
+                if let Some(start) = start.take() {
+                    arguments.push(start..i);
+                }
+                continue;
+            }
+            (None, '\'' | '"') => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            (None | Some('"'), '\\') => {
+                chars.next();
+            }
+            _ => {}
+        }
+        start.get_or_insert(i);
+    }
+    if let Some(start) = start {
+        arguments.push(start..text.len());
+    }
+    arguments
+}
+
+/// What `mode` selects around byte `at` of `text`; None for an unknown
+/// mode.
+fn select(text: &str, at: usize, mode: u32) -> Option<Range<usize>> {
+    let on_space = text[at..].chars().next().is_none_or(char::is_whitespace);
+    Some(match mode {
+        PORTABLE_PTY_SELECT_WORD | PORTABLE_PTY_SELECT_PATH if on_space => run(text, at, ""),
+        PORTABLE_PTY_SELECT_WORD => run(text, at, ""),
+        PORTABLE_PTY_SELECT_PATH => crate::links::find(text)
+            .into_iter()
+            .map(|(range, _)| range)
+            .find(|range| range.contains(&at))
+            .unwrap_or_else(|| run(text, at, PATH_CHARS)),
+        PORTABLE_PTY_SELECT_ARGUMENT => arguments(text)
+            .into_iter()
+            .find(|range| range.contains(&at))
+            .unwrap_or_else(|| run(text, at, "")),
+        _ => return None,
+    })
+}
+
+/// The selection around `col` of line `line` of `screen`, as a JSON
+/// object; None if the line isn't kept or the mode is unknown.
+#[cfg(feature = "screen")]
+fn word_at(
+    screen: &crate::screen::Screen,
+    line: usize,
+    col: usize,
+    mode: u32,
+) -> Option<serde_json::Value> {
+    screen.line(line)?;
+    let (text, placed) = screen.logical_text(line);
+    // The character drawn over the cell, or the line's last.
+    let on_line = |c: &&crate::screen::Placed| c.line == line;
+    let under = placed
+        .iter()
+        .filter(on_line)
+        .find(|c| (c.col..c.col + c.width).contains(&col));
+    let under = under.or_else(|| placed.iter().rfind(on_line))?;
+    let range = select(&text, under.at, mode)?;
+
+    let selected: Vec<_> = placed.iter().filter(|c| range.contains(&c.at)).collect();
+    let (first, last) = (selected.first()?, selected.last()?);
+    Some(serde_json::json!({
+        "start": [first.line, first.col],
+        "end": [last.line, last.col + last.width],
+        "text": &text[range],
+    }))
+}
+
+/// Find what a double-click on a cell of the tracked screen selects.
+/// Wrapped lines count as one, and on whitespace every mode selects the
+/// run of it, bar an escaped or quoted space in an argument.
+///
+/// - `line`: line number, as `portable_pty_screen_lines` gives them.
+/// - `col`: column of the cell; past the end of the line means its last.
+/// - `mode`: `PORTABLE_PTY_SELECT_WORD` for letters, digits and `_`, or a
+///   run of one punctuation character; `PORTABLE_PTY_SELECT_PATH` for the
+///   link there as `portable_pty_screen_links` finds it, else a word that
+///   may hold `/.~-:@+%#?=&`; `PORTABLE_PTY_SELECT_ARGUMENT` for a shell
+///   argument, up to unquoted whitespace, quotes and `\` escapes included.
+/// - `out_json`: receives a UTF-8 JSON object with the `start` and `end`
+///   of the selection, each `[line, column]` with the end exclusive, and
+///   its `text`. Free with `portable_pty_buffer_free`.
+///
+/// Returns `ErrUnsupported` if the screen isn't tracked, no longer keeps
+/// `line`, or `mode` is unknown.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_screen_word_at(
+    handle: *const PortablePty,
+    line: u64,
+    col: u16,
+    mode: u32,
+    out_json: *mut PortablePtyBuffer,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_ref() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        if out_json.is_null() {
+            return PortablePtyResult::ErrNull;
+        }
+        #[cfg(feature = "screen")]
+        {
+            let line = usize::try_from(line).unwrap_or(usize::MAX);
+            let found = pty
+                .view
+                .with(|screen| word_at(screen, line, col.into(), mode));
+            match found {
+                Ok(Some(selection)) => {
+                    let json = selection.to_string().into_bytes();
+                    unsafe { *out_json = PortablePtyBuffer::from_vec(json) };
+                    PortablePtyResult::Ok
+                }
+                Ok(None) => PortablePtyResult::ErrUnsupported,
+                Err(e) => e,
+            }
+        }
+        #[cfg(not(feature = "screen"))]
+        {
+            let _ = (pty, line, col, mode);
+            PortablePtyResult::ErrUnsupported
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn selected(text: &str, at: &str, mode: u32) -> String {
+        let at = text.find(at).unwrap();
+        text[select(text, at, mode).unwrap()].to_owned()
+    }
+
+    #[test]
+    fn test_modes() {
+        let text = "$ cat ~/notes/todo.txt --name='two words' a\\ b;";
+        assert_eq!(selected(text, "notes", PORTABLE_PTY_SELECT_WORD), "notes");
+        assert_eq!(selected(text, "--", PORTABLE_PTY_SELECT_WORD), "--");
+        assert_eq!(
+            selected(text, "notes", PORTABLE_PTY_SELECT_PATH),
+            "~/notes/todo.txt"
+        );
+        assert_eq!(
+            selected(text, "words", PORTABLE_PTY_SELECT_ARGUMENT),
+            "--name='two words'"
+        );
+        assert_eq!(selected(text, "b;", PORTABLE_PTY_SELECT_ARGUMENT), "a\\ b;");
+        assert_eq!(selected(text, " b", PORTABLE_PTY_SELECT_ARGUMENT), "a\\ b;");
+        assert_eq!(selected("a   b", " ", PORTABLE_PTY_SELECT_ARGUMENT), "   ");
+        assert!(select(text, 0, 9).is_none());
+    }
+
+    #[cfg(all(unix, feature = "screen"))]
+    #[test]
+    fn test_word_at_on_the_tracked_screen() {
+        use crate::tests::read_string;
+
+        let mut handle = std::ptr::null_mut();
+        crate::loopback::portable_pty_open_loopback(3, 10, &mut handle);
+        crate::view::portable_pty_screen_start(handle, 100);
+        let output = "ls 字/verylong.txt";
+        crate::loopback::portable_pty_loopback_write(handle, output.as_ptr(), output.len());
+        read_string(handle);
+
+        let word_at = |line, col, mode| {
+            let mut out = PortablePtyBuffer::EMPTY;
+            let result = portable_pty_screen_word_at(handle, line, col, mode, &mut out);
+            if !matches!(result, PortablePtyResult::Ok) {
+                return None;
+            }
+            let bytes = unsafe { std::slice::from_raw_parts(out.data, out.len) };
+            let json: serde_json::Value = serde_json::from_slice(bytes).unwrap();
+            crate::portable_pty_buffer_free(out);
+            Some(json)
+        };
+        // The second half of the wide character, and across the wrap.
+        assert_eq!(
+            word_at(0, 4, PORTABLE_PTY_SELECT_PATH).unwrap(),
+            serde_json::json!({"start": [0, 3], "end": [1, 8], "text": "字/verylong.txt"})
+        );
+        assert_eq!(
+            word_at(1, 0, PORTABLE_PTY_SELECT_WORD).unwrap(),
+            serde_json::json!({"start": [0, 6], "end": [1, 4], "text": "verylong"})
+        );
+        assert!(word_at(0, 0, 7).is_none());
+        assert!(word_at(99, 0, PORTABLE_PTY_SELECT_WORD).is_none());
+        crate::portable_pty_close(handle);
+    }
+}
@@ -0,0 +1,201 @@
+//! Spawning into WSL distributions on Windows.
+//!
+//! `portable_pty_spawn_wsl` builds the `wsl.exe` command line for the
+//! caller: distribution selection, the starting directory (Windows paths
+//! are translated to their Linux mount points), environment forwarding
+//! through `WSLENV`, and UTF-8 output from `wsl.exe` itself.
+
+use crate::pty::CommandBuilder;
+use crate::{c_string_array, PortablePty, PortablePtyResult};
+use std::ffi::{c_char, CStr};
+
+/// Turn a Windows path into the path WSL sees it under.
+///
+/// Drive paths map to `/mnt/<drive>`, and `\\wsl$\<distro>\...` or
+/// `\\wsl.localhost\<distro>\...` shares to the path inside the
+/// distribution. Anything else (Linux paths, `~`) is returned unchanged.
+fn to_linux_path(path: &str) -> String {
+    let bytes = path.as_bytes();
+    if bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':' {
+        let rest = path[2..].trim_matches(['\\', '/']).replace('\\', "/");
+        let drive = (bytes[0] as char).to_ascii_lowercase();
+        if rest.is_empty() {
+            return format!("/mnt/{drive}");
+        }
+        return format!("/mnt/{drive}/{rest}");
+    }
+
+    let unc = path.replace('\\', "/");
+    for prefix in ["//wsl$/", "//wsl.localhost/"] {
+        let Some(share) = unc.get(..prefix.len()) else {
+            continue;
+        };
+        if share.eq_ignore_ascii_case(prefix) {
+            // Skip the distribution name.
+            let inside = unc[prefix.len()..].split_once('/').map_or("", |(_, p)| p);
+            return format!("/{}", inside.trim_end_matches('/'));
+        }
+    }
+    path.to_owned()
+}
+
+/// Build the `wsl.exe` invocation.
+fn command(
+    distro: Option<&str>,
+    cwd: Option<&str>,
+    argv: Option<&[String]>,
+    env: &[(String, String)],
+) -> CommandBuilder {
+    let mut builder = CommandBuilder::new("wsl.exe");
+    if let Some(distro) = distro {
+        builder.args(["--distribution", distro]);
+    }
+    if let Some(cwd) = cwd {
+        builder.args(["--cd".to_owned(), to_linux_path(cwd)]);
+    }
+    if let Some(argv) = argv.filter(|a| !a.is_empty()) {
+        builder.arg("--exec");
+        builder.args(argv);
+    }
+
+    // wsl.exe's own messages are UTF-16 unless asked otherwise.
+    builder.env("WSL_UTF8", "1");
+    if !env.is_empty() {
+        let mut forwarded: Vec<String> = builder
+            .get_env("WSLENV")
+            .and_then(|v| v.to_str())
+            .filter(|v| !v.is_empty())
+            .map(|v| v.split(':').map(str::to_owned).collect())
+            .unwrap_or_default();
+        for (key, value) in env {
+            builder.env(key, value);
+            forwarded.push(key.clone());
+        }
+        builder.env("WSLENV", forwarded.join(":"));
+    }
+    builder
+}
+
+/// Spawn a shell or command inside a WSL distribution.
+///
+/// - `distro`: distribution name, or NULL for the default one.
+/// - `cwd`: starting directory, or NULL for the distribution's default.
+///   Windows paths (`C:\Users\me`, `\\wsl$\Ubuntu\home\me`) are translated;
+///   Linux paths and `~` are used as is.
+/// - `argv`: null-terminated command and arguments to run directly (no
+///   shell involved), or NULL for the user's login shell.
+/// - `envp`: null-terminated `"KEY=VALUE"` entries to set inside the
+///   distribution, or NULL. They're added to the inherited environment and
+///   forwarded through `WSLENV`.
+///
+/// Only available on Windows; elsewhere this returns `ErrUnsupported`.
+#[unsafe(no_mangle)]
+pub extern "C" fn portable_pty_spawn_wsl(
+    handle: *mut PortablePty,
+    distro: *const c_char,
+    cwd: *const c_char,
+    argv: *const *const c_char,
+    envp: *const *const c_char,
+) -> PortablePtyResult {
+    crate::ffi::guard(|| {
+        let pty = match unsafe { handle.as_mut() } {
+            Some(p) => p,
+            None => return PortablePtyResult::ErrNull,
+        };
+        if !cfg!(windows) {
+            return PortablePtyResult::ErrUnsupported;
+        }
+
+        let text = |s: *const c_char| {
+            if s.is_null() {
+                return Ok(None);
+            }
+            unsafe { CStr::from_ptr(s) }.to_str().map(Some)
+        };
+        let (Ok(distro), Ok(cwd)) = (text(distro), text(cwd)) else {
+            return PortablePtyResult::ErrSpawn;
+        };
+        let argv = if argv.is_null() {
+            None
+        } else {
+            match unsafe { c_string_array(argv) } {
+                Some(a) => Some(a),
+                None => return PortablePtyResult::ErrSpawn,
+            }
+        };
+        let env = if envp.is_null() {
+            Vec::new()
+        } else {
+            match unsafe { c_string_array(envp) } {
+                Some(entries) => entries
+                    .iter()
+                    .filter_map(|e| e.split_once('='))
+                    .map(|(k, v)| (k.to_owned(), v.to_owned()))
+                    .collect(),
+                None => return PortablePtyResult::ErrSpawn,
+            }
+        };
+
+        pty.spawn(command(distro, cwd, argv.as_deref(), &env))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_translates_windows_paths() {
+        assert_eq!(to_linux_path(r"C:\Users\me\src"), "/mnt/c/Users/me/src");
+        assert_eq!(to_linux_path("d:/"), "/mnt/d");
+        assert_eq!(to_linux_path(r"\\wsl$\Ubuntu\home\me\"), "/home/me");
+        assert_eq!(to_linux_path(r"\\WSL.LOCALHOST\Debian"), "/");
+        assert_eq!(to_linux_path("/home/me"), "/home/me");
+        assert_eq!(to_linux_path("~"), "~");
+    }
+
+    #[test]
+    fn test_builds_wsl_command_line() {
+        let argv = ["htop".to_owned(), "-d".to_owned(), "10".to_owned()];
+        let env = [("EDITOR".to_owned(), "vim".to_owned())];
+        let builder = command(Some("Ubuntu"), Some(r"C:\work"), Some(&argv), &env);
+        assert_eq!(
+            builder.get_argv(),
+            &[
+                "wsl.exe",
+                "--distribution",
+                "Ubuntu",
+                "--cd",
+                "/mnt/c/work",
+                "--exec",
+                "htop",
+                "-d",
+                "10"
+            ]
+        );
+        assert_eq!(builder.get_env("WSL_UTF8").unwrap(), "1");
+        let wslenv = builder.get_env("WSLENV").unwrap().to_str().unwrap();
+        assert!(wslenv.split(':').any(|v| v == "EDITOR"));
+
+        // No argv: the login shell.
+        let shell = command(None, None, None, &[]);
+        assert_eq!(shell.get_argv(), &["wsl.exe"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unsupported_outside_windows() {
+        let mut handle = std::ptr::null_mut();
+        let result = crate::portable_pty_open(24, 80, &mut handle);
+        assert!(matches!(result, PortablePtyResult::Ok));
+        let result = portable_pty_spawn_wsl(
+            handle,
+            std::ptr::null(),
+            std::ptr::null(),
+            std::ptr::null(),
+            std::ptr::null(),
+        );
+        assert!(matches!(result, PortablePtyResult::ErrUnsupported));
+        crate::portable_pty_close(handle);
+    }
+}